@@ -0,0 +1,8 @@
+CREATE TABLE cipher_shares (
+	cipher_uuid     CHAR(36) NOT NULL REFERENCES ciphers(uuid),
+	user_uuid       CHAR(36) NOT NULL REFERENCES users(uuid),
+	akey            TEXT NOT NULL,
+	read_only       BOOLEAN NOT NULL DEFAULT FALSE,
+	creation_date   DATETIME NOT NULL,
+	PRIMARY KEY(cipher_uuid, user_uuid)
+);
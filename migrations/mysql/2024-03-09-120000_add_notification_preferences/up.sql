@@ -0,0 +1,7 @@
+CREATE TABLE notification_preferences (
+	user_uuid             CHAR(36) NOT NULL PRIMARY KEY REFERENCES users(uuid),
+	new_device_login      BOOLEAN NOT NULL DEFAULT TRUE,
+	incomplete_2fa_login  BOOLEAN NOT NULL DEFAULT TRUE,
+	emergency_access      BOOLEAN NOT NULL DEFAULT TRUE,
+	updated_at            DATETIME NOT NULL
+);
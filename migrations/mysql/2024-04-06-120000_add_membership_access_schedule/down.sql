@@ -0,0 +1 @@
+ALTER TABLE users_organizations DROP COLUMN access_schedule;
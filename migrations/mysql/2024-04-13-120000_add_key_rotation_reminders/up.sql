@@ -0,0 +1,3 @@
+ALTER TABLE users ADD COLUMN key_rotated_at DATETIME;
+ALTER TABLE notification_preferences ADD COLUMN key_rotation_reminder BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE notification_preferences ADD COLUMN key_rotation_reminded_at DATETIME;
@@ -0,0 +1,2 @@
+DROP INDEX collections_org_external_id ON collections;
+DROP INDEX groups_org_external_id ON `groups`;
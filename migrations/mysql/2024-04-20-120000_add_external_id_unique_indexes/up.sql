@@ -0,0 +1,11 @@
+-- Keep the external ID of one collection or group per organization, the others lose theirs
+UPDATE collections c1 JOIN collections c2
+    ON c2.org_uuid = c1.org_uuid AND c2.external_id = c1.external_id AND c2.uuid < c1.uuid
+SET c1.external_id = NULL;
+UPDATE `groups` g1 JOIN `groups` g2
+    ON g2.organizations_uuid = g1.organizations_uuid AND g2.external_id = g1.external_id AND g2.uuid < g1.uuid
+SET g1.external_id = NULL;
+
+-- A TEXT column can only be indexed by a prefix, external IDs are much shorter in practice
+CREATE UNIQUE INDEX collections_org_external_id ON collections (org_uuid, external_id(255));
+CREATE UNIQUE INDEX groups_org_external_id ON `groups` (organizations_uuid, external_id);
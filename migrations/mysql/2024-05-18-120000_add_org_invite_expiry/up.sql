@@ -0,0 +1,3 @@
+ALTER TABLE users_organizations ADD COLUMN invited_at DATETIME;
+-- Existing invitations get a full expiry period from now on, stored in UTC like the other timestamps
+UPDATE users_organizations SET invited_at = UTC_TIMESTAMP() WHERE status = 0;
@@ -0,0 +1,13 @@
+CREATE TABLE device_approvals (
+	uuid              CHAR(36) NOT NULL PRIMARY KEY,
+	user_uuid         CHAR(36) NOT NULL REFERENCES users(uuid),
+	org_uuid          CHAR(36) NOT NULL REFERENCES organizations(uuid),
+	device_identifier CHAR(36) NOT NULL,
+	device_name       TEXT NOT NULL,
+	device_type       INTEGER NOT NULL,
+	request_ip        TEXT NOT NULL,
+	creation_date     DATETIME NOT NULL,
+	approved_by       CHAR(36),
+	approval_date     DATETIME,
+	UNIQUE(user_uuid, org_uuid, device_identifier)
+);
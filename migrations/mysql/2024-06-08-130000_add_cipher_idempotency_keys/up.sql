@@ -0,0 +1,7 @@
+CREATE TABLE cipher_idempotency_keys (
+	user_uuid    CHAR(36) NOT NULL REFERENCES users(uuid),
+	key_hash     CHAR(64) NOT NULL,
+	cipher_uuid  CHAR(36) NOT NULL,
+	created_at   DATETIME NOT NULL,
+	PRIMARY KEY (user_uuid, key_hash)
+);
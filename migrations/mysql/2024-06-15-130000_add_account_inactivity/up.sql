@@ -0,0 +1,5 @@
+CREATE TABLE account_inactivity (
+	user_uuid      CHAR(36) NOT NULL PRIMARY KEY REFERENCES users(uuid),
+	warned_at      DATETIME,
+	reactivated_at DATETIME
+);
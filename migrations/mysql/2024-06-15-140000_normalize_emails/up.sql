@@ -0,0 +1,18 @@
+-- Store every email trimmed and lowercased, like they are looked up.
+-- Emails which would then collide with another account are left as is and reported at startup.
+-- The comparison is binary since the default collation ignores case, the derived tables allow reading the updated table.
+UPDATE users SET email = LOWER(TRIM(email))
+WHERE CAST(email AS BINARY) <> CAST(LOWER(TRIM(email)) AS BINARY)
+AND LOWER(TRIM(email)) NOT IN (
+    SELECT normalized FROM (
+        SELECT LOWER(TRIM(email)) AS normalized FROM users GROUP BY LOWER(TRIM(email)) HAVING COUNT(*) > 1
+    ) AS duplicates
+);
+
+UPDATE invitations SET email = LOWER(TRIM(email))
+WHERE CAST(email AS BINARY) <> CAST(LOWER(TRIM(email)) AS BINARY)
+AND LOWER(TRIM(email)) NOT IN (
+    SELECT normalized FROM (
+        SELECT LOWER(TRIM(email)) AS normalized FROM invitations GROUP BY LOWER(TRIM(email)) HAVING COUNT(*) > 1
+    ) AS duplicates
+);
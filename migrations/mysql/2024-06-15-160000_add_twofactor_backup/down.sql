@@ -0,0 +1 @@
+DROP TABLE twofactor_backup;
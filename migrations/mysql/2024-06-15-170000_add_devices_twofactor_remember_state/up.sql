@@ -0,0 +1,2 @@
+ALTER TABLE devices ADD COLUMN twofactor_remember_at DATETIME;
+ALTER TABLE devices ADD COLUMN twofactor_remember_prev TEXT;
@@ -0,0 +1 @@
+ALTER TABLE users_organizations ADD COLUMN accepted_at DATETIME;
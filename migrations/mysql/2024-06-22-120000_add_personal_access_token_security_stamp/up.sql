@@ -0,0 +1,3 @@
+-- Tokens created before the security stamp was stored are revoked, they have to be created again
+DELETE FROM personal_access_tokens;
+ALTER TABLE personal_access_tokens ADD COLUMN security_stamp TEXT NOT NULL;
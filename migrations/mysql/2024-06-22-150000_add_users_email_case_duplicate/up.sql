@@ -0,0 +1,28 @@
+-- Accounts whose email only differs in surrounding whitespace were left as is by normalize_emails.
+-- The one found by the case-insensitive collation can log in, when none is the most recently updated one gets it.
+-- The others are flagged and reported at startup. The collation already makes the unique key of the email
+-- case-insensitive, an index on the trimmed email isn't supported by every MySQL and MariaDB version.
+-- The derived tables allow reading the updated table.
+ALTER TABLE users ADD COLUMN email_case_duplicate BOOLEAN NOT NULL DEFAULT FALSE;
+
+UPDATE users SET email = LOWER(TRIM(email))
+WHERE uuid IN (
+    SELECT uuid FROM (
+        SELECT u1.uuid FROM users u1
+        WHERE u1.email <> LOWER(TRIM(u1.email))
+        AND NOT EXISTS (SELECT 1 FROM users u2 WHERE u2.email = LOWER(TRIM(u1.email)))
+        AND NOT EXISTS (
+            SELECT 1 FROM users u2
+            WHERE LOWER(TRIM(u2.email)) = LOWER(TRIM(u1.email)) AND u2.uuid <> u1.uuid
+            AND (u2.updated_at > u1.updated_at OR (u2.updated_at = u1.updated_at AND u2.uuid > u1.uuid))
+        )
+    ) AS logins
+);
+
+UPDATE users SET email_case_duplicate = TRUE
+WHERE email <> LOWER(TRIM(email))
+AND LOWER(TRIM(email)) IN (
+    SELECT normalized FROM (
+        SELECT LOWER(TRIM(email)) AS normalized FROM users GROUP BY LOWER(TRIM(email)) HAVING COUNT(*) > 1
+    ) AS duplicates
+);
@@ -0,0 +1 @@
+ALTER TABLE users DROP COLUMN unverified_reminder_at;
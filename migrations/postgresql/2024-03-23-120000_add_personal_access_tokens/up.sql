@@ -0,0 +1,10 @@
+CREATE TABLE personal_access_tokens (
+	uuid            CHAR(36) NOT NULL PRIMARY KEY,
+	user_uuid       CHAR(36) NOT NULL REFERENCES users(uuid),
+	name            TEXT NOT NULL,
+	token_hash      TEXT NOT NULL UNIQUE,
+	read_only       BOOLEAN NOT NULL DEFAULT TRUE,
+	expires_at      TIMESTAMP,
+	creation_date   TIMESTAMP NOT NULL,
+	last_used_at    TIMESTAMP
+);
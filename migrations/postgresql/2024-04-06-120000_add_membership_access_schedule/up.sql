@@ -0,0 +1 @@
+ALTER TABLE users_organizations ADD COLUMN access_schedule TEXT;
@@ -0,0 +1,14 @@
+-- Keep the external ID of one collection or group per organization, the others lose theirs
+UPDATE collections SET external_id = NULL
+WHERE external_id IS NOT NULL AND EXISTS (
+    SELECT 1 FROM collections c2
+    WHERE c2.org_uuid = collections.org_uuid AND c2.external_id = collections.external_id AND c2.uuid < collections.uuid
+);
+UPDATE groups SET external_id = NULL
+WHERE external_id IS NOT NULL AND EXISTS (
+    SELECT 1 FROM groups g2
+    WHERE g2.organizations_uuid = groups.organizations_uuid AND g2.external_id = groups.external_id AND g2.uuid < groups.uuid
+);
+
+CREATE UNIQUE INDEX collections_org_external_id ON collections (org_uuid, external_id);
+CREATE UNIQUE INDEX groups_org_external_id ON groups (organizations_uuid, external_id);
@@ -0,0 +1 @@
+ALTER TABLE organizations ADD COLUMN legal_hold BOOLEAN NOT NULL DEFAULT FALSE;
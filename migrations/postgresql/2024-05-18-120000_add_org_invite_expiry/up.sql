@@ -0,0 +1,3 @@
+ALTER TABLE users_organizations ADD COLUMN invited_at TIMESTAMP;
+-- Existing invitations get a full expiry period from now on, stored in UTC like the other timestamps
+UPDATE users_organizations SET invited_at = timezone('utc', now()) WHERE status = 0;
@@ -0,0 +1,6 @@
+CREATE TABLE account_recovery_tokens (
+	user_uuid       CHAR(36) NOT NULL PRIMARY KEY REFERENCES users(uuid),
+	token_hash      CHAR(64) NOT NULL,
+	security_stamp  TEXT NOT NULL,
+	expires_at      TIMESTAMP NOT NULL
+);
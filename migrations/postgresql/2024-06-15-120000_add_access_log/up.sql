@@ -0,0 +1,12 @@
+CREATE TABLE access_log (
+	uuid           CHAR(36) NOT NULL PRIMARY KEY,
+	user_uuid      CHAR(36) NOT NULL REFERENCES users(uuid),
+	method         VARCHAR(10) NOT NULL,
+	endpoint_class VARCHAR(255) NOT NULL,
+	ip_address     VARCHAR(45) NOT NULL,
+	device_type    INTEGER NOT NULL,
+	client_name    VARCHAR(255),
+	created_at     TIMESTAMP NOT NULL
+);
+
+CREATE INDEX access_log_user_created_idx ON access_log (user_uuid, created_at);
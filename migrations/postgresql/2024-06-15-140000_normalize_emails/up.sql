@@ -0,0 +1,13 @@
+-- Store every email trimmed and lowercased, like they are looked up.
+-- Emails which would then collide with another account are left as is and reported at startup.
+UPDATE users SET email = LOWER(TRIM(email))
+WHERE email <> LOWER(TRIM(email))
+AND LOWER(TRIM(email)) NOT IN (
+    SELECT LOWER(TRIM(email)) FROM users GROUP BY LOWER(TRIM(email)) HAVING COUNT(*) > 1
+);
+
+UPDATE invitations SET email = LOWER(TRIM(email))
+WHERE email <> LOWER(TRIM(email))
+AND LOWER(TRIM(email)) NOT IN (
+    SELECT LOWER(TRIM(email)) FROM invitations GROUP BY LOWER(TRIM(email)) HAVING COUNT(*) > 1
+);
@@ -0,0 +1,9 @@
+CREATE TABLE twofactor_backup (
+	user_uuid                CHAR(36) NOT NULL PRIMARY KEY REFERENCES users(uuid),
+	methods                  INTEGER NOT NULL,
+	recovery_acknowledged_at TIMESTAMP
+);
+
+-- Users who already enabled two-step login, their recovery code is treated as not saved yet
+INSERT INTO twofactor_backup (user_uuid, methods)
+SELECT user_uuid, COUNT(*) FROM twofactor WHERE atype < 1000 AND enabled GROUP BY user_uuid;
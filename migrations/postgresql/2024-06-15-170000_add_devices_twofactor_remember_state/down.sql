@@ -0,0 +1,2 @@
+ALTER TABLE devices DROP COLUMN twofactor_remember_prev;
+ALTER TABLE devices DROP COLUMN twofactor_remember_at;
@@ -0,0 +1,11 @@
+CREATE TABLE cipher_transfers (
+	uuid          CHAR(36) NOT NULL PRIMARY KEY,
+	cipher_uuid   CHAR(36) NOT NULL REFERENCES ciphers(uuid),
+	user_uuid     CHAR(36) NOT NULL REFERENCES users(uuid),
+	org_uuid      CHAR(36) NOT NULL REFERENCES organizations(uuid),
+	data          TEXT,
+	status        INTEGER NOT NULL,
+	creation_date TIMESTAMP NOT NULL,
+	decided_by    CHAR(36),
+	decision_date TIMESTAMP
+);
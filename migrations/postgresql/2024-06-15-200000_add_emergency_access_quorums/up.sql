@@ -0,0 +1,4 @@
+CREATE TABLE emergency_access_quorums (
+	grantor_uuid CHAR(36) NOT NULL PRIMARY KEY REFERENCES users(uuid),
+	quorum       INTEGER  NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE account_recovery_tokens DROP COLUMN failed_attempts;
@@ -0,0 +1,2 @@
+DROP INDEX users_email_normalized;
+ALTER TABLE users DROP COLUMN email_case_duplicate;
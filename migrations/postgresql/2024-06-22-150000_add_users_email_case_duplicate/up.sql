@@ -0,0 +1,21 @@
+-- Accounts whose email only differs in case or surrounding whitespace were left as is by normalize_emails.
+-- The one with the normalized email can log in, when none has it the most recently updated one gets it.
+-- The others are flagged and reported at startup, they are left out of the case-insensitive unique index.
+ALTER TABLE users ADD COLUMN email_case_duplicate BOOLEAN NOT NULL DEFAULT FALSE;
+
+UPDATE users SET email = LOWER(TRIM(email))
+WHERE email <> LOWER(TRIM(email))
+AND NOT EXISTS (SELECT 1 FROM users u2 WHERE u2.email = LOWER(TRIM(users.email)))
+AND NOT EXISTS (
+    SELECT 1 FROM users u2
+    WHERE LOWER(TRIM(u2.email)) = LOWER(TRIM(users.email)) AND u2.uuid <> users.uuid
+    AND (u2.updated_at > users.updated_at OR (u2.updated_at = users.updated_at AND u2.uuid > users.uuid))
+);
+
+UPDATE users SET email_case_duplicate = TRUE
+WHERE email <> LOWER(TRIM(email))
+AND LOWER(TRIM(email)) IN (
+    SELECT LOWER(TRIM(email)) FROM users GROUP BY LOWER(TRIM(email)) HAVING COUNT(*) > 1
+);
+
+CREATE UNIQUE INDEX users_email_normalized ON users (LOWER(TRIM(email))) WHERE NOT email_case_duplicate;
@@ -0,0 +1,10 @@
+CREATE TABLE cipher_shares (
+	cipher_uuid     TEXT NOT NULL,
+	user_uuid       TEXT NOT NULL,
+	akey            TEXT NOT NULL,
+	read_only       BOOLEAN NOT NULL DEFAULT 0,
+	creation_date   DATETIME NOT NULL,
+	PRIMARY KEY(cipher_uuid, user_uuid),
+	FOREIGN KEY(cipher_uuid) REFERENCES ciphers(uuid),
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid)
+);
@@ -0,0 +1,8 @@
+CREATE TABLE notification_preferences (
+	user_uuid             TEXT NOT NULL PRIMARY KEY,
+	new_device_login      BOOLEAN NOT NULL DEFAULT 1,
+	incomplete_2fa_login  BOOLEAN NOT NULL DEFAULT 1,
+	emergency_access      BOOLEAN NOT NULL DEFAULT 1,
+	updated_at            DATETIME NOT NULL,
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid)
+);
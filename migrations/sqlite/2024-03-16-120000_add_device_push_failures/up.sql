@@ -0,0 +1 @@
+ALTER TABLE devices ADD COLUMN push_failures INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,11 @@
+CREATE TABLE personal_access_tokens (
+	uuid            TEXT NOT NULL PRIMARY KEY,
+	user_uuid       TEXT NOT NULL,
+	name            TEXT NOT NULL,
+	token_hash      TEXT NOT NULL UNIQUE,
+	read_only       BOOLEAN NOT NULL DEFAULT 1,
+	expires_at      DATETIME,
+	creation_date   DATETIME NOT NULL,
+	last_used_at    DATETIME,
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid)
+);
@@ -0,0 +1,3 @@
+ALTER TABLE users DROP COLUMN key_rotated_at;
+ALTER TABLE notification_preferences DROP COLUMN key_rotation_reminder;
+ALTER TABLE notification_preferences DROP COLUMN key_rotation_reminded_at;
@@ -0,0 +1,3 @@
+ALTER TABLE organizations DROP COLUMN branding_name;
+ALTER TABLE organizations DROP COLUMN branding_logo_url;
+ALTER TABLE organizations DROP COLUMN branding_logo_file;
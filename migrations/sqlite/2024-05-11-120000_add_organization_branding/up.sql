@@ -0,0 +1,3 @@
+ALTER TABLE organizations ADD COLUMN branding_name TEXT;
+ALTER TABLE organizations ADD COLUMN branding_logo_url TEXT;
+ALTER TABLE organizations ADD COLUMN branding_logo_file TEXT;
@@ -0,0 +1,15 @@
+CREATE TABLE device_approvals (
+	uuid              TEXT NOT NULL PRIMARY KEY,
+	user_uuid         TEXT NOT NULL,
+	org_uuid          TEXT NOT NULL,
+	device_identifier TEXT NOT NULL,
+	device_name       TEXT NOT NULL,
+	device_type       INTEGER NOT NULL,
+	request_ip        TEXT NOT NULL,
+	creation_date     DATETIME NOT NULL,
+	approved_by       TEXT,
+	approval_date     DATETIME,
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid),
+	FOREIGN KEY(org_uuid) REFERENCES organizations(uuid),
+	UNIQUE(user_uuid, org_uuid, device_identifier)
+);
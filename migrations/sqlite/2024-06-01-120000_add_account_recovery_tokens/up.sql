@@ -0,0 +1,7 @@
+CREATE TABLE account_recovery_tokens (
+	user_uuid       TEXT NOT NULL PRIMARY KEY,
+	token_hash      TEXT NOT NULL,
+	security_stamp  TEXT NOT NULL,
+	expires_at      DATETIME NOT NULL,
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid)
+);
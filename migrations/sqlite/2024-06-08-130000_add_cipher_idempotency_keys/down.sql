@@ -0,0 +1 @@
+DROP TABLE cipher_idempotency_keys;
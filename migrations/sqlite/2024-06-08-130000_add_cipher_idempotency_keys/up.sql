@@ -0,0 +1,8 @@
+CREATE TABLE cipher_idempotency_keys (
+	user_uuid    TEXT NOT NULL,
+	key_hash     TEXT NOT NULL,
+	cipher_uuid  TEXT NOT NULL,
+	created_at   DATETIME NOT NULL,
+	PRIMARY KEY (user_uuid, key_hash),
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid)
+);
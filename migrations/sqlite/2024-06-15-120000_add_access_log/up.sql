@@ -0,0 +1,13 @@
+CREATE TABLE access_log (
+	uuid           TEXT NOT NULL PRIMARY KEY,
+	user_uuid      TEXT NOT NULL,
+	method         TEXT NOT NULL,
+	endpoint_class TEXT NOT NULL,
+	ip_address     TEXT NOT NULL,
+	device_type    INTEGER NOT NULL,
+	client_name    TEXT,
+	created_at     DATETIME NOT NULL,
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid)
+);
+
+CREATE INDEX access_log_user_created_idx ON access_log (user_uuid, created_at);
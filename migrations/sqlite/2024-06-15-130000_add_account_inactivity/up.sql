@@ -0,0 +1,6 @@
+CREATE TABLE account_inactivity (
+	user_uuid      TEXT NOT NULL PRIMARY KEY,
+	warned_at      DATETIME,
+	reactivated_at DATETIME,
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid)
+);
@@ -0,0 +1,10 @@
+CREATE TABLE twofactor_backup (
+	user_uuid                TEXT NOT NULL PRIMARY KEY,
+	methods                  INTEGER NOT NULL,
+	recovery_acknowledged_at DATETIME,
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid)
+);
+
+-- Users who already enabled two-step login, their recovery code is treated as not saved yet
+INSERT INTO twofactor_backup (user_uuid, methods)
+SELECT user_uuid, COUNT(*) FROM twofactor WHERE atype < 1000 AND enabled GROUP BY user_uuid;
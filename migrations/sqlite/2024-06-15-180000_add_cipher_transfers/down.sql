@@ -0,0 +1 @@
+DROP TABLE cipher_transfers;
@@ -0,0 +1,14 @@
+CREATE TABLE cipher_transfers (
+	uuid          TEXT NOT NULL PRIMARY KEY,
+	cipher_uuid   TEXT NOT NULL,
+	user_uuid     TEXT NOT NULL,
+	org_uuid      TEXT NOT NULL,
+	data          TEXT,
+	status        INTEGER NOT NULL,
+	creation_date DATETIME NOT NULL,
+	decided_by    TEXT,
+	decision_date DATETIME,
+	FOREIGN KEY(cipher_uuid) REFERENCES ciphers(uuid),
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid),
+	FOREIGN KEY(org_uuid) REFERENCES organizations(uuid)
+);
@@ -0,0 +1 @@
+ALTER TABLE users_organizations DROP COLUMN accepted_at;
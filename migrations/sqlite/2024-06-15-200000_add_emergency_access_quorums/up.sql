@@ -0,0 +1,5 @@
+CREATE TABLE emergency_access_quorums (
+	grantor_uuid TEXT    NOT NULL PRIMARY KEY,
+	quorum       INTEGER NOT NULL,
+	FOREIGN KEY(grantor_uuid) REFERENCES users(uuid)
+);
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN unverified_reminder_at DATETIME;
@@ -2,6 +2,7 @@ use once_cell::sync::Lazy;
 use serde::de::DeserializeOwned;
 use serde_json::Value;
 use std::env;
+use std::sync::atomic::{AtomicI64, Ordering};
 
 use rocket::serde::json::Json;
 use rocket::{
@@ -14,7 +15,7 @@ use rocket::{
 
 use crate::{
     api::{
-        core::{log_event, two_factor},
+        core::{get_continuation_token, log_admin_event, log_event, two_factor, AdminEventTarget},
         unregister_push_device, ApiResult, EmptyResult, JsonResult, Notify,
     },
     auth::{decode_admin, encode_jwt, generate_admin_claims, ClientIp},
@@ -24,7 +25,7 @@ use crate::{
     mail,
     util::{
         container_base_image, format_naive_datetime_local, get_display_size, get_reqwest_client,
-        is_running_in_container, NumberOrString,
+        is_running_in_container, try_parse_date, NumberOrString,
     },
     CONFIG, VERSION,
 };
@@ -37,8 +38,12 @@ pub fn routes() -> Vec<Route> {
     routes![
         get_users_json,
         get_user_json,
+        get_user_access_log,
+        get_users_missing_2fa_backup,
+        get_admin_events,
         get_user_by_mail_json,
         post_admin_login,
+        post_break_glass,
         admin_page,
         admin_page_login,
         invite_user,
@@ -53,9 +58,15 @@ pub fn routes() -> Vec<Route> {
         post_config,
         delete_config,
         backup_db,
+        revoke_all_tokens,
+        rotate_jwt_key,
+        post_announcement,
         test_smtp,
         users_overview,
         organizations_overview,
+        enable_legal_hold,
+        disable_legal_hold,
+        update_org_seat_limit,
         delete_organization,
         diagnostics,
         get_diagnostics_config,
@@ -153,6 +164,7 @@ fn render_admin_login(msg: Option<&str>, redirect: Option<String>) -> ApiResult<
         "page_content": "admin/login",
         "error": msg,
         "redirect": redirect,
+        "totp_required": CONFIG.admin_totp_secret().is_some(),
         "urlpath": CONFIG.domain_path()
     });
 
@@ -164,6 +176,7 @@ fn render_admin_login(msg: Option<&str>, redirect: Option<String>) -> ApiResult<
 #[derive(FromForm)]
 struct LoginForm {
     token: String,
+    totp: Option<String>,
     redirect: Option<String>,
 }
 
@@ -179,10 +192,14 @@ fn post_admin_login(data: Form<LoginForm>, cookies: &CookieJar<'_>, ip: ClientIp
         )));
     }
 
-    // If the token is invalid, redirect to login page
-    if !_validate_token(&data.token) {
-        error!("Invalid admin token. IP: {}", ip.ip);
-        Err(AdminResponse::Unauthorized(render_admin_login(Some("Invalid admin token, please try again."), redirect)))
+    // If the token or TOTP code is invalid, redirect to login page
+    // The same error is shown for both, so it doesn't tell whether the token was right
+    if !_validate_token(&data.token) || !_validate_admin_totp(data.totp.as_deref()) {
+        error!("Invalid admin token or TOTP code. IP: {}", ip.ip);
+        Err(AdminResponse::Unauthorized(render_admin_login(
+            Some("Invalid admin token or TOTP code, please try again."),
+            redirect,
+        )))
     } else {
         // If the token received is valid, generate JWT and save it as a cookie
         let claims = generate_admin_claims();
@@ -203,6 +220,81 @@ fn post_admin_login(data: Form<LoginForm>, cookies: &CookieJar<'_>, ip: ClientIp
     }
 }
 
+#[derive(FromForm)]
+struct BreakGlassForm {
+    token: String,
+    new_admin_token: String,
+}
+
+/// Resets a lost admin token with a one-time break-glass token, created with `vaultwarden break-glass`
+#[post("/break-glass", data = "<data>")]
+async fn post_break_glass(
+    data: Form<BreakGlassForm>,
+    ip: ClientIp,
+    mut conn: DbConn,
+) -> Result<Redirect, AdminResponse> {
+    let data = data.into_inner();
+
+    if crate::ratelimit::check_limit_admin(&ip.ip).is_err() {
+        return Err(AdminResponse::TooManyRequests(render_admin_login(
+            Some("Too many requests, try again later."),
+            None,
+        )));
+    }
+
+    if data.new_admin_token.trim().len() < 8 {
+        return Err(AdminResponse::Ok(render_admin_login(
+            Some("The new admin token must contain at least 8 characters."),
+            None,
+        )));
+    }
+
+    if !consume_break_glass_token(&CONFIG.break_glass_token_filename(), &data.token) {
+        error!("Invalid break-glass token. IP: {}", ip.ip);
+        return Err(AdminResponse::Unauthorized(render_admin_login(Some("Invalid break-glass token."), None)));
+    }
+
+    let result = hash_admin_token(data.new_admin_token.trim()).and_then(|hash| CONFIG.set_admin_token(hash));
+    if let Err(e) = result {
+        error!("Failed to reset the admin token: {e:#?}");
+        return Err(AdminResponse::Ok(render_admin_login(Some("Failed to reset the admin token."), None)));
+    }
+
+    warn!("The admin token was reset with a break-glass token. IP: {}", ip.ip);
+    // There is no admin session yet, the event is only identified by the IP
+    log_admin_event(EventType::AdminTokenResetWithBreakGlass, AdminEventTarget::None, None, &ip.ip, &mut conn).await;
+    Ok(Redirect::to(admin_path()))
+}
+
+fn hash_break_glass_token(token: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, token.trim().as_bytes());
+    data_encoding::HEXLOWER.encode(digest.as_ref())
+}
+
+/// Creates a break-glass token, only its hash is stored in `path` and the token itself is returned once
+pub fn create_break_glass_token(path: &str) -> std::io::Result<String> {
+    let token = crate::crypto::encode_random_bytes::<32>(data_encoding::BASE64URL_NOPAD);
+    std::fs::write(path, hash_break_glass_token(&token))?;
+    Ok(token)
+}
+
+/// Checks a break-glass token and invalidates it when it's valid, so it can only ever be used once
+fn consume_break_glass_token(path: &str, token: &str) -> bool {
+    let Ok(stored_hash) = std::fs::read_to_string(path) else {
+        return false;
+    };
+    if !crate::crypto::ct_eq(stored_hash.trim(), hash_break_glass_token(token)) {
+        return false;
+    }
+    // Only one of multiple concurrent requests is able to remove the file
+    std::fs::remove_file(path).is_ok()
+}
+
+/// Hashes a new admin token with the same parameters as the default preset of `vaultwarden hash`
+fn hash_admin_token(token: &str) -> Result<String, Error> {
+    crate::crypto::hash_argon2id(token.as_bytes(), crate::crypto::ARGON2_BITWARDEN_PRESET)
+}
+
 fn _validate_token(token: &str) -> bool {
     match CONFIG.admin_token().as_ref() {
         None => false,
@@ -223,6 +315,35 @@ fn _validate_token(token: &str) -> bool {
     }
 }
 
+// The time step of the last valid admin TOTP code, so a code can only be used once.
+// It's only kept in memory, so a code used right before a restart can be used once more afterwards.
+static ADMIN_TOTP_LAST_USED: AtomicI64 = AtomicI64::new(0);
+
+/// Validates the TOTP code of an admin login, which is only required when `ADMIN_TOTP_SECRET` is set
+fn _validate_admin_totp(totp_code: Option<&str>) -> bool {
+    match CONFIG.admin_totp_secret() {
+        None => true,
+        Some(secret) => {
+            let steps = i64::from(!CONFIG.authenticator_disable_time_drift());
+            check_admin_totp(&secret, totp_code, chrono::Utc::now().timestamp(), steps, &ADMIN_TOTP_LAST_USED)
+        }
+    }
+}
+
+fn check_admin_totp(secret: &str, totp_code: Option<&str>, timestamp: i64, steps: i64, last_used: &AtomicI64) -> bool {
+    use two_factor::authenticator::{check_totp_code, TotpCheck};
+
+    let (Some(totp_code), Ok(decoded_secret)) = (totp_code, data_encoding::BASE32.decode(secret.trim().as_bytes()))
+    else {
+        return false;
+    };
+    match check_totp_code(&decoded_secret, totp_code.trim(), timestamp, steps, last_used.load(Ordering::Acquire)) {
+        // Only one of concurrent logins with the same code can raise the last used time step
+        TotpCheck::Valid(time_step) => last_used.fetch_max(time_step, Ordering::AcqRel) < time_step,
+        TotpCheck::Reused | TotpCheck::Invalid => false,
+    }
+}
+
 #[derive(Serialize)]
 struct AdminTemplateData {
     page_content: String,
@@ -280,7 +401,7 @@ async fn get_user_or_404(uuid: &str, conn: &mut DbConn) -> ApiResult<User> {
 }
 
 #[post("/invite", data = "<data>")]
-async fn invite_user(data: Json<InviteData>, _token: AdminToken, mut conn: DbConn) -> JsonResult {
+async fn invite_user(data: Json<InviteData>, token: AdminToken, mut conn: DbConn) -> JsonResult {
     let data: InviteData = data.into_inner();
     if User::find_by_mail(&data.email, &mut conn).await.is_some() {
         err_code!("User already exists", Status::Conflict.code)
@@ -299,15 +420,17 @@ async fn invite_user(data: Json<InviteData>, _token: AdminToken, mut conn: DbCon
 
     _generate_invite(&user, &mut conn).await.map_err(|e| e.with_code(Status::InternalServerError.code))?;
     user.save(&mut conn).await.map_err(|e| e.with_code(Status::InternalServerError.code))?;
+    token.log_event(EventType::AdminUserInvited, AdminEventTarget::User(&user.uuid), &mut conn).await;
 
     Ok(Json(user.to_json(&mut conn).await))
 }
 
 #[post("/test/smtp", data = "<data>")]
-async fn test_smtp(data: Json<InviteData>, _token: AdminToken) -> EmptyResult {
+async fn test_smtp(data: Json<InviteData>, token: AdminToken, mut conn: DbConn) -> EmptyResult {
     let data: InviteData = data.into_inner();
 
     if CONFIG.mail_enabled() {
+        token.log_event(EventType::AdminSmtpTested, AdminEventTarget::None, &mut conn).await;
         mail::send_test(&data.email).await
     } else {
         err!("Mail is not enabled")
@@ -360,6 +483,25 @@ async fn users_overview(_token: AdminToken, mut conn: DbConn) -> ApiResult<Html<
     Ok(Html(text))
 }
 
+#[get("/users/2fa-backup")]
+async fn get_users_missing_2fa_backup(_token: AdminToken, mut conn: DbConn) -> JsonResult {
+    let mut users_json = Vec::new();
+    for backup in TwoFactorBackup::find_missing_backup(&mut conn).await? {
+        if let Some(user) = User::find_by_uuid(&backup.user_uuid, &mut conn).await {
+            let mut usr = backup.to_json();
+            usr["Id"] = json!(user.uuid);
+            usr["Email"] = json!(user.email);
+            users_json.push(usr);
+        }
+    }
+
+    Ok(Json(json!({
+        "Data": users_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
 #[get("/users/by-mail/<mail>")]
 async fn get_user_by_mail_json(mail: &str, _token: AdminToken, mut conn: DbConn) -> JsonResult {
     if let Some(u) = User::find_by_mail(mail, &mut conn).await {
@@ -381,6 +523,19 @@ async fn get_user_json(uuid: &str, _token: AdminToken, mut conn: DbConn) -> Json
     Ok(Json(usr))
 }
 
+#[get("/users/<uuid>/access-log")]
+async fn get_user_access_log(uuid: &str, _token: AdminToken, mut conn: DbConn) -> JsonResult {
+    let user = get_user_or_404(uuid, &mut conn).await?;
+    let entries: Vec<Value> =
+        AccessLog::find_by_user(&user.uuid, &mut conn).await.iter().map(|e| e.to_json()).collect();
+
+    Ok(Json(json!({
+        "Data": entries,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
 #[post("/users/<uuid>/delete")]
 async fn delete_user(uuid: &str, token: AdminToken, mut conn: DbConn) -> EmptyResult {
     let user = get_user_or_404(uuid, &mut conn).await?;
@@ -388,6 +543,9 @@ async fn delete_user(uuid: &str, token: AdminToken, mut conn: DbConn) -> EmptyRe
     // Get the user_org records before deleting the actual user
     let user_orgs = UserOrganization::find_any_state_by_user(uuid, &mut conn).await;
     let res = user.delete(&mut conn).await;
+    if res.is_ok() {
+        token.log_event(EventType::AdminUserDeleted, AdminEventTarget::User(uuid), &mut conn).await;
+    }
 
     for user_org in user_orgs {
         log_event(
@@ -406,7 +564,7 @@ async fn delete_user(uuid: &str, token: AdminToken, mut conn: DbConn) -> EmptyRe
 }
 
 #[post("/users/<uuid>/deauth")]
-async fn deauth_user(uuid: &str, _token: AdminToken, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
+async fn deauth_user(uuid: &str, token: AdminToken, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
     let mut user = get_user_or_404(uuid, &mut conn).await?;
 
     nt.send_logout(&user, None).await;
@@ -423,17 +581,22 @@ async fn deauth_user(uuid: &str, _token: AdminToken, mut conn: DbConn, nt: Notif
     Device::delete_all_by_user(&user.uuid, &mut conn).await?;
     user.reset_security_stamp();
 
-    user.save(&mut conn).await
+    user.save(&mut conn).await?;
+    token.log_event(EventType::AdminUserDeauthorized, AdminEventTarget::User(&user.uuid), &mut conn).await;
+    Ok(())
 }
 
 #[post("/users/<uuid>/disable")]
-async fn disable_user(uuid: &str, _token: AdminToken, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
+async fn disable_user(uuid: &str, token: AdminToken, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
     let mut user = get_user_or_404(uuid, &mut conn).await?;
     Device::delete_all_by_user(&user.uuid, &mut conn).await?;
     user.reset_security_stamp();
     user.enabled = false;
 
     let save_result = user.save(&mut conn).await;
+    if save_result.is_ok() {
+        token.log_event(EventType::AdminUserDisabled, AdminEventTarget::User(&user.uuid), &mut conn).await;
+    }
 
     nt.send_logout(&user, None).await;
 
@@ -441,24 +604,35 @@ async fn disable_user(uuid: &str, _token: AdminToken, mut conn: DbConn, nt: Noti
 }
 
 #[post("/users/<uuid>/enable")]
-async fn enable_user(uuid: &str, _token: AdminToken, mut conn: DbConn) -> EmptyResult {
+async fn enable_user(uuid: &str, token: AdminToken, mut conn: DbConn) -> EmptyResult {
     let mut user = get_user_or_404(uuid, &mut conn).await?;
     user.enabled = true;
 
-    user.save(&mut conn).await
+    // Enabling the account again counts as activity, so it isn't disabled again right away for inactivity
+    let mut inactivity = AccountInactivity::find_or_default(&user.uuid, &mut conn).await;
+    inactivity.reactivated_at = Some(chrono::Utc::now().naive_utc());
+    inactivity.warned_at = None;
+    inactivity.save(&mut conn).await?;
+
+    user.save(&mut conn).await?;
+    token.log_event(EventType::AdminUserEnabled, AdminEventTarget::User(&user.uuid), &mut conn).await;
+    Ok(())
 }
 
 #[post("/users/<uuid>/remove-2fa")]
 async fn remove_2fa(uuid: &str, token: AdminToken, mut conn: DbConn) -> EmptyResult {
     let mut user = get_user_or_404(uuid, &mut conn).await?;
     TwoFactor::delete_all_by_user(&user.uuid, &mut conn).await?;
+    TwoFactorBackup::reset_recovery(&user.uuid, &mut conn).await?;
     two_factor::enforce_2fa_policy(&user, ACTING_ADMIN_USER, 14, &token.ip.ip, &mut conn).await?;
     user.totp_recover = None;
-    user.save(&mut conn).await
+    user.save(&mut conn).await?;
+    token.log_event(EventType::AdminUser2faRemoved, AdminEventTarget::User(&user.uuid), &mut conn).await;
+    Ok(())
 }
 
 #[post("/users/<uuid>/invite/resend")]
-async fn resend_user_invite(uuid: &str, _token: AdminToken, mut conn: DbConn) -> EmptyResult {
+async fn resend_user_invite(uuid: &str, token: AdminToken, mut conn: DbConn) -> EmptyResult {
     if let Some(user) = User::find_by_uuid(uuid, &mut conn).await {
         //TODO: replace this with user.status check when it will be available (PR#3397)
         if !user.password_hash.is_empty() {
@@ -466,10 +640,10 @@ async fn resend_user_invite(uuid: &str, _token: AdminToken, mut conn: DbConn) ->
         }
 
         if CONFIG.mail_enabled() {
-            mail::send_invite(&user.email, &user.uuid, None, None, &CONFIG.invitation_org_name(), None).await
-        } else {
-            Ok(())
+            mail::send_invite(&user.email, &user.uuid, None, None, &CONFIG.invitation_org_name(), None).await?;
         }
+        token.log_event(EventType::AdminUserInviteResent, AdminEventTarget::User(&user.uuid), &mut conn).await;
+        Ok(())
     } else {
         err_code!("User doesn't exist", Status::NotFound.code);
     }
@@ -506,19 +680,29 @@ async fn update_user_org_type(data: Json<UserOrgTypeData>, token: AdminToken, mu
 
     // This check is also done at api::organizations::{accept_invite(), _confirm_invite, _activate_user(), edit_user()}, update_user_org_type
     // It returns different error messages per function.
+    let in_2fa_grace_period =
+        user_to_edit.in_2fa_grace_period(chrono::Utc::now().naive_utc(), CONFIG.org_2fa_grace_period_days());
     if new_type < UserOrgType::Admin {
         match OrgPolicy::is_user_allowed(&user_to_edit.user_uuid, &user_to_edit.org_uuid, true, &mut conn).await {
             Ok(_) => {}
             Err(OrgPolicyErr::TwoFactorMissing) => {
                 if CONFIG.email_2fa_auto_fallback() {
                     two_factor::email::find_and_activate_email_2fa(&user_to_edit.user_uuid, &mut conn).await?;
-                } else {
+                } else if !in_2fa_grace_period {
                     err!("You cannot modify this user to this type because they have not setup 2FA");
                 }
             }
+            Err(OrgPolicyErr::TwoFactorMethodNotAllowed) => {
+                if !in_2fa_grace_period {
+                    err!("You cannot modify this user to this type because they have not setup a two-step login method this organization accepts");
+                }
+            }
             Err(OrgPolicyErr::SingleOrgEnforced) => {
                 err!("You cannot modify this user to this type because it is a member of an organization which forbids it");
             }
+            Err(OrgPolicyErr::SingleOrgOtherMemberships) => {
+                err!("You cannot modify this user to this type because it is a member of other organizations and this organization forbids it");
+            }
         }
     }
 
@@ -534,12 +718,17 @@ async fn update_user_org_type(data: Json<UserOrgTypeData>, token: AdminToken, mu
     .await;
 
     user_to_edit.atype = new_type;
-    user_to_edit.save(&mut conn).await
+    user_to_edit.save(&mut conn).await?;
+    OrgPolicy::remove_disabled_password_hint(&user_to_edit.user_uuid, &mut conn).await?;
+    token.log_event(EventType::AdminUserOrgTypeChanged, AdminEventTarget::User(&data.user_uuid), &mut conn).await;
+    Ok(())
 }
 
 #[post("/users/update_revision")]
-async fn update_revision_users(_token: AdminToken, mut conn: DbConn) -> EmptyResult {
-    User::update_all_revisions(&mut conn).await
+async fn update_revision_users(token: AdminToken, mut conn: DbConn) -> EmptyResult {
+    User::update_all_revisions(&mut conn).await?;
+    token.log_event(EventType::AdminUserRevisionsUpdated, AdminEventTarget::None, &mut conn).await;
+    Ok(())
 }
 
 #[get("/organizations/overview")]
@@ -549,12 +738,15 @@ async fn organizations_overview(_token: AdminToken, mut conn: DbConn) -> ApiResu
     for o in organizations {
         let mut org = o.to_json();
         org["user_count"] = json!(UserOrganization::count_by_org(&o.uuid, &mut conn).await);
+        org["seat_limit"] = json!(o.seat_limit);
+        org["seats_used"] = json!(UserOrganization::count_seats_by_org(&o.uuid, &mut conn).await);
         org["cipher_count"] = json!(Cipher::count_by_org(&o.uuid, &mut conn).await);
         org["collection_count"] = json!(Collection::count_by_org(&o.uuid, &mut conn).await);
         org["group_count"] = json!(Group::count_by_org(&o.uuid, &mut conn).await);
         org["event_count"] = json!(Event::count_by_org(&o.uuid, &mut conn).await);
         org["attachment_count"] = json!(Attachment::count_by_org(&o.uuid, &mut conn).await);
         org["attachment_size"] = json!(get_display_size(Attachment::size_by_org(&o.uuid, &mut conn).await));
+        org["legal_hold"] = json!(o.legal_hold);
         organizations_json.push(org);
     }
 
@@ -562,10 +754,67 @@ async fn organizations_overview(_token: AdminToken, mut conn: DbConn) -> ApiResu
     Ok(Html(text))
 }
 
+#[post("/organizations/<uuid>/legal-hold/enable")]
+async fn enable_legal_hold(uuid: &str, token: AdminToken, mut conn: DbConn) -> EmptyResult {
+    let mut org = Organization::find_by_uuid(uuid, &mut conn).await.map_res("Organization doesn't exist")?;
+    org.legal_hold = true;
+    org.save(&mut conn).await?;
+    token
+        .log_event(EventType::AdminOrganizationLegalHoldEnabled, AdminEventTarget::Organization(uuid), &mut conn)
+        .await;
+    Ok(())
+}
+
+#[post("/organizations/<uuid>/legal-hold/disable")]
+async fn disable_legal_hold(uuid: &str, token: AdminToken, mut conn: DbConn) -> EmptyResult {
+    let mut org = Organization::find_by_uuid(uuid, &mut conn).await.map_res("Organization doesn't exist")?;
+    org.legal_hold = false;
+    org.save(&mut conn).await?;
+    token
+        .log_event(EventType::AdminOrganizationLegalHoldDisabled, AdminEventTarget::Organization(uuid), &mut conn)
+        .await;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct OrgSeatLimitData {
+    seat_limit: Option<i32>,
+}
+
+/// Only the server admin sets the seat limit, the owners of the organization can't raise it themselves
+#[post("/organizations/<uuid>/seat-limit", data = "<data>")]
+async fn update_org_seat_limit(
+    uuid: &str,
+    data: Json<OrgSeatLimitData>,
+    token: AdminToken,
+    mut conn: DbConn,
+) -> EmptyResult {
+    let seat_limit = data.into_inner().seat_limit;
+    let mut org = Organization::find_by_uuid(uuid, &mut conn).await.map_res("Organization doesn't exist")?;
+    if let Some(seat_limit) = seat_limit {
+        if seat_limit < 0 {
+            err!("The seat limit can't be negative")
+        }
+        let used = UserOrganization::count_seats_by_org(uuid, &mut conn).await;
+        if i64::from(seat_limit) < used {
+            err!(format!("The seat limit can't be lower than the {used} seats currently in use"))
+        }
+    }
+
+    org.seat_limit = seat_limit;
+    org.save(&mut conn).await?;
+    token
+        .log_event(EventType::AdminOrganizationSeatLimitChanged, AdminEventTarget::Organization(uuid), &mut conn)
+        .await;
+    Ok(())
+}
+
 #[post("/organizations/<uuid>/delete")]
-async fn delete_organization(uuid: &str, _token: AdminToken, mut conn: DbConn) -> EmptyResult {
+async fn delete_organization(uuid: &str, token: AdminToken, mut conn: DbConn) -> EmptyResult {
     let org = Organization::find_by_uuid(uuid, &mut conn).await.map_res("Organization doesn't exist")?;
-    org.delete(&mut conn).await
+    org.delete(&mut conn).await?;
+    token.log_event(EventType::AdminOrganizationDeleted, AdminEventTarget::Organization(uuid), &mut conn).await;
+    Ok(())
 }
 
 #[derive(Deserialize)]
@@ -741,27 +990,128 @@ fn get_diagnostics_config(_token: AdminToken) -> Json<Value> {
 }
 
 #[post("/config", data = "<data>")]
-fn post_config(data: Json<ConfigBuilder>, _token: AdminToken) -> EmptyResult {
+async fn post_config(data: Json<ConfigBuilder>, token: AdminToken, mut conn: DbConn) -> EmptyResult {
     let data: ConfigBuilder = data.into_inner();
-    CONFIG.update_config(data)
+    CONFIG.update_config(data)?;
+    token.log_event(EventType::AdminConfigUpdated, AdminEventTarget::None, &mut conn).await;
+    Ok(())
 }
 
 #[post("/config/delete")]
-fn delete_config(_token: AdminToken) -> EmptyResult {
-    CONFIG.delete_user_config()
+async fn delete_config(token: AdminToken, mut conn: DbConn) -> EmptyResult {
+    CONFIG.delete_user_config()?;
+    token.log_event(EventType::AdminConfigDeleted, AdminEventTarget::None, &mut conn).await;
+    Ok(())
 }
 
 #[post("/config/backup_db")]
-async fn backup_db(_token: AdminToken, mut conn: DbConn) -> EmptyResult {
+async fn backup_db(token: AdminToken, mut conn: DbConn) -> EmptyResult {
     if *CAN_BACKUP {
-        backup_database(&mut conn).await
+        backup_database(&mut conn).await?;
+        token.log_event(EventType::AdminDatabaseBackedUp, AdminEventTarget::None, &mut conn).await;
+        Ok(())
     } else {
         err!("Can't back up current DB (Only SQLite supports this feature)");
     }
 }
 
+/// Logs out every user on every device: login tokens and access tokens issued until now are rejected
+/// and all refresh tokens are replaced
+#[post("/tokens/revoke")]
+async fn revoke_all_tokens(token: AdminToken, mut conn: DbConn) -> EmptyResult {
+    let epoch = CONFIG.revoke_all_tokens()?;
+    Device::rotate_all_refresh_tokens(&mut conn).await?;
+    warn!("All login tokens issued until {} have been revoked by an admin", epoch);
+    token.log_event(EventType::AdminTokensRevoked, AdminEventTarget::None, &mut conn).await;
+    Ok(())
+}
+
+/// Replaces the key signing the tokens, the tokens signed by the previous key stay valid during the overlap window
+#[post("/tokens/rotate-key")]
+async fn rotate_jwt_key(token: AdminToken, mut conn: DbConn) -> EmptyResult {
+    crate::auth::rotate_keys().await?;
+    info!("The JWT signing key has been rotated by an admin");
+    token.log_event(EventType::AdminJwtKeyRotated, AdminEventTarget::None, &mut conn).await;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct AnnouncementData {
+    message: String,
+    severity: Option<String>,
+    expiration_date: Option<String>,
+}
+
+/// Replaces the announcement served to the clients, an empty message removes it
+#[post("/announcement", data = "<data>")]
+async fn post_announcement(data: Json<AnnouncementData>, token: AdminToken, mut conn: DbConn) -> EmptyResult {
+    let data = data.into_inner();
+    CONFIG.set_announcement(
+        data.message,
+        data.severity.unwrap_or_else(|| String::from("info")),
+        data.expiration_date,
+    )?;
+    token.log_event(EventType::AdminConfigUpdated, AdminEventTarget::None, &mut conn).await;
+    Ok(())
+}
+
+pub async fn jwt_key_rotation_job() {
+    debug!("Start jwt_key_rotation_job");
+    match crate::auth::rotate_keys().await {
+        Ok(()) => info!("The JWT signing key has been rotated"),
+        Err(e) => error!("Error rotating the JWT signing key: {:#?}", e),
+    }
+}
+
+#[derive(FromForm)]
+struct AdminEventRange {
+    start: Option<String>,
+    end: Option<String>,
+    #[field(name = "continuationToken")]
+    continuation_token: Option<String>,
+}
+
+#[get("/events?<data..>")]
+async fn get_admin_events(data: AdminEventRange, _token: AdminToken, mut conn: DbConn) -> JsonResult {
+    // Without a range the most recent events are returned, the next page ends at the continuation token
+    let end_date = match data.continuation_token.as_deref().or(data.end.as_deref()) {
+        Some(end) => try_parse_date(end),
+        None => Some(chrono::Utc::now().naive_utc()),
+    };
+    let start_date = match data.start.as_deref() {
+        Some(start) => try_parse_date(start),
+        None => Some(chrono::NaiveDateTime::MIN),
+    };
+    let (Some(start_date), Some(end_date)) = (start_date, end_date) else {
+        err!("Invalid date")
+    };
+
+    let events_json: Vec<Value> =
+        Event::find_admin_events(&start_date, &end_date, &mut conn).await.iter().map(|e| e.to_json()).collect();
+
+    Ok(Json(json!({
+        "Data": events_json,
+        "Object": "list",
+        "ContinuationToken": get_continuation_token(&events_json),
+    })))
+}
+
 pub struct AdminToken {
     ip: ClientIp,
+    // Identifies the admin session in the admin events, None when the admin token is disabled
+    session: Option<String>,
+}
+
+impl AdminToken {
+    async fn log_event(&self, event_type: EventType, target: AdminEventTarget<'_>, conn: &mut DbConn) {
+        log_admin_event(event_type, target, self.session.as_deref(), &self.ip.ip, conn).await;
+    }
+}
+
+/// A short identifier of an admin session, derived from its JWT so the token itself isn't stored
+fn admin_session_id(token: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, token.as_bytes());
+    data_encoding::HEXLOWER.encode(&digest.as_ref()[..8])
 }
 
 #[rocket::async_trait]
@@ -777,6 +1127,7 @@ impl<'r> FromRequest<'r> for AdminToken {
         if CONFIG.disable_admin_token() {
             Outcome::Success(Self {
                 ip,
+                session: None,
             })
         } else {
             let cookies = request.cookies();
@@ -805,7 +1156,120 @@ impl<'r> FromRequest<'r> for AdminToken {
 
             Outcome::Success(Self {
                 ip,
+                session: Some(admin_session_id(access_token)),
             })
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token_path() -> String {
+        std::env::temp_dir().join(format!("vaultwarden-break-glass-{}", crate::util::get_uuid())).display().to_string()
+    }
+
+    const TOTP_SECRET: &str = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+
+    fn totp_code(timestamp: i64) -> String {
+        let secret = data_encoding::BASE32.decode(TOTP_SECRET.as_bytes()).unwrap();
+        totp_lite::totp_custom::<totp_lite::Sha1>(30, 6, &secret, timestamp as u64)
+    }
+
+    #[test]
+    #[cfg(sqlite)]
+    fn deleted_user_admin_event_logged() {
+        use crate::db::DbPool;
+        use chrono::{TimeDelta, Utc};
+        use rocket::local::asynchronous::Client;
+
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            // Other tests only get additional events when they run meanwhile
+            CONFIG.set_admin_events_enabled(true);
+
+            let pool = DbPool::sqlite_in_memory();
+            let mut conn = pool.get().await.unwrap();
+            conn.insert_user("user").await;
+            drop(conn);
+
+            let rocket = rocket::build().manage(pool).mount("/admin", routes![delete_user]);
+            let client = Client::untracked(rocket).await.unwrap();
+            let admin_token = crate::auth::test_admin_token();
+
+            let response = client
+                .post("/admin/users/user/delete")
+                .cookie(Cookie::new(COOKIE_NAME, admin_token.clone()))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+
+            let mut conn = client.rocket().state::<DbPool>().unwrap().get().await.unwrap();
+            assert!(User::find_by_uuid("user", &mut conn).await.is_none());
+            let now = Utc::now().naive_utc();
+            let events = Event::find_admin_events(&(now - TimeDelta::try_hours(1).unwrap()), &now, &mut conn).await;
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].event_type, EventType::AdminUserDeleted as i32);
+            assert_eq!(events[0].user_uuid.as_deref(), Some("user"));
+            // The admin session is identified without storing its token
+            assert_eq!(events[0].detail, Some(admin_session_id(&admin_token)));
+        });
+    }
+
+    #[test]
+    fn admin_totp_valid_code_accepted() {
+        let now = chrono::Utc::now().timestamp();
+        let last_used = AtomicI64::new(0);
+        assert!(check_admin_totp(TOTP_SECRET, Some(&totp_code(now)), now, 1, &last_used));
+
+        // A code of the previous time step is accepted with time drift
+        let last_used = AtomicI64::new(0);
+        assert!(check_admin_totp(TOTP_SECRET, Some(&totp_code(now - 30)), now, 1, &last_used));
+    }
+
+    #[test]
+    fn admin_totp_invalid_code_rejected() {
+        let now = chrono::Utc::now().timestamp();
+        let last_used = AtomicI64::new(0);
+        assert!(!check_admin_totp(TOTP_SECRET, None, now, 1, &last_used));
+        assert!(!check_admin_totp(TOTP_SECRET, Some("not-a-code"), now, 1, &last_used));
+        // Expired codes, and codes of a drifted time step when time drift is disabled
+        assert!(!check_admin_totp(TOTP_SECRET, Some(&totp_code(now - 300)), now, 1, &last_used));
+        assert!(!check_admin_totp(TOTP_SECRET, Some(&totp_code(now - 30)), now, 0, &last_used));
+
+        // A code can only be used once
+        assert!(check_admin_totp(TOTP_SECRET, Some(&totp_code(now)), now, 1, &last_used));
+        assert!(!check_admin_totp(TOTP_SECRET, Some(&totp_code(now)), now, 1, &last_used));
+    }
+
+    #[test]
+    fn break_glass_token_used_once() {
+        let path = token_path();
+        let token = create_break_glass_token(&path).unwrap();
+        assert!(!std::fs::read_to_string(&path).unwrap().contains(&token));
+
+        assert!(consume_break_glass_token(&path, &token));
+        assert!(!consume_break_glass_token(&path, &token));
+    }
+
+    #[test]
+    fn break_glass_token_invalid() {
+        let path = token_path();
+        let token = create_break_glass_token(&path).unwrap();
+
+        // A wrong token doesn't invalidate the real one
+        assert!(!consume_break_glass_token(&path, "not-the-token"));
+        assert!(consume_break_glass_token(&path, &token));
+
+        assert!(!consume_break_glass_token(&token_path(), &token));
+    }
+
+    #[test]
+    fn admin_session_id_hides_token() {
+        let session = admin_session_id("eyJ0eXAiOiJKV1QiLCJhbGciOiJSUzI1NiJ9.payload.signature");
+        assert_eq!(session.len(), 16);
+        assert!(!"eyJ0eXAiOiJKV1QiLCJhbGciOiJSUzI1NiJ9.payload.signature".contains(&session));
+        assert_eq!(session, admin_session_id("eyJ0eXAiOiJKV1QiLCJhbGciOiJSUzI1NiJ9.payload.signature"));
+        assert_ne!(session, admin_session_id("eyJ0eXAiOiJKV1QiLCJhbGciOiJSUzI1NiJ9.other.signature"));
+    }
+}
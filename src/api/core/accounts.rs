@@ -1,7 +1,10 @@
 use crate::db::DbPool;
-use chrono::Utc;
+use chrono::{TimeDelta, Utc};
+use dashmap::{mapref::entry::Entry, DashMap};
+use once_cell::sync::Lazy;
 use rocket::serde::json::Json;
 use serde_json::Value;
+use std::time::{Duration, Instant};
 
 use crate::{
     api::{
@@ -9,11 +12,12 @@ use crate::{
         register_push_device, unregister_push_device, AnonymousNotify, EmptyResult, JsonResult, JsonUpcase, Notify,
         PasswordOrOtpData, UpdateType,
     },
-    auth::{decode_delete, decode_invite, decode_verify_email, ClientHeaders, Headers},
+    auth::{decode_invite, decode_verify_email, ClientHeaders, ClientIp, Headers},
+    config::Feature,
     crypto,
-    db::{models::*, DbConn},
+    db::{begin_transaction, finish_transaction, models::*, DbConn},
     mail,
-    util::NumberOrString,
+    util::{self, NumberOrString},
     CONFIG,
 };
 
@@ -59,6 +63,12 @@ pub fn routes() -> Vec<rocket::Route> {
         put_auth_request,
         get_auth_request_response,
         get_auth_requests,
+        get_notification_preferences,
+        put_notification_preferences,
+        get_access_tokens,
+        post_access_token,
+        delete_access_token,
+        get_access_log,
     ]
 }
 
@@ -74,10 +84,13 @@ pub struct RegisterData {
     Keys: Option<KeysData>,
     MasterPasswordHash: String,
     MasterPasswordHint: Option<String>,
+    // zxcvbn score (0-4) of the master password, as calculated by the client
+    MasterPasswordStrength: Option<i32>,
     Name: Option<String>,
     Token: Option<String>,
     #[allow(dead_code)]
     OrganizationUserId: Option<String>,
+    CaptchaResponse: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -104,6 +117,30 @@ fn enforce_password_hint_setting(password_hint: &Option<String>) -> EmptyResult
     }
     Ok(())
 }
+
+/// Enforces the Vaultwarden specific `DisablePasswordHint` policy
+fn enforce_password_hint_policy(password_hint: &Option<String>, disabled_by_policy: bool) -> EmptyResult {
+    if password_hint.is_some() && disabled_by_policy {
+        err!("Due to an Enterprise Policy, you are not allowed to set a master password hint. Remove the hint and try again.");
+    }
+    Ok(())
+}
+
+/// Best-effort check of the strength score reported by the client.
+/// The stock clients don't send a score, so a missing one passes.
+fn check_password_strength(score: Option<i32>, min_strength: u8) -> EmptyResult {
+    match score {
+        Some(score) if score < i32::from(min_strength) => {
+            err!("The master password is too weak. Choose a stronger password and try again.")
+        }
+        _ => Ok(()),
+    }
+}
+
+fn enforce_password_strength_setting(score: Option<i32>) -> EmptyResult {
+    check_password_strength(score, CONFIG.registration_min_password_strength())
+}
+
 async fn is_email_2fa_required(org_user_uuid: Option<String>, conn: &mut DbConn) -> bool {
     if !CONFIG._enable_email_2fa() {
         return false;
@@ -125,7 +162,7 @@ async fn register(data: JsonUpcase<RegisterData>, conn: DbConn) -> JsonResult {
 
 pub async fn _register(data: JsonUpcase<RegisterData>, mut conn: DbConn) -> JsonResult {
     let data: RegisterData = data.into_inner().data;
-    let email = data.Email.to_lowercase();
+    let email = normalize_email(&data.Email);
 
     // Check if the length of the username exceeds 50 characters (Same is Upstream Bitwarden)
     // This also prevents issues with very long usernames causing to large JWT's. See #2419
@@ -135,19 +172,44 @@ pub async fn _register(data: JsonUpcase<RegisterData>, mut conn: DbConn) -> Json
         }
     }
 
+    crate::captcha::check_registration_captcha(data.CaptchaResponse.as_deref()).await?;
+
     // Check against the password hint setting here so if it fails, the user
     // can retry without losing their invitation below.
     let password_hint = clean_password_hint(&data.MasterPasswordHint);
     enforce_password_hint_setting(&password_hint)?;
+    enforce_password_strength_setting(data.MasterPasswordStrength)?;
 
     let mut verified_by_invite = false;
 
     let mut user = match User::find_by_mail(&email, &mut conn).await {
         Some(mut user) => {
             if !user.password_hash.is_empty() {
-                err!("Registration not allowed or user already exists")
+                let response =
+                    existing_account_response(CONFIG.registration_anti_enum(), CONFIG.is_signup_allowed(&email))?;
+                // Hash the password and write the user like a new registration does,
+                // so the response time doesn't reveal the account
+                crypto::hash_password(data.MasterPasswordHash.as_bytes(), &user.salt, user.password_iterations as u32);
+                user.rewrite_unchanged(&mut conn).await?;
+                if CONFIG.mail_enabled()
+                    && should_mail_existing_account(&EXISTING_ACCOUNT_MAILS, &user.email, Instant::now())
+                {
+                    let email = user.email;
+                    tokio::spawn(async move {
+                        if let Err(e) = mail::send_register_existing_account(&email).await {
+                            error!("Error sending existing account registration email: {:#?}", e);
+                        }
+                    });
+                }
+                return Ok(response);
             }
 
+            // Checked before an invitation is used up, so the user can retry without the hint
+            enforce_password_hint_policy(
+                &password_hint,
+                OrgPolicy::is_password_hint_disabled(&user.uuid, &mut conn).await,
+            )?;
+
             if let Some(token) = data.Token {
                 let claims = decode_invite(&token)?;
                 if claims.email == email {
@@ -159,18 +221,29 @@ pub async fn _register(data: JsonUpcase<RegisterData>, mut conn: DbConn) -> Json
                     err!("Registration email does not match invite email")
                 }
             } else if Invitation::take(&email, &mut conn).await {
+                let cutoff =
+                    UserOrganization::invite_expiry_cutoff(Utc::now().naive_utc(), CONFIG.org_invite_expiry_days());
                 for user_org in UserOrganization::find_invited_by_user(&user.uuid, &mut conn).await.iter_mut() {
-                    user_org.status = UserOrgStatus::Accepted as i32;
+                    if user_org.is_invite_expired(cutoff) {
+                        continue;
+                    }
+                    // Like accept_invite, organizations restricted to their own domains keep a mismatched or unverified member invited
+                    let email_verified = user.verified_at.is_some() || !CONFIG.mail_enabled();
+                    match Organization::find_by_uuid(&user_org.org_uuid, &mut conn).await {
+                        Some(org) if org.check_invite_domain(&user.email, email_verified).is_ok() => (),
+                        _ => continue,
+                    }
+                    user_org.accept(Utc::now().naive_utc());
                     user_org.save(&mut conn).await?;
                 }
                 user
             } else if CONFIG.is_signup_allowed(&email)
-                || (CONFIG.emergency_access_allowed()
+                || (CONFIG.is_feature_enabled(Feature::EmergencyAccess)
                     && EmergencyAccess::find_invited_by_grantee_email(&email, &mut conn).await.is_some())
             {
                 user
             } else {
-                err!("Registration not allowed or user already exists")
+                err!(REGISTRATION_NOT_ALLOWED)
             }
         }
         None => {
@@ -180,7 +253,7 @@ pub async fn _register(data: JsonUpcase<RegisterData>, mut conn: DbConn) -> Json
             if Invitation::take(&email, &mut conn).await || CONFIG.is_signup_allowed(&email) {
                 User::new(email.clone())
             } else {
-                err!("Registration not allowed or user already exists")
+                err!(REGISTRATION_NOT_ALLOWED)
             }
         }
     };
@@ -212,34 +285,73 @@ pub async fn _register(data: JsonUpcase<RegisterData>, mut conn: DbConn) -> Json
         user.public_key = Some(keys.PublicKey);
     }
 
+    let must_verify = CONFIG.mail_enabled() && CONFIG.signups_verify() && !verified_by_invite;
+    if must_verify {
+        user.last_verifying_at = Some(user.created_at);
+    }
+    if CONFIG.mail_enabled() && verified_by_invite && is_email_2fa_required(data.OrganizationUserId, &mut conn).await {
+        let _ = email::activate_email_2fa(&user, &mut conn).await;
+    }
+
+    user.save(&mut conn).await?;
+
+    // Sent in the background like the mail to an existing account, so the response time doesn't reveal the account
     if CONFIG.mail_enabled() {
-        if CONFIG.signups_verify() && !verified_by_invite {
-            if let Err(e) = mail::send_welcome_must_verify(&user.email, &user.uuid).await {
+        let (email, uuid) = (user.email.clone(), user.uuid.clone());
+        tokio::spawn(async move {
+            let sent = if must_verify {
+                mail::send_welcome_must_verify(&email, &uuid).await
+            } else {
+                mail::send_welcome(&email).await
+            };
+            if let Err(e) = sent {
                 error!("Error sending welcome email: {:#?}", e);
             }
-            user.last_verifying_at = Some(user.created_at);
-        } else if let Err(e) = mail::send_welcome(&user.email).await {
-            error!("Error sending welcome email: {:#?}", e);
-        }
-
-        if verified_by_invite && is_email_2fa_required(data.OrganizationUserId, &mut conn).await {
-            let _ = email::activate_email_2fa(&user, &mut conn).await;
-        }
+        });
     }
 
-    user.save(&mut conn).await?;
-
     // accept any open emergency access invitations
-    if !CONFIG.mail_enabled() && CONFIG.emergency_access_allowed() {
+    if !CONFIG.mail_enabled() && CONFIG.is_feature_enabled(Feature::EmergencyAccess) {
         for mut emergency_invite in EmergencyAccess::find_all_invited_by_grantee_email(&user.email, &mut conn).await {
             let _ = emergency_invite.accept_invite(&user.uuid, &user.email, &mut conn).await;
         }
     }
 
-    Ok(Json(json!({
+    Ok(register_response())
+}
+
+fn register_response() -> Json<Value> {
+    Json(json!({
       "Object": "register",
       "CaptchaBypassToken": "",
-    })))
+    }))
+}
+
+const REGISTRATION_NOT_ALLOWED: &str = "Registration not allowed or user already exists";
+
+/// The response to registering an already registered email. With `REGISTRATION_ANTI_ENUM` it can't be told apart
+/// from registering a new email: it succeeds when a new signup with that email would, and fails the same way otherwise.
+fn existing_account_response(anti_enum: bool, signup_allowed: bool) -> JsonResult {
+    if !anti_enum || !signup_allowed {
+        err!(REGISTRATION_NOT_ALLOWED)
+    }
+    Ok(register_response())
+}
+
+// When the owners of existing accounts were last notified of a registration with their email
+static EXISTING_ACCOUNT_MAILS: Lazy<DashMap<String, Instant>> = Lazy::new(DashMap::new);
+const EXISTING_ACCOUNT_MAIL_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The owner of an existing account is notified at most once per interval, so registrations can't flood the mailbox
+fn should_mail_existing_account(sent: &DashMap<String, Instant>, email: &str, now: Instant) -> bool {
+    sent.retain(|_, sent_at| now.saturating_duration_since(*sent_at) < EXISTING_ACCOUNT_MAIL_INTERVAL);
+    match sent.entry(email.to_string()) {
+        Entry::Occupied(_) => false,
+        Entry::Vacant(entry) => {
+            entry.insert(now);
+            true
+        }
+    }
 }
 
 #[get("/accounts/profile")]
@@ -359,8 +471,16 @@ async fn post_password(
         err!("Invalid password")
     }
 
+    if let Some(interval_hours) = CONFIG.min_password_change_interval() {
+        user.check_password_change_interval(Utc::now().naive_utc(), interval_hours)?;
+    }
+
     user.password_hint = clean_password_hint(&data.MasterPasswordHint);
     enforce_password_hint_setting(&user.password_hint)?;
+    enforce_password_hint_policy(
+        &user.password_hint,
+        OrgPolicy::is_password_hint_disabled(&user.uuid, &mut conn).await,
+    )?;
 
     log_user_event(EventType::UserChangedPassword as i32, &user.uuid, headers.device.atype, &headers.ip.ip, &mut conn)
         .await;
@@ -371,6 +491,7 @@ async fn post_password(
         true,
         Some(vec![String::from("post_rotatekey"), String::from("get_contacts"), String::from("get_public_keys")]),
     );
+    user.mark_password_changed();
 
     let save_result = user.save(&mut conn).await;
 
@@ -404,6 +525,11 @@ async fn post_kdf(data: JsonUpcase<ChangeKdfData>, headers: Headers, mut conn: D
         err!("Invalid password")
     }
 
+    // Changing the KDF also replaces the master password hash and key, like a password change
+    if let Some(interval_hours) = CONFIG.min_password_change_interval() {
+        user.check_password_change_interval(Utc::now().naive_utc(), interval_hours)?;
+    }
+
     if data.Kdf == UserKdfType::Pbkdf2 as i32 && data.KdfIterations < 100_000 {
         err!("PBKDF2 KDF iterations must be at least 100000.")
     }
@@ -435,6 +561,7 @@ async fn post_kdf(data: JsonUpcase<ChangeKdfData>, headers: Headers, mut conn: D
     user.client_kdf_iter = data.KdfIterations;
     user.client_kdf_type = data.Kdf;
     user.set_password(&data.NewMasterPasswordHash, Some(data.Key), true, None);
+    user.mark_password_changed();
     let save_result = user.save(&mut conn).await;
 
     nt.send_logout(&user, Some(headers.device.uuid)).await;
@@ -484,13 +611,15 @@ struct KeyData {
 
 #[post("/accounts/key", data = "<data>")]
 async fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
-    // TODO: See if we can wrap everything within a SQL Transaction. If something fails it should revert everything.
-    let data: KeyData = data.into_inner().data;
+    let mut data: KeyData = data.into_inner().data;
 
     if !headers.user.check_valid_password(&data.MasterPasswordHash) {
         err!("Invalid password")
     }
 
+    // A key rotation keeps the master password, so `MIN_PASSWORD_CHANGE_INTERVAL` doesn't apply.
+    // The clients also rotate the keys right after changing the password, which has to keep working.
+
     // Validate the import before continuing
     // Bitwarden does not process the import if there is one item invalid.
     // Since we check for the size of the encrypted note length, we need to do that here to pre-validate it.
@@ -499,12 +628,51 @@ async fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, mut conn: D
 
     let user_uuid = &headers.user.uuid;
 
+    // Attachments left out of the rotation can't be decrypted anymore afterwards, so check them before saving anything
+    if CONFIG.key_rotation_require_attachments() {
+        let attachments = Attachment::find_all_by_user(user_uuid, &mut conn).await;
+        super::ciphers::validate_rotated_attachments(&data.Ciphers, &attachments)?;
+    }
+
+    // Either all data is rotated or none, a partial rotation would leave data which can't be decrypted anymore
+    let key = std::mem::take(&mut data.Key);
+    let private_key = std::mem::take(&mut data.PrivateKey);
+
+    begin_transaction(&mut conn).await?;
+    let result = rotate_user_data(data, &headers, &mut conn, &nt).await;
+
+    // Update user data
+    let mut user = headers.user;
+
+    user.akey = key;
+    user.mark_key_rotated();
+    user.private_key = Some(private_key);
+    user.reset_security_stamp();
+
+    let save_result = match result {
+        Ok(()) => user.save(&mut conn).await,
+        Err(e) => Err(e),
+    };
+    finish_transaction(save_result, &mut conn).await?;
+
+    // Prevent logging out the client where the user requested this endpoint from.
+    // If you do logout the user it will causes issues at the client side.
+    // Adding the device uuid will prevent this.
+    nt.send_logout(&user, Some(headers.device.uuid)).await;
+
+    Ok(())
+}
+
+/// Saves the re-encrypted folders, emergency accesses, reset password keys, sends and ciphers of a key rotation
+async fn rotate_user_data(data: KeyData, headers: &Headers, conn: &mut DbConn, nt: &Notify<'_>) -> EmptyResult {
+    let user_uuid = &headers.user.uuid;
+
     // Update folder data
     for folder_data in data.Folders {
         // Skip `null` folder id entries.
         // See: https://github.com/bitwarden/clients/issues/8453
         if let Some(folder_id) = folder_data.Id {
-            let mut saved_folder = match Folder::find_by_uuid(&folder_id, &mut conn).await {
+            let mut saved_folder = match Folder::find_by_uuid(&folder_id, conn).await {
                 Some(folder) => folder,
                 None => err!("Folder doesn't exist"),
             };
@@ -514,14 +682,13 @@ async fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, mut conn: D
             }
 
             saved_folder.name = folder_data.Name;
-            saved_folder.save(&mut conn).await?
+            saved_folder.save(conn).await?
         }
     }
 
     // Update emergency access data
     for emergency_access_data in data.EmergencyAccessKeys {
-        let mut saved_emergency_access = match EmergencyAccess::find_by_uuid(&emergency_access_data.Id, &mut conn).await
-        {
+        let mut saved_emergency_access = match EmergencyAccess::find_by_uuid(&emergency_access_data.Id, conn).await {
             Some(emergency_access) => emergency_access,
             None => err!("Emergency access doesn't exist"),
         };
@@ -531,31 +698,29 @@ async fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, mut conn: D
         }
 
         saved_emergency_access.key_encrypted = Some(emergency_access_data.KeyEncrypted);
-        saved_emergency_access.save(&mut conn).await?
+        saved_emergency_access.save(conn).await?
     }
 
     // Update reset password data
     for reset_password_data in data.ResetPasswordKeys {
         let mut user_org =
-            match UserOrganization::find_by_user_and_org(user_uuid, &reset_password_data.OrganizationId, &mut conn)
-                .await
-            {
+            match UserOrganization::find_by_user_and_org(user_uuid, &reset_password_data.OrganizationId, conn).await {
                 Some(reset_password) => reset_password,
                 None => err!("Reset password doesn't exist"),
             };
 
         user_org.reset_password_key = Some(reset_password_data.ResetPasswordKey);
-        user_org.save(&mut conn).await?
+        user_org.save(conn).await?
     }
 
     // Update send data
     for send_data in data.Sends {
-        let mut send = match Send::find_by_uuid(send_data.Id.as_ref().unwrap(), &mut conn).await {
+        let mut send = match Send::find_by_uuid(send_data.Id.as_ref().unwrap(), conn).await {
             Some(send) => send,
             None => err!("Send doesn't exist"),
         };
 
-        update_send_from_data(&mut send, send_data, &headers, &mut conn, &nt, UpdateType::None).await?;
+        update_send_from_data(&mut send, send_data, headers, conn, nt, UpdateType::None).await?;
     }
 
     // Update cipher data
@@ -563,7 +728,7 @@ async fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, mut conn: D
 
     for cipher_data in data.Ciphers {
         if cipher_data.OrganizationId.is_none() {
-            let mut saved_cipher = match Cipher::find_by_uuid(cipher_data.Id.as_ref().unwrap(), &mut conn).await {
+            let mut saved_cipher = match Cipher::find_by_uuid(cipher_data.Id.as_ref().unwrap(), conn).await {
                 Some(cipher) => cipher,
                 None => err!("Cipher doesn't exist"),
             };
@@ -575,26 +740,11 @@ async fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, mut conn: D
             // Prevent triggering cipher updates via WebSockets by settings UpdateType::None
             // The user sessions are invalidated because all the ciphers were re-encrypted and thus triggering an update could cause issues.
             // We force the users to logout after the user has been saved to try and prevent these issues.
-            update_cipher_from_data(&mut saved_cipher, cipher_data, &headers, None, &mut conn, &nt, UpdateType::None)
-                .await?
+            update_cipher_from_data(&mut saved_cipher, cipher_data, headers, None, conn, nt, UpdateType::None).await?
         }
     }
 
-    // Update user data
-    let mut user = headers.user;
-
-    user.akey = data.Key;
-    user.private_key = Some(data.PrivateKey);
-    user.reset_security_stamp();
-
-    let save_result = user.save(&mut conn).await;
-
-    // Prevent logging out the client where the user requested this endpoint from.
-    // If you do logout the user it will causes issues at the client side.
-    // Adding the device uuid will prevent this.
-    nt.send_logout(&user, Some(headers.device.uuid)).await;
-
-    save_result
+    Ok(())
 }
 
 #[post("/accounts/security-stamp", data = "<data>")]
@@ -610,6 +760,8 @@ async fn post_sstamp(
     data.validate(&user, true, &mut conn).await?;
 
     Device::delete_all_by_user(&user.uuid, &mut conn).await?;
+    // Deauthorizing all sessions also revokes the access tokens
+    PersonalAccessToken::delete_all_by_user(&user.uuid, &mut conn).await?;
     user.reset_security_stamp();
     let save_result = user.save(&mut conn).await;
 
@@ -632,31 +784,32 @@ async fn post_email_token(data: JsonUpcase<EmailTokenData>, headers: Headers, mu
     }
 
     let data: EmailTokenData = data.into_inner().data;
+    let new_email = normalize_email(&data.NewEmail);
     let mut user = headers.user;
 
     if !user.check_valid_password(&data.MasterPasswordHash) {
         err!("Invalid password")
     }
 
-    if User::find_by_mail(&data.NewEmail, &mut conn).await.is_some() {
+    if User::find_by_mail(&new_email, &mut conn).await.is_some() {
         err!("Email already in use");
     }
 
-    if !CONFIG.is_email_domain_allowed(&data.NewEmail) {
+    if !CONFIG.is_email_domain_allowed(&new_email) {
         err!("Email domain not allowed");
     }
 
     let token = crypto::generate_email_token(6);
 
     if CONFIG.mail_enabled() {
-        if let Err(e) = mail::send_change_email(&data.NewEmail, &token).await {
+        if let Err(e) = mail::send_change_email(&new_email, &token).await {
             error!("Error sending change-email email: {:#?}", e);
         }
     } else {
-        debug!("Email change request for user ({}) to email ({}) with token ({})", user.uuid, data.NewEmail, token);
+        debug!("Email change request for user ({}) to email ({}) with token ({})", user.uuid, new_email, token);
     }
 
-    user.email_new = Some(data.NewEmail);
+    user.email_new = Some(new_email);
     user.email_new_token = Some(token);
     user.save(&mut conn).await
 }
@@ -684,19 +837,20 @@ async fn post_email(
     }
 
     let data: ChangeEmailData = data.into_inner().data;
+    let new_email = normalize_email(&data.NewEmail);
     let mut user = headers.user;
 
     if !user.check_valid_password(&data.MasterPasswordHash) {
         err!("Invalid password")
     }
 
-    if User::find_by_mail(&data.NewEmail, &mut conn).await.is_some() {
+    if User::find_by_mail(&new_email, &mut conn).await.is_some() {
         err!("Email already in use");
     }
 
     match user.email_new {
         Some(ref val) => {
-            if val != &data.NewEmail {
+            if val != &new_email {
                 err!("Email change mismatch");
             }
         }
@@ -718,7 +872,7 @@ async fn post_email(
         user.verified_at = None;
     }
 
-    user.email = data.NewEmail;
+    user.email = new_email;
     user.email_new = None;
     user.email_new_token = None;
 
@@ -791,7 +945,11 @@ async fn post_delete_recover(data: JsonUpcase<DeleteRecoverData>, mut conn: DbCo
 
     if CONFIG.mail_enabled() {
         if let Some(user) = User::find_by_mail(&data.Email, &mut conn).await {
-            if let Err(e) = mail::send_delete_account(&user.email, &user.uuid).await {
+            // The lifetime is validated to be at most 7 days
+            let ttl = TimeDelta::try_seconds(CONFIG.password_reset_token_ttl() as i64).unwrap();
+            let (recovery_token, token) = AccountRecoveryToken::new(&user, ttl);
+            recovery_token.save(&mut conn).await?;
+            if let Err(e) = mail::send_delete_account(&user.email, &user.uuid, &token).await {
                 error!("Error sending delete account email: {:#?}", e);
             }
         }
@@ -813,7 +971,12 @@ struct DeleteRecoverTokenData {
 }
 
 #[post("/accounts/delete-recover-token", data = "<data>")]
-async fn post_delete_recover_token(data: JsonUpcase<DeleteRecoverTokenData>, mut conn: DbConn) -> EmptyResult {
+async fn post_delete_recover_token(
+    data: JsonUpcase<DeleteRecoverTokenData>,
+    ip: ClientIp,
+    mut conn: DbConn,
+) -> EmptyResult {
+    crate::ratelimit::check_limit_login(&ip.ip)?;
     let data: DeleteRecoverTokenData = data.into_inner().data;
 
     let user = match User::find_by_uuid(&data.UserId, &mut conn).await {
@@ -821,12 +984,9 @@ async fn post_delete_recover_token(data: JsonUpcase<DeleteRecoverTokenData>, mut
         None => err!("User doesn't exist"),
     };
 
-    let claims = match decode_delete(&data.Token) {
-        Ok(claims) => claims,
-        Err(_) => err!("Invalid claim"),
-    };
-    if claims.sub != user.uuid {
-        err!("Invalid claim");
+    // A valid token is used up, wrong guesses are counted against it
+    if AccountRecoveryToken::take_valid(&user, &data.Token, &Utc::now().naive_utc(), &mut conn).await.is_none() {
+        err!("Invalid or expired token, please request a new one")
     }
     user.delete(&mut conn).await
 }
@@ -852,6 +1012,47 @@ fn revision_date(headers: Headers) -> JsonResult {
     Ok(Json(json!(revision_date)))
 }
 
+#[get("/accounts/notification-preferences")]
+async fn get_notification_preferences(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let prefs = NotificationPreference::find_or_default(&headers.user.uuid, &mut conn).await;
+    Json(prefs.to_json())
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct NotificationPreferencesData {
+    NewDeviceLogin: Option<bool>,
+    Incomplete2faLogin: Option<bool>,
+    EmergencyAccess: Option<bool>,
+    KeyRotationReminder: Option<bool>,
+}
+
+#[put("/accounts/notification-preferences", data = "<data>")]
+async fn put_notification_preferences(
+    data: JsonUpcase<NotificationPreferencesData>,
+    headers: Headers,
+    mut conn: DbConn,
+) -> JsonResult {
+    let data: NotificationPreferencesData = data.into_inner().data;
+
+    let mut prefs = NotificationPreference::find_or_default(&headers.user.uuid, &mut conn).await;
+    if let Some(new_device_login) = data.NewDeviceLogin {
+        prefs.new_device_login = new_device_login;
+    }
+    if let Some(incomplete_2fa_login) = data.Incomplete2faLogin {
+        prefs.incomplete_2fa_login = incomplete_2fa_login;
+    }
+    if let Some(emergency_access) = data.EmergencyAccess {
+        prefs.emergency_access = emergency_access;
+    }
+    if let Some(key_rotation_reminder) = data.KeyRotationReminder {
+        prefs.key_rotation_reminder = key_rotation_reminder;
+    }
+    prefs.save(&mut conn).await?;
+
+    Ok(Json(prefs.to_json()))
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct PasswordHintData {
@@ -978,6 +1179,85 @@ async fn rotate_api_key(data: JsonUpcase<PasswordOrOtpData>, headers: Headers, c
     _api_key(data, true, headers, conn).await
 }
 
+#[get("/accounts/access-tokens")]
+async fn get_access_tokens(headers: Headers, mut conn: DbConn) -> JsonResult {
+    let tokens_json: Vec<Value> =
+        PersonalAccessToken::find_by_user(&headers.user.uuid, &mut conn).await.iter().map(|t| t.to_json()).collect();
+
+    Ok(Json(json!({
+        "Data": tokens_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+#[get("/accounts/access-log")]
+async fn get_access_log(headers: Headers, mut conn: DbConn) -> JsonResult {
+    if !CONFIG.access_log_enabled() {
+        err!("Access logging is not enabled")
+    }
+
+    let entries_json: Vec<Value> =
+        AccessLog::find_by_user(&headers.user.uuid, &mut conn).await.iter().map(|e| e.to_json()).collect();
+
+    Ok(Json(json!({
+        "Data": entries_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct AccessTokenData {
+    Name: String,
+    ReadOnly: Option<bool>,
+    ExpirationDays: Option<i64>,
+    MasterPasswordHash: Option<String>,
+    Otp: Option<String>,
+}
+
+#[post("/accounts/access-tokens", data = "<data>")]
+async fn post_access_token(data: JsonUpcase<AccessTokenData>, headers: Headers, mut conn: DbConn) -> JsonResult {
+    let data: AccessTokenData = data.into_inner().data;
+    PasswordOrOtpData {
+        MasterPasswordHash: data.MasterPasswordHash,
+        Otp: data.Otp,
+    }
+    .validate(&headers.user, true, &mut conn)
+    .await?;
+
+    if data.Name.trim().is_empty() {
+        err!("The access token needs a name")
+    }
+
+    let expires_at = match data.ExpirationDays {
+        Some(days) => match TimeDelta::try_days(days) {
+            Some(delta) if days > 0 => Some(Utc::now().naive_utc() + delta),
+            _ => err!("Invalid expiration"),
+        },
+        None => None,
+    };
+
+    // Tokens are read-only unless requested otherwise
+    let (pat, token) = PersonalAccessToken::new(&headers.user, data.Name, data.ReadOnly.unwrap_or(true), expires_at);
+    pat.save(&mut conn).await?;
+    pat.new_device().save(&mut conn).await?;
+
+    // The token is only returned once, only its hash is stored
+    let mut token_json = pat.to_json();
+    token_json["Token"] = json!(token);
+    Ok(Json(token_json))
+}
+
+#[delete("/accounts/access-tokens/<token_id>")]
+async fn delete_access_token(token_id: &str, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    match PersonalAccessToken::find_by_uuid_and_user(token_id, &headers.user.uuid, &mut conn).await {
+        Some(pat) => pat.delete(&mut conn).await,
+        None => err!("Access token not found"),
+    }
+}
+
 #[get("/devices/knowndevice")]
 async fn get_known_device(device: KnownDevice, mut conn: DbConn) -> JsonResult {
     let mut result = false;
@@ -1126,7 +1406,7 @@ async fn post_auth_request(
         user.uuid.clone(),
         data.deviceIdentifier.clone(),
         headers.device_type,
-        headers.ip.ip.to_string(),
+        util::stored_ip(&headers.ip.ip),
         data.accessCode,
         data.publicKey,
     );
@@ -1302,3 +1582,269 @@ pub async fn purge_auth_requests(pool: DbPool) {
         error!("Failed to get DB connection while purging trashed ciphers")
     }
 }
+
+pub async fn key_rotation_reminder_job(pool: DbPool) {
+    debug!("Start key_rotation_reminder_job");
+    let Some(reminder_days) = CONFIG.key_rotation_reminder_days() else {
+        return;
+    };
+    if !CONFIG.mail_enabled() {
+        return;
+    }
+
+    let Ok(mut conn) = pool.get().await else {
+        error!("Failed to get DB connection while sending key rotation reminders");
+        return;
+    };
+
+    let now = Utc::now().naive_utc();
+    let cutoff = now - TimeDelta::try_days(reminder_days).unwrap_or_default();
+    for user in User::find_key_rotation_overdue(&cutoff, &mut conn).await {
+        // Invited users without an account don't have a master password yet
+        if user.password_hash.is_empty() {
+            continue;
+        }
+        let mut prefs = NotificationPreference::find_or_default(&user.uuid, &mut conn).await;
+        if !prefs.allows(UserNotification::KeyRotationReminder)
+            || !user.is_key_rotation_reminder_due(prefs.key_rotation_reminded_at, now, reminder_days)
+        {
+            continue;
+        }
+
+        if let Err(e) = mail::send_key_rotation_reminder(&user.email, reminder_days).await {
+            error!("Error sending key rotation reminder to {}: {e:#?}", user.email);
+            continue;
+        }
+        prefs.key_rotation_reminded_at = Some(now);
+        if let Err(e) = prefs.save(&mut conn).await {
+            error!("Error saving key rotation reminder date for {}: {e:#?}", user.email);
+        }
+    }
+}
+
+pub async fn access_log_cleanup_job(pool: DbPool) {
+    debug!("Start access_log_cleanup_job");
+    // Also runs with access logging disabled, so entries from before it was turned off still expire
+    let Ok(mut conn) = pool.get().await else {
+        error!("Failed to get DB connection while cleaning the access log");
+        return;
+    };
+
+    let cutoff = Utc::now().naive_utc() - TimeDelta::try_days(CONFIG.access_log_days_retain()).unwrap_or_default();
+    AccessLog::delete_older_than(&cutoff, &mut conn).await.ok();
+}
+
+pub async fn inactive_account_disable_job(pool: DbPool) {
+    debug!("Start inactive_account_disable_job");
+    let Some(disable_days) = CONFIG.inactive_account_disable_days() else {
+        return;
+    };
+    // Without email the users can't be warned beforehand
+    let warning_days = match CONFIG.inactive_account_warning_days() {
+        days if days > 0 && CONFIG.mail_enabled() => Some(days),
+        _ => None,
+    };
+
+    let Ok(mut conn) = pool.get().await else {
+        error!("Failed to get DB connection while disabling inactive accounts");
+        return;
+    };
+
+    let now = Utc::now().naive_utc();
+    for mut user in User::get_all(&mut conn).await {
+        // Invited users without an account can't log in anyway
+        if !user.enabled || user.password_hash.is_empty() {
+            continue;
+        }
+
+        let mut inactivity = AccountInactivity::find_or_default(&user.uuid, &mut conn).await;
+        let activity = user.last_active(&mut conn).await.map_or(user.created_at, |active| active.max(user.created_at));
+        let last_activity = inactivity.last_activity(activity);
+
+        match inactivity_action(last_activity, inactivity.warned_at, now, disable_days, warning_days) {
+            InactivityAction::None => (),
+            InactivityAction::Warn => {
+                // Both the inactivity and the warning period have to pass, the days are validated in the config
+                let disable_at = (last_activity + TimeDelta::try_days(disable_days).unwrap())
+                    .max(now + TimeDelta::try_days(warning_days.unwrap_or_default()).unwrap());
+                let disable_date = disable_at.format("%Y-%m-%d").to_string();
+                // The warning is sent once the account was inactive for `disable_days - warning_days`, or later
+                // when the job didn't run or the settings changed, so it states the actual inactive days
+                let inactive_days = (now - last_activity).num_days();
+                if let Err(e) = mail::send_inactive_account_warning(&user.email, inactive_days, &disable_date).await {
+                    error!("Error sending inactive account warning to {}: {e:#?}", user.email);
+                    continue;
+                }
+                inactivity.warned_at = Some(now);
+                if let Err(e) = inactivity.save(&mut conn).await {
+                    error!("Error saving inactive account warning date for {}: {e:#?}", user.email);
+                }
+            }
+            InactivityAction::Disable => {
+                info!("Disabling account {} after {disable_days} days of inactivity", user.email);
+                if let Err(e) = disable_inactive_account(&mut user, &mut conn).await {
+                    error!("Error disabling inactive account {}: {e:#?}", user.email);
+                }
+            }
+        }
+    }
+}
+
+/// Disables the account and removes its devices, so the sessions on them can't be refreshed anymore
+pub async fn disable_inactive_account(user: &mut User, conn: &mut DbConn) -> EmptyResult {
+    Device::delete_all_by_user(&user.uuid, conn).await?;
+    user.reset_security_stamp();
+    user.enabled = false;
+    user.save(conn).await
+}
+
+pub async fn unverified_account_purge_job(pool: DbPool) {
+    debug!("Start unverified_account_purge_job");
+    // Without email the users can't verify their address, nor be reminded.
+    // The verification date is only kept up to date when verification is required.
+    let Some(purge_days) =
+        CONFIG.unverified_account_purge_days().filter(|_| CONFIG.mail_enabled() && CONFIG.signups_verify())
+    else {
+        return;
+    };
+    let reminder_days = CONFIG.unverified_account_reminder_days();
+
+    let Ok(mut conn) = pool.get().await else {
+        error!("Failed to get DB connection while purging unverified accounts");
+        return;
+    };
+
+    let now = Utc::now().naive_utc();
+    for mut user in User::get_all(&mut conn).await {
+        // Invited users without an account have nothing to verify
+        if user.password_hash.is_empty() {
+            continue;
+        }
+
+        match user.unverified_account_action(true, now, purge_days, reminder_days) {
+            UnverifiedAccountAction::None => (),
+            UnverifiedAccountAction::Remind => {
+                // Organization members, including pending invitations, are kept
+                if !UserOrganization::find_any_state_by_user(&user.uuid, &mut conn).await.is_empty() {
+                    continue;
+                }
+                // Both the purge and the reminder period have to pass, the days are validated in the config
+                let purge_at = (user.created_at + TimeDelta::try_days(purge_days).unwrap())
+                    .max(now + TimeDelta::try_days(reminder_days).unwrap());
+                let purge_date = purge_at.format("%Y-%m-%d").to_string();
+                if let Err(e) = mail::send_unverified_account_reminder(&user.email, &user.uuid, &purge_date).await {
+                    error!("Error sending unverified account reminder to {}: {e:#?}", user.email);
+                    continue;
+                }
+                user.last_verifying_at = Some(now);
+                user.unverified_reminder_at = Some(now);
+                if let Err(e) = user.save(&mut conn).await {
+                    error!("Error saving unverified account reminder date for {}: {e:#?}", user.email);
+                }
+            }
+            UnverifiedAccountAction::Purge => {
+                if !UserOrganization::find_any_state_by_user(&user.uuid, &mut conn).await.is_empty() {
+                    continue;
+                }
+                info!("Deleting account {} which wasn't verified within {purge_days} days", user.email);
+                if let Err(e) = user.delete(&mut conn).await {
+                    error!("Error deleting unverified account: {e:#?}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(sqlite)]
+    fn authenticated_request_access_logged() {
+        use rocket::{http::Header, local::asynchronous::Client};
+
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            // Other tests only get additional entries when they run meanwhile
+            CONFIG.set_access_log_enabled(true);
+
+            let pool = DbPool::sqlite_in_memory();
+            let mut conn = pool.get().await.unwrap();
+            conn.insert_user("user").await;
+            let authorization = Headers::test_authorization("user", &mut conn).await;
+            drop(conn);
+
+            let rocket = rocket::build()
+                .manage(pool)
+                .manage(std::sync::Arc::clone(&crate::api::WS_USERS))
+                .mount("/api", routes![get_access_log]);
+            let client = Client::untracked(rocket).await.unwrap();
+
+            let response = client
+                .get("/api/accounts/access-log?filter=secret")
+                .header(authorization)
+                .header(Header::new("Bitwarden-Client-Name", "web"))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+            // The request is logged by the Headers guard before the route reads the log
+            let body: Value = response.into_json().await.unwrap();
+            assert_eq!(body["Data"].as_array().unwrap().len(), 1);
+            assert_eq!(body["Data"][0]["Method"], "GET");
+            assert_eq!(body["Data"][0]["Endpoint"], "/api/accounts/access-log");
+            assert_eq!(body["Data"][0]["ClientName"], "web");
+
+            let mut conn = client.rocket().state::<DbPool>().unwrap().get().await.unwrap();
+            assert_eq!(AccessLog::find_by_user("user", &mut conn).await.len(), 1);
+        });
+    }
+
+    #[test]
+    fn password_strength_below_threshold_rejected() {
+        assert!(check_password_strength(Some(1), 3).is_err());
+        assert!(check_password_strength(Some(0), 1).is_err());
+    }
+
+    #[test]
+    fn password_strength_accepted() {
+        assert!(check_password_strength(Some(3), 3).is_ok());
+        assert!(check_password_strength(Some(4), 3).is_ok());
+        // Clients which don't report a score can still register
+        assert!(check_password_strength(None, 2).is_ok());
+        // Disabled, so a missing or weak score doesn't matter
+        assert!(check_password_strength(None, 0).is_ok());
+        assert!(check_password_strength(Some(0), 0).is_ok());
+    }
+
+    #[test]
+    fn existing_email_registration_indistinguishable() {
+        // Signups are open, a new email registers successfully, so does an existing one
+        let existing = existing_account_response(true, true).unwrap().into_inner();
+        assert_eq!(existing, register_response().into_inner());
+
+        // Signups are closed for this email, a new one is refused, so is an existing one with the same error
+        let existing = existing_account_response(true, false).unwrap_err();
+        assert!(existing.to_string().contains(REGISTRATION_NOT_ALLOWED));
+
+        assert!(existing_account_response(false, true).is_err());
+    }
+
+    #[test]
+    fn existing_account_mail_throttled() {
+        let sent = DashMap::new();
+        let now = Instant::now();
+        assert!(should_mail_existing_account(&sent, "user@example.com", now));
+        assert!(!should_mail_existing_account(&sent, "user@example.com", now + Duration::from_secs(60)));
+        assert!(should_mail_existing_account(&sent, "other@example.com", now));
+        assert!(should_mail_existing_account(&sent, "user@example.com", now + EXISTING_ACCOUNT_MAIL_INTERVAL));
+    }
+
+    #[test]
+    fn password_hint_rejected_by_policy() {
+        let hint = clean_password_hint(&Some(String::from("my dog's name")));
+        assert!(enforce_password_hint_policy(&hint, true).is_err());
+        assert!(enforce_password_hint_policy(&hint, false).is_ok());
+        // Members subject to the policy can still register or change their password without a hint
+        assert!(enforce_password_hint_policy(&clean_password_hint(&Some(String::from("  "))), true).is_ok());
+    }
+}
@@ -1,25 +1,34 @@
 use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
 use chrono::{NaiveDateTime, Utc};
 use num_traits::ToPrimitive;
 use rocket::fs::TempFile;
 use rocket::serde::json::Json;
 use rocket::{
-    form::{Form, FromForm},
+    data::{Data, ToByteUnit},
+    form::FromForm,
+    request::{FromRequest, Outcome, Request},
     Route,
 };
 use serde_json::Value;
+use tokio::io::AsyncReadExt;
 
-use crate::util::NumberOrString;
+use crate::util::{NumberOrString, UpCase};
 use crate::{
-    api::{self, core::log_event, EmptyResult, JsonResult, JsonUpcase, Notify, PasswordOrOtpData, UpdateType},
+    api::{
+        self, core::log_event, ApiResult, EmptyResult, JsonResult, JsonUpcase, MultipartForm, Notify,
+        PasswordOrOtpData, UpdateType,
+    },
     auth::Headers,
+    config::Feature,
     crypto,
-    db::{models::*, DbConn, DbPool},
+    db::{begin_transaction, finish_transaction, models::*, DbConn, DbPool, DbReadConn},
     CONFIG,
 };
 
-use super::folders::FolderData;
+use super::folders::{check_folder_name, enforce_folder_count, FolderData};
 
 pub fn routes() -> Vec<Route> {
     // Note that many routes have an `admin` variant; this seems to be
@@ -43,6 +52,7 @@ pub fn routes() -> Vec<Route> {
         post_ciphers_admin,
         post_ciphers_create,
         post_ciphers_import,
+        post_ciphers_import_file,
         get_attachment,
         post_attachment_v2,
         post_attachment_v2_data,
@@ -57,6 +67,10 @@ pub fn routes() -> Vec<Route> {
         post_cipher_share,
         put_cipher_share,
         put_cipher_share_selected,
+        get_cipher_transfers,
+        get_cipher_user_shares,
+        post_cipher_user_share,
+        delete_cipher_user_share,
         post_cipher,
         post_cipher_partial,
         put_cipher,
@@ -73,6 +87,7 @@ pub fn routes() -> Vec<Route> {
         delete_cipher_selected_admin,
         delete_cipher_selected_post_admin,
         delete_cipher_selected_put_admin,
+        delete_cipher_bulk,
         restore_cipher_put,
         restore_cipher_put_admin,
         restore_cipher_selected,
@@ -95,6 +110,82 @@ pub async fn purge_trashed_ciphers(pool: DbPool) {
     }
 }
 
+/// Removes attachment files without an attachment in the database, like files left behind by a crash while deleting an item
+pub async fn purge_orphaned_attachments(pool: DbPool) {
+    debug!("Purging orphaned attachment files");
+    let Ok(mut conn) = pool.get().await else {
+        error!("Failed to get DB connection while purging orphaned attachment files");
+        return;
+    };
+
+    // Loaded before listing the files, files of attachments added meanwhile are protected by the minimum age
+    let known: HashSet<String> = match Attachment::find_all_paths(&mut conn).await {
+        Ok(paths) => paths.into_iter().map(|(cipher_uuid, id)| format!("{cipher_uuid}/{id}")).collect(),
+        Err(e) => {
+            error!("Error loading attachments while purging orphaned attachment files: {e:#?}");
+            return;
+        }
+    };
+    let min_age = Duration::from_secs(CONFIG.orphaned_attachments_min_age_hours() * 3600);
+    let cutoff = SystemTime::now() - min_age;
+    let dry_run = CONFIG.orphaned_attachments_dry_run();
+
+    match remove_orphaned_attachment_files(Path::new(&CONFIG.attachments_folder()), &known, cutoff, dry_run) {
+        Ok(orphans) if dry_run => {
+            for path in &orphans {
+                info!(
+                    "Orphaned attachment file {} would be removed, ORPHANED_ATTACHMENTS_DRY_RUN is enabled",
+                    path.display()
+                );
+            }
+        }
+        Ok(orphans) if !orphans.is_empty() => info!("Removed {} orphaned attachment files", orphans.len()),
+        Ok(_) => (),
+        Err(e) => error!("Error purging orphaned attachment files: {e:#?}"),
+    }
+}
+
+/// Finds the files in the attachments folder, stored as `<cipher_uuid>/<attachment_id>`, which aren't in `known`
+/// and weren't modified after `cutoff`, and removes them unless `dry_run` is set
+fn remove_orphaned_attachment_files(
+    folder: &Path,
+    known: &HashSet<String>,
+    cutoff: SystemTime,
+    dry_run: bool,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut orphans = Vec::new();
+    for cipher_dir in std::fs::read_dir(folder)? {
+        let cipher_dir = cipher_dir?;
+        let cipher_uuid = cipher_dir.file_name().to_string_lossy().into_owned();
+        // Organization logos are stored in the attachments folder as well
+        if cipher_uuid == "branding" || !cipher_dir.file_type()?.is_dir() {
+            continue;
+        }
+
+        for file in std::fs::read_dir(cipher_dir.path())? {
+            let file = file?;
+            let metadata = file.metadata()?;
+            if !metadata.is_file() || metadata.modified()? > cutoff {
+                continue;
+            }
+            if !known.contains(&format!("{cipher_uuid}/{}", file.file_name().to_string_lossy())) {
+                orphans.push(file.path());
+            }
+        }
+    }
+
+    if !dry_run {
+        for path in &orphans {
+            std::fs::remove_file(path)?;
+            if let Some(cipher_dir) = path.parent() {
+                // Only succeeds when no other attachments of the item are left
+                std::fs::remove_dir(cipher_dir).ok();
+            }
+        }
+    }
+    Ok(orphans)
+}
+
 #[derive(FromForm, Default)]
 struct SyncData {
     #[field(name = "excludeDomains")]
@@ -102,7 +193,7 @@ struct SyncData {
 }
 
 #[get("/sync?<data..>")]
-async fn sync(data: SyncData, headers: Headers, mut conn: DbConn) -> Json<Value> {
+async fn sync(data: SyncData, headers: Headers, mut conn: DbReadConn) -> Json<Value> {
     let user_json = headers.user.to_json(&mut conn).await;
 
     // Get all ciphers which are visible by the user
@@ -128,8 +219,11 @@ async fn sync(data: SyncData, headers: Headers, mut conn: DbConn) -> Json<Value>
     let folders_json: Vec<Value> =
         Folder::find_by_user(&headers.user.uuid, &mut conn).await.iter().map(Folder::to_json).collect();
 
-    let sends_json: Vec<Value> =
-        Send::find_by_user(&headers.user.uuid, &mut conn).await.iter().map(Send::to_json).collect();
+    let sends_json: Vec<Value> = if CONFIG.is_feature_enabled(Feature::Sends) {
+        Send::find_by_user(&headers.user.uuid, &mut conn).await.iter().map(Send::to_json).collect()
+    } else {
+        Vec::new()
+    };
 
     let policies_json: Vec<Value> =
         OrgPolicy::find_confirmed_by_user(&headers.user.uuid, &mut conn).await.iter().map(OrgPolicy::to_json).collect();
@@ -154,7 +248,7 @@ async fn sync(data: SyncData, headers: Headers, mut conn: DbConn) -> Json<Value>
 }
 
 #[get("/ciphers")]
-async fn get_ciphers(headers: Headers, mut conn: DbConn) -> Json<Value> {
+async fn get_ciphers(headers: Headers, mut conn: DbReadConn) -> Json<Value> {
     let ciphers = Cipher::find_by_user_visible(&headers.user.uuid, &mut conn).await;
     let cipher_sync_data = CipherSyncData::new(&headers.user.uuid, CipherSyncType::User, &mut conn).await;
 
@@ -174,7 +268,7 @@ async fn get_ciphers(headers: Headers, mut conn: DbConn) -> Json<Value> {
 }
 
 #[get("/ciphers/<uuid>")]
-async fn get_cipher(uuid: &str, headers: Headers, mut conn: DbConn) -> JsonResult {
+async fn get_cipher(uuid: &str, headers: Headers, mut conn: DbReadConn) -> JsonResult {
     let cipher = match Cipher::find_by_uuid(uuid, &mut conn).await {
         Some(cipher) => cipher,
         None => err!("Cipher doesn't exist"),
@@ -188,17 +282,17 @@ async fn get_cipher(uuid: &str, headers: Headers, mut conn: DbConn) -> JsonResul
 }
 
 #[get("/ciphers/<uuid>/admin")]
-async fn get_cipher_admin(uuid: &str, headers: Headers, conn: DbConn) -> JsonResult {
+async fn get_cipher_admin(uuid: &str, headers: Headers, conn: DbReadConn) -> JsonResult {
     // TODO: Implement this correctly
     get_cipher(uuid, headers, conn).await
 }
 
 #[get("/ciphers/<uuid>/details")]
-async fn get_cipher_details(uuid: &str, headers: Headers, conn: DbConn) -> JsonResult {
+async fn get_cipher_details(uuid: &str, headers: Headers, conn: DbReadConn) -> JsonResult {
     get_cipher(uuid, headers, conn).await
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 #[allow(non_snake_case)]
 pub struct CipherData {
     // Id is optional as it is included only in bulk share
@@ -254,22 +348,117 @@ pub struct PartialCipherData {
     Favorite: bool,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 #[allow(non_snake_case)]
 pub struct Attachments2Data {
     FileName: String,
     Key: String,
 }
 
+impl Attachments2Data {
+    /// Sets the re-encrypted name and key of an attachment
+    fn apply_to(self, attachment: &mut Attachment, max_name_length: usize) -> EmptyResult {
+        attachment.file_name = sanitize_attachment_name(&self.FileName, max_name_length)?;
+        attachment.akey = Some(self.Key);
+        Ok(())
+    }
+}
+
+/// Checks that a key rotation contains the re-encrypted key of every attachment of the user's personal ciphers,
+/// as attachments left out would stay encrypted with the old key. See `KEY_ROTATION_REQUIRE_ATTACHMENTS`.
+pub fn validate_rotated_attachments(ciphers: &[CipherData], attachments: &[Attachment]) -> EmptyResult {
+    // The cipher every rotated attachment was sent with
+    let rotated: HashMap<&str, &str> = ciphers
+        .iter()
+        .filter(|c| c.OrganizationId.is_none())
+        .filter_map(|c| Some((c.Id.as_deref()?, c.Attachments2.as_ref()?)))
+        .flat_map(|(cipher_id, attachments)| attachments.keys().map(move |id| (id.as_str(), cipher_id)))
+        .collect();
+
+    for attachment in attachments {
+        match rotated.get(attachment.id.as_str()) {
+            Some(cipher_id) if *cipher_id == attachment.cipher_uuid => {}
+            Some(_) => err!("Attachment is not owned by the cipher"),
+            None => err!(
+                "The key rotation is missing attachments. Sync your vault and try again.",
+                format!("Attachment {} of cipher {} is missing", attachment.id, attachment.cipher_uuid)
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// The optional `Idempotency-Key` header of a cipher create request, set by clients which retry the request
+struct IdempotencyKey(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(IdempotencyKey(req.headers().get_one("Idempotency-Key").map(String::from)))
+    }
+}
+
+enum IdempotencyClaim {
+    Unused,
+    Claimed(CipherIdempotencyKey),
+    // The response for the cipher created by an earlier request with the same key
+    Replayed(Value),
+}
+
+/// Claims the idempotency key of a create request for the new cipher, or returns the cipher created before with that key
+async fn claim_idempotency_key(
+    key: &IdempotencyKey,
+    cipher_uuid: &str,
+    headers: &Headers,
+    conn: &mut DbConn,
+) -> ApiResult<IdempotencyClaim> {
+    let window = CONFIG.cipher_idempotency_window();
+    let Some(key) = key.0.as_deref().filter(|_| window > 0) else {
+        return Ok(IdempotencyClaim::Unused);
+    };
+    CipherIdempotencyKey::check_key(key)?;
+
+    let cutoff = CipherIdempotencyKey::cutoff(&Utc::now().naive_utc(), window);
+    let claim = CipherIdempotencyKey::new(headers.user.uuid.clone(), key, cipher_uuid.to_string());
+    if claim.claim(&cutoff, conn).await? {
+        return Ok(IdempotencyClaim::Claimed(claim));
+    }
+
+    let existing = CipherIdempotencyKey::find_by_user_and_key(&headers.user.uuid, key, conn).await;
+    let cipher = match CipherIdempotencyKey::replayed_cipher(existing.as_ref(), &cutoff) {
+        Some(cipher_uuid) => Cipher::find_by_uuid(cipher_uuid, conn).await,
+        None => None,
+    };
+    match cipher {
+        Some(cipher) => Ok(IdempotencyClaim::Replayed(
+            cipher.to_json(&headers.host, &headers.user.uuid, None, CipherSyncType::User, conn).await,
+        )),
+        // The earlier request is still being processed
+        None => err_code!("A request with this Idempotency-Key is already in progress", 409),
+    }
+}
+
+/// Releases the idempotency key when creating the cipher failed, so the request can be retried
+async fn release_idempotency_key(claim: Option<CipherIdempotencyKey>, failed: bool, conn: &mut DbConn) {
+    if let (Some(claim), true) = (claim, failed) {
+        if let Err(e) = claim.delete(conn).await {
+            warn!("Error releasing idempotency key: {e:#?}");
+        }
+    }
+}
+
 /// Called when an org admin clones an org cipher.
 #[post("/ciphers/admin", data = "<data>")]
 async fn post_ciphers_admin(
     data: JsonUpcase<ShareCipherData>,
     headers: Headers,
+    idempotency_key: IdempotencyKey,
     conn: DbConn,
     nt: Notify<'_>,
 ) -> JsonResult {
-    post_ciphers_create(data, headers, conn, nt).await
+    post_ciphers_create(data, headers, idempotency_key, conn, nt).await
 }
 
 /// Called when creating a new org-owned cipher, or cloning a cipher (whether
@@ -279,6 +468,7 @@ async fn post_ciphers_admin(
 async fn post_ciphers_create(
     data: JsonUpcase<ShareCipherData>,
     headers: Headers,
+    idempotency_key: IdempotencyKey,
     mut conn: DbConn,
     nt: Notify<'_>,
 ) -> JsonResult {
@@ -290,14 +480,19 @@ async fn post_ciphers_create(
         err!("You must select at least one collection.");
     }
 
-    // This check is usually only needed in update_cipher_from_data(), but we
-    // need it here as well to avoid creating an empty cipher in the call to
+    // These checks are usually only needed in update_cipher_from_data(), but we
+    // need them here as well to avoid creating an empty cipher in the call to
     // cipher.save() below.
     enforce_personal_ownership_policy(Some(&data.Cipher), &headers, &mut conn).await?;
+    check_cipher_size_limits(&data.Cipher, CONFIG.cipher_max_field_bytes(), CONFIG.cipher_max_notes_bytes())?;
 
     let mut cipher = Cipher::new(data.Cipher.Type, data.Cipher.Name.clone());
     cipher.user_uuid = Some(headers.user.uuid.clone());
-    cipher.save(&mut conn).await?;
+    let claim = match claim_idempotency_key(&idempotency_key, &cipher.uuid, &headers, &mut conn).await? {
+        IdempotencyClaim::Replayed(json) => return Ok(Json(json)),
+        IdempotencyClaim::Claimed(claim) => Some(claim),
+        IdempotencyClaim::Unused => None,
+    };
 
     // When cloning a cipher, the Bitwarden clients seem to set this field
     // based on the cipher being cloned (when creating a new cipher, it's set
@@ -307,12 +502,23 @@ async fn post_ciphers_create(
     // or otherwise), we can just ignore this field entirely.
     data.Cipher.LastKnownRevisionDate = None;
 
-    share_cipher_by_uuid(&cipher.uuid, data, &headers, &mut conn, &nt).await
+    let result = match cipher.save(&mut conn).await {
+        Ok(()) => share_cipher_by_uuid(&cipher.uuid, data, &headers, &mut conn, &nt, false).await,
+        Err(e) => Err(e),
+    };
+    release_idempotency_key(claim, result.is_err(), &mut conn).await;
+    result
 }
 
 /// Called when creating a new user-owned cipher.
 #[post("/ciphers", data = "<data>")]
-async fn post_ciphers(data: JsonUpcase<CipherData>, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> JsonResult {
+async fn post_ciphers(
+    data: JsonUpcase<CipherData>,
+    headers: Headers,
+    idempotency_key: IdempotencyKey,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
     let mut data: CipherData = data.into_inner().data;
 
     // The web/browser clients set this field to null as expected, but the
@@ -322,7 +528,16 @@ async fn post_ciphers(data: JsonUpcase<CipherData>, headers: Headers, mut conn:
     data.LastKnownRevisionDate = None;
 
     let mut cipher = Cipher::new(data.Type, data.Name.clone());
-    update_cipher_from_data(&mut cipher, data, &headers, None, &mut conn, &nt, UpdateType::SyncCipherCreate).await?;
+    let claim = match claim_idempotency_key(&idempotency_key, &cipher.uuid, &headers, &mut conn).await? {
+        IdempotencyClaim::Replayed(json) => return Ok(Json(json)),
+        IdempotencyClaim::Claimed(claim) => Some(claim),
+        IdempotencyClaim::Unused => None,
+    };
+
+    let result =
+        update_cipher_from_data(&mut cipher, data, &headers, None, &mut conn, &nt, UpdateType::SyncCipherCreate).await;
+    release_idempotency_key(claim, result.is_err(), &mut conn).await;
+    result?;
 
     Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, None, CipherSyncType::User, &mut conn).await))
 }
@@ -349,6 +564,73 @@ async fn enforce_personal_ownership_policy(
     Ok(())
 }
 
+/// Rejects ciphers with oversized values, to prevent bloating the sync of everyone they are shared with.
+/// The values are encrypted, so the limits apply to the length of the encrypted strings.
+fn check_cipher_size_limits(data: &CipherData, max_field: Option<usize>, max_notes: usize) -> EmptyResult {
+    fn check_str(name: &str, value: &str, max: usize) -> EmptyResult {
+        if value.len() > max {
+            err!(format!("The field {name} exceeds the maximum encrypted value length of {max} characters."))
+        }
+        Ok(())
+    }
+
+    fn check_value(name: &str, value: &Value, max: usize) -> EmptyResult {
+        match value {
+            Value::String(s) => check_str(name, s, max),
+            Value::Array(values) => values.iter().try_for_each(|v| check_value(name, v, max)),
+            Value::Object(map) => map.iter().try_for_each(|(k, v)| check_value(k, v, max)),
+            _ => Ok(()),
+        }
+    }
+
+    if let Some(notes) = &data.Notes {
+        check_str("Notes", notes, max_notes)?;
+    }
+    let Some(max_field) = max_field else {
+        return Ok(());
+    };
+    check_str("Name", &data.Name, max_field)?;
+
+    let typed_values = [
+        ("Fields", &data.Fields),
+        ("Login", &data.Login),
+        ("SecureNote", &data.SecureNote),
+        ("Card", &data.Card),
+        ("Identity", &data.Identity),
+        ("PasswordHistory", &data.PasswordHistory),
+    ];
+    for (name, value) in typed_values {
+        if let Some(value) = value {
+            check_value(name, value, max_field)?;
+        }
+    }
+    Ok(())
+}
+
+/// Enforces the Vaultwarden specific allowed cipher types policy of an organization,
+/// which limits the item types members can create in it. The personal vault isn't affected.
+async fn enforce_allowed_cipher_types_policy(cipher_type: i32, org_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+    match OrgPolicy::find_by_org_and_type(org_uuid, OrgPolicyType::AllowedCipherTypes, conn).await {
+        Some(policy) if policy.enabled => {
+            if !is_cipher_type_allowed(cipher_type, policy.allowed_cipher_types().as_deref()) {
+                err!("Due to an Enterprise Policy, you are restricted from saving items of this type to this organization.")
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Applies the Vaultwarden specific enforced reprompt policy of an organization, overriding the reprompt
+/// setting of its ciphers of the affected types. The reprompt itself is still enforced by the clients.
+fn enforce_reprompt_policy(cipher: &mut Cipher, policy: Option<&OrgPolicy>) {
+    if let Some(policy) = policy.filter(|p| p.enabled) {
+        if is_reprompt_enforced(cipher.atype, policy.reprompt_cipher_types().as_deref()) {
+            cipher.reprompt = Some(RepromptType::Password as i32);
+        }
+    }
+}
+
 pub async fn update_cipher_from_data(
     cipher: &mut Cipher,
     data: CipherData,
@@ -359,6 +641,7 @@ pub async fn update_cipher_from_data(
     ut: UpdateType,
 ) -> EmptyResult {
     enforce_personal_ownership_policy(Some(&data), headers, conn).await?;
+    check_cipher_size_limits(&data, CONFIG.cipher_max_field_bytes(), CONFIG.cipher_max_notes_bytes())?;
 
     // Check that the client isn't updating an existing cipher with stale data.
     // And only perform this check when not importing ciphers, else the date/time check will fail.
@@ -379,19 +662,24 @@ pub async fn update_cipher_from_data(
         err!("Organization mismatch. Please resync the client before updating the cipher")
     }
 
-    if let Some(note) = &data.Notes {
-        if note.len() > 10_000 {
-            err!("The field Notes exceeds the maximum encrypted value length of 10000 characters.")
-        }
-    }
-
     // Check if this cipher is being transferred from a personal to an organization vault
     let transfer_cipher = cipher.organization_uuid.is_none() && data.OrganizationId.is_some();
 
+    // Users a personal cipher is shared with can edit it, but the owner and key stay the same
+    let shared_with_user = cipher.is_shared_with_user(&headers.user.uuid, conn).await;
+    if shared_with_user && transfer_cipher {
+        err!("Only the owner can move a shared cipher to an organization")
+    }
+
     if let Some(org_id) = data.OrganizationId {
         match UserOrganization::find_by_user_and_org(&headers.user.uuid, &org_id, conn).await {
             None => err!("You don't have permission to add item to organization"),
             Some(org_user) => {
+                // Ciphers newly added to the organization need to be of a type allowed for the member
+                if cipher.organization_uuid.is_none() && org_user.atype < UserOrgType::Admin {
+                    enforce_allowed_cipher_types_policy(data.Type, &org_id, conn).await?;
+                }
+
                 if shared_to_collections.is_some()
                     || org_user.has_full_access()
                     || cipher.is_write_accessible_to_user(&headers.user.uuid, conn).await
@@ -408,7 +696,7 @@ pub async fn update_cipher_from_data(
                 }
             }
         }
-    } else {
+    } else if !shared_with_user {
         cipher.user_uuid = Some(headers.user.uuid.clone());
     }
 
@@ -445,9 +733,7 @@ pub async fn update_cipher_from_data(
                 break;
             }
 
-            saved_att.akey = Some(attachment.Key);
-            saved_att.file_name = attachment.FileName;
-
+            attachment.apply_to(&mut saved_att, CONFIG.max_attachment_name_length())?;
             saved_att.save(conn).await?;
         }
     }
@@ -487,15 +773,25 @@ pub async fn update_cipher_from_data(
         None => err!("Data missing"),
     };
 
-    cipher.key = data.Key;
+    if !shared_with_user {
+        cipher.key = data.Key;
+    }
     cipher.name = data.Name;
     cipher.notes = data.Notes;
     cipher.fields = data.Fields.map(|f| _clean_cipher_data(f).to_string());
     cipher.data = type_data.to_string();
     cipher.password_history = data.PasswordHistory.map(|f| f.to_string());
     cipher.reprompt = data.Reprompt;
+    if let Some(org_uuid) = &cipher.organization_uuid {
+        let policy = OrgPolicy::find_by_org_and_type(org_uuid, OrgPolicyType::EnforcedReprompt, conn).await;
+        enforce_reprompt_policy(cipher, policy.as_ref());
+    }
 
     cipher.save(conn).await?;
+    if transfer_cipher {
+        // Personal shares don't apply to organization ciphers
+        CipherShare::delete_all_by_cipher(&cipher.uuid, conn).await?;
+    }
     cipher.move_to_folder(data.FolderId, &headers.user.uuid, conn).await?;
     cipher.set_favorite(data.Favorite, &headers.user.uuid, conn).await?;
 
@@ -559,40 +855,163 @@ async fn post_ciphers_import(
     enforce_personal_ownership_policy(None, &headers, &mut conn).await?;
 
     let data: ImportData = data.into_inner().data;
+    import_ciphers(data, headers, &mut conn, &nt).await
+}
+
+/// Imports a raw export file, converting it on the server.
+/// The server never has access to the user key, so it can't encrypt any vault data itself.
+/// Only the Bitwarden JSON export which is encrypted with the account key can be imported this way,
+/// all other formats (including password protected exports) need to be imported using a client.
+#[post("/ciphers/import-file?<format>", data = "<data>")]
+async fn post_ciphers_import_file(
+    format: &str,
+    data: Data<'_>,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> EmptyResult {
+    enforce_personal_ownership_policy(None, &headers, &mut conn).await?;
+
+    let content = match data.open(CONFIG.import_max_size_mb().megabytes()).into_string().await {
+        Ok(content) if content.is_complete() => content.into_inner(),
+        Ok(_) => err!("The import file is too large"),
+        Err(e) => err!(format!("Unable to read the import file: {e}")),
+    };
 
+    let data = parse_import_file(format, &content)?;
+    import_ciphers(data, headers, &mut conn, &nt).await
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct BitwardenJsonExport {
+    Encrypted: bool,
+    PasswordProtected: Option<bool>,
+    EncKeyValidation_DO_NOT_EDIT: Option<String>,
+    #[serde(default)]
+    Folders: Vec<BitwardenJsonFolder>,
+    #[serde(default)]
+    Items: Vec<CipherData>,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct BitwardenJsonFolder {
+    Id: String,
+    Name: String,
+}
+
+fn parse_import_file(format: &str, content: &str) -> ApiResult<ImportData> {
+    match format {
+        "bitwarden_json" => (),
+        "lastpass_csv" | "onepassword_1pux" => {
+            err!("This format contains unencrypted data, which can only be imported using a Bitwarden client")
+        }
+        _ => err!(format!("Unsupported import format: {format}")),
+    }
+
+    let export = match serde_json::from_str::<UpCase<BitwardenJsonExport>>(content) {
+        Ok(export) => export.data,
+        Err(e) => err!(format!("Invalid Bitwarden JSON export: {e}")),
+    };
+
+    if !export.Encrypted {
+        err!("Unencrypted Bitwarden JSON exports can only be imported using a Bitwarden client")
+    }
+    if export.PasswordProtected.unwrap_or(false) {
+        err!("Password protected Bitwarden JSON exports can only be imported using a Bitwarden client")
+    }
+    // Clients decrypt this marker to check the export belongs to the account, the server can't decrypt it,
+    // but an export without a valid marker has been edited or wasn't created by a Bitwarden client
+    match export.EncKeyValidation_DO_NOT_EDIT.as_deref() {
+        Some(marker) if is_encrypted_string(marker) => (),
+        _ => err!("The Bitwarden JSON export is missing its encryption key validation, it can't be imported"),
+    }
+
+    let folder_indexes: HashMap<&str, usize> =
+        export.Folders.iter().enumerate().map(|(index, folder)| (folder.Id.as_str(), index)).collect();
+
+    let mut relations = Vec::new();
+    for (index, item) in export.Items.iter().enumerate() {
+        if let Some(folder_index) = item.FolderId.as_deref().and_then(|id| folder_indexes.get(id)) {
+            relations.push(RelationsData {
+                Key: index,
+                Value: *folder_index,
+            });
+        }
+    }
+
+    let ciphers = export
+        .Items
+        .into_iter()
+        .map(|mut item| {
+            // The ids are those of the exported vault, new ones are created during the import
+            item.Id = None;
+            item.FolderId = None;
+            item.OrganizationId = None;
+            item
+        })
+        .collect();
+
+    Ok(ImportData {
+        Ciphers: ciphers,
+        Folders: export
+            .Folders
+            .into_iter()
+            .map(|f| FolderData {
+                Name: f.Name,
+            })
+            .collect(),
+        FolderRelationships: relations,
+    })
+}
+
+async fn import_ciphers(data: ImportData, headers: Headers, conn: &mut DbConn, nt: &Notify<'_>) -> EmptyResult {
     // Validate the import before continuing
     // Bitwarden does not process the import if there is one item invalid.
     // Since we check for the size of the encrypted note length, we need to do that here to pre-validate it.
     // TODO: See if we can optimize the whole cipher adding/importing and prevent duplicate code and checks.
     Cipher::validate_notes(&data.Ciphers)?;
-
-    // Read and create the folders
-    let mut folders: Vec<_> = Vec::new();
-    for folder in data.Folders.into_iter() {
-        let mut new_folder = Folder::new(headers.user.uuid.clone(), folder.Name);
-        new_folder.save(&mut conn).await?;
-
-        folders.push(new_folder);
+    for folder in &data.Folders {
+        check_folder_name(&folder.Name, CONFIG.max_folder_name_length())?;
     }
+    enforce_folder_count(&headers.user.uuid, data.Folders.len() as i64, conn).await?;
 
     // Read the relations between folders and ciphers
     let mut relations_map = HashMap::new();
 
     for relation in data.FolderRelationships {
+        if relation.Value >= data.Folders.len() {
+            err!("Invalid folder relationship in import")
+        }
         relations_map.insert(relation.Key, relation.Value);
     }
 
-    // Read and create the ciphers
-    for (index, mut cipher_data) in data.Ciphers.into_iter().enumerate() {
-        let folder_uuid = relations_map.get(&index).map(|i| folders[*i].uuid.clone());
-        cipher_data.FolderId = folder_uuid;
+    // The import is all or nothing, nothing is stored when one of the items fails
+    begin_transaction(conn).await?;
+    let result = async {
+        let mut folders: Vec<Folder> = Vec::new();
+        for folder in data.Folders.into_iter() {
+            let mut new_folder = Folder::new(headers.user.uuid.clone(), folder.Name);
+            new_folder.save(conn).await?;
 
-        let mut cipher = Cipher::new(cipher_data.Type, cipher_data.Name.clone());
-        update_cipher_from_data(&mut cipher, cipher_data, &headers, None, &mut conn, &nt, UpdateType::None).await?;
+            folders.push(new_folder);
+        }
+
+        for (index, mut cipher_data) in data.Ciphers.into_iter().enumerate() {
+            let folder_uuid = relations_map.get(&index).map(|i| folders[*i].uuid.clone());
+            cipher_data.FolderId = folder_uuid;
+
+            let mut cipher = Cipher::new(cipher_data.Type, cipher_data.Name.clone());
+            update_cipher_from_data(&mut cipher, cipher_data, &headers, None, conn, nt, UpdateType::None).await?;
+        }
+        Ok(())
     }
+    .await;
+    finish_transaction(result, conn).await?;
 
     let mut user = headers.user;
-    user.update_revision(&mut conn).await?;
+    user.update_revision(conn).await?;
     nt.send_user_update(UpdateType::SyncVault, &user).await;
 
     Ok(())
@@ -764,6 +1183,12 @@ async fn post_collections_admin(
     }
 
     let posted_collections: HashSet<String> = data.CollectionIds.iter().cloned().collect();
+    if let Some(ref org_uuid) = cipher.organization_uuid {
+        let member_type = UserOrganization::find_by_user_and_org(&headers.user.uuid, org_uuid, &mut conn)
+            .await
+            .map(|member| member.atype);
+        check_collection_count(posted_collections.len(), member_type, CONFIG.org_items_require_collection())?;
+    }
     let current_collections: HashSet<String> =
         cipher.get_collections(headers.user.uuid.clone(), &mut conn).await.iter().cloned().collect();
 
@@ -810,7 +1235,7 @@ async fn post_collections_admin(
     Ok(())
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[allow(non_snake_case)]
 struct ShareCipherData {
     Cipher: CipherData,
@@ -827,7 +1252,7 @@ async fn post_cipher_share(
 ) -> JsonResult {
     let data: ShareCipherData = data.into_inner().data;
 
-    share_cipher_by_uuid(uuid, data, &headers, &mut conn, &nt).await
+    share_cipher_by_uuid(uuid, data, &headers, &mut conn, &nt, true).await
 }
 
 #[put("/ciphers/<uuid>/share", data = "<data>")]
@@ -840,7 +1265,7 @@ async fn put_cipher_share(
 ) -> JsonResult {
     let data: ShareCipherData = data.into_inner().data;
 
-    share_cipher_by_uuid(uuid, data, &headers, &mut conn, &nt).await
+    share_cipher_by_uuid(uuid, data, &headers, &mut conn, &nt, true).await
 }
 
 #[derive(Deserialize)]
@@ -880,7 +1305,7 @@ async fn put_cipher_share_selected(
         };
 
         match shared_cipher_data.Cipher.Id.take() {
-            Some(id) => share_cipher_by_uuid(&id, shared_cipher_data, &headers, &mut conn, &nt).await?,
+            Some(id) => share_cipher_by_uuid(&id, shared_cipher_data, &headers, &mut conn, &nt, true).await?,
             None => err!("Request missing ids field"),
         };
     }
@@ -888,12 +1313,42 @@ async fn put_cipher_share_selected(
     Ok(())
 }
 
+/// The transfer requests of the user into organizations with the transfer approval policy
+#[get("/ciphers/transfers")]
+async fn get_cipher_transfers(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let transfers_json: Vec<Value> = CipherTransfer::find_by_user(&headers.user.uuid, &mut conn)
+        .await
+        .iter()
+        .map(|transfer| transfer.to_json(&headers.user.email))
+        .collect();
+
+    Json(json!({
+        "Data": transfers_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    }))
+}
+
+/// Applies a transfer request approved by an admin, as the member who requested it
+pub async fn share_approved_transfer(
+    uuid: &str,
+    data: &str,
+    headers: &Headers,
+    conn: &mut DbConn,
+    nt: &Notify<'_>,
+) -> EmptyResult {
+    let data: ShareCipherData = serde_json::from_str(data)?;
+    share_cipher_by_uuid(uuid, data, headers, conn, nt, false).await?;
+    Ok(())
+}
+
 async fn share_cipher_by_uuid(
     uuid: &str,
     data: ShareCipherData,
     headers: &Headers,
     conn: &mut DbConn,
     nt: &Notify<'_>,
+    check_approval: bool,
 ) -> JsonResult {
     let mut cipher = match Cipher::find_by_uuid(uuid, conn).await {
         Some(cipher) => {
@@ -906,20 +1361,44 @@ async fn share_cipher_by_uuid(
         None => err!("Cipher doesn't exist"),
     };
 
+    // Validate all target collections before anything is saved, so a rejected share doesn't leave the cipher in some of them
     let mut shared_to_collections = vec![];
-
     if let Some(organization_uuid) = &data.Cipher.OrganizationId {
+        let member = UserOrganization::find_by_user_and_org(&headers.user.uuid, organization_uuid, conn).await;
+        let member_status = member.as_ref().map(|member| member.status);
+
+        let mut targets = Vec::with_capacity(data.CollectionIds.len());
         for uuid in &data.CollectionIds {
-            match Collection::find_by_uuid_and_org(uuid, organization_uuid, conn).await {
-                None => err!("Invalid collection ID provided"),
-                Some(collection) => {
-                    if collection.is_writable_by_user(&headers.user.uuid, conn).await {
-                        CollectionCipher::save(&cipher.uuid, &collection.uuid, conn).await?;
-                        shared_to_collections.push(collection.uuid);
-                    } else {
-                        err!("No rights to modify the collection")
-                    }
+            let target = match Collection::find_by_uuid_and_org(uuid, organization_uuid, conn).await {
+                None => ShareTarget::Missing,
+                Some(collection) if collection.is_writable_by_user(&headers.user.uuid, conn).await => {
+                    ShareTarget::Writable
                 }
+                Some(_) => ShareTarget::ReadOnly,
+            };
+            targets.push(target);
+        }
+        check_share_targets(member_status, &targets)?;
+
+        let approval_required = check_approval
+            && cipher.organization_uuid.is_none()
+            && OrgPolicy::is_enabled_by_org(organization_uuid, OrgPolicyType::TransferApproval, conn).await;
+        if approval_required && needs_transfer_approval(member.map(|member| member.atype)) {
+            // The cipher stays personal until an admin approves the request, a new request replaces the previous one
+            CipherTransfer::delete_pending_by_cipher(&cipher.uuid, conn).await?;
+            let transfer = CipherTransfer::new(
+                cipher.uuid.clone(),
+                headers.user.uuid.clone(),
+                organization_uuid.clone(),
+                serde_json::to_string(&data)?,
+            );
+            transfer.save(conn).await?;
+            return Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, None, CipherSyncType::User, conn).await));
+        }
+
+        for uuid in &data.CollectionIds {
+            if !shared_to_collections.contains(uuid) {
+                shared_to_collections.push(uuid.clone());
             }
         }
     };
@@ -931,11 +1410,174 @@ async fn share_cipher_by_uuid(
         UpdateType::SyncCipherCreate
     };
 
-    update_cipher_from_data(&mut cipher, data.Cipher, headers, Some(shared_to_collections), conn, nt, ut).await?;
+    // The cipher is only added to the target collections when the update succeeds as well
+    begin_transaction(conn).await?;
+    let result = async {
+        for collection_uuid in &shared_to_collections {
+            CollectionCipher::save(&cipher.uuid, collection_uuid, conn).await?;
+        }
+        update_cipher_from_data(&mut cipher, data.Cipher, headers, Some(shared_to_collections.clone()), conn, nt, ut)
+            .await
+    }
+    .await;
+    finish_transaction(result, conn).await?;
 
     Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, None, CipherSyncType::User, conn).await))
 }
 
+/// Admins and owners of an organization with the transfer approval policy don't need to approve their own transfers
+fn needs_transfer_approval(member_type: Option<i32>) -> bool {
+    !member_type.is_some_and(|atype| atype >= UserOrgType::Admin)
+}
+
+enum ShareTarget {
+    Missing,
+    ReadOnly,
+    Writable,
+}
+
+/// A cipher can only be shared by a confirmed member, into at least one collection the member can write to
+fn check_share_targets(member_status: Option<i32>, targets: &[ShareTarget]) -> EmptyResult {
+    if member_status != Some(UserOrgStatus::Confirmed as i32) {
+        err!("You don't have permission to add item to organization")
+    }
+    if targets.is_empty() {
+        err!("You must select at least one collection")
+    }
+    for target in targets {
+        match target {
+            ShareTarget::Missing => err!("Invalid collection ID provided"),
+            ShareTarget::ReadOnly => err!("No rights to modify the collection"),
+            ShareTarget::Writable => (),
+        }
+    }
+    Ok(())
+}
+
+/// Org items without any collection are only visible to admins and owners, so other members can't remove the last one.
+/// See `ORG_ITEMS_REQUIRE_COLLECTION`.
+fn check_collection_count(collections: usize, member_type: Option<i32>, required: bool) -> EmptyResult {
+    let is_admin = member_type.is_some_and(|atype| atype >= UserOrgType::Admin);
+    if required && collections == 0 && !is_admin {
+        err!("You must select at least one collection")
+    }
+    Ok(())
+}
+
+// Sharing a single personal cipher directly with another user, without an organization.
+// The client of the owner encrypts the cipher key with the public key of the recipient.
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct CipherUserShareData {
+    Email: String,
+    Key: String,
+    ReadOnly: Option<bool>,
+}
+
+async fn get_owned_personal_cipher(uuid: &str, headers: &Headers, conn: &mut DbConn) -> ApiResult<Cipher> {
+    let cipher = match Cipher::find_by_uuid(uuid, conn).await {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    if cipher.organization_uuid.is_some() || !cipher.is_owned_by_user(&headers.user.uuid) {
+        err!("Only personal ciphers can be shared by their owner")
+    }
+
+    Ok(cipher)
+}
+
+#[get("/ciphers/<uuid>/user-shares")]
+async fn get_cipher_user_shares(uuid: &str, headers: Headers, mut conn: DbConn) -> JsonResult {
+    let cipher = get_owned_personal_cipher(uuid, &headers, &mut conn).await?;
+
+    let mut shares_json = Vec::new();
+    for share in CipherShare::find_by_cipher(&cipher.uuid, &mut conn).await {
+        shares_json.push(share.to_json(&mut conn).await);
+    }
+
+    Ok(Json(json!({
+        "Data": shares_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+#[post("/ciphers/<uuid>/user-shares", data = "<data>")]
+async fn post_cipher_user_share(
+    uuid: &str,
+    data: JsonUpcase<CipherUserShareData>,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
+    let data: CipherUserShareData = data.into_inner().data;
+    let cipher = get_owned_personal_cipher(uuid, &headers, &mut conn).await?;
+
+    // Without an individual cipher key, the cipher is encrypted with the user key of the owner
+    if cipher.key.is_none() {
+        err!("Only ciphers with an individual encryption key can be shared")
+    }
+
+    let recipient = match User::find_by_mail(&data.Email, &mut conn).await {
+        Some(user) if user.uuid != headers.user.uuid => user,
+        Some(_) => err!("You can't share a cipher with yourself"),
+        None => err!("User doesn't exist"),
+    };
+
+    let share = CipherShare::new(cipher.uuid.clone(), recipient.uuid.clone(), data.Key, data.ReadOnly.unwrap_or(false));
+    share.save(&mut conn).await?;
+
+    nt.send_cipher_update(
+        UpdateType::SyncCipherCreate,
+        &cipher,
+        &[recipient.uuid],
+        &headers.device.uuid,
+        None,
+        &mut conn,
+    )
+    .await;
+
+    Ok(Json(share.to_json(&mut conn).await))
+}
+
+// Can be used by the owner to revoke a share, or by the recipient to remove the cipher from their vault
+#[delete("/ciphers/<uuid>/user-shares/<user_uuid>")]
+async fn delete_cipher_user_share(
+    uuid: &str,
+    user_uuid: &str,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> EmptyResult {
+    let cipher = match Cipher::find_by_uuid(uuid, &mut conn).await {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    if !cipher.is_owned_by_user(&headers.user.uuid) && user_uuid != headers.user.uuid {
+        err!("Only the owner can revoke a share")
+    }
+
+    let share = match CipherShare::find_by_cipher_and_user(&cipher.uuid, user_uuid, &mut conn).await {
+        Some(share) => share,
+        None => err!("Cipher isn't shared with this user"),
+    };
+    share.delete(&mut conn).await?;
+
+    nt.send_cipher_update(
+        UpdateType::SyncCipherDelete,
+        &cipher,
+        &[user_uuid.to_string()],
+        &headers.device.uuid,
+        None,
+        &mut conn,
+    )
+    .await;
+
+    Ok(())
+}
+
 /// v2 API for downloading an attachment. This just redirects the client to
 /// the actual location of an attachment.
 ///
@@ -994,15 +1636,18 @@ async fn post_attachment_v2(
         err!("Cipher is not write accessible")
     }
 
+    enforce_attachment_count(&cipher.uuid, &mut conn).await?;
+
     let data: AttachmentRequestData = data.into_inner().data;
     let file_size = data.FileSize.into_i64()?;
 
     if file_size < 0 {
         err!("Attachment size can't be negative")
     }
+    let file_name = sanitize_attachment_name(&data.FileName, CONFIG.max_attachment_name_length())?;
+    check_attachment_key(Some(&data.Key), CONFIG.attachment_require_encrypted_key())?;
     let attachment_id = crypto::generate_attachment_id();
-    let attachment =
-        Attachment::new(attachment_id.clone(), cipher.uuid.clone(), data.FileName, file_size, Some(data.Key));
+    let attachment = Attachment::new(attachment_id.clone(), cipher.uuid.clone(), file_name, file_size, Some(data.Key));
     attachment.save(&mut conn).await.expect("Error saving attachment");
 
     let url = format!("/ciphers/{}/attachment/{}", cipher.uuid, attachment_id);
@@ -1020,6 +1665,111 @@ async fn post_attachment_v2(
     })))
 }
 
+/// Cleans the file name an attachment is declared with. Clients send it encrypted, so a valid name never contains
+/// control characters, backslashes or `.`/`..` path segments, and removing them leaves it intact.
+/// The files themselves are stored under a generated id, this only protects the places the name is shown or used.
+fn sanitize_attachment_name(name: &str, max_length: usize) -> ApiResult<String> {
+    let name: String = name.chars().filter(|c| !c.is_control() && *c != '\\').collect();
+    let name = name.split('/').filter(|segment| *segment != "." && *segment != "..").collect::<Vec<_>>().join("/");
+    let name = name.trim_start_matches('/');
+
+    if name.is_empty() {
+        err!("No filename provided")
+    }
+    if name.len() > max_length {
+        err!(format!("The attachment name can't be longer than {max_length} characters"))
+    }
+    Ok(String::from(name))
+}
+
+/// Checks that an attachment comes with its key, without it the attachment can't be decrypted by anyone.
+/// With `require_encrypted` the key also has to be an encrypted string, like `2.<iv>|<data>|<mac>`, see `ATTACHMENT_REQUIRE_ENCRYPTED_KEY`.
+fn check_attachment_key(key: Option<&str>, require_encrypted: bool) -> EmptyResult {
+    let Some(key) = key else {
+        err!("No attachment key provided")
+    };
+    if require_encrypted && !is_encrypted_string(key) {
+        err!("The attachment key isn't encrypted, the attachment would be unreadable")
+    }
+    Ok(())
+}
+
+// The encryption type, followed by the base64 encoded parts: the IV and data, and the MAC for the authenticated types
+fn is_encrypted_string(value: &str) -> bool {
+    let Some((enc_type, parts)) = value.split_once('.') else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.split('|').collect();
+    enc_type.parse::<u8>().is_ok()
+        && (2..=3).contains(&parts.len())
+        && parts.iter().all(|part| !part.is_empty() && data_encoding::BASE64.decode(part.as_bytes()).is_ok())
+}
+
+/// Recognizes a few common file types from their first bytes. Encrypted attachments start with their
+/// encryption type byte and never match, so this only catches uploads which weren't encrypted by the client.
+fn sniff_mime_type(head: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: [(&[u8], &str); 11] = [
+        (b"MZ", "application/x-msdownload"),
+        (b"\x7fELF", "application/x-executable"),
+        (b"\xfe\xed\xfa\xce", "application/x-mach-binary"),
+        (b"\xfe\xed\xfa\xcf", "application/x-mach-binary"),
+        (b"\xce\xfa\xed\xfe", "application/x-mach-binary"),
+        (b"\xcf\xfa\xed\xfe", "application/x-mach-binary"),
+        (b"#!", "text/x-shellscript"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+    ];
+    SIGNATURES.iter().find(|(magic, _)| head.starts_with(magic)).map(|(_, mime)| *mime)
+}
+
+/// Matches a MIME type, without its parameters, against a pattern like `image/png` or `image/*`
+fn mime_matches(mime: &str, pattern: &str) -> bool {
+    let mime = mime.split(';').next().unwrap_or_default().trim().to_lowercase();
+    let pattern = pattern.trim().to_lowercase();
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(main_type) => mime.split_once('/').is_some_and(|(t, _)| t == main_type),
+        None => mime == pattern,
+    }
+}
+
+/// Enforces `ATTACHMENT_ALLOWED_MIME` and `ATTACHMENT_BLOCKED_MIME` for the declared and the sniffed type of an attachment.
+/// Without a declared type the attachment is treated as `application/octet-stream`.
+fn check_attachment_type(declared: Option<&str>, sniffed: Option<&str>, allowed: &str, blocked: &str) -> EmptyResult {
+    let patterns = |list: &str| -> Vec<String> {
+        list.split(',').map(str::trim).filter(|p| !p.is_empty()).map(String::from).collect()
+    };
+    let (allowed, blocked) = (patterns(allowed), patterns(blocked));
+
+    for mime in [Some(declared.unwrap_or("application/octet-stream")), sniffed].into_iter().flatten() {
+        if blocked.iter().any(|p| mime_matches(mime, p))
+            || !(allowed.is_empty() || allowed.iter().any(|p| mime_matches(mime, p)))
+        {
+            err!(format!("Attachments of type {mime} are not allowed"))
+        }
+    }
+    Ok(())
+}
+
+/// Fails when a cipher with `existing` attachments can't get another one
+fn check_attachment_count(existing: i64, max_attachments: Option<i64>) -> EmptyResult {
+    match max_attachments {
+        Some(max) if existing >= max => {
+            err!(format!("This item can't have more than {max} attachments. Delete some attachments first"))
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn enforce_attachment_count(cipher_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+    let Some(max_attachments) = CONFIG.max_attachments_per_cipher() else {
+        return Ok(());
+    };
+    check_attachment_count(Attachment::count_by_cipher(cipher_uuid, conn).await, Some(max_attachments))
+}
+
 #[derive(FromForm)]
 struct UploadData<'f> {
     key: Option<String>,
@@ -1037,12 +1787,13 @@ struct UploadData<'f> {
 async fn save_attachment(
     mut attachment: Option<Attachment>,
     cipher_uuid: &str,
-    data: Form<UploadData<'_>>,
+    data: MultipartForm<UploadData<'_>>,
     headers: &Headers,
     mut conn: DbConn,
     nt: Notify<'_>,
 ) -> Result<(Cipher, DbConn), crate::error::Error> {
-    let mut data = data.into_inner();
+    let mut data = data.into_inner().data;
+    let declared_type = data.data.content_type().map(ToString::to_string);
 
     let Some(size) = data.data.len().to_i64() else {
         err!("Attachment data size overflow");
@@ -1060,6 +1811,11 @@ async fn save_attachment(
         err!("Cipher is not write accessible")
     }
 
+    // In the v2 API, the attachment record has already been created and counted
+    if attachment.is_none() {
+        enforce_attachment_count(&cipher.uuid, &mut conn).await?;
+    }
+
     // In the v2 API, the attachment record has already been created,
     // so the size limit needs to be adjusted to account for that.
     let size_adjust = match &attachment {
@@ -1121,6 +1877,17 @@ async fn save_attachment(
         }
     }
 
+    // Checked while the upload is still a temporary file, so a rejected file is never stored
+    let _staged = match check_uploaded_attachment(&mut data.data, declared_type.as_deref()).await {
+        Ok(staged) => staged,
+        Err(e) => {
+            if let Some(attachment) = &attachment {
+                attachment.delete(&mut conn).await.ok();
+            }
+            return Err(e);
+        }
+    };
+
     let file_id = match &attachment {
         Some(attachment) => attachment.id.clone(), // v2 API
         None => crypto::generate_attachment_id(),  // Legacy API
@@ -1157,16 +1924,14 @@ async fn save_attachment(
         // SAFETY: This value is only stored in the database and is not used to access the file system.
         // As a result, the conditions specified by Rocket [0] are met and this is safe to use.
         // [0]: https://docs.rs/rocket/latest/rocket/fs/struct.FileName.html#-danger-
-        let encrypted_filename = data.data.raw_name().map(|s| s.dangerous_unsafe_unsanitized_raw().to_string());
-
-        if encrypted_filename.is_none() {
+        let Some(encrypted_filename) = data.data.raw_name().map(|s| s.dangerous_unsafe_unsanitized_raw().as_str())
+        else {
             err!("No filename provided")
-        }
-        if data.key.is_none() {
-            err!("No attachment key provided")
-        }
+        };
+        let encrypted_filename = sanitize_attachment_name(encrypted_filename, CONFIG.max_attachment_name_length())?;
+        check_attachment_key(data.key.as_deref(), CONFIG.attachment_require_encrypted_key())?;
         let attachment =
-            Attachment::new(file_id.clone(), String::from(cipher_uuid), encrypted_filename.unwrap(), size, data.key);
+            Attachment::new(file_id.clone(), String::from(cipher_uuid), encrypted_filename, size, data.key);
         attachment.save(&mut conn).await.expect("Error saving attachment");
     }
 
@@ -1175,7 +1940,7 @@ async fn save_attachment(
     tokio::fs::create_dir_all(&folder_path).await?;
 
     if let Err(_err) = data.data.persist_to(&file_path).await {
-        data.data.move_copy_to(file_path).await?
+        data.data.move_copy_to(&file_path).await?
     }
 
     nt.send_cipher_update(
@@ -1204,6 +1969,100 @@ async fn save_attachment(
     Ok((cipher, conn))
 }
 
+/// An upload written to the temporary folder for scanning, which is removed unless it was moved to the attachments folder
+struct StagedUpload(PathBuf);
+
+impl Drop for StagedUpload {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).ok();
+    }
+}
+
+/// Applies the allowed and blocked types and the virus scan to an uploaded attachment.
+/// Small uploads are only kept in memory, for the scan these are written to the temporary folder first.
+async fn check_uploaded_attachment(
+    file: &mut TempFile<'_>,
+    declared_type: Option<&str>,
+) -> ApiResult<Option<StagedUpload>> {
+    if !(CONFIG.attachment_allowed_mime().is_empty() && CONFIG.attachment_blocked_mime().is_empty()) {
+        let mut head = [0u8; 16];
+        let read = match file.open().await {
+            Ok(reader) => {
+                tokio::pin!(reader);
+                reader.read(&mut head).await.unwrap_or_default()
+            }
+            Err(_) => 0,
+        };
+        check_attachment_type(
+            declared_type,
+            sniff_mime_type(&head[..read]),
+            &CONFIG.attachment_allowed_mime(),
+            &CONFIG.attachment_blocked_mime(),
+        )?;
+    }
+
+    let Some(scan_cmd) = CONFIG.attachment_scan_cmd() else {
+        return Ok(None);
+    };
+
+    let mut staged = None;
+    if file.path().is_none() {
+        // Staged next to the other uploads, the system temp folder might be on another filesystem or too small
+        let path =
+            std::path::Path::new(&CONFIG.tmp_folder()).join(format!("vaultwarden-upload-{}", crate::util::get_uuid()));
+        file.persist_to(&path).await?;
+        staged = Some(StagedUpload(path));
+    }
+    let Some(path) = file.path() else {
+        err!("Unable to scan the attachment")
+    };
+
+    match scan_attachment(&scan_cmd, path, CONFIG.attachment_scan_timeout()).await {
+        Ok(ScanResult::Clean) => Ok(staged),
+        Ok(ScanResult::Flagged) => err!("The attachment was rejected by the virus scanner"),
+        Err(e) => err!(format!("Unable to scan the attachment: {e}")),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ScanResult {
+    Clean,
+    Flagged,
+}
+
+/// Scans an uploaded attachment with the configured command, the path of the file is appended as last argument.
+/// Follows the exit codes of ClamAV, 0 means clean and 1 means flagged, everything else is an error.
+async fn scan_attachment(scan_cmd: &str, path: &std::path::Path, timeout: u64) -> ApiResult<ScanResult> {
+    let mut args = scan_cmd.split_whitespace();
+    let Some(program) = args.next() else {
+        err!("The scan command is empty")
+    };
+
+    let mut command = tokio::process::Command::new(program);
+    command
+        .args(args)
+        .arg(path)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true);
+
+    let status = match tokio::time::timeout(std::time::Duration::from_secs(timeout), command.status()).await {
+        Ok(Ok(status)) => status,
+        Ok(Err(e)) => err!(format!("Unable to run the scan command: {e}")),
+        Err(_) => err!("The scan did not finish in time"),
+    };
+
+    match status.code() {
+        Some(0) => Ok(ScanResult::Clean),
+        Some(1) => {
+            warn!("Attachment {} was flagged by the virus scanner", path.display());
+            Ok(ScanResult::Flagged)
+        }
+        _ => err!(format!("The scan command failed with {status}")),
+    }
+}
+
 /// v2 API for uploading the actual data content of an attachment.
 /// This route needs a rank specified so that Rocket prioritizes the
 /// /ciphers/<uuid>/attachment/v2 route, which would otherwise conflict
@@ -1212,7 +2071,7 @@ async fn save_attachment(
 async fn post_attachment_v2_data(
     uuid: &str,
     attachment_id: &str,
-    data: Form<UploadData<'_>>,
+    data: MultipartForm<UploadData<'_>>,
     headers: Headers,
     mut conn: DbConn,
     nt: Notify<'_>,
@@ -1232,7 +2091,7 @@ async fn post_attachment_v2_data(
 #[post("/ciphers/<uuid>/attachment", format = "multipart/form-data", data = "<data>")]
 async fn post_attachment(
     uuid: &str,
-    data: Form<UploadData<'_>>,
+    data: MultipartForm<UploadData<'_>>,
     headers: Headers,
     conn: DbConn,
     nt: Notify<'_>,
@@ -1249,7 +2108,7 @@ async fn post_attachment(
 #[post("/ciphers/<uuid>/attachment-admin", format = "multipart/form-data", data = "<data>")]
 async fn post_attachment_admin(
     uuid: &str,
-    data: Form<UploadData<'_>>,
+    data: MultipartForm<UploadData<'_>>,
     headers: Headers,
     conn: DbConn,
     nt: Notify<'_>,
@@ -1261,7 +2120,7 @@ async fn post_attachment_admin(
 async fn post_attachment_share(
     uuid: &str,
     attachment_id: &str,
-    data: Form<UploadData<'_>>,
+    data: MultipartForm<UploadData<'_>>,
     headers: Headers,
     mut conn: DbConn,
     nt: Notify<'_>,
@@ -1409,6 +2268,162 @@ async fn delete_cipher_selected_put_admin(
     _delete_multiple_ciphers(data, headers, conn, true, nt).await // soft delete
 }
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct BulkDeleteCipherData {
+    Ids: Vec<String>,
+    // Soft-deletes the ciphers into the trash unless set
+    Permanent: Option<bool>,
+}
+
+#[derive(Debug, PartialEq)]
+enum BulkDeleteStatus {
+    Deleted,
+    NotFound,
+    Forbidden,
+}
+
+impl BulkDeleteStatus {
+    fn error(&self) -> Option<&'static str> {
+        match self {
+            BulkDeleteStatus::Deleted => None,
+            BulkDeleteStatus::NotFound => Some("Cipher doesn't exist"),
+            BulkDeleteStatus::Forbidden => Some("Cipher can't be deleted by user"),
+        }
+    }
+}
+
+/// Decides per requested id whether it gets deleted, ids which are requested more than once are only reported once.
+fn bulk_delete_statuses(
+    ids: &[String],
+    found: &HashSet<&str>,
+    deletable: &HashSet<&str>,
+) -> Vec<(String, BulkDeleteStatus)> {
+    let mut seen = HashSet::new();
+    ids.iter()
+        .filter(|id| seen.insert(id.as_str()))
+        .map(|id| {
+            let status = if !found.contains(id.as_str()) {
+                BulkDeleteStatus::NotFound
+            } else if deletable.contains(id.as_str()) {
+                BulkDeleteStatus::Deleted
+            } else {
+                BulkDeleteStatus::Forbidden
+            };
+            (id.clone(), status)
+        })
+        .collect()
+}
+
+/// Deletes all requested ciphers the user is allowed to delete and reports the result per id.
+/// Instead of a notification per cipher, every affected user receives a single vault sync.
+#[post("/ciphers/bulk-delete", data = "<data>")]
+async fn delete_cipher_bulk(
+    data: JsonUpcase<BulkDeleteCipherData>,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
+    let data: BulkDeleteCipherData = data.into_inner().data;
+    let soft_delete = !data.Permanent.unwrap_or(false);
+
+    let mut ciphers = Vec::with_capacity(data.Ids.len());
+    for uuid in data.Ids.iter().collect::<HashSet<_>>() {
+        if let Some(cipher) = Cipher::find_by_uuid(uuid, &mut conn).await {
+            ciphers.push(cipher);
+        }
+    }
+
+    // Ciphers of organizations under legal hold can only be moved to the trash
+    let held_orgs = match soft_delete {
+        true => Vec::new(),
+        false => Organization::find_legal_hold_uuids(&mut conn).await,
+    };
+
+    let mut deletable = HashSet::new();
+    for cipher in &ciphers {
+        if cipher.is_write_accessible_to_user(&headers.user.uuid, &mut conn).await
+            && !cipher.is_shared_with_user(&headers.user.uuid, &mut conn).await
+            && cipher.is_permanently_deletable(&held_orgs)
+        {
+            deletable.insert(cipher.uuid.as_str());
+        }
+    }
+    let found: HashSet<&str> = ciphers.iter().map(|c| c.uuid.as_str()).collect();
+    let statuses = bulk_delete_statuses(&data.Ids, &found, &deletable);
+
+    let deleted: Vec<Cipher> = ciphers
+        .into_iter()
+        .filter(|c| statuses.iter().any(|(id, status)| id == &c.uuid && *status == BulkDeleteStatus::Deleted))
+        .collect();
+
+    // Either all ciphers are deleted, or none
+    begin_transaction(&mut conn).await?;
+    let result = _delete_ciphers_in_bulk(deleted, soft_delete, &headers, &mut conn).await;
+    let affected_users = finish_transaction(result, &mut conn).await?;
+
+    for user_uuid in affected_users {
+        if let Some(user) = User::find_by_uuid(&user_uuid, &mut conn).await {
+            nt.send_user_update(UpdateType::SyncVault, &user).await;
+        }
+    }
+
+    let results: Vec<Value> = statuses
+        .iter()
+        .map(|(id, status)| {
+            json!({
+                "Id": id,
+                "Deleted": *status == BulkDeleteStatus::Deleted,
+                "Error": status.error(),
+            })
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "Data": results,
+        "Object": "list",
+        "ContinuationToken": null
+    })))
+}
+
+// Returns the users who can no longer see the deleted ciphers, collected before the collections and shares are removed
+async fn _delete_ciphers_in_bulk(
+    ciphers: Vec<Cipher>,
+    soft_delete: bool,
+    headers: &Headers,
+    conn: &mut DbConn,
+) -> ApiResult<HashSet<String>> {
+    let mut affected_users = HashSet::new();
+    for mut cipher in ciphers {
+        affected_users.extend(cipher.update_users_revision(conn).await);
+        if soft_delete {
+            cipher.deleted_at = Some(Utc::now().naive_utc());
+            cipher.save(conn).await?;
+        } else {
+            cipher.delete(conn).await?;
+        }
+
+        if let Some(org_uuid) = cipher.organization_uuid {
+            let event_type = match soft_delete {
+                true => EventType::CipherSoftDeleted as i32,
+                false => EventType::CipherDeleted as i32,
+            };
+
+            log_event(
+                event_type,
+                &cipher.uuid,
+                &org_uuid,
+                &headers.user.uuid,
+                headers.device.atype,
+                &headers.ip.ip,
+                conn,
+            )
+            .await;
+        }
+    }
+    Ok(affected_users)
+}
+
 #[put("/ciphers/<uuid>/restore")]
 async fn restore_cipher_put(uuid: &str, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> JsonResult {
     _restore_cipher_by_uuid(uuid, &headers, &mut conn, &nt).await
@@ -1520,6 +2535,9 @@ async fn delete_all(
                 None => err!("You don't have permission to purge the organization vault"),
                 Some(user_org) => {
                     if user_org.atype == UserOrgType::Owner {
+                        if Organization::find_legal_hold_uuids(&mut conn).await.contains(&org_data.org_id) {
+                            err!("This organization is under legal hold, its vault can't be purged")
+                        }
                         Cipher::delete_all_by_organization(&org_data.org_id, &mut conn).await?;
                         nt.send_user_update(UpdateType::SyncVault, &user).await;
 
@@ -1573,10 +2591,16 @@ async fn _delete_cipher_by_uuid(
         None => err!("Cipher doesn't exist"),
     };
 
-    if !cipher.is_write_accessible_to_user(&headers.user.uuid, conn).await {
+    if !cipher.is_write_accessible_to_user(&headers.user.uuid, conn).await
+        || cipher.is_shared_with_user(&headers.user.uuid, conn).await
+    {
         err!("Cipher can't be deleted by user")
     }
 
+    if !soft_delete {
+        cipher.check_permanently_deletable(conn).await?;
+    }
+
     if soft_delete {
         cipher.deleted_at = Some(Utc::now().naive_utc());
         cipher.save(conn).await?;
@@ -1647,7 +2671,9 @@ async fn _restore_cipher_by_uuid(uuid: &str, headers: &Headers, conn: &mut DbCon
         None => err!("Cipher doesn't exist"),
     };
 
-    if !cipher.is_write_accessible_to_user(&headers.user.uuid, conn).await {
+    if !cipher.is_write_accessible_to_user(&headers.user.uuid, conn).await
+        || cipher.is_shared_with_user(&headers.user.uuid, conn).await
+    {
         err!("Cipher can't be restored by user")
     }
 
@@ -1736,6 +2762,11 @@ async fn _delete_cipher_attachment_by_id(
         err!("Cipher cannot be deleted by user")
     }
 
+    // Like the ciphers, their attachments are kept while the organization is under legal hold
+    if !cipher.is_permanently_deletable(&Organization::find_legal_hold_uuids(conn).await) {
+        err!("This item belongs to an organization under legal hold, its attachments can't be deleted")
+    }
+
     // Delete attachment
     attachment.delete(conn).await?;
     nt.send_cipher_update(
@@ -1776,6 +2807,9 @@ pub struct CipherSyncData {
     pub user_collections: HashMap<String, CollectionUser>,
     pub user_collections_groups: HashMap<String, CollectionGroup>,
     pub user_group_full_access_for_organizations: HashSet<String>,
+    pub cipher_shares: HashMap<String, CipherShare>,
+    // Organizations the user is a member of, but can't access right now
+    pub restricted_orgs: HashSet<String>,
 }
 
 #[derive(Eq, PartialEq)]
@@ -1788,6 +2822,7 @@ impl CipherSyncData {
     pub async fn new(user_uuid: &str, sync_type: CipherSyncType, conn: &mut DbConn) -> Self {
         let cipher_folders: HashMap<String, String>;
         let cipher_favorites: HashSet<String>;
+        let cipher_shares: HashMap<String, CipherShare>;
         match sync_type {
             // User Sync supports Folders and Favorites
             CipherSyncType::User => {
@@ -1796,12 +2831,20 @@ impl CipherSyncData {
 
                 // Generate a HashSet of all the Cipher UUID's which are marked as favorite
                 cipher_favorites = Favorite::get_all_cipher_uuid_by_user(user_uuid, conn).await.into_iter().collect();
+
+                // Generate a HashMap with the Cipher UUID as key and the CipherShare record for personal ciphers shared with the user
+                cipher_shares = CipherShare::find_by_user(user_uuid, conn)
+                    .await
+                    .into_iter()
+                    .map(|share| (share.cipher_uuid.clone(), share))
+                    .collect();
             }
             // Organization Sync does not support Folders and Favorites.
             // If these are set, it will cause issues in the web-vault.
             CipherSyncType::Organization => {
                 cipher_folders = HashMap::with_capacity(0);
                 cipher_favorites = HashSet::with_capacity(0);
+                cipher_shares = HashMap::with_capacity(0);
             }
         }
 
@@ -1812,6 +2855,9 @@ impl CipherSyncData {
         for attachment in attachments {
             cipher_attachments.entry(attachment.cipher_uuid.clone()).or_default().push(attachment);
         }
+        for cipher_uuid in cipher_shares.keys() {
+            cipher_attachments.insert(cipher_uuid.clone(), Attachment::find_by_cipher(cipher_uuid, conn).await);
+        }
 
         // Generate a HashMap with the Cipher UUID as key and one or more Collection UUID's
         let user_cipher_collections = Cipher::get_collections_with_cipher_by_user(user_uuid.to_string(), conn).await;
@@ -1835,17 +2881,15 @@ impl CipherSyncData {
             .map(|uc| (uc.collection_uuid.clone(), uc))
             .collect();
 
-        // Generate a HashMap with the collections_uuid as key and the CollectionGroup record
+        // Generate a HashMap with the collections_uuid as key and the most permissive CollectionGroup record
         let user_collections_groups: HashMap<String, CollectionGroup> = if CONFIG.org_groups_enabled() {
-            CollectionGroup::find_by_user(user_uuid, conn)
-                .await
-                .into_iter()
-                .map(|collection_group| (collection_group.collections_uuid.clone(), collection_group))
-                .collect()
+            CollectionGroup::merge_by_collection(CollectionGroup::find_by_user(user_uuid, conn).await)
         } else {
             HashMap::new()
         };
 
+        let restricted_orgs = UserOrganization::find_restricted_org_uuids(user_uuid, conn).await;
+
         // Get all organizations that the user has full access to via group assignment
         let user_group_full_access_for_organizations: HashSet<String> = if CONFIG.org_groups_enabled() {
             Group::gather_user_organizations_full_access(user_uuid, conn).await.into_iter().collect()
@@ -1862,6 +2906,471 @@ impl CipherSyncData {
             user_collections,
             user_collections_groups,
             user_group_full_access_for_organizations,
+            cipher_shares,
+            restricted_orgs,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotated_cipher(id: &str, attachments: Value) -> CipherData {
+        serde_json::from_value(json!({
+            "Id": id,
+            "Type": 2,
+            "Name": "2.name",
+            "Attachments2": attachments,
+        }))
+        .unwrap()
+    }
+
+    fn attachment(id: &str, cipher_uuid: &str) -> Attachment {
+        Attachment::new(
+            id.to_string(),
+            cipher_uuid.to_string(),
+            String::from("2.old-name"),
+            10,
+            Some(String::from("2.old")),
+        )
+    }
+
+    #[test]
+    fn key_rotation_updates_attachment_keys() {
+        let attachments = vec![attachment("att-1", "cipher-1"), attachment("att-2", "cipher-1")];
+        let mut cipher = rotated_cipher(
+            "cipher-1",
+            json!({
+                "att-1": { "FileName": "2.new-name-1", "Key": "2.new-1" },
+                "att-2": { "FileName": "2.new-name-2", "Key": "2.new-2" },
+            }),
+        );
+        assert!(validate_rotated_attachments(std::slice::from_ref(&cipher), &attachments).is_ok());
+
+        let mut saved = attachment("att-1", "cipher-1");
+        cipher.Attachments2.take().unwrap().remove("att-1").unwrap().apply_to(&mut saved, 1000).unwrap();
+        assert_eq!(saved.akey.as_deref(), Some("2.new-1"));
+        assert_eq!(saved.file_name, "2.new-name-1");
+    }
+
+    #[test]
+    fn key_rotation_incomplete_attachments_rejected() {
+        let attachments = vec![attachment("att-1", "cipher-1"), attachment("att-2", "cipher-2")];
+        let cipher_1 = rotated_cipher("cipher-1", json!({ "att-1": { "FileName": "2.n", "Key": "2.k" } }));
+        let cipher_2 = rotated_cipher("cipher-2", json!(null));
+        assert!(validate_rotated_attachments(&[cipher_1, cipher_2], &attachments).is_err());
+
+        // Sent with another cipher
+        let cipher_2 = rotated_cipher("cipher-2", json!({ "att-1": { "FileName": "2.n", "Key": "2.k" } }));
+        assert!(validate_rotated_attachments(&[cipher_2], &attachments[..1]).is_err());
+    }
+
+    #[test]
+    #[cfg(sqlite)]
+    fn attachment_of_held_organization_not_deleted() {
+        use rocket::{http::Status, local::asynchronous::Client};
+
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let pool = DbPool::sqlite_in_memory();
+            let mut conn = pool.get().await.unwrap();
+            conn.insert_user("owner").await;
+            conn.batch_execute(
+                "INSERT INTO organizations (uuid, name, billing_email, legal_hold) VALUES \
+                ('held-org', 'Held', 'owner@example.com', 1), ('org', 'Org', 'owner@example.com', 0); \
+                INSERT INTO users_organizations (uuid, user_uuid, org_uuid, access_all, akey, status, atype) VALUES \
+                ('m1', 'owner', 'held-org', 1, '', 2, 0), ('m2', 'owner', 'org', 1, '', 2, 0); \
+                INSERT INTO ciphers (uuid, created_at, updated_at, organization_uuid, atype, name, data) VALUES \
+                ('held-cipher', '2024-01-01 00:00:00', '2024-01-01 00:00:00', 'held-org', 1, '2.name', '{}'), \
+                ('cipher', '2024-01-01 00:00:00', '2024-01-01 00:00:00', 'org', 1, '2.name', '{}'); \
+                INSERT INTO attachments (id, cipher_uuid, file_name, file_size) VALUES \
+                ('held-att', 'held-cipher', '2.file', 10), ('att', 'cipher', '2.file', 10)",
+            )
+            .await;
+            let authorization = Headers::test_authorization("owner", &mut conn).await;
+            drop(conn);
+
+            let rocket = rocket::build()
+                .manage(pool)
+                .manage(std::sync::Arc::clone(&crate::api::WS_USERS))
+                .mount("/api", routes![delete_attachment, delete_attachment_post_admin]);
+            let client = Client::untracked(rocket).await.unwrap();
+
+            let response = client
+                .delete("/api/ciphers/held-cipher/attachment/held-att")
+                .header(authorization.clone())
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::BadRequest);
+            let response = client
+                .post("/api/ciphers/held-cipher/attachment/held-att/delete-admin")
+                .header(authorization.clone())
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::BadRequest);
+            let response = client.delete("/api/ciphers/cipher/attachment/att").header(authorization).dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+
+            let mut conn = client.rocket().state::<DbPool>().unwrap().get().await.unwrap();
+            assert!(Attachment::find_by_id("held-att", &mut conn).await.is_some());
+            assert!(Attachment::find_by_id("att", &mut conn).await.is_none());
+        });
+    }
+
+    #[test]
+    fn attachment_name_traversal_sanitized() {
+        assert_eq!(sanitize_attachment_name("../../etc/passwd", 1000).unwrap(), "etc/passwd");
+        assert_eq!(sanitize_attachment_name("..\\..\\boot.ini", 1000).unwrap(), "....boot.ini");
+        // Header injection through line breaks
+        assert_eq!(sanitize_attachment_name("name\r\nX-Injected: 1\0", 1000).unwrap(), "nameX-Injected: 1");
+
+        // Encrypted names contain base64 and are kept as is
+        let encrypted =
+            "2.ZAdhfv/AX2sXyXWFdSZ7lw==|mXPaCO//yu4RWqHnpHnQ0Q==|6FiHnjf6c+W4kVaW3NQ8/3mR1pAYa3bYyPBWWLZwmTw=";
+        assert_eq!(sanitize_attachment_name(encrypted, 1000).unwrap(), encrypted);
+
+        assert!(sanitize_attachment_name("/../\n", 1000).is_err());
+        assert!(sanitize_attachment_name(encrypted, 50).is_err());
+    }
+
+    #[test]
+    fn keyless_attachment_rejected() {
+        assert!(check_attachment_key(None, false).is_err());
+        assert!(check_attachment_key(None, true).is_err());
+        assert!(check_attachment_key(Some(""), true).is_err());
+        // Plaintext or malformed keys
+        assert!(check_attachment_key(Some("c2VjcmV0LWtleQ=="), true).is_err());
+        assert!(check_attachment_key(Some("2.aXY=|"), true).is_err());
+        assert!(check_attachment_key(Some("2.aXY=|ZGF0YQ==|not base64!"), true).is_err());
+        // Only the presence is checked without the option
+        assert!(check_attachment_key(Some(""), false).is_ok());
+    }
+
+    #[test]
+    fn encrypted_attachment_key_accepted() {
+        assert!(check_attachment_key(Some("2.aXY=|ZGF0YQ==|bWFj"), true).is_ok());
+        assert!(check_attachment_key(Some("0.aXY=|ZGF0YQ=="), true).is_ok());
+    }
+
+    #[test]
+    fn blocked_attachment_type_rejected() {
+        let blocked = "application/x-msdownload, application/x-executable";
+        assert!(check_attachment_type(Some("application/x-msdownload"), None, "", blocked).is_err());
+        // Declared as binary data, but recognized as an executable
+        let sniffed = sniff_mime_type(b"MZ\x90\x00\x03");
+        assert_eq!(sniffed, Some("application/x-msdownload"));
+        assert!(check_attachment_type(Some("application/octet-stream"), sniffed, "", blocked).is_err());
+        // An encrypted attachment starts with its encryption type
+        assert_eq!(sniff_mime_type(b"\x02\x8f\x1c"), None);
+        assert!(check_attachment_type(Some("application/octet-stream"), None, "", blocked).is_ok());
+    }
+
+    #[test]
+    fn attachment_type_allowlist() {
+        let allowed = "image/*, application/octet-stream";
+        assert!(check_attachment_type(Some("image/png; charset=binary"), None, allowed, "").is_ok());
+        assert!(check_attachment_type(None, None, allowed, "").is_ok());
+        assert!(check_attachment_type(Some("application/pdf"), None, allowed, "").is_err());
+        assert!(check_attachment_type(None, Some("application/pdf"), allowed, "").is_err());
+        assert!(check_attachment_type(Some("text/plain"), None, "", "").is_ok());
+    }
+
+    #[test]
+    fn transfer_approval_only_for_members_below_admin() {
+        assert!(needs_transfer_approval(Some(UserOrgType::User as i32)));
+        assert!(needs_transfer_approval(Some(UserOrgType::Manager as i32)));
+        assert!(!needs_transfer_approval(Some(UserOrgType::Admin as i32)));
+        assert!(!needs_transfer_approval(Some(UserOrgType::Owner as i32)));
+    }
+
+    #[test]
+    fn transfer_request_round_trips() {
+        let data = ShareCipherData {
+            Cipher: rotated_cipher("cipher", json!({"attachment": {"FileName": "2.name", "Key": "2.key"}})),
+            CollectionIds: vec![String::from("collection")],
+        };
+        let stored = serde_json::to_string(&data).unwrap();
+
+        let restored: ShareCipherData = serde_json::from_str(&stored).unwrap();
+        assert_eq!(restored.CollectionIds, data.CollectionIds);
+        assert_eq!(restored.Cipher.Name, data.Cipher.Name);
+        assert_eq!(restored.Cipher.Attachments2.unwrap()["attachment"].Key, "2.key");
+    }
+
+    #[test]
+    fn share_to_permitted_collection_allowed() {
+        let confirmed = Some(UserOrgStatus::Confirmed as i32);
+        assert!(check_share_targets(confirmed, &[ShareTarget::Writable]).is_ok());
+        assert!(check_share_targets(confirmed, &[ShareTarget::Writable, ShareTarget::Writable]).is_ok());
+    }
+
+    #[test]
+    fn share_to_unauthorized_collection_rejected() {
+        let confirmed = Some(UserOrgStatus::Confirmed as i32);
+        assert!(check_share_targets(confirmed, &[ShareTarget::Writable, ShareTarget::ReadOnly]).is_err());
+        assert!(check_share_targets(confirmed, &[ShareTarget::Missing]).is_err());
+        assert!(check_share_targets(confirmed, &[]).is_err());
+
+        // Members who aren't confirmed can't share into collections they're assigned to
+        assert!(check_share_targets(Some(UserOrgStatus::Accepted as i32), &[ShareTarget::Writable]).is_err());
+        assert!(check_share_targets(None, &[ShareTarget::Writable]).is_err());
+    }
+
+    #[test]
+    fn member_cannot_remove_last_collection() {
+        let user = Some(UserOrgType::User as i32);
+        let manager = Some(UserOrgType::Manager as i32);
+        assert!(check_collection_count(0, user, true).is_err());
+        assert!(check_collection_count(0, manager, true).is_err());
+        assert!(check_collection_count(0, None, true).is_err());
+        assert!(check_collection_count(1, user, true).is_ok());
+
+        // Admins and owners can see items without collections
+        assert!(check_collection_count(0, Some(UserOrgType::Admin as i32), true).is_ok());
+        assert!(check_collection_count(0, Some(UserOrgType::Owner as i32), true).is_ok());
+        assert!(check_collection_count(0, user, false).is_ok());
+    }
+
+    #[test]
+    fn bulk_delete_mixed_permissions() {
+        let ids: Vec<String> = ["own", "shared-read-only", "own", "missing", "org-writable"].map(String::from).to_vec();
+        let found = HashSet::from(["own", "shared-read-only", "org-writable"]);
+        let deletable = HashSet::from(["own", "org-writable"]);
+
+        assert_eq!(
+            bulk_delete_statuses(&ids, &found, &deletable),
+            vec![
+                (String::from("own"), BulkDeleteStatus::Deleted),
+                (String::from("shared-read-only"), BulkDeleteStatus::Forbidden),
+                (String::from("missing"), BulkDeleteStatus::NotFound),
+                (String::from("org-writable"), BulkDeleteStatus::Deleted),
+            ]
+        );
+    }
+
+    #[test]
+    fn bulk_delete_all_forbidden() {
+        let ids: Vec<String> = vec![String::from("a"), String::from("b")];
+        let found = HashSet::from(["a", "b"]);
+        let statuses = bulk_delete_statuses(&ids, &found, &HashSet::new());
+        assert!(statuses.iter().all(|(_, status)| *status == BulkDeleteStatus::Forbidden));
+        assert_eq!(statuses[0].1.error(), Some("Cipher can't be deleted by user"));
+    }
+
+    const ENCRYPTED_EXPORT: &str = r#"{
+        "encrypted": true,
+        "encKeyValidation_DO_NOT_EDIT": "2.aXY=|ZGF0YQ==|bWFj",
+        "folders": [
+            { "id": "f1", "name": "2.folder-one|data|mac" },
+            { "id": "f2", "name": "2.folder-two|data|mac" }
+        ],
+        "items": [
+            {
+                "id": "c1",
+                "organizationId": null,
+                "folderId": "f2",
+                "type": 1,
+                "reprompt": 0,
+                "name": "2.login|data|mac",
+                "notes": null,
+                "favorite": true,
+                "login": { "username": "2.user|data|mac", "password": "2.pass|data|mac", "uris": [] },
+                "collectionIds": null
+            },
+            {
+                "id": "c2",
+                "folderId": null,
+                "type": 2,
+                "name": "2.note|data|mac",
+                "secureNote": { "type": 0 }
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn import_bitwarden_json() {
+        let data = parse_import_file("bitwarden_json", ENCRYPTED_EXPORT).unwrap();
+
+        assert_eq!(
+            data.Folders.iter().map(|f| f.Name.as_str()).collect::<Vec<_>>(),
+            ["2.folder-one|data|mac", "2.folder-two|data|mac"]
+        );
+        assert_eq!(data.Ciphers.len(), 2);
+        assert_eq!(data.Ciphers[0].Name, "2.login|data|mac");
+        assert_eq!(data.Ciphers[0].Favorite, Some(true));
+        assert!(data.Ciphers[0].Login.is_some());
+        assert!(data.Ciphers[1].SecureNote.is_some());
+
+        // The exported ids are never reused
+        assert!(data.Ciphers.iter().all(|c| c.Id.is_none() && c.FolderId.is_none()));
+
+        // The first item belongs to the second folder
+        assert_eq!(data.FolderRelationships.len(), 1);
+        assert_eq!((data.FolderRelationships[0].Key, data.FolderRelationships[0].Value), (0, 1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn attachment_scan_rejects_flagged() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("vw-attachment-scan-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let write_scanner = |name: &str, script: &str| {
+            let scanner = dir.join(name);
+            std::fs::write(&scanner, format!("#!/bin/sh\n{script}\n")).unwrap();
+            std::fs::set_permissions(&scanner, std::fs::Permissions::from_mode(0o755)).unwrap();
+            scanner.to_str().unwrap().to_string()
+        };
+
+        // A fake scanner which flags every file containing a known pattern
+        let scan_cmd = &write_scanner("scanner.sh", "grep -q 'KNOWN-BAD' \"$1\" && exit 1\nexit 0");
+
+        let clean = dir.join("clean");
+        std::fs::write(&clean, "2.encrypted|data|mac").unwrap();
+        let flagged = dir.join("flagged");
+        std::fs::write(&flagged, "KNOWN-BAD").unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        assert_eq!(runtime.block_on(scan_attachment(scan_cmd, &clean, 10)).unwrap(), ScanResult::Clean);
+        assert_eq!(runtime.block_on(scan_attachment(scan_cmd, &flagged, 10)).unwrap(), ScanResult::Flagged);
+
+        // A scanner which can't be run or fails is an error
+        assert!(runtime.block_on(scan_attachment("/nonexistent/scanner", &clean, 10)).is_err());
+        assert!(runtime.block_on(scan_attachment(&write_scanner("failing.sh", "exit 2"), &clean, 10)).is_err());
+        assert!(runtime.block_on(scan_attachment(&write_scanner("slow.sh", "sleep 5"), &clean, 1)).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn import_requires_encrypted_export() {
+        let unencrypted = ENCRYPTED_EXPORT.replace(r#""encrypted": true"#, r#""encrypted": false"#);
+        assert!(parse_import_file("bitwarden_json", &unencrypted).is_err());
+
+        let password_protected =
+            ENCRYPTED_EXPORT.replace(r#""encrypted": true,"#, r#""encrypted": true, "passwordProtected": true,"#);
+        assert!(parse_import_file("bitwarden_json", &password_protected).is_err());
+
+        assert!(parse_import_file("lastpass_csv", "url,username,password").is_err());
+        assert!(parse_import_file("onepassword_1pux", "").is_err());
+        assert!(parse_import_file("keepass_xml", ENCRYPTED_EXPORT).is_err());
+        assert!(parse_import_file("bitwarden_json", "not json").is_err());
+    }
+
+    #[test]
+    fn import_requires_key_validation() {
+        let marker = r#""encKeyValidation_DO_NOT_EDIT": "2.aXY=|ZGF0YQ==|bWFj","#;
+        assert!(parse_import_file("bitwarden_json", &ENCRYPTED_EXPORT.replace(marker, "")).is_err());
+
+        let edited = ENCRYPTED_EXPORT.replace(marker, r#""encKeyValidation_DO_NOT_EDIT": "validation","#);
+        assert!(parse_import_file("bitwarden_json", &edited).is_err());
+    }
+
+    fn reprompt_policy(enabled: bool, data: &str) -> OrgPolicy {
+        let mut policy = OrgPolicy::new(String::from("org"), OrgPolicyType::EnforcedReprompt, data.to_string());
+        policy.enabled = enabled;
+        policy
+    }
+
+    #[test]
+    fn enforced_reprompt_on_new_org_login() {
+        let mut cipher = Cipher::new(1, String::from("2.login"));
+        cipher.organization_uuid = Some(String::from("org"));
+        cipher.reprompt = Some(RepromptType::None as i32);
+
+        enforce_reprompt_policy(&mut cipher, Some(&reprompt_policy(true, r#"{"CipherTypes":[1]}"#)));
+        assert_eq!(cipher.reprompt, Some(RepromptType::Password as i32));
+    }
+
+    #[test]
+    fn enforced_reprompt_not_applicable() {
+        // Only logins are affected, so a secure note is left alone
+        let mut note = Cipher::new(2, String::from("2.note"));
+        enforce_reprompt_policy(&mut note, Some(&reprompt_policy(true, r#"{"CipherTypes":[1]}"#)));
+        assert_eq!(note.reprompt, None);
+
+        // A disabled policy doesn't change anything
+        let mut login = Cipher::new(1, String::from("2.login"));
+        enforce_reprompt_policy(&mut login, Some(&reprompt_policy(false, "null")));
+        enforce_reprompt_policy(&mut login, None);
+        assert_eq!(login.reprompt, None);
+
+        enforce_reprompt_policy(&mut login, Some(&reprompt_policy(true, "null")));
+        assert_eq!(login.reprompt, Some(RepromptType::Password as i32));
+    }
+
+    fn cipher_data(notes: &str) -> CipherData {
+        serde_json::from_value(json!({
+            "Type": 2,
+            "Name": "2.name",
+            "Notes": notes,
+            "SecureNote": {"Type": 0},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn cipher_notes_size_boundary() {
+        assert!(check_cipher_size_limits(&cipher_data(&"a".repeat(100)), Some(50), 100).is_ok());
+        // One byte over the limit is rejected
+        assert!(check_cipher_size_limits(&cipher_data(&"a".repeat(101)), Some(50), 100).is_err());
+    }
+
+    #[test]
+    fn cipher_field_size_limit() {
+        let mut data = cipher_data("2.notes");
+        data.Login = Some(json!({"Username": "a".repeat(50), "Uris": [{"Uri": "a".repeat(50)}]}));
+        assert!(check_cipher_size_limits(&data, Some(50), 100).is_ok());
+
+        data.Fields = Some(json!([{"Name": "2.name", "Value": "a".repeat(51), "Type": 0}]));
+        assert!(check_cipher_size_limits(&data, Some(50), 100).is_err());
+        // Without a field limit only the notes are limited
+        assert!(check_cipher_size_limits(&data, None, 100).is_ok());
+    }
+
+    #[test]
+    fn orphaned_attachment_file_removed() {
+        let folder = std::env::temp_dir().join(format!("vw-orphans-{}", crate::util::get_uuid()));
+        let old = SystemTime::now() - Duration::from_secs(48 * 3600);
+        let create = |path: &str, modified: SystemTime| {
+            let path = folder.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::File::create(&path).unwrap().set_modified(modified).unwrap();
+        };
+        create("cipher-a/in-use", old);
+        create("cipher-a/orphan", old);
+        create("cipher-b/orphan", old);
+        create("cipher-c/uploading", SystemTime::now());
+        create("branding/org/logo.png", old);
+
+        let known = HashSet::from([String::from("cipher-a/in-use")]);
+        let cutoff = SystemTime::now() - Duration::from_secs(24 * 3600);
+
+        // A dry run only reports the orphans
+        let mut orphans = remove_orphaned_attachment_files(&folder, &known, cutoff, true).unwrap();
+        orphans.sort();
+        assert_eq!(orphans, vec![folder.join("cipher-a/orphan"), folder.join("cipher-b/orphan")]);
+        assert!(folder.join("cipher-a/orphan").exists());
+
+        remove_orphaned_attachment_files(&folder, &known, cutoff, false).unwrap();
+        assert!(!folder.join("cipher-a/orphan").exists());
+        assert!(!folder.join("cipher-b").exists());
+        // In-use files, recent uploads and logos are kept
+        assert!(folder.join("cipher-a/in-use").exists());
+        assert!(folder.join("cipher-c/uploading").exists());
+        assert!(folder.join("branding/org/logo.png").exists());
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn attachment_count_boundary() {
+        assert!(check_attachment_count(2, Some(3)).is_ok());
+        // The attachment which would exceed the limit is rejected
+        assert!(check_attachment_count(3, Some(3)).is_err());
+        assert!(check_attachment_count(0, Some(0)).is_err());
+        assert!(check_attachment_count(10_000, None).is_ok());
+    }
+}
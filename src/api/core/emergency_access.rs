@@ -1,13 +1,18 @@
 use chrono::{TimeDelta, Utc};
+use num_traits::FromPrimitive;
 use rocket::{serde::json::Json, Route};
 use serde_json::Value;
 
 use crate::{
     api::{
-        core::{CipherSyncData, CipherSyncType},
-        EmptyResult, JsonResult, JsonUpcase,
+        core::{
+            two_factor::{authenticator, email, yubikey},
+            CipherSyncData, CipherSyncType,
+        },
+        ApiResult, EmptyResult, JsonResult, JsonUpcase,
     },
-    auth::{decode_emergency_access_invite, Headers},
+    auth::{decode_emergency_access_invite, ClientIp, Headers},
+    config::Feature,
     db::{models::*, DbConn, DbPool},
     mail,
     util::NumberOrString,
@@ -32,8 +37,11 @@ pub fn routes() -> Vec<Route> {
         reject_emergency_access,
         takeover_emergency_access,
         password_emergency_access,
+        send_takeover_email_token,
         view_emergency_access,
         policies_emergency_access,
+        get_emergency_access_quorum,
+        put_emergency_access_quorum,
     ]
 }
 
@@ -196,7 +204,7 @@ async fn send_invite(data: JsonUpcase<EmergencyAccessInviteData>, headers: Heade
     check_emergency_access_enabled()?;
 
     let data: EmergencyAccessInviteData = data.into_inner().data;
-    let email = data.Email.to_lowercase();
+    let email = normalize_email(&data.Email);
     let wait_time_days = data.WaitTimeDays;
 
     let emergency_access_status = EmergencyAccessStatus::Invited as i32;
@@ -363,7 +371,10 @@ async fn accept_invite(emer_id: &str, data: JsonUpcase<AcceptData>, headers: Hea
     {
         emergency_access.accept_invite(&grantee_user.uuid, &grantee_user.email, &mut conn).await?;
 
-        if CONFIG.mail_enabled() {
+        if CONFIG.mail_enabled()
+            && NotificationPreference::is_enabled(&grantor_user.uuid, UserNotification::EmergencyAccess, &mut conn)
+                .await
+        {
             mail::send_emergency_access_invite_accepted(&grantor_user.email, &grantee_user.email).await?;
         }
 
@@ -420,7 +431,10 @@ async fn confirm_emergency_access(
 
         emergency_access.save(&mut conn).await?;
 
-        if CONFIG.mail_enabled() {
+        if CONFIG.mail_enabled()
+            && NotificationPreference::is_enabled(&grantee_user.uuid, UserNotification::EmergencyAccess, &mut conn)
+                .await
+        {
             mail::send_emergency_access_invite_confirmed(&grantee_user.email, &grantor_user.name).await?;
         }
         Ok(Json(emergency_access.to_json()))
@@ -604,6 +618,7 @@ async fn takeover_emergency_access(emer_id: &str, headers: Headers, mut conn: Db
     if !is_valid_request(&emergency_access, &requesting_user.uuid, EmergencyAccessType::Takeover) {
         err!("Emergency access not valid.")
     }
+    check_takeover_quorum(&emergency_access.grantor_uuid, &mut conn).await?;
 
     let grantor_user = match User::find_by_uuid(&emergency_access.grantor_uuid, &mut conn).await {
         Some(user) => user,
@@ -627,6 +642,9 @@ async fn takeover_emergency_access(emer_id: &str, headers: Headers, mut conn: Db
 struct EmergencyAccessPasswordData {
     NewMasterPasswordHash: String,
     Key: String,
+    // Only needed when `EMERGENCY_ACCESS_TAKEOVER_REQUIRE_2FA` is enabled, this is a provider of the grantee
+    TwoFactorProvider: Option<i32>,
+    TwoFactorToken: Option<String>,
 }
 
 #[post("/emergency-access/<emer_id>/password", data = "<data>")]
@@ -634,6 +652,7 @@ async fn password_emergency_access(
     emer_id: &str,
     data: JsonUpcase<EmergencyAccessPasswordData>,
     headers: Headers,
+    ip: ClientIp,
     mut conn: DbConn,
 ) -> EmptyResult {
     check_emergency_access_enabled()?;
@@ -651,12 +670,22 @@ async fn password_emergency_access(
     if !is_valid_request(&emergency_access, &requesting_user.uuid, EmergencyAccessType::Takeover) {
         err!("Emergency access not valid.")
     }
+    check_takeover_quorum(&emergency_access.grantor_uuid, &mut conn).await?;
 
     let mut grantor_user = match User::find_by_uuid(&emergency_access.grantor_uuid, &mut conn).await {
         Some(user) => user,
         None => err!("Grantor user not found."),
     };
 
+    let require_2fa = CONFIG.emergency_access_takeover_require_2fa();
+    if require_2fa {
+        let grantee_providers = TwoFactor::find_enabled_types_by_user(&requesting_user.uuid, &mut conn).await;
+        let provider =
+            takeover_2fa_provider(&grantee_providers, data.TwoFactorProvider, data.TwoFactorToken.as_deref())?;
+        let token = data.TwoFactorToken.as_deref().unwrap_or_default();
+        validate_takeover_2fa(&requesting_user, provider, token, &ip, &mut conn).await?;
+    }
+
     // change grantor_user password
     grantor_user.set_password(new_master_password_hash, Some(data.Key), true, None);
     grantor_user.save(&mut conn).await?;
@@ -670,9 +699,86 @@ async fn password_emergency_access(
             user_org.delete(&mut conn).await?;
         }
     }
+
+    // The takeover is already done, failing to notify the grantor mustn't report it as failed
+    if require_2fa && CONFIG.mail_enabled() {
+        if let Err(e) = mail::send_emergency_access_takeover_completed(&grantor_user.email, &requesting_user.name).await
+        {
+            error!("Error sending emergency access takeover completed email: {:#?}", e);
+        }
+    }
     Ok(())
 }
 
+/// Sends an email 2FA token to the grantee, so it can be used to confirm the takeover.
+#[post("/emergency-access/<emer_id>/takeover/send-email")]
+async fn send_takeover_email_token(emer_id: &str, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    check_emergency_access_enabled()?;
+
+    if !CONFIG.emergency_access_takeover_require_2fa() {
+        err!("Two-step verification is not required for emergency access takeovers.")
+    }
+
+    let requesting_user = headers.user;
+    let emergency_access = match EmergencyAccess::find_by_uuid(emer_id, &mut conn).await {
+        Some(emer) => emer,
+        None => err!("Emergency access not valid."),
+    };
+
+    if !is_valid_request(&emergency_access, &requesting_user.uuid, EmergencyAccessType::Takeover) {
+        err!("Emergency access not valid.")
+    }
+    check_takeover_quorum(&emergency_access.grantor_uuid, &mut conn).await?;
+
+    email::send_token(&requesting_user.uuid, &mut conn).await
+}
+
+/// Providers the grantee can use to confirm a takeover, they all work with a single token.
+const TAKEOVER_2FA_PROVIDERS: [i32; 3] =
+    [TwoFactorType::Authenticator as i32, TwoFactorType::Email as i32, TwoFactorType::YubiKey as i32];
+
+/// Checks the submitted provider against the enabled providers of the grantee, without validating the token itself.
+fn takeover_2fa_provider(grantee_providers: &[i32], provider: Option<i32>, token: Option<&str>) -> ApiResult<i32> {
+    let usable = |atype: &i32| TAKEOVER_2FA_PROVIDERS.contains(atype);
+    if !grantee_providers.iter().any(usable) {
+        err!("You need to enable an authenticator app, email or YubiKey two-step login before taking over an account.")
+    }
+
+    let (Some(provider), Some(token)) = (provider, token) else {
+        err!("Two-step verification is required to take over this account.")
+    };
+    if token.is_empty() || !usable(&provider) || !grantee_providers.contains(&provider) {
+        err!("Invalid two-step verification provider.")
+    }
+
+    Ok(provider)
+}
+
+async fn validate_takeover_2fa(
+    user: &User,
+    provider: i32,
+    token: &str,
+    ip: &ClientIp,
+    conn: &mut DbConn,
+) -> EmptyResult {
+    // The codes are short, limit the guesses like on login
+    crate::ratelimit::check_limit_login(&ip.ip)?;
+
+    let twofactor = match TwoFactor::find_by_user_and_type(&user.uuid, provider, conn).await {
+        Some(tf) if tf.enabled => tf,
+        _ => err!("Invalid two-step verification provider."),
+    };
+
+    match TwoFactorType::from_i32(provider) {
+        Some(TwoFactorType::Authenticator) => {
+            authenticator::validate_totp_code_str(&user.uuid, token, &twofactor.data, ip, conn).await
+        }
+        Some(TwoFactorType::Email) => email::validate_email_code_str(&user.uuid, token, &twofactor.data, conn).await,
+        Some(TwoFactorType::YubiKey) => yubikey::validate_yubikey_login(token, &twofactor.data).await,
+        _ => err!("Invalid two-step verification provider."),
+    }
+}
+
 // endregion
 
 #[get("/emergency-access/<emer_id>/policies")]
@@ -686,6 +792,7 @@ async fn policies_emergency_access(emer_id: &str, headers: Headers, mut conn: Db
     if !is_valid_request(&emergency_access, &requesting_user.uuid, EmergencyAccessType::Takeover) {
         err!("Emergency access not valid.")
     }
+    check_takeover_quorum(&emergency_access.grantor_uuid, &mut conn).await?;
 
     let grantor_user = match User::find_by_uuid(&emergency_access.grantor_uuid, &mut conn).await {
         Some(user) => user,
@@ -702,6 +809,45 @@ async fn policies_emergency_access(emer_id: &str, headers: Headers, mut conn: Db
     })))
 }
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct EmergencyAccessQuorumData {
+    Quorum: i32,
+}
+
+#[get("/emergency-access/quorum")]
+async fn get_emergency_access_quorum(headers: Headers, mut conn: DbConn) -> JsonResult {
+    check_emergency_access_enabled()?;
+
+    let quorum = EmergencyAccessQuorum::find_or_default(&headers.user.uuid, &mut conn).await;
+    Ok(Json(quorum.to_json(CONFIG.emergency_access_quorum_window_hours())))
+}
+
+#[put("/emergency-access/quorum", data = "<data>")]
+async fn put_emergency_access_quorum(
+    data: JsonUpcase<EmergencyAccessQuorumData>,
+    headers: Headers,
+    mut conn: DbConn,
+) -> JsonResult {
+    check_emergency_access_enabled()?;
+
+    let data: EmergencyAccessQuorumData = data.into_inner().data;
+    let grantees = EmergencyAccess::find_all_by_grantor_uuid(&headers.user.uuid, &mut conn)
+        .await
+        .iter()
+        .filter(|ea| {
+            ea.atype == EmergencyAccessType::Takeover as i32 && ea.status >= EmergencyAccessStatus::Confirmed as i32
+        })
+        .count();
+    if data.Quorum < 1 || usize::try_from(data.Quorum).unwrap_or(usize::MAX) > grantees.max(1) {
+        err!(format!("The quorum must be between 1 and the number of confirmed takeover grantees ({grantees})"))
+    }
+
+    let quorum = EmergencyAccessQuorum::new(headers.user.uuid, data.Quorum);
+    quorum.save(&mut conn).await?;
+    Ok(Json(quorum.to_json(CONFIG.emergency_access_quorum_window_hours())))
+}
+
 fn is_valid_request(
     emergency_access: &EmergencyAccess,
     requesting_user_uuid: &str,
@@ -713,6 +859,33 @@ fn is_valid_request(
         && emergency_access.atype == requested_access_type as i32
 }
 
+/// With a quorum, a takeover needs that many takeover grantees of the grantor who initiated a recovery close together
+async fn check_takeover_quorum(grantor_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+    let quorum = EmergencyAccessQuorum::find_or_default(grantor_uuid, conn).await;
+    if quorum.quorum <= 1 {
+        return Ok(());
+    }
+
+    let recovery_initiated_at: Vec<_> = EmergencyAccess::find_all_by_grantor_uuid(grantor_uuid, conn)
+        .await
+        .into_iter()
+        .filter(|ea| {
+            ea.atype == EmergencyAccessType::Takeover as i32
+                && (ea.status == EmergencyAccessStatus::RecoveryInitiated as i32
+                    || ea.status == EmergencyAccessStatus::RecoveryApproved as i32)
+        })
+        .filter_map(|ea| ea.recovery_initiated_at)
+        .collect();
+    let window_hours = CONFIG.emergency_access_quorum_window_hours();
+    if !quorum.is_met(&recovery_initiated_at, window_hours) {
+        err!(format!(
+            "A takeover of this account needs {} grantees to initiate emergency access within {window_hours} hours of each other.",
+            quorum.quorum
+        ))
+    }
+    Ok(())
+}
+
 fn check_emergency_access_enabled() -> EmptyResult {
     if !CONFIG.emergency_access_allowed() {
         err!("Emergency access is not enabled.")
@@ -722,7 +895,7 @@ fn check_emergency_access_enabled() -> EmptyResult {
 
 pub async fn emergency_request_timeout_job(pool: DbPool) {
     debug!("Start emergency_request_timeout_job");
-    if !CONFIG.emergency_access_allowed() {
+    if !CONFIG.is_feature_enabled(Feature::EmergencyAccess) {
         return;
     }
 
@@ -735,10 +908,7 @@ pub async fn emergency_request_timeout_job(pool: DbPool) {
 
         let now = Utc::now().naive_utc();
         for mut emer in emergency_access_list {
-            // The find_all_recoveries_initiated already checks if the recovery_initiated_at is not null (None)
-            let recovery_allowed_at =
-                emer.recovery_initiated_at.unwrap() + TimeDelta::try_days(i64::from(emer.wait_time_days)).unwrap();
-            if recovery_allowed_at.le(&now) {
+            if emer.is_recovery_due(now) {
                 // Only update the access status
                 // Updating the whole record could cause issues when the emergency_notification_reminder_job is also active
                 emer.update_access_status_and_save(EmergencyAccessStatus::RecoveryApproved as i32, &now, &mut conn)
@@ -777,7 +947,7 @@ pub async fn emergency_request_timeout_job(pool: DbPool) {
 
 pub async fn emergency_notification_reminder_job(pool: DbPool) {
     debug!("Start emergency_notification_reminder_job");
-    if !CONFIG.emergency_access_allowed() {
+    if !CONFIG.is_feature_enabled(Feature::EmergencyAccess) {
         return;
     }
 
@@ -833,3 +1003,30 @@ pub async fn emergency_notification_reminder_job(pool: DbPool) {
         error!("Failed to get DB connection while searching emergency notification reminder")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUTHENTICATOR: i32 = TwoFactorType::Authenticator as i32;
+    const EMAIL: i32 = TwoFactorType::Email as i32;
+    const WEBAUTHN: i32 = TwoFactorType::Webauthn as i32;
+
+    #[test]
+    fn takeover_requires_grantee_2fa() {
+        // The grantee has no usable provider
+        assert!(takeover_2fa_provider(&[], Some(AUTHENTICATOR), Some("123456")).is_err());
+        assert!(takeover_2fa_provider(&[WEBAUTHN], Some(WEBAUTHN), Some("123456")).is_err());
+        // The grantee has a provider, but didn't submit a token
+        assert!(takeover_2fa_provider(&[AUTHENTICATOR], None, None).is_err());
+        assert!(takeover_2fa_provider(&[AUTHENTICATOR], Some(AUTHENTICATOR), None).is_err());
+        assert!(takeover_2fa_provider(&[AUTHENTICATOR], Some(AUTHENTICATOR), Some("")).is_err());
+    }
+
+    #[test]
+    fn takeover_2fa_provider_must_be_enabled() {
+        assert!(takeover_2fa_provider(&[AUTHENTICATOR], Some(EMAIL), Some("123456")).is_err());
+        assert!(takeover_2fa_provider(&[AUTHENTICATOR, WEBAUTHN], Some(WEBAUTHN), Some("123456")).is_err());
+        assert!(matches!(takeover_2fa_provider(&[AUTHENTICATOR, EMAIL], Some(EMAIL), Some("123456")), Ok(EMAIL)));
+    }
+}
@@ -8,10 +8,10 @@ use crate::{
     api::{EmptyResult, JsonResult, JsonUpcaseVec},
     auth::{AdminHeaders, Headers},
     db::{
-        models::{Cipher, Event, UserOrganization},
+        models::{Cipher, Event, EventType, UserOrganization},
         DbConn, DbPool,
     },
-    util::parse_date,
+    util::{parse_date, stored_ip},
     CONFIG,
 };
 
@@ -125,7 +125,7 @@ async fn get_user_events(
     })))
 }
 
-fn get_continuation_token(events_json: &[Value]) -> Option<&str> {
+pub fn get_continuation_token(events_json: &[Value]) -> Option<&str> {
     // When the length of the vec equals the max page_size there probably is more data
     // When it is less, then all events are loaded.
     if events_json.len() as i64 == Event::PAGE_SIZE {
@@ -176,6 +176,7 @@ async fn post_events_collect(data: JsonUpcaseVec<EventCollection>, headers: Head
                     headers.device.atype,
                     Some(event_date),
                     &headers.ip.ip,
+                    None,
                     &mut conn,
                 )
                 .await;
@@ -223,7 +224,22 @@ pub async fn log_user_event(event_type: i32, user_uuid: &str, device_type: i32,
     if !CONFIG.org_events_enabled() {
         return;
     }
-    _log_user_event(event_type, user_uuid, device_type, None, ip, conn).await;
+    _log_user_event(event_type, user_uuid, device_type, None, ip, None, conn).await;
+}
+
+/// Logs a user event with a reason code, like why a login failed
+pub async fn log_user_event_with_detail(
+    event_type: i32,
+    user_uuid: &str,
+    device_type: i32,
+    ip: &IpAddr,
+    detail: Option<&str>,
+    conn: &mut DbConn,
+) {
+    if !CONFIG.org_events_enabled() {
+        return;
+    }
+    _log_user_event(event_type, user_uuid, device_type, None, ip, detail, conn).await;
 }
 
 async fn _log_user_event(
@@ -232,6 +248,7 @@ async fn _log_user_event(
     device_type: i32,
     event_date: Option<NaiveDateTime>,
     ip: &IpAddr,
+    detail: Option<&str>,
     conn: &mut DbConn,
 ) {
     let orgs = UserOrganization::get_org_uuid_by_user(user_uuid, conn).await;
@@ -242,7 +259,8 @@ async fn _log_user_event(
     event.user_uuid = Some(String::from(user_uuid));
     event.act_user_uuid = Some(String::from(user_uuid));
     event.device_type = Some(device_type);
-    event.ip_address = Some(ip.to_string());
+    event.ip_address = Some(stored_ip(ip));
+    event.detail = detail.map(String::from);
     events.push(event);
 
     // For each org a user is a member of store these events per org
@@ -252,7 +270,8 @@ async fn _log_user_event(
         event.org_uuid = Some(org_uuid);
         event.act_user_uuid = Some(String::from(user_uuid));
         event.device_type = Some(device_type);
-        event.ip_address = Some(ip.to_string());
+        event.ip_address = Some(stored_ip(ip));
+        event.detail = detail.map(String::from);
         events.push(event);
     }
 
@@ -285,6 +304,19 @@ async fn _log_event(
     ip: &IpAddr,
     conn: &mut DbConn,
 ) {
+    let event = new_org_event(event_type, source_uuid, org_uuid, act_user_uuid, device_type, event_date, stored_ip(ip));
+    event.save(conn).await.unwrap_or(());
+}
+
+fn new_org_event(
+    event_type: i32,
+    source_uuid: &str,
+    org_uuid: &str,
+    act_user_uuid: &str,
+    device_type: i32,
+    event_date: Option<NaiveDateTime>,
+    ip_address: String,
+) -> Event {
     // Create a new empty event
     let mut event = Event::new(event_type, event_date);
     match event_type {
@@ -317,8 +349,47 @@ async fn _log_event(
     event.org_uuid = Some(String::from(org_uuid));
     event.act_user_uuid = Some(String::from(act_user_uuid));
     event.device_type = Some(device_type);
-    event.ip_address = Some(ip.to_string());
-    event.save(conn).await.unwrap_or(());
+    event.ip_address = Some(ip_address);
+    event
+}
+
+/// The object an admin panel action changed
+pub enum AdminEventTarget<'a> {
+    None,
+    User(&'a str),
+    Organization(&'a str),
+}
+
+/// Logs an action done in the admin panel, with an identifier of the admin session which did it
+pub async fn log_admin_event(
+    event_type: EventType,
+    target: AdminEventTarget<'_>,
+    session: Option<&str>,
+    ip: &IpAddr,
+    conn: &mut DbConn,
+) {
+    if !CONFIG.admin_events_enabled() {
+        return;
+    }
+    new_admin_event(event_type, target, session, stored_ip(ip)).save(conn).await.unwrap_or(());
+}
+
+fn new_admin_event(
+    event_type: EventType,
+    target: AdminEventTarget<'_>,
+    session: Option<&str>,
+    ip_address: String,
+) -> Event {
+    let mut event = Event::new(event_type as i32, None);
+    match target {
+        AdminEventTarget::None => (),
+        AdminEventTarget::User(user_uuid) => event.user_uuid = Some(String::from(user_uuid)),
+        AdminEventTarget::Organization(org_uuid) => event.org_uuid = Some(String::from(org_uuid)),
+    }
+    event.device_type = Some(14); // Use UnknownBrowser type
+    event.ip_address = Some(ip_address);
+    event.detail = session.map(String::from);
+    event
 }
 
 pub async fn event_cleanup_job(pool: DbPool) {
@@ -334,3 +405,52 @@ pub async fn event_cleanup_job(pool: DbPool) {
         error!("Failed to get DB connection while trying to cleanup the events table")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_event_recorded_for_org() {
+        let event = new_org_event(
+            EventType::OrganizationClientExportedVault as i32,
+            "org-uuid",
+            "org-uuid",
+            "user-uuid",
+            9,
+            None,
+            String::from("192.0.2.10"),
+        );
+        assert_eq!(event.event_type, EventType::OrganizationClientExportedVault as i32);
+        assert_eq!(event.org_uuid.as_deref(), Some("org-uuid"));
+        assert_eq!(event.act_user_uuid.as_deref(), Some("user-uuid"));
+        assert_eq!(event.ip_address.as_deref(), Some("192.0.2.10"));
+        // Organization events don't reference any other object
+        assert!(event.cipher_uuid.is_none() && event.collection_uuid.is_none() && event.org_user_uuid.is_none());
+    }
+
+    #[test]
+    fn admin_user_delete_event_recorded() {
+        let event = new_admin_event(
+            EventType::AdminUserDeleted,
+            AdminEventTarget::User("user-uuid"),
+            Some("3f2a9c0d1e4b5a6c"),
+            String::from("192.0.2.10"),
+        );
+        assert_eq!(event.event_type, EventType::AdminUserDeleted as i32);
+        assert_eq!(event.user_uuid.as_deref(), Some("user-uuid"));
+        assert_eq!(event.detail.as_deref(), Some("3f2a9c0d1e4b5a6c"));
+        assert_eq!(event.ip_address.as_deref(), Some("192.0.2.10"));
+        // Admin events of users don't show up in any organization event log
+        assert!(event.org_uuid.is_none() && event.act_user_uuid.is_none());
+
+        let event = new_admin_event(
+            EventType::AdminOrganizationDeleted,
+            AdminEventTarget::Organization("org-uuid"),
+            None,
+            String::from("192.0.2.10"),
+        );
+        assert_eq!(event.org_uuid.as_deref(), Some("org-uuid"));
+        assert!(event.user_uuid.is_none() && event.detail.is_none());
+    }
+}
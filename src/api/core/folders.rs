@@ -4,7 +4,8 @@ use serde_json::Value;
 use crate::{
     api::{EmptyResult, JsonResult, JsonUpcase, Notify, UpdateType},
     auth::Headers,
-    db::{models::*, DbConn},
+    db::{models::*, DbConn, DbReadConn},
+    CONFIG,
 };
 
 pub fn routes() -> Vec<rocket::Route> {
@@ -12,7 +13,7 @@ pub fn routes() -> Vec<rocket::Route> {
 }
 
 #[get("/folders")]
-async fn get_folders(headers: Headers, mut conn: DbConn) -> Json<Value> {
+async fn get_folders(headers: Headers, mut conn: DbReadConn) -> Json<Value> {
     let folders = Folder::find_by_user(&headers.user.uuid, &mut conn).await;
     let folders_json: Vec<Value> = folders.iter().map(Folder::to_json).collect();
 
@@ -46,6 +47,8 @@ pub struct FolderData {
 #[post("/folders", data = "<data>")]
 async fn post_folders(data: JsonUpcase<FolderData>, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> JsonResult {
     let data: FolderData = data.into_inner().data;
+    check_folder_name(&data.Name, CONFIG.max_folder_name_length())?;
+    enforce_folder_count(&headers.user.uuid, 1, &mut conn).await?;
 
     let mut folder = Folder::new(headers.user.uuid, data.Name);
 
@@ -75,6 +78,7 @@ async fn put_folder(
     nt: Notify<'_>,
 ) -> JsonResult {
     let data: FolderData = data.into_inner().data;
+    check_folder_name(&data.Name, CONFIG.max_folder_name_length())?;
 
     let mut folder = match Folder::find_by_uuid(uuid, &mut conn).await {
         Some(folder) => folder,
@@ -115,3 +119,50 @@ async fn delete_folder(uuid: &str, headers: Headers, mut conn: DbConn, nt: Notif
     nt.send_folder_update(UpdateType::SyncFolderDelete, &folder, &headers.device.uuid, &mut conn).await;
     Ok(())
 }
+
+/// Fails when the encrypted folder name is longer than `max_length` characters
+pub fn check_folder_name(name: &str, max_length: usize) -> EmptyResult {
+    if name.len() > max_length {
+        err!(format!("The folder name exceeds the maximum encrypted value length of {max_length} characters"))
+    }
+    Ok(())
+}
+
+/// Fails when a user with `existing` folders can't create `new` more
+fn check_folder_count(existing: i64, new: i64, max_folders: Option<i64>) -> EmptyResult {
+    match max_folders {
+        Some(max) if existing + new > max => {
+            err!(format!("You can't have more than {max} folders. Delete some folders first"))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub async fn enforce_folder_count(user_uuid: &str, new: i64, conn: &mut DbConn) -> EmptyResult {
+    let Some(max_folders) = CONFIG.max_folders_per_user() else {
+        return Ok(());
+    };
+    check_folder_count(Folder::count_by_user(user_uuid, conn).await, new, Some(max_folders))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folder_count_limit() {
+        assert!(check_folder_count(9, 1, Some(10)).is_ok());
+        assert!(check_folder_count(10, 1, Some(10)).is_err());
+        // An import of several folders counts all of them
+        assert!(check_folder_count(5, 5, Some(10)).is_ok());
+        assert!(check_folder_count(5, 6, Some(10)).is_err());
+        assert!(check_folder_count(100_000, 1, None).is_ok());
+    }
+
+    #[test]
+    fn folder_name_length_limit() {
+        assert!(check_folder_name(&"a".repeat(1000), 1000).is_ok());
+        assert!(check_folder_name(&"a".repeat(1001), 1000).is_err());
+        assert!(check_folder_name("", 1000).is_ok());
+    }
+}
@@ -8,26 +8,34 @@ mod public;
 mod sends;
 pub mod two_factor;
 
-pub use accounts::purge_auth_requests;
-pub use ciphers::{purge_trashed_ciphers, CipherData, CipherSyncData, CipherSyncType};
+pub use accounts::{
+    access_log_cleanup_job, inactive_account_disable_job, key_rotation_reminder_job, purge_auth_requests,
+    unverified_account_purge_job,
+};
+pub use ciphers::{purge_orphaned_attachments, purge_trashed_ciphers, CipherData, CipherSyncData, CipherSyncType};
 pub use emergency_access::{emergency_notification_reminder_job, emergency_request_timeout_job};
-pub use events::{event_cleanup_job, log_event, log_user_event};
+pub use events::{
+    event_cleanup_job, get_continuation_token, log_admin_event, log_event, log_user_event, log_user_event_with_detail,
+    AdminEventTarget,
+};
+pub use organizations::purge_expired_org_invitations;
 pub use sends::purge_sends;
 
 pub fn routes() -> Vec<Route> {
     let mut eq_domains_routes = routes![get_eq_domains, post_eq_domains, put_eq_domains];
     let mut hibp_routes = routes![hibp_breach];
-    let mut meta_routes = routes![alive, now, version, config];
+    let mut meta_routes = routes![alive, now, version, config, announcement];
 
     let mut routes = Vec::new();
     routes.append(&mut accounts::routes());
     routes.append(&mut ciphers::routes());
-    routes.append(&mut emergency_access::routes());
+    routes.append(&mut with_feature(emergency_access::routes(), Feature::EmergencyAccess));
     routes.append(&mut events::routes());
     routes.append(&mut folders::routes());
     routes.append(&mut organizations::routes());
+    routes.append(&mut with_feature(organizations::org_creation_routes(), Feature::OrgCreation));
     routes.append(&mut two_factor::routes());
-    routes.append(&mut sends::routes());
+    routes.append(&mut with_feature(sends::routes(), Feature::Sends));
     routes.append(&mut public::routes());
     routes.append(&mut eq_domains_routes);
     routes.append(&mut hibp_routes);
@@ -46,14 +54,22 @@ pub fn events_routes() -> Vec<Route> {
 //
 // Move this somewhere else
 //
-use rocket::{serde::json::Json, serde::json::Value, Catcher, Route};
+use chrono::{DateTime, Utc};
+use rocket::{
+    http::Status,
+    route::{self, Handler},
+    serde::json::Json,
+    serde::json::Value,
+    Catcher, Data, Request, Route,
+};
 
 use crate::{
     api::{JsonResult, JsonUpcase, Notify, UpdateType},
     auth::Headers,
+    config::Feature,
     db::DbConn,
     error::Error,
-    util::{get_reqwest_client, parse_experimental_client_feature_flags},
+    util::{format_date, get_reqwest_client, parse_experimental_client_feature_flags},
 };
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -220,6 +236,89 @@ fn config() -> Json<Value> {
     }))
 }
 
+/// The announcement set by the admin, like upcoming maintenance. `null` when there is none
+#[get("/announcement")]
+fn announcement() -> Json<Value> {
+    let announcement = active_announcement(
+        &crate::CONFIG.announcement_message(),
+        &crate::CONFIG.announcement_severity(),
+        crate::CONFIG.announcement_expiration_date().as_deref(),
+        Utc::now(),
+    );
+    Json(announcement.unwrap_or(Value::Null))
+}
+
+/// Empty and expired announcements aren't served, an empty expiration date means the announcement doesn't expire
+fn active_announcement(
+    message: &str,
+    severity: &str,
+    expiration_date: Option<&str>,
+    now: DateTime<Utc>,
+) -> Option<Value> {
+    let message = message.trim();
+    if message.is_empty() {
+        return None;
+    }
+    let expiration_date = match expiration_date.map(str::trim).filter(|date| !date.is_empty()) {
+        Some(date) => Some(DateTime::parse_from_rfc3339(date).ok()?.with_timezone(&Utc)),
+        None => None,
+    };
+    if expiration_date.is_some_and(|date| date <= now) {
+        return None;
+    }
+
+    Some(json!({
+        "Message": message,
+        "Severity": severity,
+        "ExpirationDate": expiration_date.map(|date| format_date(&date.naive_utc())),
+        "Object": "announcement",
+    }))
+}
+
+/// Wraps the handlers of a feature's routes, which respond with a 404 Not Found as if they didn't exist
+/// while the feature is turned off with `DISABLED_FEATURES`. The setting is checked on every request,
+/// as it can be changed from the admin panel.
+fn with_feature(routes: Vec<Route>, feature: Feature) -> Vec<Route> {
+    gate_routes(routes, feature, |feature| crate::CONFIG.is_feature_disabled(feature))
+}
+
+fn gate_routes(routes: Vec<Route>, feature: Feature, is_disabled: fn(Feature) -> bool) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(FeatureHandler {
+                inner: route.handler,
+                feature,
+                is_disabled,
+            });
+            route
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+struct FeatureHandler {
+    inner: Box<dyn Handler>,
+    feature: Feature,
+    is_disabled: fn(Feature) -> bool,
+}
+
+#[rocket::async_trait]
+impl Handler for FeatureHandler {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        if (self.is_disabled)(self.feature) {
+            debug!(
+                "{} {} is unavailable, the {} feature is disabled",
+                request.method(),
+                request.uri().path(),
+                self.feature.name()
+            );
+            return route::Outcome::Error(Status::NotFound);
+        }
+        self.inner.handle(request, data).await
+    }
+}
+
 pub fn catchers() -> Vec<Catcher> {
     catchers![api_not_found]
 }
@@ -234,3 +333,74 @@ fn api_not_found() -> Json<Value> {
         }
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn active_announcement_served() {
+        let now = Utc::now();
+        let expiration_date = (now + chrono::TimeDelta::try_hours(2).unwrap()).to_rfc3339();
+        let announcement =
+            active_announcement(" Maintenance tonight ", "warning", Some(&expiration_date), now).unwrap();
+        assert_eq!(announcement["Message"], "Maintenance tonight");
+        assert_eq!(announcement["Severity"], "warning");
+        assert!(announcement["ExpirationDate"].is_string());
+
+        let announcement = active_announcement("Maintenance tonight", "info", Some(""), now).unwrap();
+        assert!(announcement["ExpirationDate"].is_null());
+    }
+
+    #[test]
+    fn expired_announcement_omitted() {
+        let now = Utc::now();
+        let expired = (now - chrono::TimeDelta::try_minutes(1).unwrap()).to_rfc3339();
+        assert!(active_announcement("Maintenance tonight", "info", Some(&expired), now).is_none());
+        assert!(active_announcement("  ", "info", None, now).is_none());
+    }
+
+    static GATE_CHECKS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    fn disabled(_feature: Feature) -> bool {
+        GATE_CHECKS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        true
+    }
+
+    #[test]
+    fn disabled_feature_unavailable() {
+        use rocket::local::blocking::Client;
+
+        let mut routes = gate_routes(sends::routes(), Feature::Sends, disabled);
+        routes.append(&mut gate_routes(emergency_access::routes(), Feature::EmergencyAccess, disabled));
+        routes.append(&mut gate_routes(organizations::org_creation_routes(), Feature::OrgCreation, disabled));
+        let requests: Vec<_> = routes
+            .iter()
+            .map(|route| {
+                let path = route.uri.path().split('/').map(|s| {
+                    if s.starts_with('<') {
+                        "id"
+                    } else {
+                        s
+                    }
+                });
+                (route.method, path.collect::<Vec<_>>().join("/"), route.format.clone())
+            })
+            .collect();
+        let rocket = rocket::build()
+            .manage(std::sync::Arc::clone(&crate::api::WS_USERS))
+            .manage(std::sync::Arc::clone(&crate::api::WS_ANONYMOUS_SUBSCRIPTIONS))
+            .mount("/", routes);
+        let client = Client::untracked(rocket).unwrap();
+
+        // Every endpoint of the features is reached and answers as if it didn't exist
+        for (method, path, format) in &requests {
+            let mut request = client.req(*method, path);
+            if let Some(format) = format {
+                request.add_header(rocket::http::ContentType(format.clone()));
+            }
+            assert_eq!(request.dispatch().status(), Status::NotFound, "{method} {path}");
+        }
+        assert_eq!(GATE_CHECKS.load(std::sync::atomic::Ordering::Relaxed), requests.len());
+    }
+}
@@ -1,4 +1,8 @@
+use std::collections::HashMap;
+
+use chrono::{Duration, NaiveDateTime, Utc};
 use num_traits::FromPrimitive;
+use rocket::fs::{NamedFile, TempFile};
 use rocket::serde::json::Json;
 use rocket::Route;
 use serde_json::Value;
@@ -6,20 +10,20 @@ use serde_json::Value;
 use crate::{
     api::{
         core::{log_event, two_factor, CipherSyncData, CipherSyncType},
-        EmptyResult, JsonResult, JsonUpcase, JsonUpcaseVec, JsonVec, Notify, PasswordOrOtpData, UpdateType,
+        EmptyResult, JsonResult, JsonUpcase, JsonUpcaseVec, JsonVec, MultipartForm, Notify, PasswordOrOtpData,
+        UpdateType,
     },
-    auth::{decode_invite, AdminHeaders, Headers, ManagerHeaders, ManagerHeadersLoose, OwnerHeaders},
-    db::{models::*, DbConn},
+    auth::{decode_invite, AdminHeaders, Headers, ManagerHeaders, ManagerHeadersLoose, OrgHeaders, OwnerHeaders},
+    db::{begin_transaction, finish_transaction, models::*, DbConn, DbPool},
     error::Error,
     mail,
-    util::{convert_json_key_lcase_first, NumberOrString},
+    util::{convert_json_key_lcase_first, format_date, NumberOrString},
     CONFIG,
 };
 
 pub fn routes() -> Vec<Route> {
     routes![
         get_organization,
-        create_organization,
         delete_organization,
         post_delete_organization,
         leave_organization,
@@ -31,6 +35,21 @@ pub fn routes() -> Vec<Route> {
         put_collection_users,
         put_organization,
         post_organization,
+        get_organization_seat_limit,
+        post_organization_force_sync,
+        get_device_approvals,
+        approve_device,
+        deny_device,
+        get_cipher_transfers,
+        approve_cipher_transfer,
+        reject_cipher_transfer,
+        get_organization_branding,
+        put_organization_branding,
+        post_organization_branding_logo,
+        delete_organization_branding_logo,
+        get_organization_branding_logo,
+        get_organization_invite_domains,
+        put_organization_invite_domains,
         post_organization_collections,
         delete_organization_collection_user,
         post_organization_collection_delete_user,
@@ -41,6 +60,7 @@ pub fn routes() -> Vec<Route> {
         bulk_delete_organization_collections,
         get_org_details,
         get_org_users,
+        get_org_users_inactive,
         send_invite,
         reinvite_user,
         bulk_reinvite_user,
@@ -50,6 +70,7 @@ pub fn routes() -> Vec<Route> {
         get_user,
         edit_user,
         put_organization_user,
+        put_organization_user_access_schedule,
         delete_user,
         bulk_delete_user,
         post_delete_user,
@@ -94,11 +115,18 @@ pub fn routes() -> Vec<Route> {
         get_reset_password_details,
         put_reset_password,
         get_org_export,
+        get_org_structure,
+        post_org_structure,
         api_key,
         rotate_api_key,
     ]
 }
 
+/// Gated separately from the other routes, see `DISABLED_FEATURES`
+pub fn org_creation_routes() -> Vec<Route> {
+    routes![create_organization]
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct OrgData {
@@ -288,6 +316,362 @@ async fn post_organization(
     Ok(Json(org.to_json()))
 }
 
+async fn seat_limit_json(org: &Organization, conn: &mut DbConn) -> Value {
+    json!({
+        "SeatLimit": org.seat_limit,
+        "SeatsUsed": UserOrganization::count_seats_by_org(&org.uuid, conn).await,
+        "Object": "organizationSeatLimit",
+    })
+}
+
+/// The seat limit is set by the server admin, see `admin::update_org_seat_limit`
+#[get("/organizations/<org_id>/seat-limit")]
+async fn get_organization_seat_limit(org_id: &str, _headers: OwnerHeaders, mut conn: DbConn) -> JsonResult {
+    match Organization::find_by_uuid(org_id, &mut conn).await {
+        Some(org) => Ok(Json(seat_limit_json(&org, &mut conn).await)),
+        None => err!("Can't find organization details"),
+    }
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OrganizationBrandingData {
+    DisplayName: Option<String>,
+    LogoUrl: Option<String>,
+}
+
+#[derive(FromForm)]
+struct BrandingLogoData<'f> {
+    data: TempFile<'f>,
+}
+
+const BRANDING_LOGO_MAX_BYTES: usize = 512 * 1024;
+const BRANDING_LOGO_MAX_SIZE: u32 = 1024;
+
+/// Checks an uploaded logo is a reasonably sized bitmap image, and returns its file extension.
+/// SVG images are refused, as they can contain scripts.
+fn check_branding_logo(bytes: &[u8]) -> Result<&'static str, Error> {
+    use image::io::Reader;
+    use std::io::Cursor;
+
+    if bytes.len() > BRANDING_LOGO_MAX_BYTES {
+        err!(format!("The logo can't be larger than {} KiB", BRANDING_LOGO_MAX_BYTES / 1024))
+    }
+    let extension = match crate::api::icons::get_icon_type(bytes) {
+        Some("png") => "png",
+        Some("jpeg") => "jpg",
+        Some("gif") => "gif",
+        Some("webp") => "webp",
+        _ => err!("The logo must be a PNG, JPEG, GIF or WebP image"),
+    };
+
+    // Only the header is read here, so this doesn't decode the whole image
+    let dimensions = Reader::new(Cursor::new(bytes)).with_guessed_format().ok().and_then(|r| r.into_dimensions().ok());
+    match dimensions {
+        Some((width, height)) if width <= BRANDING_LOGO_MAX_SIZE && height <= BRANDING_LOGO_MAX_SIZE => Ok(extension),
+        Some(_) => {
+            err!(format!("The logo can't be larger than {BRANDING_LOGO_MAX_SIZE}x{BRANDING_LOGO_MAX_SIZE} pixels"))
+        }
+        None => err!("The logo is not a valid image"),
+    }
+}
+
+async fn log_branding_updated(org_id: &str, headers: &AdminHeaders, conn: &mut DbConn) {
+    log_event(
+        EventType::OrganizationUpdated as i32,
+        org_id,
+        org_id,
+        &headers.user.uuid,
+        headers.device.atype,
+        &headers.ip.ip,
+        conn,
+    )
+    .await;
+}
+
+#[get("/organizations/<org_id>/branding")]
+async fn get_organization_branding(org_id: &str, headers: OrgHeaders, mut conn: DbConn) -> JsonResult {
+    match Organization::find_by_uuid(org_id, &mut conn).await {
+        Some(org) => Ok(Json(org.branding_json(&headers.host))),
+        None => err!("Can't find organization details"),
+    }
+}
+
+#[put("/organizations/<org_id>/branding", data = "<data>")]
+async fn put_organization_branding(
+    org_id: &str,
+    headers: AdminHeaders,
+    data: JsonUpcase<OrganizationBrandingData>,
+    mut conn: DbConn,
+) -> JsonResult {
+    let data: OrganizationBrandingData = data.into_inner().data;
+
+    let Some(mut org) = Organization::find_by_uuid(org_id, &mut conn).await else {
+        err!("Can't find organization details")
+    };
+
+    org.set_branding(data.DisplayName, data.LogoUrl)?;
+    // A logo URL replaces the uploaded logo
+    let replaced_file = if org.branding_logo_url.is_some() {
+        org.branding_logo_file.take()
+    } else {
+        None
+    };
+    org.save(&mut conn).await?;
+    org.remove_branding_logo_file(replaced_file);
+    log_branding_updated(org_id, &headers, &mut conn).await;
+
+    Ok(Json(org.branding_json(&headers.host)))
+}
+
+#[post("/organizations/<org_id>/branding/logo", format = "multipart/form-data", data = "<data>")]
+async fn post_organization_branding_logo(
+    org_id: &str,
+    headers: AdminHeaders,
+    data: MultipartForm<BrandingLogoData<'_>>,
+    mut conn: DbConn,
+) -> JsonResult {
+    let mut data = data.into_inner().data;
+
+    let Some(mut org) = Organization::find_by_uuid(org_id, &mut conn).await else {
+        err!("Can't find organization details")
+    };
+
+    if data.data.len() > BRANDING_LOGO_MAX_BYTES as u64 {
+        err!(format!("The logo can't be larger than {} KiB", BRANDING_LOGO_MAX_BYTES / 1024))
+    }
+
+    // Stored next to the attachments, but in its own folder so it can't collide with a cipher
+    let folder_path = org.branding_logo_folder();
+    let temp_path = folder_path.join(crate::crypto::generate_attachment_id());
+    tokio::fs::create_dir_all(&folder_path).await?;
+    if let Err(_err) = data.data.persist_to(&temp_path).await {
+        data.data.move_copy_to(&temp_path).await?
+    }
+
+    let extension = match check_branding_logo(&tokio::fs::read(&temp_path).await?) {
+        Ok(extension) => extension,
+        Err(e) => {
+            tokio::fs::remove_file(&temp_path).await.ok();
+            return Err(e);
+        }
+    };
+    let file_name = format!("{}.{extension}", crate::crypto::generate_attachment_id());
+    tokio::fs::rename(&temp_path, folder_path.join(&file_name)).await?;
+
+    let previous_file = org.branding_logo_file.replace(file_name);
+    org.save(&mut conn).await?;
+    org.remove_branding_logo_file(previous_file);
+    log_branding_updated(org_id, &headers, &mut conn).await;
+
+    Ok(Json(org.branding_json(&headers.host)))
+}
+
+#[delete("/organizations/<org_id>/branding/logo")]
+async fn delete_organization_branding_logo(org_id: &str, headers: AdminHeaders, mut conn: DbConn) -> JsonResult {
+    let Some(mut org) = Organization::find_by_uuid(org_id, &mut conn).await else {
+        err!("Can't find organization details")
+    };
+
+    let previous_file = org.branding_logo_file.take();
+    org.save(&mut conn).await?;
+    org.remove_branding_logo_file(previous_file);
+    log_branding_updated(org_id, &headers, &mut conn).await;
+
+    Ok(Json(org.branding_json(&headers.host)))
+}
+
+// Not authenticated, as the clients load the logo as a regular image
+#[get("/organizations/<org_id>/branding/logo/<file_name>")]
+async fn get_organization_branding_logo(org_id: &str, file_name: &str, mut conn: DbConn) -> Option<NamedFile> {
+    let org = Organization::find_by_uuid(org_id, &mut conn).await?;
+    if org.branding_logo_file.as_deref() != Some(file_name) {
+        return None;
+    }
+    NamedFile::open(org.branding_logo_folder().join(file_name)).await.ok()
+}
+
+#[get("/organizations/<org_id>/device-approvals")]
+async fn get_device_approvals(org_id: &str, _headers: AdminHeaders, mut conn: DbConn) -> Json<Value> {
+    let mut approvals_json = Vec::new();
+    for approval in DeviceApproval::find_pending_by_org(org_id, &mut conn).await {
+        if let Some(user) = User::find_by_uuid(&approval.user_uuid, &mut conn).await {
+            approvals_json.push(approval.to_json(&user.email));
+        }
+    }
+
+    Json(json!({
+        "Data": approvals_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    }))
+}
+
+#[post("/organizations/<org_id>/device-approvals/<approval_id>/approve")]
+async fn approve_device(org_id: &str, approval_id: &str, headers: AdminHeaders, mut conn: DbConn) -> EmptyResult {
+    let Some(mut approval) = DeviceApproval::find_by_uuid_and_org(approval_id, org_id, &mut conn).await else {
+        err!("Device approval request not found")
+    };
+    if approval.is_approved() {
+        err!("Device approval request is not pending")
+    }
+
+    approval.approve(&headers.user.uuid);
+    approval.save(&mut conn).await
+}
+
+/// Denying removes the request, so a new login from the device creates a new one
+#[post("/organizations/<org_id>/device-approvals/<approval_id>/deny")]
+async fn deny_device(org_id: &str, approval_id: &str, _headers: AdminHeaders, mut conn: DbConn) -> EmptyResult {
+    let Some(approval) = DeviceApproval::find_by_uuid_and_org(approval_id, org_id, &mut conn).await else {
+        err!("Device approval request not found")
+    };
+
+    approval.delete(&mut conn).await
+}
+
+#[get("/organizations/<org_id>/cipher-transfers")]
+async fn get_cipher_transfers(org_id: &str, _headers: AdminHeaders, mut conn: DbConn) -> Json<Value> {
+    let mut transfers_json = Vec::new();
+    for transfer in CipherTransfer::find_pending_by_org(org_id, &mut conn).await {
+        if let Some(user) = User::find_by_uuid(&transfer.user_uuid, &mut conn).await {
+            transfers_json.push(transfer.to_json(&user.email));
+        }
+    }
+
+    Json(json!({
+        "Data": transfers_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    }))
+}
+
+/// The cipher is moved into the organization as the member who requested it, so their collection rights still apply
+#[post("/organizations/<org_id>/cipher-transfers/<transfer_id>/approve")]
+async fn approve_cipher_transfer(
+    org_id: &str,
+    transfer_id: &str,
+    headers: AdminHeaders,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> EmptyResult {
+    let Some(mut transfer) = CipherTransfer::find_by_uuid_and_org(transfer_id, org_id, &mut conn).await else {
+        err!("Transfer request not found")
+    };
+    let Some(user) = User::find_by_uuid(&transfer.user_uuid, &mut conn).await else {
+        err!("User of the transfer request not found")
+    };
+
+    let data = transfer.approve(&headers.user.uuid)?;
+    let member_headers = Headers {
+        host: headers.host,
+        device: headers.device,
+        user,
+        ip: headers.ip,
+    };
+
+    // The transfer is only marked as approved when the cipher was moved, and not changed since the request
+    begin_transaction(&mut conn).await?;
+    let result = async {
+        match Cipher::find_by_uuid(&transfer.cipher_uuid, &mut conn).await {
+            Some(cipher) => transfer.check_cipher_unchanged(&cipher)?,
+            None => err!("Cipher of the transfer request not found"),
+        }
+        share_approved_transfer(&transfer.cipher_uuid, &data, &member_headers, &mut conn, &nt).await?;
+        transfer.save(&mut conn).await
+    }
+    .await;
+    finish_transaction(result, &mut conn).await
+}
+
+/// Rejecting keeps the cipher as a personal cipher of the member
+#[post("/organizations/<org_id>/cipher-transfers/<transfer_id>/reject")]
+async fn reject_cipher_transfer(
+    org_id: &str,
+    transfer_id: &str,
+    headers: AdminHeaders,
+    mut conn: DbConn,
+) -> EmptyResult {
+    let Some(mut transfer) = CipherTransfer::find_by_uuid_and_org(transfer_id, org_id, &mut conn).await else {
+        err!("Transfer request not found")
+    };
+
+    transfer.reject(&headers.user.uuid)?;
+    transfer.save(&mut conn).await
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OrganizationInviteDomainsData {
+    Domains: Vec<String>,
+}
+
+#[get("/organizations/<org_id>/invite-domains")]
+async fn get_organization_invite_domains(org_id: &str, _headers: AdminHeaders, mut conn: DbConn) -> JsonResult {
+    match Organization::find_by_uuid(org_id, &mut conn).await {
+        Some(org) => Ok(Json(org.invite_domains_json())),
+        None => err!("Can't find organization details"),
+    }
+}
+
+#[put("/organizations/<org_id>/invite-domains", data = "<data>")]
+async fn put_organization_invite_domains(
+    org_id: &str,
+    headers: AdminHeaders,
+    data: JsonUpcase<OrganizationInviteDomainsData>,
+    mut conn: DbConn,
+) -> JsonResult {
+    let data: OrganizationInviteDomainsData = data.into_inner().data;
+
+    let Some(mut org) = Organization::find_by_uuid(org_id, &mut conn).await else {
+        err!("Can't find organization details")
+    };
+
+    org.set_invite_domains(data.Domains)?;
+    org.save(&mut conn).await?;
+    log_event(
+        EventType::OrganizationUpdated as i32,
+        org_id,
+        org_id,
+        &headers.user.uuid,
+        headers.device.atype,
+        &headers.ip.ip,
+        &mut conn,
+    )
+    .await;
+
+    Ok(Json(org.invite_domains_json()))
+}
+
+/// Makes the devices of all confirmed members sync right away, for example after a bulk change to collections
+#[post("/organizations/<org_id>/force-sync")]
+async fn post_organization_force_sync(
+    org_id: &str,
+    headers: AdminHeaders,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
+    let mut users = Vec::new();
+    for member in UserOrganization::find_confirmed_by_org(org_id, &mut conn).await {
+        if let Some(mut user) = User::find_by_uuid(&member.user_uuid, &mut conn).await {
+            user.update_revision(&mut conn).await?;
+            users.push(user);
+        }
+    }
+
+    let notified_devices = nt.send_force_sync(&users).await;
+    info!(
+        "User {} requested a sync of organization {org_id}, {notified_devices} connected devices were notified",
+        headers.user.email
+    );
+
+    Ok(Json(json!({
+        "NotifiedUsers": users.len(),
+        "NotifiedDevices": notified_devices,
+        "Object": "organizationForceSync",
+    })))
+}
+
 // GET /api/collections?writeOnly=false
 #[get("/collections")]
 async fn get_user_collections(headers: Headers, mut conn: DbConn) -> Json<Value> {
@@ -389,6 +773,9 @@ async fn post_organization_collections(
     };
 
     let collection = Collection::new(org.uuid, data.Name, data.ExternalId);
+    if collection.is_external_id_in_use(&mut conn).await {
+        err!("The external ID is already used by another collection")
+    }
     collection.save(&mut conn).await?;
 
     log_event(
@@ -465,10 +852,10 @@ async fn post_organization_collection_update(
     }
 
     collection.name = data.Name;
-    collection.external_id = match data.ExternalId {
-        Some(external_id) if !external_id.trim().is_empty() => Some(external_id),
-        _ => None,
-    };
+    collection.set_external_id(data.ExternalId);
+    if collection.is_external_id_in_use(&mut conn).await {
+        err!("The external ID is already used by another collection")
+    }
 
     collection.save(&mut conn).await?;
 
@@ -801,6 +1188,62 @@ async fn get_org_users(
     }))
 }
 
+// Number of days without any activity after which a member is reported as inactive
+const DEFAULT_INACTIVE_DAYS: u32 = 90;
+
+/// A member without any known activity (no device ever logged in) is always considered inactive
+fn is_inactive(last_active: Option<NaiveDateTime>, cutoff: NaiveDateTime) -> bool {
+    match last_active {
+        Some(dt) => dt < cutoff,
+        None => true,
+    }
+}
+
+// This is a Vaultwarden specific report to help admins find dormant members, based on the last device activity
+#[get("/organizations/<org_id>/users/inactive?<days>")]
+async fn get_org_users_inactive(
+    org_id: &str,
+    days: Option<u32>,
+    _headers: AdminHeaders,
+    mut conn: DbConn,
+) -> JsonResult {
+    let days = days.unwrap_or(DEFAULT_INACTIVE_DAYS);
+    let Some(cutoff) = Duration::try_days(i64::from(days)).and_then(|d| Utc::now().naive_utc().checked_sub_signed(d))
+    else {
+        err!("Invalid number of days")
+    };
+
+    let users: HashMap<String, User> =
+        User::find_by_org(org_id, &mut conn).await.into_iter().map(|u| (u.uuid.clone(), u)).collect();
+    let last_active_by_user = Device::find_last_active_by_org(org_id, &mut conn).await;
+
+    let mut users_json = Vec::new();
+    for u in UserOrganization::find_by_org(org_id, &mut conn).await {
+        let Some(user) = users.get(&u.user_uuid) else {
+            continue;
+        };
+        let last_active = last_active_by_user.get(&u.user_uuid).copied();
+        if is_inactive(last_active, cutoff) {
+            users_json.push(json!({
+                "Id": u.uuid,
+                "UserId": u.user_uuid,
+                "Name": user.name,
+                "Email": user.email,
+                "Status": u.status,
+                "Type": u.atype,
+                "LastActive": last_active.map(|dt| format_date(&dt)),
+                "Object": "organizationUserInactive",
+            }));
+        }
+    }
+
+    Ok(Json(json!({
+        "Data": users_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
 #[post("/organizations/<org_id>/keys", data = "<data>")]
 async fn post_org_keys(
     org_id: &str,
@@ -868,8 +1311,16 @@ async fn send_invite(
         err!("Only Owners can invite Managers, Admins or Owners")
     }
 
+    // Pending invitations take a seat as well
+    let Some(org) = Organization::find_by_uuid(org_id, &mut conn).await else {
+        err!("Error looking up organization")
+    };
+    org.check_seat_limit(data.Emails.len() as i64, &mut conn).await?;
+
     for email in data.Emails.iter() {
-        let email = email.to_lowercase();
+        let email = normalize_email(email);
+        // Checked when the invitation is accepted as well, but without mail existing users are accepted right away
+        org.check_invite_domain(&email, true)?;
         let mut user_org_status = UserOrgStatus::Invited as i32;
         let user = match User::find_by_mail(&email, &mut conn).await {
             None => {
@@ -907,7 +1358,11 @@ async fn send_invite(
         let access_all = data.AccessAll.unwrap_or(false);
         new_user.access_all = access_all;
         new_user.atype = new_type;
-        new_user.status = user_org_status;
+        if user_org_status == UserOrgStatus::Accepted as i32 {
+            new_user.accept(Utc::now().naive_utc());
+        } else {
+            new_user.status = user_org_status;
+        }
 
         // If no accessAll, add the collections received
         if !access_all {
@@ -1007,7 +1462,7 @@ async fn _reinvite_user(org_id: &str, user_org: &str, invited_by_email: &str, co
         err!("SMTP is not configured.")
     }
 
-    let user_org = match UserOrganization::find_by_uuid(user_org, conn).await {
+    let mut user_org = match UserOrganization::find_by_uuid(user_org, conn).await {
         Some(user_org) => user_org,
         None => err!("The user hasn't been invited to the organization."),
     };
@@ -1016,6 +1471,10 @@ async fn _reinvite_user(org_id: &str, user_org: &str, invited_by_email: &str, co
         err!("The user is already accepted or confirmed to the organization")
     }
 
+    // Re-inviting renews an expiring invitation
+    user_org.invited_at = Some(Utc::now().naive_utc());
+    user_org.save(conn).await?;
+
     let user = match User::find_by_uuid(&user_org.user_uuid, conn).await {
         Some(user) => user,
         None => err!("User not found."),
@@ -1044,6 +1503,13 @@ async fn _reinvite_user(org_id: &str, user_org: &str, invited_by_email: &str, co
     Ok(())
 }
 
+fn check_invite_not_expired(user_org: &UserOrganization, cutoff: Option<NaiveDateTime>) -> EmptyResult {
+    if user_org.is_invite_expired(cutoff) {
+        err!("This invitation has expired, ask an administrator of the organization to invite you again")
+    }
+    Ok(())
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct AcceptData {
@@ -1076,11 +1542,28 @@ async fn accept_invite(
                     err!("User already accepted the invitation")
                 }
 
+                check_invite_not_expired(
+                    &user_org,
+                    UserOrganization::invite_expiry_cutoff(Utc::now().naive_utc(), CONFIG.org_invite_expiry_days()),
+                )?;
+
+                match Organization::find_by_uuid(org, &mut conn).await {
+                    Some(org) => {
+                        org.check_invite_domain(&user.email, user.verified_at.is_some() || !CONFIG.mail_enabled())?
+                    }
+                    None => err!("Organization not found."),
+                }
+
                 let master_password_required = OrgPolicy::org_is_reset_password_auto_enroll(org, &mut conn).await;
                 if data.ResetPasswordKey.is_none() && master_password_required {
                     err!("Reset password key is required, but not provided.");
                 }
 
+                // The grace period to enroll the required two-step login starts now
+                user_org.accept(Utc::now().naive_utc());
+                let in_2fa_grace_period =
+                    user_org.in_2fa_grace_period(Utc::now().naive_utc(), CONFIG.org_2fa_grace_period_days());
+
                 // This check is also done at accept_invite(), _confirm_invite, _activate_user(), edit_user(), admin::update_user_org_type
                 // It returns different error messages per function.
                 if user_org.atype < UserOrgType::Admin {
@@ -1089,23 +1572,30 @@ async fn accept_invite(
                         Err(OrgPolicyErr::TwoFactorMissing) => {
                             if CONFIG.email_2fa_auto_fallback() {
                                 two_factor::email::activate_email_2fa(&user, &mut conn).await?;
-                            } else {
+                            } else if !in_2fa_grace_period {
                                 err!("You cannot join this organization until you enable two-step login on your user account");
                             }
                         }
+                        Err(OrgPolicyErr::TwoFactorMethodNotAllowed) => {
+                            if !in_2fa_grace_period {
+                                err!("You cannot join this organization until you enable a two-step login method it accepts on your user account");
+                            }
+                        }
                         Err(OrgPolicyErr::SingleOrgEnforced) => {
                             err!("You cannot join this organization because you are a member of an organization which forbids it");
                         }
+                        Err(OrgPolicyErr::SingleOrgOtherMemberships) => {
+                            err!("You cannot join this organization until you leave or remove all other organizations");
+                        }
                     }
                 }
 
-                user_org.status = UserOrgStatus::Accepted as i32;
-
                 if master_password_required {
                     user_org.reset_password_key = data.ResetPasswordKey;
                 }
 
                 user_org.save(&mut conn).await?;
+                OrgPolicy::remove_disabled_password_hint(&user_org.user_uuid, &mut conn).await?;
             }
         }
         None => err!("Invited user not found"),
@@ -1210,21 +1700,37 @@ async fn _confirm_invite(
         err!("User in invalid state")
     }
 
+    // The accepted member already takes a seat, but the seat limit could have been lowered since
+    match Organization::find_by_uuid(org_id, conn).await {
+        Some(org) => org.check_seat_limit(0, conn).await?,
+        None => err!("Error looking up organization"),
+    }
+
     // This check is also done at accept_invite(), _confirm_invite, _activate_user(), edit_user(), admin::update_user_org_type
     // It returns different error messages per function.
+    let in_2fa_grace_period =
+        user_to_confirm.in_2fa_grace_period(Utc::now().naive_utc(), CONFIG.org_2fa_grace_period_days());
     if user_to_confirm.atype < UserOrgType::Admin {
         match OrgPolicy::is_user_allowed(&user_to_confirm.user_uuid, org_id, true, conn).await {
             Ok(_) => {}
             Err(OrgPolicyErr::TwoFactorMissing) => {
                 if CONFIG.email_2fa_auto_fallback() {
                     two_factor::email::find_and_activate_email_2fa(&user_to_confirm.user_uuid, conn).await?;
-                } else {
+                } else if !in_2fa_grace_period {
                     err!("You cannot confirm this user because they have not setup 2FA");
                 }
             }
+            Err(OrgPolicyErr::TwoFactorMethodNotAllowed) => {
+                if !in_2fa_grace_period {
+                    err!("You cannot confirm this user because they have not setup a two-step login method this organization accepts");
+                }
+            }
             Err(OrgPolicyErr::SingleOrgEnforced) => {
                 err!("You cannot confirm this user because they are a member of an organization which forbids it");
             }
+            Err(OrgPolicyErr::SingleOrgOtherMemberships) => {
+                err!("You cannot confirm this user because they are a member of other organizations and this organization forbids it");
+            }
         }
     }
 
@@ -1284,6 +1790,59 @@ async fn get_user(
     ))
 }
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct AccessScheduleData {
+    // Removes the schedule when not set, allowing access at any time
+    AccessSchedule: Option<AccessSchedule>,
+}
+
+#[put("/organizations/<org_id>/users/<org_user_id>/access-schedule", data = "<data>")]
+async fn put_organization_user_access_schedule(
+    org_id: &str,
+    org_user_id: &str,
+    data: JsonUpcase<AccessScheduleData>,
+    headers: AdminHeaders,
+    mut conn: DbConn,
+) -> JsonResult {
+    let data: AccessScheduleData = data.into_inner().data;
+
+    let mut user_to_edit = match UserOrganization::find_by_uuid_and_org(org_user_id, org_id, &mut conn).await {
+        Some(user) => user,
+        None => err!("The specified user isn't member of the organization"),
+    };
+
+    if user_to_edit.atype >= UserOrgType::Admin {
+        err!("Access schedules only apply to users and managers")
+    }
+
+    user_to_edit.access_schedule = match data.AccessSchedule {
+        Some(schedule) => {
+            schedule.validate()?;
+            Some(serde_json::to_string(&schedule)?)
+        }
+        None => None,
+    };
+    user_to_edit.save(&mut conn).await?;
+
+    log_event(
+        EventType::OrganizationUserUpdated as i32,
+        &user_to_edit.uuid,
+        org_id,
+        &headers.user.uuid,
+        headers.device.atype,
+        &headers.ip.ip,
+        &mut conn,
+    )
+    .await;
+
+    Ok(Json(json!({
+        "Id": user_to_edit.uuid,
+        "AccessSchedule": user_to_edit.access_schedule.as_deref().map(serde_json::from_str::<Value>).transpose()?,
+        "Object": "organizationUserAccessSchedule",
+    })))
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct EditUserData {
@@ -1347,19 +1906,29 @@ async fn edit_user(
 
     // This check is also done at accept_invite(), _confirm_invite, _activate_user(), edit_user(), admin::update_user_org_type
     // It returns different error messages per function.
+    let in_2fa_grace_period =
+        user_to_edit.in_2fa_grace_period(Utc::now().naive_utc(), CONFIG.org_2fa_grace_period_days());
     if new_type < UserOrgType::Admin {
         match OrgPolicy::is_user_allowed(&user_to_edit.user_uuid, org_id, true, &mut conn).await {
             Ok(_) => {}
             Err(OrgPolicyErr::TwoFactorMissing) => {
                 if CONFIG.email_2fa_auto_fallback() {
                     two_factor::email::find_and_activate_email_2fa(&user_to_edit.user_uuid, &mut conn).await?;
-                } else {
+                } else if !in_2fa_grace_period {
                     err!("You cannot modify this user to this type because they have not setup 2FA");
                 }
             }
+            Err(OrgPolicyErr::TwoFactorMethodNotAllowed) => {
+                if !in_2fa_grace_period {
+                    err!("You cannot modify this user to this type because they have not setup a two-step login method this organization accepts");
+                }
+            }
             Err(OrgPolicyErr::SingleOrgEnforced) => {
                 err!("You cannot modify this user to this type because they are a member of an organization which forbids it");
             }
+            Err(OrgPolicyErr::SingleOrgOtherMemberships) => {
+                err!("You cannot modify this user to this type because they are a member of other organizations and this organization forbids it");
+            }
         }
     }
 
@@ -1408,7 +1977,8 @@ async fn edit_user(
     )
     .await;
 
-    user_to_edit.save(&mut conn).await
+    user_to_edit.save(&mut conn).await?;
+    OrgPolicy::remove_disabled_password_hint(&user_to_edit.user_uuid, &mut conn).await
 }
 
 #[delete("/organizations/<org_id>/users", data = "<data>")]
@@ -1544,6 +2114,7 @@ async fn bulk_public_keys(
     }))
 }
 
+use super::ciphers::share_approved_transfer;
 use super::ciphers::update_cipher_from_data;
 use super::ciphers::CipherData;
 
@@ -1583,7 +2154,11 @@ async fn post_org_import(
 
     let mut collections = Vec::new();
     for coll in data.Collections {
-        let collection = Collection::new(org_id.clone(), coll.Name, coll.ExternalId);
+        let mut collection = Collection::new(org_id.clone(), coll.Name, coll.ExternalId);
+        // Imported collections keep their name, but not an external ID which is already in use
+        if collection.is_external_id_in_use(&mut conn).await {
+            collection.set_external_id(None);
+        }
         if collection.save(&mut conn).await.is_err() {
             collections.push(Err(Error::new("Failed to create Collection", "Failed to create Collection")));
         } else {
@@ -1681,6 +2256,15 @@ struct PolicyData {
     data: Option<Value>,
 }
 
+async fn remove_password_hint(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+    if let Some(mut user) = User::find_by_uuid(user_uuid, conn).await {
+        if user.password_hint.take().is_some() {
+            user.save(conn).await?;
+        }
+    }
+    Ok(())
+}
+
 #[put("/organizations/<org_id>/policies/<pol_type>", data = "<data>")]
 async fn put_policy(
     org_id: &str,
@@ -1742,6 +2326,15 @@ async fn put_policy(
         }
     }
 
+    // When enabling the DisablePasswordHint policy, remove the master password hints of the members it applies to
+    if pol_type_enum == OrgPolicyType::DisablePasswordHint && data.enabled {
+        for member in UserOrganization::find_by_org(org_id, &mut conn).await {
+            if member.atype < UserOrgType::Admin && member.status != UserOrgStatus::Revoked as i32 {
+                remove_password_hint(&member.user_uuid, &mut conn).await?;
+            }
+        }
+    }
+
     let mut policy = match OrgPolicy::find_by_org_and_type(org_id, pol_type_enum, &mut conn).await {
         Some(p) => p,
         None => OrgPolicy::new(String::from(org_id), pol_type_enum, "{}".to_string()),
@@ -1864,6 +2457,16 @@ async fn import(org_id: &str, data: JsonUpcase<OrgImportData>, headers: Headers,
         None => err!("User not part of organization"),
     };
 
+    // New members have to match the invite domains like single invitations, checked before anything is changed
+    let Some(org) = Organization::find_by_uuid(org_id, &mut conn).await else {
+        err!("Error looking up organization")
+    };
+    for user_data in data.Users.iter().filter(|u| !u.Deleted) {
+        if UserOrganization::find_by_email_and_org(&user_data.Email, org_id, &mut conn).await.is_none() {
+            org.check_invite_domain(&user_data.Email, true)?;
+        }
+    }
+
     for user_data in &data.Users {
         if user_data.Deleted {
             // If user is marked for deletion and it exists, delete it
@@ -1885,6 +2488,11 @@ async fn import(org_id: &str, data: JsonUpcase<OrgImportData>, headers: Headers,
         // If user is not part of the organization, but it exists
         } else if UserOrganization::find_by_email_and_org(&user_data.Email, org_id, &mut conn).await.is_none() {
             if let Some(user) = User::find_by_mail(&user_data.Email, &mut conn).await {
+                match Organization::find_by_uuid(org_id, &mut conn).await {
+                    Some(org) => org.check_seat_limit(1, &mut conn).await?,
+                    None => err!("Error looking up organization"),
+                }
+
                 let user_org_status = if CONFIG.mail_enabled() {
                     UserOrgStatus::Invited as i32
                 } else {
@@ -2149,26 +2757,42 @@ async fn _restore_organization_user(
                 err!("Only owners can restore other owners")
             }
 
+            match Organization::find_by_uuid(org_id, conn).await {
+                Some(org) => org.check_seat_limit(1, conn).await?,
+                None => err!("Error looking up organization"),
+            }
+
             // This check is also done at accept_invite(), _confirm_invite, _activate_user(), edit_user(), admin::update_user_org_type
             // It returns different error messages per function.
+            let in_2fa_grace_period =
+                user_org.in_2fa_grace_period(Utc::now().naive_utc(), CONFIG.org_2fa_grace_period_days());
             if user_org.atype < UserOrgType::Admin {
                 match OrgPolicy::is_user_allowed(&user_org.user_uuid, org_id, false, conn).await {
                     Ok(_) => {}
                     Err(OrgPolicyErr::TwoFactorMissing) => {
                         if CONFIG.email_2fa_auto_fallback() {
                             two_factor::email::find_and_activate_email_2fa(&user_org.user_uuid, conn).await?;
-                        } else {
+                        } else if !in_2fa_grace_period {
                             err!("You cannot restore this user because they have not setup 2FA");
                         }
                     }
+                    Err(OrgPolicyErr::TwoFactorMethodNotAllowed) => {
+                        if !in_2fa_grace_period {
+                            err!("You cannot restore this user because they have not setup a two-step login method this organization accepts");
+                        }
+                    }
                     Err(OrgPolicyErr::SingleOrgEnforced) => {
                         err!("You cannot restore this user because they are a member of an organization which forbids it");
                     }
+                    Err(OrgPolicyErr::SingleOrgOtherMemberships) => {
+                        err!("You cannot restore this user because they are a member of other organizations and this organization forbids it");
+                    }
                 }
             }
 
             user_org.restore();
             user_org.save(conn).await?;
+            OrgPolicy::remove_disabled_password_hint(&user_org.user_uuid, conn).await?;
 
             log_event(
                 EventType::OrganizationUserRestored as i32,
@@ -2298,6 +2922,9 @@ async fn post_groups(
 
     let group_request = data.into_inner().data;
     let group = group_request.to_group(org_id);
+    if group.is_external_id_in_use(&mut conn).await {
+        err!("The external ID is already used by another group")
+    }
 
     log_event(
         EventType::GroupCreated as i32,
@@ -2332,13 +2959,24 @@ async fn put_group(
 
     let group_request = data.into_inner().data;
     let updated_group = group_request.update_group(group);
+    if updated_group.is_external_id_in_use(&mut conn).await {
+        err!("The external ID is already used by another group")
+    }
 
-    CollectionGroup::delete_all_by_group(group_id, &mut conn).await?;
-    GroupUser::delete_all_by_group(group_id, &mut conn).await?;
+    // The memberships are replaced, so they're only removed when the new ones are saved too
+    begin_transaction(&mut conn).await?;
+    let result = async {
+        CollectionGroup::delete_all_by_group(group_id, &mut conn).await?;
+        GroupUser::delete_all_by_group(group_id, &mut conn).await?;
+        add_update_group(updated_group, group_request.Collections, group_request.Users, org_id, &headers, &mut conn)
+            .await
+    }
+    .await;
+    let response = finish_transaction(result, &mut conn).await?;
 
     log_event(
         EventType::GroupUpdated as i32,
-        &updated_group.uuid,
+        group_id,
         org_id,
         &headers.user.uuid,
         headers.device.atype,
@@ -2347,7 +2985,7 @@ async fn put_group(
     )
     .await;
 
-    add_update_group(updated_group, group_request.Collections, group_request.Users, org_id, &headers, &mut conn).await
+    Ok(response)
 }
 
 async fn add_update_group(
@@ -2880,9 +3518,22 @@ async fn put_reset_password_enrollment(
 //       We need to convert all keys so they have the first character to be a lowercase.
 //       Else the export will be just an empty JSON file.
 #[get("/organizations/<org_id>/export")]
-async fn get_org_export(org_id: &str, headers: AdminHeaders, mut conn: DbConn) -> Json<Value> {
+async fn get_org_export(org_id: &str, headers: AdminHeaders, mut conn: DbConn) -> JsonResult {
     use semver::{Version, VersionReq};
 
+    crate::ratelimit::check_limit_export(&headers.user.uuid)?;
+
+    log_event(
+        EventType::OrganizationClientExportedVault as i32,
+        org_id,
+        org_id,
+        &headers.user.uuid,
+        headers.device.atype,
+        &headers.ip.ip,
+        &mut conn,
+    )
+    .await;
+
     // Since version v2023.1.0 the format of the export is different.
     // Also, this endpoint was created since v2022.9.0.
     // Therefore, we will check for any version smaller then v2023.1.0 and return a different response.
@@ -2899,7 +3550,7 @@ async fn get_org_export(org_id: &str, headers: AdminHeaders, mut conn: DbConn) -
     // Also both main keys here need to be lowercase, else the export will fail.
     if use_list_response_model {
         // Backwards compatible pre v2023.1.0 response
-        Json(json!({
+        Ok(Json(json!({
             "collections": {
                 "data": convert_json_key_lcase_first(_get_org_collections(org_id, &mut conn).await),
                 "object": "list",
@@ -2910,14 +3561,195 @@ async fn get_org_export(org_id: &str, headers: AdminHeaders, mut conn: DbConn) -
                 "object": "list",
                 "continuationToken": null,
             }
-        }))
+        })))
     } else {
         // v2023.1.0 and newer response
-        Json(json!({
+        Ok(Json(json!({
             "collections": convert_json_key_lcase_first(_get_org_collections(org_id, &mut conn).await),
             "ciphers": convert_json_key_lcase_first(_get_org_details(org_id, &headers.host, &headers.user.uuid, &mut conn).await),
-        }))
+        })))
+    }
+}
+
+/// The collections and groups of an organization, and which collections the groups can access,
+/// without any members or items. Used to copy the setup of an organization to another one.
+#[derive(Deserialize, Serialize)]
+#[allow(non_snake_case)]
+struct OrgStructure {
+    Collections: Vec<OrgStructureCollection>,
+    Groups: Vec<OrgStructureGroup>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[allow(non_snake_case)]
+struct OrgStructureCollection {
+    Id: String,
+    // Encrypted with the organization key, clients need to re-encrypt it before importing it into another organization
+    Name: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[allow(non_snake_case)]
+struct OrgStructureGroup {
+    Name: String,
+    AccessAll: bool,
+    // The `Id` refers to a collection of the structure
+    Collections: Vec<SelectionReadOnly>,
+}
+
+fn export_org_structure(
+    collections: &[Collection],
+    groups: &[Group],
+    collection_groups: &[CollectionGroup],
+) -> OrgStructure {
+    OrgStructure {
+        Collections: collections
+            .iter()
+            .map(|c| OrgStructureCollection {
+                Id: c.uuid.clone(),
+                Name: c.name.clone(),
+            })
+            .collect(),
+        Groups: groups
+            .iter()
+            .map(|g| OrgStructureGroup {
+                Name: g.name.clone(),
+                AccessAll: g.access_all,
+                Collections: collection_groups
+                    .iter()
+                    .filter(|cg| cg.groups_uuid == g.uuid)
+                    .map(|cg| SelectionReadOnly {
+                        Id: cg.collections_uuid.clone(),
+                        ReadOnly: cg.read_only,
+                        HidePasswords: cg.hide_passwords,
+                    })
+                    .collect(),
+            })
+            .collect(),
+    }
+}
+
+type ImportedOrgStructure = (Vec<Collection>, Vec<Group>, Vec<CollectionGroup>);
+
+/// Creates new collections and groups for the structure, linked the same way but with new ids
+fn import_org_structure(structure: OrgStructure, org_uuid: &str) -> Result<ImportedOrgStructure, Error> {
+    let mut collection_ids = HashMap::with_capacity(structure.Collections.len());
+    let mut collections = Vec::with_capacity(structure.Collections.len());
+    for c in structure.Collections {
+        let collection = Collection::new(String::from(org_uuid), c.Name, None);
+        if collection_ids.insert(c.Id, collection.uuid.clone()).is_some() {
+            err!("The structure contains a collection id more than once")
+        }
+        collections.push(collection);
     }
+
+    let mut groups = Vec::with_capacity(structure.Groups.len());
+    let mut collection_groups = Vec::new();
+    for g in structure.Groups {
+        let group = Group::new(String::from(org_uuid), g.Name, g.AccessAll, None);
+        for selection in g.Collections {
+            let Some(collection_uuid) = collection_ids.get(&selection.Id) else {
+                err!("A group refers to a collection which isn't part of the structure")
+            };
+            collection_groups.push(CollectionGroup::new(
+                collection_uuid.clone(),
+                group.uuid.clone(),
+                selection.ReadOnly,
+                selection.HidePasswords,
+            ));
+        }
+        groups.push(group);
+    }
+
+    Ok((collections, groups, collection_groups))
+}
+
+#[get("/organizations/<org_id>/structure")]
+async fn get_org_structure(org_id: &str, _headers: AdminHeaders, mut conn: DbConn) -> JsonResult {
+    let collections = Collection::find_by_organization(org_id, &mut conn).await;
+    let (groups, collection_groups) = if CONFIG.org_groups_enabled() {
+        let groups = Group::find_by_organization(org_id, &mut conn).await;
+        let mut collection_groups = Vec::new();
+        for group in &groups {
+            collection_groups.extend(CollectionGroup::find_by_group(&group.uuid, &mut conn).await);
+        }
+        (groups, collection_groups)
+    } else {
+        (Vec::new(), Vec::new())
+    };
+
+    let structure = export_org_structure(&collections, &groups, &collection_groups);
+    Ok(Json(json!({
+        "Collections": structure.Collections,
+        "Groups": structure.Groups,
+        "Object": "organizationStructure",
+    })))
+}
+
+/// Adds the collections and groups of an exported structure to the organization, existing ones are kept
+#[post("/organizations/<org_id>/structure", data = "<data>")]
+async fn post_org_structure(
+    org_id: &str,
+    headers: AdminHeaders,
+    data: JsonUpcase<OrgStructure>,
+    mut conn: DbConn,
+) -> JsonResult {
+    let structure: OrgStructure = data.into_inner().data;
+    if !structure.Groups.is_empty() && !CONFIG.org_groups_enabled() {
+        err!("Group support is disabled");
+    }
+
+    let (collections, mut groups, collection_groups) = import_org_structure(structure, org_id)?;
+
+    // Either the whole structure is created or nothing of it
+    begin_transaction(&mut conn).await?;
+    let result = async {
+        for collection in &collections {
+            collection.save(&mut conn).await?;
+        }
+        for group in &mut groups {
+            group.save(&mut conn).await?;
+        }
+        for mut collection_group in collection_groups {
+            collection_group.save(&mut conn).await?;
+        }
+        Ok::<(), Error>(())
+    }
+    .await;
+    finish_transaction(result, &mut conn).await?;
+
+    for collection in &collections {
+        log_event(
+            EventType::CollectionCreated as i32,
+            &collection.uuid,
+            org_id,
+            &headers.user.uuid,
+            headers.device.atype,
+            &headers.ip.ip,
+            &mut conn,
+        )
+        .await;
+    }
+
+    for group in &groups {
+        log_event(
+            EventType::GroupCreated as i32,
+            &group.uuid,
+            org_id,
+            &headers.user.uuid,
+            headers.device.atype,
+            &headers.ip.ip,
+            &mut conn,
+        )
+        .await;
+    }
+
+    let collections: Vec<Value> = collections.iter().map(Collection::to_json).collect();
+    Ok(Json(json!({
+        "Data": collections,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
 }
 
 async fn _api_key(
@@ -2933,25 +3765,32 @@ async fn _api_key(
     // Validate the admin users password/otp
     data.validate(&user, true, &mut conn).await?;
 
-    let org_api_key = match OrganizationApiKey::find_by_org_uuid(org_id, &conn).await {
+    // Only the hash of a new API key is stored, so the key itself can only be returned when it is created or rotated.
+    // Legacy plaintext API keys can still be viewed like before, until they are rotated.
+    let (org_api_key, api_key) = match OrganizationApiKey::find_by_org_uuid(org_id, &conn).await {
         Some(mut org_api_key) => {
             if rotate {
-                org_api_key.api_key = crate::crypto::generate_api_key();
-                org_api_key.revision_date = chrono::Utc::now().naive_utc();
+                let api_key = crate::crypto::generate_api_key();
+                org_api_key.set_api_key(&api_key);
                 org_api_key.save(&conn).await.expect("Error rotating organization API Key");
+                (org_api_key, api_key)
+            } else if !org_api_key.is_hashed() {
+                let api_key = org_api_key.api_key.clone();
+                (org_api_key, api_key)
+            } else {
+                err!("The API key can only be viewed when it is created, rotate it to get a new one")
             }
-            org_api_key
         }
         None => {
             let api_key = crate::crypto::generate_api_key();
-            let new_org_api_key = OrganizationApiKey::new(String::from(org_id), api_key);
+            let new_org_api_key = OrganizationApiKey::new(String::from(org_id), api_key.clone());
             new_org_api_key.save(&conn).await.expect("Error creating organization API Key");
-            new_org_api_key
+            (new_org_api_key, api_key)
         }
     };
 
     Ok(Json(json!({
-      "ApiKey": org_api_key.api_key,
+      "ApiKey": api_key,
       "RevisionDate": crate::util::format_date(&org_api_key.revision_date),
       "Object": "apiKey",
     })))
@@ -2971,3 +3810,135 @@ async fn rotate_api_key(
 ) -> JsonResult {
     _api_key(org_id, data, true, headers, conn).await
 }
+
+/// Removes organization invitations which weren't accepted within ORG_INVITE_EXPIRY_DAYS, which frees their seats
+pub async fn purge_expired_org_invitations(pool: DbPool) {
+    debug!("Purging expired organization invitations");
+    let Some(cutoff) = UserOrganization::invite_expiry_cutoff(Utc::now().naive_utc(), CONFIG.org_invite_expiry_days())
+    else {
+        return;
+    };
+
+    let Ok(mut conn) = pool.get().await else {
+        error!("Failed to get DB connection while purging expired organization invitations");
+        return;
+    };
+
+    for user_org in UserOrganization::find_expired_invitations(&cutoff, &mut conn).await {
+        let (uuid, org_uuid) = (user_org.uuid.clone(), user_org.org_uuid.clone());
+        match user_org.delete(&mut conn).await {
+            Ok(()) => info!("Removed expired invitation {uuid} of organization {org_uuid}"),
+            Err(e) => error!("Error removing expired invitation {uuid}: {e:#?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inactive_members_report() {
+        let now = Utc::now().naive_utc();
+        let cutoff = now - Duration::days(90);
+
+        assert!(is_inactive(Some(now - Duration::days(120)), cutoff));
+        assert!(!is_inactive(Some(now - Duration::days(2)), cutoff));
+        assert!(is_inactive(None, cutoff));
+    }
+
+    #[test]
+    fn expired_invite_not_accepted() {
+        let now = Utc::now().naive_utc();
+        let cutoff = UserOrganization::invite_expiry_cutoff(now, Some(30));
+        let mut user_org = UserOrganization::new(String::from("user"), String::from("org"));
+        user_org.status = UserOrgStatus::Invited as i32;
+        assert!(check_invite_not_expired(&user_org, cutoff).is_ok());
+
+        user_org.invited_at = Some(now - Duration::days(31));
+        assert!(check_invite_not_expired(&user_org, cutoff).is_err());
+        // Invitations don't expire without ORG_INVITE_EXPIRY_DAYS
+        assert!(check_invite_not_expired(&user_org, None).is_ok());
+    }
+
+    #[test]
+    fn org_structure_round_trip() {
+        let engineering = Collection::new(String::from("org"), String::from("2.engineering"), None);
+        let finance = Collection::new(String::from("org"), String::from("2.finance"), None);
+        let developers = Group::new(String::from("org"), String::from("Developers"), false, None);
+        let auditors = Group::new(String::from("org"), String::from("Auditors"), true, None);
+        let collection_groups = vec![
+            CollectionGroup::new(engineering.uuid.clone(), developers.uuid.clone(), false, false),
+            CollectionGroup::new(finance.uuid.clone(), developers.uuid.clone(), true, true),
+        ];
+
+        let exported = export_org_structure(&[engineering, finance], &[developers, auditors], &collection_groups);
+        let json = serde_json::to_value(&exported).unwrap();
+        let (collections, groups, collection_groups) =
+            import_org_structure(serde_json::from_value(json).unwrap(), "new-org").unwrap();
+
+        assert!(collections.iter().all(|c| c.org_uuid == "new-org"));
+        assert!(groups.iter().all(|g| g.organizations_uuid == "new-org"));
+        let names = |c: &[Collection]| c.iter().map(|c| c.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&collections), vec!["2.engineering", "2.finance"]);
+
+        // Exporting the new organization gives the same structure with new ids
+        let reexported = export_org_structure(&collections, &groups, &collection_groups);
+        assert_eq!(reexported.Groups.len(), 2);
+        assert_eq!(reexported.Groups[0].Name, "Developers");
+        assert!(reexported.Groups[1].AccessAll);
+        assert!(reexported.Groups[1].Collections.is_empty());
+        let access: Vec<_> = reexported.Groups[0]
+            .Collections
+            .iter()
+            .map(|s| {
+                let collection = collections.iter().find(|c| c.uuid == s.Id).unwrap();
+                (collection.name.as_str(), s.ReadOnly, s.HidePasswords)
+            })
+            .collect();
+        assert_eq!(access, vec![("2.engineering", false, false), ("2.finance", true, true)]);
+        assert!(exported.Collections.iter().all(|c| reexported.Collections.iter().all(|n| n.Id != c.Id)));
+    }
+
+    #[test]
+    fn org_structure_unknown_collection_rejected() {
+        let structure = OrgStructure {
+            Collections: Vec::new(),
+            Groups: vec![OrgStructureGroup {
+                Name: String::from("Developers"),
+                AccessAll: false,
+                Collections: vec![SelectionReadOnly {
+                    Id: String::from("missing"),
+                    ReadOnly: false,
+                    HidePasswords: false,
+                }],
+            }],
+        };
+        assert!(import_org_structure(structure, "org").is_err());
+    }
+
+    fn png_logo(width: u32, height: u32) -> Vec<u8> {
+        let img = image::DynamicImage::new_rgba8(width, height);
+        let mut png = std::io::Cursor::new(Vec::new());
+        img.write_to(&mut png, image::ImageOutputFormat::Png).unwrap();
+        png.into_inner()
+    }
+
+    #[test]
+    fn branding_logo_accepted() {
+        assert_eq!(check_branding_logo(&png_logo(256, 64)).unwrap(), "png");
+        assert_eq!(check_branding_logo(&png_logo(BRANDING_LOGO_MAX_SIZE, BRANDING_LOGO_MAX_SIZE)).unwrap(), "png");
+    }
+
+    #[test]
+    fn branding_logo_rejected() {
+        // Too large images, SVG images and files which only look like an image
+        assert!(check_branding_logo(&png_logo(BRANDING_LOGO_MAX_SIZE + 1, 16)).is_err());
+        assert!(check_branding_logo(br#"<svg xmlns="http://www.w3.org/2000/svg"></svg>"#).is_err());
+        assert!(check_branding_logo(&[137, 80, 78, 71, 0, 0, 0, 0]).is_err());
+
+        let mut oversized = png_logo(16, 16);
+        oversized.resize(BRANDING_LOGO_MAX_BYTES + 1, 0);
+        assert!(check_branding_logo(&oversized).is_err());
+    }
+}
@@ -1,20 +1,21 @@
 use chrono::Utc;
 use rocket::{
     request::{self, FromRequest, Outcome},
+    serde::json::Json,
     Request, Route,
 };
 
 use std::collections::HashSet;
 
 use crate::{
-    api::{EmptyResult, JsonUpcase},
+    api::{EmptyResult, JsonResult, JsonUpcase},
     auth,
     db::{models::*, DbConn},
     mail, CONFIG,
 };
 
 pub fn routes() -> Vec<Route> {
-    routes![ldap_import]
+    routes![ldap_import, get_collection_by_external_id, get_group_by_external_id]
 }
 
 #[derive(Deserialize)]
@@ -50,6 +51,16 @@ async fn ldap_import(data: JsonUpcase<OrgImportData>, token: PublicToken, mut co
     let org_id = token.0;
     let data = data.into_inner().data;
 
+    // New members have to match the invite domains like single invitations, checked before anything is changed
+    let Some(org) = Organization::find_by_uuid(&org_id, &mut conn).await else {
+        err!("Error looking up organization")
+    };
+    for user_data in data.Members.iter().filter(|u| !u.Deleted) {
+        if UserOrganization::find_by_email_and_org(&user_data.Email, &org_id, &mut conn).await.is_none() {
+            org.check_invite_domain(&user_data.Email, true)?;
+        }
+    }
+
     for user_data in &data.Members {
         if user_data.Deleted {
             // If user is marked for deletion and it exists, revoke it
@@ -81,6 +92,12 @@ async fn ldap_import(data: JsonUpcase<OrgImportData>, token: PublicToken, mut co
         } else if let Some(mut user_org) =
             UserOrganization::find_by_email_and_org(&user_data.Email, &org_id, &mut conn).await
         {
+            if !user_org.occupies_seat() {
+                match Organization::find_by_uuid(&org_id, &mut conn).await {
+                    Some(org) => org.check_seat_limit(1, &mut conn).await?,
+                    None => err!("Error looking up organization"),
+                }
+            }
             let restored = user_org.restore();
             let ext_modified = user_org.set_external_id(Some(user_data.ExternalId.clone()));
             if restored || ext_modified {
@@ -88,6 +105,11 @@ async fn ldap_import(data: JsonUpcase<OrgImportData>, token: PublicToken, mut co
             }
         } else {
             // If user is not part of the organization
+            match Organization::find_by_uuid(&org_id, &mut conn).await {
+                Some(org) => org.check_seat_limit(1, &mut conn).await?,
+                None => err!("Error looking up organization"),
+            }
+
             let user = match User::find_by_mail(&user_data.Email, &mut conn).await {
                 Some(user) => user, // exists in vaultwarden
                 None => {
@@ -188,6 +210,26 @@ async fn ldap_import(data: JsonUpcase<OrgImportData>, token: PublicToken, mut co
     Ok(())
 }
 
+#[get("/public/collections/external/<external_id>")]
+async fn get_collection_by_external_id(external_id: &str, token: PublicToken, mut conn: DbConn) -> JsonResult {
+    match Collection::find_by_external_id_and_org(external_id, &token.0, &mut conn).await {
+        Some(collection) => Ok(Json(collection.to_json())),
+        None => err_code!("Collection not found", 404),
+    }
+}
+
+#[get("/public/groups/external/<external_id>")]
+async fn get_group_by_external_id(external_id: &str, token: PublicToken, mut conn: DbConn) -> JsonResult {
+    if !CONFIG.org_groups_enabled() {
+        err!("Group support is disabled");
+    }
+
+    match Group::find_by_external_id_and_org(external_id, &token.0, &mut conn).await {
+        Some(group) => Ok(Json(group.to_json())),
+        None => err_code!("Group not found", 404),
+    }
+}
+
 pub struct PublicToken(String);
 
 #[rocket::async_trait]
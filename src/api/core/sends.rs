@@ -2,14 +2,13 @@ use std::path::Path;
 
 use chrono::{DateTime, TimeDelta, Utc};
 use num_traits::ToPrimitive;
-use rocket::form::Form;
 use rocket::fs::NamedFile;
 use rocket::fs::TempFile;
 use rocket::serde::json::Json;
 use serde_json::Value;
 
 use crate::{
-    api::{ApiResult, EmptyResult, JsonResult, JsonUpcase, Notify, UpdateType},
+    api::{ApiResult, EmptyResult, JsonResult, JsonUpcase, MultipartForm, Notify, UpdateType},
     auth::{ClientIp, Headers, Host},
     db::{models::*, DbConn, DbPool},
     util::{NumberOrString, SafeString},
@@ -211,13 +210,18 @@ struct UploadDataV2<'f> {
 // This method still exists to support older clients, probably need to remove it sometime.
 // Upstream: https://github.com/bitwarden/server/blob/d0c793c95181dfb1b447eb450f85ba0bfd7ef643/src/Api/Controllers/SendsController.cs#L164-L167
 #[post("/sends/file", format = "multipart/form-data", data = "<data>")]
-async fn post_send_file(data: Form<UploadData<'_>>, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> JsonResult {
+async fn post_send_file(
+    data: MultipartForm<UploadData<'_>>,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
     enforce_disable_send_policy(&headers, &mut conn).await?;
 
     let UploadData {
         model,
         mut data,
-    } = data.into_inner();
+    } = data.into_inner().data;
     let model = model.into_inner().data;
 
     let Some(size) = data.len().to_i64() else {
@@ -354,14 +358,14 @@ async fn post_send_file_v2(data: JsonUpcase<SendData>, headers: Headers, mut con
 async fn post_send_file_v2_data(
     send_uuid: &str,
     file_id: &str,
-    data: Form<UploadDataV2<'_>>,
+    data: MultipartForm<UploadDataV2<'_>>,
     headers: Headers,
     mut conn: DbConn,
     nt: Notify<'_>,
 ) -> EmptyResult {
     enforce_disable_send_policy(&headers, &mut conn).await?;
 
-    let mut data = data.into_inner();
+    let mut data = data.into_inner().data;
 
     let Some(send) = Send::find_by_uuid(send_uuid, &mut conn).await else {
         err!("Send not found. Unable to save the file.")
@@ -434,9 +438,14 @@ async fn post_access(
     }
 
     if send.password_hash.is_some() {
+        crate::ratelimit::check_limit_send_access(&send.uuid, &ip.ip)?;
+
         match data.into_inner().data.Password {
             Some(ref p) if send.check_password(p) => { /* Nothing to do here */ }
-            Some(_) => err!("Invalid password", format!("IP: {}.", ip.ip)),
+            Some(_) => {
+                crate::ratelimit::register_send_access_failure(&send.uuid, &ip.ip);
+                err!("Invalid password", format!("IP: {}.", ip.ip))
+            }
             None => err_code!("Password not provided", format!("IP: {}.", ip.ip), 401),
         }
     }
@@ -467,6 +476,7 @@ async fn post_access_file(
     data: JsonUpcase<SendAccessData>,
     host: Host,
     mut conn: DbConn,
+    ip: ClientIp,
     nt: Notify<'_>,
 ) -> JsonResult {
     let mut send = match Send::find_by_uuid(send_id, &mut conn).await {
@@ -495,10 +505,15 @@ async fn post_access_file(
     }
 
     if send.password_hash.is_some() {
+        crate::ratelimit::check_limit_send_access(&send.uuid, &ip.ip)?;
+
         match data.into_inner().data.Password {
             Some(ref p) if send.check_password(p) => { /* Nothing to do here */ }
-            Some(_) => err!("Invalid password."),
-            None => err_code!("Password not provided", 401),
+            Some(_) => {
+                crate::ratelimit::register_send_access_failure(&send.uuid, &ip.ip);
+                err!("Invalid password.", format!("IP: {}.", ip.ip))
+            }
+            None => err_code!("Password not provided", format!("IP: {}.", ip.ip), 401),
         }
     }
 
@@ -1,4 +1,4 @@
-use data_encoding::BASE32;
+use data_encoding::{BASE32, BASE64};
 use rocket::serde::json::Json;
 use rocket::Route;
 
@@ -13,6 +13,7 @@ use crate::{
         models::{EventType, TwoFactor, TwoFactorType},
         DbConn,
     },
+    error::Error,
     util::NumberOrString,
 };
 
@@ -33,7 +34,7 @@ async fn generate_authenticator(data: JsonUpcase<PasswordOrOtpData>, headers: He
     let twofactor = TwoFactor::find_by_user_and_type(&user.uuid, type_, &mut conn).await;
 
     let (enabled, key) = match twofactor {
-        Some(tf) => (true, tf.data),
+        Some(tf) => (true, open_totp_secret(&tf.data, CONFIG.totp_encryption_key().as_deref())?),
         _ => (false, crypto::encode_random_bytes::<20>(BASE32)),
     };
 
@@ -82,6 +83,13 @@ async fn activate_authenticator(
         err!("Invalid key length")
     }
 
+    if CONFIG.authenticator_require_unique_secret() {
+        let authenticators = TwoFactor::find_by_type(TwoFactorType::Authenticator as i32, &mut conn).await;
+        if is_secret_in_use(&decoded_key, &authenticators, &user.uuid, CONFIG.totp_encryption_key().as_deref()) {
+            err!("This authenticator key is already in use by another account, generate a new key")
+        }
+    }
+
     // Validate the token provided with the key, and save new twofactor
     validate_totp_code(&user.uuid, &token, &key.to_uppercase(), &headers.ip, &mut conn).await?;
 
@@ -126,7 +134,8 @@ pub async fn validate_totp_code(
     ip: &ClientIp,
     conn: &mut DbConn,
 ) -> EmptyResult {
-    use totp_lite::{totp_custom, Sha1};
+    let encryption_key = CONFIG.totp_encryption_key();
+    let secret = open_totp_secret(secret, encryption_key.as_deref())?;
 
     let decoded_secret = match BASE32.decode(secret.as_bytes()) {
         Ok(s) => s,
@@ -136,9 +145,19 @@ pub async fn validate_totp_code(
     let mut twofactor =
         match TwoFactor::find_by_user_and_type(user_uuid, TwoFactorType::Authenticator as i32, conn).await {
             Some(tf) => tf,
-            _ => TwoFactor::new(user_uuid.to_string(), TwoFactorType::Authenticator, secret.to_string()),
+            _ => TwoFactor::new(
+                user_uuid.to_string(),
+                TwoFactorType::Authenticator,
+                seal_totp_secret(&secret, encryption_key.as_deref()),
+            ),
         };
 
+    // Encrypt secrets stored before an encryption key was configured.
+    // This is saved together with the last used time step when the code is valid.
+    if encryption_key.is_some() && !is_encrypted_totp_secret(&twofactor.data) {
+        twofactor.data = seal_totp_secret(&secret, encryption_key.as_deref());
+    }
+
     // The amount of steps back and forward in time
     // Also check if we need to disable time drifted TOTP codes.
     // If that is the case, we set the steps to 0 so only the current TOTP is valid.
@@ -146,44 +165,205 @@ pub async fn validate_totp_code(
 
     // Get the current system time in UNIX Epoch (UTC)
     let current_time = chrono::Utc::now();
-    let current_timestamp = current_time.timestamp();
+
+    match check_totp_code(&decoded_secret, totp_code, current_time.timestamp(), steps, twofactor.last_used) {
+        TotpCheck::Valid(time_step) => {
+            // Save the last used time step so only totp time steps higher then this one are allowed.
+            // This will also save a newly created twofactor if the code is correct.
+            twofactor.last_used = time_step;
+            twofactor.save(conn).await?;
+            Ok(())
+        }
+        TotpCheck::Reused | TotpCheck::Invalid => err!(
+            format!("Invalid TOTP code! Server time: {} IP: {}", current_time.format("%F %T UTC"), ip.ip),
+            ErrorEvent {
+                event: EventType::UserFailedLogIn2fa
+            }
+        ),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TotpCheck {
+    // The time step of the valid code, which needs to be stored as last used
+    Valid(i64),
+    // The code is valid, but its time step or a later one was used before
+    Reused,
+    Invalid,
+}
+
+/// Checks a TOTP code against `decoded_secret` at `timestamp`, allowing `steps` time steps of drift back and forward.
+/// Codes of a time step up to `last_used` are rejected, so a code can only be used once.
+pub fn check_totp_code(
+    decoded_secret: &[u8],
+    totp_code: &str,
+    timestamp: i64,
+    steps: i64,
+    last_used: i64,
+) -> TotpCheck {
+    use totp_lite::{totp_custom, Sha1};
 
     for step in -steps..=steps {
-        let time_step = current_timestamp / 30i64 + step;
+        let time_step = timestamp / 30i64 + step;
 
         // We need to calculate the time offsite and cast it as an u64.
         // Since we only have times into the future and the totp generator needs an u64 instead of the default i64.
-        let time = (current_timestamp + step * 30i64) as u64;
-        let generated = totp_custom::<Sha1>(30, 6, &decoded_secret, time);
-
-        // Check the given code equals the generated and if the time_step is larger then the one last used.
-        if generated == totp_code && time_step > twofactor.last_used {
-            // If the step does not equals 0 the time is drifted either server or client side.
-            if step != 0 {
-                warn!("TOTP Time drift detected. The step offset is {}", step);
-            }
+        let time = (timestamp + step * 30i64) as u64;
+        let generated = totp_custom::<Sha1>(30, 6, decoded_secret, time);
 
-            // Save the last used time step so only totp time steps higher then this one are allowed.
-            // This will also save a newly created twofactor if the code is correct.
-            twofactor.last_used = time_step;
-            twofactor.save(conn).await?;
-            return Ok(());
-        } else if generated == totp_code && time_step <= twofactor.last_used {
+        if !crypto::ct_eq(&generated, totp_code) {
+            continue;
+        }
+        // Check the time_step is larger then the one last used.
+        if time_step <= last_used {
             warn!("This TOTP or a TOTP code within {} steps back or forward has already been used!", steps);
-            err!(
-                format!("Invalid TOTP code! Server time: {} IP: {}", current_time.format("%F %T UTC"), ip.ip),
-                ErrorEvent {
-                    event: EventType::UserFailedLogIn2fa
-                }
-            );
+            return TotpCheck::Reused;
+        }
+        // If the step does not equals 0 the time is drifted either server or client side.
+        if step != 0 {
+            warn!("TOTP Time drift detected. The step offset is {}", step);
         }
+        return TotpCheck::Valid(time_step);
     }
 
     // Else no valid code received, deny access
-    err!(
-        format!("Invalid TOTP code! Server time: {} IP: {}", current_time.format("%F %T UTC"), ip.ip),
-        ErrorEvent {
-            event: EventType::UserFailedLogIn2fa
+    TotpCheck::Invalid
+}
+
+// Encrypted secrets are stored with this prefix, which can never be part of a BASE32 encoded secret
+const ENCRYPTED_SECRET_PREFIX: &str = "enc:";
+const TOTP_SECRET_CONTEXT: &[u8] = b"totp-secret";
+
+fn is_encrypted_totp_secret(stored: &str) -> bool {
+    stored.starts_with(ENCRYPTED_SECRET_PREFIX)
+}
+
+/// Returns the value to store for a TOTP secret, encrypted if an encryption key is given
+fn seal_totp_secret(secret: &str, encryption_key: Option<&str>) -> String {
+    match encryption_key {
+        Some(key) => {
+            let encrypted = crypto::encrypt_secret(key, TOTP_SECRET_CONTEXT, secret.as_bytes());
+            format!("{ENCRYPTED_SECRET_PREFIX}{}", BASE64.encode(&encrypted))
         }
-    );
+        None => secret.to_string(),
+    }
+}
+
+/// Whether the authenticator of another account uses the same secret, see `AUTHENTICATOR_REQUIRE_UNIQUE_SECRET`.
+/// All the secrets are compared in constant time, so the duration doesn't reveal which one matched.
+fn is_secret_in_use(
+    secret: &[u8],
+    authenticators: &[TwoFactor],
+    user_uuid: &str,
+    encryption_key: Option<&str>,
+) -> bool {
+    let mut in_use = false;
+    for twofactor in authenticators.iter().filter(|tf| tf.user_uuid != user_uuid) {
+        let Ok(other) = open_totp_secret(&twofactor.data, encryption_key) else {
+            continue;
+        };
+        if let Ok(other) = BASE32.decode(other.to_uppercase().as_bytes()) {
+            in_use |= crypto::ct_eq(secret, other);
+        }
+    }
+    in_use
+}
+
+/// Returns the plaintext TOTP secret from its stored value, legacy plaintext secrets are returned as-is
+fn open_totp_secret(stored: &str, encryption_key: Option<&str>) -> Result<String, Error> {
+    let Some(encoded) = stored.strip_prefix(ENCRYPTED_SECRET_PREFIX) else {
+        return Ok(stored.to_string());
+    };
+
+    let Some(key) = encryption_key else {
+        err!("TOTP secret is encrypted, but no TOTP_ENCRYPTION_KEY is configured")
+    };
+
+    let decrypted = BASE64
+        .decode(encoded.as_bytes())
+        .ok()
+        .and_then(|encrypted| crypto::decrypt_secret(key, TOTP_SECRET_CONTEXT, &encrypted))
+        .and_then(|decrypted| String::from_utf8(decrypted).ok());
+
+    match decrypted {
+        Some(secret) => Ok(secret),
+        None => err!("Unable to decrypt TOTP secret, the TOTP_ENCRYPTION_KEY might have changed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+    const KEY: &str = "some-totp-encryption-key";
+
+    #[test]
+    fn totp_secret_encrypted_on_write() {
+        let stored = seal_totp_secret(SECRET, Some(KEY));
+        assert!(is_encrypted_totp_secret(&stored));
+        assert!(!stored.contains(SECRET));
+
+        // A random nonce is used, so the same secret doesn't produce the same value twice
+        assert_ne!(stored, seal_totp_secret(SECRET, Some(KEY)));
+
+        // Without a key, the secret is stored as-is
+        assert_eq!(seal_totp_secret(SECRET, None), SECRET);
+    }
+
+    fn authenticator(user_uuid: &str, stored: String) -> TwoFactor {
+        TwoFactor::new(user_uuid.to_string(), TwoFactorType::Authenticator, stored)
+    }
+
+    #[test]
+    fn duplicate_totp_secret_rejected() {
+        let secret = BASE32.decode(SECRET.as_bytes()).unwrap();
+        // Also found when the other secret is stored encrypted
+        let authenticators = [authenticator("other", seal_totp_secret(SECRET, Some(KEY)))];
+        assert!(is_secret_in_use(&secret, &authenticators, "user", Some(KEY)));
+
+        let authenticators = [authenticator("other", SECRET.to_lowercase())];
+        assert!(is_secret_in_use(&secret, &authenticators, "user", None));
+    }
+
+    #[test]
+    fn unique_totp_secret_accepted() {
+        let secret = BASE32.decode(SECRET.as_bytes()).unwrap();
+        let other_secret = crypto::encode_random_bytes::<20>(BASE32);
+        let authenticators = [authenticator("other", other_secret), authenticator("user", SECRET.to_string())];
+        // Re-enrolling the same secret on the own account is fine
+        assert!(!is_secret_in_use(&secret, &authenticators, "user", None));
+    }
+
+    #[test]
+    fn totp_secret_decrypted_on_verify() {
+        use totp_lite::{totp_custom, Sha1};
+
+        let stored = seal_totp_secret(SECRET, Some(KEY));
+        let secret = open_totp_secret(&stored, Some(KEY)).unwrap();
+        assert_eq!(secret, SECRET);
+
+        let decoded = BASE32.decode(secret.as_bytes()).unwrap();
+        let expected = BASE32.decode(SECRET.as_bytes()).unwrap();
+        assert_eq!(
+            totp_custom::<Sha1>(30, 6, &decoded, 1_700_000_000),
+            totp_custom::<Sha1>(30, 6, &expected, 1_700_000_000)
+        );
+
+        assert!(open_totp_secret(&stored, Some("another-key")).is_err());
+        assert!(open_totp_secret(&stored, None).is_err());
+    }
+
+    #[test]
+    fn totp_legacy_plaintext_secret_migrated() {
+        // Legacy plaintext secrets are still readable when a key is configured
+        assert!(!is_encrypted_totp_secret(SECRET));
+        let secret = open_totp_secret(SECRET, Some(KEY)).unwrap();
+        assert_eq!(secret, SECRET);
+
+        // And are re-encrypted with the configured key
+        let migrated = seal_totp_secret(&secret, Some(KEY));
+        assert!(is_encrypted_totp_secret(&migrated));
+        assert_eq!(open_totp_secret(&migrated, Some(KEY)).unwrap(), SECRET);
+    }
 }
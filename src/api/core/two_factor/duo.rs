@@ -14,7 +14,8 @@ use crate::{
         models::{EventType, TwoFactor, TwoFactorType, User},
         DbConn,
     },
-    error::MapResult,
+    error::{Error, ErrorEvent, MapResult},
+    mail,
     util::get_reqwest_client,
     CONFIG,
 };
@@ -201,6 +202,8 @@ async fn duo_api_request(method: &str, path: &str, params: &str, data: &DuoData)
     use reqwest::{header, Method};
     use std::str::FromStr;
 
+    check_allowed_host(&data.host)?;
+
     // https://duo.com/docs/authapi#api-details
     let url = format!("https://{}{}", &data.host, path);
     let date = Utc::now().to_rfc2822();
@@ -224,6 +227,125 @@ async fn duo_api_request(method: &str, path: &str, params: &str, data: &DuoData)
     Ok(())
 }
 
+/// Checks the global Duo integration with the same request used to validate keys when Duo is activated.
+pub async fn duo_health_check_job() {
+    debug!("Start duo_health_check_job");
+    let Some(data) = DuoData::global() else {
+        debug!("Global Duo is not configured, skipping health check");
+        return;
+    };
+
+    let result = duo_api_request("GET", "/auth/v2/check", "", &data).await;
+    let alert_emails = CONFIG.duo_health_check_alert_emails();
+    let recipients = duo_health_alert_recipients(&result, alert_emails.as_deref(), CONFIG.mail_enabled());
+    match result {
+        Ok(()) => debug!("Duo health check for {} succeeded", data.host),
+        Err(e) => {
+            error!("Duo health check for {} failed: {:#?}", data.host, e);
+            for address in recipients {
+                if let Err(e) = mail::send_duo_health_check_failed(address, &data.host, &e.to_string()).await {
+                    error!("Error sending Duo health check alert to {}: {:#?}", address, e);
+                }
+            }
+        }
+    }
+}
+
+/// Addresses which should be alerted about the result of a health check, only failures are alerted.
+fn duo_health_alert_recipients<'a>(
+    result: &EmptyResult,
+    alert_emails: Option<&'a str>,
+    mail_enabled: bool,
+) -> Vec<&'a str> {
+    match (result, alert_emails) {
+        (Err(_), Some(emails)) if mail_enabled => {
+            emails.split(',').map(str::trim).filter(|address| !address.is_empty()).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// How the email address of a user is mapped to their Duo username, for tenants where those differ
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DuoUsernameFormat {
+    // The email address as-is
+    Email,
+    Lowercase,
+    // Only the part before the `@`, which also maps UPN style usernames (`user@corp.example.com`)
+    StripDomain,
+}
+
+impl DuoUsernameFormat {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "email" => Some(Self::Email),
+            "lowercase" => Some(Self::Lowercase),
+            "strip_domain" => Some(Self::StripDomain),
+            _ => None,
+        }
+    }
+
+    /// `DUO_USERNAME_FORMAT` only applies to the global Duo keys, users with their own Duo keys log in with their email
+    fn for_status(status: &DuoStatus) -> Self {
+        match status {
+            DuoStatus::Global(_) => Self::from_name(&CONFIG.duo_username_format()).unwrap_or(Self::Email),
+            DuoStatus::User(_) | DuoStatus::Disabled(_) => Self::Email,
+        }
+    }
+
+    fn normalize(self, username: &str) -> String {
+        match self {
+            Self::Email => username.to_string(),
+            Self::Lowercase => username.to_lowercase(),
+            Self::StripDomain => {
+                let username = username.to_lowercase();
+                match username.split_once('@') {
+                    Some((local, _domain)) => local.to_string(),
+                    None => username,
+                }
+            }
+        }
+    }
+
+    /// Whether the username returned by Duo belongs to the user with this email address
+    fn matches(self, duo_username: &str, email: &str) -> bool {
+        crypto::ct_eq(self.normalize(duo_username), self.normalize(email))
+    }
+}
+
+pub fn is_valid_username_format(name: &str) -> bool {
+    DuoUsernameFormat::from_name(name).is_some()
+}
+
+/// Whether `host` is a plain hostname matching an entry of the comma separated `allowed_hosts`.
+/// Entries starting with a `.` match any subdomain, other entries only match exactly.
+pub fn is_allowed_host(host: &str, allowed_hosts: &str) -> bool {
+    let host = host.to_lowercase();
+    // Anything else could change the URL the signed requests are sent to
+    if host.is_empty() || !host.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-')) {
+        return false;
+    }
+
+    allowed_hosts.split(',').map(|h| h.trim().to_lowercase()).filter(|h| !h.is_empty()).any(|allowed| {
+        if allowed.starts_with('.') {
+            host.ends_with(&allowed) && host.len() > allowed.len()
+        } else {
+            host == allowed
+        }
+    })
+}
+
+/// Refuses Duo hosts outside of `DUO_ALLOWED_HOSTS` when it is set,
+/// so a tampered key store can't send signed requests elsewhere
+fn check_allowed_host(host: &str) -> EmptyResult {
+    if let Some(allowed_hosts) = CONFIG.duo_allowed_hosts() {
+        if !is_allowed_host(host, &allowed_hosts) {
+            err!("Duo host is not allowed", format!("`{host}` is not in DUO_ALLOWED_HOSTS"))
+        }
+    }
+    Ok(())
+}
+
 const DUO_EXPIRE: i64 = 300;
 const APP_EXPIRE: i64 = 3600;
 
@@ -254,24 +376,32 @@ async fn get_user_duo_data(uuid: &str, conn: &mut DbConn) -> DuoStatus {
     DuoStatus::Disabled(false)
 }
 
-// let (ik, sk, ak, host) = get_duo_keys();
-async fn get_duo_keys_email(email: &str, conn: &mut DbConn) -> ApiResult<(String, String, String, String)> {
-    let data = match User::find_by_mail(email, conn).await {
-        Some(u) => get_user_duo_data(&u.uuid, conn).await.data(),
-        _ => DuoData::global(),
-    }
-    .map_res("Can't fetch Duo Keys")?;
+// let (ik, sk, ak, host, username_format) = get_duo_keys();
+async fn get_duo_keys_email(
+    email: &str,
+    conn: &mut DbConn,
+) -> ApiResult<(String, String, String, String, DuoUsernameFormat)> {
+    let status = match User::find_by_mail(email, conn).await {
+        Some(u) => get_user_duo_data(&u.uuid, conn).await,
+        _ => DuoData::global().map_or(DuoStatus::Disabled(false), DuoStatus::Global),
+    };
+    let username_format = DuoUsernameFormat::for_status(&status);
+    let data = status.data().map_res("Can't fetch Duo Keys")?;
+
+    check_allowed_host(&data.host)?;
 
-    Ok((data.ik, data.sk, CONFIG.get_duo_akey(), data.host))
+    Ok((data.ik, data.sk, CONFIG.get_duo_akey(), data.host, username_format))
 }
 
 pub async fn generate_duo_signature(email: &str, conn: &mut DbConn) -> ApiResult<(String, String)> {
     let now = Utc::now().timestamp();
 
-    let (ik, sk, ak, host) = get_duo_keys_email(email, conn).await?;
+    let (ik, sk, ak, host, username_format) = get_duo_keys_email(email, conn).await?;
 
-    let duo_sign = sign_duo_values(&sk, email, &ik, DUO_PREFIX, now + DUO_EXPIRE);
-    let app_sign = sign_duo_values(&ak, email, &ik, APP_PREFIX, now + APP_EXPIRE);
+    // Duo identifies the user by this username
+    let username = username_format.normalize(email);
+    let duo_sign = sign_duo_values(&sk, &username, &ik, DUO_PREFIX, now + DUO_EXPIRE);
+    let app_sign = sign_duo_values(&ak, &username, &ik, APP_PREFIX, now + APP_EXPIRE);
 
     Ok((format!("{duo_sign}:{app_sign}"), host))
 }
@@ -283,6 +413,54 @@ fn sign_duo_values(key: &str, email: &str, ikey: &str, prefix: &str, expire: i64
     format!("{}|{}", cookie, crypto::hmac_sign(key, &cookie))
 }
 
+/// Why a Duo login failed, stored as reason code with the failed login event.
+/// This allows to tell a misconfiguration apart from tampered responses, and never contains keys or signed values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DuoFailure {
+    KeysUnavailable,
+    InvalidResponse,
+    SignatureMismatch,
+    PrefixMismatch,
+    IkeyMismatch,
+    Expired,
+    UsernameMismatch,
+}
+
+impl DuoFailure {
+    const fn code(self) -> &'static str {
+        match self {
+            Self::KeysUnavailable => "duo_keys_unavailable",
+            Self::InvalidResponse => "duo_invalid_response",
+            Self::SignatureMismatch => "duo_signature_mismatch",
+            Self::PrefixMismatch => "duo_prefix_mismatch",
+            Self::IkeyMismatch => "duo_ikey_mismatch",
+            Self::Expired => "duo_expired",
+            Self::UsernameMismatch => "duo_username_mismatch",
+        }
+    }
+
+    const fn message(self) -> &'static str {
+        match self {
+            Self::KeysUnavailable => "Can't fetch Duo Keys",
+            Self::InvalidResponse => "Invalid Duo response",
+            Self::SignatureMismatch => "Duo signatures don't match",
+            Self::PrefixMismatch => "Prefixes don't match",
+            Self::IkeyMismatch => "Invalid ikey",
+            Self::Expired => "Expired authorization",
+            Self::UsernameMismatch => "Error validating duo authentication",
+        }
+    }
+
+    fn into_error(self) -> Error {
+        error!("{}", self.message());
+        Error::new(self.message(), self.message())
+            .with_event(ErrorEvent {
+                event: EventType::UserFailedLogIn2fa,
+            })
+            .with_event_detail(self.code())
+    }
+}
+
 pub async fn validate_duo_login(email: &str, response: &str, conn: &mut DbConn) -> EmptyResult {
     // email is as entered by the user, so it needs to be normalized before
     // comparison with auth_user below.
@@ -290,12 +468,7 @@ pub async fn validate_duo_login(email: &str, response: &str, conn: &mut DbConn)
 
     let split: Vec<&str> = response.split(':').collect();
     if split.len() != 2 {
-        err!(
-            "Invalid response length",
-            ErrorEvent {
-                event: EventType::UserFailedLogIn2fa
-            }
-        );
+        return Err(DuoFailure::InvalidResponse.into_error());
     }
 
     let auth_sig = split[0];
@@ -303,27 +476,23 @@ pub async fn validate_duo_login(email: &str, response: &str, conn: &mut DbConn)
 
     let now = Utc::now().timestamp();
 
-    let (ik, sk, ak, _host) = get_duo_keys_email(email, conn).await?;
+    let (ik, sk, ak, _host, username_format) =
+        get_duo_keys_email(email, conn).await.map_err(|_| DuoFailure::KeysUnavailable.into_error())?;
 
-    let auth_user = parse_duo_values(&sk, auth_sig, &ik, AUTH_PREFIX, now)?;
-    let app_user = parse_duo_values(&ak, app_sig, &ik, APP_PREFIX, now)?;
+    let auth_user = parse_duo_values(&sk, auth_sig, &ik, AUTH_PREFIX, now).map_err(DuoFailure::into_error)?;
+    let app_user = parse_duo_values(&ak, app_sig, &ik, APP_PREFIX, now).map_err(DuoFailure::into_error)?;
 
-    if !crypto::ct_eq(&auth_user, app_user) || !crypto::ct_eq(&auth_user, email) {
-        err!(
-            "Error validating duo authentication",
-            ErrorEvent {
-                event: EventType::UserFailedLogIn2fa
-            }
-        )
+    if !username_format.matches(&auth_user, &app_user) || !username_format.matches(&auth_user, email) {
+        return Err(DuoFailure::UsernameMismatch.into_error());
     }
 
     Ok(())
 }
 
-fn parse_duo_values(key: &str, val: &str, ikey: &str, prefix: &str, time: i64) -> ApiResult<String> {
+fn parse_duo_values(key: &str, val: &str, ikey: &str, prefix: &str, time: i64) -> Result<String, DuoFailure> {
     let split: Vec<&str> = val.split('|').collect();
     if split.len() != 3 {
-        err!("Invalid value length")
+        return Err(DuoFailure::InvalidResponse);
     }
 
     let u_prefix = split[0];
@@ -333,26 +502,22 @@ fn parse_duo_values(key: &str, val: &str, ikey: &str, prefix: &str, time: i64) -
     let sig = crypto::hmac_sign(key, &format!("{u_prefix}|{u_b64}"));
 
     if !crypto::ct_eq(crypto::hmac_sign(key, &sig), crypto::hmac_sign(key, u_sig)) {
-        err!("Duo signatures don't match")
+        return Err(DuoFailure::SignatureMismatch);
     }
 
     if u_prefix != prefix {
-        err!("Prefixes don't match")
+        return Err(DuoFailure::PrefixMismatch);
     }
 
-    let cookie_vec = match BASE64.decode(u_b64.as_bytes()) {
-        Ok(c) => c,
-        Err(_) => err!("Invalid Duo cookie encoding"),
-    };
-
-    let cookie = match String::from_utf8(cookie_vec) {
-        Ok(c) => c,
-        Err(_) => err!("Invalid Duo cookie encoding"),
-    };
+    let cookie = BASE64
+        .decode(u_b64.as_bytes())
+        .ok()
+        .and_then(|c| String::from_utf8(c).ok())
+        .ok_or(DuoFailure::InvalidResponse)?;
 
     let cookie_split: Vec<&str> = cookie.split('|').collect();
     if cookie_split.len() != 3 {
-        err!("Invalid cookie length")
+        return Err(DuoFailure::InvalidResponse);
     }
 
     let username = cookie_split[0];
@@ -360,17 +525,108 @@ fn parse_duo_values(key: &str, val: &str, ikey: &str, prefix: &str, time: i64) -
     let expire = cookie_split[2];
 
     if !crypto::ct_eq(ikey, u_ikey) {
-        err!("Invalid ikey")
+        return Err(DuoFailure::IkeyMismatch);
     }
 
-    let expire: i64 = match expire.parse() {
-        Ok(e) => e,
-        Err(_) => err!("Invalid expire time"),
-    };
+    let expire: i64 = expire.parse().map_err(|_| DuoFailure::InvalidResponse)?;
 
     if time >= expire {
-        err!("Expired authorization")
+        return Err(DuoFailure::Expired);
     }
 
     Ok(username.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_health_check_alerts_admins() {
+        let failed: EmptyResult = Err(crate::error::Error::new("Duo error", "401 Unauthorized"));
+        let emails = Some("admin@example.com, security@example.com,");
+        assert_eq!(duo_health_alert_recipients(&failed, emails, true), ["admin@example.com", "security@example.com"]);
+        // Failures are only logged without addresses or mail
+        assert!(duo_health_alert_recipients(&failed, None, true).is_empty());
+        assert!(duo_health_alert_recipients(&failed, emails, false).is_empty());
+    }
+
+    #[test]
+    fn duo_upn_username_matches_after_normalization() {
+        let format = DuoUsernameFormat::from_name("strip_domain").unwrap();
+        assert!(format.matches("JDoe@corp.example.com", "jdoe@example.com"));
+        assert!(DuoUsernameFormat::Lowercase.matches("JDoe@Example.com", "jdoe@example.com"));
+
+        // The username signed for Duo is returned in the signed response
+        let now = Utc::now().timestamp();
+        let response = sign_duo_values("secret", &format.normalize("jdoe@example.com"), "ikey", AUTH_PREFIX, now + 60);
+        let duo_user = parse_duo_values("secret", &response, "ikey", AUTH_PREFIX, now).unwrap();
+        assert_eq!(duo_user, "jdoe");
+        assert!(format.matches(&duo_user, "jdoe@example.com"));
+    }
+
+    #[test]
+    fn duo_username_mismatch_fails() {
+        assert!(!DuoUsernameFormat::StripDomain.matches("jsmith@corp.example.com", "jdoe@example.com"));
+        assert!(!DuoUsernameFormat::Lowercase.matches("jdoe", "jdoe@example.com"));
+        // The default keeps comparing the exact email address
+        assert!(!DuoUsernameFormat::Email.matches("JDoe@example.com", "jdoe@example.com"));
+        assert!(DuoUsernameFormat::Email.matches("jdoe@example.com", "jdoe@example.com"));
+        assert_eq!(DuoUsernameFormat::from_name("upn"), None);
+    }
+
+    #[test]
+    fn duo_user_keys_use_email_username() {
+        let data = DuoData {
+            host: String::from("api-12345678.duosecurity.com"),
+            ik: String::from("ikey"),
+            sk: String::from("skey"),
+        };
+        assert_eq!(DuoUsernameFormat::for_status(&DuoStatus::User(data)), DuoUsernameFormat::Email);
+    }
+
+    #[test]
+    fn duo_failure_reason_recorded() {
+        let now = Utc::now().timestamp();
+        let response = sign_duo_values("secret", "jdoe@example.com", "ikey", AUTH_PREFIX, now + 60);
+        assert_eq!(parse_duo_values("secret", &response, "ikey", AUTH_PREFIX, now).unwrap(), "jdoe@example.com");
+
+        // An expired response and a tampered one are told apart
+        let expired = parse_duo_values("secret", &response, "ikey", AUTH_PREFIX, now + 60).unwrap_err();
+        assert_eq!(expired, DuoFailure::Expired);
+        let tampered = parse_duo_values("other-secret", &response, "ikey", AUTH_PREFIX, now).unwrap_err();
+        assert_eq!(tampered, DuoFailure::SignatureMismatch);
+        let wrong_ikey = parse_duo_values("secret", &response, "other-ikey", AUTH_PREFIX, now).unwrap_err();
+        assert_eq!(wrong_ikey, DuoFailure::IkeyMismatch);
+
+        let err = tampered.into_error();
+        assert_eq!(err.get_event_detail(), Some("duo_signature_mismatch"));
+        assert!(matches!(
+            err.get_event(),
+            Some(ErrorEvent {
+                event: EventType::UserFailedLogIn2fa
+            })
+        ));
+    }
+
+    #[test]
+    fn duo_host_allowlist() {
+        let default = ".duosecurity.com,.duofederal.com";
+        assert!(is_allowed_host("api-1a2b3c4d.duosecurity.com", default));
+        assert!(is_allowed_host("API-1A2B3C4D.DuoSecurity.com", default));
+        assert!(is_allowed_host("api-1a2b3c4d.duofederal.com", default));
+        assert!(is_allowed_host("duo.example.com", "duo.example.com, .duosecurity.com"));
+
+        assert!(!is_allowed_host("api.attacker.example", default));
+        assert!(!is_allowed_host("duosecurity.com.attacker.example", default));
+        assert!(!is_allowed_host("attacker.example/.duosecurity.com", default));
+        assert!(!is_allowed_host("attacker.example#.duosecurity.com", default));
+        assert!(!is_allowed_host(".duosecurity.com", default));
+        assert!(!is_allowed_host("sub.duo.example.com", "duo.example.com"));
+    }
+
+    #[test]
+    fn healthy_check_does_not_alert() {
+        assert!(duo_health_alert_recipients(&Ok(()), Some("admin@example.com"), true).is_empty());
+    }
+}
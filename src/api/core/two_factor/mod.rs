@@ -7,7 +7,7 @@ use serde_json::Value;
 use crate::{
     api::{
         core::{log_event, log_user_event},
-        EmptyResult, JsonResult, JsonUpcase, PasswordOrOtpData,
+        ApiResult, EmptyResult, JsonResult, JsonUpcase, PasswordOrOtpData,
     },
     auth::{ClientHeaders, Headers},
     crypto,
@@ -62,6 +62,9 @@ async fn get_recover(data: JsonUpcase<PasswordOrOtpData>, headers: Headers, mut
     let user = headers.user;
 
     data.validate(&user, true, &mut conn).await?;
+    if user.totp_recover.is_some() {
+        TwoFactorBackup::acknowledge_recovery(&user.uuid, &mut conn).await?;
+    }
 
     Ok(Json(json!({
         "Code": user.totp_recover,
@@ -101,6 +104,7 @@ async fn recover(data: JsonUpcase<RecoverTwoFactor>, client_headers: ClientHeade
 
     // Remove all twofactors from the user
     TwoFactor::delete_all_by_user(&user.uuid, &mut conn).await?;
+    TwoFactorBackup::reset_recovery(&user.uuid, &mut conn).await?;
     enforce_2fa_policy(&user, &user.uuid, client_headers.device_type, &client_headers.ip.ip, &mut conn).await?;
 
     log_user_event(
@@ -123,6 +127,15 @@ async fn _generate_recover_code(user: &mut User, conn: &mut DbConn) {
         let totp_recover = crypto::encode_random_bytes::<20>(BASE32);
         user.totp_recover = Some(totp_recover);
         user.save(conn).await.ok();
+        TwoFactorBackup::reset_recovery(&user.uuid, conn).await.ok();
+    }
+
+    // Ask the user to save the recovery code when the new method is the only one
+    let backup = TwoFactorBackup::find_or_default(&user.uuid, conn).await;
+    if backup.is_missing_backup() && CONFIG.two_factor_backup_required() && CONFIG.mail_enabled() {
+        if let Err(e) = mail::send_two_factor_backup_missing(&user.email).await {
+            error!("Error sending two-step login backup email: {:#?}", e);
+        }
     }
 }
 
@@ -150,6 +163,11 @@ async fn disable_twofactor(data: JsonUpcase<DisableTwoFactorData>, headers: Head
     let type_ = data.Type.into_i32()?;
 
     if let Some(twofactor) = TwoFactor::find_by_user_and_type(&user.uuid, type_, &mut conn).await {
+        if twofactor.enabled {
+            TwoFactorBackup::find_or_default(&user.uuid, &mut conn)
+                .await
+                .check_disable_allowed(CONFIG.two_factor_backup_required())?;
+        }
         twofactor.delete(&mut conn).await?;
         log_user_event(EventType::UserDisabled2fa as i32, &user.uuid, headers.device.atype, &headers.ip.ip, &mut conn)
             .await;
@@ -182,8 +200,10 @@ pub async fn enforce_2fa_policy(
         .await
         .into_iter()
     {
-        // Policy only applies to non-Owner/non-Admin members who have accepted joining the org
-        if member.atype < UserOrgType::Admin {
+        // Policy only applies to non-Owner/non-Admin members who have accepted joining the org, and had time to enroll
+        if member.atype < UserOrgType::Admin
+            && !member.in_2fa_grace_period(Utc::now().naive_utc(), CONFIG.org_2fa_grace_period_days())
+        {
             if CONFIG.mail_enabled() {
                 let org = Organization::find_by_uuid(&member.org_uuid, conn).await.unwrap();
                 mail::send_2fa_removed_from_org(&user.email, &org.name).await?;
@@ -208,6 +228,89 @@ pub async fn enforce_2fa_policy(
     Ok(())
 }
 
+/// The two-step login methods of the user accepted by the two-step login policies which apply to them, `None` if unrestricted,
+/// and the memberships of the organizations which don't accept any of the user's methods once their grace period ended.
+async fn check_2fa_method_policies(
+    user: &User,
+    user_methods: &[i32],
+    conn: &mut DbConn,
+) -> (Option<Vec<i32>>, Vec<UserOrganization>) {
+    let mut login_methods: Option<Vec<i32>> = None;
+    let mut unsatisfied = Vec::new();
+
+    for member in UserOrganization::find_by_user_and_policy(&user.uuid, OrgPolicyType::TwoFactorAuthentication, conn)
+        .await
+        .into_iter()
+    {
+        // Policy only applies to non-Owner/non-Admin members who have accepted joining the org
+        if member.atype >= UserOrgType::Admin {
+            continue;
+        }
+        let Some(allowed) =
+            OrgPolicy::find_by_org_and_type(&member.org_uuid, OrgPolicyType::TwoFactorAuthentication, conn)
+                .await
+                .and_then(|p| p.allowed_2fa_methods())
+        else {
+            continue;
+        };
+
+        if satisfies_2fa_policy(user_methods, Some(&allowed)) {
+            let current = login_methods.unwrap_or_else(|| user_methods.to_vec());
+            login_methods = Some(current.into_iter().filter(|m| allowed.contains(m)).collect());
+            continue;
+        }
+        // New members can still log in with their other methods to enroll an accepted one
+        if !member.in_2fa_grace_period(Utc::now().naive_utc(), CONFIG.org_2fa_grace_period_days()) {
+            unsatisfied.push(member);
+        }
+    }
+
+    // Organizations which accept disjoint methods can't all be satisfied with one login, don't restrict in that case
+    (login_methods.filter(|m| !m.is_empty()), unsatisfied)
+}
+
+/// The two-step login methods the user may log in with, `None` if unrestricted. Unlike `enforce_2fa_method_policy`,
+/// no membership is revoked, so it can be checked before the user proved their identity.
+pub async fn allowed_2fa_login_methods(user: &User, user_methods: &[i32], conn: &mut DbConn) -> Option<Vec<i32>> {
+    check_2fa_method_policies(user, user_methods, conn).await.0
+}
+
+/// Enforces the two-step login methods accepted by the two-step login policies which apply to the user.
+/// Memberships of organizations which don't accept any of the user's methods are revoked, so a stronger method
+/// has to be enrolled before they can be restored. Returns the methods the user may log in with, `None` if unrestricted.
+pub async fn enforce_2fa_method_policy(
+    user: &User,
+    user_methods: &[i32],
+    act_uuid: &str,
+    device_type: i32,
+    ip: &std::net::IpAddr,
+    conn: &mut DbConn,
+) -> ApiResult<Option<Vec<i32>>> {
+    let (login_methods, unsatisfied) = check_2fa_method_policies(user, user_methods, conn).await;
+
+    for mut member in unsatisfied {
+        if CONFIG.mail_enabled() {
+            let org = Organization::find_by_uuid(&member.org_uuid, conn).await.unwrap();
+            mail::send_2fa_method_removed_from_org(&user.email, &org.name).await?;
+        }
+        member.revoke();
+        member.save(conn).await?;
+
+        log_event(
+            EventType::OrganizationUserRevoked as i32,
+            &member.uuid,
+            &member.org_uuid,
+            act_uuid,
+            device_type,
+            ip,
+            conn,
+        )
+        .await;
+    }
+
+    Ok(login_methods)
+}
+
 pub async fn enforce_2fa_policy_for_org(
     org_uuid: &str,
     act_uuid: &str,
@@ -218,7 +321,10 @@ pub async fn enforce_2fa_policy_for_org(
     let org = Organization::find_by_uuid(org_uuid, conn).await.unwrap();
     for member in UserOrganization::find_confirmed_by_org(org_uuid, conn).await.into_iter() {
         // Don't enforce the policy for Admins and Owners.
-        if member.atype < UserOrgType::Admin && TwoFactor::find_by_user(&member.user_uuid, conn).await.is_empty() {
+        if member.atype < UserOrgType::Admin
+            && !member.in_2fa_grace_period(Utc::now().naive_utc(), CONFIG.org_2fa_grace_period_days())
+            && TwoFactor::find_by_user(&member.user_uuid, conn).await.is_empty()
+        {
             if CONFIG.mail_enabled() {
                 let user = User::find_by_uuid(&member.user_uuid, conn).await.unwrap();
                 mail::send_2fa_removed_from_org(&user.email, &org.name).await?;
@@ -268,9 +374,11 @@ pub async fn send_incomplete_2fa_notifications(pool: DbPool) {
             "User {} did not complete a 2FA login within the configured time limit. IP: {}",
             user.email, login.ip_address
         );
-        mail::send_incomplete_2fa_login(&user.email, &login.ip_address, &login.login_time, &login.device_name)
-            .await
-            .expect("Error sending incomplete 2FA email");
+        if NotificationPreference::is_enabled(&user.uuid, UserNotification::Incomplete2faLogin, &mut conn).await {
+            mail::send_incomplete_2fa_login(&user.email, &login.ip_address, &login.login_time, &login.device_name)
+                .await
+                .expect("Error sending incomplete 2FA email");
+        }
         login.delete(&mut conn).await.expect("Error deleting incomplete 2FA record");
     }
 }
@@ -82,6 +82,40 @@ impl webauthn_rs::WebauthnConfig for WebauthnConfig {
     fn get_require_uv_consistency(&self) -> bool {
         false
     }
+
+    /// The signature counter is checked by `validate_webauthn_login` instead,
+    /// so a possibly cloned authenticator can be reported with a clear error.
+    fn require_valid_counter_value(&self) -> bool {
+        false
+    }
+}
+
+/// The user verification to request from authenticators, see `WEBAUTHN_REQUIRE_USER_VERIFICATION`
+fn user_verification_policy(required: bool) -> UserVerificationPolicy {
+    if required {
+        UserVerificationPolicy::Required
+    } else {
+        UserVerificationPolicy::Discouraged
+    }
+}
+
+/// Rejects assertions without the user verified (PIN or biometric) flag when user verification is required
+fn check_user_verification(user_verified: bool, required: bool) -> EmptyResult {
+    if required && !user_verified {
+        err!(
+            "The security key did not verify the user with a PIN or biometric",
+            ErrorEvent {
+                event: EventType::UserFailedLogIn2fa
+            }
+        )
+    }
+    Ok(())
+}
+
+/// Checks the signature counter reported by an authenticator against the stored one.
+/// The counter has to increase, unless the authenticator doesn't implement it and always reports 0.
+fn is_valid_sign_count(stored: u32, reported: u32) -> bool {
+    (stored == 0 && reported == 0) || reported > stored
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -147,7 +181,7 @@ async fn generate_webauthn_challenge(
         user.email,
         user.name,
         Some(registrations),
-        None,
+        Some(user_verification_policy(CONFIG.webauthn_require_user_verification())),
         None,
     )?;
 
@@ -377,9 +411,17 @@ pub async fn get_webauthn_registrations(
 
 pub async fn generate_webauthn_login(user_uuid: &str, conn: &mut DbConn) -> JsonResult {
     // Load saved credentials
-    let creds: Vec<Credential> =
+    let mut creds: Vec<Credential> =
         get_webauthn_registrations(user_uuid, conn).await?.1.into_iter().map(|r| r.credential).collect();
 
+    // The requested user verification follows the policy the credentials were registered with,
+    // so keys registered before user verification was required are asked for it as well
+    if CONFIG.webauthn_require_user_verification() {
+        for cred in &mut creds {
+            cred.registration_policy = UserVerificationPolicy::Required;
+        }
+    }
+
     if creds.is_empty() {
         err!("No Webauthn devices registered")
     }
@@ -421,9 +463,22 @@ pub async fn validate_webauthn_login(user_uuid: &str, response: &str, conn: &mut
     // If the credential we received is migrated from U2F, enable the U2F compatibility
     //let use_u2f = registrations.iter().any(|r| r.migrated && r.credential.cred_id == rsp.raw_id.0);
     let (cred_id, auth_data) = WebauthnConfig::load().authenticate_credential(&rsp, &state)?;
+    check_user_verification(auth_data.user_verified, CONFIG.webauthn_require_user_verification())?;
 
     for reg in &mut registrations {
         if &reg.credential.cred_id == cred_id {
+            if !is_valid_sign_count(reg.credential.counter, auth_data.counter) {
+                warn!(
+                    "Security key '{}' of user {user_uuid} reported signature counter {} while {} was stored, it may be cloned",
+                    reg.name, auth_data.counter, reg.credential.counter
+                );
+                err!(
+                    "The security key's signature counter did not increase, it may have been cloned",
+                    ErrorEvent {
+                        event: EventType::UserFailedLogIn2fa
+                    }
+                )
+            }
             reg.credential.counter = auth_data.counter;
 
             TwoFactor::new(user_uuid.to_string(), TwoFactorType::Webauthn, serde_json::to_string(&registrations)?)
@@ -440,3 +495,35 @@ pub async fn validate_webauthn_login(user_uuid: &str, response: &str, conn: &mut
         }
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_count_regression_rejected() {
+        assert!(!is_valid_sign_count(10, 9));
+        assert!(!is_valid_sign_count(10, 10));
+        // Reporting 0 after a non-zero counter was stored is a regression as well
+        assert!(!is_valid_sign_count(10, 0));
+    }
+
+    #[test]
+    fn sign_count_accepted() {
+        assert!(is_valid_sign_count(10, 11));
+        assert!(is_valid_sign_count(0, 1));
+        // Authenticators without a counter always report 0
+        assert!(is_valid_sign_count(0, 0));
+    }
+
+    #[test]
+    fn assertion_without_user_verification_rejected() {
+        assert!(check_user_verification(false, true).is_err());
+        assert!(check_user_verification(true, true).is_ok());
+        // Only user presence is needed by default
+        assert!(check_user_verification(false, false).is_ok());
+
+        assert_eq!(user_verification_policy(true), UserVerificationPolicy::Required);
+        assert_eq!(user_verification_policy(false), UserVerificationPolicy::Discouraged);
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    error::Error as StdError,
     net::IpAddr,
     sync::{Arc, Mutex},
     time::{Duration, SystemTime},
@@ -21,6 +22,7 @@ use tokio::{
 use html5gum::{Emitter, HtmlString, InfallibleTokenizer, Readable, StringReader, Tokenizer};
 
 use crate::{
+    auth::ClientIp,
     error::Error,
     util::{get_reqwest_client_builder, Cached, CustomDnsResolver, CustomResolverError},
     CONFIG,
@@ -86,24 +88,60 @@ fn icon_external(domain: &str) -> Option<Redirect> {
     }
 }
 
-#[get("/<domain>/icon.png")]
-async fn icon_internal(domain: &str) -> Cached<(ContentType, Vec<u8>)> {
-    const FALLBACK_ICON: &[u8] = include_bytes!("../static/images/fallback-icon.png");
+// The icon served when no icon could be fetched, loaded only once
+static FALLBACK_ICON: Lazy<(&'static str, Vec<u8>)> = Lazy::new(|| {
+    const DEFAULT_FALLBACK_ICON: &[u8] = include_bytes!("../static/images/fallback-icon.png");
+
+    // The file is validated with the config, but it could have been removed or changed since
+    match CONFIG.icon_fallback_path().map(|path| load_fallback_icon(&path)) {
+        Some(Ok(icon)) => icon,
+        Some(Err(e)) => {
+            error!("Unable to load the fallback icon, using the default one: {e}");
+            ("png", DEFAULT_FALLBACK_ICON.to_vec())
+        }
+        None => ("png", DEFAULT_FALLBACK_ICON.to_vec()),
+    }
+});
+
+/// Loads a custom fallback icon and detects its type.
+pub fn load_fallback_icon(path: &str) -> Result<(&'static str, Vec<u8>), Error> {
+    let icon = match std::fs::read(path) {
+        Ok(icon) => icon,
+        Err(e) => err!(format!("Unable to read `{path}`: {e}")),
+    };
 
+    match get_icon_type(&icon).or_else(|| is_svg(&icon).then_some(SVG_ICON_TYPE)) {
+        Some(icon_type) => Ok((icon_type, icon)),
+        None => err!(format!("`{path}` is not a supported image")),
+    }
+}
+
+fn fallback_icon(fallback: &(&'static str, Vec<u8>), ttl: u64) -> Cached<(ContentType, Vec<u8>)> {
+    Cached::ttl((ContentType::new("image", fallback.0), fallback.1.clone()), ttl, true)
+}
+
+#[get("/<domain>/icon.png")]
+async fn icon_internal(domain: &str, ip: ClientIp) -> Result<Cached<(ContentType, Vec<u8>)>, Error> {
     if !is_valid_domain(domain) {
         warn!("Invalid domain: {}", domain);
-        return Cached::ttl(
-            (ContentType::new("image", "png"), FALLBACK_ICON.to_vec()),
-            CONFIG.icon_cache_negttl(),
-            true,
-        );
+        return Ok(fallback_icon(&FALLBACK_ICON, CONFIG.icon_cache_negttl()));
     }
 
-    match get_icon(domain).await {
-        Some((icon, icon_type)) => {
-            Cached::ttl((ContentType::new("image", icon_type), icon), CONFIG.icon_cache_ttl(), true)
+    let path = format!("{}/{}.png", CONFIG.icon_cache_folder(), domain);
+    let icon = match get_cached(&path).await {
+        Some(cached) => cached,
+        None => {
+            // Only requests which make the server fetch an icon are rate limited
+            crate::ratelimit::check_limit_icon(&ip.ip)?;
+            fetch_icon(domain, path).await
+        }
+    };
+
+    match icon {
+        Ok((icon, icon_type)) => {
+            Ok(Cached::ttl((ContentType::new("image", icon_type), icon), CONFIG.icon_cache_ttl(), true))
         }
-        _ => Cached::ttl((ContentType::new("image", "png"), FALLBACK_ICON.to_vec()), CONFIG.icon_cache_negttl(), true),
+        Err(miss) => Ok(fallback_icon(&FALLBACK_ICON, miss.ttl())),
     }
 }
 
@@ -168,44 +206,103 @@ pub fn is_domain_blacklisted(domain: &str) -> bool {
     is_match
 }
 
-async fn get_icon(domain: &str) -> Option<(Vec<u8>, String)> {
-    let path = format!("{}/{}.png", CONFIG.icon_cache_folder(), domain);
-
+/// The cached icon or miss at `path`, `None` when it has to be fetched
+async fn get_cached(path: &str) -> Option<Result<(Vec<u8>, String), IconMiss>> {
     // Check for expiration of negatively cached copy
-    if icon_is_negcached(&path).await {
-        return None;
+    if let Some(miss) = icon_is_negcached(path).await {
+        return Some(Err(miss));
     }
 
-    if let Some(icon) = get_cached_icon(&path).await {
-        let icon_type = match get_icon_type(&icon) {
-            Some(x) => x,
-            _ => "x-icon",
-        };
-        return Some((icon, icon_type.to_string()));
-    }
+    let icon = get_cached_icon(path).await?;
+    let icon_type = match get_allowed_icon_type(&icon) {
+        Some(x) => x,
+        // SVG icons cached before ICON_ALLOW_SVG was disabled are fetched again instead
+        None if is_svg(&icon) => return None,
+        None => "x-icon",
+    };
+    Some(Ok((icon, icon_type.to_string())))
+}
 
+async fn fetch_icon(domain: &str, path: String) -> Result<(Vec<u8>, String), IconMiss> {
     if CONFIG.disable_icon_download() {
-        return None;
+        return Err(IconMiss::Permanent);
     }
 
-    // Get the icon, or None in case of error
+    // Get the icon, or the kind of miss in case of error
     match download_icon(domain).await {
         Ok((icon, icon_type)) => {
+            let (icon, icon_type) = if CONFIG.icon_normalize() {
+                normalize_icon(&icon, icon_type, CONFIG.icon_normalize_max_size())
+            } else {
+                (icon.to_vec(), icon_type)
+            };
             save_icon(&path, &icon).await;
-            Some((icon.to_vec(), icon_type.unwrap_or("x-icon").to_string()))
+            Ok((icon, icon_type.unwrap_or("x-icon").to_string()))
         }
         Err(e) => {
             // If this error comes from the custom resolver, this means this is a blacklisted domain
             // or non global IP, don't save the miss file in this case to avoid leaking it
             if let Some(error) = CustomResolverError::downcast_ref(&e) {
                 warn!("{error}");
-                return None;
+                return Err(IconMiss::Permanent);
             }
 
-            warn!("Unable to download icon: {:?}", e);
+            let miss = IconMiss::from_error(&e);
+            warn!("Unable to download icon ({miss:?} failure): {:?}", e);
             let miss_indicator = path + ".miss";
-            save_icon(&miss_indicator, &[]).await;
-            None
+            save_icon(&miss_indicator, miss.marker()).await;
+            Err(miss)
+        }
+    }
+}
+
+/// The kind of failure which caused an icon to be negatively cached.
+/// Transient failures (timeouts, connection errors, server errors) are retried a lot sooner
+/// than permanent ones (no icon found, client errors), which use `ICON_CACHE_NEGTTL`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IconMiss {
+    Transient,
+    Permanent,
+}
+
+impl IconMiss {
+    const TRANSIENT_MARKER: &'static [u8] = b"transient";
+
+    fn from_error(e: &Error) -> Self {
+        let Some(req_err) = e.source().and_then(|s| s.downcast_ref::<reqwest::Error>()) else {
+            return Self::Permanent;
+        };
+
+        let transient_status =
+            req_err.status().is_some_and(|s| s.is_server_error() || s == reqwest::StatusCode::TOO_MANY_REQUESTS);
+        if req_err.is_timeout() || req_err.is_connect() || transient_status {
+            Self::Transient
+        } else {
+            Self::Permanent
+        }
+    }
+
+    /// The contents of the `.miss` indicator file.
+    /// Permanent misses keep using an empty file, which is what older versions wrote.
+    fn marker(self) -> &'static [u8] {
+        match self {
+            Self::Transient => Self::TRANSIENT_MARKER,
+            Self::Permanent => &[],
+        }
+    }
+
+    fn from_marker(marker: &[u8]) -> Self {
+        if marker == Self::TRANSIENT_MARKER {
+            Self::Transient
+        } else {
+            Self::Permanent
+        }
+    }
+
+    fn ttl(self) -> u64 {
+        match self {
+            Self::Transient => CONFIG.icon_cache_transient_negttl(),
+            Self::Permanent => CONFIG.icon_cache_negttl(),
         }
     }
 }
@@ -236,9 +333,14 @@ async fn file_is_expired(path: &str, ttl: u64) -> Result<bool, Error> {
     Ok(ttl > 0 && ttl <= age.as_secs())
 }
 
-async fn icon_is_negcached(path: &str) -> bool {
+async fn icon_is_negcached(path: &str) -> Option<IconMiss> {
     let miss_indicator = path.to_owned() + ".miss";
-    let expired = file_is_expired(&miss_indicator, CONFIG.icon_cache_negttl()).await;
+    let miss = match tokio::fs::read(&miss_indicator).await {
+        Ok(marker) => IconMiss::from_marker(&marker),
+        // The marker is missing or inaccessible in some way.
+        Err(_) => return None,
+    };
+    let expired = file_is_expired(&miss_indicator, miss.ttl()).await;
 
     match expired {
         // No longer negatively cached, drop the marker
@@ -246,12 +348,12 @@ async fn icon_is_negcached(path: &str) -> bool {
             if let Err(e) = remove_file(&miss_indicator).await {
                 error!("Could not remove negative cache indicator for icon {:?}: {:?}", path, e);
             }
-            false
+            None
         }
         // The marker hasn't expired yet.
-        Ok(false) => true,
+        Ok(false) => Some(miss),
         // The marker is missing or inaccessible in some way.
-        Err(_) => false,
+        Err(_) => None,
     }
 }
 
@@ -526,11 +628,11 @@ fn parse_sizes(sizes: &str) -> (u16, u16) {
     (width, height)
 }
 
-async fn download_icon(domain: &str) -> Result<(Bytes, Option<&str>), Error> {
+async fn download_icon(domain: &str) -> Result<(Bytes, Option<&'static str>), Error> {
     let icon_result = get_icon_url(domain).await?;
 
     let mut buffer = Bytes::new();
-    let mut icon_type: Option<&str> = None;
+    let mut icon_type: Option<&'static str> = None;
 
     use data_url::DataUrl;
 
@@ -549,7 +651,7 @@ async fn download_icon(domain: &str) -> Result<(Bytes, Option<&str>), Error> {
                     // Also check if the size is atleast 67 bytes, which seems to be the smallest png i could create
                     if body.len() >= 67 {
                         // Check if the icon type is allowed, else try an icon from the list.
-                        icon_type = get_icon_type(&body);
+                        icon_type = get_allowed_icon_type(&body);
                         if icon_type.is_none() {
                             debug!("Icon from {} data:image uri, is not a valid image type", domain);
                             continue;
@@ -567,7 +669,7 @@ async fn download_icon(domain: &str) -> Result<(Bytes, Option<&str>), Error> {
             buffer = stream_to_bytes_limit(res, 5120 * 1024).await?; // 5120KB/5MB for each icon max (Same as icons.bitwarden.net)
 
             // Check if the icon type is allowed, else try an icon from the list.
-            icon_type = get_icon_type(&buffer);
+            icon_type = get_allowed_icon_type(&buffer);
             if icon_type.is_none() {
                 buffer.clear();
                 debug!("Icon from {}, is not a valid image type", icon.href);
@@ -599,7 +701,7 @@ async fn save_icon(path: &str, icon: &[u8]) {
     }
 }
 
-fn get_icon_type(bytes: &[u8]) -> Option<&'static str> {
+pub(crate) fn get_icon_type(bytes: &[u8]) -> Option<&'static str> {
     match bytes {
         [137, 80, 78, 71, ..] => Some("png"),
         [0, 0, 1, 0, ..] => Some("x-icon"),
@@ -611,6 +713,71 @@ fn get_icon_type(bytes: &[u8]) -> Option<&'static str> {
     }
 }
 
+const SVG_ICON_TYPE: &str = "svg+xml";
+
+fn is_svg(bytes: &[u8]) -> bool {
+    // Only look at the start of the file, after an optional XML declaration and comments
+    let start = String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]).to_lowercase();
+    let start = start.trim_start_matches('\u{feff}').trim_start();
+    (start.starts_with("<svg") || start.starts_with("<?xml") || start.starts_with("<!--")) && start.contains("<svg")
+}
+
+/// Same as `get_icon_type`, but also allows SVG icons if enabled
+fn get_allowed_icon_type(bytes: &[u8]) -> Option<&'static str> {
+    get_icon_type(bytes).or_else(|| (CONFIG.icon_allow_svg() && is_svg(bytes)).then_some(SVG_ICON_TYPE))
+}
+
+/// Downscales icons larger than `max_size` and converts them to PNG.
+/// Icons which are already small enough PNGs, SVGs and icons which can't be decoded are returned as-is.
+fn normalize_icon(icon: &[u8], icon_type: Option<&'static str>, max_size: u32) -> (Vec<u8>, Option<&'static str>) {
+    use image::{imageops::FilterType, io::Limits, io::Reader, ImageOutputFormat};
+    use std::io::Cursor;
+
+    if icon_type == Some(SVG_ICON_TYPE) {
+        return (icon.to_vec(), icon_type);
+    }
+
+    // Don't even try to decode unreasonably large images
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(4096);
+    limits.max_image_height = Some(4096);
+    limits.max_alloc = Some(64 * 1024 * 1024);
+
+    let mut reader = match Reader::new(Cursor::new(icon)).with_guessed_format() {
+        Ok(reader) => reader,
+        Err(_) => return (icon.to_vec(), icon_type),
+    };
+    reader.limits(limits);
+    let img = match reader.decode() {
+        Ok(img) => img,
+        Err(e) => {
+            debug!("Unable to decode icon for normalization: {e}");
+            return (icon.to_vec(), icon_type);
+        }
+    };
+
+    let oversized = img.width() > max_size || img.height() > max_size;
+    if !oversized && icon_type == Some("png") {
+        return (icon.to_vec(), icon_type);
+    }
+
+    // This keeps the aspect ratio of the icon
+    let img = if oversized {
+        img.resize(max_size, max_size, FilterType::Lanczos3)
+    } else {
+        img
+    };
+
+    let mut normalized = Cursor::new(Vec::new());
+    match img.write_to(&mut normalized, ImageOutputFormat::Png) {
+        Ok(_) => (normalized.into_inner(), Some("png")),
+        Err(e) => {
+            debug!("Unable to encode normalized icon: {e}");
+            (icon.to_vec(), icon_type)
+        }
+    }
+}
+
 /// Minimize the amount of bytes to be parsed from a reqwest result.
 /// This prevents very long parsing and memory usage.
 async fn stream_to_bytes_limit(res: Response, max_size: usize) -> Result<Bytes, reqwest::Error> {
@@ -832,3 +999,85 @@ impl Emitter for FaviconEmitter {
     fn set_force_quirks(&mut self) {}
     fn set_self_closing(&mut self) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn icon_miss_marker_roundtrip() {
+        assert_eq!(IconMiss::from_marker(IconMiss::Transient.marker()), IconMiss::Transient);
+        assert_eq!(IconMiss::from_marker(IconMiss::Permanent.marker()), IconMiss::Permanent);
+        // Markers written by older versions are always empty files
+        assert_eq!(IconMiss::from_marker(b""), IconMiss::Permanent);
+    }
+
+    #[test]
+    fn icon_miss_permanent_when_no_icon_found() {
+        let e = Error::new("Empty response or unable find a valid icon", "example.com");
+        assert_eq!(IconMiss::from_error(&e), IconMiss::Permanent);
+    }
+
+    #[test]
+    fn icon_miss_transient_on_connection_failure() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let e: Error = runtime
+            .block_on(reqwest::Client::new().get("http://127.0.0.1:9/favicon.ico").send())
+            .expect_err("nothing should be listening on the discard port")
+            .into();
+        assert_eq!(IconMiss::from_error(&e), IconMiss::Transient);
+    }
+
+    fn png_icon(width: u32, height: u32) -> Vec<u8> {
+        let img = image::DynamicImage::new_rgba8(width, height);
+        let mut png = std::io::Cursor::new(Vec::new());
+        img.write_to(&mut png, image::ImageOutputFormat::Png).unwrap();
+        png.into_inner()
+    }
+
+    #[test]
+    fn normalize_icon_downscales_oversized() {
+        let (icon, icon_type) = normalize_icon(&png_icon(512, 256), Some("png"), 128);
+        assert_eq!(icon_type, Some("png"));
+
+        let img = image::load_from_memory(&icon).unwrap();
+        assert_eq!((img.width(), img.height()), (128, 64));
+    }
+
+    #[test]
+    fn normalize_icon_passes_small_through() {
+        let small = png_icon(32, 32);
+        let (icon, icon_type) = normalize_icon(&small, Some("png"), 128);
+        assert_eq!(icon_type, Some("png"));
+        assert_eq!(icon, small);
+
+        // SVG icons and icons which can't be decoded are kept as-is
+        let svg = br#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg"></svg>"#;
+        assert!(is_svg(svg));
+        assert_eq!(normalize_icon(svg, Some(SVG_ICON_TYPE), 128), (svg.to_vec(), Some(SVG_ICON_TYPE)));
+        assert_eq!(normalize_icon(b"not an icon", None, 128), (b"not an icon".to_vec(), None));
+    }
+
+    #[test]
+    fn custom_fallback_icon_served() {
+        let dir = std::env::temp_dir().join(format!("vw-fallback-icon-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("fallback.png");
+        let custom = png_icon(32, 32);
+        std::fs::write(&path, &custom).unwrap();
+
+        let fallback = load_fallback_icon(path.to_str().unwrap()).unwrap();
+        assert_eq!(fallback, ("png", custom.clone()));
+
+        // Blocked and failed fetches both serve the fallback icon
+        let served = fallback_icon(&fallback, 600);
+        assert_eq!(served.response(), &(ContentType::PNG, custom));
+
+        // Missing files and non-images are refused
+        assert!(load_fallback_icon(dir.join("missing.png").to_str().unwrap()).is_err());
+        std::fs::write(&path, b"not an image").unwrap();
+        assert!(load_fallback_icon(path.to_str().unwrap()).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 use chrono::Utc;
 use num_traits::FromPrimitive;
+use once_cell::sync::Lazy;
 use rocket::serde::json::Json;
 use rocket::{
     form::{Form, FromForm},
@@ -11,13 +12,17 @@ use crate::{
     api::{
         core::{
             accounts::{PreloginData, RegisterData, _prelogin, _register},
-            log_user_event,
-            two_factor::{authenticator, duo, email, enforce_2fa_policy, webauthn, yubikey},
+            log_user_event, log_user_event_with_detail,
+            two_factor::{
+                allowed_2fa_login_methods, authenticator, duo, email, enforce_2fa_method_policy, enforce_2fa_policy,
+                webauthn, yubikey,
+            },
         },
         push::register_push_device,
         ApiResult, EmptyResult, JsonResult, JsonUpcase,
     },
     auth::{generate_organization_api_key_login_claims, ClientHeaders, ClientIp},
+    crypto,
     db::{models::*, DbConn},
     error::MapResult,
     mail, util, CONFIG,
@@ -31,12 +36,16 @@ pub fn routes() -> Vec<Route> {
 async fn login(data: Form<ConnectData>, client_header: ClientHeaders, mut conn: DbConn) -> JsonResult {
     let data: ConnectData = data.into_inner();
 
+    if let Some(client_type) = ClientType::detect(client_header.client_name.as_deref(), data.device_type.as_deref()) {
+        check_client_version(client_header.client_version.as_deref(), client_type.min_version().as_deref())?;
+    }
+
     let mut user_uuid: Option<String> = None;
 
     let login_result = match data.grant_type.as_ref() {
         "refresh_token" => {
             _check_is_some(&data.refresh_token, "refresh_token cannot be blank")?;
-            _refresh_login(data, &mut conn).await
+            _refresh_login(data, &mut conn, &client_header.ip).await
         }
         "password" => {
             _check_is_some(&data.client_id, "client_id cannot be blank")?;
@@ -78,11 +87,12 @@ async fn login(data: Form<ConnectData>, client_header: ClientHeaders, mut conn:
             }
             Err(e) => {
                 if let Some(ev) = e.get_event() {
-                    log_user_event(
+                    log_user_event_with_detail(
                         ev.event as i32,
                         &user_uuid,
                         client_header.device_type,
                         &client_header.ip.ip,
+                        e.get_event_detail(),
                         &mut conn,
                     )
                     .await
@@ -94,18 +104,27 @@ async fn login(data: Form<ConnectData>, client_header: ClientHeaders, mut conn:
     login_result
 }
 
-async fn _refresh_login(data: ConnectData, conn: &mut DbConn) -> JsonResult {
+async fn _refresh_login(data: ConnectData, conn: &mut DbConn, ip: &ClientIp) -> JsonResult {
     // Extract token
     let token = data.refresh_token.unwrap();
 
-    // Get device by refresh token
-    let mut device = Device::find_by_refresh_token(&token, conn).await.map_res("Invalid refresh token")?;
+    // Get device and user by refresh token
+    let (mut device, user) = find_refresh_device(&token, conn).await?;
 
     let scope = "api offline_access";
     let scope_vec = vec!["api".into(), "offline_access".into()];
 
     // Common
-    let user = User::find_by_uuid(&device.user_uuid, conn).await.unwrap();
+    // Members whose two-step login grace period ended lose their memberships, also when they don't log in again
+    if CONFIG.org_2fa_grace_period_days() > 0 {
+        let methods: Vec<_> =
+            TwoFactor::find_by_user(&user.uuid, conn).await.iter().filter(|tf| tf.enabled).map(|tf| tf.atype).collect();
+        if methods.is_empty() {
+            enforce_2fa_policy(&user, &user.uuid, device.atype, &ip.ip, conn).await?;
+        } else {
+            enforce_2fa_method_policy(&user, &methods, &user.uuid, device.atype, &ip.ip, conn).await?;
+        }
+    }
     // ---
     // Disabled this variable, it was used to generate the JWT
     // Because this might get used in the future, and is add by the Bitwarden Server, lets keep it, but then commented out
@@ -135,6 +154,47 @@ async fn _refresh_login(data: ConnectData, conn: &mut DbConn) -> JsonResult {
     Ok(Json(result))
 }
 
+/// The device of a refresh token and its user.
+/// Disabled users, by an admin or for inactivity, can't refresh the sessions of their remaining devices.
+async fn find_refresh_device(token: &str, conn: &mut DbConn) -> ApiResult<(Device, User)> {
+    let device = Device::find_by_refresh_token(token, conn).await.map_res("Invalid refresh token")?;
+    let Some(user) = User::find_by_uuid(&device.user_uuid, conn).await else {
+        err!("Invalid refresh token")
+    };
+    if !user.enabled {
+        err!("This user has been disabled")
+    }
+    Ok((device, user))
+}
+
+/// Verifies a password against a random hash, to spend the same time on logins of unknown users as on those of existing users
+fn verify_dummy_password(password: &str, iterations: u32) {
+    static DUMMY_SALT: Lazy<[u8; 64]> = Lazy::new(crypto::get_random_bytes::<64>);
+    static DUMMY_HASH: Lazy<[u8; 32]> = Lazy::new(crypto::get_random_bytes::<32>);
+    crypto::verify_password_hash(password.as_bytes(), &*DUMMY_SALT, &*DUMMY_HASH, iterations);
+}
+
+// Counts the delayed failed logins of the current thread, so the tests can check that every failure path is delayed
+#[cfg(test)]
+thread_local! {
+    static DELAYED_LOGIN_FAILURES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Registers a failed login for the CAPTCHA and adds a random delay to it,
+/// to blur the remaining timing differences between the failure paths
+async fn delay_login_failure(ip: &ClientIp) {
+    #[cfg(test)]
+    DELAYED_LOGIN_FAILURES.with(|failures| failures.set(failures.get() + 1));
+
+    crate::captcha::register_login_failure(&ip.ip);
+    let max_jitter = CONFIG.login_error_jitter_ms();
+    if max_jitter > 0 {
+        use rand::Rng;
+        let jitter = rand::thread_rng().gen_range(0..=max_jitter);
+        tokio::time::sleep(tokio::time::Duration::from_millis(jitter)).await;
+    }
+}
+
 async fn _password_login(
     data: ConnectData,
     user_uuid: &mut Option<String>,
@@ -150,12 +210,20 @@ async fn _password_login(
 
     // Ratelimit the login
     crate::ratelimit::check_limit_login(&ip.ip)?;
+    crate::captcha::check_login_captcha(&ip.ip, data.captcha_response.as_deref()).await?;
 
     // Get the user
     let username = data.username.as_ref().unwrap().trim();
     let mut user = match User::find_by_mail(username, conn).await {
         Some(user) => user,
-        None => err!("Username or password is incorrect. Try again", format!("IP: {}. Username: {}.", ip.ip, username)),
+        None => {
+            // Take as long as a wrong password of an existing user would
+            if data.auth_request.is_none() {
+                verify_dummy_password(data.password.as_ref().unwrap(), CONFIG.password_iterations() as u32);
+            }
+            delay_login_failure(ip).await;
+            err!("Username or password is incorrect. Try again", format!("IP: {}. Username: {}.", ip.ip, username))
+        }
     };
 
     // Set the user_uuid here to be passed back used for event logging.
@@ -166,6 +234,7 @@ async fn _password_login(
     if let Some(auth_request_uuid) = data.auth_request.clone() {
         if let Some(auth_request) = AuthRequest::find_by_uuid(auth_request_uuid.as_str(), conn).await {
             if !auth_request.check_access_code(password) {
+                delay_login_failure(ip).await;
                 err!(
                     "Username or access code is incorrect. Try again",
                     format!("IP: {}. Username: {}.", ip.ip, username),
@@ -175,6 +244,7 @@ async fn _password_login(
                 )
             }
         } else {
+            delay_login_failure(ip).await;
             err!(
                 "Auth request not found. Try again.",
                 format!("IP: {}. Username: {}.", ip.ip, username),
@@ -184,6 +254,7 @@ async fn _password_login(
             )
         }
     } else if !user.check_valid_password(password) {
+        delay_login_failure(ip).await;
         err!(
             "Username or password is incorrect. Try again",
             format!("IP: {}. Username: {}.", ip.ip, username),
@@ -252,7 +323,12 @@ async fn _password_login(
 
     let twofactor_token = twofactor_auth(&user, &data, &mut device, ip, conn).await?;
 
-    if CONFIG.mail_enabled() && new_device {
+    check_device_approval(&user, &device, new_device, ip, conn).await?;
+
+    if CONFIG.mail_enabled()
+        && new_device
+        && NotificationPreference::is_enabled(&user.uuid, UserNotification::NewDeviceLogin, conn).await
+    {
         if let Err(e) = mail::send_new_device_logged_in(&user.email, &ip.ip.to_string(), &now, &device.name).await {
             error!("Error sending new device email: {:#?}", e);
 
@@ -379,7 +455,12 @@ async fn _user_api_key_login(
 
     let (mut device, new_device) = get_device(&data, conn, &user).await;
 
-    if CONFIG.mail_enabled() && new_device {
+    check_device_approval(&user, &device, new_device, ip, conn).await?;
+
+    if CONFIG.mail_enabled()
+        && new_device
+        && NotificationPreference::is_enabled(&user.uuid, UserNotification::NewDeviceLogin, conn).await
+    {
         let now = Utc::now().naive_utc();
         if let Err(e) = mail::send_new_device_logged_in(&user.email, &ip.ip.to_string(), &now, &device.name).await {
             error!("Error sending new device email: {:#?}", e);
@@ -436,7 +517,7 @@ async fn _organization_api_key_login(data: ConnectData, conn: &mut DbConn, ip: &
         Some(uuid) => uuid,
         None => err!("Malformed client_id", format!("IP: {}.", ip.ip)),
     };
-    let org_api_key = match OrganizationApiKey::find_by_org_uuid(org_uuid, conn).await {
+    let mut org_api_key = match OrganizationApiKey::find_by_org_uuid(org_uuid, conn).await {
         Some(org_api_key) => org_api_key,
         None => err!("Invalid client_id", format!("IP: {}.", ip.ip)),
     };
@@ -447,6 +528,14 @@ async fn _organization_api_key_login(data: ConnectData, conn: &mut DbConn, ip: &
         err!("Incorrect client_secret", format!("IP: {}. Organization: {}.", ip.ip, org_api_key.org_uuid))
     }
 
+    // Replace a legacy plaintext API key with its hash
+    if !org_api_key.is_hashed() {
+        let revision_date = org_api_key.revision_date;
+        org_api_key.set_api_key(client_secret);
+        org_api_key.revision_date = revision_date;
+        org_api_key.save(conn).await?;
+    }
+
     let claim = generate_organization_api_key_login_claims(org_api_key.uuid, org_api_key.org_uuid);
     let access_token = crate::auth::encode_jwt(&claim);
 
@@ -459,6 +548,76 @@ async fn _organization_api_key_login(data: ConnectData, conn: &mut DbConn, ip: &
     })))
 }
 
+/// Holds the first login from an unknown device of a member of organizations with the device approval policy,
+/// until an admin of each of those organizations approved the device. Known devices are never held.
+async fn check_device_approval(
+    user: &User,
+    device: &Device,
+    new_device: bool,
+    ip: &ClientIp,
+    conn: &mut DbConn,
+) -> EmptyResult {
+    if !new_device {
+        return Ok(());
+    }
+
+    let required_orgs = OrgPolicy::find_device_approval_org_uuids(&user.uuid, conn).await;
+    let approvals = DeviceApproval::find_by_user_and_device(&user.uuid, &device.uuid, conn).await;
+    let pending_orgs = DeviceApproval::pending_orgs(&required_orgs, &approvals);
+    if pending_orgs.is_empty() {
+        return Ok(());
+    }
+
+    for org_uuid in pending_orgs {
+        // Only the first login creates a request, retries wait for the existing one
+        if approvals.iter().any(|a| a.org_uuid == org_uuid) {
+            continue;
+        }
+        let approval = DeviceApproval::new(
+            user.uuid.clone(),
+            org_uuid.to_string(),
+            device.uuid.clone(),
+            device.name.clone(),
+            device.atype,
+            util::stored_ip(&ip.ip),
+        );
+        approval.save(conn).await?;
+
+        if CONFIG.mail_enabled() {
+            let Some(org) = Organization::find_by_uuid(org_uuid, conn).await else {
+                continue;
+            };
+            for admin in UserOrganization::find_confirmed_by_org(org_uuid, conn).await {
+                if admin.atype < UserOrgType::Admin {
+                    continue;
+                }
+                let Some(admin_user) = User::find_by_uuid(&admin.user_uuid, conn).await else {
+                    continue;
+                };
+                if let Err(e) = mail::send_device_approval_requested(
+                    &admin_user.email,
+                    &user.email,
+                    &org.name,
+                    &ip.ip.to_string(),
+                    &device.name,
+                )
+                .await
+                {
+                    error!("Error sending device approval email: {:#?}", e);
+                }
+            }
+        }
+    }
+
+    err!(
+        "This device has to be approved by an administrator of your organization before it can be used. Try again once it is approved.",
+        format!("IP: {}. Username: {}. Device awaiting approval.", ip.ip, user.email),
+        ErrorEvent {
+            event: EventType::UserFailedLogIn
+        }
+    )
+}
+
 /// Retrieves an existing device or creates a new device from ConnectData and the User
 async fn get_device(data: &ConnectData, conn: &mut DbConn, user: &User) -> (Device, bool) {
     // On iOS, device_type sends "iOS", on others it sends a number
@@ -497,8 +656,22 @@ async fn twofactor_auth(
 
     TwoFactorIncomplete::mark_incomplete(&user.uuid, &device.uuid, &device.name, ip, conn).await?;
 
-    let twofactor_ids: Vec<_> = twofactors.iter().map(|tf| tf.atype).collect();
-    let selected_id = data.two_factor_provider.unwrap_or(twofactor_ids[0]); // If we aren't given a two factor provider, assume the first one
+    let mut twofactor_ids: Vec<_> = twofactors.iter().map(|tf| tf.atype).collect();
+    let mut selected_id = data.two_factor_provider.unwrap_or(twofactor_ids[0]); // If we aren't given a two factor provider, assume the first one
+
+    // Only offer the methods accepted by the two-step login policies of the user's organizations
+    let enabled_ids: Vec<_> = twofactors.iter().filter(|tf| tf.enabled).map(|tf| tf.atype).collect();
+    if let Some(allowed_ids) = allowed_2fa_login_methods(user, &enabled_ids, conn).await {
+        twofactor_ids.retain(|id| allowed_ids.contains(id));
+        if data.two_factor_provider.is_none() {
+            selected_id = twofactor_ids[0];
+        } else if selected_id != TwoFactorType::Remember as i32 && !twofactor_ids.contains(&selected_id) {
+            err_json!(
+                _json_err_twofactor(&twofactor_ids, &user.uuid, conn).await?,
+                "This two-step login method is not accepted by your organization"
+            )
+        }
+    }
 
     let twofactor_code = match data.two_factor_token {
         Some(ref code) => code,
@@ -507,8 +680,6 @@ async fn twofactor_auth(
 
     let selected_twofactor = twofactors.into_iter().find(|tf| tf.atype == selected_id && tf.enabled);
 
-    use crate::crypto::ct_eq;
-
     let selected_data = _selected_data(selected_twofactor);
     let mut remember = data.two_factor_remember.unwrap_or(0);
 
@@ -526,11 +697,24 @@ async fn twofactor_auth(
         }
 
         Some(TwoFactorType::Remember) => {
-            match device.twofactor_remember {
-                Some(ref code) if !CONFIG.disable_2fa_remember() && ct_eq(code, twofactor_code) => {
+            let check = device.check_twofactor_remember(
+                twofactor_code,
+                Utc::now().naive_utc(),
+                CONFIG.two_factor_remember_max_days(),
+            );
+            match check {
+                TwoFactorRemember::Valid if !CONFIG.disable_2fa_remember() => {
                     remember = 1; // Make sure we also return the token here, otherwise it will only remember the first time
                 }
                 _ => {
+                    if matches!(check, TwoFactorRemember::Expired | TwoFactorRemember::Replayed) {
+                        if check == TwoFactorRemember::Replayed {
+                            warn!("Replayed 2FA remember token for device {} of user {}", device.uuid, user.uuid);
+                        }
+                        // The other two-step login methods are needed to remember the device again
+                        device.delete_twofactor_remember();
+                        device.save(conn).await?;
+                    }
                     err_json!(
                         _json_err_twofactor(&twofactor_ids, &user.uuid, conn).await?,
                         "2FA Remember token not provided"
@@ -546,9 +730,16 @@ async fn twofactor_auth(
         ),
     }
 
+    // Only a login which passed the two-step login can revoke the memberships which don't accept the user's methods
+    enforce_2fa_method_policy(user, &enabled_ids, &user.uuid, device.atype, &ip.ip, conn).await?;
+
     TwoFactorIncomplete::mark_complete(&user.uuid, &device.uuid, conn).await?;
 
     if !CONFIG.disable_2fa_remember() && remember == 1 {
+        if selected_id != TwoFactorType::Remember as i32 {
+            // A new grant, which starts a new max age
+            device.delete_twofactor_remember();
+        }
         Ok(Some(device.refresh_twofactor_remember()))
     } else {
         device.delete_twofactor_remember();
@@ -560,17 +751,24 @@ fn _selected_data(tf: Option<TwoFactor>) -> ApiResult<String> {
     tf.map(|t| t.data).map_res("Two factor doesn't exist")
 }
 
-async fn _json_err_twofactor(providers: &[i32], user_uuid: &str, conn: &mut DbConn) -> ApiResult<Value> {
-    let mut result = json!({
-        "error" : "invalid_grant",
-        "error_description" : "Two factor required.",
-        "TwoFactorProviders" : providers,
-        "TwoFactorProviders2" : {} // { "0" : null }
-    });
+// The 2FA challenge sent to the clients, in the same envelope as the other errors
+fn _json_twofactor_required(providers: &[i32]) -> Value {
+    let mut result = crate::error::api_error_json("Two factor required.");
+    result["error"] = json!("invalid_grant");
+    result["error_description"] = json!("Two factor required.");
+    result["TwoFactorProviders"] = json!(providers);
+    result["TwoFactorProviders2"] = json!({}); // { "0" : null }
 
     for provider in providers {
         result["TwoFactorProviders2"][provider.to_string()] = Value::Null;
+    }
+    result
+}
 
+async fn _json_err_twofactor(providers: &[i32], user_uuid: &str, conn: &mut DbConn) -> ApiResult<Value> {
+    let mut result = _json_twofactor_required(providers);
+
+    for provider in providers {
         match TwoFactorType::from_i32(*provider) {
             Some(TwoFactorType::Authenticator) => { /* Nothing to do for TOTP */ }
 
@@ -694,6 +892,9 @@ struct ConnectData {
     two_factor_remember: Option<i32>,
     #[field(name = uncased("authrequest"))]
     auth_request: Option<String>,
+    #[field(name = uncased("captcha_response"))]
+    #[field(name = uncased("captcharesponse"))]
+    captcha_response: Option<String>,
 }
 
 fn _check_is_some<T>(value: &Option<T>, msg: &str) -> EmptyResult {
@@ -702,3 +903,185 @@ fn _check_is_some<T>(value: &Option<T>, msg: &str) -> EmptyResult {
     }
     Ok(())
 }
+
+/// The client types which can be required to have a minimum version
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ClientType {
+    Web,
+    Desktop,
+    Mobile,
+    Cli,
+}
+
+impl ClientType {
+    /// Detects the client by the `Bitwarden-Client-Name` header, or else by the device type of the login request
+    fn detect(client_name: Option<&str>, device_type: Option<&str>) -> Option<Self> {
+        if let Some(client_name) = client_name {
+            return match client_name {
+                "web" => Some(Self::Web),
+                "desktop" => Some(Self::Desktop),
+                "mobile" => Some(Self::Mobile),
+                "cli" => Some(Self::Cli),
+                // Browser extensions and other clients have no minimum version
+                _ => None,
+            };
+        }
+
+        // Upstream enum: https://github.com/bitwarden/server/blob/main/src/Core/Enums/DeviceType.cs
+        match device_type?.parse::<i32>().ok()? {
+            0 | 1 | 15 => Some(Self::Mobile),
+            6..=8 | 16 => Some(Self::Desktop),
+            9..=14 | 17 | 18 => Some(Self::Web),
+            23..=25 => Some(Self::Cli),
+            _ => None,
+        }
+    }
+
+    fn min_version(self) -> Option<String> {
+        match self {
+            Self::Web => CONFIG.min_client_version_web(),
+            Self::Desktop => CONFIG.min_client_version_desktop(),
+            Self::Mobile => CONFIG.min_client_version_mobile(),
+            Self::Cli => CONFIG.min_client_version_cli(),
+        }
+    }
+}
+
+/// Refuses clients older than the minimum version, also when they don't report a version
+fn check_client_version(client_version: Option<&str>, min_version: Option<&str>) -> EmptyResult {
+    let Some(min_version) = min_version else {
+        return Ok(());
+    };
+
+    let outdated = match (client_version.and_then(util::parse_client_version), util::parse_client_version(min_version))
+    {
+        (Some(version), Some(min)) => version < min,
+        // The minimum version is validated with the config
+        (None, _) | (_, None) => true,
+    };
+    if outdated {
+        err!(
+            format!("This version of the app is no longer supported, please update to version {min_version} or newer"),
+            format!("Client version {client_version:?} is older than {min_version}")
+        )
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(sqlite)]
+    fn inactive_user_disabled_login_refused() {
+        use crate::api::core::accounts::disable_inactive_account;
+
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut conn = DbConn::sqlite_in_memory();
+            conn.insert_user("user").await;
+            let mut user = User::find_by_uuid("user", &mut conn).await.unwrap();
+            let mut device = Device::new(String::from("device"), user.uuid.clone(), String::from("phone"), 0);
+            device.refresh_token = String::from("refresh-token");
+            device.save(&mut conn).await.unwrap();
+            assert!(find_refresh_device("refresh-token", &mut conn).await.is_ok());
+
+            disable_inactive_account(&mut user, &mut conn).await.unwrap();
+            assert!(find_refresh_device("refresh-token", &mut conn).await.is_err());
+
+            // A device registered again still can't refresh the session of the disabled user
+            device.save(&mut conn).await.unwrap();
+            let refused = find_refresh_device("refresh-token", &mut conn).await.err().unwrap();
+            assert!(refused.to_string().contains("This user has been disabled"));
+        });
+    }
+
+    #[test]
+    fn outdated_client_blocked() {
+        assert!(check_client_version(Some("2023.12.1"), Some("2024.1.0")).is_err());
+        assert!(check_client_version(Some("2024.1.0-beta"), Some("2024.1.1")).is_err());
+        // Without a version header a client can't be shown to be recent enough
+        assert!(check_client_version(None, Some("2024.1.0")).is_err());
+    }
+
+    #[test]
+    fn current_client_allowed() {
+        assert!(check_client_version(Some("2024.1.0"), Some("2024.1.0")).is_ok());
+        assert!(check_client_version(Some("2024.6.0 (19823)"), Some("2024.1")).is_ok());
+        // No minimum configured
+        assert!(check_client_version(Some("2020.1.0"), None).is_ok());
+        assert!(check_client_version(None, None).is_ok());
+    }
+
+    #[test]
+    fn client_type_detected() {
+        assert_eq!(ClientType::detect(Some("mobile"), Some("9")), Some(ClientType::Mobile));
+        assert_eq!(ClientType::detect(Some("browser"), None), None);
+        assert_eq!(ClientType::detect(None, Some("1")), Some(ClientType::Mobile));
+        assert_eq!(ClientType::detect(None, Some("8")), Some(ClientType::Desktop));
+        assert_eq!(ClientType::detect(None, Some("10")), Some(ClientType::Web));
+        assert_eq!(ClientType::detect(None, Some("25")), Some(ClientType::Cli));
+        assert_eq!(ClientType::detect(None, Some("2")), None);
+        assert_eq!(ClientType::detect(None, None), None);
+    }
+
+    #[test]
+    fn twofactor_required_envelope() {
+        let providers = [TwoFactorType::Authenticator as i32, TwoFactorType::Duo as i32];
+        let json = _json_twofactor_required(&providers);
+
+        assert_eq!(json["error"], "invalid_grant");
+        assert_eq!(json["error_description"], "Two factor required.");
+        assert_eq!(json["ErrorModel"]["Message"], "Two factor required.");
+        assert_eq!(json["TwoFactorProviders"], json!([0, 2]));
+        assert!(json["TwoFactorProviders2"].as_object().unwrap().contains_key("2"));
+
+        // Sent as a Json error, so the body is returned as-is with a 400 status
+        let error: crate::error::Error = ("2FA token not provided", json.clone()).into();
+        let body: Value = serde_json::from_str(&error.to_string()).unwrap();
+        assert_eq!(body, json);
+    }
+
+    #[test]
+    #[cfg(sqlite)]
+    fn login_failures_delayed() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut conn = DbConn::sqlite_in_memory();
+            conn.insert_user("user").await;
+            // Keep the hashing of the wrong password cheap
+            conn.batch_execute("UPDATE users SET password_iterations = 1").await;
+            let mut auth_request = AuthRequest::new(
+                String::from("user"),
+                String::from("device"),
+                0,
+                String::from("192.0.2.1"),
+                String::from("access-code"),
+                String::new(),
+            );
+            auth_request.save(&mut conn).await.unwrap();
+
+            let ip = ClientIp {
+                ip: "192.0.2.1".parse().unwrap(),
+            };
+            let login = |username: &str, password: &str, auth_request: Option<&str>| ConnectData {
+                grant_type: String::from("password"),
+                scope: Some(String::from("api offline_access")),
+                username: Some(username.to_string()),
+                password: Some(password.to_string()),
+                auth_request: auth_request.map(str::to_string),
+                ..Default::default()
+            };
+            let failures = [
+                ("unknown user", login("nobody@example.com", "password", None)),
+                ("wrong password", login("user@example.com", "password", None)),
+                ("wrong access code", login("user@example.com", "wrong-code", Some(&auth_request.uuid))),
+                ("unknown auth request", login("user@example.com", "access-code", Some("unknown"))),
+            ];
+            for (path, data) in failures {
+                let delayed = DELAYED_LOGIN_FAILURES.with(std::cell::Cell::get);
+                assert!(_password_login(data, &mut None, &mut conn, &ip).await.is_err(), "{path}");
+                assert_eq!(DELAYED_LOGIN_FAILURES.with(std::cell::Cell::get), delayed + 1, "{path}");
+            }
+        });
+    }
+}
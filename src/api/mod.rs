@@ -11,22 +11,34 @@ use serde_json::Value;
 
 pub use crate::api::{
     admin::catchers as admin_catchers,
+    admin::create_break_glass_token,
+    admin::jwt_key_rotation_job,
     admin::routes as admin_routes,
+    core::access_log_cleanup_job,
     core::catchers as core_catchers,
+    core::inactive_account_disable_job,
+    core::key_rotation_reminder_job,
     core::purge_auth_requests,
+    core::purge_expired_org_invitations,
+    core::purge_orphaned_attachments,
     core::purge_sends,
     core::purge_trashed_ciphers,
     core::routes as core_routes,
+    core::two_factor::duo::{
+        duo_health_check_job, is_allowed_host as is_allowed_duo_host,
+        is_valid_username_format as is_valid_duo_username_format,
+    },
     core::two_factor::send_incomplete_2fa_notifications,
+    core::unverified_account_purge_job,
     core::{emergency_notification_reminder_job, emergency_request_timeout_job},
     core::{event_cleanup_job, events_routes as core_events_routes},
-    icons::{is_domain_blacklisted, routes as icons_routes},
+    icons::{is_domain_blacklisted, load_fallback_icon, routes as icons_routes},
     identity::routes as identity_routes,
     notifications::routes as notifications_routes,
     notifications::{AnonymousNotify, Notify, UpdateType, WS_ANONYMOUS_SUBSCRIPTIONS, WS_USERS},
     push::{
-        push_cipher_update, push_folder_update, push_logout, push_send_update, push_user_update, register_push_device,
-        unregister_push_device,
+        init_push_tracking, push_cipher_update, push_folder_update, push_logout, push_send_update, push_user_update,
+        register_push_device, unregister_push_device,
     },
     web::catchers as web_catchers,
     web::routes as web_routes,
@@ -43,6 +55,7 @@ pub type EmptyResult = ApiResult<()>;
 type JsonUpcase<T> = Json<util::UpCase<T>>;
 type JsonUpcaseVec<T> = Json<Vec<util::UpCase<T>>>;
 type JsonVec<T> = Json<Vec<T>>;
+type MultipartForm<T> = util::UploadForm<util::LimitedParts<T>>;
 
 // Common structs representing JSON data received
 #[derive(Deserialize)]
@@ -1,9 +1,16 @@
-use std::{net::IpAddr, sync::Arc, time::Duration};
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use chrono::{NaiveDateTime, Utc};
 use rmpv::Value;
-use rocket::{futures::StreamExt, Route};
-use tokio::sync::mpsc::Sender;
+use rocket::{
+    futures::{Stream, StreamExt},
+    Route,
+};
+use tokio::sync::mpsc::{Receiver, Sender};
 
 use rocket_ws::{Message, WebSocket};
 
@@ -75,6 +82,8 @@ impl Drop for WSEntryMapGuard {
         if let Some(mut entry) = self.users.map.get_mut(&self.user_uuid) {
             entry.retain(|(uuid, _)| uuid != &self.entry_uuid);
         }
+        // Don't keep users without any connection around
+        self.users.map.remove_if(&self.user_uuid, |_, entries| entries.is_empty());
     }
 }
 
@@ -101,6 +110,101 @@ impl Drop for WSAnonymousEntryMapGuard {
     }
 }
 
+/// Keeps track of the last message received from a websocket client, to close connections which stopped responding to pings
+struct Heartbeat {
+    last_seen: Instant,
+    timeout: Duration,
+}
+
+impl Heartbeat {
+    fn new(timeout: Duration, now: Instant) -> Self {
+        Self {
+            last_seen: now,
+            timeout,
+        }
+    }
+
+    /// Any message, not only a pong, shows that the client is still connected
+    fn seen(&mut self, now: Instant) {
+        self.last_seen = now;
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.last_seen) >= self.timeout
+    }
+}
+
+/// The messages sent to a websocket client: the replies to its own messages, the notifications received on `rx` and a ping
+/// every `ping_interval`. The stream ends once the client disconnects or hasn't sent anything for `pong_timeout`,
+/// dropping `guard` and with it the subscription.
+fn hub_messages<S, E, G>(
+    mut ws: S,
+    mut rx: Receiver<Message>,
+    guard: G,
+    addr: IpAddr,
+    ping_interval: Duration,
+    pong_timeout: Duration,
+) -> impl Stream<Item = Message>
+where
+    S: Stream<Item = Result<Message, E>> + Unpin,
+{
+    rocket::response::stream::stream! {
+        let _guard = guard;
+        let mut interval = tokio::time::interval(ping_interval);
+        let mut heartbeat = Heartbeat::new(pong_timeout, Instant::now());
+        loop {
+            tokio::select! {
+                res = ws.next() =>  {
+                    match res {
+                        Some(Ok(message)) => {
+                            heartbeat.seen(Instant::now());
+                            match message {
+                                // Respond to any pings
+                                Message::Ping(ping) => yield Message::Pong(ping),
+                                Message::Pong(_) => {/* Ignored */},
+
+                                // We should receive an initial message with the protocol and version, and we will reply to it
+                                Message::Text(ref message) => {
+                                    let msg = message.strip_suffix(RECORD_SEPARATOR as char).unwrap_or(message);
+
+                                    if serde_json::from_str(msg).ok() == Some(INITIAL_MESSAGE) {
+                                        yield Message::binary(INITIAL_RESPONSE);
+                                        continue;
+                                    }
+                                }
+
+                                // Prevent sending anything back when a `Close` Message is received.
+                                // Just break the loop
+                                Message::Close(_) => break,
+
+                                // Just echo anything else the client sends
+                                _ => yield message,
+                            }
+                        }
+                        _ => break,
+                    }
+                }
+
+                res = rx.recv() => {
+                    match res {
+                        Some(res) => yield res,
+                        None => break,
+                    }
+                }
+
+                _ = interval.tick() => {
+                    // Dropping the guard once the loop ends removes the subscription of a dead connection
+                    if heartbeat.is_expired(Instant::now()) {
+                        info!("WS connection from {addr} didn't respond to pings, closing it");
+                        break;
+                    }
+                    yield Message::Ping(create_ping())
+                }
+            }
+        }
+    }
+}
+
 #[get("/hub?<data..>")]
 fn websockets_hub<'r>(
     ws: WebSocket,
@@ -123,7 +227,7 @@ fn websockets_hub<'r>(
         err_code!("Invalid token", 401)
     };
 
-    let (mut rx, guard) = {
+    let (rx, guard) = {
         let users = Arc::clone(&WS_USERS);
 
         // Add a channel to send messages to this client to the map
@@ -135,52 +239,14 @@ fn websockets_hub<'r>(
         (rx, WSEntryMapGuard::new(users, claims.sub, entry_uuid, addr))
     };
 
+    let ping_interval = Duration::from_secs(CONFIG.websocket_ping_interval());
+    let pong_timeout = Duration::from_secs(CONFIG.websocket_pong_timeout());
+
     Ok({
         rocket_ws::Stream! { ws => {
-            let mut ws = ws;
-            let _guard = guard;
-            let mut interval = tokio::time::interval(Duration::from_secs(15));
-            loop {
-                tokio::select! {
-                    res = ws.next() =>  {
-                        match res {
-                            Some(Ok(message)) => {
-                                match message {
-                                    // Respond to any pings
-                                    Message::Ping(ping) => yield Message::Pong(ping),
-                                    Message::Pong(_) => {/* Ignored */},
-
-                                    // We should receive an initial message with the protocol and version, and we will reply to it
-                                    Message::Text(ref message) => {
-                                        let msg = message.strip_suffix(RECORD_SEPARATOR as char).unwrap_or(message);
-
-                                        if serde_json::from_str(msg).ok() == Some(INITIAL_MESSAGE) {
-                                            yield Message::binary(INITIAL_RESPONSE);
-                                            continue;
-                                        }
-                                    }
-
-                                    // Prevent sending anything back when a `Close` Message is received.
-                                    // Just break the loop
-                                    Message::Close(_) => break,
-
-                                    // Just echo anything else the client sends
-                                    _ => yield message,
-                                }
-                            }
-                            _ => break,
-                        }
-                    }
-
-                    res = rx.recv() => {
-                        match res {
-                            Some(res) => yield res,
-                            None => break,
-                        }
-                    }
-
-                    _ = interval.tick() => yield Message::Ping(create_ping())
-                }
+            let messages = hub_messages(ws, rx, guard, addr, ping_interval, pong_timeout);
+            for await message in messages {
+                yield message;
             }
         }}
     })
@@ -191,7 +257,7 @@ fn anonymous_websockets_hub<'r>(ws: WebSocket, token: String, ip: ClientIp) -> R
     let addr = ip.ip;
     info!("Accepting Anonymous Rocket WS connection from {addr}");
 
-    let (mut rx, guard) = {
+    let (rx, guard) = {
         let subscriptions = Arc::clone(&WS_ANONYMOUS_SUBSCRIPTIONS);
 
         // Add a channel to send messages to this client to the map
@@ -202,52 +268,14 @@ fn anonymous_websockets_hub<'r>(ws: WebSocket, token: String, ip: ClientIp) -> R
         (rx, WSAnonymousEntryMapGuard::new(subscriptions, token, addr))
     };
 
+    let ping_interval = Duration::from_secs(CONFIG.websocket_ping_interval());
+    let pong_timeout = Duration::from_secs(CONFIG.websocket_pong_timeout());
+
     Ok({
         rocket_ws::Stream! { ws => {
-            let mut ws = ws;
-            let _guard = guard;
-            let mut interval = tokio::time::interval(Duration::from_secs(15));
-            loop {
-                tokio::select! {
-                    res = ws.next() =>  {
-                        match res {
-                            Some(Ok(message)) => {
-                                match message {
-                                    // Respond to any pings
-                                    Message::Ping(ping) => yield Message::Pong(ping),
-                                    Message::Pong(_) => {/* Ignored */},
-
-                                    // We should receive an initial message with the protocol and version, and we will reply to it
-                                    Message::Text(ref message) => {
-                                        let msg = message.strip_suffix(RECORD_SEPARATOR as char).unwrap_or(message);
-
-                                        if serde_json::from_str(msg).ok() == Some(INITIAL_MESSAGE) {
-                                            yield Message::binary(INITIAL_RESPONSE);
-                                            continue;
-                                        }
-                                    }
-
-                                    // Prevent sending anything back when a `Close` Message is received.
-                                    // Just break the loop
-                                    Message::Close(_) => break,
-
-                                    // Just echo anything else the client sends
-                                    _ => yield message,
-                                }
-                            }
-                            _ => break,
-                        }
-                    }
-
-                    res = rx.recv() => {
-                        match res {
-                            Some(res) => yield res,
-                            None => break,
-                        }
-                    }
-
-                    _ = interval.tick() => yield Message::Ping(create_ping())
-                }
+            let messages = hub_messages(ws, rx, guard, addr, ping_interval, pong_timeout);
+            for await message in messages {
+                yield message;
             }
         }}
     })
@@ -328,14 +356,48 @@ pub struct WebSocketUsers {
 }
 
 impl WebSocketUsers {
-    async fn send_update(&self, user_uuid: &str, data: &[u8]) {
+    /// Returns the number of connected devices the update was sent to
+    async fn send_update(&self, user_uuid: &str, data: &[u8]) -> usize {
+        let mut sent = 0;
         if let Some(user) = self.map.get(user_uuid).map(|v| v.clone()) {
             for (_, sender) in user.iter() {
-                if let Err(e) = sender.send(Message::binary(data)).await {
-                    error!("Error sending WS update {e}");
+                match sender.send(Message::binary(data)).await {
+                    Ok(()) => sent += 1,
+                    Err(e) => error!("Error sending WS update {e}"),
                 }
             }
         }
+        sent
+    }
+
+    async fn send_sync_vault(&self, user_uuid: &str, date: NaiveDateTime) -> usize {
+        let data = create_update(
+            vec![("UserId".into(), user_uuid.into()), ("Date".into(), serialize_date(date))],
+            UpdateType::SyncVault,
+            None,
+        );
+        self.send_update(user_uuid, &data).await
+    }
+
+    /// Makes all devices of the given users do a full sync, offline devices will sync on their next connect anyway.
+    /// Returns the number of connected WebSocket devices which were notified.
+    // NOTE: The last modified date of the users needs to be updated before calling this method
+    pub async fn send_force_sync(&self, users: &[User]) -> usize {
+        // Skip any processing if both WebSockets and Push are not active
+        if *NOTIFICATIONS_DISABLED {
+            return 0;
+        }
+
+        let mut notified = 0;
+        for user in users {
+            if CONFIG.enable_websocket() {
+                notified += self.send_sync_vault(&user.uuid, user.updated_at).await;
+            }
+            if CONFIG.push_enabled() {
+                push_user_update(UpdateType::SyncVault, user);
+            }
+        }
+        notified
     }
 
     // NOTE: The last modified date needs to be updated before calling these methods
@@ -656,3 +718,79 @@ pub enum UpdateType {
 
 pub type Notify<'a> = &'a rocket::State<Arc<WebSocketUsers>>;
 pub type AnonymousNotify<'a> = &'a rocket::State<Arc<AnonymousWebSocketSubscriptions>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn force_sync_reaches_connected_client() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let users = WebSocketUsers {
+                map: Arc::new(dashmap::DashMap::new()),
+            };
+            let (tx, mut rx) = tokio::sync::mpsc::channel::<Message>(4);
+            users.map.insert(String::from("connected-user"), vec![(uuid::Uuid::new_v4(), tx)]);
+
+            let now = Utc::now().naive_utc();
+            assert_eq!(users.send_sync_vault("connected-user", now).await, 1);
+            // Users without a connected device are skipped
+            assert_eq!(users.send_sync_vault("offline-user", now).await, 0);
+
+            let expected = create_update(
+                vec![("UserId".into(), "connected-user".into()), ("Date".into(), serialize_date(now))],
+                UpdateType::SyncVault,
+                None,
+            );
+            match rx.try_recv() {
+                Ok(Message::Binary(data)) => assert_eq!(data, expected),
+                other => panic!("Expected a binary sync message, got {other:?}"),
+            }
+            assert!(rx.try_recv().is_err());
+        });
+    }
+
+    #[test]
+    fn heartbeat_expires_without_messages() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(60), start);
+        assert!(!heartbeat.is_expired(start + Duration::from_secs(59)));
+
+        // A pong, or any other message, postpones the timeout
+        heartbeat.seen(start + Duration::from_secs(30));
+        assert!(!heartbeat.is_expired(start + Duration::from_secs(80)));
+        assert!(heartbeat.is_expired(start + Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn unresponsive_connection_reaped() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let users = Arc::new(WebSocketUsers {
+                map: Arc::new(dashmap::DashMap::new()),
+            });
+            let (tx, rx) = tokio::sync::mpsc::channel::<Message>(4);
+            let entry_uuid = uuid::Uuid::new_v4();
+            users.map.insert(String::from("stale-user"), vec![(entry_uuid, tx)]);
+            let guard =
+                WSEntryMapGuard::new(Arc::clone(&users), String::from("stale-user"), entry_uuid, [127, 0, 0, 1].into());
+
+            // The client never sends anything, not even a pong
+            let client = futures::stream::pending::<Result<Message, ()>>();
+            let messages = hub_messages(
+                client,
+                rx,
+                guard,
+                [127, 0, 0, 1].into(),
+                Duration::from_millis(10),
+                Duration::from_millis(50),
+            );
+            let sent: Vec<Message> = tokio::time::timeout(Duration::from_secs(10), messages.collect())
+                .await
+                .expect("The connection wasn't closed");
+
+            assert!(!sent.is_empty());
+            assert!(sent.iter().all(|message| matches!(message, Message::Ping(_))));
+            assert!(users.map.get("stale-user").is_none());
+        });
+    }
+}
@@ -4,13 +4,19 @@ use tokio::sync::RwLock;
 
 use crate::{
     api::{ApiResult, EmptyResult, UpdateType},
-    db::models::{Cipher, Device, Folder, Send, User},
+    db::{
+        models::{Cipher, Device, Folder, Send, User},
+        DbPool,
+    },
     util::get_reqwest_client,
     CONFIG,
 };
 
-use once_cell::sync::Lazy;
-use std::time::{Duration, Instant};
+use once_cell::sync::{Lazy, OnceCell};
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
 
 #[derive(Deserialize)]
 struct AuthPushToken {
@@ -121,9 +127,29 @@ pub async fn register_push_device(device: &mut Device, conn: &mut crate::db::DbC
         err!(format!("An error occurred while trying to save the (registered) device push uuid: {e}"));
     }
 
+    evict_push_devices_over_limit(device, conn).await;
+
     Ok(())
 }
 
+async fn evict_push_devices_over_limit(device: &Device, conn: &mut crate::db::DbConn) {
+    let registered = Device::find_registered_push_devices_by_user(&device.user_uuid, conn).await;
+    for mut evicted in Device::push_devices_to_evict(registered, &device.uuid, CONFIG.push_max_devices()) {
+        info!("Removing the push registration of device {}, the user has too many push devices", evicted.uuid);
+        remove_push_registration(&mut evicted, conn).await;
+    }
+}
+
+async fn remove_push_registration(device: &mut Device, conn: &mut crate::db::DbConn) {
+    if let Err(e) = unregister_push_device(device.push_uuid.clone()).await {
+        warn!("Unable to unregister device {} from the push relay: {}", device.uuid, e);
+    }
+    device.clear_push_registration();
+    if let Err(e) = device.save_push_state(conn).await {
+        error!("Unable to remove the push registration of device {}: {:#?}", device.uuid, e);
+    }
+}
+
 pub async fn unregister_push_device(push_uuid: Option<String>) -> EmptyResult {
     if !CONFIG.push_enabled() || push_uuid.is_none() {
         return Ok(());
@@ -163,7 +189,9 @@ pub async fn push_cipher_update(
     };
 
     if Device::check_user_has_push_device(user_uuid, conn).await {
-        send_to_push_relay(json!({
+        send_to_push_relay_tracked(
+            user_uuid,
+            json!({
             "userId": user_uuid,
             "organizationId": (),
             "deviceId": acting_device_uuid,
@@ -175,7 +203,9 @@ pub async fn push_cipher_update(
                 "organizationId": (),
                 "revisionDate": cipher.updated_at
             }
-        }))
+            }),
+            conn,
+        )
         .await;
     }
 }
@@ -217,81 +247,312 @@ pub async fn push_folder_update(
     conn: &mut crate::db::DbConn,
 ) {
     if Device::check_user_has_push_device(&folder.user_uuid, conn).await {
-        tokio::task::spawn(send_to_push_relay(json!({
-            "userId": folder.user_uuid,
-            "organizationId": (),
-            "deviceId": acting_device_uuid,
-            "identifier": acting_device_uuid,
-            "type": ut as i32,
-            "payload": {
-                "id": folder.uuid,
+        send_to_push_relay_tracked(
+            &folder.user_uuid,
+            json!({
                 "userId": folder.user_uuid,
-                "revisionDate": folder.updated_at
-            }
-        })));
+                "organizationId": (),
+                "deviceId": acting_device_uuid,
+                "identifier": acting_device_uuid,
+                "type": ut as i32,
+                "payload": {
+                    "id": folder.uuid,
+                    "userId": folder.user_uuid,
+                    "revisionDate": folder.updated_at
+                }
+            }),
+            conn,
+        )
+        .await;
     }
 }
 
 pub async fn push_send_update(ut: UpdateType, send: &Send, acting_device_uuid: &String, conn: &mut crate::db::DbConn) {
     if let Some(s) = &send.user_uuid {
         if Device::check_user_has_push_device(s, conn).await {
-            tokio::task::spawn(send_to_push_relay(json!({
-                "userId": send.user_uuid,
-                "organizationId": (),
-                "deviceId": acting_device_uuid,
-                "identifier": acting_device_uuid,
-                "type": ut as i32,
-                "payload": {
-                    "id": send.uuid,
+            send_to_push_relay_tracked(
+                s,
+                json!({
                     "userId": send.user_uuid,
-                    "revisionDate": send.revision_date
-                }
-            })));
+                    "organizationId": (),
+                    "deviceId": acting_device_uuid,
+                    "identifier": acting_device_uuid,
+                    "type": ut as i32,
+                    "payload": {
+                        "id": send.uuid,
+                        "userId": send.user_uuid,
+                        "revisionDate": send.revision_date
+                    }
+                }),
+                conn,
+            )
+            .await;
+        }
+    }
+}
+
+/// The result of delivering a notification to the push relay
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PushOutcome {
+    Delivered,
+    // The relay doesn't know the push registration (anymore), the only answer which says something about a device
+    InvalidRegistration,
+    // Refused by the relay, like when the installation id or key are wrong. Sending it again won't help
+    Rejected,
+    // The relay couldn't be reached or had a temporary problem, these are retried, see `PUSH_RETRY_ATTEMPTS`
+    Transient,
+    // Nothing was sent because push is disabled or the relay couldn't be authenticated with
+    Skipped,
+}
+
+impl PushOutcome {
+    fn from_status(status: reqwest::StatusCode) -> Self {
+        use reqwest::StatusCode;
+
+        if status.is_success() {
+            Self::Delivered
+        } else if status == StatusCode::NOT_FOUND || status == StatusCode::GONE {
+            Self::InvalidRegistration
+        } else if status.is_server_error()
+            || status == StatusCode::TOO_MANY_REQUESTS
+            || status == StatusCode::REQUEST_TIMEOUT
+        {
+            Self::Transient
+        } else {
+            Self::Rejected
         }
     }
 }
 
-async fn send_to_push_relay(notification_data: Value) {
+// Sends the notification, transient failures are retried. Returns the outcome of the last attempt.
+// This waits for the retries, so it is meant to run in a background task.
+async fn send_to_push_relay(notification_data: Value) -> PushOutcome {
     if !CONFIG.push_enabled() {
-        return;
+        return PushOutcome::Skipped;
     }
 
+    let notification_data = if CONFIG.push_minimal_payload() {
+        minimize_notification(notification_data)
+    } else {
+        notification_data
+    };
+
+    let outcome = deliver_to_push_relay(&notification_data).await;
+    if outcome == PushOutcome::Transient {
+        return retry_push(&notification_data).await;
+    }
+    outcome
+}
+
+async fn deliver_to_push_relay(notification_data: &Value) -> PushOutcome {
     let auth_push_token = match get_auth_push_token().await {
         Ok(s) => s,
         Err(e) => {
             debug!("Could not get the auth push token: {}", e);
-            return;
+            return PushOutcome::Skipped;
         }
     };
 
     let auth_header = format!("Bearer {}", &auth_push_token);
 
-    if let Err(e) = get_reqwest_client()
+    match get_reqwest_client()
         .post(CONFIG.push_relay_uri() + "/push/send")
         .header(ACCEPT, "application/json")
         .header(CONTENT_TYPE, "application/json")
         .header(AUTHORIZATION, &auth_header)
-        .json(&notification_data)
+        .json(notification_data)
         .send()
         .await
     {
-        error!("An error occurred while sending a send update to the push relay: {}", e);
+        Ok(res) => {
+            let outcome = PushOutcome::from_status(res.status());
+            if outcome != PushOutcome::Delivered {
+                error!("The push relay refused the notification with status {} ({:?})", res.status(), outcome);
+            }
+            outcome
+        }
+        Err(e) => {
+            error!("An error occurred while sending a send update to the push relay: {}", e);
+            PushOutcome::Transient
+        }
+    }
+}
+
+// Retries which are waiting, new ones are dropped above `MAX_QUEUED_PUSH_RETRIES`
+static QUEUED_PUSH_RETRIES: AtomicUsize = AtomicUsize::new(0);
+const MAX_QUEUED_PUSH_RETRIES: usize = 1000;
+const PUSH_RETRY_BASE_DELAY: Duration = Duration::from_secs(5);
+
+/// The delay before the next retry after `attempt` retries, doubling every time. `None` once all attempts are used.
+fn push_retry_delay(attempt: u32, max_attempts: u32) -> Option<Duration> {
+    (attempt < max_attempts).then(|| PUSH_RETRY_BASE_DELAY * 2u32.pow(attempt.min(8)))
+}
+
+async fn retry_push(notification_data: &Value) -> PushOutcome {
+    if CONFIG.push_retry_attempts() == 0 {
+        return PushOutcome::Transient;
+    }
+    if QUEUED_PUSH_RETRIES.fetch_add(1, Ordering::Relaxed) >= MAX_QUEUED_PUSH_RETRIES {
+        QUEUED_PUSH_RETRIES.fetch_sub(1, Ordering::Relaxed);
+        warn!("Too many push notifications waiting to be retried, dropping a notification");
+        return PushOutcome::Transient;
+    }
+
+    let mut outcome = PushOutcome::Transient;
+    let mut attempt = 0;
+    while let Some(delay) = push_retry_delay(attempt, CONFIG.push_retry_attempts()) {
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+        outcome = deliver_to_push_relay(notification_data).await;
+        if outcome != PushOutcome::Transient {
+            break;
+        }
+    }
+    QUEUED_PUSH_RETRIES.fetch_sub(1, Ordering::Relaxed);
+    outcome
+}
+
+// Replaces the notifications about vault items by a vault sync which only identifies the user, its date is the time it's sent
+fn minimize_notification(mut notification_data: Value) -> Value {
+    const VAULT_ITEM_UPDATES: [UpdateType; 11] = [
+        UpdateType::SyncCipherUpdate,
+        UpdateType::SyncCipherCreate,
+        UpdateType::SyncLoginDelete,
+        UpdateType::SyncFolderDelete,
+        UpdateType::SyncCiphers,
+        UpdateType::SyncFolderCreate,
+        UpdateType::SyncFolderUpdate,
+        UpdateType::SyncCipherDelete,
+        UpdateType::SyncSendCreate,
+        UpdateType::SyncSendUpdate,
+        UpdateType::SyncSendDelete,
+    ];
+
+    let Some(ut) = notification_data["type"].as_i64() else {
+        return notification_data;
     };
+    if VAULT_ITEM_UPDATES.iter().any(|t| *t as i64 == ut) {
+        notification_data["type"] = json!(UpdateType::SyncVault as i32);
+        notification_data["payload"] = json!({
+            "userId": notification_data["userId"],
+            "date": chrono::Utc::now().naive_utc(),
+        });
+    }
+    notification_data
+}
+
+// The pool used by the background tasks which send the notifications to keep track of failed deliveries
+static PUSH_TRACKING_POOL: OnceCell<DbPool> = OnceCell::new();
+
+pub fn init_push_tracking(pool: DbPool) {
+    if PUSH_TRACKING_POOL.set(pool).is_err() {
+        warn!("The push tracking pool was already initialized");
+    }
+}
+
+// Sends the notification in the background and keeps track of failed deliveries of the push registration.
+// The relay sends to every device of the user and doesn't say which one failed, so a failed delivery can
+// only be counted when the user has a single registered device.
+async fn send_to_push_relay_tracked(user_uuid: &str, notification_data: Value, conn: &mut crate::db::DbConn) {
+    let mut registered = Device::find_registered_push_devices_by_user(user_uuid, conn).await;
+    let tracked = if registered.len() == 1 {
+        registered.pop()
+    } else {
+        None
+    };
+
+    tokio::task::spawn(async move {
+        let outcome = send_to_push_relay(notification_data).await;
+        if let Some(device) = tracked {
+            track_push_outcome(device, outcome).await;
+        }
+    });
+}
+
+/// What to do with a push registration after a notification was sent to it
+#[derive(Debug, PartialEq, Eq)]
+enum PushRegistrationUpdate {
+    Unchanged,
+    Save,
+    Prune,
+}
+
+/// Applies the final outcome of a notification to the failure count of the only registration of the user
+fn apply_push_outcome(device: &mut Device, outcome: PushOutcome, prune_failures: u32) -> PushRegistrationUpdate {
+    match outcome {
+        PushOutcome::InvalidRegistration if device.record_push_failure(prune_failures) => PushRegistrationUpdate::Prune,
+        PushOutcome::InvalidRegistration => PushRegistrationUpdate::Save,
+        PushOutcome::Delivered if device.push_failures > 0 => {
+            device.push_failures = 0;
+            PushRegistrationUpdate::Save
+        }
+        _ => PushRegistrationUpdate::Unchanged,
+    }
+}
+
+async fn track_push_outcome(device: Device, outcome: PushOutcome) {
+    // Nothing to update for a delivery to a device without failures, which is the common case
+    let needs_update = match outcome {
+        PushOutcome::InvalidRegistration => true,
+        PushOutcome::Delivered => device.push_failures > 0,
+        _ => false,
+    };
+    if !needs_update {
+        return;
+    }
+    let Some(pool) = PUSH_TRACKING_POOL.get() else {
+        return;
+    };
+    let mut conn = match pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get DB connection while tracking push deliveries: {:#?}", e);
+            return;
+        }
+    };
+    // Reload it, the registration could have changed while the notification was sent
+    let Some(mut device) = Device::find_by_uuid_and_user(&device.uuid, &device.user_uuid, &mut conn).await else {
+        return;
+    };
+    if !device.is_registered() {
+        return;
+    }
+
+    match apply_push_outcome(&mut device, outcome, CONFIG.push_prune_failures()) {
+        PushRegistrationUpdate::Unchanged => {}
+        PushRegistrationUpdate::Save => {
+            if let Err(e) = device.save_push_state(&mut conn).await {
+                error!("Unable to save the push failures of device {}: {:#?}", device.uuid, e);
+            }
+        }
+        PushRegistrationUpdate::Prune => {
+            info!(
+                "Removing the push registration of device {} after {} failed deliveries",
+                device.uuid, device.push_failures
+            );
+            remove_push_registration(&mut device, &mut conn).await;
+        }
+    }
 }
 
 pub async fn push_auth_request(user_uuid: String, auth_request_uuid: String, conn: &mut crate::db::DbConn) {
     if Device::check_user_has_push_device(user_uuid.as_str(), conn).await {
-        tokio::task::spawn(send_to_push_relay(json!({
-            "userId": user_uuid,
-            "organizationId": (),
-            "deviceId": null,
-            "identifier": null,
-            "type": UpdateType::AuthRequest as i32,
-            "payload": {
-                "id": auth_request_uuid,
+        send_to_push_relay_tracked(
+            &user_uuid,
+            json!({
                 "userId": user_uuid,
-            }
-        })));
+                "organizationId": (),
+                "deviceId": null,
+                "identifier": null,
+                "type": UpdateType::AuthRequest as i32,
+                "payload": {
+                    "id": auth_request_uuid,
+                    "userId": user_uuid,
+                }
+            }),
+            conn,
+        )
+        .await;
     }
 }
 
@@ -302,16 +563,99 @@ pub async fn push_auth_response(
     conn: &mut crate::db::DbConn,
 ) {
     if Device::check_user_has_push_device(user_uuid.as_str(), conn).await {
-        tokio::task::spawn(send_to_push_relay(json!({
-            "userId": user_uuid,
+        send_to_push_relay_tracked(
+            &user_uuid,
+            json!({
+                "userId": user_uuid,
+                "organizationId": (),
+                "deviceId": approving_device_uuid,
+                "identifier": approving_device_uuid,
+                "type": UpdateType::AuthRequestResponse as i32,
+                "payload": {
+                    "id": auth_request_uuid,
+                    "userId": user_uuid,
+                }
+            }),
+            conn,
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minimal_payload_omits_item_details() {
+        let notification = json!({
+            "userId": "user-uuid",
             "organizationId": (),
-            "deviceId": approving_device_uuid,
-            "identifier": approving_device_uuid,
-            "type": UpdateType::AuthRequestResponse as i32,
+            "deviceId": "device-uuid",
+            "identifier": "device-uuid",
+            "type": UpdateType::SyncCipherUpdate as i32,
             "payload": {
-                "id": auth_request_uuid,
-                "userId": user_uuid,
+                "id": "cipher-uuid",
+                "userId": "user-uuid",
+                "organizationId": (),
+                "revisionDate": "2024-06-15T12:00:00"
+            }
+        });
+
+        let minimal = minimize_notification(notification);
+        assert_eq!(minimal["type"], UpdateType::SyncVault as i32);
+        assert_eq!(minimal["payload"]["userId"], "user-uuid");
+        assert!(minimal["payload"].get("id").is_none() && minimal["payload"].get("revisionDate").is_none());
+        assert!(!minimal.to_string().contains("cipher-uuid"));
+        // The acting device is still skipped by the relay
+        assert_eq!(minimal["deviceId"], "device-uuid");
+    }
+
+    #[test]
+    fn minimal_payload_keeps_auth_requests() {
+        let notification = json!({
+            "userId": "user-uuid",
+            "type": UpdateType::AuthRequest as i32,
+            "payload": {
+                "id": "auth-request-uuid",
+                "userId": "user-uuid",
             }
-        })));
+        });
+        assert_eq!(minimize_notification(notification.clone()), notification);
+    }
+
+    #[test]
+    fn transient_push_failure_retried() {
+        assert_eq!(PushOutcome::from_status(reqwest::StatusCode::SERVICE_UNAVAILABLE), PushOutcome::Transient);
+        assert_eq!(PushOutcome::from_status(reqwest::StatusCode::TOO_MANY_REQUESTS), PushOutcome::Transient);
+
+        // Retried with backoff until the attempts are used
+        assert_eq!(push_retry_delay(0, 3), Some(Duration::from_secs(5)));
+        assert_eq!(push_retry_delay(1, 3), Some(Duration::from_secs(10)));
+        assert_eq!(push_retry_delay(2, 3), Some(Duration::from_secs(20)));
+        assert_eq!(push_retry_delay(3, 3), None);
+        assert_eq!(push_retry_delay(0, 0), None);
+    }
+
+    #[test]
+    fn invalid_push_token_deregisters_device() {
+        let status_outcome = |code: u16| PushOutcome::from_status(reqwest::StatusCode::from_u16(code).unwrap());
+        let mut device = Device::new(String::from("a"), String::from("user"), String::from("phone"), 0);
+        device.push_uuid = Some(String::from("push-a"));
+        device.push_token = Some(String::from("token-a"));
+
+        // Errors which don't tell anything about the registration, like a misconfigured installation, never count
+        for code in [400, 401, 403, 429, 500, 503] {
+            assert_eq!(apply_push_outcome(&mut device, status_outcome(code), 2), PushRegistrationUpdate::Unchanged);
+        }
+        assert_eq!(device.push_failures, 0);
+
+        // An unknown registration is pruned once the threshold is reached, unless a delivery succeeded in between
+        assert_eq!(apply_push_outcome(&mut device, status_outcome(404), 2), PushRegistrationUpdate::Save);
+        assert_eq!(apply_push_outcome(&mut device, status_outcome(200), 2), PushRegistrationUpdate::Save);
+        assert_eq!(device.push_failures, 0);
+        assert_eq!(apply_push_outcome(&mut device, status_outcome(200), 2), PushRegistrationUpdate::Unchanged);
+        assert_eq!(apply_push_outcome(&mut device, status_outcome(410), 2), PushRegistrationUpdate::Save);
+        assert_eq!(apply_push_outcome(&mut device, status_outcome(404), 2), PushRegistrationUpdate::Prune);
     }
 }
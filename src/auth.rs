@@ -2,7 +2,8 @@
 //
 use chrono::{TimeDelta, Utc};
 use num_traits::FromPrimitive;
-use once_cell::sync::{Lazy, OnceCell};
+use once_cell::sync::Lazy;
+use std::sync::RwLock;
 
 use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header};
 use openssl::rsa::Rsa;
@@ -20,71 +21,211 @@ pub static JWT_LOGIN_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|login", CON
 static JWT_INVITE_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|invite", CONFIG.domain_origin()));
 static JWT_EMERGENCY_ACCESS_INVITE_ISSUER: Lazy<String> =
     Lazy::new(|| format!("{}|emergencyaccessinvite", CONFIG.domain_origin()));
-static JWT_DELETE_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|delete", CONFIG.domain_origin()));
 static JWT_VERIFYEMAIL_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|verifyemail", CONFIG.domain_origin()));
 static JWT_ADMIN_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|admin", CONFIG.domain_origin()));
 static JWT_SEND_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|send", CONFIG.domain_origin()));
 static JWT_ORG_API_KEY_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|api.organization", CONFIG.domain_origin()));
 static JWT_FILE_DOWNLOAD_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|file_download", CONFIG.domain_origin()));
 
-static PRIVATE_RSA_KEY: OnceCell<EncodingKey> = OnceCell::new();
-static PUBLIC_RSA_KEY: OnceCell<DecodingKey> = OnceCell::new();
+// The audience of the access tokens, the other tokens are only validated by their issuer
+pub static JWT_AUDIENCE: Lazy<String> = Lazy::new(|| CONFIG.jwt_audience());
+
+// The signing key, and the key it replaced which still validates tokens during JWT_KEY_ROTATION_OVERLAP_HOURS
+static JWT_KEYS: RwLock<Option<JwtKeys>> = RwLock::new(None);
+
+struct JwtKeys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    // The key before the last rotation, with the UNIX timestamp of that rotation
+    previous: Option<(DecodingKey, i64)>,
+}
+
+impl JwtKeys {
+    fn from_private_pem(pem: &[u8]) -> Result<(EncodingKey, DecodingKey), Error> {
+        let pub_key_buffer = Rsa::private_key_from_pem(pem)?.public_key_to_pem()?;
+        Ok((EncodingKey::from_rsa_pem(pem)?, DecodingKey::from_rsa_pem(&pub_key_buffer)?))
+    }
+
+    // The previous key is only accepted for the overlap window after the rotation
+    fn decoding_keys(&self, now: i64, overlap_secs: i64) -> Vec<&DecodingKey> {
+        let mut keys = vec![&self.decoding];
+        if let Some((previous, rotated_at)) = &self.previous {
+            if now < rotated_at.saturating_add(overlap_secs) {
+                keys.push(previous);
+            }
+        }
+        keys
+    }
+}
 
 pub fn initialize_keys() -> Result<(), crate::error::Error> {
     let mut priv_key_buffer = Vec::with_capacity(2048);
 
-    let priv_key = {
+    {
         let mut priv_key_file =
             File::options().create(true).truncate(false).read(true).write(true).open(CONFIG.private_rsa_key())?;
 
         #[allow(clippy::verbose_file_reads)]
         let bytes_read = priv_key_file.read_to_end(&mut priv_key_buffer)?;
 
-        if bytes_read > 0 {
-            Rsa::private_key_from_pem(&priv_key_buffer[..bytes_read])?
-        } else {
+        if bytes_read == 0 {
             // Only create the key if the file doesn't exist or is empty
             let rsa_key = openssl::rsa::Rsa::generate(2048)?;
             priv_key_buffer = rsa_key.private_key_to_pem()?;
             priv_key_file.write_all(&priv_key_buffer)?;
             info!("Private key created correctly.");
-            rsa_key
         }
+    }
+    let (encoding, decoding) = JwtKeys::from_private_pem(&priv_key_buffer)?;
+
+    // The modification time of the previous key file is the moment of the last rotation
+    let previous = match std::fs::read(CONFIG.previous_private_rsa_key()) {
+        Ok(pem) => {
+            let rotated_at = std::fs::metadata(CONFIG.previous_private_rsa_key())?.modified()?;
+            let rotated_at = chrono::DateTime::<Utc>::from(rotated_at).timestamp();
+            Some((JwtKeys::from_private_pem(&pem)?.1, rotated_at))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
     };
 
-    let pub_key_buffer = priv_key.public_key_to_pem()?;
+    let mut keys = JWT_KEYS.write().unwrap();
+    if keys.is_some() {
+        err!("JWT keys must only be initialized once")
+    }
+    *keys = Some(JwtKeys {
+        encoding,
+        decoding,
+        previous,
+    });
+    Ok(())
+}
+
+/// Replaces the signing key with a new one, without invalidating the issued tokens right away:
+/// the replaced key keeps validating them during `JWT_KEY_ROTATION_OVERLAP_HOURS`.
+/// Other instances sharing the key files only pick up the new key when restarted.
+pub async fn rotate_keys() -> Result<(), Error> {
+    // Generating the RSA key takes a while, which shouldn't block the other requests on the worker
+    crate::db::run_blocking(_rotate_keys).await
+}
 
-    let enc = EncodingKey::from_rsa_pem(&priv_key_buffer)?;
-    let dec: DecodingKey = DecodingKey::from_rsa_pem(&pub_key_buffer)?;
-    if PRIVATE_RSA_KEY.set(enc).is_err() {
-        err!("PRIVATE_RSA_KEY must only be initialized once")
+// Serializes the rotations, a rotation checks the previous one before replacing the key files
+static JWT_KEY_ROTATION: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// The previous key file only holds one key, so rotating again while the previous key is still accepted
+/// would invalidate every token signed by it right away.
+fn check_key_rotation_allowed(previous_rotated_at: Option<i64>, now: i64, overlap_secs: i64) -> Result<(), Error> {
+    match previous_rotated_at {
+        Some(rotated_at) if now < rotated_at.saturating_add(overlap_secs) => err!(format!(
+            "The signing key was rotated less than JWT_KEY_ROTATION_OVERLAP_HOURS ago, it can be rotated again after {}",
+            chrono::DateTime::from_timestamp(rotated_at.saturating_add(overlap_secs), 0)
+                .map(|d| d.format("%Y-%m-%d %H:%M UTC").to_string())
+                .unwrap_or_default()
+        )),
+        _ => Ok(()),
     }
-    if PUBLIC_RSA_KEY.set(dec).is_err() {
-        err!("PUBLIC_RSA_KEY must only be initialized once")
+}
+
+fn _rotate_keys() -> Result<(), Error> {
+    let _rotation = JWT_KEY_ROTATION.lock().unwrap();
+    {
+        let keys = JWT_KEYS.read().unwrap();
+        let Some(keys) = keys.as_ref() else {
+            err!("JWT keys are not initialized")
+        };
+        let overlap_secs = i64::from(CONFIG.jwt_key_rotation_overlap_hours()) * 3600;
+        check_key_rotation_allowed(keys.previous.as_ref().map(|p| p.1), Utc::now().timestamp(), overlap_secs)?;
     }
+
+    let current_path = CONFIG.private_rsa_key();
+    let current_pem = std::fs::read(&current_path)?;
+    let new_pem = Rsa::generate(2048)?.private_key_to_pem()?;
+    let (encoding, decoding) = JwtKeys::from_private_pem(&new_pem)?;
+
+    std::fs::write(CONFIG.previous_private_rsa_key(), current_pem)?;
+    let tmp_path = format!("{current_path}.tmp");
+    std::fs::write(&tmp_path, &new_pem)?;
+    std::fs::rename(&tmp_path, &current_path)?;
+
+    let mut keys = JWT_KEYS.write().unwrap();
+    let Some(keys) = keys.as_mut() else {
+        err!("JWT keys are not initialized")
+    };
+    let previous = std::mem::replace(&mut keys.decoding, decoding);
+    keys.encoding = encoding;
+    keys.previous = Some((previous, Utc::now().timestamp()));
     Ok(())
 }
 
 pub fn encode_jwt<T: Serialize>(claims: &T) -> String {
-    match jsonwebtoken::encode(&JWT_HEADER, claims, PRIVATE_RSA_KEY.wait()) {
+    let keys = JWT_KEYS.read().unwrap();
+    let keys = keys.as_ref().expect("JWT keys must be initialized");
+    match jsonwebtoken::encode(&JWT_HEADER, claims, &keys.encoding) {
         Ok(token) => token,
         Err(e) => panic!("Error encoding jwt {e}"),
     }
 }
 
 fn decode_jwt<T: DeserializeOwned>(token: &str, issuer: String) -> Result<T, Error> {
+    decode_jwt_with_keys(token, &issuer, None)
+}
+
+fn decode_access_jwt<T: DeserializeOwned>(token: &str, issuer: String) -> Result<T, Error> {
+    decode_jwt_with_keys(token, &issuer, Some(&JWT_AUDIENCE))
+}
+
+fn decode_jwt_with_keys<T: DeserializeOwned>(token: &str, issuer: &str, audience: Option<&str>) -> Result<T, Error> {
+    let keys = JWT_KEYS.read().unwrap();
+    let keys = keys.as_ref().expect("JWT keys must be initialized");
+    let overlap_secs = i64::from(CONFIG.jwt_key_rotation_overlap_hours()) * 3600;
+    _decode_jwt_keys(token, &keys.decoding_keys(Utc::now().timestamp(), overlap_secs), issuer, audience)
+}
+
+fn _decode_jwt<T: DeserializeOwned>(
+    token: &str,
+    key: &DecodingKey,
+    issuer: &str,
+    audience: Option<&str>,
+) -> Result<T, Error> {
+    _decode_jwt_keys(token, &[key], issuer, audience)
+}
+
+fn _decode_jwt_keys<T: DeserializeOwned>(
+    token: &str,
+    keys: &[&DecodingKey],
+    issuer: &str,
+    audience: Option<&str>,
+) -> Result<T, Error> {
     let mut validation = jsonwebtoken::Validation::new(JWT_ALGORITHM);
     validation.leeway = 30; // 30 seconds
     validation.validate_exp = true;
     validation.validate_nbf = true;
     validation.set_issuer(&[issuer]);
+    // The issuer and audience are only checked when present, unless they are required
+    match audience {
+        Some(audience) => {
+            validation.set_audience(&[audience]);
+            validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        }
+        None => validation.set_required_spec_claims(&["exp", "iss"]),
+    }
 
     let token = token.replace(char::is_whitespace, "");
-    match jsonwebtoken::decode(&token, PUBLIC_RSA_KEY.wait(), &validation) {
+    let mut result = Err(ErrorKind::InvalidSignature.into());
+    for key in keys {
+        result = jsonwebtoken::decode(&token, key, &validation);
+        // Only a token signed by another key is tried with the next one
+        if !matches!(result, Err(ref e) if *e.kind() == ErrorKind::InvalidSignature) {
+            break;
+        }
+    }
+    match result {
         Ok(d) => Ok(d.claims),
         Err(err) => match *err.kind() {
             ErrorKind::InvalidToken => err!("Token is invalid"),
             ErrorKind::InvalidIssuer => err!("Issuer is invalid"),
+            ErrorKind::InvalidAudience => err!("Audience is invalid"),
+            ErrorKind::MissingRequiredClaim(_) => err!("Token is missing a required claim"),
             ErrorKind::ExpiredSignature => err!("Token has expired"),
             _ => err!("Error decoding JWT"),
         },
@@ -92,7 +233,26 @@ fn decode_jwt<T: DeserializeOwned>(token: &str, issuer: String) -> Result<T, Err
 }
 
 pub fn decode_login(token: &str) -> Result<LoginJwtClaims, Error> {
-    decode_jwt(token, JWT_LOGIN_ISSUER.to_string())
+    let claims: LoginJwtClaims = decode_access_jwt(token, JWT_LOGIN_ISSUER.to_string())?;
+    check_token_epoch(claims.nbf, CONFIG._token_epoch())?;
+    Ok(claims)
+}
+
+/// The `nbf` of a new login token. A token issued in the same second as the last global token revocation
+/// starts at the next second, otherwise it would be revoked right away.
+pub fn login_not_before(now: i64, token_epoch: Option<i64>) -> i64 {
+    match token_epoch {
+        Some(epoch) if now <= epoch => epoch + 1,
+        _ => now,
+    }
+}
+
+/// Rejects login tokens issued up to the last global token revocation, see `/admin/tokens/revoke`
+fn check_token_epoch(issued_at: i64, token_epoch: Option<i64>) -> Result<(), Error> {
+    match token_epoch {
+        Some(epoch) if issued_at <= epoch => err!("Token has been revoked"),
+        _ => Ok(()),
+    }
 }
 
 pub fn decode_invite(token: &str) -> Result<InviteJwtClaims, Error> {
@@ -103,10 +263,6 @@ pub fn decode_emergency_access_invite(token: &str) -> Result<EmergencyAccessInvi
     decode_jwt(token, JWT_EMERGENCY_ACCESS_INVITE_ISSUER.to_string())
 }
 
-pub fn decode_delete(token: &str) -> Result<BasicJwtClaims, Error> {
-    decode_jwt(token, JWT_DELETE_ISSUER.to_string())
-}
-
 pub fn decode_verify_email(token: &str) -> Result<BasicJwtClaims, Error> {
     decode_jwt(token, JWT_VERIFYEMAIL_ISSUER.to_string())
 }
@@ -120,7 +276,7 @@ pub fn decode_send(token: &str) -> Result<BasicJwtClaims, Error> {
 }
 
 pub fn decode_api_org(token: &str) -> Result<OrgApiKeyLoginJwtClaims, Error> {
-    decode_jwt(token, JWT_ORG_API_KEY_ISSUER.to_string())
+    decode_access_jwt(token, JWT_ORG_API_KEY_ISSUER.to_string())
 }
 
 pub fn decode_file_download(token: &str) -> Result<FileDownloadClaims, Error> {
@@ -135,6 +291,8 @@ pub struct LoginJwtClaims {
     pub exp: i64,
     // Issuer
     pub iss: String,
+    // Audience
+    pub aud: String,
     // Subject
     pub sub: String,
 
@@ -248,6 +406,8 @@ pub struct OrgApiKeyLoginJwtClaims {
     pub exp: i64,
     // Issuer
     pub iss: String,
+    // Audience
+    pub aud: String,
     // Subject
     pub sub: String,
 
@@ -262,6 +422,7 @@ pub fn generate_organization_api_key_login_claims(uuid: String, org_id: String)
         nbf: time_now.timestamp(),
         exp: (time_now + TimeDelta::try_hours(1).unwrap()).timestamp(),
         iss: JWT_ORG_API_KEY_ISSUER.to_string(),
+        aud: JWT_AUDIENCE.to_string(),
         sub: uuid,
         client_id: format!("organization.{org_id}"),
         client_sub: org_id,
@@ -306,17 +467,6 @@ pub struct BasicJwtClaims {
     pub sub: String,
 }
 
-pub fn generate_delete_claims(uuid: String) -> BasicJwtClaims {
-    let time_now = Utc::now();
-    let expire_hours = i64::from(CONFIG.invitation_expiration_hours());
-    BasicJwtClaims {
-        nbf: time_now.timestamp(),
-        exp: (time_now + TimeDelta::try_hours(expire_hours).unwrap()).timestamp(),
-        iss: JWT_DELETE_ISSUER.to_string(),
-        sub: uuid,
-    }
-}
-
 pub fn generate_verify_email_claims(uuid: String) -> BasicJwtClaims {
     let time_now = Utc::now();
     let expire_hours = i64::from(CONFIG.invitation_expiration_hours());
@@ -352,12 +502,16 @@ pub fn generate_send_claims(send_id: &str, file_id: &str) -> BasicJwtClaims {
 // Bearer token authentication
 //
 use rocket::{
+    http::Status,
     outcome::try_outcome,
     request::{FromRequest, Outcome, Request},
 };
 
 use crate::db::{
-    models::{Collection, Device, User, UserOrgStatus, UserOrgType, UserOrganization, UserStampException},
+    models::{
+        is_email_verified_for_policy, satisfies_email_verification_policy, AccessLog, Collection, Device, OrgPolicy,
+        OrgPolicyType, PersonalAccessToken, User, UserOrgStatus, UserOrgType, UserOrganization, UserStampException,
+    },
     DbConn,
 };
 
@@ -408,6 +562,8 @@ pub struct ClientHeaders {
     pub host: String,
     pub device_type: i32,
     pub ip: ClientIp,
+    pub client_name: Option<String>,
+    pub client_version: Option<String>,
 }
 
 #[rocket::async_trait]
@@ -424,10 +580,15 @@ impl<'r> FromRequest<'r> for ClientHeaders {
         let device_type: i32 =
             request.headers().get_one("device-type").map(|d| d.parse().unwrap_or(14)).unwrap_or_else(|| 14);
 
+        let client_name = request.headers().get_one("Bitwarden-Client-Name").map(String::from);
+        let client_version = request.headers().get_one("Bitwarden-Client-Version").map(String::from);
+
         Outcome::Success(ClientHeaders {
             host,
             device_type,
             ip,
+            client_name,
+            client_version,
         })
     }
 }
@@ -439,6 +600,77 @@ pub struct Headers {
     pub ip: ClientIp,
 }
 
+#[cfg(test)]
+impl Headers {
+    /// The `Authorization` header of a login of the user on a new device, to test the routes behind this guard
+    pub async fn test_authorization(user_uuid: &str, conn: &mut DbConn) -> rocket::http::Header<'static> {
+        initialize_test_keys();
+        let user = User::find_by_uuid(user_uuid, conn).await.unwrap();
+        let mut device = Device::new(crate::util::get_uuid(), user.uuid.clone(), String::from("test"), 9);
+        let (access_token, _) = device.refresh_tokens(&user, vec!["api".into()]);
+        device.save(conn).await.unwrap();
+        rocket::http::Header::new("Authorization", format!("Bearer {access_token}"))
+    }
+}
+
+/// The token of an admin panel session, to test the routes behind the admin guard
+#[cfg(test)]
+pub fn test_admin_token() -> String {
+    initialize_test_keys();
+    encode_jwt(&generate_admin_claims())
+}
+
+/// Signs the tokens of the tests with a generated key, instead of the key files
+#[cfg(test)]
+fn initialize_test_keys() {
+    let mut keys = JWT_KEYS.write().unwrap();
+    if keys.is_none() {
+        let pem = Rsa::generate(2048).unwrap().private_key_to_pem().unwrap();
+        let (encoding, decoding) = JwtKeys::from_private_pem(&pem).unwrap();
+        *keys = Some(JwtKeys {
+            encoding,
+            decoding,
+            previous: None,
+        });
+    }
+}
+
+/// Tokens stay valid until they expire, so the user of a token could have been disabled or deleted since it was issued.
+/// Those requests are rejected and the clients have to log in again instead of being served stale data.
+async fn find_token_user(user_uuid: &str, conn: &mut DbConn) -> Result<User, &'static str> {
+    let Some(user) = User::find_by_uuid(user_uuid, conn).await else {
+        return Err("This account no longer exists, log in again");
+    };
+    if !user.enabled {
+        return Err("This account has been disabled, log in again");
+    }
+    Ok(user)
+}
+
+struct AccessLogged(std::sync::atomic::AtomicBool);
+
+/// Records an authenticated request in the access log, once per request
+async fn log_access(request: &Request<'_>, user: &User, device: &Device, ip: &ClientIp, conn: &mut DbConn) {
+    let logged = request.local_cache(|| AccessLogged(std::sync::atomic::AtomicBool::new(false)));
+    if logged.0.swap(true, std::sync::atomic::Ordering::Relaxed) {
+        return;
+    }
+
+    let entry = AccessLog::from_request(
+        CONFIG.access_log_enabled(),
+        request,
+        &CONFIG.domain_path(),
+        &user.uuid,
+        device.atype,
+        &crate::util::stored_ip(&ip.ip),
+    );
+    if let Some(entry) = entry {
+        if let Err(e) = entry.save(conn).await {
+            error!("Error saving access log entry: {:#?}", e);
+        }
+    }
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Headers {
     type Error = &'static str;
@@ -461,6 +693,55 @@ impl<'r> FromRequest<'r> for Headers {
             None => err_handler!("No access token provided"),
         };
 
+        // Personal access tokens can be used instead of a login session, limited to their scope
+        if PersonalAccessToken::is_token(access_token) {
+            let mut conn = match DbConn::from_request(request).await {
+                Outcome::Success(conn) => conn,
+                _ => err_handler!("Error getting DB"),
+            };
+
+            let Some(mut pat) = PersonalAccessToken::find_by_token(access_token, &mut conn).await else {
+                err_handler!("Invalid access token")
+            };
+            if pat.is_expired(&Utc::now().naive_utc()) {
+                err_handler!("Access token has expired")
+            }
+            // Tokens are revoked together with the logins, by the global token revocation too
+            if check_token_epoch(pat.creation_date.and_utc().timestamp(), CONFIG._token_epoch()).is_err() {
+                err_handler!("Access token has been revoked")
+            }
+            if !pat.allows_route(request.route().and_then(|r| r.name.as_deref())) {
+                return Outcome::Error((Status::Forbidden, "This access token can't be used for this request"));
+            }
+
+            let user = match find_token_user(&pat.user_uuid, &mut conn).await {
+                Ok(user) => user,
+                Err(msg) => err_handler!(msg),
+            };
+            if user.security_stamp != pat.security_stamp {
+                err_handler!("Access token has been revoked")
+            }
+
+            let device = match Device::find_by_uuid_and_user(&pat.uuid, &pat.user_uuid, &mut conn).await {
+                Some(device) => device,
+                None => err_handler!("Access token has been revoked"),
+            };
+
+            if let Err(e) = pat.update_last_used(&mut conn).await {
+                error!("Error updating access token: {:#?}", e);
+            }
+
+            log_access(request, &user, &device, &ip, &mut conn).await;
+            crate::db::set_request_user(request, &user.uuid);
+
+            return Outcome::Success(Headers {
+                host,
+                device,
+                user,
+                ip,
+            });
+        }
+
         // Check JWT token is valid and get device and user from it
         let claims = match decode_login(access_token) {
             Ok(claims) => claims,
@@ -475,16 +756,17 @@ impl<'r> FromRequest<'r> for Headers {
             _ => err_handler!("Error getting DB"),
         };
 
+        // Checked before the device, which is removed together with the user
+        let user = match find_token_user(&user_uuid, &mut conn).await {
+            Ok(user) => user,
+            Err(msg) => err_handler!(msg),
+        };
+
         let device = match Device::find_by_uuid_and_user(&device_uuid, &user_uuid, &mut conn).await {
             Some(device) => device,
             None => err_handler!("Invalid device id"),
         };
 
-        let user = match User::find_by_uuid(&user_uuid, &mut conn).await {
-            Some(user) => user,
-            None => err_handler!("Device has no user associated"),
-        };
-
         if user.security_stamp != claims.sstamp {
             if let Some(stamp_exception) =
                 user.stamp_exception.as_deref().and_then(|s| serde_json::from_str::<UserStampException>(s).ok())
@@ -516,6 +798,9 @@ impl<'r> FromRequest<'r> for Headers {
             }
         }
 
+        log_access(request, &user, &device, &ip, &mut conn).await;
+        crate::db::set_request_user(request, &user.uuid);
+
         Outcome::Success(Headers {
             host,
             device,
@@ -581,6 +866,21 @@ impl<'r> FromRequest<'r> for OrgHeaders {
                     None => err_handler!("The current user isn't member of the organization"),
                 };
 
+                if !org_user.is_within_access_schedule(Utc::now()) {
+                    err_handler!("Access to the organization isn't allowed at this time")
+                }
+
+                // Only the organization is blocked, the personal vault stays accessible to verify the email address
+                let email_verified = is_email_verified_for_policy(user.verified_at, CONFIG.mail_enabled());
+                if !satisfies_email_verification_policy(org_user.atype, email_verified)
+                    && OrgPolicy::is_enabled_by_org(org_id, OrgPolicyType::RequireEmailVerification, &mut conn).await
+                {
+                    return Outcome::Error((
+                        Status::Forbidden,
+                        "The organization requires a verified email address, use the verify email option in the account settings to continue",
+                    ));
+                }
+
                 Outcome::Success(Self {
                     host: headers.host,
                     device: headers.device,
@@ -820,9 +1120,11 @@ impl<'r> FromRequest<'r> for OwnerHeaders {
 use std::{
     fs::File,
     io::{Read, Write},
-    net::IpAddr,
+    net::{AddrParseError, IpAddr},
 };
 
+use crate::util::IpNetwork;
+
 pub struct ClientIp {
     pub ip: IpAddr,
 }
@@ -834,13 +1136,12 @@ impl<'r> FromRequest<'r> for ClientIp {
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
         let ip = if CONFIG._ip_header_enabled() {
             req.headers().get_one(&CONFIG.ip_header()).and_then(|ip| {
-                match ip.find(',') {
-                    Some(idx) => &ip[..idx],
-                    None => ip,
-                }
-                .parse()
-                .map_err(|_| warn!("'{}' header is malformed: {}", CONFIG.ip_header(), ip))
-                .ok()
+                let trusted_proxies = CONFIG.ip_header_trusted_proxy_networks();
+                let remote = req.remote().map(|r| r.ip());
+                client_ip_from_header(ip, remote, CONFIG.ip_header_trusted_hops() as usize, &trusted_proxies)
+                    .map_err(|_| warn!("'{}' header is malformed: {}", CONFIG.ip_header(), ip))
+                    .ok()
+                    .flatten()
             })
         } else {
             None
@@ -854,6 +1155,45 @@ impl<'r> FromRequest<'r> for ClientIp {
     }
 }
 
+/// Picks the client IP out of the value of the IP header, `None` when the header can't be trusted.
+/// Every proxy appends the address it received the request from, so the addresses are checked from the right.
+/// Only the addresses up to the client one are parsed, anything in front of it is set by the client and ignored.
+fn client_ip_from_header(
+    value: &str,
+    remote: Option<IpAddr>,
+    trusted_hops: usize,
+    trusted_proxies: &[IpNetwork],
+) -> Result<Option<IpAddr>, AddrParseError> {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|network| network.contains(ip));
+    // Anyone connecting directly could set the header, only the trusted proxies are believed
+    if !trusted_proxies.is_empty() && !remote.is_some_and(|remote| is_trusted(&remote)) {
+        return Ok(None);
+    }
+
+    let addresses: Vec<&str> = value.split(',').map(str::trim).collect();
+    if trusted_hops > 0 {
+        // The address in front of the ones appended by the trusted proxies
+        return addresses[addresses.len().saturating_sub(trusted_hops)].parse().map(Some);
+    }
+    if !trusted_proxies.is_empty() {
+        // The first address which wasn't appended by a trusted proxy, or the first one when they all are
+        let mut client_ip = None;
+        for address in addresses.iter().rev() {
+            let ip: IpAddr = address.parse()?;
+            client_ip = Some(ip);
+            if !is_trusted(&ip) {
+                break;
+            }
+        }
+        return Ok(client_ip);
+    }
+    // Legacy behaviour, the first address which can be parsed
+    match addresses.iter().find_map(|address| address.parse().ok()) {
+        Some(ip) => Ok(Some(ip)),
+        None => addresses[0].parse().map(Some),
+    }
+}
+
 pub struct WsAccessTokenHeader {
     pub access_token: Option<String>,
 }
@@ -876,3 +1216,213 @@ impl<'r> FromRequest<'r> for WsAccessTokenHeader {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_keys() -> (EncodingKey, DecodingKey) {
+        let rsa_key = Rsa::generate(2048).unwrap();
+        let enc = EncodingKey::from_rsa_pem(&rsa_key.private_key_to_pem().unwrap()).unwrap();
+        let dec = DecodingKey::from_rsa_pem(&rsa_key.public_key_to_pem().unwrap()).unwrap();
+        (enc, dec)
+    }
+
+    fn test_token(enc: &EncodingKey, iss: Option<&str>, aud: Option<&str>) -> String {
+        let now = Utc::now().timestamp();
+        let mut claims = json!({ "nbf": now, "exp": now + 60, "sub": "user" });
+        if let Some(iss) = iss {
+            claims["iss"] = json!(iss);
+        }
+        if let Some(aud) = aud {
+            claims["aud"] = json!(aud);
+        }
+        jsonwebtoken::encode(&JWT_HEADER, &claims, enc).unwrap()
+    }
+
+    const ISSUER: &str = "https://vw.example.com|login";
+    const AUDIENCE: &str = "https://vw.example.com";
+
+    #[test]
+    fn tokens_issued_before_epoch_rejected() {
+        let (enc, dec) = test_keys();
+        let token = test_token(&enc, Some(ISSUER), Some(AUDIENCE));
+        let claims: serde_json::Value = _decode_jwt(&token, &dec, ISSUER, Some(AUDIENCE)).unwrap();
+        let issued_at = claims["nbf"].as_i64().unwrap();
+
+        assert!(check_token_epoch(issued_at, None).is_ok());
+        // Revoked in the same second or later
+        assert!(check_token_epoch(issued_at, Some(issued_at)).is_err());
+        assert!(check_token_epoch(issued_at, Some(issued_at + 60)).is_err());
+        // Tokens issued after the revocation keep working
+        assert!(check_token_epoch(issued_at, Some(issued_at - 1)).is_ok());
+    }
+
+    #[test]
+    fn login_after_revocation_in_same_second_accepted() {
+        let epoch = chrono::Utc::now().timestamp();
+        assert_eq!(login_not_before(epoch, Some(epoch)), epoch + 1);
+        assert!(check_token_epoch(login_not_before(epoch, Some(epoch)), Some(epoch)).is_ok());
+        assert_eq!(login_not_before(epoch + 5, Some(epoch)), epoch + 5);
+        assert_eq!(login_not_before(epoch, None), epoch);
+    }
+
+    #[test]
+    fn jwt_matching_issuer_and_audience() {
+        let (enc, dec) = test_keys();
+        let token = test_token(&enc, Some(ISSUER), Some(AUDIENCE));
+        assert!(_decode_jwt::<serde_json::Value>(&token, &dec, ISSUER, Some(AUDIENCE)).is_ok());
+
+        // Tokens without an audience are only accepted when no audience is expected
+        let token = test_token(&enc, Some(ISSUER), None);
+        assert!(_decode_jwt::<serde_json::Value>(&token, &dec, ISSUER, None).is_ok());
+    }
+
+    #[test]
+    fn jwt_mismatched_issuer_rejected() {
+        let (enc, dec) = test_keys();
+        let token = test_token(&enc, Some("https://other.example.com|login"), Some(AUDIENCE));
+        assert!(_decode_jwt::<serde_json::Value>(&token, &dec, ISSUER, Some(AUDIENCE)).is_err());
+
+        // A token without issuer must not pass either
+        let token = test_token(&enc, None, Some(AUDIENCE));
+        assert!(_decode_jwt::<serde_json::Value>(&token, &dec, ISSUER, Some(AUDIENCE)).is_err());
+        assert!(_decode_jwt::<serde_json::Value>(&token, &dec, ISSUER, None).is_err());
+    }
+
+    #[test]
+    fn jwt_mismatched_audience_rejected() {
+        let (enc, dec) = test_keys();
+        let token = test_token(&enc, Some(ISSUER), Some("https://other.example.com"));
+        assert!(_decode_jwt::<serde_json::Value>(&token, &dec, ISSUER, Some(AUDIENCE)).is_err());
+
+        let token = test_token(&enc, Some(ISSUER), None);
+        assert!(_decode_jwt::<serde_json::Value>(&token, &dec, ISSUER, Some(AUDIENCE)).is_err());
+    }
+
+    fn networks(list: &str) -> Vec<IpNetwork> {
+        crate::util::parse_ip_networks(list).unwrap()
+    }
+
+    #[test]
+    fn client_ip_from_multi_hop_forwarded_for() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        // A spoofed address in front of the client, then the addresses appended by a CDN and the local proxy
+        let xff = "6.6.6.6, 203.0.113.7, 198.51.100.20, 10.0.0.2";
+        let remote = Some(ip("10.0.0.3"));
+
+        // Legacy behaviour, the first address is used
+        assert_eq!(client_ip_from_header(xff, remote, 0, &[]).unwrap(), Some(ip("6.6.6.6")));
+        // Three proxies appended an address each
+        assert_eq!(client_ip_from_header(xff, remote, 3, &[]).unwrap(), Some(ip("203.0.113.7")));
+
+        let trusted = networks("10.0.0.0/8, 198.51.100.0/24");
+        assert_eq!(client_ip_from_header(xff, remote, 0, &trusted).unwrap(), Some(ip("203.0.113.7")));
+        // A single address header, like CF-Connecting-IP
+        assert_eq!(client_ip_from_header("203.0.113.7", remote, 1, &trusted).unwrap(), Some(ip("203.0.113.7")));
+        assert!(client_ip_from_header("203.0.113.7:443", remote, 0, &trusted).is_err());
+    }
+
+    #[test]
+    fn client_ip_ignores_garbage_in_front() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let remote = Some(ip("10.0.0.3"));
+        // Whatever the client sent itself doesn't make the addresses appended by the proxies unusable
+        let xff = "garbage, 203.0.113.7, 10.0.0.2";
+        assert_eq!(client_ip_from_header(xff, remote, 2, &[]).unwrap(), Some(ip("203.0.113.7")));
+        let trusted = networks("10.0.0.0/8");
+        assert_eq!(client_ip_from_header(xff, remote, 0, &trusted).unwrap(), Some(ip("203.0.113.7")));
+        assert_eq!(client_ip_from_header("garbage, 203.0.113.7", remote, 0, &[]).unwrap(), Some(ip("203.0.113.7")));
+
+        // The address of the client itself has to be valid
+        assert!(client_ip_from_header("203.0.113.7, garbage, 10.0.0.2", remote, 2, &[]).is_err());
+        assert!(client_ip_from_header("garbage", remote, 0, &[]).is_err());
+    }
+
+    #[test]
+    fn client_ip_header_ignored_from_untrusted_remote() {
+        let remote = Some("192.0.2.50".parse().unwrap());
+        let trusted = networks("10.0.0.0/8");
+        assert_eq!(client_ip_from_header("6.6.6.6", remote, 0, &trusted).unwrap(), None);
+
+        // IPv4 clients of a dual-stack listener
+        let mapped = Some("::ffff:10.1.2.3".parse().unwrap());
+        assert!(client_ip_from_header("203.0.113.7", mapped, 0, &trusted).unwrap().is_some());
+        assert!(crate::util::parse_ip_networks("10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn previous_key_valid_during_overlap() {
+        let (old_enc, old_dec) = test_keys();
+        let (new_enc, new_dec) = test_keys();
+        let rotated_at = Utc::now().timestamp();
+        let keys = JwtKeys {
+            encoding: new_enc,
+            decoding: new_dec,
+            previous: Some((old_dec, rotated_at)),
+        };
+
+        let old_token = test_token(&old_enc, Some(ISSUER), Some(AUDIENCE));
+        let new_token = test_token(&keys.encoding, Some(ISSUER), Some(AUDIENCE));
+        let during = keys.decoding_keys(rotated_at + 3599, 3600);
+        assert!(_decode_jwt_keys::<serde_json::Value>(&old_token, &during, ISSUER, Some(AUDIENCE)).is_ok());
+        assert!(_decode_jwt_keys::<serde_json::Value>(&new_token, &during, ISSUER, Some(AUDIENCE)).is_ok());
+    }
+
+    #[test]
+    fn key_rotation_refused_during_overlap() {
+        let rotated_at = Utc::now().timestamp();
+        assert!(check_key_rotation_allowed(None, rotated_at, 3600).is_ok());
+        assert!(check_key_rotation_allowed(Some(rotated_at), rotated_at + 3599, 3600).is_err());
+        assert!(check_key_rotation_allowed(Some(rotated_at), rotated_at + 3600, 3600).is_ok());
+    }
+
+    #[test]
+    fn previous_key_invalid_after_overlap() {
+        let (old_enc, old_dec) = test_keys();
+        let (new_enc, new_dec) = test_keys();
+        let rotated_at = Utc::now().timestamp();
+        let keys = JwtKeys {
+            encoding: new_enc,
+            decoding: new_dec,
+            previous: Some((old_dec, rotated_at)),
+        };
+
+        let old_token = test_token(&old_enc, Some(ISSUER), Some(AUDIENCE));
+        let after = keys.decoding_keys(rotated_at + 3600, 3600);
+        assert!(_decode_jwt_keys::<serde_json::Value>(&old_token, &after, ISSUER, Some(AUDIENCE)).is_err());
+        // Still valid with the new key
+        let new_token = test_token(&keys.encoding, Some(ISSUER), Some(AUDIENCE));
+        assert!(_decode_jwt_keys::<serde_json::Value>(&new_token, &after, ISSUER, Some(AUDIENCE)).is_ok());
+    }
+
+    #[test]
+    #[cfg(sqlite)]
+    fn token_of_disabled_user_rejected() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut conn = DbConn::sqlite_in_memory();
+            conn.insert_user("user").await;
+            let mut user = find_token_user("user", &mut conn).await.unwrap();
+
+            // The user was disabled after the token was issued
+            user.enabled = false;
+            user.save(&mut conn).await.unwrap();
+            assert_eq!(
+                find_token_user("user", &mut conn).await.err(),
+                Some("This account has been disabled, log in again")
+            );
+        });
+    }
+
+    #[test]
+    #[cfg(sqlite)]
+    fn token_of_deleted_user_rejected() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut conn = DbConn::sqlite_in_memory();
+            assert_eq!(
+                find_token_user("user", &mut conn).await.err(),
+                Some("This account no longer exists, log in again")
+            );
+        });
+    }
+}
@@ -0,0 +1,257 @@
+use std::net::IpAddr;
+
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+use crate::{api::EmptyResult, util::get_reqwest_client, Error, CONFIG};
+
+// Failed logins of a client are forgotten after this many minutes without a new failure
+const LOGIN_FAILURE_WINDOW_MINUTES: i64 = 60;
+
+static LOGIN_FAILURES: Lazy<LoginFailures> = Lazy::new(LoginFailures::default);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CaptchaProvider {
+    HCaptcha,
+}
+
+impl CaptchaProvider {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "hcaptcha" => Some(Self::HCaptcha),
+            _ => None,
+        }
+    }
+
+    fn verify_url(self) -> &'static str {
+        match self {
+            Self::HCaptcha => "https://api.hcaptcha.com/siteverify",
+        }
+    }
+}
+
+pub fn is_valid_provider(name: &str) -> bool {
+    CaptchaProvider::from_name(name).is_some()
+}
+
+#[rocket::async_trait]
+trait CaptchaVerifier: Sync {
+    /// Validates a token solved by the client, returns whether the provider accepted it
+    async fn verify(&self, token: &str, ip: Option<&IpAddr>) -> Result<bool, Error>;
+}
+
+/// Verifies tokens with the siteverify API of the configured provider
+struct SiteVerifier {
+    provider: CaptchaProvider,
+    site_key: String,
+    secret_key: String,
+}
+
+impl SiteVerifier {
+    fn from_config() -> Option<Self> {
+        if !CONFIG._enable_captcha() {
+            return None;
+        }
+        Some(Self {
+            provider: CaptchaProvider::from_name(&CONFIG.captcha_provider())?,
+            site_key: CONFIG.captcha_site_key()?,
+            secret_key: CONFIG.captcha_secret_key()?,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl CaptchaVerifier for SiteVerifier {
+    async fn verify(&self, token: &str, ip: Option<&IpAddr>) -> Result<bool, Error> {
+        let mut form = vec![("secret", self.secret_key.clone()), ("response", token.to_string())];
+        if let Some(ip) = ip {
+            form.push(("remoteip", ip.to_string()));
+        }
+
+        let response: Value = get_reqwest_client()
+            .post(self.provider.verify_url())
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(response["success"].as_bool().unwrap_or(false))
+    }
+}
+
+fn captcha_error_json(site_key: &str, msg: &str) -> Value {
+    json!({
+        "error": "invalid_grant",
+        "error_description": msg,
+        // The name of this field is used by the clients to show the CAPTCHA, they only support hCaptcha
+        "HCaptcha_SiteKey": site_key,
+        "ErrorModel": {
+            "Message": msg,
+            "Object": "error"
+        }
+    })
+}
+
+async fn check_captcha(
+    verifier: &impl CaptchaVerifier,
+    site_key: &str,
+    token: Option<&str>,
+    ip: Option<&IpAddr>,
+) -> EmptyResult {
+    let Some(token) = token.filter(|t| !t.is_empty()) else {
+        err_json!(captcha_error_json(site_key, "Captcha required."), "CAPTCHA token not provided")
+    };
+
+    if !verifier.verify(token, ip).await? {
+        err_json!(captcha_error_json(site_key, "Captcha is invalid. Please try again."), "Invalid CAPTCHA token")
+    }
+    Ok(())
+}
+
+/// Registrations need a solved CAPTCHA when a provider is configured
+pub async fn check_registration_captcha(token: Option<&str>) -> EmptyResult {
+    match SiteVerifier::from_config() {
+        Some(verifier) => check_captcha(&verifier, &verifier.site_key, token, None).await,
+        None => Ok(()),
+    }
+}
+
+/// Logins need a solved CAPTCHA after `CAPTCHA_LOGIN_FAILURES` failed logins from the same IP
+pub async fn check_login_captcha(ip: &IpAddr, token: Option<&str>) -> EmptyResult {
+    let Some(verifier) = SiteVerifier::from_config() else {
+        return Ok(());
+    };
+
+    if LOGIN_FAILURES.count(ip, Utc::now().naive_utc()) < CONFIG.captcha_login_failures() {
+        return Ok(());
+    }
+    check_captcha(&verifier, &verifier.site_key, token, Some(ip)).await
+}
+
+pub fn register_login_failure(ip: &IpAddr) {
+    if SiteVerifier::from_config().is_some() {
+        LOGIN_FAILURES.add(ip, Utc::now().naive_utc());
+    }
+}
+
+// Failures aren't cleared by a successful login, as the login could be to an account of whoever is guessing.
+// The expired ones are removed at most once per window, when new failures come in.
+#[derive(Default)]
+struct LoginFailures {
+    failures: DashMap<IpAddr, (u32, NaiveDateTime)>,
+    last_purge: std::sync::Mutex<Option<NaiveDateTime>>,
+}
+
+impl LoginFailures {
+    fn is_expired(last_failure: NaiveDateTime, now: NaiveDateTime) -> bool {
+        now - last_failure >= TimeDelta::try_minutes(LOGIN_FAILURE_WINDOW_MINUTES).unwrap()
+    }
+
+    fn purge_expired(&self, now: NaiveDateTime) {
+        {
+            let mut last_purge = self.last_purge.lock().unwrap();
+            match *last_purge {
+                Some(last) if !Self::is_expired(last, now) => return,
+                _ => *last_purge = Some(now),
+            }
+        }
+        self.failures.retain(|_, (_, last_failure)| !Self::is_expired(*last_failure, now));
+    }
+
+    fn add(&self, ip: &IpAddr, now: NaiveDateTime) {
+        self.purge_expired(now);
+
+        let mut entry = self.failures.entry(*ip).or_insert((0, now));
+        if Self::is_expired(entry.1, now) {
+            entry.0 = 0;
+        }
+        entry.0 += 1;
+        entry.1 = now;
+    }
+
+    fn count(&self, ip: &IpAddr, now: NaiveDateTime) -> u32 {
+        match self.failures.get(ip) {
+            Some(entry) if !Self::is_expired(entry.1, now) => entry.0,
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASS_TOKEN: &str = "10000000-aaaa-bbbb-cccc-000000000001";
+
+    /// Accepts only `PASS_TOKEN`, like the test keys of the providers do
+    struct MockVerifier;
+
+    #[rocket::async_trait]
+    impl CaptchaVerifier for MockVerifier {
+        async fn verify(&self, token: &str, _ip: Option<&IpAddr>) -> Result<bool, Error> {
+            Ok(token == PASS_TOKEN)
+        }
+    }
+
+    fn check(token: Option<&str>) -> EmptyResult {
+        tokio::runtime::Runtime::new().unwrap().block_on(check_captcha(&MockVerifier, "site-key", token, None))
+    }
+
+    #[test]
+    fn captcha_token_passes() {
+        assert!(check(Some(PASS_TOKEN)).is_ok());
+    }
+
+    #[test]
+    fn captcha_token_fails() {
+        assert!(check(Some("20000000-aaaa-bbbb-cccc-000000000002")).is_err());
+        assert!(check(Some("")).is_err());
+        assert!(check(None).is_err());
+    }
+
+    #[test]
+    fn login_failures_counted_per_ip() {
+        let failures = LoginFailures::default();
+        let ip: IpAddr = "192.0.2.10".parse().unwrap();
+        let other_ip: IpAddr = "192.0.2.11".parse().unwrap();
+        let now = Utc::now().naive_utc();
+
+        for _ in 0..3 {
+            failures.add(&ip, now);
+        }
+        assert_eq!(failures.count(&ip, now), 3);
+        assert_eq!(failures.count(&other_ip, now), 0);
+
+        // Failures expire after a while without new ones
+        let later = now + TimeDelta::try_minutes(LOGIN_FAILURE_WINDOW_MINUTES).unwrap();
+        assert_eq!(failures.count(&ip, later), 0);
+        failures.add(&ip, later);
+        assert_eq!(failures.count(&ip, later), 1);
+    }
+
+    #[test]
+    fn expired_login_failures_removed() {
+        let failures = LoginFailures::default();
+        let now = Utc::now().naive_utc();
+        for i in 0..10u8 {
+            failures.add(&IpAddr::from([192, 0, 2, i]), now);
+        }
+        assert_eq!(failures.failures.len(), 10);
+
+        let later = now + TimeDelta::try_minutes(LOGIN_FAILURE_WINDOW_MINUTES).unwrap();
+        failures.add(&IpAddr::from([198, 51, 100, 1]), later);
+        assert_eq!(failures.failures.len(), 1);
+    }
+
+    #[test]
+    fn captcha_provider_names() {
+        assert_eq!(CaptchaProvider::from_name("hCaptcha"), Some(CaptchaProvider::HCaptcha));
+        // The clients can't show a Turnstile CAPTCHA
+        assert_eq!(CaptchaProvider::from_name("turnstile"), None);
+        assert_eq!(CaptchaProvider::from_name("recaptcha"), None);
+    }
+}
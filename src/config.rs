@@ -9,7 +9,7 @@ use reqwest::Url;
 use crate::{
     db::DbConnType,
     error::Error,
-    util::{get_env, get_env_bool, parse_experimental_client_feature_flags},
+    util::{get_env, get_env_bool, parse_experimental_client_feature_flags, parse_ip_networks, IpNetwork},
 };
 
 static CONFIG_FILE: Lazy<String> = Lazy::new(|| {
@@ -42,6 +42,8 @@ macro_rules! make_config {
 
             templates: Handlebars<'static>,
             config: ConfigItems,
+            // Parsed from `IP_HEADER_TRUSTED_PROXIES` when the config is loaded, it's needed for every request
+            ip_header_trusted_proxies: Vec<IpNetwork>,
 
             _env: ConfigBuilder,
             _usr: ConfigBuilder,
@@ -153,6 +155,17 @@ macro_rules! make_config {
         #[derive(Clone, Default)]
         struct ConfigItems { $($( $name: make_config!{@type $ty, $none_action}, )+)+ }
 
+        impl ConfigItems {
+            /// Replaces references to secrets stored elsewhere with the secrets themselves,
+            /// so only the reference is ever saved in the config file.
+            fn resolve_secrets(&mut self) -> Result<(), Error> {
+                $($(
+                    make_config!{ @resolve paste::paste!(stringify!([<$name:upper>])), self.$name, $ty, $none_action };
+                )+)+
+                Ok(())
+            }
+        }
+
         #[allow(unused)]
         impl Config {
             $($(
@@ -163,9 +176,11 @@ macro_rules! make_config {
             )+)+
 
             pub fn prepare_json(&self) -> serde_json::Value {
+                // Show secret references instead of the resolved secrets, so saving doesn't write the secrets to the config file
                 let (def, cfg, overridden) = {
                     let inner = &self.inner.read().unwrap();
-                    (inner._env.build(), inner.config.clone(), inner._overrides.clone())
+                    let cfg = inner._env.merge(&inner._usr, false, &mut Vec::new()).build();
+                    (inner._env.build(), cfg, inner._overrides.clone())
                 };
 
                 fn _get_form_type(rust_type: &str) -> &'static str {
@@ -232,6 +247,7 @@ macro_rules! make_config {
                     "domain_path",
                     "domain",
                     "helo_name",
+                    "jwt_audience",
                     "org_creation_users",
                     "signups_domains_whitelist",
                     "smtp_from",
@@ -305,6 +321,15 @@ macro_rules! make_config {
     ( @supportstr $name:ident, $value:expr, $ty:ty, option ) => { serde_json::to_value($value).unwrap() }; // Optional other value, we return as is or convert to string to apply the privacy config
     ( @supportstr $name:ident, $value:expr, $ty:ty, $none_action:ident ) => { ($value).into() }; // Required other value, we return as is or convert to string to apply the privacy config
 
+    // Resolve secret references, only for Pass types
+    ( @resolve $name:expr, $value:expr, Pass, option ) => {
+        if let Some(value) = &mut $value {
+            resolve_secret_ref($name, value)?;
+        }
+    };
+    ( @resolve $name:expr, $value:expr, Pass, $none_action:ident ) => { resolve_secret_ref($name, &mut $value)?; };
+    ( @resolve $name:expr, $value:expr, $ty:ident, $none_action:ident ) => {};
+
     // Group or empty string
     ( @show ) => { "" };
     ( @show $lit:literal ) => { $lit };
@@ -365,12 +390,18 @@ make_config! {
         templates_folder:       String, false,  auto,   |c| format!("{}/{}", c.data_folder, "templates");
         /// Session JWT key
         rsa_key_filename:       String, false,  auto,   |c| format!("{}/{}", c.data_folder, "rsa_key");
+        /// Break-glass token |> Hash of the one-time token created with `vaultwarden break-glass` to reset a lost admin token
+        break_glass_token_filename: String, false, auto, |c| format!("{}/{}", c.data_folder, "break_glass_token");
         /// Web vault folder
         web_vault_folder:       String, false,  def,    "web-vault/".to_string();
     },
     ws {
         /// Enable websocket notifications
         enable_websocket:       bool,   false,  def,    true;
+        /// Ping interval |> Seconds between the pings sent to websocket clients
+        websocket_ping_interval: u64,   false,  def,    15;
+        /// Pong timeout |> Seconds without a pong or other message after which a websocket connection is closed
+        websocket_pong_timeout: u64,    false,  def,    60;
     },
     push {
         /// Enable push notifications
@@ -383,6 +414,19 @@ make_config! {
         push_installation_id:   Pass,   false,  def,    String::new();
         /// Installation key |> The installation key from https://bitwarden.com/host
         push_installation_key:  Pass,   false,  def,    String::new();
+        /// Push failures before pruning |> Number of consecutive deliveries for which the push relay didn't know the push registration of a device, after which the registration is removed.
+        /// Only counted for users with a single registered device. The device will register again once the app provides a new push token. Set to 0 to never prune registrations.
+        push_prune_failures:    u32,    false,  def,    5;
+        /// Max push devices per user |> Maximum number of devices registered for push notifications per user.
+        /// When a new device registers, the oldest registrations above this limit are removed. Set to 0 for no limit.
+        push_max_devices:       u32,    false,  def,    0;
+        /// Minimal push payloads |> Replace cipher, folder and send notifications by a plain vault sync signal without any item ids or revision dates,
+        /// so the push relay never sees them. It only contains the user id and the time it was sent. The devices then fetch the changes over the API,
+        /// at the cost of a full sync
+        push_minimal_payload:   bool,   false,  def,    false;
+        /// Push retry attempts |> Number of times a notification is sent again when the push relay couldn't be reached or had a temporary problem,
+        /// waiting 5 seconds before the first retry and twice as long before every next one. Set to 0 to never retry
+        push_retry_attempts:    u32,    false,  def,    3;
     },
     jobs {
         /// Job scheduler poll interval |> How often the job scheduler thread checks for jobs to run.
@@ -403,12 +447,46 @@ make_config! {
         /// Emergency request timeout schedule |> Cron schedule of the job that grants emergency access requests that have met the required wait time.
         /// Defaults to hourly. (7 minutes after the hour) Set blank to disable this job.
         emergency_request_timeout_schedule:   String, false,  def,    "0 7 * * * *".to_string();
+        /// Key rotation reminder schedule |> Cron schedule of the job that reminds users to change their master password, see KEY_ROTATION_REMINDER_DAYS.
+        /// Defaults to daily. Set blank to disable this job.
+        key_rotation_reminder_schedule:   String, false,  def,    "0 20 8 * * *".to_string();
+        /// Duo health check schedule |> Cron schedule of the job that checks whether the global Duo integration still works, see DUO_HEALTH_CHECK_ALERT_EMAILS.
+        /// Disabled by default. Set a cron expression to enable this job.
+        duo_health_check_schedule:   String, false,  def,    String::new();
         /// Event cleanup schedule |> Cron schedule of the job that cleans old events from the event table.
         /// Defaults to daily. Set blank to disable this job.
         event_cleanup_schedule:   String, false,  def,    "0 10 0 * * *".to_string();
         /// Auth Request cleanup schedule |> Cron schedule of the job that cleans old auth requests from the auth request.
         /// Defaults to every minute. Set blank to disable this job.
         auth_request_purge_schedule:   String, false,  def,    "30 * * * * *".to_string();
+        /// Organization invitation purge schedule |> Cron schedule of the job that removes expired organization invitations, see ORG_INVITE_EXPIRY_DAYS.
+        /// Defaults to hourly. (15 minutes after the hour) Set blank to disable this job.
+        org_invite_purge_schedule:   String, false,  def,    "0 15 * * * *".to_string();
+        /// Orphaned attachment purge schedule |> Cron schedule of the job that removes attachment files without an attachment in the database,
+        /// see ORPHANED_ATTACHMENTS_DRY_RUN. Defaults to daily. Set blank to disable this job.
+        orphaned_attachments_purge_schedule:   String, false,  def,    "0 40 3 * * *".to_string();
+        /// Access log cleanup schedule |> Cron schedule of the job that removes access log entries older than ACCESS_LOG_DAYS_RETAIN.
+        /// Defaults to daily. Set blank to disable this job.
+        access_log_cleanup_schedule:   String, false,  def,    "0 25 0 * * *".to_string();
+        /// Inactive account disable schedule |> Cron schedule of the job that warns and disables inactive accounts, see INACTIVE_ACCOUNT_DISABLE_DAYS.
+        /// Defaults to daily. Set blank to disable this job.
+        inactive_account_disable_schedule:   String, false,  def,    "0 35 2 * * *".to_string();
+        /// Unverified account purge schedule |> Cron schedule of the job that reminds and deletes unverified accounts, see UNVERIFIED_ACCOUNT_PURGE_DAYS.
+        /// Defaults to daily. Set blank to disable this job.
+        unverified_account_purge_schedule:   String, false,  def,    "0 45 2 * * *".to_string();
+        /// Database integrity check schedule |> Cron schedule of the job that checks the SQLite database for corruption, see DB_INTEGRITY_CHECK_ALERT_EMAILS.
+        /// Disabled by default. Set a cron expression to enable this job. Does nothing with MySQL/MariaDB and PostgreSQL.
+        db_integrity_check_schedule:   String, false,  def,    String::new();
+        /// Database optimize schedule |> Cron schedule of the job that runs `PRAGMA optimize` on the SQLite database.
+        /// Disabled by default. Set a cron expression to enable this job. Does nothing with MySQL/MariaDB and PostgreSQL.
+        db_optimize_schedule:   String, false,  def,    String::new();
+        /// Database vacuum schedule |> Cron schedule of the job that runs `VACUUM` on the SQLite database to reclaim the space of removed data.
+        /// The database is locked while it runs, so schedule it during low traffic. Disabled by default. Does nothing with MySQL/MariaDB and PostgreSQL.
+        db_vacuum_schedule:     String, false,  def,    String::new();
+        /// JWT key rotation schedule |> Cron schedule of the job that replaces the key signing the login and other tokens,
+        /// see JWT_KEY_ROTATION_OVERLAP_HOURS. Disabled by default, the key can also be rotated from the admin panel.
+        /// The rotations have to be at least the overlap apart, and the overlap at least INVITATION_EXPIRATION_HOURS.
+        jwt_key_rotation_schedule: String, false, def,  String::new();
 
     },
 
@@ -423,12 +501,24 @@ make_config! {
         domain_origin:          String, false,  auto,   |c| extract_url_origin(&c.domain);
         /// Domain path |> Domain URL path (in https://example.com:8443/path, /path is the path)
         domain_path:            String, false,  auto,   |c| extract_url_path(&c.domain);
+        /// Token audience |> The audience of the access tokens, only tokens with a matching audience are accepted.
+        /// Defaults to the domain origin. Changing it invalidates all the access tokens which were issued before.
+        jwt_audience:           String, false,  auto,   |c| extract_url_origin(&c.domain);
         /// Enable web vault
         web_vault_enabled:      bool,   false,  def,    true;
 
         /// Allow Sends |> Controls whether users are allowed to create Bitwarden Sends.
         /// This setting applies globally to all users. To control this on a per-org basis instead, use the "Disable Send" org policy.
         sends_allowed:          bool,   true,   def,    true;
+        /// Disabled features |> Comma-separated list of features which are turned off for this instance: `sends`, `emergency_access` and `org_creation`.
+        /// Their endpoints respond with a 404 Not Found, including the public Send access links
+        disabled_features:      String, true,   def,    String::new();
+        /// Announcement message |> Notice served to the clients, like upcoming maintenance. Blank serves no announcement
+        announcement_message:   String, true,   def,    String::new();
+        /// Announcement severity |> One of `info`, `warning` or `critical`
+        announcement_severity:  String, true,   def,    "info".to_string();
+        /// Announcement expiration date |> The announcement isn't served anymore after this date, like `2024-07-01T06:00:00Z`. Leave unset to serve it until it's removed
+        announcement_expiration_date: String, true, option;
 
         /// HIBP Api Key |> HaveIBeenPwned API Key, request it here: https://haveibeenpwned.com/API/Key
         hibp_api_key:           Pass,   true,   option;
@@ -437,6 +527,50 @@ make_config! {
         user_attachment_limit:  i64,    true,   option;
         /// Per-organization attachment storage limit (KB) |> Max kilobytes of attachment storage allowed per org. When this limit is reached, org members will not be allowed to upload further attachments for ciphers owned by that org.
         org_attachment_limit:   i64,    true,   option;
+        /// Max attachments per item |> Maximum number of attachments a single item can have. Leave unset for no limit
+        max_attachments_per_cipher: i64, true, option;
+        /// Max attachment name length |> Maximum length of the encrypted file name of an attachment
+        max_attachment_name_length: usize, true, def, 1000;
+        /// Require encrypted attachment keys |> Reject attachment uploads whose key isn't an encrypted string,
+        /// as the attachment couldn't be decrypted. Uploads without any key are always rejected
+        attachment_require_encrypted_key: bool, true, def, true;
+        /// Allowed attachment MIME types |> Comma-separated list of MIME types, like `image/*` or `application/pdf`, attachments have to match. Blank allows any type.
+        /// Attachments are encrypted, so this is checked on a best-effort basis against the type declared by the client, Bitwarden clients declare `application/octet-stream`,
+        /// and the type recognized from the start of the uploaded data
+        attachment_allowed_mime: String, true, def, String::new();
+        /// Blocked attachment MIME types |> Comma-separated list of MIME types, like `application/x-msdownload`, attachments are rejected with. Checked like the allowed types
+        attachment_blocked_mime: String, true, def, String::new();
+        /// Require attachments on key rotation |> Reject key rotations which don't include the re-encrypted key of every attachment,
+        /// as attachments left out can't be decrypted anymore afterwards
+        key_rotation_require_attachments: bool, true, def, true;
+        /// Max folders per user |> Maximum number of folders a user can have, creating or importing more is rejected. Leave unset for no limit
+        max_folders_per_user:   i64,    true,   option;
+        /// Max folder name length |> Maximum length of the encrypted name of a folder
+        max_folder_name_length: usize,  true,   def,    1000;
+        /// Max import size (MB) |> Maximum size of an import, both of the JSON sent by clients and of an uploaded export file
+        import_max_size_mb:     u64,    false,  def,    20;
+        /// Orphaned attachments dry run |> Only log the attachment files without an attachment in the database, instead of removing them
+        orphaned_attachments_dry_run: bool, true, def, true;
+        /// Orphaned attachments minimum age (hours) |> Attachment files modified more recently are never removed as orphaned,
+        /// as they could belong to an upload in progress
+        orphaned_attachments_min_age_hours: u64, true, def, 24;
+        /// Attachment scan command |> Command used to scan uploaded attachments, the path of the file is appended as last argument.
+        /// An exit code of 0 means the file is clean, 1 means it's flagged and the upload is rejected. Any other result also rejects the upload.
+        attachment_scan_cmd:    String, false,  option;
+        /// Attachment scan timeout |> Number of seconds after which a running attachment scan is stopped and the upload is rejected
+        attachment_scan_timeout: u64,   false,  def,    60;
+        /// Max multipart parts |> Maximum number of parts in a single multipart upload request, requests with more parts are rejected
+        multipart_max_parts:    u32,    true,   def,    16;
+        /// Max multipart fields size (KB) |> Maximum combined size of the non-file parts in a single multipart upload request, larger requests are rejected
+        multipart_max_fields_size: u64, true,   def,    64;
+        /// Max cipher notes size |> Maximum length of the encrypted notes of an item, larger items are rejected
+        cipher_max_notes_bytes: usize,  true,   def,    10_000;
+        /// Max cipher field size |> Maximum length of every other encrypted value of an item, like the name, password or a custom field.
+        /// Leave unset for no limit
+        cipher_max_field_bytes: usize,  true,   option;
+        /// Cipher idempotency window (seconds) |> How long the `Idempotency-Key` of an item create request is remembered, so a retried request
+        /// returns the item created before instead of a duplicate. Set to 0 to ignore the header
+        cipher_idempotency_window: u64, true,   def,    300;
         /// Per-user send storage limit (KB) |> Max kilobytes of sends storage allowed per user. When this limit is reached, the user will not be allowed to upload further sends.
         user_send_limit:   i64,    true,   option;
 
@@ -467,20 +601,80 @@ make_config! {
         signups_verify_resend_limit: u32, true, def,    6;
         /// Email domain whitelist |> Allow signups only from this list of comma-separated domains, even when signups are otherwise disabled
         signups_domains_whitelist: String, true, def,   String::new();
+        /// Minimum password strength |> Reject signups whose client reported master password strength score (0-4) is below this value.
+        /// The score is calculated by the client and the stock clients don't send it, so this is only a best-effort check. 0 disables the check
+        registration_min_password_strength: u8, true, def, 0;
+        /// Hide existing accounts on registration |> Registering an already registered email returns the same response as a new registration,
+        /// when a new signup with that email would succeed. The owner of the account is notified by email instead, at most once per hour
+        registration_anti_enum: bool,   true,   def,    false;
+        /// Minimum web vault version |> Logins with an older web vault are refused with a request to update. Leave unset to allow all versions
+        min_client_version_web: String, true,   option;
+        /// Minimum desktop app version |> Logins with an older desktop app are refused with a request to update. Leave unset to allow all versions
+        min_client_version_desktop: String, true, option;
+        /// Minimum mobile app version |> Logins with an older mobile app are refused with a request to update. Leave unset to allow all versions
+        min_client_version_mobile: String, true, option;
+        /// Minimum CLI version |> Logins with an older CLI are refused with a request to update. Leave unset to allow all versions
+        min_client_version_cli: String, true,   option;
+        /// Key rotation reminder days |> Email users whose master password and keys haven't been changed for this many days,
+        /// at most once per period. Leave unset to disable the reminders
+        key_rotation_reminder_days: i64, true, option;
+        /// Minimum password change interval (hours) |> Users can't change their master password or KDF settings again within this many hours of the previous change.
+        /// Password resets by organization admins and emergency access takeovers aren't restricted. Leave unset to allow changes at any time
+        min_password_change_interval: i64, true, option;
+        /// Inactive account disable days |> Disable accounts without any login activity for this many days. Admins can enable them again.
+        /// Leave unset to keep inactive accounts enabled
+        inactive_account_disable_days: i64, true, option;
+        /// Inactive account warning days |> Email users this many days before their account is disabled for inactivity. Set to 0 to disable without a warning
+        inactive_account_warning_days: i64, true, def, 14;
+        /// Unverified account purge days |> Delete accounts which didn't verify their email address within this many days of registering.
+        /// Members of an organization, including pending invitations, are kept. Requires email and SIGNUPS_VERIFY to be enabled. Leave unset to keep unverified accounts
+        unverified_account_purge_days: i64, true, option;
+        /// Unverified account reminder days |> Email users a new verification link this many days before their unverified account is deleted.
+        /// An account is only deleted after it got this reminder, and not within this many days of it
+        unverified_account_reminder_days: i64, true, def, 7;
         /// Enable event logging |> Enables event logging for organizations.
         org_events_enabled:     bool,   false,  def,    false;
+        /// Enable admin event logging |> Logs the actions taken in the admin panel as events, with the targeted user or organization
+        /// and the admin session. They are listed at /admin/events and cleaned up with the other events
+        admin_events_enabled:   bool,   false,  def,    false;
+        /// Enable access logging |> Records the authenticated API requests of every user (endpoint, time, IP address and client),
+        /// request bodies are never recorded. Users can view their own entries, admins those of any user
+        access_log_enabled:     bool,   true,   def,    false;
+        /// Access log days retain |> Number of days access log entries are kept, see ACCESS_LOG_CLEANUP_SCHEDULE
+        access_log_days_retain: i64,    true,   def,    30;
         /// Org creation users |> Allow org creation only by this list of comma-separated user emails.
         /// Blank or 'all' means all users can create orgs; 'none' means no users can create orgs.
         org_creation_users:     String, true,   def,    String::new();
+        /// Require a collection for org items |> Members which aren't admins or owners can't remove the last collection of an organization item,
+        /// as items without collections are only visible to admins and owners
+        org_items_require_collection: bool, true, def, true;
         /// Allow invitations |> Controls whether users can be invited by organization admins, even when signups are otherwise disabled
         invitations_allowed:    bool,   true,   def,    true;
-        /// Invitation token expiration time (in hours) |> The number of hours after which an organization invite token, emergency access invite token,
-        /// email verification token and deletion request token will expire (must be at least 1)
+        /// Invitation token expiration time (in hours) |> The number of hours after which an organization invite token, emergency access invite token
+        /// and email verification token will expire (must be at least 1). Account deletion links expire after the account recovery token lifetime instead
         invitation_expiration_hours: u32, false, def, 120;
+        /// Account recovery token lifetime (seconds) |> The number of seconds an emailed account recovery link, like the one to delete an account
+        /// with a forgotten master password, stays valid. Each link can only be used once, and a master password change invalidates it
+        password_reset_token_ttl: u64, true, def, 3600;
+        /// Organization invitation expiry days |> Pending organization invitations which aren't accepted within this many days expire,
+        /// and are removed by the ORG_INVITE_PURGE_SCHEDULE job. Re-inviting renews the period. Leave unset to keep invitations indefinitely
+        org_invite_expiry_days: i64, true, option;
+        /// Two-step login grace period days |> New members of an organization with the two-step login policy can join and use the vault
+        /// for this many days after accepting the invitation, to enroll a two-step login method it accepts. Afterwards their membership
+        /// is revoked at their next login or session refresh until they enrolled one and an admin restores it. Set to 0 to require it when joining
+        org_2fa_grace_period_days: i64, true, def, 0;
         /// Enable emergency access |> Controls whether users can enable emergency access to their accounts. This setting applies globally to all users.
         emergency_access_allowed:    bool,   true,   def,    true;
+        /// Emergency access quorum window (hours) |> Users can require several of their takeover grantees to initiate a recovery before any of them
+        /// can take over the account. The recoveries have to be initiated within this many hours of each other to count towards that quorum
+        emergency_access_quorum_window_hours: i64, true, def, 72;
+        /// Require 2FA for emergency access takeovers |> The grantee has to confirm a takeover with one of their own two-step login providers (authenticator app, email or YubiKey) and the grantor receives an email once it completes
+        emergency_access_takeover_require_2fa: bool, true, def, false;
         /// Allow email change |> Controls whether users can change their email. This setting applies globally to all users.
         email_change_allowed:    bool,   true,   def,    true;
+        /// Enforce unique emails |> Refuse to start while multiple accounts share an email in different case or with surrounding whitespace,
+        /// instead of only warning about them. Older versions stored changed emails as entered
+        enforce_unique_emails:   bool,   false,  def,    false;
         /// Password iterations |> Number of server-side passwords hashing iterations for the password hash.
         /// The default for new users. If changed, it will be updated during login for existing users.
         password_iterations:    i32,    true,   def,    600_000;
@@ -493,6 +687,8 @@ make_config! {
 
         /// Admin token/Argon2 PHC |> The plain text token or Argon2 PHC string used to authenticate in this very same page. Changing it here will not deauthorize the current session!
         admin_token:            Pass,   true,   option;
+        /// Admin TOTP secret |> BASE32 encoded TOTP secret, when set a code of an authenticator app is required in addition to the admin token
+        admin_totp_secret:      Pass,   true,   option;
 
         /// Invitation organization name |> Name shown in the invitation emails that don't come from a specific organization
         invitation_org_name:    String, true,   def,    "Vaultwarden".to_string();
@@ -508,6 +704,31 @@ make_config! {
         ip_header:              String, true,   def,    "X-Real-IP".to_string();
         /// Internal IP header property, used to avoid recomputing each time
         _ip_header_enabled:     bool,   false,  gen,    |c| &c.ip_header.trim().to_lowercase() != "none";
+        /// Client IP header trusted hops |> Number of proxies in front of Vaultwarden which append an address to the IP header,
+        /// like with X-Forwarded-For. The client IP is the address in front of theirs. Set to 0 to use the first address
+        ip_header_trusted_hops: u32,    true,   def,    0;
+        /// Client IP header trusted proxies |> Comma separated list of IP networks of the proxies, like `10.0.0.0/8,2001:db8::/32`.
+        /// When set, the IP header is only used for requests from these proxies, and without trusted hops the
+        /// last address in the header outside of these networks is the client IP
+        ip_header_trusted_proxies: String, true, def,   String::new();
+        /// Anonymize stored IPs |> Zero the last octet of IPv4 and the last 80 bits of IPv6 client addresses
+        /// before they are stored with events and incomplete two-step login attempts
+        ip_anonymize:           bool,   true,   def,    false;
+        /// JWT key rotation overlap (hours) |> After a rotation of the signing key, tokens signed with the previous key stay valid
+        /// for this many hours, so nobody is logged out. Tokens still unused afterwards, like pending invitations, become invalid
+        jwt_key_rotation_overlap_hours: u32, false, def, 120;
+        /// Token epoch (generated) |> Login tokens issued up to this UNIX timestamp are rejected, set by revoking all tokens from the admin panel
+        _token_epoch:           i64,    false,  option;
+        /// Enable compression |> Compress responses with brotli or gzip when the client supports it
+        enable_compression:     bool,   true,   def,    false;
+        /// Compression minimum size |> Responses smaller than this many bytes are not compressed
+        compression_min_size:   u64,    true,   def,    1024;
+        /// Request timeout (seconds) |> Read-only requests which aren't handled within this many seconds are cut off with a 504 error.
+        /// Requests which make changes and websocket notifications are never cut off. Leave unset for no timeout
+        request_timeout_secs:   u64,    false,  option;
+        /// Upload request timeout (seconds) |> Uploads of attachments, Send files and organization logos which aren't received
+        /// within this many seconds are rejected with a 408 error. Leave unset for no timeout
+        upload_request_timeout_secs: u64, false, option;
         /// Icon service |> The predefined icon services are: internal, bitwarden, duckduckgo, google.
         /// To specify a custom icon service, set a URL template with exactly one instance of `{}`,
         /// which is replaced with the domain. For example: `https://icon.example.com/domain/{}`.
@@ -529,6 +750,9 @@ make_config! {
         icon_cache_ttl:         u64,    true,   def,    2_592_000;
         /// Negative icon cache expiry |> Number of seconds before trying to download an icon that failed again.
         icon_cache_negttl:      u64,    true,   def,    259_200;
+        /// Transient negative icon cache expiry |> Number of seconds before trying to download an icon again, when the previous
+        /// attempt failed because of a transient error like a timeout, a connection error or a server error.
+        icon_cache_transient_negttl: u64, true, def,    600;
         /// Icon download timeout |> Number of seconds when to stop attempting to download an icon.
         icon_download_timeout:  u64,    true,   def,    10;
         /// Icon blacklist Regex |> Any domains or IPs that match this regex won't be fetched by the icon service.
@@ -537,15 +761,61 @@ make_config! {
         /// Icon blacklist non global IPs |> Any IP which is not defined as a global IP will be blacklisted.
         /// Useful to secure your internal environment: See https://en.wikipedia.org/wiki/Reserved_IP_addresses for a list of IPs which it will block
         icon_blacklist_non_global_ips:  bool,   true,   def,    true;
+        /// Normalize icons |> Downscale icons larger than the max size and convert them to PNG before caching them
+        icon_normalize:         bool,   true,   def,    false;
+        /// Normalized icon max size |> Maximum width and height in pixels of normalized icons
+        icon_normalize_max_size: u32,   true,   def,    128;
+        /// Allow SVG icons |> Pass through SVG icons as-is. When disabled, SVG icons are skipped and another icon is used if available
+        icon_allow_svg:         bool,   true,   def,    false;
+        /// Fallback icon path |> Path to a local PNG, JPEG, GIF, ICO, WEBP, BMP or SVG image served when an icon can't be fetched or is blocked, instead of the built-in one
+        icon_fallback_path:     String, false,  option;
+
+        /// Minimum TLS version for outbound requests |> The minimum TLS version used by all outgoing HTTPS requests (icons, HIBP, Duo, push, ...).
+        /// Connections to servers which only support an older version will fail. Valid values are 1.0, 1.1 and 1.2
+        outbound_min_tls:       String, false,  def,    "1.2".to_string();
+        /// DNS-over-HTTPS resolver URL |> Resolve the hostnames of all outgoing requests with this RFC 8484 DNS-over-HTTPS endpoint instead of the system resolver, e.g. https://1.1.1.1/dns-query
+        outbound_doh_url:       String, false,  option;
+        /// Outbound proxy |> Send all outgoing requests (icons, HIBP, Duo, push, ...) through this proxy, e.g. http://proxy.internal:3128 or socks5://proxy.internal:1080.
+        /// When any outbound proxy is set, the HTTP_PROXY, HTTPS_PROXY and ALL_PROXY environment variables are ignored
+        outbound_proxy:         String, false,  option;
+        /// Outbound HTTP proxy |> Proxy for outgoing plain HTTP requests, takes precedence over the outbound proxy
+        outbound_proxy_http:    String, false,  option;
+        /// Outbound HTTPS proxy |> Proxy for outgoing HTTPS requests, takes precedence over the outbound proxy
+        outbound_proxy_https:   String, false,  option;
+        /// Outbound proxy username |> Username to authenticate with at the outbound proxies
+        outbound_proxy_username: String, false, option;
+        /// Outbound proxy password |> Password to authenticate with at the outbound proxies
+        outbound_proxy_password: Pass,  false,  option;
+        /// Outbound no proxy |> Comma-separated hosts, domains and IP ranges which are contacted directly, e.g. localhost,.internal,10.0.0.0/8
+        outbound_no_proxy:      String, false,  option;
 
         /// Disable Two-Factor remember |> Enabling this would force the users to use a second factor to login every time.
         /// Note that the checkbox would still be present, but ignored.
         disable_2fa_remember:   bool,   true,   def,    false;
+        /// Max age of Two-Factor remember (days) |> A device is only remembered this many days after the second factor was used,
+        /// the remember token is also replaced on every use. Leave unset to remember devices until they log out
+        two_factor_remember_max_days: i64, true, option;
+
+        /// Require a two-step login backup |> Users with a single two-step login method are asked to save their recovery code,
+        /// and can't remove their second method before doing so. Admins can list the users still lacking a backup.
+        two_factor_backup_required: bool, true, def,    false;
 
         /// Disable authenticator time drifted codes to be valid |> Enabling this only allows the current TOTP code to be valid
         /// TOTP codes of the previous and next 30 seconds will be invalid.
         authenticator_disable_time_drift: bool, true, def, false;
 
+        /// Require unique authenticator secrets |> Reject enrolling an authenticator (TOTP) secret which another account already uses,
+        /// as a shared seed lets several people generate the same codes. Every stored secret is compared on enrollment
+        authenticator_require_unique_secret: bool, true, def, false;
+
+        /// Require WebAuthn user verification |> Security keys have to verify the user with a PIN or biometric, not just their presence.
+        /// Keys which can't verify users can't be registered nor used to log in anymore.
+        webauthn_require_user_verification: bool, true, def, false;
+
+        /// TOTP secret encryption key |> When set, the authenticator (TOTP) secrets are stored encrypted with a key derived from this value.
+        /// Existing plaintext secrets are encrypted on their next successful use. Changing or removing this key afterwards makes the encrypted secrets unusable.
+        totp_encryption_key: Pass, false, option;
+
         /// Customize the enabled feature flags on the clients |> This is a comma separated list of feature flags to enable.
         experimental_client_feature_flags: String, false, def, "fido2-vault-credentials".to_string();
 
@@ -571,6 +841,12 @@ make_config! {
         /// that do not support WAL. Please make sure you read project wiki on the topic before changing this setting.
         enable_db_wal:          bool,   false,  def,    true;
 
+        /// Full database integrity check |> Run `PRAGMA integrity_check` instead of the faster `PRAGMA quick_check` in the scheduled
+        /// SQLite integrity check, which also verifies that the indexes match their tables
+        db_integrity_check_full: bool,  false,  def,    false;
+        /// Database integrity alert emails |> Comma separated list of addresses to email when the scheduled SQLite integrity check fails
+        db_integrity_check_alert_emails: String, true, option;
+
         /// Max database connection retries |> Number of times to retry the database connection during startup, with 1 second between each retry, set to 0 to retry indefinitely
         db_connection_retries:  u32,    false,  def,    15;
 
@@ -580,20 +856,60 @@ make_config! {
         /// Database connection pool size
         database_max_conns:     u32,    false,  def,    10;
 
+        /// Database minimum idle connections |> Number of idle connections kept open in the pool, the others are closed after the idle timeout.
+        /// Defaults to 2, or to the pool size when that is smaller
+        database_min_conns:     u32,    false,  auto,   |c| c.database_max_conns.min(2);
+
+        /// Database idle connection timeout |> Number of seconds after which an idle connection above the minimum is closed, set to 0 to keep them open
+        database_idle_timeout:  u64,    false,  def,    600;
+
+        /// Read replica URL |> A read-only replica of the database, used by the sync and other read-only endpoints. Must be the same type of database as the Database URL
+        database_read_url:      String, false,  option;
+
+        /// Read after write window |> Number of seconds after a write affecting a user, their own or one which changes their vault revision, during which the reads of that user still go to the primary database, so the replica has time to catch up.
+        /// The writes are only known to the instance which made them, so several instances need sticky sessions per user
+        database_read_after_write_seconds: u64, false, def, 10;
+
+        /// Database connection pool size for scheduled jobs |> The scheduled jobs use their own pool, so they don't contend with the request traffic
+        database_jobs_max_conns: u32,   false,  def,    2;
+
         /// Database connection init |> SQL statements to run when creating a new database connection, mainly useful for connection-scoped pragmas. If empty, a database-specific default is used.
         database_conn_init:     String, false,  def,    String::new();
 
+        /// Slow database query threshold (ms) |> Database queries which take at least this many milliseconds are logged as a warning,
+        /// with the location of the query in the code instead of the SQL. Leave unset to disable
+        db_slow_query_threshold_ms: u64, true, option;
+
         /// Bypass admin page security (Know the risks!) |> Disables the Admin Token for the admin page so you may use your own auth in-front
         disable_admin_token:    bool,   false,  def,    false;
 
         /// Allowed iframe ancestors (Know the risks!) |> Allows other domains to embed the web vault into an iframe, useful for embedding into secure intranets
         allowed_iframe_ancestors: String, true, def,    String::new();
 
+        /// Login error jitter (ms) |> Failed logins are answered after a random delay of up to this many milliseconds, so the response time doesn't reveal whether an account exists
+        login_error_jitter_ms:         u64, true,  def, 250;
+
         /// Seconds between login requests |> Number of seconds, on average, between login and 2FA requests from the same IP address before rate limiting kicks in
         login_ratelimit_seconds:       u64, false, def, 60;
         /// Max burst size for login requests |> Allow a burst of requests of up to this size, while maintaining the average indicated by `login_ratelimit_seconds`. Note that this applies to both the login and the 2FA, so it's recommended to allow a burst size of at least 2
         login_ratelimit_max_burst:     u32, false, def, 10;
 
+        /// Seconds between failed Send access attempts |> Number of seconds, on average, between wrong passwords for the same password protected Send from the same IP address before rate limiting kicks in. Successful accesses aren't counted
+        send_access_ratelimit_seconds:   u64, false, def, 60;
+        /// Max burst size for failed Send access attempts |> Allow a burst of wrong passwords of up to this size, while maintaining the average indicated by `send_access_ratelimit_seconds`
+        send_access_ratelimit_max_burst: u32, false, def, 5;
+
+        /// Seconds between vault exports |> Number of seconds, on average, between organization vault exports of the same user before rate limiting kicks in
+        export_ratelimit_seconds:      u64, false, def, 720;
+        /// Max burst size for vault exports |> Allow a burst of exports of up to this size, while maintaining the average indicated by `export_ratelimit_seconds`
+        export_ratelimit_max_burst:    u32, false, def, 5;
+
+        /// Seconds between icon downloads |> Number of seconds, on average, between icon requests from the same IP address which make the server download an icon before rate limiting kicks in.
+        /// Cached icons are always served. Leave unset to disable
+        icon_ratelimit_seconds:        u64, false, option;
+        /// Max burst size for icon downloads |> Allow a burst of icon downloads of up to this size, while maintaining the average indicated by `icon_ratelimit_seconds`
+        icon_ratelimit_max_burst:      u32, false, def, 100;
+
         /// Seconds between admin login requests |> Number of seconds, on average, between admin requests from the same IP address before rate limiting kicks in
         admin_ratelimit_seconds:       u64, false, def, 300;
         /// Max burst size for admin login requests |> Allow a burst of requests of up to this size, while maintaining the average indicated by `admin_ratelimit_seconds`
@@ -618,6 +934,20 @@ make_config! {
         yubico_server:          String, true,   option;
     },
 
+    /// CAPTCHA settings
+    captcha: _enable_captcha {
+        /// Enabled
+        _enable_captcha:        bool,   true,   def,     true;
+        /// Provider |> The CAPTCHA provider, only hcaptcha is supported as it's the only one the clients can show
+        captcha_provider:       String, true,   def,     "hcaptcha".to_string();
+        /// Site Key
+        captcha_site_key:       String, true,   option;
+        /// Secret Key
+        captcha_secret_key:     Pass,   true,   option;
+        /// Login failures |> Number of failed logins from the same IP after which logins need a CAPTCHA, 0 requires it for every login
+        captcha_login_failures: u32,    true,   def,     3;
+    },
+
     /// Global Duo settings (Note that users can override them)
     duo: _enable_duo {
         /// Enabled
@@ -628,6 +958,16 @@ make_config! {
         duo_skey:               Pass,   true,   option;
         /// Host
         duo_host:               String, true,   option;
+        /// Username format |> How the email address of a user maps to their Duo username with the global Duo keys:
+        /// `email` (as-is), `lowercase`, or `strip_domain` to only use the part before the `@`.
+        /// Only use `strip_domain` when all users share one email domain
+        duo_username_format:    String, true,   def,     "email".to_string();
+        /// Allowed hosts |> Comma separated list of Duo API hostnames which are contacted,
+        /// entries starting with a `.` match all subdomains. When set, hosts of global and user configured Duo keys
+        /// outside of this list are refused. Any host is allowed when unset
+        duo_allowed_hosts:      String, true,   option;
+        /// Health check alert emails |> Comma separated list of addresses to email when the scheduled Duo health check fails
+        duo_health_check_alert_emails: String, true, option;
         /// Application Key (generated automatically)
         _duo_akey:              Pass,   false,  option;
     },
@@ -693,6 +1033,21 @@ make_config! {
     },
 }
 
+const SECRET_FILE_SCHEME: &str = "file://";
+
+/// Secrets can be stored in a file, and referenced with `file:///run/secrets/smtp_password`
+fn resolve_secret_ref(name: &str, value: &mut String) -> Result<(), Error> {
+    if let Some(path) = value.strip_prefix(SECRET_FILE_SCHEME) {
+        match std::fs::read_to_string(path) {
+            Ok(secret) => *value = secret.trim().to_string(),
+            Err(e) => err!(format!("Failed to load the secret `{name}` from `{path}`: {e}")),
+        }
+    } else if value.starts_with("secret://") {
+        err!(format!("The secret `{name}` uses an unsupported reference, only `{SECRET_FILE_SCHEME}` is supported"))
+    }
+    Ok(())
+}
+
 fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
     // Validate connection URL is valid and DB feature is enabled
     let url = &cfg.database_url;
@@ -705,6 +1060,16 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         }
     }
 
+    if let Some(read_url) = &cfg.database_read_url {
+        if DbConnType::from_url(read_url)? != DbConnType::from_url(url)? {
+            err!("`DATABASE_READ_URL` must be the same type of database as `DATABASE_URL`");
+        }
+    }
+
+    if let Err(e) = parse_ip_networks(&cfg.ip_header_trusted_proxies) {
+        err!(format!("`IP_HEADER_TRUSTED_PROXIES` is invalid: {e}"));
+    }
+
     if cfg.password_iterations < 100_000 {
         err!("PASSWORD_ITERATIONS should be at least 100000 or higher. The default is 600000!");
     }
@@ -714,6 +1079,26 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         err!(format!("`DATABASE_MAX_CONNS` contains an invalid value. Ensure it is between 1 and {limit}.",));
     }
 
+    if cfg.database_min_conns > cfg.database_max_conns {
+        err!("`DATABASE_MIN_CONNS` can't be larger than `DATABASE_MAX_CONNS`");
+    }
+
+    if cfg.websocket_ping_interval == 0 {
+        err!("`WEBSOCKET_PING_INTERVAL` must be at least 1 second");
+    }
+
+    if cfg.websocket_pong_timeout <= cfg.websocket_ping_interval {
+        err!("`WEBSOCKET_PONG_TIMEOUT` must be larger than `WEBSOCKET_PING_INTERVAL`");
+    }
+
+    if cfg.db_slow_query_threshold_ms == Some(0) {
+        err!("`DB_SLOW_QUERY_THRESHOLD_MS` must be at least 1, leave it unset to disable the slow query log");
+    }
+
+    if cfg.database_jobs_max_conns < 1 || cfg.database_jobs_max_conns > limit {
+        err!(format!("`DATABASE_JOBS_MAX_CONNS` contains an invalid value. Ensure it is between 1 and {limit}.",));
+    }
+
     if let Some(log_file) = &cfg.log_file {
         if std::fs::OpenOptions::new().append(true).create(true).open(log_file).is_err() {
             err!("Unable to write to log file", log_file);
@@ -732,6 +1117,38 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         err!("`SIGNUPS_DOMAINS_WHITELIST` contains empty tokens");
     }
 
+    if cfg.two_factor_remember_max_days.is_some_and(|days| days < 1) {
+        err!("`TWO_FACTOR_REMEMBER_MAX_DAYS` must be at least 1");
+    }
+
+    if cfg.registration_min_password_strength > 4 {
+        err!("`REGISTRATION_MIN_PASSWORD_STRENGTH` must be between 0 and 4");
+    }
+
+    for (name, min_version) in [
+        ("MIN_CLIENT_VERSION_WEB", &cfg.min_client_version_web),
+        ("MIN_CLIENT_VERSION_DESKTOP", &cfg.min_client_version_desktop),
+        ("MIN_CLIENT_VERSION_MOBILE", &cfg.min_client_version_mobile),
+        ("MIN_CLIENT_VERSION_CLI", &cfg.min_client_version_cli),
+    ] {
+        if min_version.as_deref().is_some_and(|v| crate::util::parse_client_version(v).is_none()) {
+            err!(format!("`{name}` must be a version like `2024.6.0`"));
+        }
+    }
+
+    if !ANNOUNCEMENT_SEVERITIES.contains(&cfg.announcement_severity.as_str()) {
+        err!(format!("`ANNOUNCEMENT_SEVERITY` must be one of {}", ANNOUNCEMENT_SEVERITIES.join(", ")))
+    }
+    if let Some(ref date) = cfg.announcement_expiration_date {
+        if !date.trim().is_empty() && chrono::DateTime::parse_from_rfc3339(date.trim()).is_err() {
+            err!("`ANNOUNCEMENT_EXPIRATION_DATE` must be a date like `2024-07-01T06:00:00Z`")
+        }
+    }
+
+    if let Err(e) = parse_features(&cfg.disabled_features) {
+        err!(format!("`DISABLED_FEATURES` {e}"));
+    }
+
     let org_creation_users = cfg.org_creation_users.trim().to_lowercase();
     if !(org_creation_users.is_empty() || org_creation_users == "all" || org_creation_users == "none")
         && org_creation_users.split(',').any(|u| !u.contains('@'))
@@ -746,6 +1163,12 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         }
     }
 
+    if let Some(ref secret) = cfg.admin_totp_secret {
+        if data_encoding::BASE32.decode(secret.trim().as_bytes()).map_or(true, |s| s.is_empty()) {
+            err!("`ADMIN_TOTP_SECRET` must be a BASE32 encoded secret");
+        }
+    }
+
     if cfg.push_enabled && (cfg.push_installation_id == String::new() || cfg.push_installation_key == String::new()) {
         err!(
             "Misconfigured Push Notification service\n\
@@ -790,6 +1213,15 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
 
     const MAX_FILESIZE_KB: i64 = i64::MAX >> 10;
 
+    for (name, list) in [
+        ("ATTACHMENT_ALLOWED_MIME", &cfg.attachment_allowed_mime),
+        ("ATTACHMENT_BLOCKED_MIME", &cfg.attachment_blocked_mime),
+    ] {
+        if list.split(',').map(str::trim).any(|mime| !mime.is_empty() && !mime.contains('/')) {
+            err!(format!("`{name}` must be a comma-separated list of MIME types like `image/*`"))
+        }
+    }
+
     if let Some(limit) = cfg.user_attachment_limit {
         if !(0i64..=MAX_FILESIZE_KB).contains(&limit) {
             err!("`USER_ATTACHMENT_LIMIT` is out of bounds");
@@ -802,6 +1234,26 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         }
     }
 
+    if matches!(cfg.max_attachments_per_cipher, Some(max) if max < 0) {
+        err!("`MAX_ATTACHMENTS_PER_CIPHER` can't be negative");
+    }
+
+    if matches!(cfg.max_folders_per_user, Some(max) if max < 0) {
+        err!("`MAX_FOLDERS_PER_USER` can't be negative");
+    }
+
+    if cfg.max_attachment_name_length < 100 {
+        err!("`MAX_ATTACHMENT_NAME_LENGTH` must be at least 100");
+    }
+
+    if cfg.max_folder_name_length < 100 {
+        err!("`MAX_FOLDER_NAME_LENGTH` must be at least 100");
+    }
+
+    if cfg.import_max_size_mb == 0 {
+        err!("`IMPORT_MAX_SIZE_MB` must be at least 1");
+    }
+
     if let Some(limit) = cfg.user_send_limit {
         if !(0i64..=MAX_FILESIZE_KB).contains(&limit) {
             err!("`USER_SEND_LIMIT` is out of bounds");
@@ -815,6 +1267,16 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         err!("All Duo options need to be set for global Duo support")
     }
 
+    if let (Some(duo_host), Some(duo_allowed_hosts)) = (&cfg.duo_host, &cfg.duo_allowed_hosts) {
+        if !crate::api::is_allowed_duo_host(duo_host, duo_allowed_hosts) {
+            err!("`DUO_HOST` is not in `DUO_ALLOWED_HOSTS`")
+        }
+    }
+
+    if !crate::api::is_valid_duo_username_format(&cfg.duo_username_format) {
+        err!("`DUO_USERNAME_FORMAT` must be one of `email`, `lowercase` or `strip_domain`")
+    }
+
     if cfg._enable_yubico {
         if cfg.yubico_client_id.is_some() != cfg.yubico_secret_key.is_some() {
             err!("Both `YUBICO_CLIENT_ID` and `YUBICO_SECRET_KEY` must be set for Yubikey OTP support")
@@ -828,6 +1290,16 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         }
     }
 
+    if cfg._enable_captcha {
+        if cfg.captcha_site_key.is_some() != cfg.captcha_secret_key.is_some() {
+            err!("Both `CAPTCHA_SITE_KEY` and `CAPTCHA_SECRET_KEY` must be set for CAPTCHA support")
+        }
+
+        if !crate::captcha::is_valid_provider(&cfg.captcha_provider) {
+            err!("`CAPTCHA_PROVIDER` is invalid. Only hcaptcha is supported by the clients")
+        }
+    }
+
     if cfg._enable_smtp {
         match cfg.smtp_security.as_str() {
             "off" | "starttls" | "force_tls" => (),
@@ -908,6 +1380,63 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         }
     }
 
+    if cfg.icon_ratelimit_seconds == Some(0) {
+        err!("`ICON_RATELIMIT_SECONDS` can't be 0, leave it unset to disable the icon rate limit")
+    }
+    if cfg.icon_ratelimit_max_burst == 0 {
+        err!("`ICON_RATELIMIT_MAX_BURST` can't be 0")
+    }
+
+    if cfg.icon_normalize_max_size < 16 || cfg.icon_normalize_max_size > 1024 {
+        err!("`ICON_NORMALIZE_MAX_SIZE` must be between 16 and 1024")
+    }
+
+    if let Some(ref path) = cfg.icon_fallback_path {
+        if let Err(e) = crate::api::load_fallback_icon(path) {
+            err!(format!("`ICON_FALLBACK_PATH` is invalid: {e}"))
+        }
+    }
+
+    if crate::util::parse_tls_version(&cfg.outbound_min_tls).is_none() {
+        err!("`OUTBOUND_MIN_TLS` must be one of 1.0, 1.1 or 1.2, TLS 1.3 can't be set as the minimum version")
+    }
+
+    if let Some(doh_url) = &cfg.outbound_doh_url {
+        if !doh_url.starts_with("https://") || Url::parse(doh_url).is_err() {
+            err!("`OUTBOUND_DOH_URL` must be a valid https:// URL")
+        }
+    }
+
+    for (name, proxy) in [
+        ("OUTBOUND_PROXY", &cfg.outbound_proxy),
+        ("OUTBOUND_PROXY_HTTP", &cfg.outbound_proxy_http),
+        ("OUTBOUND_PROXY_HTTPS", &cfg.outbound_proxy_https),
+    ] {
+        let Some(proxy) = proxy else {
+            continue;
+        };
+        match Url::parse(proxy) {
+            Ok(url) if matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") && url.has_host() => (),
+            _ => err!(format!("`{name}` must be a valid http://, https://, socks5:// or socks5h:// URL")),
+        }
+    }
+
+    if cfg.outbound_proxy_username.is_some() != cfg.outbound_proxy_password.is_some() {
+        err!("`OUTBOUND_PROXY_USERNAME` and `OUTBOUND_PROXY_PASSWORD` must be set together")
+    }
+
+    // Requests would otherwise be sent directly instead of through the proxy
+    let proxy_auth = cfg.outbound_proxy_username.as_deref().zip(cfg.outbound_proxy_password.as_deref());
+    if let Err(e) = crate::util::outbound_proxies(
+        cfg.outbound_proxy.as_deref(),
+        cfg.outbound_proxy_http.as_deref(),
+        cfg.outbound_proxy_https.as_deref(),
+        proxy_auth,
+        cfg.outbound_no_proxy.as_deref(),
+    ) {
+        err!(format!("The outbound proxy configuration is invalid: {e}"))
+    }
+
     // Check if the icon service is valid
     let icon_service = cfg.icon_service.as_str();
     match icon_service {
@@ -934,6 +1463,18 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         err!("`INVITATION_EXPIRATION_HOURS` has a minimum duration of 1 hour")
     }
 
+    if cfg.request_timeout_secs == Some(0) || cfg.upload_request_timeout_secs == Some(0) {
+        err!("`REQUEST_TIMEOUT_SECS` and `UPLOAD_REQUEST_TIMEOUT_SECS` must be at least 1 second when set")
+    }
+
+    if cfg.cipher_idempotency_window > 86_400 {
+        err!("`CIPHER_IDEMPOTENCY_WINDOW` can be at most one day")
+    }
+
+    if !(60..=604_800).contains(&cfg.password_reset_token_ttl) {
+        err!("`PASSWORD_RESET_TOKEN_TTL` must be between 60 seconds and 7 days")
+    }
+
     // Validate schedule crontab format
     if !cfg.send_purge_schedule.is_empty() && cfg.send_purge_schedule.parse::<Schedule>().is_err() {
         err!("`SEND_PURGE_SCHEDULE` is not a valid cron expression")
@@ -959,6 +1500,88 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         err!("`EMERGENCY_REQUEST_TIMEOUT_SCHEDULE` is not a valid cron expression")
     }
 
+    if !cfg.key_rotation_reminder_schedule.is_empty() && cfg.key_rotation_reminder_schedule.parse::<Schedule>().is_err()
+    {
+        err!("`KEY_ROTATION_REMINDER_SCHEDULE` is not a valid cron expression")
+    }
+
+    if !cfg.duo_health_check_schedule.is_empty() && cfg.duo_health_check_schedule.parse::<Schedule>().is_err() {
+        err!("`DUO_HEALTH_CHECK_SCHEDULE` is not a valid cron expression")
+    }
+
+    if !cfg.db_integrity_check_schedule.is_empty() && cfg.db_integrity_check_schedule.parse::<Schedule>().is_err() {
+        err!("`DB_INTEGRITY_CHECK_SCHEDULE` is not a valid cron expression")
+    }
+
+    if !cfg.db_optimize_schedule.is_empty() && cfg.db_optimize_schedule.parse::<Schedule>().is_err() {
+        err!("`DB_OPTIMIZE_SCHEDULE` is not a valid cron expression")
+    }
+
+    if !cfg.db_vacuum_schedule.is_empty() && cfg.db_vacuum_schedule.parse::<Schedule>().is_err() {
+        err!("`DB_VACUUM_SCHEDULE` is not a valid cron expression")
+    }
+
+    if !cfg.jwt_key_rotation_schedule.is_empty() {
+        let Ok(schedule) = cfg.jwt_key_rotation_schedule.parse::<Schedule>() else {
+            err!("`JWT_KEY_ROTATION_SCHEDULE` is not a valid cron expression")
+        };
+        check_jwt_key_rotation(&schedule, cfg.jwt_key_rotation_overlap_hours, cfg.invitation_expiration_hours)?;
+    }
+
+    if cfg.jwt_key_rotation_overlap_hours < 2 {
+        err!("`JWT_KEY_ROTATION_OVERLAP_HOURS` must be at least 2, the lifetime of the login tokens")
+    }
+
+    if matches!(cfg.key_rotation_reminder_days, Some(days) if days < 1) {
+        err!("`KEY_ROTATION_REMINDER_DAYS` must be at least 1")
+    }
+
+    if let Some(disable_days) = cfg.inactive_account_disable_days {
+        if !(1..=36500).contains(&disable_days) {
+            err!("`INACTIVE_ACCOUNT_DISABLE_DAYS` must be between 1 and 36500")
+        }
+        if cfg.inactive_account_warning_days < 0 || cfg.inactive_account_warning_days >= disable_days {
+            err!("`INACTIVE_ACCOUNT_WARNING_DAYS` must be at least 0 and less than `INACTIVE_ACCOUNT_DISABLE_DAYS`")
+        }
+    }
+
+    if !cfg.inactive_account_disable_schedule.is_empty()
+        && cfg.inactive_account_disable_schedule.parse::<Schedule>().is_err()
+    {
+        err!("`INACTIVE_ACCOUNT_DISABLE_SCHEDULE` is not a valid cron expression")
+    }
+
+    if !(1..=8760).contains(&cfg.emergency_access_quorum_window_hours) {
+        err!("`EMERGENCY_ACCESS_QUORUM_WINDOW_HOURS` must be between 1 and 8760")
+    }
+
+    if let Some(purge_days) = cfg.unverified_account_purge_days {
+        if !(1..=36500).contains(&purge_days) {
+            err!("`UNVERIFIED_ACCOUNT_PURGE_DAYS` must be between 1 and 36500")
+        }
+        if cfg.unverified_account_reminder_days < 1 || cfg.unverified_account_reminder_days >= purge_days {
+            err!("`UNVERIFIED_ACCOUNT_REMINDER_DAYS` must be at least 1 and less than `UNVERIFIED_ACCOUNT_PURGE_DAYS`")
+        }
+    }
+
+    if !cfg.unverified_account_purge_schedule.is_empty()
+        && cfg.unverified_account_purge_schedule.parse::<Schedule>().is_err()
+    {
+        err!("`UNVERIFIED_ACCOUNT_PURGE_SCHEDULE` is not a valid cron expression")
+    }
+
+    if matches!(cfg.min_password_change_interval, Some(hours) if !(1..=87600).contains(&hours)) {
+        err!("`MIN_PASSWORD_CHANGE_INTERVAL` must be between 1 and 87600 hours")
+    }
+
+    if !cfg.access_log_cleanup_schedule.is_empty() && cfg.access_log_cleanup_schedule.parse::<Schedule>().is_err() {
+        err!("`ACCESS_LOG_CLEANUP_SCHEDULE` is not a valid cron expression")
+    }
+
+    if !(1..=36500).contains(&cfg.access_log_days_retain) {
+        err!("`ACCESS_LOG_DAYS_RETAIN` must be between 1 and 36500")
+    }
+
     if !cfg.event_cleanup_schedule.is_empty() && cfg.event_cleanup_schedule.parse::<Schedule>().is_err() {
         err!("`EVENT_CLEANUP_SCHEDULE` is not a valid cron expression")
     }
@@ -967,6 +1590,28 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         err!("`AUTH_REQUEST_PURGE_SCHEDULE` is not a valid cron expression")
     }
 
+    if !cfg.org_invite_purge_schedule.is_empty() && cfg.org_invite_purge_schedule.parse::<Schedule>().is_err() {
+        err!("`ORG_INVITE_PURGE_SCHEDULE` is not a valid cron expression")
+    }
+
+    if !cfg.orphaned_attachments_purge_schedule.is_empty()
+        && cfg.orphaned_attachments_purge_schedule.parse::<Schedule>().is_err()
+    {
+        err!("`ORPHANED_ATTACHMENTS_PURGE_SCHEDULE` is not a valid cron expression")
+    }
+
+    if !(1..=87_600).contains(&cfg.orphaned_attachments_min_age_hours) {
+        err!("`ORPHANED_ATTACHMENTS_MIN_AGE_HOURS` must be between 1 and 87600")
+    }
+
+    if !(0..=365).contains(&cfg.org_2fa_grace_period_days) {
+        err!("`ORG_2FA_GRACE_PERIOD_DAYS` must be between 0 and 365")
+    }
+
+    if matches!(cfg.org_invite_expiry_days, Some(days) if !(1..=36_500).contains(&days)) {
+        err!("`ORG_INVITE_EXPIRY_DAYS` must be between 1 and 36500")
+    }
+
     if !cfg.disable_admin_token {
         match cfg.admin_token.as_ref() {
             Some(t) if t.starts_with("$argon2") => {
@@ -1060,6 +1705,58 @@ fn smtp_convert_deprecated_ssl_options(smtp_ssl: Option<bool>, smtp_explicit_tls
     "starttls".to_string()
 }
 
+pub const ANNOUNCEMENT_SEVERITIES: [&str; 3] = ["info", "warning", "critical"];
+
+/// Only the previous signing key is kept after a rotation, so the next rotation can't happen before its overlap ended.
+/// Invitations are only accepted during the overlap after a rotation, so it can't be shorter than their expiration.
+fn check_jwt_key_rotation(schedule: &Schedule, overlap_hours: u32, invitation_hours: u32) -> Result<(), Error> {
+    let overlap_secs = i64::from(overlap_hours) * 3600;
+    let rotations: Vec<i64> = schedule.upcoming(chrono::Utc).take(100).map(|time| time.timestamp()).collect();
+    if rotations.windows(2).any(|pair| pair[1] - pair[0] < overlap_secs) {
+        err!("`JWT_KEY_ROTATION_SCHEDULE` rotates the key more often than `JWT_KEY_ROTATION_OVERLAP_HOURS`")
+    }
+    if invitation_hours > overlap_hours {
+        err!("`JWT_KEY_ROTATION_OVERLAP_HOURS` must be at least `INVITATION_EXPIRATION_HOURS` when the key is rotated on a schedule")
+    }
+    Ok(())
+}
+
+/// Features which can be turned off per deployment with `DISABLED_FEATURES`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Feature {
+    Sends,
+    EmergencyAccess,
+    OrgCreation,
+}
+
+impl Feature {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Feature::Sends => "sends",
+            Feature::EmergencyAccess => "emergency_access",
+            Feature::OrgCreation => "org_creation",
+        }
+    }
+}
+
+impl std::str::FromStr for Feature {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().replace('-', "_").as_str() {
+            "sends" | "send" => Ok(Feature::Sends),
+            "emergency_access" => Ok(Feature::EmergencyAccess),
+            "org_creation" => Ok(Feature::OrgCreation),
+            other => Err(format!("contains the unknown feature `{other}`")),
+        }
+    }
+}
+
+/// Parse a comma-separated list of features, empty entries are ignored
+fn parse_features(list: &str) -> Result<Vec<Feature>, String> {
+    list.split(',').filter(|f| !f.trim().is_empty()).map(str::parse).collect()
+}
+
 impl Config {
     pub fn load() -> Result<Self, Error> {
         // Loading from env and file
@@ -1071,13 +1768,15 @@ impl Config {
         let builder = _env.merge(&_usr, true, &mut _overrides);
 
         // Fill any missing with defaults
-        let config = builder.build();
+        let mut config = builder.build();
+        config.resolve_secrets()?;
         validate_config(&config)?;
 
         Ok(Config {
             inner: RwLock::new(Inner {
                 rocket_shutdown_handle: None,
                 templates: load_templates(&config.templates_folder),
+                ip_header_trusted_proxies: parse_ip_networks(&config.ip_header_trusted_proxies).unwrap_or_default(),
                 config,
                 _env,
                 _usr,
@@ -1091,22 +1790,29 @@ impl Config {
         //let builder = other.remove(&self.inner.read().unwrap()._env);
 
         // TODO: Remove values that are defaults, above only checks those set by env and not the defaults
-        let builder = other;
+        let mut builder = other;
+
+        // The token epoch isn't part of the admin form, saving it must not make revoked tokens valid again
+        if builder._token_epoch.is_none() {
+            builder._token_epoch = self.inner.read().unwrap()._usr._token_epoch;
+        }
 
         // Serialize now before we consume the builder
         let config_str = serde_json::to_string_pretty(&builder)?;
 
         // Prepare the combined config
         let mut overrides = Vec::new();
-        let config = {
+        let mut config = {
             let env = &self.inner.read().unwrap()._env;
             env.merge(&builder, false, &mut overrides).build()
         };
+        config.resolve_secrets()?;
         validate_config(&config)?;
 
         // Save both the user and the combined config
         {
             let mut writer = self.inner.write().unwrap();
+            writer.ip_header_trusted_proxies = parse_ip_networks(&config.ip_header_trusted_proxies).unwrap_or_default();
             writer.config = config;
             writer._usr = builder;
             writer._overrides = overrides;
@@ -1155,13 +1861,29 @@ impl Config {
         }
     }
 
+    /// Tests whether a feature is available on this instance. Combines `DISABLED_FEATURES` with the older
+    /// per-feature settings, `SENDS_ALLOWED` only restricts creating and editing Sends and is handled separately.
+    pub fn is_feature_enabled(&self, feature: Feature) -> bool {
+        let allowed = match feature {
+            Feature::Sends => true,
+            Feature::EmergencyAccess => self.emergency_access_allowed(),
+            Feature::OrgCreation => self.org_creation_users() != "none",
+        };
+        allowed && !self.is_feature_disabled(feature)
+    }
+
+    /// Tests whether a feature is turned off with `DISABLED_FEATURES`, which makes its endpoints respond with a 404
+    pub fn is_feature_disabled(&self, feature: Feature) -> bool {
+        parse_features(&self.disabled_features()).unwrap_or_default().contains(&feature)
+    }
+
     /// Tests whether the specified user is allowed to create an organization.
     pub fn is_org_creation_allowed(&self, email: &str) -> bool {
         let users = self.org_creation_users();
-        if users.is_empty() || users == "all" {
-            true
-        } else if users == "none" {
+        if !self.is_feature_enabled(Feature::OrgCreation) {
             false
+        } else if users.is_empty() || users == "all" {
+            true
         } else {
             let email = email.to_lowercase();
             users.split(',').any(|u| u.trim() == email)
@@ -1169,20 +1891,31 @@ impl Config {
     }
 
     pub fn delete_user_config(&self) -> Result<(), Error> {
-        std::fs::remove_file(&*CONFIG_FILE)?;
+        // Like when saving, the token epoch is kept so revoked tokens stay revoked
+        let token_epoch = self.inner.read().unwrap()._usr._token_epoch;
+        if token_epoch.is_some() {
+            return self.update_config(ConfigBuilder {
+                _token_epoch: token_epoch,
+                ..Default::default()
+            });
+        }
 
         // Empty user config
         let usr = ConfigBuilder::default();
 
-        // Config now is env + defaults
-        let config = {
+        // Config now is env + defaults, with the secret references resolved like when loading
+        let mut config = {
             let env = &self.inner.read().unwrap()._env;
             env.build()
         };
+        config.resolve_secrets()?;
+
+        std::fs::remove_file(&*CONFIG_FILE)?;
 
         // Save configs
         {
             let mut writer = self.inner.write().unwrap();
+            writer.ip_header_trusted_proxies = parse_ip_networks(&config.ip_header_trusted_proxies).unwrap_or_default();
             writer.config = config;
             writer._usr = usr;
             writer._overrides = Vec::new();
@@ -1191,14 +1924,49 @@ impl Config {
         Ok(())
     }
 
+    /// The networks of `IP_HEADER_TRUSTED_PROXIES`, validated and parsed when the config is loaded
+    pub fn ip_header_trusted_proxy_networks(&self) -> Vec<IpNetwork> {
+        self.inner.read().unwrap().ip_header_trusted_proxies.clone()
+    }
+
     pub fn private_rsa_key(&self) -> String {
         format!("{}.pem", CONFIG.rsa_key_filename())
     }
+    /// The signing key replaced by the last rotation, see `JWT_KEY_ROTATION_OVERLAP_HOURS`
+    pub fn previous_private_rsa_key(&self) -> String {
+        format!("{}.previous.pem", CONFIG.rsa_key_filename())
+    }
     pub fn mail_enabled(&self) -> bool {
         let inner = &self.inner.read().unwrap().config;
         inner._enable_smtp && (inner.smtp_host.is_some() || inner.use_sendmail)
     }
 
+    /// Replaces the admin token, saved to the config file like changes made in the admin panel
+    pub fn set_admin_token(&self, admin_token: String) -> Result<(), Error> {
+        let builder = ConfigBuilder {
+            admin_token: Some(admin_token),
+            ..Default::default()
+        };
+        self.update_config_partial(builder)
+    }
+
+    /// Replaces the announcement served to the clients, saved to the config file like changes made in the admin panel
+    pub fn set_announcement(
+        &self,
+        message: String,
+        severity: String,
+        expiration_date: Option<String>,
+    ) -> Result<(), Error> {
+        let builder = ConfigBuilder {
+            announcement_message: Some(message),
+            announcement_severity: Some(severity),
+            // Empty instead of unset, so a previous expiration date is removed
+            announcement_expiration_date: Some(expiration_date.unwrap_or_default()),
+            ..Default::default()
+        };
+        self.update_config_partial(builder)
+    }
+
     pub fn get_duo_akey(&self) -> String {
         if let Some(akey) = self._duo_akey() {
             akey
@@ -1216,6 +1984,17 @@ impl Config {
         }
     }
 
+    /// Invalidates all login tokens issued until now, returns the new token epoch
+    pub fn revoke_all_tokens(&self) -> Result<i64, Error> {
+        let epoch = chrono::Utc::now().timestamp();
+        let builder = ConfigBuilder {
+            _token_epoch: Some(epoch),
+            ..Default::default()
+        };
+        self.update_config_partial(builder)?;
+        Ok(epoch)
+    }
+
     /// Tests whether the admin token is set to a non-empty value.
     pub fn is_admin_token_set(&self) -> bool {
         let token = self.admin_token();
@@ -1238,6 +2017,24 @@ impl Config {
         }
     }
 
+    /// Turns the access log on or off without validating or saving the config, the tests of the access log need it enabled
+    #[cfg(test)]
+    pub fn set_access_log_enabled(&self, enabled: bool) {
+        self.inner.write().unwrap().config.access_log_enabled = enabled;
+    }
+
+    /// Turns the admin events on or off without validating or saving the config, the tests of the admin events need them enabled
+    #[cfg(test)]
+    pub fn set_admin_events_enabled(&self, enabled: bool) {
+        self.inner.write().unwrap().config.admin_events_enabled = enabled;
+    }
+
+    /// Sets the read replica URL without validating or saving the config, no replica pool is created for it
+    #[cfg(test)]
+    pub fn set_database_read_url(&self, url: Option<String>) {
+        self.inner.write().unwrap().config.database_read_url = url;
+    }
+
     pub fn set_rocket_shutdown_handle(&self, handle: rocket::Shutdown) {
         self.inner.write().unwrap().rocket_shutdown_handle = Some(handle);
     }
@@ -1290,18 +2087,27 @@ where
     reg!("email/emergency_access_invite_accepted", ".html");
     reg!("email/emergency_access_invite_confirmed", ".html");
     reg!("email/emergency_access_recovery_approved", ".html");
+    reg!("email/emergency_access_takeover_completed", ".html");
     reg!("email/emergency_access_recovery_initiated", ".html");
     reg!("email/emergency_access_recovery_rejected", ".html");
     reg!("email/emergency_access_recovery_reminder", ".html");
     reg!("email/emergency_access_recovery_timed_out", ".html");
     reg!("email/incomplete_2fa_login", ".html");
+    reg!("email/twofactor_backup_missing", ".html");
     reg!("email/invite_accepted", ".html");
+    reg!("email/device_approval_requested", ".html");
     reg!("email/invite_confirmed", ".html");
+    reg!("email/key_rotation_reminder", ".html");
+    reg!("email/inactive_account_warning", ".html");
+    reg!("email/unverified_account_reminder", ".html");
+    reg!("email/duo_health_check_failed", ".html");
+    reg!("email/db_integrity_check_failed", ".html");
     reg!("email/new_device_logged_in", ".html");
     reg!("email/protected_action", ".html");
     reg!("email/pw_hint_none", ".html");
     reg!("email/pw_hint_some", ".html");
     reg!("email/send_2fa_removed_from_org", ".html");
+    reg!("email/send_2fa_method_removed_from_org", ".html");
     reg!("email/send_emergency_access_invite", ".html");
     reg!("email/send_org_invite", ".html");
     reg!("email/send_single_org_removed_from_org", ".html");
@@ -1310,6 +2116,7 @@ where
     reg!("email/verify_email", ".html");
     reg!("email/welcome_must_verify", ".html");
     reg!("email/welcome", ".html");
+    reg!("email/register_existing_account", ".html");
 
     reg!("admin/base");
     reg!("admin/login");
@@ -1395,3 +2202,59 @@ fn to_json<'reg, 'rc>(
     out.write(&json)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_file_reference_resolved() {
+        let path = std::env::temp_dir().join(format!("vaultwarden-secret-{}", crate::util::get_uuid()));
+        std::fs::write(&path, "s3cr3t\n").unwrap();
+
+        let mut value = format!("{SECRET_FILE_SCHEME}{}", path.display());
+        let result = resolve_secret_ref("SMTP_PASSWORD", &mut value);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(value, "s3cr3t");
+    }
+
+    #[test]
+    fn secret_file_reference_missing() {
+        let mut value = format!("{SECRET_FILE_SCHEME}/nonexistent/vaultwarden/secret");
+        assert!(resolve_secret_ref("SMTP_PASSWORD", &mut value).is_err());
+        let mut value = String::from("secret://vault/smtp");
+        assert!(resolve_secret_ref("SMTP_PASSWORD", &mut value).is_err());
+    }
+
+    #[test]
+    fn secret_plain_value_kept() {
+        let mut value = String::from("plain-password");
+        assert!(resolve_secret_ref("SMTP_PASSWORD", &mut value).is_ok());
+        assert_eq!(value, "plain-password");
+    }
+
+    #[test]
+    fn disabled_features_parsed() {
+        assert_eq!(parse_features("").unwrap(), vec![]);
+        assert_eq!(
+            parse_features(" Sends, emergency-access,,org_creation ").unwrap(),
+            vec![Feature::Sends, Feature::EmergencyAccess, Feature::OrgCreation]
+        );
+        assert!(parse_features("sends,organizations").is_err());
+    }
+
+    #[test]
+    fn jwt_key_rotation_schedule_checked() {
+        let monthly: Schedule = "0 30 3 1 * *".parse().unwrap();
+        assert!(check_jwt_key_rotation(&monthly, 120, 120).is_ok());
+        // The invitations sent before a rotation would expire early
+        assert!(check_jwt_key_rotation(&monthly, 120, 240).is_err());
+
+        // A daily rotation drops the previous key before its 5 days of overlap ended
+        let daily: Schedule = "0 30 3 * * *".parse().unwrap();
+        assert!(check_jwt_key_rotation(&daily, 120, 120).is_err());
+        assert!(check_jwt_key_rotation(&daily, 24, 24).is_ok());
+    }
+}
@@ -4,7 +4,7 @@
 use std::num::NonZeroU32;
 
 use data_encoding::{Encoding, HEXLOWER};
-use ring::{digest, hmac, pbkdf2};
+use ring::{aead, digest, hkdf, hmac, pbkdf2};
 
 static DIGEST_ALG: pbkdf2::Algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
 const OUTPUT_LEN: usize = digest::SHA256_OUTPUT_LEN;
@@ -23,6 +23,31 @@ pub fn verify_password_hash(secret: &[u8], salt: &[u8], previous: &[u8], iterati
     pbkdf2::verify(DIGEST_ALG, iterations, salt, secret, previous).is_ok()
 }
 
+//
+// Argon2id
+//
+/// Presets of the `hash` command, as `(m_cost, t_cost, p_cost)`, admin tokens set in the admin panel use Bitwarden's
+pub const ARGON2_BITWARDEN_PRESET: (u32, u32, u32) = (65540, 3, 4);
+pub const ARGON2_OWASP_PRESET: (u32, u32, u32) = (19456, 2, 1);
+
+/// Creates an Argon2id PHC string of `secret` with a random salt, like the ones used as `ADMIN_TOKEN`
+pub fn hash_argon2id(secret: &[u8], (m_cost, t_cost, p_cost): (u32, u32, u32)) -> Result<String, crate::Error> {
+    use argon2::{
+        password_hash::SaltString, Algorithm::Argon2id, Argon2, ParamsBuilder, PasswordHasher, Version::V0x13,
+    };
+
+    let Ok(params) = ParamsBuilder::new().m_cost(m_cost).t_cost(t_cost).p_cost(p_cost).build() else {
+        err!("Invalid Argon2 parameters")
+    };
+    let Ok(salt) = SaltString::encode_b64(&get_random_bytes::<32>()) else {
+        err!("Unable to generate Argon2 salt")
+    };
+    match Argon2::new(Argon2id, V0x13, params).hash_password(secret, &salt) {
+        Ok(hash) => Ok(hash.to_string()),
+        Err(e) => err!(format!("Unable to generate Argon2id PHC hash: {e}")),
+    }
+}
+
 //
 // HMAC
 //
@@ -33,6 +58,48 @@ pub fn hmac_sign(key: &str, data: &str) -> String {
     HEXLOWER.encode(signature.as_ref())
 }
 
+//
+// Symmetric encryption of secrets at rest
+//
+const SECRET_KEY_SALT: &[u8] = b"vaultwarden-secret-encryption";
+
+fn derive_secret_key(key: &str, context: &[u8]) -> aead::LessSafeKey {
+    let salt = hkdf::Salt::new(hkdf::HKDF_SHA256, SECRET_KEY_SALT);
+    let prk = salt.extract(key.as_bytes());
+    let info = [context];
+    let okm = prk.expand(&info, &aead::AES_256_GCM).expect("Error deriving secret key");
+    aead::LessSafeKey::new(aead::UnboundKey::from(okm))
+}
+
+/// Encrypts `plaintext` with AES-256-GCM, using a key derived from `key` and `context`.
+/// The returned value is the random nonce followed by the ciphertext and tag.
+pub fn encrypt_secret(key: &str, context: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let nonce_bytes = get_random_bytes::<{ aead::NONCE_LEN }>();
+    let nonce = aead::Nonce::assume_unique_for_key(nonce_bytes);
+
+    let mut in_out = plaintext.to_vec();
+    derive_secret_key(key, context)
+        .seal_in_place_append_tag(nonce, aead::Aad::from(context), &mut in_out)
+        .expect("Error encrypting secret");
+
+    let mut out = nonce_bytes.to_vec();
+    out.extend(in_out);
+    out
+}
+
+/// Decrypts a value produced by `encrypt_secret`, returns `None` if the key or data are invalid.
+pub fn decrypt_secret(key: &str, context: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < aead::NONCE_LEN {
+        return None;
+    }
+    let (nonce_bytes, ciphertext) = data.split_at(aead::NONCE_LEN);
+    let nonce = aead::Nonce::try_assume_unique_for_key(nonce_bytes).ok()?;
+
+    let mut in_out = ciphertext.to_vec();
+    let plaintext = derive_secret_key(key, context).open_in_place(nonce, aead::Aad::from(context), &mut in_out).ok()?;
+    Some(plaintext.to_vec())
+}
+
 //
 // Random values
 //
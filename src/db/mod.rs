@@ -1,4 +1,11 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
 
 use diesel::{
     connection::SimpleConnection,
@@ -6,9 +13,10 @@ use diesel::{
 };
 
 use rocket::{
-    http::Status,
+    fairing::{Fairing, Info, Kind},
+    http::{Method, Status},
     request::{FromRequest, Outcome},
-    Request,
+    Request, Response,
 };
 
 use tokio::{
@@ -50,6 +58,21 @@ where
     }
 }
 
+// Creates a pool builder with the given size and timeouts (in seconds), an idle timeout of 0 keeps idle connections open
+fn pool_builder<M: diesel::r2d2::ManageConnection>(
+    max_conns: u32,
+    min_conns: u32,
+    conn_timeout: u64,
+    idle_timeout: u64,
+) -> diesel::r2d2::Builder<M> {
+    let idle_timeout = (idle_timeout > 0).then(|| Duration::from_secs(idle_timeout));
+    Pool::builder()
+        .max_size(max_conns)
+        .min_idle(Some(min_conns))
+        .connection_timeout(Duration::from_secs(conn_timeout))
+        .idle_timeout(idle_timeout)
+}
+
 // This is used to generate the main DbConn and DbPool enums, which contain one variant for each database supported
 macro_rules! generate_connections {
     ( $( $name:ident: $ty:ty ),+ ) => {
@@ -85,7 +108,9 @@ macro_rules! generate_connections {
         pub struct DbPool {
             // This is an 'Option' so that we can drop the pool in a 'spawn_blocking'.
             pool: Option<DbPoolInner>,
-            semaphore: Arc<Semaphore>
+            semaphore: Arc<Semaphore>,
+            // The read replica used by `DbReadConn`, see `DATABASE_READ_URL`
+            replica: Option<Arc<DbPool>>,
         }
 
         #[allow(non_camel_case_types)]
@@ -123,18 +148,32 @@ macro_rules! generate_connections {
         impl DbPool {
             // For the given database URL, guess its type, run migrations, create pool, and return it
             pub fn from_config() -> Result<Self, Error> {
-                let url = CONFIG.database_url();
-                let conn_type = DbConnType::from_url(&url)?;
+                let mut pool = Self::create(&CONFIG.database_url(), CONFIG.database_max_conns(), CONFIG.database_min_conns(), true)?;
+                if let Some(read_url) = CONFIG.database_read_url() {
+                    // The replica gets its schema from the primary, so no migrations are run on it
+                    let replica = Self::create(&read_url, CONFIG.database_max_conns(), CONFIG.database_min_conns(), false)?;
+                    pool.replica = Some(Arc::new(replica));
+                }
+                Ok(pool)
+            }
+
+            // A separate small pool used by the scheduled jobs, so they don't contend with the request traffic
+            pub fn from_config_for_jobs() -> Result<Self, Error> {
+                Self::create(&CONFIG.database_url(), CONFIG.database_jobs_max_conns(), 0, false)
+            }
+
+            fn create(url: &str, max_conns: u32, min_conns: u32, run_migrations: bool) -> Result<Self, Error> {
+                let conn_type = DbConnType::from_url(url)?;
 
                 match conn_type { $(
                     DbConnType::$name => {
                         #[cfg($name)]
                         {
-                            paste::paste!{ [< $name _migrations >]::run_migrations()?; }
-                            let manager = ConnectionManager::new(&url);
-                            let pool = Pool::builder()
-                                .max_size(CONFIG.database_max_conns())
-                                .connection_timeout(Duration::from_secs(CONFIG.database_timeout()))
+                            if run_migrations {
+                                paste::paste!{ [< $name _migrations >]::run_migrations()?; }
+                            }
+                            let manager = ConnectionManager::new(url);
+                            let pool = pool_builder(max_conns, min_conns, CONFIG.database_timeout(), CONFIG.database_idle_timeout())
                                 .connection_customizer(Box::new(DbConnOptions{
                                     init_stmts: conn_type.get_init_stmts()
                                 }))
@@ -142,7 +181,8 @@ macro_rules! generate_connections {
                                 .map_res("Failed to create pool")?;
                             Ok(DbPool {
                                 pool: Some(DbPoolInner::$name(pool)),
-                                semaphore: Arc::new(Semaphore::new(CONFIG.database_max_conns() as usize)),
+                                semaphore: Arc::new(Semaphore::new(max_conns as usize)),
+                                replica: None,
                             })
                         }
                         #[cfg(not($name))]
@@ -173,6 +213,15 @@ macro_rules! generate_connections {
                     },
                 )+ }
             }
+
+            // Get a connection for a read-only request, from the read replica unless the user wrote recently
+            pub async fn get_read(&self, user_uuid: Option<&str>) -> Result<DbConn, Error> {
+                let recent_write = user_uuid.is_some_and(has_recent_write);
+                match (&self.replica, read_target(self.replica.is_some(), recent_write)) {
+                    (Some(replica), DbTarget::Replica) => replica.get().await,
+                    _ => self.get().await,
+                }
+            }
         }
     };
 }
@@ -255,7 +304,8 @@ macro_rules! db_run {
 
         let conn = $conn.conn.clone();
         let mut conn = conn.lock_owned().await;
-        match conn.as_mut().expect("internal invariant broken: self.connection is Some") {
+        let started = std::time::Instant::now();
+        let result = match conn.as_mut().expect("internal invariant broken: self.connection is Some") {
                 $($(
                 #[cfg($db)]
                 $crate::db::DbConnInner::$db($conn) => {
@@ -267,7 +317,10 @@ macro_rules! db_run {
                     tokio::task::block_in_place(move || { $body }) // Run blocking can't be used due to the 'static limitation, use block_in_place instead
                 },
             )+)+
-        }
+        };
+        // Identified by the call site, the SQL and its parameters could contain sensitive data
+        $crate::db::log_slow_query(concat!(module_path!(), ":", line!()), started.elapsed());
+        result
     }};
 
     ( @raw $conn:ident: $( $($db:ident),+ $body:block )+ ) => {{
@@ -276,7 +329,8 @@ macro_rules! db_run {
 
         let conn = $conn.conn.clone();
         let mut conn = conn.lock_owned().await;
-        match conn.as_mut().expect("internal invariant broken: self.connection is Some") {
+        let started = std::time::Instant::now();
+        let result = match conn.as_mut().expect("internal invariant broken: self.connection is Some") {
                 $($(
                 #[cfg($db)]
                 $crate::db::DbConnInner::$db($conn) => {
@@ -288,10 +342,25 @@ macro_rules! db_run {
                     tokio::task::block_in_place(move || { $body }) // Run blocking can't be used due to the 'static limitation, use block_in_place instead
                 },
             )+)+
-        }
+        };
+        // Identified by the call site, the SQL and its parameters could contain sensitive data
+        $crate::db::log_slow_query(concat!(module_path!(), ":", line!()), started.elapsed());
+        result
     }};
 }
 
+/// Logs the queries which take longer than `DB_SLOW_QUERY_THRESHOLD_MS`
+pub fn log_slow_query(query_id: &str, elapsed: Duration) {
+    if let Some(msg) = slow_query_message(query_id, elapsed, CONFIG.db_slow_query_threshold_ms()) {
+        warn!("{msg}");
+    }
+}
+
+fn slow_query_message(query_id: &str, elapsed: Duration, threshold_ms: Option<u64>) -> Option<String> {
+    let threshold = Duration::from_millis(threshold_ms?);
+    (elapsed >= threshold).then(|| format!("Slow database query at {query_id} took {}ms", elapsed.as_millis()))
+}
+
 pub trait FromDb {
     type Output;
     #[allow(clippy::wrong_self_convention)]
@@ -385,6 +454,188 @@ pub async fn backup_database(conn: &mut DbConn) -> Result<(), Error> {
     }
 }
 
+/// Outcome of a database integrity check
+#[derive(Debug, PartialEq)]
+pub enum IntegrityCheck {
+    // Only SQLite databases are checked
+    #[cfg_attr(not(any(mysql, postgresql)), allow(dead_code))]
+    Unsupported,
+    #[cfg_attr(not(sqlite), allow(dead_code))]
+    Passed,
+    #[cfg_attr(not(sqlite), allow(dead_code))]
+    Failed(Vec<String>),
+}
+
+impl IntegrityCheck {
+    // The integrity check pragmas return a single `ok` row, or one row per problem found
+    #[cfg_attr(not(sqlite), allow(dead_code))]
+    fn from_rows(rows: Vec<String>) -> Self {
+        if rows.iter().all(|row| row == "ok") {
+            Self::Passed
+        } else {
+            Self::Failed(rows)
+        }
+    }
+}
+
+#[cfg(sqlite)]
+fn sqlite_integrity_check(conn: &mut diesel::SqliteConnection, full: bool) -> Result<IntegrityCheck, Error> {
+    use diesel::RunQueryDsl;
+
+    #[derive(QueryableByName)]
+    struct CheckRow {
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        result: String,
+    }
+
+    let query = if full {
+        "SELECT integrity_check AS result FROM pragma_integrity_check()"
+    } else {
+        "SELECT quick_check AS result FROM pragma_quick_check()"
+    };
+    let rows = diesel::sql_query(query).load::<CheckRow>(conn)?;
+    Ok(IntegrityCheck::from_rows(rows.into_iter().map(|row| row.result).collect()))
+}
+
+/// Checks the sqlite database for corruption, with `full` also verifying the indexes match their tables.
+/// MySQL/MariaDB and PostgreSQL are not supported.
+pub async fn check_database_integrity(conn: &mut DbConn, full: bool) -> Result<IntegrityCheck, Error> {
+    db_run! {@raw conn:
+        postgresql, mysql {
+            let _ = (conn, full);
+            Ok(IntegrityCheck::Unsupported)
+        }
+        sqlite {
+            sqlite_integrity_check(conn, full)
+        }
+    }
+}
+
+#[cfg(sqlite)]
+fn sqlite_optimize(conn: &mut diesel::SqliteConnection, vacuum: bool) -> Result<(), Error> {
+    // Rebuilding the file first gives `PRAGMA optimize` fresh statistics to work with
+    if vacuum {
+        conn.batch_execute("VACUUM")?;
+    }
+    conn.batch_execute("PRAGMA optimize")?;
+    Ok(())
+}
+
+/// Runs `PRAGMA optimize` on the sqlite database, with `vacuum` first rebuilding the file to reclaim the free pages.
+/// MySQL/MariaDB and PostgreSQL are not supported, which returns false.
+pub async fn optimize_database(conn: &mut DbConn, vacuum: bool) -> Result<bool, Error> {
+    db_run! {@raw conn:
+        postgresql, mysql {
+            let _ = (conn, vacuum);
+            Ok(false)
+        }
+        sqlite {
+            sqlite_optimize(conn, vacuum).map(|_| true)
+        }
+    }
+}
+
+/// Checks the SQLite database for corruption and alerts the configured addresses when problems are found
+pub async fn db_integrity_check_job(pool: DbPool) {
+    debug!("Start db_integrity_check_job");
+    let Ok(mut conn) = pool.get().await else {
+        error!("Failed to get DB connection while checking the database integrity");
+        return;
+    };
+
+    let problems = match check_database_integrity(&mut conn, CONFIG.db_integrity_check_full()).await {
+        Ok(IntegrityCheck::Unsupported) => {
+            debug!("Only SQLite databases are checked for integrity, skipping");
+            return;
+        }
+        Ok(IntegrityCheck::Passed) => {
+            info!("Database integrity check passed");
+            return;
+        }
+        Ok(IntegrityCheck::Failed(problems)) => problems.join("; "),
+        Err(e) => e.to_string(),
+    };
+    error!("Database integrity check failed: {}", problems);
+
+    if !CONFIG.mail_enabled() {
+        return;
+    }
+    let alert_emails = CONFIG.db_integrity_check_alert_emails().unwrap_or_default();
+    for address in alert_emails.split(',').map(str::trim).filter(|address| !address.is_empty()) {
+        if let Err(e) = crate::mail::send_db_integrity_check_failed(address, &problems).await {
+            error!("Error sending database integrity alert to {}: {:#?}", address, e);
+        }
+    }
+}
+
+/// Optimizes the SQLite database, with `vacuum` also rebuilding the file to reclaim the space of removed data
+pub async fn db_optimize_job(pool: DbPool, vacuum: bool) {
+    debug!("Start db_optimize_job");
+    let Ok(mut conn) = pool.get().await else {
+        error!("Failed to get DB connection while optimizing the database");
+        return;
+    };
+
+    match optimize_database(&mut conn, vacuum).await {
+        Ok(false) => debug!("Only SQLite databases are optimized, skipping"),
+        Ok(true) if vacuum => info!("Database vacuumed and optimized"),
+        Ok(true) => info!("Database optimized"),
+        Err(e) => error!("Error optimizing the database: {:#?}", e),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum TransactionStep {
+    Begin,
+    Commit,
+    Rollback,
+}
+
+fn run_transaction_step<C: diesel::Connection>(conn: &mut C, step: TransactionStep) -> diesel::QueryResult<()> {
+    use diesel::connection::TransactionManager;
+    match step {
+        TransactionStep::Begin => C::TransactionManager::begin_transaction(conn),
+        TransactionStep::Commit => C::TransactionManager::commit_transaction(conn),
+        TransactionStep::Rollback => C::TransactionManager::rollback_transaction(conn),
+    }
+}
+
+/// Starts a transaction, all queries on this connection are part of it until it's committed or rolled back.
+/// A connection which is dropped while still in a transaction isn't returned to the pool, so it's rolled back.
+pub async fn begin_transaction(conn: &mut DbConn) -> Result<(), Error> {
+    db_run! {@raw conn: {
+        run_transaction_step(conn, TransactionStep::Begin).map_res("Error starting a transaction")
+    }}
+}
+
+pub async fn commit_transaction(conn: &mut DbConn) -> Result<(), Error> {
+    db_run! {@raw conn: {
+        run_transaction_step(conn, TransactionStep::Commit).map_res("Error committing a transaction")
+    }}
+}
+
+pub async fn rollback_transaction(conn: &mut DbConn) -> Result<(), Error> {
+    db_run! {@raw conn: {
+        run_transaction_step(conn, TransactionStep::Rollback).map_res("Error rolling back a transaction")
+    }}
+}
+
+/// Commits the transaction when `result` is Ok, rolls it back otherwise
+pub async fn finish_transaction<T>(result: Result<T, Error>, conn: &mut DbConn) -> Result<T, Error> {
+    match result {
+        Ok(value) => {
+            commit_transaction(conn).await?;
+            Ok(value)
+        }
+        Err(e) => {
+            if let Err(rollback_err) = rollback_transaction(conn).await {
+                error!("Error rolling back a transaction: {rollback_err:#?}");
+            }
+            Err(e)
+        }
+    }
+}
+
 /// Get the SQL Server version
 pub async fn get_sql_server_version(conn: &mut DbConn) -> String {
     db_run! {@raw conn:
@@ -421,6 +672,126 @@ impl<'r> FromRequest<'r> for DbConn {
     }
 }
 
+/// The user of the current request, cached by the `Headers` guard to route reads and track writes
+struct RequestUser(Option<String>);
+
+pub fn set_request_user(request: &Request<'_>, user_uuid: &str) {
+    request.local_cache(|| RequestUser(Some(user_uuid.to_string())));
+}
+
+fn get_request_user<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    request.local_cache(|| RequestUser(None)).0.as_deref()
+}
+
+/// A connection for read-only requests, from the read replica when `DATABASE_READ_URL` is configured.
+/// Put it after the `Headers` guard in the route, so a user who wrote recently reads from the primary.
+pub struct DbReadConn(DbConn);
+
+impl Deref for DbReadConn {
+    type Target = DbConn;
+
+    fn deref(&self) -> &DbConn {
+        &self.0
+    }
+}
+
+impl DerefMut for DbReadConn {
+    fn deref_mut(&mut self) -> &mut DbConn {
+        &mut self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DbReadConn {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.rocket().state::<DbPool>() {
+            Some(p) => match p.get_read(get_request_user(request)).await {
+                Ok(dbconn) => Outcome::Success(DbReadConn(dbconn)),
+                _ => Outcome::Error((Status::ServiceUnavailable, ())),
+            },
+            None => Outcome::Error((Status::InternalServerError, ())),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum DbTarget {
+    Primary,
+    Replica,
+}
+
+// The replica might lag behind, so the user who just wrote something reads it back from the primary
+fn read_target(has_replica: bool, recent_write: bool) -> DbTarget {
+    if has_replica && !recent_write {
+        DbTarget::Replica
+    } else {
+        DbTarget::Primary
+    }
+}
+
+// The last write of each user, only tracked while a read replica is configured.
+// Only the writes made by this process are known, other instances don't see them.
+static RECENT_WRITES: Lazy<DashMap<String, Instant>> = Lazy::new(DashMap::new);
+// The last write which affected all users, like bumping the revision of every user
+static RECENT_WRITE_ALL: Lazy<RwLock<Option<Instant>>> = Lazy::new(|| RwLock::new(None));
+
+fn is_recent_write(written_at: Option<Instant>, now: Instant, window: Duration) -> bool {
+    written_at.is_some_and(|written_at| now.saturating_duration_since(written_at) < window)
+}
+
+fn has_recent_write(user_uuid: &str) -> bool {
+    let window = Duration::from_secs(CONFIG.database_read_after_write_seconds());
+    let written_at = RECENT_WRITES.get(user_uuid).map(|entry| *entry.value());
+    let all_written_at = *RECENT_WRITE_ALL.read().unwrap();
+    is_recent_write(written_at.max(all_written_at), Instant::now(), window)
+}
+
+/// Routes the reads of this user to the primary for a while, used for every write which affects the user,
+/// also when it's made on behalf of another user, like the revision bump of the members of a shared collection.
+pub fn record_write(user_uuid: &str) {
+    if CONFIG.database_read_url().is_none() {
+        return;
+    }
+    let now = Instant::now();
+    // Keep the map small, the older writes don't affect the routing anymore
+    if RECENT_WRITES.len() >= 1000 {
+        let window = Duration::from_secs(CONFIG.database_read_after_write_seconds());
+        RECENT_WRITES.retain(|_, written_at| is_recent_write(Some(*written_at), now, window));
+    }
+    RECENT_WRITES.insert(user_uuid.to_string(), now);
+}
+
+/// Like `record_write`, for a write which affects all users
+pub fn record_write_all() {
+    if CONFIG.database_read_url().is_some() {
+        *RECENT_WRITE_ALL.write().unwrap() = Some(Instant::now());
+    }
+}
+
+/// Records the users of requests which can write to the database, see `DbReadConn`
+pub struct ReadAfterWrite();
+
+#[rocket::async_trait]
+impl Fairing for ReadAfterWrite {
+    fn info(&self) -> Info {
+        Info {
+            name: "Read after write",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, _response: &mut Response<'r>) {
+        if matches!(request.method(), Method::Get | Method::Head | Method::Options) {
+            return;
+        }
+        if let Some(user_uuid) = get_request_user(request) {
+            record_write(user_uuid);
+        }
+    }
+}
+
 // Embed the migrations from the migrations folder into the application
 // This way, the program automatically migrates the database to the latest version
 // https://docs.rs/diesel_migrations/*/diesel_migrations/macro.embed_migrations.html
@@ -488,3 +859,224 @@ mod postgresql_migrations {
         Ok(())
     }
 }
+
+#[cfg(test)]
+#[cfg(all(sqlite, not(query_logger)))]
+impl DbConn {
+    /// A connection to a new in-memory SQLite database with all migrations applied, to test the model queries
+    pub fn sqlite_in_memory() -> Self {
+        use diesel::RunQueryDsl;
+        use diesel_migrations::MigrationHarness;
+
+        let manager = ConnectionManager::<diesel::SqliteConnection>::new(":memory:");
+        let mut conn = pool_builder(1, 0, 1, 0).build(manager).unwrap().get().unwrap();
+        // The tests only insert the rows they need, without the users or ciphers they refer to
+        diesel::sql_query("PRAGMA foreign_keys = OFF").execute(&mut *conn).unwrap();
+        conn.run_pending_migrations(sqlite_migrations::MIGRATIONS).unwrap();
+        DbConn {
+            conn: Arc::new(Mutex::new(Some(DbConnInner::sqlite(conn)))),
+            permit: None,
+        }
+    }
+
+    /// Runs SQL on the test database, to add the rows which the models can't create without a loaded config
+    pub async fn batch_execute(&mut self, sql: &str) {
+        let mut conn = self.conn.lock().await;
+        #[allow(unreachable_patterns)]
+        match conn.as_mut().unwrap() {
+            DbConnInner::sqlite(conn) => conn.batch_execute(sql).unwrap(),
+            _ => unreachable!("The test database is SQLite"),
+        }
+    }
+
+    /// Adds an enabled user with the given uuid, `User::new` reads the config
+    pub async fn insert_user(&mut self, uuid: &str) {
+        self.batch_execute(&format!(
+            "INSERT INTO users (uuid, created_at, updated_at, email, name, password_hash, salt, password_iterations, \
+            akey, security_stamp, equivalent_domains, excluded_globals) VALUES ('{uuid}', '2024-01-01 00:00:00', \
+            '2024-01-01 00:00:00', '{uuid}@example.com', '{uuid}', x'01', x'01', 600000, '', 'stamp', '[]', '[]')"
+        ))
+        .await;
+    }
+}
+
+#[cfg(test)]
+#[cfg(all(sqlite, not(query_logger)))]
+impl DbPool {
+    /// A pool with the only connection to a new in-memory SQLite database with all migrations applied,
+    /// to test the routes together with their request guards
+    pub fn sqlite_in_memory() -> Self {
+        use diesel::RunQueryDsl;
+        use diesel_migrations::MigrationHarness;
+
+        let manager = ConnectionManager::<diesel::SqliteConnection>::new(":memory:");
+        // The database is gone with its connection, so the pool has to keep it open
+        let pool = pool_builder(1, 1, 1, 0).max_lifetime(None).build(manager).unwrap();
+        {
+            let mut conn = pool.get().unwrap();
+            diesel::sql_query("PRAGMA foreign_keys = OFF").execute(&mut *conn).unwrap();
+            conn.run_pending_migrations(sqlite_migrations::MIGRATIONS).unwrap();
+        }
+        DbPool {
+            pool: Some(DbPoolInner::sqlite(pool)),
+            semaphore: Arc::new(Semaphore::new(1)),
+            replica: None,
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(sqlite)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_respects_max_conns() {
+        let manager = ConnectionManager::<diesel::SqliteConnection>::new(":memory:");
+        let pool = pool_builder(2, 0, 1, 0).build(manager).unwrap();
+        assert_eq!(pool.max_size(), 2);
+
+        let first = pool.get().unwrap();
+        let _second = pool.get().unwrap();
+        assert!(pool.get_timeout(Duration::from_millis(100)).is_err());
+
+        drop(first);
+        assert!(pool.get_timeout(Duration::from_millis(100)).is_ok());
+    }
+
+    /// A SQLite database file with an indexed table of two rows, removed again by `remove_temp_sqlite_db`
+    fn temp_sqlite_db(name: &str) -> (std::path::PathBuf, diesel::SqliteConnection) {
+        use diesel::Connection;
+
+        let path = std::env::temp_dir().join(format!("vaultwarden-{name}-{}.sqlite3", crate::util::get_uuid()));
+        let mut conn = diesel::SqliteConnection::establish(&path.display().to_string()).unwrap();
+        conn.batch_execute(
+            "CREATE TABLE items (uuid TEXT PRIMARY KEY, name TEXT NOT NULL);
+            CREATE INDEX items_name ON items (name);
+            INSERT INTO items VALUES ('a', 'first'), ('b', 'second');",
+        )
+        .unwrap();
+        (path, conn)
+    }
+
+    fn remove_temp_sqlite_db(path: std::path::PathBuf, conn: diesel::SqliteConnection) {
+        drop(conn);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn clean_sqlite_db_passes_integrity_check() {
+        let (path, mut conn) = temp_sqlite_db("integrity");
+        assert_eq!(sqlite_integrity_check(&mut conn, false).unwrap(), IntegrityCheck::Passed);
+        assert_eq!(sqlite_integrity_check(&mut conn, true).unwrap(), IntegrityCheck::Passed);
+        remove_temp_sqlite_db(path, conn);
+    }
+
+    #[test]
+    fn reads_use_replica_unless_user_wrote_recently() {
+        assert_eq!(read_target(true, false), DbTarget::Replica);
+        assert_eq!(read_target(true, true), DbTarget::Primary);
+        assert_eq!(read_target(false, false), DbTarget::Primary);
+
+        let now = Instant::now();
+        let window = Duration::from_secs(10);
+        assert!(!is_recent_write(None, now, window));
+        assert!(is_recent_write(Some(now), now + Duration::from_secs(9), window));
+        assert!(!is_recent_write(Some(now), now + Duration::from_secs(10), window));
+
+        // A write for all users counts as a write of every user
+        let all_written_at = Some(now + Duration::from_secs(5));
+        assert!(is_recent_write(Some(now).max(all_written_at), now + Duration::from_secs(12), window));
+        assert!(is_recent_write(None.max(all_written_at), now + Duration::from_secs(12), window));
+    }
+
+    #[test]
+    fn recorded_write_routes_reads_to_primary() {
+        // Only the tracking of the writes is enabled, there is no replica pool in the tests
+        CONFIG.set_database_read_url(Some(String::from("sqlite://replica.db")));
+
+        let user_uuid = crate::util::get_uuid();
+        record_write(&user_uuid);
+        assert!(RECENT_WRITES.contains_key(&user_uuid));
+        assert!(has_recent_write(&user_uuid));
+    }
+
+    #[test]
+    fn twofactor_backup_migration_counts_existing_methods() {
+        use diesel::{migration::MigrationSource, sql_types::Integer, Connection, QueryableByName, RunQueryDsl};
+
+        #[derive(QueryableByName)]
+        struct Methods {
+            #[diesel(sql_type = Integer)]
+            methods: i32,
+        }
+
+        let mut conn = diesel::SqliteConnection::establish(":memory:").unwrap();
+        // Like when running the migrations, the test data doesn't need matching users
+        diesel::sql_query("PRAGMA foreign_keys = OFF").execute(&mut conn).unwrap();
+        let mut migrations =
+            MigrationSource::<diesel::sqlite::Sqlite>::migrations(&sqlite_migrations::MIGRATIONS).unwrap();
+        migrations.sort_by_key(|m| m.name().to_string());
+        let (before, after): (Vec<_>, Vec<_>) =
+            migrations.into_iter().partition(|m| m.name().to_string().as_str() < "2024-06-15-160000");
+        for migration in &before {
+            migration.run(&mut conn).unwrap();
+        }
+
+        // Two enabled methods, a disabled one and an implementation type which isn't a method
+        diesel::sql_query(
+            "INSERT INTO twofactor (uuid, user_uuid, atype, enabled, data, last_used) VALUES \
+            ('a', 'both', 0, 1, '', 0), ('b', 'both', 3, 1, '', 0), ('c', 'single', 0, 1, '', 0), \
+            ('d', 'single', 1, 0, '', 0), ('e', 'none', 1000, 1, '', 0)",
+        )
+        .execute(&mut conn)
+        .unwrap();
+        after[0].run(&mut conn).unwrap();
+
+        let mut methods = |user: &str| {
+            diesel::sql_query(format!("SELECT methods FROM twofactor_backup WHERE user_uuid = '{user}'"))
+                .load::<Methods>(&mut conn)
+                .unwrap()
+                .first()
+                .map(|m| m.methods)
+        };
+        assert_eq!(methods("both"), Some(2));
+        // Flagged as missing a backup, the recovery code isn't known to be saved
+        assert_eq!(methods("single"), Some(1));
+        assert_eq!(methods("none"), None);
+    }
+
+    #[test]
+    fn slow_query_logged() {
+        let msg = slow_query_message("vaultwarden::db::models::cipher:42", Duration::from_millis(750), Some(500));
+        assert_eq!(msg.as_deref(), Some("Slow database query at vaultwarden::db::models::cipher:42 took 750ms"));
+    }
+
+    #[test]
+    fn fast_query_not_logged() {
+        assert_eq!(
+            slow_query_message("vaultwarden::db::models::cipher:42", Duration::from_millis(20), Some(500)),
+            None
+        );
+        // Disabled without a threshold
+        assert_eq!(slow_query_message("vaultwarden::db::models::cipher:42", Duration::from_secs(60), None), None);
+    }
+
+    #[test]
+    fn sqlite_db_optimized_and_vacuumed() {
+        let (path, mut conn) = temp_sqlite_db("optimize");
+        conn.batch_execute("DELETE FROM items WHERE uuid = 'a'").unwrap();
+
+        sqlite_optimize(&mut conn, false).unwrap();
+        sqlite_optimize(&mut conn, true).unwrap();
+        assert_eq!(sqlite_integrity_check(&mut conn, true).unwrap(), IntegrityCheck::Passed);
+        remove_temp_sqlite_db(path, conn);
+    }
+
+    #[test]
+    fn integrity_check_reports_problems() {
+        let problems = vec![String::from("row 2 missing from index items_name")];
+        assert_eq!(IntegrityCheck::from_rows(problems.clone()), IntegrityCheck::Failed(problems));
+        assert_eq!(IntegrityCheck::from_rows(vec![String::from("ok")]), IntegrityCheck::Passed);
+    }
+}
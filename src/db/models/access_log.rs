@@ -0,0 +1,175 @@
+use chrono::{NaiveDateTime, Utc};
+use rocket::Request;
+use serde_json::Value;
+
+use crate::{api::EmptyResult, db::DbConn, error::MapResult};
+
+db_object! {
+    // An authenticated API request of a user, see `ACCESS_LOG_ENABLED`.
+    // Only the route template is stored, never the request path values, query or body.
+    #[derive(Identifiable, Queryable, Insertable)]
+    #[diesel(table_name = access_log)]
+    #[diesel(primary_key(uuid))]
+    pub struct AccessLog {
+        pub uuid: String,
+        pub user_uuid: String,
+        pub method: String,
+        pub endpoint_class: String,
+        pub ip_address: String,
+        pub device_type: i32,
+        pub client_name: Option<String>,
+        pub created_at: NaiveDateTime,
+    }
+}
+
+const CLIENT_NAME_MAX_LENGTH: usize = 64;
+
+/// The route template of a request without the domain path, like `/api/ciphers/<uuid>`
+fn endpoint_class(route_path: &str, base_path: &str) -> String {
+    let path = route_path.strip_prefix(base_path).filter(|p| p.starts_with('/')).unwrap_or(route_path);
+    path.chars().take(255).collect()
+}
+
+/// Local methods
+impl AccessLog {
+    /// The access log entry of an authenticated request, when access logging is enabled.
+    /// `ip` is stored as is, so it has to be formatted with `util::stored_ip` already.
+    pub fn from_request(
+        enabled: bool,
+        request: &Request<'_>,
+        base_path: &str,
+        user_uuid: &str,
+        device_type: i32,
+        ip: &str,
+    ) -> Option<Self> {
+        if !enabled {
+            return None;
+        }
+
+        let endpoint_class = match request.route() {
+            Some(route) => endpoint_class(route.uri.path(), base_path),
+            None => String::from("unknown"),
+        };
+        let client_name = request
+            .headers()
+            .get_one("Bitwarden-Client-Name")
+            .map(|name| name.chars().take(CLIENT_NAME_MAX_LENGTH).collect());
+
+        Some(Self {
+            uuid: crate::util::get_uuid(),
+            user_uuid: user_uuid.to_string(),
+            method: request.method().as_str().to_string(),
+            endpoint_class,
+            ip_address: ip.to_string(),
+            device_type,
+            client_name,
+            created_at: Utc::now().naive_utc(),
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Method": self.method,
+            "Endpoint": self.endpoint_class,
+            "IpAddress": self.ip_address,
+            "DeviceType": self.device_type,
+            "ClientName": self.client_name,
+            "Date": format_date(&self.created_at),
+        })
+    }
+}
+
+/// Database methods
+impl AccessLog {
+    pub const PAGE_SIZE: i64 = 100;
+
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::insert_into(access_log::table)
+                .values(AccessLogDb::to_db(self))
+                .execute(conn)
+                .map_res("Error saving access log entry")
+        }}
+    }
+
+    /// The most recent entries of a user
+    pub async fn find_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            access_log::table
+                .filter(access_log::user_uuid.eq(user_uuid))
+                .order_by(access_log::created_at.desc())
+                .limit(Self::PAGE_SIZE)
+                .load::<AccessLogDb>(conn)
+                .expect("Error loading access log")
+                .from_db()
+        }}
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(access_log::table.filter(access_log::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting access log")
+        }}
+    }
+
+    pub async fn delete_older_than(cutoff: &NaiveDateTime, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(access_log::table.filter(access_log::created_at.lt(cutoff)))
+                .execute(conn)
+                .map_res("Error cleaning the access log")
+        }}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{
+        http::Header,
+        local::blocking::Client,
+        request::{FromRequest, Outcome},
+    };
+
+    struct LoggedAccess(Option<AccessLog>);
+
+    #[rocket::async_trait]
+    impl<'r> FromRequest<'r> for LoggedAccess {
+        type Error = ();
+
+        async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+            let enabled = request.headers().get_one("X-Access-Log").is_some();
+            Outcome::Success(LoggedAccess(AccessLog::from_request(enabled, request, "", "user-uuid", 9, "10.0.0.1")))
+        }
+    }
+
+    #[get("/ciphers/<_uuid>?<_search>")]
+    fn get_cipher(_uuid: &str, _search: Option<&str>, access: LoggedAccess) -> String {
+        access.0.map(|log| format!("{} {} {:?}", log.method, log.endpoint_class, log.client_name)).unwrap_or_default()
+    }
+
+    #[test]
+    fn authenticated_request_logged_when_enabled() {
+        let client = Client::untracked(rocket::build().mount("/api", routes![get_cipher])).unwrap();
+
+        let response = client
+            .get("/api/ciphers/8a3fd6a4-secret?search=bank")
+            .header(Header::new("X-Access-Log", "1"))
+            .header(Header::new("Bitwarden-Client-Name", "web"))
+            .dispatch();
+        // Path values and the query aren't recorded
+        assert_eq!(response.into_string().unwrap(), r#"GET /api/ciphers/<_uuid> Some("web")"#);
+
+        let response = client.get("/api/ciphers/8a3fd6a4").dispatch();
+        assert_eq!(response.into_string().unwrap(), "");
+    }
+
+    #[test]
+    fn access_log_endpoint_without_domain_path() {
+        assert_eq!(endpoint_class("/vault/api/sync", "/vault"), "/api/sync");
+        assert_eq!(endpoint_class("/api/sync", ""), "/api/sync");
+        assert_eq!(endpoint_class("/vaultapi/sync", "/vault"), "/vaultapi/sync");
+    }
+}
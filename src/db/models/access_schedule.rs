@@ -0,0 +1,158 @@
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::api::EmptyResult;
+
+/// Restricts the times at which a member can access an organization, stored as JSON with the membership.
+/// Example: `{"Timezone": "Europe/Berlin", "Windows": [{"Days": [1, 2, 3, 4, 5], "Start": "08:00", "End": "18:00"}]}`
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct AccessSchedule {
+    pub Timezone: String,
+    pub Windows: Vec<AccessWindow>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct AccessWindow {
+    // ISO weekdays, 1 is Monday and 7 is Sunday
+    pub Days: Vec<u32>,
+    // Local times in `HH:MM` format, a window which ends before it starts continues on the next day
+    pub Start: String,
+    pub End: String,
+}
+
+const TIME_FORMAT: &str = "%H:%M";
+
+impl AccessSchedule {
+    pub fn validate(&self) -> EmptyResult {
+        if self.Timezone.parse::<Tz>().is_err() {
+            err!(format!("Unknown timezone: {}", self.Timezone))
+        }
+        if self.Windows.is_empty() {
+            err!("An access schedule needs at least one window")
+        }
+        for window in &self.Windows {
+            if window.Days.is_empty() || window.Days.iter().any(|d| !(1..=7).contains(d)) {
+                err!("Access window days must be between 1 (Monday) and 7 (Sunday)")
+            }
+            match (window.start(), window.end()) {
+                (Some(start), Some(end)) if start != end => {}
+                (Some(_), Some(_)) => err!("Access window start and end can't be the same"),
+                _ => err!("Access window times must be in HH:MM format"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the schedule allows access at the given time, evaluated in the timezone of the schedule.
+    pub fn allows(&self, now: DateTime<Utc>) -> bool {
+        let Ok(tz) = self.Timezone.parse::<Tz>() else {
+            return false;
+        };
+        let local = tz.from_utc_datetime(&now.naive_utc());
+        let today = local.weekday().number_from_monday();
+        let yesterday = local.weekday().pred().number_from_monday();
+        let time = local.time();
+
+        self.Windows.iter().any(|window| {
+            let (Some(start), Some(end)) = (window.start(), window.end()) else {
+                return false;
+            };
+            if start < end {
+                window.Days.contains(&today) && start <= time && time < end
+            } else {
+                // Overnight window, it is either the evening of a listed day or the morning after it
+                (window.Days.contains(&today) && time >= start) || (window.Days.contains(&yesterday) && time < end)
+            }
+        })
+    }
+}
+
+impl AccessWindow {
+    fn start(&self) -> Option<NaiveTime> {
+        NaiveTime::parse_from_str(&self.Start, TIME_FORMAT).ok()
+    }
+
+    fn end(&self) -> Option<NaiveTime> {
+        NaiveTime::parse_from_str(&self.End, TIME_FORMAT).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn business_hours(timezone: &str) -> AccessSchedule {
+        AccessSchedule {
+            Timezone: String::from(timezone),
+            Windows: vec![AccessWindow {
+                Days: vec![1, 2, 3, 4, 5],
+                Start: String::from("09:00"),
+                End: String::from("17:00"),
+            }],
+        }
+    }
+
+    fn utc(datetime: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(datetime).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn access_in_window_allowed() {
+        let schedule = business_hours("UTC");
+        assert!(schedule.validate().is_ok());
+        // Wednesday
+        assert!(schedule.allows(utc("2024-03-13T09:00:00Z")));
+        assert!(schedule.allows(utc("2024-03-13T16:59:59Z")));
+    }
+
+    #[test]
+    fn access_out_of_window_denied() {
+        let schedule = business_hours("UTC");
+        assert!(!schedule.allows(utc("2024-03-13T08:59:59Z")));
+        assert!(!schedule.allows(utc("2024-03-13T17:00:00Z")));
+        // Saturday
+        assert!(!schedule.allows(utc("2024-03-16T12:00:00Z")));
+    }
+
+    #[test]
+    fn access_window_timezone() {
+        let schedule = business_hours("America/New_York");
+        // Monday 01:00 UTC is still Sunday evening in New York
+        assert!(!schedule.allows(utc("2024-03-11T01:00:00Z")));
+        // 13:30 UTC is 08:30 EST on Friday, but 09:30 EDT on Monday after the DST change
+        assert!(!schedule.allows(utc("2024-03-08T13:30:00Z")));
+        assert!(schedule.allows(utc("2024-03-11T13:30:00Z")));
+    }
+
+    #[test]
+    fn access_window_overnight() {
+        let schedule = AccessSchedule {
+            Timezone: String::from("Europe/Amsterdam"),
+            Windows: vec![AccessWindow {
+                Days: vec![5],
+                Start: String::from("22:00"),
+                End: String::from("06:00"),
+            }],
+        };
+        assert!(schedule.validate().is_ok());
+        // Friday 23:00 and Saturday 05:00 local time
+        assert!(schedule.allows(utc("2024-03-15T22:00:00Z")));
+        assert!(schedule.allows(utc("2024-03-16T04:00:00Z")));
+        // Saturday 23:00 local time
+        assert!(!schedule.allows(utc("2024-03-16T22:00:00Z")));
+    }
+
+    #[test]
+    fn access_schedule_invalid() {
+        assert!(business_hours("Mars/Olympus_Mons").validate().is_err());
+        let mut schedule = business_hours("UTC");
+        schedule.Windows[0].Days.push(8);
+        assert!(schedule.validate().is_err());
+        let mut schedule = business_hours("UTC");
+        schedule.Windows[0].End = String::from("5pm");
+        assert!(schedule.validate().is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use chrono::{NaiveDateTime, TimeDelta};
+
+use crate::{api::EmptyResult, db::DbConn, error::MapResult};
+
+db_object! {
+    // Tracks the automatic disabling of inactive accounts, see `INACTIVE_ACCOUNT_DISABLE_DAYS`.
+    // Users without a stored row were never warned nor re-enabled.
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = account_inactivity)]
+    #[diesel(treat_none_as_null = true)]
+    #[diesel(primary_key(user_uuid))]
+    pub struct AccountInactivity {
+        pub user_uuid: String,
+        // When the user was last warned about the upcoming disabling
+        pub warned_at: Option<NaiveDateTime>,
+        // When an admin last re-enabled the account, this counts as activity
+        pub reactivated_at: Option<NaiveDateTime>,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum InactivityAction {
+    None,
+    Warn,
+    Disable,
+}
+
+/// What to do with an account last active at `last_activity`.
+/// With warnings enabled an account is only disabled `warning_days` after the user was warned.
+pub fn inactivity_action(
+    last_activity: NaiveDateTime,
+    warned_at: Option<NaiveDateTime>,
+    now: NaiveDateTime,
+    disable_days: i64,
+    warning_days: Option<i64>,
+) -> InactivityAction {
+    let Some(disable_after) = TimeDelta::try_days(disable_days) else {
+        return InactivityAction::None;
+    };
+    let Some(warning_period) = warning_days.and_then(TimeDelta::try_days) else {
+        return if now - last_activity >= disable_after {
+            InactivityAction::Disable
+        } else {
+            InactivityAction::None
+        };
+    };
+
+    // Only a warning sent after the last activity counts
+    match warned_at.filter(|warned_at| *warned_at > last_activity) {
+        Some(warned_at) if now - last_activity >= disable_after && now - warned_at >= warning_period => {
+            InactivityAction::Disable
+        }
+        Some(_) => InactivityAction::None,
+        None if now - last_activity >= disable_after - warning_period => InactivityAction::Warn,
+        None => InactivityAction::None,
+    }
+}
+
+/// Local methods
+impl AccountInactivity {
+    pub fn new(user_uuid: String) -> Self {
+        Self {
+            user_uuid,
+            warned_at: None,
+            reactivated_at: None,
+        }
+    }
+
+    /// The latest of the given activity and the last re-enabling by an admin
+    pub fn last_activity(&self, activity: NaiveDateTime) -> NaiveDateTime {
+        self.reactivated_at.map_or(activity, |reactivated_at| activity.max(reactivated_at))
+    }
+}
+
+/// Database methods
+impl AccountInactivity {
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(account_inactivity::table)
+                    .values(AccountInactivityDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving account inactivity")
+            }
+            postgresql {
+                let value = AccountInactivityDb::to_db(self);
+                diesel::insert_into(account_inactivity::table)
+                    .values(&value)
+                    .on_conflict(account_inactivity::user_uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving account inactivity")
+            }
+        }
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(account_inactivity::table.filter(account_inactivity::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting account inactivity")
+        }}
+    }
+
+    pub async fn find_or_default(user_uuid: &str, conn: &mut DbConn) -> Self {
+        let inactivity: Option<Self> = db_run! { conn: {
+            account_inactivity::table
+                .filter(account_inactivity::user_uuid.eq(user_uuid))
+                .first::<AccountInactivityDb>(conn)
+                .ok()
+                .from_db()
+        }};
+        inactivity.unwrap_or_else(|| Self::new(String::from(user_uuid)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn days(days: i64) -> TimeDelta {
+        TimeDelta::try_days(days).unwrap()
+    }
+
+    #[test]
+    fn inactive_user_warned_then_disabled() {
+        let now = Utc::now().naive_utc();
+        let last_activity = now - days(100);
+
+        assert_eq!(inactivity_action(last_activity, None, now, 90, Some(14)), InactivityAction::Warn);
+        // Disabled only once the warning period has passed
+        assert_eq!(inactivity_action(last_activity, Some(now - days(3)), now, 90, Some(14)), InactivityAction::None);
+        assert_eq!(
+            inactivity_action(last_activity, Some(now - days(14)), now, 90, Some(14)),
+            InactivityAction::Disable
+        );
+        // Without warnings the account is disabled right away
+        assert_eq!(inactivity_action(last_activity, None, now, 90, None), InactivityAction::Disable);
+    }
+
+    #[test]
+    fn active_user_not_disabled() {
+        let now = Utc::now().naive_utc();
+        assert_eq!(inactivity_action(now - days(10), None, now, 90, Some(14)), InactivityAction::None);
+        assert_eq!(inactivity_action(now - days(80), None, now, 90, Some(14)), InactivityAction::Warn);
+        // A warning from before the latest activity doesn't count
+        assert_eq!(inactivity_action(now - days(80), Some(now - days(200)), now, 90, Some(14)), InactivityAction::Warn);
+
+        // Re-enabling by an admin counts as activity
+        let mut inactivity = AccountInactivity::new(String::from("user"));
+        inactivity.reactivated_at = Some(now - days(1));
+        let last_activity = inactivity.last_activity(now - days(100));
+        assert_eq!(inactivity_action(last_activity, Some(now - days(20)), now, 90, Some(14)), InactivityAction::None);
+    }
+}
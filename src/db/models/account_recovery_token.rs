@@ -0,0 +1,186 @@
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+
+use super::User;
+use crate::{api::EmptyResult, crypto, db::DbConn, error::MapResult};
+
+db_object! {
+    // The token of an emailed account recovery link, like the one to delete an account with a forgotten master password.
+    // Only the hash of the token is stored, and a user only has one outstanding token.
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = account_recovery_tokens)]
+    #[diesel(primary_key(user_uuid))]
+    pub struct AccountRecoveryToken {
+        pub user_uuid: String,
+        pub token_hash: String,
+        // The security stamp of the user when the token was issued, it changes with the master password
+        pub security_stamp: String,
+        pub expires_at: NaiveDateTime,
+        pub failed_attempts: i32,
+    }
+}
+
+// A token is refused after this many wrong guesses, until a new one is requested
+const MAX_FAILED_ATTEMPTS: i32 = 5;
+
+fn hash_token(token: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, token.as_bytes());
+    data_encoding::HEXLOWER.encode(digest.as_ref())
+}
+
+/// Local methods
+impl AccountRecoveryToken {
+    /// Creates a new token, the returned plaintext token is only available here
+    pub fn new(user: &User, ttl: TimeDelta) -> (Self, String) {
+        Self::with_security_stamp(user.uuid.clone(), user.security_stamp.clone(), ttl)
+    }
+
+    fn with_security_stamp(user_uuid: String, security_stamp: String, ttl: TimeDelta) -> (Self, String) {
+        let token = crypto::encode_random_bytes::<32>(data_encoding::BASE64URL_NOPAD);
+
+        let recovery_token = Self {
+            user_uuid,
+            token_hash: hash_token(&token),
+            security_stamp,
+            expires_at: Utc::now().naive_utc() + ttl,
+            failed_attempts: 0,
+        };
+        (recovery_token, token)
+    }
+
+    /// Tokens expire, and are invalidated when the master password of the user changed after they were issued
+    pub fn is_valid(&self, token: &str, user: &User, now: &NaiveDateTime) -> bool {
+        self.user_uuid == user.uuid && self.is_valid_for_stamp(token, &user.security_stamp, now)
+    }
+
+    fn is_valid_for_stamp(&self, token: &str, security_stamp: &str, now: &NaiveDateTime) -> bool {
+        self.expires_at > *now
+            && self.failed_attempts < MAX_FAILED_ATTEMPTS
+            && crypto::ct_eq(&self.security_stamp, security_stamp)
+            && crypto::ct_eq(&self.token_hash, hash_token(token))
+    }
+}
+
+/// Database methods
+impl AccountRecoveryToken {
+    /// Replaces any outstanding token of the user
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        Self::delete_all_by_user(&self.user_uuid, conn).await?;
+        db_run! { conn: {
+            diesel::insert_into(account_recovery_tokens::table)
+                .values(AccountRecoveryTokenDb::to_db(self))
+                .execute(conn)
+                .map_res("Error saving account recovery token")
+        }}
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(account_recovery_tokens::table.filter(account_recovery_tokens::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting account recovery token")
+        }}
+    }
+
+    /// Takes the token of the user when `token` is valid, so it can only be used once.
+    /// A wrong guess doesn't remove the token, so it can't be used to invalidate the link of someone else,
+    /// it's counted instead and the token is refused after too many of them.
+    pub async fn take_valid(user: &User, token: &str, now: &NaiveDateTime, conn: &mut DbConn) -> Option<Self> {
+        let user_uuid = &user.uuid;
+        let recovery_token: Self = db_run! { conn: {
+            account_recovery_tokens::table
+                .filter(account_recovery_tokens::user_uuid.eq(user_uuid))
+                .first::<AccountRecoveryTokenDb>(conn)
+                .ok()
+                .from_db()
+        }}?;
+
+        if !recovery_token.is_valid(token, user, now) {
+            let counted = db_run! { conn: {
+                diesel::update(account_recovery_tokens::table.filter(account_recovery_tokens::user_uuid.eq(user_uuid)))
+                    .set(account_recovery_tokens::failed_attempts.eq(account_recovery_tokens::failed_attempts + 1))
+                    .execute(conn)
+                    .map_res("Error counting failed account recovery attempt")
+            }};
+            if let Err(e) = counted {
+                error!("{:#?}", e);
+            }
+            return None;
+        }
+
+        // Only the request which actually removed the token may use it, in case of concurrent requests
+        let token_hash = recovery_token.token_hash.clone();
+        let deleted = db_run! { conn: {
+            diesel::delete(
+                account_recovery_tokens::table
+                    .filter(account_recovery_tokens::user_uuid.eq(user_uuid))
+                    .filter(account_recovery_tokens::token_hash.eq(token_hash)),
+            )
+            .execute(conn)
+            .unwrap_or(0)
+        }};
+        (deleted == 1).then_some(recovery_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recovery_token(ttl: TimeDelta) -> (AccountRecoveryToken, String) {
+        AccountRecoveryToken::with_security_stamp(String::from("user"), String::from("stamp"), ttl)
+    }
+
+    #[test]
+    fn recovery_token_stored_hashed() {
+        let (recovery_token, token) = recovery_token(TimeDelta::try_hours(1).unwrap());
+
+        assert_ne!(recovery_token.token_hash, token);
+        let now = Utc::now().naive_utc();
+        assert!(recovery_token.is_valid_for_stamp(&token, "stamp", &now));
+        assert!(!recovery_token.is_valid_for_stamp("wrong-token", "stamp", &now));
+    }
+
+    #[test]
+    fn recovery_token_expires() {
+        let (recovery_token, token) = recovery_token(TimeDelta::try_minutes(10).unwrap());
+
+        let later = Utc::now().naive_utc() + TimeDelta::try_minutes(11).unwrap();
+        assert!(!recovery_token.is_valid_for_stamp(&token, "stamp", &later));
+    }
+
+    #[test]
+    fn recovery_token_refused_after_failed_attempts() {
+        let (mut recovery_token, token) = recovery_token(TimeDelta::try_hours(1).unwrap());
+
+        recovery_token.failed_attempts = MAX_FAILED_ATTEMPTS - 1;
+        assert!(recovery_token.is_valid_for_stamp(&token, "stamp", &Utc::now().naive_utc()));
+        recovery_token.failed_attempts = MAX_FAILED_ATTEMPTS;
+        assert!(!recovery_token.is_valid_for_stamp(&token, "stamp", &Utc::now().naive_utc()));
+    }
+
+    #[test]
+    fn recovery_token_invalidated_by_password_change() {
+        let (recovery_token, token) = recovery_token(TimeDelta::try_hours(1).unwrap());
+
+        // Changing the master password resets the security stamp
+        assert!(!recovery_token.is_valid_for_stamp(&token, "new-stamp", &Utc::now().naive_utc()));
+    }
+
+    #[test]
+    #[cfg(sqlite)]
+    fn wrong_guess_keeps_recovery_token() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut conn = DbConn::sqlite_in_memory();
+            conn.insert_user("user").await;
+            let user = User::find_by_uuid("user", &mut conn).await.unwrap();
+            let (recovery_token, token) = AccountRecoveryToken::new(&user, TimeDelta::try_hours(1).unwrap());
+            recovery_token.save(&mut conn).await.unwrap();
+
+            let now = Utc::now().naive_utc();
+            assert!(AccountRecoveryToken::take_valid(&user, "wrong-token", &now, &mut conn).await.is_none());
+            assert!(AccountRecoveryToken::take_valid(&user, &token, &now, &mut conn).await.is_some());
+            // Used up
+            assert!(AccountRecoveryToken::take_valid(&user, &token, &now, &mut conn).await.is_none());
+        });
+    }
+}
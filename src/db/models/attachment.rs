@@ -144,6 +144,16 @@ impl Attachment {
         }}
     }
 
+    pub async fn count_by_cipher(cipher_uuid: &str, conn: &mut DbConn) -> i64 {
+        db_run! { conn: {
+            attachments::table
+                .filter(attachments::cipher_uuid.eq(cipher_uuid))
+                .count()
+                .first(conn)
+                .unwrap_or(0)
+        }}
+    }
+
     pub async fn size_by_user(user_uuid: &str, conn: &mut DbConn) -> i64 {
         db_run! { conn: {
             let result: Option<BigDecimal> = attachments::table
@@ -200,6 +210,29 @@ impl Attachment {
         }}
     }
 
+    /// The cipher and id of every attachment, which is also the path of its file in the attachments folder
+    pub async fn find_all_paths(conn: &mut DbConn) -> Result<Vec<(String, String)>, crate::Error> {
+        db_run! { conn: {
+            attachments::table
+                .select((attachments::cipher_uuid, attachments::id))
+                .load::<(String, String)>(conn)
+                .map_res("Error loading attachments")
+        }}
+    }
+
+    /// The attachments of the personal ciphers of a user
+    pub async fn find_all_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            attachments::table
+                .inner_join(ciphers::table.on(ciphers::uuid.eq(attachments::cipher_uuid)))
+                .filter(ciphers::user_uuid.eq(user_uuid))
+                .select(attachments::all_columns)
+                .load::<AttachmentDb>(conn)
+                .expect("Error loading attachments")
+                .from_db()
+        }}
+    }
+
     // This will return all attachments linked to the user or org
     // There is no filtering done here if the user actually has access!
     // It is used to speed up the sync process, and the matching is done in a different part.
@@ -3,12 +3,14 @@ use chrono::{NaiveDateTime, TimeDelta, Utc};
 use serde_json::Value;
 
 use super::{
-    Attachment, CollectionCipher, Favorite, FolderCipher, Group, User, UserOrgStatus, UserOrgType, UserOrganization,
+    Attachment, CipherShare, CipherTransfer, CollectionCipher, Favorite, FolderCipher, Group, Organization, User,
+    UserOrgStatus, UserOrgType, UserOrganization,
 };
 
 use crate::api::core::{CipherData, CipherSyncData, CipherSyncType};
 
 use std::borrow::Cow;
+use std::collections::HashSet;
 
 db_object! {
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
@@ -47,7 +49,7 @@ db_object! {
 #[allow(dead_code)]
 pub enum RepromptType {
     None = 0,
-    Password = 1, // not currently used in server
+    Password = 1, // Only set by the server for the enforced reprompt policy
 }
 
 /// Local methods
@@ -78,6 +80,13 @@ impl Cipher {
         }
     }
 
+    pub fn without_restricted_orgs(ciphers: Vec<Self>, restricted_orgs: &HashSet<String>) -> Vec<Self> {
+        ciphers
+            .into_iter()
+            .filter(|c| c.organization_uuid.as_ref().map_or(true, |org_uuid| !restricted_orgs.contains(org_uuid)))
+            .collect()
+    }
+
     pub fn validate_notes(cipher_data: &[CipherData]) -> EmptyResult {
         let mut validation_errors = serde_json::Map::new();
         for (index, cipher) in cipher_data.iter().enumerate() {
@@ -94,11 +103,10 @@ impl Cipher {
             }
         }
         if !validation_errors.is_empty() {
-            let err_json = json!({
-                "message": "The model state is invalid.",
-                "validationErrors" : validation_errors,
-                "object": "error"
-            });
+            let err_json = crate::error::api_validation_error_json(
+                "The model state is invalid.",
+                Value::Object(validation_errors),
+            );
             err_json!(err_json, "Import validation errors")
         } else {
             Ok(())
@@ -153,6 +161,18 @@ impl Cipher {
             (false, false)
         };
 
+        // A personal cipher shared with this user is returned with the cipher key encrypted for them
+        let key = if self.organization_uuid.is_none() && !self.is_owned_by_user(user_uuid) {
+            if let Some(cipher_sync_data) = cipher_sync_data {
+                self.key_for_user(user_uuid, cipher_sync_data.cipher_shares.get(&self.uuid))
+            } else {
+                let share = CipherShare::find_by_cipher_and_user(&self.uuid, user_uuid, conn).await;
+                self.key_for_user(user_uuid, share.as_ref())
+            }
+        } else {
+            self.key.clone()
+        };
+
         // Get the type_data or a default to an empty json object '{}'.
         // If not passing an empty object, mobile clients will crash.
         let mut type_data_json: Value =
@@ -206,7 +226,7 @@ impl Cipher {
             "DeletedDate": self.deleted_at.map_or(Value::Null, |d| Value::String(format_date(&d))),
             "Reprompt": self.reprompt.unwrap_or(RepromptType::None as i32),
             "OrganizationId": self.organization_uuid,
-            "Key": self.key,
+            "Key": key,
             "Attachments": attachments_json,
             // We have UseTotp set to true by default within the Organization model.
             // This variable together with UsersGetPremium is used to show or hide the TOTP counter.
@@ -268,7 +288,13 @@ impl Cipher {
         match self.user_uuid {
             Some(ref user_uuid) => {
                 User::update_uuid_revision(user_uuid, conn).await;
-                user_uuids.push(user_uuid.clone())
+                user_uuids.push(user_uuid.clone());
+
+                // Users this personal cipher is shared with
+                for share in CipherShare::find_by_cipher(&self.uuid, conn).await {
+                    User::update_uuid_revision(&share.user_uuid, conn).await;
+                    user_uuids.push(share.user_uuid)
+                }
             }
             None => {
                 // Belongs to Organization, need to update affected users
@@ -334,6 +360,8 @@ impl Cipher {
         CollectionCipher::delete_all_by_cipher(&self.uuid, conn).await?;
         Attachment::delete_all_by_cipher(&self.uuid, conn).await?;
         Favorite::delete_all_by_cipher(&self.uuid, conn).await?;
+        CipherShare::delete_all_by_cipher(&self.uuid, conn).await?;
+        CipherTransfer::delete_all_by_cipher(&self.uuid, conn).await?;
 
         db_run! { conn: {
             diesel::delete(ciphers::table.filter(ciphers::uuid.eq(&self.uuid)))
@@ -357,17 +385,34 @@ impl Cipher {
         Ok(())
     }
 
-    /// Purge all ciphers that are old enough to be auto-deleted.
+    /// Purge all ciphers that are old enough to be auto-deleted, except those of organizations under legal hold.
     pub async fn purge_trash(conn: &mut DbConn) {
         if let Some(auto_delete_days) = CONFIG.trash_auto_delete_days() {
             let now = Utc::now().naive_utc();
             let dt = now - TimeDelta::try_days(auto_delete_days).unwrap();
+            let held_orgs = Organization::find_legal_hold_uuids(conn).await;
             for cipher in Self::find_deleted_before(&dt, conn).await {
-                cipher.delete(conn).await.ok();
+                if cipher.is_permanently_deletable(&held_orgs) {
+                    cipher.delete(conn).await.ok();
+                }
             }
         }
     }
 
+    /// Ciphers of an organization under legal hold can only be soft-deleted
+    pub fn is_permanently_deletable(&self, held_orgs: &[String]) -> bool {
+        !self.organization_uuid.as_ref().is_some_and(|org_uuid| held_orgs.contains(org_uuid))
+    }
+
+    pub async fn check_permanently_deletable(&self, conn: &mut DbConn) -> EmptyResult {
+        if self.organization_uuid.is_some()
+            && !self.is_permanently_deletable(&Organization::find_legal_hold_uuids(conn).await)
+        {
+            err!("This item belongs to an organization under legal hold and can't be permanently deleted")
+        }
+        Ok(())
+    }
+
     pub async fn move_to_folder(&self, folder_uuid: Option<String>, user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
         User::update_uuid_revision(user_uuid, conn).await;
 
@@ -400,6 +445,26 @@ impl Cipher {
         self.user_uuid.is_some() && self.user_uuid.as_ref().unwrap() == user_uuid
     }
 
+    /// Returns the cipher key as it should be returned to the user.
+    /// For a personal cipher shared with the user this is the key encrypted for them by the owner,
+    /// and None if the cipher isn't shared with the user.
+    pub fn key_for_user(&self, user_uuid: &str, share: Option<&CipherShare>) -> Option<String> {
+        if self.organization_uuid.is_some() || self.is_owned_by_user(user_uuid) {
+            return self.key.clone();
+        }
+        share.filter(|s| s.user_uuid == user_uuid && s.access_restrictions(self).is_some()).map(|s| s.akey.clone())
+    }
+
+    /// Returns whether this is a personal cipher of another user, which is shared with this user.
+    pub async fn is_shared_with_user(&self, user_uuid: &str, conn: &mut DbConn) -> bool {
+        if self.organization_uuid.is_some() || self.user_uuid.is_none() || self.is_owned_by_user(user_uuid) {
+            return false;
+        }
+        CipherShare::find_by_cipher_and_user(&self.uuid, user_uuid, conn)
+            .await
+            .is_some_and(|share| share.access_restrictions(self).is_some())
+    }
+
     /// Returns whether this cipher is owned by an org in which the user has full access.
     async fn is_in_full_access_org(
         &self,
@@ -450,6 +515,17 @@ impl Cipher {
         cipher_sync_data: Option<&CipherSyncData>,
         conn: &mut DbConn,
     ) -> Option<(bool, bool)> {
+        // Organizations can be out of reach for a while, like outside of the access schedule of the member
+        if let Some(ref org_uuid) = self.organization_uuid {
+            let restricted = match cipher_sync_data {
+                Some(cipher_sync_data) => cipher_sync_data.restricted_orgs.contains(org_uuid),
+                None => UserOrganization::find_restricted_org_uuids(user_uuid, conn).await.contains(org_uuid),
+            };
+            if restricted {
+                return None;
+            }
+        }
+
         // Check whether this cipher is directly owned by the user, or is in
         // a collection that the user has full access to. If so, there are no
         // access restrictions.
@@ -460,6 +536,17 @@ impl Cipher {
             return Some((false, false));
         }
 
+        // Personal ciphers can only be accessed by others if they are shared with them
+        if self.organization_uuid.is_none() {
+            return if let Some(cipher_sync_data) = cipher_sync_data {
+                cipher_sync_data.cipher_shares.get(&self.uuid).and_then(|s| s.access_restrictions(self))
+            } else {
+                CipherShare::find_by_cipher_and_user(&self.uuid, user_uuid, conn)
+                    .await
+                    .and_then(|s| s.access_restrictions(self))
+            };
+        }
+
         let rows = if let Some(cipher_sync_data) = cipher_sync_data {
             let mut rows: Vec<(bool, bool)> = Vec::new();
             if let Some(collections) = cipher_sync_data.cipher_collections.get(&self.uuid) {
@@ -635,11 +722,16 @@ impl Cipher {
                                 collections_groups::groups_uuid.eq(groups::uuid)
                                 )
                             ))
+                    .left_join(cipher_shares::table.on(
+                            ciphers::uuid.eq(cipher_shares::cipher_uuid)
+                            .and(cipher_shares::user_uuid.eq(user_uuid))
+                            ))
                     .filter(ciphers::user_uuid.eq(user_uuid)) // Cipher owner
                     .or_filter(users_organizations::access_all.eq(true)) // access_all in org
                     .or_filter(users_collections::user_uuid.eq(user_uuid)) // Access to collection
                     .or_filter(groups::access_all.eq(true)) // Access via groups
                     .or_filter(collections_groups::collections_uuid.is_not_null()) // Access via groups
+                    .or_filter(cipher_shares::user_uuid.eq(user_uuid).and(ciphers::organization_uuid.is_null())) // Shared personal cipher
                     .into_boxed();
 
                 if !visible_only {
@@ -669,9 +761,14 @@ impl Cipher {
                             // Ensure that users_collections::user_uuid is NULL for unconfirmed users.
                             .and(users_organizations::user_uuid.eq(users_collections::user_uuid))
                             ))
+                    .left_join(cipher_shares::table.on(
+                            ciphers::uuid.eq(cipher_shares::cipher_uuid)
+                            .and(cipher_shares::user_uuid.eq(user_uuid))
+                            ))
                     .filter(ciphers::user_uuid.eq(user_uuid)) // Cipher owner
                     .or_filter(users_organizations::access_all.eq(true)) // access_all in org
                     .or_filter(users_collections::user_uuid.eq(user_uuid)) // Access to collection
+                    .or_filter(cipher_shares::user_uuid.eq(user_uuid).and(ciphers::organization_uuid.is_null())) // Shared personal cipher
                     .into_boxed();
 
                     if !visible_only {
@@ -688,9 +785,10 @@ impl Cipher {
         }
     }
 
-    // Find all ciphers visible to the specified user.
+    // Find all ciphers visible to the specified user, leaving out the organizations the user can't access right now.
     pub async fn find_by_user_visible(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
-        Self::find_by_user(user_uuid, true, conn).await
+        let restricted_orgs = UserOrganization::find_restricted_org_uuids(user_uuid, conn).await;
+        Self::without_restricted_orgs(Self::find_by_user(user_uuid, true, conn).await, &restricted_orgs)
     }
 
     // Find all ciphers directly owned by the specified user.
@@ -820,3 +918,124 @@ impl Cipher {
         }}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    fn trashed_cipher(org_uuid: Option<&str>) -> Cipher {
+        let mut cipher = Cipher::new(1, String::from("2.name"));
+        cipher.organization_uuid = org_uuid.map(String::from);
+        cipher.deleted_at = Some(Utc::now().naive_utc() - TimeDelta::try_days(60).unwrap());
+        cipher
+    }
+
+    #[test]
+    fn ciphers_hidden_outside_access_schedule() {
+        let business_hours =
+            r#"{"Timezone": "UTC", "Windows": [{"Days": [1, 2, 3, 4, 5], "Start": "09:00", "End": "17:00"}]}"#;
+        let mut scheduled = UserOrganization::new(String::from("user"), String::from("scheduled-org"));
+        scheduled.access_schedule = Some(String::from(business_hours));
+        let unrestricted = UserOrganization::new(String::from("user"), String::from("other-org"));
+        let memberships = [scheduled, unrestricted];
+
+        let org_cipher = |org_uuid: Option<&str>| {
+            let mut cipher = Cipher::new(1, String::from("2.name"));
+            cipher.organization_uuid = org_uuid.map(String::from);
+            cipher
+        };
+        let visible = || vec![org_cipher(Some("scheduled-org")), org_cipher(Some("other-org")), org_cipher(None)];
+
+        // Saturday, outside of the schedule, only the personal cipher and the other organization are left
+        let saturday = DateTime::parse_from_rfc3339("2024-03-16T12:00:00Z").unwrap().with_timezone(&Utc);
+        let restricted = UserOrganization::restricted_org_uuids(&memberships, saturday, true, &HashSet::new());
+        let synced = Cipher::without_restricted_orgs(visible(), &restricted);
+        assert_eq!(synced.len(), 2);
+        assert!(synced.iter().all(|c| c.organization_uuid.as_deref() != Some("scheduled-org")));
+
+        // Wednesday, within the schedule
+        let wednesday = DateTime::parse_from_rfc3339("2024-03-13T12:00:00Z").unwrap().with_timezone(&Utc);
+        let restricted = UserOrganization::restricted_org_uuids(&memberships, wednesday, true, &HashSet::new());
+        assert_eq!(Cipher::without_restricted_orgs(visible(), &restricted).len(), 3);
+    }
+
+    #[test]
+    fn ciphers_hidden_without_verified_email() {
+        let member = UserOrganization::new(String::from("user"), String::from("verifying-org"));
+        let mut admin = UserOrganization::new(String::from("user"), String::from("admin-org"));
+        admin.atype = UserOrgType::Admin as i32;
+        let other = UserOrganization::new(String::from("user"), String::from("other-org"));
+        let memberships = [member, admin, other];
+        let verification_required: HashSet<String> =
+            [String::from("verifying-org"), String::from("admin-org")].into_iter().collect();
+
+        let visible = || {
+            ["verifying-org", "admin-org", "other-org"]
+                .into_iter()
+                .map(|org_uuid| {
+                    let mut cipher = Cipher::new(1, String::from("2.name"));
+                    cipher.organization_uuid = Some(String::from(org_uuid));
+                    cipher
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Admins keep their access, so they can't lock themselves out
+        let now = Utc::now();
+        let restricted = UserOrganization::restricted_org_uuids(&memberships, now, false, &verification_required);
+        let synced = Cipher::without_restricted_orgs(visible(), &restricted);
+        assert_eq!(
+            synced.iter().filter_map(|c| c.organization_uuid.as_deref()).collect::<Vec<_>>(),
+            vec!["admin-org", "other-org"]
+        );
+
+        let restricted = UserOrganization::restricted_org_uuids(&memberships, now, true, &verification_required);
+        assert_eq!(Cipher::without_restricted_orgs(visible(), &restricted).len(), 3);
+    }
+
+    #[test]
+    fn legal_hold_survives_trash_purge() {
+        let held_orgs = vec![String::from("held-org")];
+        let trash = [trashed_cipher(Some("held-org")), trashed_cipher(Some("other-org")), trashed_cipher(None)];
+
+        let purged: Vec<_> = trash.iter().filter(|c| c.is_permanently_deletable(&held_orgs)).collect();
+        assert_eq!(purged.len(), 2);
+        assert!(purged.iter().all(|c| c.organization_uuid.as_deref() != Some("held-org")));
+    }
+
+    #[test]
+    fn legal_hold_blocks_permanent_delete() {
+        let cipher = trashed_cipher(Some("held-org"));
+        assert!(!cipher.is_permanently_deletable(&[String::from("held-org")]));
+        // Once the hold is released the cipher can be deleted again
+        assert!(cipher.is_permanently_deletable(&[]));
+    }
+
+    #[test]
+    #[cfg(sqlite)]
+    fn shared_only_with_a_share() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut conn = DbConn::sqlite_in_memory();
+            let mut cipher = Cipher::new(1, String::from("2.name"));
+            cipher.user_uuid = Some(String::from("owner"));
+
+            // A personal cipher of another user isn't shared without a share
+            assert!(!cipher.is_shared_with_user("recipient", &mut conn).await);
+
+            let sql = format!(
+                "INSERT INTO cipher_shares (cipher_uuid, user_uuid, akey, read_only, creation_date)
+                 VALUES ('{}', 'recipient', '2.key', 0, '2024-01-01 00:00:00')",
+                cipher.uuid
+            );
+            conn.batch_execute(&sql).await;
+            assert!(cipher.is_shared_with_user("recipient", &mut conn).await);
+            assert!(!cipher.is_shared_with_user("other", &mut conn).await);
+            assert!(!cipher.is_shared_with_user("owner", &mut conn).await);
+
+            // Shares are ignored once the cipher is moved to an organization
+            cipher.organization_uuid = Some(String::from("org"));
+            assert!(!cipher.is_shared_with_user("recipient", &mut conn).await);
+        });
+    }
+}
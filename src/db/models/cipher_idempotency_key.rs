@@ -0,0 +1,188 @@
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+
+use crate::{api::EmptyResult, db::DbConn, error::MapResult};
+
+db_object! {
+    // The `Idempotency-Key` of a cipher create request, so a retried request returns the cipher created before.
+    // Only the hash of the key is stored, keys are only used within `CIPHER_IDEMPOTENCY_WINDOW`.
+    #[derive(Identifiable, Queryable, Insertable)]
+    #[diesel(table_name = cipher_idempotency_keys)]
+    #[diesel(primary_key(user_uuid, key_hash))]
+    pub struct CipherIdempotencyKey {
+        pub user_uuid: String,
+        pub key_hash: String,
+        pub cipher_uuid: String,
+        pub created_at: NaiveDateTime,
+    }
+}
+
+pub const IDEMPOTENCY_KEY_MAX_LENGTH: usize = 255;
+
+fn hash_key(key: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+    data_encoding::HEXLOWER.encode(digest.as_ref())
+}
+
+/// Local methods
+impl CipherIdempotencyKey {
+    pub fn new(user_uuid: String, key: &str, cipher_uuid: String) -> Self {
+        Self {
+            user_uuid,
+            key_hash: hash_key(key),
+            cipher_uuid,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    /// Keys created before the returned time aren't used anymore
+    pub fn cutoff(now: &NaiveDateTime, window_secs: u64) -> NaiveDateTime {
+        // The window is validated to be at most one day
+        *now - TimeDelta::try_seconds(window_secs as i64).unwrap()
+    }
+
+    /// The cipher created by an earlier request with the same key, if that request is recent enough
+    pub fn replayed_cipher<'a>(existing: Option<&'a Self>, cutoff: &NaiveDateTime) -> Option<&'a str> {
+        existing.filter(|k| k.created_at > *cutoff).map(|k| k.cipher_uuid.as_str())
+    }
+
+    pub fn check_key(key: &str) -> EmptyResult {
+        if key.is_empty() || key.len() > IDEMPOTENCY_KEY_MAX_LENGTH {
+            err!(format!("The Idempotency-Key must be between 1 and {IDEMPOTENCY_KEY_MAX_LENGTH} characters"))
+        }
+        Ok(())
+    }
+}
+
+/// Database methods
+impl CipherIdempotencyKey {
+    /// Claims the key for a new cipher. Returns false when a request with the same key claimed it before,
+    /// expired keys of the user are removed first so they can be reused.
+    pub async fn claim(&self, cutoff: &NaiveDateTime, conn: &mut DbConn) -> Result<bool, crate::Error> {
+        Self::delete_expired_by_user(&self.user_uuid, cutoff, conn).await?;
+        db_run! { conn: {
+            match diesel::insert_into(cipher_idempotency_keys::table)
+                .values(CipherIdempotencyKeyDb::to_db(self))
+                .execute(conn)
+            {
+                Ok(_) => Ok(true),
+                Err(diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::UniqueViolation, _)) => {
+                    Ok(false)
+                }
+                Err(e) => Err(e).map_res("Error saving idempotency key"),
+            }
+        }}
+    }
+
+    /// Releases a claimed key, when creating the cipher failed and the request may be retried
+    pub async fn delete(self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(
+                cipher_idempotency_keys::table
+                    .filter(cipher_idempotency_keys::user_uuid.eq(self.user_uuid))
+                    .filter(cipher_idempotency_keys::key_hash.eq(self.key_hash)),
+            )
+            .execute(conn)
+            .map_res("Error deleting idempotency key")
+        }}
+    }
+
+    pub async fn delete_expired_by_user(user_uuid: &str, cutoff: &NaiveDateTime, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(
+                cipher_idempotency_keys::table
+                    .filter(cipher_idempotency_keys::user_uuid.eq(user_uuid))
+                    .filter(cipher_idempotency_keys::created_at.le(cutoff)),
+            )
+            .execute(conn)
+            .map_res("Error deleting idempotency keys")
+        }}
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(cipher_idempotency_keys::table.filter(cipher_idempotency_keys::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting idempotency keys")
+        }}
+    }
+
+    pub async fn find_by_user_and_key(user_uuid: &str, key: &str, conn: &mut DbConn) -> Option<Self> {
+        let key_hash = hash_key(key);
+        db_run! { conn: {
+            cipher_idempotency_keys::table
+                .filter(cipher_idempotency_keys::user_uuid.eq(user_uuid))
+                .filter(cipher_idempotency_keys::key_hash.eq(key_hash))
+                .first::<CipherIdempotencyKeyDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_idempotency_key_returns_same_cipher() {
+        let now = Utc::now().naive_utc();
+        let cutoff = CipherIdempotencyKey::cutoff(&now, 300);
+        let first = CipherIdempotencyKey::new(String::from("user"), "retry-key", String::from("cipher-1"));
+
+        // A retry finds the key stored by the first request
+        assert_eq!(
+            first.key_hash,
+            CipherIdempotencyKey::new(String::from("user"), "retry-key", String::new()).key_hash
+        );
+        assert_eq!(CipherIdempotencyKey::replayed_cipher(Some(&first), &cutoff), Some("cipher-1"));
+
+        // After the window the key creates a new cipher again
+        let later = CipherIdempotencyKey::cutoff(&(now + TimeDelta::try_seconds(301).unwrap()), 300);
+        assert_eq!(CipherIdempotencyKey::replayed_cipher(Some(&first), &later), None);
+    }
+
+    #[test]
+    fn new_idempotency_key_creates_new_cipher() {
+        let cutoff = CipherIdempotencyKey::cutoff(&Utc::now().naive_utc(), 300);
+        assert_eq!(CipherIdempotencyKey::replayed_cipher(None, &cutoff), None);
+
+        let first = CipherIdempotencyKey::new(String::from("user"), "key-1", String::from("cipher-1"));
+        let second = CipherIdempotencyKey::new(String::from("user"), "key-2", String::from("cipher-2"));
+        assert_ne!(first.key_hash, second.key_hash);
+
+        assert!(CipherIdempotencyKey::check_key("key-1").is_ok());
+        assert!(CipherIdempotencyKey::check_key("").is_err());
+        assert!(CipherIdempotencyKey::check_key(&"k".repeat(IDEMPOTENCY_KEY_MAX_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn retried_request_replays_claimed_key() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut conn = DbConn::sqlite_in_memory();
+            let now = Utc::now().naive_utc();
+            let cutoff = CipherIdempotencyKey::cutoff(&now, 300);
+
+            let first = CipherIdempotencyKey::new(String::from("user"), "retry-key", String::from("cipher-1"));
+            assert!(first.claim(&cutoff, &mut conn).await.unwrap());
+
+            // The retry can't claim the key again and is answered with the cipher of the first request
+            let retry = CipherIdempotencyKey::new(String::from("user"), "retry-key", String::from("cipher-2"));
+            assert!(!retry.claim(&cutoff, &mut conn).await.unwrap());
+            let existing = CipherIdempotencyKey::find_by_user_and_key("user", "retry-key", &mut conn).await;
+            assert_eq!(CipherIdempotencyKey::replayed_cipher(existing.as_ref(), &cutoff), Some("cipher-1"));
+
+            // The same key of another user is unrelated
+            let other = CipherIdempotencyKey::new(String::from("other"), "retry-key", String::from("cipher-3"));
+            assert!(other.claim(&cutoff, &mut conn).await.unwrap());
+
+            // Released after the first request failed, the retry creates its own cipher
+            first.delete(&mut conn).await.unwrap();
+            assert!(retry.claim(&cutoff, &mut conn).await.unwrap());
+
+            // After the window the expired key is removed and can be claimed again
+            let later = CipherIdempotencyKey::cutoff(&(now + TimeDelta::try_seconds(301).unwrap()), 300);
+            let next = CipherIdempotencyKey::new(String::from("user"), "retry-key", String::from("cipher-4"));
+            assert!(next.claim(&later, &mut conn).await.unwrap());
+        });
+    }
+}
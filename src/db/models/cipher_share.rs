@@ -0,0 +1,195 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use super::{Cipher, User};
+
+db_object! {
+    // A personal cipher shared directly with another user, without an organization.
+    // The `akey` is the cipher key encrypted for the recipient, provided by the client of the owner.
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = cipher_shares)]
+    #[diesel(primary_key(cipher_uuid, user_uuid))]
+    pub struct CipherShare {
+        pub cipher_uuid: String,
+        pub user_uuid: String,
+        pub akey: String,
+        pub read_only: bool,
+        pub creation_date: NaiveDateTime,
+    }
+}
+
+/// Local methods
+impl CipherShare {
+    pub fn new(cipher_uuid: String, user_uuid: String, akey: String, read_only: bool) -> Self {
+        Self {
+            cipher_uuid,
+            user_uuid,
+            akey,
+            read_only,
+            creation_date: Utc::now().naive_utc(),
+        }
+    }
+
+    /// Returns the (read_only, hide_passwords) access restrictions this share grants on the cipher.
+    /// Shares only apply to personal ciphers, once a cipher is moved to an organization they are ignored.
+    pub fn access_restrictions(&self, cipher: &Cipher) -> Option<(bool, bool)> {
+        if cipher.organization_uuid.is_some() || cipher.uuid != self.cipher_uuid {
+            return None;
+        }
+        Some((self.read_only, false))
+    }
+
+    pub async fn to_json(&self, conn: &mut DbConn) -> Value {
+        let user = User::find_by_uuid(&self.user_uuid, conn).await;
+
+        json!({
+            "CipherId": self.cipher_uuid,
+            "UserId": self.user_uuid,
+            "Email": user.as_ref().map(|u| u.email.clone()),
+            "Name": user.map(|u| u.name),
+            "ReadOnly": self.read_only,
+            "CreationDate": crate::util::format_date(&self.creation_date),
+            "Object": "cipherShare",
+        })
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl CipherShare {
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        User::update_uuid_revision(&self.user_uuid, conn).await;
+
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(cipher_shares::table)
+                    .values(CipherShareDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving cipher share")
+            }
+            postgresql {
+                let value = CipherShareDb::to_db(self);
+                diesel::insert_into(cipher_shares::table)
+                    .values(&value)
+                    .on_conflict((cipher_shares::cipher_uuid, cipher_shares::user_uuid))
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving cipher share")
+            }
+        }
+    }
+
+    pub async fn delete(self, conn: &mut DbConn) -> EmptyResult {
+        User::update_uuid_revision(&self.user_uuid, conn).await;
+
+        db_run! { conn: {
+            diesel::delete(
+                cipher_shares::table
+                    .filter(cipher_shares::cipher_uuid.eq(self.cipher_uuid))
+                    .filter(cipher_shares::user_uuid.eq(self.user_uuid))
+            )
+            .execute(conn)
+            .map_res("Error deleting cipher share")
+        }}
+    }
+
+    pub async fn delete_all_by_cipher(cipher_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        for share in Self::find_by_cipher(cipher_uuid, conn).await {
+            share.delete(conn).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(cipher_shares::table.filter(cipher_shares::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting cipher shares by user")
+        }}
+    }
+
+    pub async fn find_by_cipher(cipher_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            cipher_shares::table
+                .filter(cipher_shares::cipher_uuid.eq(cipher_uuid))
+                .load::<CipherShareDb>(conn)
+                .expect("Error loading cipher shares")
+                .from_db()
+        }}
+    }
+
+    pub async fn find_by_cipher_and_user(cipher_uuid: &str, user_uuid: &str, conn: &mut DbConn) -> Option<Self> {
+        db_run! { conn: {
+            cipher_shares::table
+                .filter(cipher_shares::cipher_uuid.eq(cipher_uuid))
+                .filter(cipher_shares::user_uuid.eq(user_uuid))
+                .first::<CipherShareDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub async fn find_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            cipher_shares::table
+                .filter(cipher_shares::user_uuid.eq(user_uuid))
+                .load::<CipherShareDb>(conn)
+                .expect("Error loading cipher shares")
+                .from_db()
+        }}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn personal_cipher(owner: &str) -> Cipher {
+        let mut cipher = Cipher::new(1, String::from("name"));
+        cipher.user_uuid = Some(String::from(owner));
+        cipher.key = Some(String::from("owner-wrapped-key"));
+        cipher
+    }
+
+    #[test]
+    fn cipher_share_grant() {
+        let cipher = personal_cipher("owner");
+        let share = CipherShare::new(cipher.uuid.clone(), String::from("recipient"), String::from("rsa-key"), true);
+
+        assert_eq!(share.access_restrictions(&cipher), Some((true, false)));
+        let share = CipherShare::new(cipher.uuid.clone(), String::from("recipient"), String::from("rsa-key"), false);
+        assert_eq!(share.access_restrictions(&cipher), Some((false, false)));
+    }
+
+    #[test]
+    fn cipher_share_recipient_sees_cipher() {
+        let cipher = personal_cipher("owner");
+        let share = CipherShare::new(cipher.uuid.clone(), String::from("recipient"), String::from("rsa-key"), false);
+
+        // The recipient gets the cipher with the key encrypted for them, the owner keeps their own key
+        assert_eq!(cipher.key_for_user("recipient", Some(&share)), Some(String::from("rsa-key")));
+        assert_eq!(cipher.key_for_user("owner", None), Some(String::from("owner-wrapped-key")));
+    }
+
+    #[test]
+    fn cipher_share_revoked() {
+        let mut cipher = personal_cipher("owner");
+        let share = CipherShare::new(cipher.uuid.clone(), String::from("recipient"), String::from("rsa-key"), false);
+
+        // Without a share the recipient has no access
+        assert_eq!(cipher.key_for_user("recipient", None), None);
+
+        // A share on another cipher doesn't grant access
+        let other = personal_cipher("owner");
+        assert_eq!(share.access_restrictions(&other), None);
+
+        // Moving the cipher to an organization revokes personal shares
+        cipher.organization_uuid = Some(String::from("org"));
+        assert_eq!(share.access_restrictions(&cipher), None);
+    }
+}
@@ -0,0 +1,252 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use super::Cipher;
+use crate::{api::EmptyResult, db::DbConn, error::MapResult, util::format_date};
+
+db_object! {
+    // A request of a member to move a personal cipher into an organization with the transfer approval policy.
+    // The cipher stays personal until an admin of that organization approved it.
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = cipher_transfers)]
+    #[diesel(treat_none_as_null = true)]
+    #[diesel(primary_key(uuid))]
+    pub struct CipherTransfer {
+        pub uuid: String,
+        pub cipher_uuid: String,
+        pub user_uuid: String,
+        pub org_uuid: String,
+
+        // The share request of the member, encrypted with the organization key. Removed once decided
+        pub data: Option<String>,
+        pub status: i32,
+
+        pub creation_date: NaiveDateTime,
+        pub decided_by: Option<String>,
+        pub decision_date: Option<NaiveDateTime>,
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CipherTransferStatus {
+    Pending = 0,
+    Approved = 1,
+    Rejected = 2,
+}
+
+/// Local methods
+impl CipherTransfer {
+    pub fn new(cipher_uuid: String, user_uuid: String, org_uuid: String, data: String) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            cipher_uuid,
+            user_uuid,
+            org_uuid,
+
+            data: Some(data),
+            status: CipherTransferStatus::Pending as i32,
+
+            creation_date: Utc::now().naive_utc(),
+            decided_by: None,
+            decision_date: None,
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.status == CipherTransferStatus::Pending as i32
+    }
+
+    /// The request holds the cipher as it was when the transfer was requested, a changed cipher can't be approved anymore
+    pub fn check_cipher_unchanged(&self, cipher: &Cipher) -> EmptyResult {
+        if cipher.uuid != self.cipher_uuid
+            || cipher.organization_uuid.is_some()
+            || cipher.updated_at > self.creation_date
+        {
+            err!("The item was changed since the transfer was requested, it has to be requested again")
+        }
+        Ok(())
+    }
+
+    /// Returns the share request to apply as the member
+    pub fn approve(&mut self, decided_by: &str) -> Result<String, crate::Error> {
+        let data = self.decide(CipherTransferStatus::Approved, decided_by)?;
+        data.map_res("The transfer request has no data")
+    }
+
+    /// The cipher stays a personal cipher of the member
+    pub fn reject(&mut self, decided_by: &str) -> EmptyResult {
+        self.decide(CipherTransferStatus::Rejected, decided_by)?;
+        Ok(())
+    }
+
+    fn decide(&mut self, status: CipherTransferStatus, decided_by: &str) -> Result<Option<String>, crate::Error> {
+        if !self.is_pending() {
+            err!("The transfer request was already decided")
+        }
+        self.status = status as i32;
+        self.decided_by = Some(decided_by.to_string());
+        self.decision_date = Some(Utc::now().naive_utc());
+        Ok(self.data.take())
+    }
+
+    pub fn to_json(&self, user_email: &str) -> Value {
+        json!({
+            "Id": self.uuid,
+            "CipherId": self.cipher_uuid,
+            "UserId": self.user_uuid,
+            "Email": user_email,
+            "OrganizationId": self.org_uuid,
+            "Status": self.status,
+            "CreationDate": format_date(&self.creation_date),
+            "DecisionDate": self.decision_date.as_ref().map(format_date),
+            "Object": "cipherTransfer",
+        })
+    }
+}
+
+/// Database methods
+impl CipherTransfer {
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(cipher_transfers::table)
+                    .values(CipherTransferDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving cipher transfer")
+            }
+            postgresql {
+                let value = CipherTransferDb::to_db(self);
+                diesel::insert_into(cipher_transfers::table)
+                    .values(&value)
+                    .on_conflict(cipher_transfers::uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving cipher transfer")
+            }
+        }
+    }
+
+    pub async fn delete_all_by_cipher(cipher_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(cipher_transfers::table.filter(cipher_transfers::cipher_uuid.eq(cipher_uuid)))
+                .execute(conn)
+                .map_res("Error deleting cipher transfers")
+        }}
+    }
+
+    /// A new request replaces the pending ones of the cipher
+    pub async fn delete_pending_by_cipher(cipher_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(
+                cipher_transfers::table
+                    .filter(cipher_transfers::cipher_uuid.eq(cipher_uuid))
+                    .filter(cipher_transfers::status.eq(CipherTransferStatus::Pending as i32)),
+            )
+            .execute(conn)
+            .map_res("Error deleting cipher transfers")
+        }}
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(cipher_transfers::table.filter(cipher_transfers::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting cipher transfers")
+        }}
+    }
+
+    pub async fn delete_all_by_organization(org_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(cipher_transfers::table.filter(cipher_transfers::org_uuid.eq(org_uuid)))
+                .execute(conn)
+                .map_res("Error deleting cipher transfers")
+        }}
+    }
+
+    pub async fn find_by_uuid_and_org(uuid: &str, org_uuid: &str, conn: &mut DbConn) -> Option<Self> {
+        db_run! { conn: {
+            cipher_transfers::table
+                .filter(cipher_transfers::uuid.eq(uuid))
+                .filter(cipher_transfers::org_uuid.eq(org_uuid))
+                .first::<CipherTransferDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub async fn find_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            cipher_transfers::table
+                .filter(cipher_transfers::user_uuid.eq(user_uuid))
+                .order(cipher_transfers::creation_date.desc())
+                .load::<CipherTransferDb>(conn)
+                .unwrap_or_default()
+                .from_db()
+        }}
+    }
+
+    pub async fn find_pending_by_org(org_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            cipher_transfers::table
+                .filter(cipher_transfers::org_uuid.eq(org_uuid))
+                .filter(cipher_transfers::status.eq(CipherTransferStatus::Pending as i32))
+                .order(cipher_transfers::creation_date.asc())
+                .load::<CipherTransferDb>(conn)
+                .unwrap_or_default()
+                .from_db()
+        }}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer() -> CipherTransfer {
+        CipherTransfer::new(String::from("cipher"), String::from("user"), String::from("org"), String::from("{}"))
+    }
+
+    #[test]
+    fn transfer_pending_then_approved() {
+        let mut transfer = transfer();
+        assert!(transfer.is_pending());
+
+        assert_eq!(transfer.approve("admin").unwrap(), "{}");
+        assert_eq!(transfer.status, CipherTransferStatus::Approved as i32);
+        assert_eq!(transfer.decided_by.as_deref(), Some("admin"));
+        // The encrypted cipher isn't kept after the decision
+        assert!(transfer.data.is_none());
+
+        assert!(transfer.approve("admin").is_err());
+        assert!(transfer.reject("admin").is_err());
+    }
+
+    #[test]
+    fn transfer_rejected() {
+        let mut transfer = transfer();
+        transfer.reject("admin").unwrap();
+        assert!(!transfer.is_pending());
+        assert_eq!(transfer.status, CipherTransferStatus::Rejected as i32);
+        assert!(transfer.data.is_none());
+
+        assert!(transfer.approve("admin").is_err());
+    }
+
+    #[test]
+    fn changed_cipher_transfer_refused() {
+        let mut cipher = Cipher::new(1, String::from("2.name"));
+        cipher.uuid = String::from("cipher");
+        cipher.updated_at = Utc::now().naive_utc() - chrono::TimeDelta::try_minutes(1).unwrap();
+        let transfer = transfer();
+        assert!(transfer.check_cipher_unchanged(&cipher).is_ok());
+
+        cipher.updated_at = transfer.creation_date + chrono::TimeDelta::try_seconds(1).unwrap();
+        assert!(transfer.check_cipher_unchanged(&cipher).is_err());
+
+        // Moved into an organization meanwhile
+        cipher.updated_at = transfer.creation_date;
+        cipher.organization_uuid = Some(String::from("other-org"));
+        assert!(transfer.check_cipher_unchanged(&cipher).is_err());
+    }
+}
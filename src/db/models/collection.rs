@@ -58,18 +58,16 @@ impl Collection {
     }
 
     pub fn set_external_id(&mut self, external_id: Option<String>) {
-        //Check if external id is empty. We don't want to have
-        //empty strings in the database
-        match external_id {
-            Some(external_id) => {
-                if external_id.is_empty() {
-                    self.external_id = None;
-                } else {
-                    self.external_id = Some(external_id)
-                }
-            }
-            None => self.external_id = None,
-        }
+        // Check if external_id is empty. We do not want to have empty strings in the database
+        self.external_id = match external_id {
+            Some(external_id) if !external_id.trim().is_empty() => Some(external_id),
+            _ => None,
+        };
+    }
+
+    /// External IDs are unique within an organization, `found` is the collection found by the external ID of this one
+    fn is_external_id_conflict(&self, found: Option<&Self>) -> bool {
+        found.is_some_and(|found| found.uuid != self.uuid)
     }
 
     pub async fn to_json_details(
@@ -82,13 +80,13 @@ impl Collection {
             match cipher_sync_data.user_organizations.get(&self.org_uuid) {
                 Some(uo) if uo.has_full_access() => (false, false),
                 Some(_) => {
-                    if let Some(uc) = cipher_sync_data.user_collections.get(&self.uuid) {
-                        (uc.read_only, uc.hide_passwords)
-                    } else if let Some(cg) = cipher_sync_data.user_collections_groups.get(&self.uuid) {
-                        (cg.read_only, cg.hide_passwords)
-                    } else {
-                        (false, false)
-                    }
+                    let direct =
+                        cipher_sync_data.user_collections.get(&self.uuid).map(|uc| (uc.read_only, uc.hide_passwords));
+                    let group = cipher_sync_data
+                        .user_collections_groups
+                        .get(&self.uuid)
+                        .map(|cg| (cg.read_only, cg.hide_passwords));
+                    Self::most_permissive_access(direct.into_iter().chain(group)).unwrap_or((false, false))
                 }
                 _ => (true, true),
             }
@@ -103,6 +101,12 @@ impl Collection {
         json_object
     }
 
+    /// Combines the `(read_only, hide_passwords)` access a member has to a collection directly and through groups,
+    /// every flag is only in effect when all of the access grants have it set
+    pub fn most_permissive_access(access: impl IntoIterator<Item = (bool, bool)>) -> Option<(bool, bool)> {
+        access.into_iter().reduce(|(ro_a, hp_a), (ro_b, hp_b)| (ro_a && ro_b, hp_a && hp_b))
+    }
+
     pub async fn can_access_collection(org_user: &UserOrganization, col_id: &str, conn: &mut DbConn) -> bool {
         org_user.has_status(UserOrgStatus::Confirmed)
             && (org_user.has_full_access()
@@ -191,7 +195,9 @@ impl Collection {
     }
 
     pub async fn find_by_user_uuid(user_uuid: String, conn: &mut DbConn) -> Vec<Self> {
-        if CONFIG.org_groups_enabled() {
+        // The collections of organizations the user can't access right now are left out
+        let restricted_orgs = UserOrganization::find_restricted_org_uuids(&user_uuid, conn).await;
+        let collections: Vec<Self> = if CONFIG.org_groups_enabled() {
             db_run! { conn: {
                 collections::table
                 .left_join(users_collections::table.on(
@@ -258,7 +264,8 @@ impl Collection {
                 .distinct()
                 .load::<CollectionDb>(conn).expect("Error loading collections").from_db()
             }}
-        }
+        };
+        collections.into_iter().filter(|c| !restricted_orgs.contains(&c.org_uuid)).collect()
     }
 
     pub async fn find_by_organization_and_user_uuid(org_uuid: &str, user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
@@ -302,6 +309,28 @@ impl Collection {
         }}
     }
 
+    pub async fn find_by_external_id_and_org(external_id: &str, org_uuid: &str, conn: &mut DbConn) -> Option<Self> {
+        db_run! { conn: {
+            collections::table
+                .filter(collections::external_id.eq(external_id))
+                .filter(collections::org_uuid.eq(org_uuid))
+                .first::<CollectionDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    /// Whether another collection of the organization already uses the external ID of this one
+    pub async fn is_external_id_in_use(&self, conn: &mut DbConn) -> bool {
+        match &self.external_id {
+            Some(external_id) => {
+                let found = Self::find_by_external_id_and_org(external_id, &self.org_uuid, conn).await;
+                self.is_external_id_conflict(found.as_ref())
+            }
+            None => false,
+        }
+    }
+
     pub async fn find_by_uuid_and_user(uuid: &str, user_uuid: String, conn: &mut DbConn) -> Option<Self> {
         if CONFIG.org_groups_enabled() {
             db_run! { conn: {
@@ -716,3 +745,44 @@ impl CollectionCipher {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collection_external_id_set() {
+        let collection = Collection::new(String::from("org"), String::from("Sales"), Some(String::from("cn=sales")));
+        assert_eq!(collection.external_id.as_deref(), Some("cn=sales"));
+        assert_eq!(
+            Collection::new(String::from("org"), String::from("Sales"), Some(String::from(" "))).external_id,
+            None
+        );
+
+        let mut collection = collection;
+        collection.set_external_id(None);
+        assert_eq!(collection.external_id, None);
+    }
+
+    #[test]
+    fn collection_external_id_unique_in_org() {
+        let collection = Collection::new(String::from("org"), String::from("Sales"), Some(String::from("cn=sales")));
+        let other = Collection::new(String::from("org"), String::from("Marketing"), Some(String::from("cn=sales")));
+
+        // The lookup by external ID finding the collection itself is fine, finding another one is not
+        assert!(!collection.is_external_id_conflict(None));
+        assert!(!collection.is_external_id_conflict(Some(&collection)));
+        assert!(collection.is_external_id_conflict(Some(&other)));
+    }
+
+    #[test]
+    fn collection_access_union_of_direct_and_group() {
+        // Read-only directly, but editable through a group
+        assert_eq!(Collection::most_permissive_access([(true, false), (false, false)]), Some((false, false)));
+        // Passwords hidden through a group, but visible directly
+        assert_eq!(Collection::most_permissive_access([(false, false), (false, true)]), Some((false, false)));
+        // Only through a group
+        assert_eq!(Collection::most_permissive_access([(true, true)]), Some((true, true)));
+        assert_eq!(Collection::most_permissive_access([]), None);
+    }
+}
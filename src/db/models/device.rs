@@ -1,4 +1,6 @@
-use chrono::{NaiveDateTime, Utc};
+use std::collections::HashMap;
+
+use chrono::{NaiveDateTime, TimeDelta, Utc};
 
 use crate::{crypto, CONFIG};
 use core::fmt;
@@ -23,9 +25,27 @@ db_object! {
         pub refresh_token: String,
 
         pub twofactor_remember: Option<String>,
+
+        // Consecutive failed deliveries to the push relay since the last successful one
+        pub push_failures: i32,
+
+        // When the remember token was granted by another two-step login method, kept when it's rotated
+        pub twofactor_remember_at: Option<NaiveDateTime>,
+        // The remember token replaced by the last rotation, presenting it again means it was copied
+        pub twofactor_remember_prev: Option<String>,
     }
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum TwoFactorRemember {
+    Valid,
+    Invalid,
+    // Granted longer ago than `TWO_FACTOR_REMEMBER_MAX_DAYS`
+    Expired,
+    // The token was already rotated
+    Replayed,
+}
+
 /// Local methods
 impl Device {
     pub fn new(uuid: String, user_uuid: String, name: String, atype: i32) -> Self {
@@ -44,19 +64,50 @@ impl Device {
             push_token: None,
             refresh_token: String::new(),
             twofactor_remember: None,
+            push_failures: 0,
+            twofactor_remember_at: None,
+            twofactor_remember_prev: None,
         }
     }
 
+    /// Issues a new remember token, which replaces the current one.
+    /// The time of the original grant is kept, so rotating doesn't extend the max age.
     pub fn refresh_twofactor_remember(&mut self) -> String {
         use data_encoding::BASE64;
         let twofactor_remember = crypto::encode_random_bytes::<180>(BASE64);
-        self.twofactor_remember = Some(twofactor_remember.clone());
+        self.twofactor_remember_prev = self.twofactor_remember.replace(twofactor_remember.clone());
+        self.twofactor_remember_at.get_or_insert_with(|| Utc::now().naive_utc());
 
         twofactor_remember
     }
 
     pub fn delete_twofactor_remember(&mut self) {
         self.twofactor_remember = None;
+        self.twofactor_remember_at = None;
+        self.twofactor_remember_prev = None;
+    }
+
+    pub fn check_twofactor_remember(
+        &self,
+        token: &str,
+        now: NaiveDateTime,
+        max_days: Option<i64>,
+    ) -> TwoFactorRemember {
+        use crate::crypto::ct_eq;
+
+        match self.twofactor_remember {
+            Some(ref current) if ct_eq(current, token) => {
+                let max_age = max_days.and_then(TimeDelta::try_days);
+                match (self.twofactor_remember_at, max_age) {
+                    (Some(granted_at), Some(max_age)) if now - granted_at > max_age => TwoFactorRemember::Expired,
+                    _ => TwoFactorRemember::Valid,
+                }
+            }
+            _ if self.twofactor_remember_prev.as_ref().is_some_and(|prev| ct_eq(prev, token)) => {
+                TwoFactorRemember::Replayed
+            }
+            _ => TwoFactorRemember::Invalid,
+        }
     }
 
     pub fn refresh_tokens(&mut self, user: &super::User, scope: Vec<String>) -> (String, i64) {
@@ -83,11 +134,14 @@ impl Device {
         // let orgmanager: Vec<_> = orgs.iter().filter(|o| o.atype == 3).map(|o| o.org_uuid.clone()).collect();
 
         // Create the JWT claims struct, to send to the client
-        use crate::auth::{encode_jwt, LoginJwtClaims, DEFAULT_VALIDITY, JWT_LOGIN_ISSUER};
+        use crate::auth::{
+            encode_jwt, login_not_before, LoginJwtClaims, DEFAULT_VALIDITY, JWT_AUDIENCE, JWT_LOGIN_ISSUER,
+        };
         let claims = LoginJwtClaims {
-            nbf: time_now.timestamp(),
+            nbf: login_not_before(time_now.timestamp(), CONFIG._token_epoch()),
             exp: (time_now + *DEFAULT_VALIDITY).timestamp(),
             iss: JWT_LOGIN_ISSUER.to_string(),
+            aud: JWT_AUDIENCE.to_string(),
             sub: user.uuid.clone(),
 
             premium: true,
@@ -121,6 +175,41 @@ impl Device {
     pub fn is_registered(&self) -> bool {
         self.push_uuid.is_some()
     }
+
+    /// Counts a failed push delivery, returns true when the registration reached the threshold and should be pruned.
+    /// A threshold of 0 disables pruning.
+    pub fn record_push_failure(&mut self, threshold: u32) -> bool {
+        self.push_failures = self.push_failures.saturating_add(1);
+        threshold > 0 && self.push_failures >= threshold as i32
+    }
+
+    pub fn clear_push_registration(&mut self) {
+        self.push_uuid = None;
+        self.push_token = None;
+        self.push_failures = 0;
+    }
+
+    /// Returns the registered devices which exceed the limit, oldest first, never including `keep_uuid`.
+    /// A limit of 0 means no limit.
+    pub fn push_devices_to_evict(mut devices: Vec<Self>, keep_uuid: &str, max_devices: u32) -> Vec<Self> {
+        if max_devices == 0 || devices.len() <= max_devices as usize {
+            return Vec::new();
+        }
+
+        let excess = devices.len() - max_devices as usize;
+        devices.sort_by_key(|d| d.created_at);
+        devices.into_iter().filter(|d| d.uuid != keep_uuid).take(excess).collect()
+    }
+}
+
+/// The latest activity per user, of all the devices of those users
+fn last_active_by_user(activity: Vec<(String, NaiveDateTime)>) -> HashMap<String, NaiveDateTime> {
+    let mut last_active: HashMap<String, NaiveDateTime> = HashMap::new();
+    for (user_uuid, updated_at) in activity {
+        let entry = last_active.entry(user_uuid).or_insert(updated_at);
+        *entry = (*entry).max(updated_at);
+    }
+    last_active
 }
 
 use crate::db::DbConn;
@@ -158,6 +247,33 @@ impl Device {
         }}
     }
 
+    pub async fn delete_by_uuid_and_user(uuid: &str, user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(devices::table.filter(devices::uuid.eq(uuid)).filter(devices::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error removing device")
+        }}
+    }
+
+    /// Replaces the refresh token of every device, so they have to log in again instead of refreshing their session.
+    /// Either all tokens are replaced or none, every device needs its own token so this can't be a single update.
+    pub async fn rotate_all_refresh_tokens(conn: &mut DbConn) -> EmptyResult {
+        use data_encoding::BASE64URL;
+        db_run! { conn: {
+            conn.transaction::<_, diesel::result::Error, _>(|conn| {
+                let devices: Vec<(String, String)> =
+                    devices::table.select((devices::uuid, devices::user_uuid)).load(conn)?;
+                for (uuid, user_uuid) in devices {
+                    diesel::update(devices::table.filter(devices::uuid.eq(uuid)).filter(devices::user_uuid.eq(user_uuid)))
+                        .set(devices::refresh_token.eq(crypto::encode_random_bytes::<64>(BASE64URL)))
+                        .execute(conn)?;
+                }
+                Ok(())
+            })
+            .map_res("Error rotating refresh tokens")
+        }}
+    }
+
     pub async fn find_by_uuid_and_user(uuid: &str, user_uuid: &str, conn: &mut DbConn) -> Option<Self> {
         db_run! { conn: {
             devices::table
@@ -219,6 +335,19 @@ impl Device {
         }}
     }
 
+    /// The last activity of every member of an organization, loaded in one query for the inactive members report
+    pub async fn find_last_active_by_org(org_uuid: &str, conn: &mut DbConn) -> HashMap<String, NaiveDateTime> {
+        let activity: Vec<(String, NaiveDateTime)> = db_run! { conn: {
+            devices::table
+                .inner_join(users_organizations::table.on(users_organizations::user_uuid.eq(devices::user_uuid)))
+                .filter(users_organizations::org_uuid.eq(org_uuid))
+                .select((devices::user_uuid, devices::updated_at))
+                .load::<(String, NaiveDateTime)>(conn)
+                .unwrap_or_default()
+        }};
+        last_active_by_user(activity)
+    }
+
     pub async fn find_push_devices_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
         db_run! { conn: {
             devices::table
@@ -230,6 +359,33 @@ impl Device {
         }}
     }
 
+    pub async fn find_registered_push_devices_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            devices::table
+                .filter(devices::user_uuid.eq(user_uuid))
+                .filter(devices::push_uuid.is_not_null())
+                .load::<DeviceDb>(conn)
+                .expect("Error loading registered push devices")
+                .from_db()
+        }}
+    }
+
+    /// Stores the push registration state, without touching the last activity of the device
+    pub async fn save_push_state(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::update(devices::table)
+                .filter(devices::uuid.eq(&self.uuid))
+                .filter(devices::user_uuid.eq(&self.user_uuid))
+                .set((
+                    devices::push_uuid.eq(&self.push_uuid),
+                    devices::push_token.eq(&self.push_token),
+                    devices::push_failures.eq(self.push_failures),
+                ))
+                .execute(conn)
+                .map_res("Error saving push state")
+        }}
+    }
+
     pub async fn check_user_has_push_device(user_uuid: &str, conn: &mut DbConn) -> bool {
         db_run! { conn: {
             devices::table
@@ -329,3 +485,97 @@ impl DeviceType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_active_of_all_devices() {
+        let now = Utc::now().naive_utc();
+        let day = TimeDelta::try_days(1).unwrap();
+        let activity = vec![
+            (String::from("a"), now - day * 30),
+            (String::from("a"), now - day),
+            (String::from("b"), now - day * 90),
+        ];
+        let last_active = last_active_by_user(activity);
+        assert_eq!(last_active["a"], now - day);
+        assert_eq!(last_active["b"], now - day * 90);
+        assert!(!last_active.contains_key("c"));
+    }
+
+    fn push_device(uuid: &str, age_days: i64) -> Device {
+        let mut device = Device::new(String::from(uuid), String::from("user"), String::from("phone"), 0);
+        device.created_at -= TimeDelta::try_days(age_days).unwrap();
+        device.push_uuid = Some(format!("push-{uuid}"));
+        device.push_token = Some(format!("token-{uuid}"));
+        device
+    }
+
+    #[test]
+    fn push_prune_after_threshold() {
+        let mut device = push_device("a", 0);
+        assert!(!device.record_push_failure(3));
+        assert!(!device.record_push_failure(3));
+        assert!(device.record_push_failure(3));
+
+        device.clear_push_registration();
+        assert!(!device.is_registered());
+        assert_eq!(device.push_token, None);
+        assert_eq!(device.push_failures, 0);
+
+        // A threshold of 0 never prunes
+        for _ in 0..10 {
+            assert!(!device.record_push_failure(0));
+        }
+    }
+
+    #[test]
+    fn push_cap_evicts_oldest() {
+        let devices = vec![push_device("new", 1), push_device("oldest", 30), push_device("old", 10)];
+
+        let evicted = Device::push_devices_to_evict(devices, "new", 2);
+        assert_eq!(evicted.iter().map(|d| d.uuid.as_str()).collect::<Vec<_>>(), vec!["oldest"]);
+
+        // The device which is registering is kept, even when it's the oldest
+        let devices = vec![push_device("new", 1), push_device("oldest", 30), push_device("old", 10)];
+        let evicted = Device::push_devices_to_evict(devices, "oldest", 1);
+        assert_eq!(evicted.iter().map(|d| d.uuid.as_str()).collect::<Vec<_>>(), vec!["old", "new"]);
+
+        let devices = vec![push_device("new", 1), push_device("oldest", 30)];
+        assert!(Device::push_devices_to_evict(devices, "new", 0).is_empty());
+    }
+
+    #[test]
+    fn remember_token_rotated_on_use() {
+        let mut device = Device::new(String::from("a"), String::from("user"), String::from("phone"), 0);
+        let first = device.refresh_twofactor_remember();
+        let granted_at = device.twofactor_remember_at;
+        let now = Utc::now().naive_utc();
+        assert_eq!(device.check_twofactor_remember(&first, now, None), TwoFactorRemember::Valid);
+
+        let second = device.refresh_twofactor_remember();
+        assert_ne!(first, second);
+        assert_eq!(device.twofactor_remember_at, granted_at);
+        assert_eq!(device.check_twofactor_remember(&second, now, Some(30)), TwoFactorRemember::Valid);
+        assert_eq!(device.check_twofactor_remember("other", now, None), TwoFactorRemember::Invalid);
+
+        // Rotating doesn't extend the max age
+        let later = now + TimeDelta::try_days(31).unwrap();
+        assert_eq!(device.check_twofactor_remember(&second, later, Some(30)), TwoFactorRemember::Expired);
+        assert_eq!(device.check_twofactor_remember(&second, later, None), TwoFactorRemember::Valid);
+    }
+
+    #[test]
+    fn remember_token_replay_rejected() {
+        let mut device = Device::new(String::from("a"), String::from("user"), String::from("phone"), 0);
+        let old = device.refresh_twofactor_remember();
+        device.refresh_twofactor_remember();
+        let now = Utc::now().naive_utc();
+        assert_eq!(device.check_twofactor_remember(&old, now, None), TwoFactorRemember::Replayed);
+
+        device.delete_twofactor_remember();
+        assert_eq!(device.check_twofactor_remember(&old, now, None), TwoFactorRemember::Invalid);
+    }
+}
@@ -0,0 +1,211 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use crate::{api::EmptyResult, db::DbConn, error::MapResult, util::format_date};
+
+db_object! {
+    // A login from an unknown device of a member of an organization with the device approval policy.
+    // The device can only be used after an admin of that organization approved it.
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = device_approvals)]
+    #[diesel(treat_none_as_null = true)]
+    #[diesel(primary_key(uuid))]
+    pub struct DeviceApproval {
+        pub uuid: String,
+        pub user_uuid: String,
+        pub org_uuid: String,
+
+        pub device_identifier: String,
+        pub device_name: String,
+        pub device_type: i32,
+
+        pub request_ip: String,
+        pub creation_date: NaiveDateTime,
+        pub approved_by: Option<String>,
+        pub approval_date: Option<NaiveDateTime>,
+    }
+}
+
+/// Local methods
+impl DeviceApproval {
+    pub fn new(
+        user_uuid: String,
+        org_uuid: String,
+        device_identifier: String,
+        device_name: String,
+        device_type: i32,
+        request_ip: String,
+    ) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            user_uuid,
+            org_uuid,
+
+            device_identifier,
+            device_name,
+            device_type,
+
+            request_ip,
+            creation_date: Utc::now().naive_utc(),
+            approved_by: None,
+            approval_date: None,
+        }
+    }
+
+    pub fn is_approved(&self) -> bool {
+        self.approval_date.is_some()
+    }
+
+    pub fn approve(&mut self, approved_by: &str) {
+        self.approved_by = Some(approved_by.to_string());
+        self.approval_date = Some(Utc::now().naive_utc());
+    }
+
+    /// The organizations of `required_org_uuids` which haven't approved the device (yet)
+    pub fn pending_orgs<'a>(required_org_uuids: &'a [String], approvals: &[Self]) -> Vec<&'a str> {
+        required_org_uuids
+            .iter()
+            .filter(|org_uuid| !approvals.iter().any(|a| &a.org_uuid == *org_uuid && a.is_approved()))
+            .map(String::as_str)
+            .collect()
+    }
+
+    pub fn to_json(&self, user_email: &str) -> Value {
+        json!({
+            "Id": self.uuid,
+            "UserId": self.user_uuid,
+            "Email": user_email,
+            "DeviceIdentifier": self.device_identifier,
+            "DeviceName": self.device_name,
+            "DeviceType": self.device_type,
+            "RequestIpAddress": self.request_ip,
+            "CreationDate": format_date(&self.creation_date),
+            "Approved": self.is_approved(),
+            "Object": "deviceApproval",
+        })
+    }
+}
+
+/// Database methods
+impl DeviceApproval {
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            let value = DeviceApprovalDb::to_db(self);
+            let exists = device_approvals::table
+                .filter(device_approvals::uuid.eq(&self.uuid))
+                .count()
+                .first::<i64>(conn)
+                .unwrap_or(0) > 0;
+            if exists {
+                diesel::update(device_approvals::table)
+                    .filter(device_approvals::uuid.eq(&self.uuid))
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving device approval")
+            } else {
+                diesel::insert_into(device_approvals::table)
+                    .values(&value)
+                    .execute(conn)
+                    .map_res("Error saving device approval")
+            }
+        }}
+    }
+
+    pub async fn delete(self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(device_approvals::table.filter(device_approvals::uuid.eq(self.uuid)))
+                .execute(conn)
+                .map_res("Error deleting device approval")
+        }}
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(device_approvals::table.filter(device_approvals::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting device approvals")
+        }}
+    }
+
+    pub async fn delete_all_by_organization(org_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(device_approvals::table.filter(device_approvals::org_uuid.eq(org_uuid)))
+                .execute(conn)
+                .map_res("Error deleting device approvals")
+        }}
+    }
+
+    pub async fn find_by_uuid_and_org(uuid: &str, org_uuid: &str, conn: &mut DbConn) -> Option<Self> {
+        db_run! { conn: {
+            device_approvals::table
+                .filter(device_approvals::uuid.eq(uuid))
+                .filter(device_approvals::org_uuid.eq(org_uuid))
+                .first::<DeviceApprovalDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub async fn find_by_user_and_device(user_uuid: &str, device_identifier: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            device_approvals::table
+                .filter(device_approvals::user_uuid.eq(user_uuid))
+                .filter(device_approvals::device_identifier.eq(device_identifier))
+                .load::<DeviceApprovalDb>(conn)
+                .unwrap_or_default()
+                .from_db()
+        }}
+    }
+
+    pub async fn find_pending_by_org(org_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            device_approvals::table
+                .filter(device_approvals::org_uuid.eq(org_uuid))
+                .filter(device_approvals::approval_date.is_null())
+                .order(device_approvals::creation_date.asc())
+                .load::<DeviceApprovalDb>(conn)
+                .unwrap_or_default()
+                .from_db()
+        }}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn approval(org_uuid: &str) -> DeviceApproval {
+        DeviceApproval::new(
+            String::from("user"),
+            String::from(org_uuid),
+            String::from("device"),
+            String::from("firefox"),
+            10,
+            String::from("192.0.2.10"),
+        )
+    }
+
+    #[test]
+    fn new_device_held_pending() {
+        let required = vec![String::from("org")];
+        assert_eq!(DeviceApproval::pending_orgs(&required, &[]), vec!["org"]);
+        assert_eq!(DeviceApproval::pending_orgs(&required, &[approval("org")]), vec!["org"]);
+    }
+
+    #[test]
+    fn approved_device_proceeds() {
+        let required = vec![String::from("org"), String::from("other-org")];
+        let mut approved = approval("org");
+        approved.approve("admin");
+        assert!(approved.is_approved());
+
+        // Every organization requiring approval has to approve the device
+        assert_eq!(DeviceApproval::pending_orgs(&required, &[approved]), vec!["other-org"]);
+        let mut other = approval("other-org");
+        other.approve("other-admin");
+        let mut approved = approval("org");
+        approved.approve("admin");
+        assert!(DeviceApproval::pending_orgs(&required, &[approved, other]).is_empty());
+        assert!(DeviceApproval::pending_orgs(&[], &[]).is_empty());
+    }
+}
@@ -1,4 +1,4 @@
-use chrono::{NaiveDateTime, Utc};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
 use serde_json::Value;
 
 use crate::{api::EmptyResult, db::DbConn, error::MapResult};
@@ -24,6 +24,17 @@ db_object! {
         pub updated_at: NaiveDateTime,
         pub created_at: NaiveDateTime,
     }
+
+    // The number of takeover grantees which have to initiate a recovery of the grantor's account within
+    // `EMERGENCY_ACCESS_QUORUM_WINDOW_HOURS` before any of them can take it over.
+    // Grantors without a stored row need a single grantee.
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = emergency_access_quorums)]
+    #[diesel(primary_key(grantor_uuid))]
+    pub struct EmergencyAccessQuorum {
+        pub grantor_uuid: String,
+        pub quorum: i32,
+    }
 }
 
 /// Local methods
@@ -48,6 +59,15 @@ impl EmergencyAccess {
         }
     }
 
+    /// Whether the wait time of an initiated recovery passed, after which it's approved automatically
+    pub fn is_recovery_due(&self, now: NaiveDateTime) -> bool {
+        let wait_time = TimeDelta::try_days(i64::from(self.wait_time_days));
+        match (self.recovery_initiated_at, wait_time) {
+            (Some(initiated_at), Some(wait_time)) => initiated_at + wait_time <= now,
+            _ => false,
+        }
+    }
+
     pub fn get_type_as_str(&self) -> &'static str {
         if self.atype == EmergencyAccessType::View as i32 {
             "View"
@@ -333,3 +353,136 @@ impl EmergencyAccess {
 }
 
 // endregion
+
+impl EmergencyAccessQuorum {
+    pub fn new(grantor_uuid: String, quorum: i32) -> Self {
+        Self {
+            grantor_uuid,
+            quorum,
+        }
+    }
+
+    /// Whether enough grantees initiated a recovery within the window of each other, each grantee is counted once.
+    /// The window isn't relative to now, the takeover itself is only possible after the wait time of the grantees.
+    pub fn is_met(&self, recovery_initiated_at: &[NaiveDateTime], window_hours: i64) -> bool {
+        let (Some(window), Ok(quorum)) = (TimeDelta::try_hours(window_hours), usize::try_from(self.quorum)) else {
+            return false;
+        };
+        if quorum <= 1 {
+            return quorum == 1 && !recovery_initiated_at.is_empty();
+        }
+
+        let mut initiated_at = recovery_initiated_at.to_vec();
+        initiated_at.sort();
+        initiated_at.windows(quorum).any(|group| group[quorum - 1] - group[0] <= window)
+    }
+
+    pub fn to_json(&self, window_hours: i64) -> Value {
+        json!({
+            "Quorum": self.quorum,
+            "WindowHours": window_hours,
+            "Object": "emergencyAccessQuorum",
+        })
+    }
+}
+
+impl EmergencyAccessQuorum {
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(emergency_access_quorums::table)
+                    .values(EmergencyAccessQuorumDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving emergency access quorum")
+            }
+            postgresql {
+                let value = EmergencyAccessQuorumDb::to_db(self);
+                diesel::insert_into(emergency_access_quorums::table)
+                    .values(&value)
+                    .on_conflict(emergency_access_quorums::grantor_uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving emergency access quorum")
+            }
+        }
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(emergency_access_quorums::table.filter(emergency_access_quorums::grantor_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting emergency access quorum")
+        }}
+    }
+
+    pub async fn find_or_default(grantor_uuid: &str, conn: &mut DbConn) -> Self {
+        let quorum: Option<Self> = db_run! { conn: {
+            emergency_access_quorums::table
+                .filter(emergency_access_quorums::grantor_uuid.eq(grantor_uuid))
+                .first::<EmergencyAccessQuorumDb>(conn)
+                .ok()
+                .from_db()
+        }};
+        quorum.unwrap_or_else(|| Self::new(String::from(grantor_uuid), 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hours(hours: i64) -> TimeDelta {
+        TimeDelta::try_hours(hours).unwrap()
+    }
+
+    #[test]
+    fn quorum_not_met() {
+        let now = Utc::now().naive_utc();
+        let quorum = EmergencyAccessQuorum::new(String::from("grantor"), 2);
+        assert!(!quorum.is_met(&[], 72));
+        assert!(!quorum.is_met(&[now - hours(1)], 72));
+        // The second request is outside of the window of the first one
+        assert!(!quorum.is_met(&[now - hours(1), now - hours(74)], 72));
+    }
+
+    #[test]
+    fn quorum_met() {
+        let now = Utc::now().naive_utc();
+        let quorum = EmergencyAccessQuorum::new(String::from("grantor"), 2);
+        assert!(quorum.is_met(&[now - hours(1), now - hours(71)], 72));
+        assert!(quorum.is_met(&[now, now - hours(100), now - hours(150)], 72));
+        // A single grantee is enough by default
+        assert!(EmergencyAccessQuorum::new(String::from("grantor"), 1).is_met(&[now], 72));
+    }
+
+    #[test]
+    fn quorum_met_after_wait_time_approval() {
+        let initiated_at = Utc::now().naive_utc() - TimeDelta::try_days(8).unwrap();
+        let mut grantees: Vec<EmergencyAccess> = [initiated_at, initiated_at + hours(2)]
+            .into_iter()
+            .map(|at| {
+                let mut ea = EmergencyAccess::new(
+                    String::from("grantor"),
+                    String::from("grantee@example.com"),
+                    EmergencyAccessStatus::RecoveryInitiated as i32,
+                    EmergencyAccessType::Takeover as i32,
+                    7,
+                );
+                ea.recovery_initiated_at = Some(at);
+                ea
+            })
+            .collect();
+
+        // Both recoveries are approved once the 7 days of wait time passed, long after the 72 hours window
+        let now = initiated_at + TimeDelta::try_days(7).unwrap() + hours(2);
+        assert!(!grantees[1].is_recovery_due(now - hours(1)));
+        for ea in &mut grantees {
+            assert!(ea.is_recovery_due(now));
+            ea.status = EmergencyAccessStatus::RecoveryApproved as i32;
+        }
+
+        let recovery_initiated_at: Vec<_> = grantees.iter().filter_map(|ea| ea.recovery_initiated_at).collect();
+        assert!(EmergencyAccessQuorum::new(String::from("grantor"), 2).is_met(&recovery_initiated_at, 72));
+    }
+}
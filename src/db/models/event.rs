@@ -32,6 +32,8 @@ db_object! {
         pub provider_uuid: Option<String>,
         pub provider_user_uuid: Option<String>,
         pub provider_org_uuid: Option<String>,
+        // Reason code of a failed event, like a failed two-step login
+        pub detail: Option<String>,
     }
 }
 
@@ -117,6 +119,28 @@ pub enum EventType {
     // ProviderOrganizationAdded = 1901, // Not supported
     // ProviderOrganizationRemoved = 1902, // Not supported
     // ProviderOrganizationVaultAccessed = 1903, // Not supported
+
+    // Admin panel, Vaultwarden specific
+    AdminUserInvited = 9000,
+    AdminUserDeleted = 9001,
+    AdminUserDeauthorized = 9002,
+    AdminUserDisabled = 9003,
+    AdminUserEnabled = 9004,
+    AdminUser2faRemoved = 9005,
+    AdminUserInviteResent = 9006,
+    AdminUserOrgTypeChanged = 9007,
+    AdminOrganizationDeleted = 9008,
+    AdminOrganizationLegalHoldEnabled = 9009,
+    AdminOrganizationLegalHoldDisabled = 9010,
+    AdminConfigUpdated = 9011,
+    AdminConfigDeleted = 9012,
+    AdminDatabaseBackedUp = 9013,
+    AdminTokensRevoked = 9014,
+    AdminJwtKeyRotated = 9015,
+    AdminOrganizationSeatLimitChanged = 9016,
+    AdminUserRevisionsUpdated = 9017,
+    AdminSmtpTested = 9018,
+    AdminTokenResetWithBreakGlass = 9019,
 }
 
 /// Local methods
@@ -144,6 +168,7 @@ impl Event {
             provider_uuid: None,
             provider_user_uuid: None,
             provider_org_uuid: None,
+            detail: None,
         }
     }
 
@@ -166,6 +191,7 @@ impl Event {
             "providerId": self.provider_uuid,
             "providerUserId": self.provider_user_uuid,
             "providerOrganizationId": self.provider_org_uuid,
+            "detail": self.detail,
             // "installationId": null, // Not supported
         })
     }
@@ -175,6 +201,8 @@ impl Event {
 /// https://github.com/bitwarden/server/blob/8a22c0479e987e756ce7412c48a732f9002f0a2d/src/Core/Services/Implementations/EventService.cs
 impl Event {
     pub const PAGE_SIZE: i64 = 30;
+    /// The Vaultwarden specific types of the admin panel actions
+    const ADMIN_EVENT_TYPES: (i32, i32) = (9000, 9099);
 
     /// #############
     /// Basic Queries
@@ -254,6 +282,21 @@ impl Event {
         db_run! { conn: {
             event::table
                 .filter(event::org_uuid.eq(org_uuid))
+                // The admin panel actions on the organization are only shown to the server admin
+                .filter(event::event_type.not_between(Self::ADMIN_EVENT_TYPES.0, Self::ADMIN_EVENT_TYPES.1))
+                .filter(event::event_date.between(start, end))
+                .order_by(event::event_date.desc())
+                .limit(Self::PAGE_SIZE)
+                .load::<EventDb>(conn)
+                .expect("Error filtering events")
+                .from_db()
+        }}
+    }
+
+    pub async fn find_admin_events(start: &NaiveDateTime, end: &NaiveDateTime, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            event::table
+                .filter(event::event_type.between(Self::ADMIN_EVENT_TYPES.0, Self::ADMIN_EVENT_TYPES.1))
                 .filter(event::event_date.between(start, end))
                 .order_by(event::event_date.desc())
                 .limit(Self::PAGE_SIZE)
@@ -139,6 +139,16 @@ impl Folder {
                 .from_db()
         }}
     }
+
+    pub async fn count_by_user(user_uuid: &str, conn: &mut DbConn) -> i64 {
+        db_run! { conn: {
+            folders::table
+                .filter(folders::user_uuid.eq(user_uuid))
+                .count()
+                .first(conn)
+                .unwrap_or(0)
+        }}
+    }
 }
 
 impl FolderCipher {
@@ -1,3 +1,5 @@
+use std::collections::{hash_map::Entry, HashMap};
+
 use chrono::{NaiveDateTime, Utc};
 use serde_json::Value;
 
@@ -100,9 +102,32 @@ impl Group {
             _ => None,
         };
     }
+
+    /// External IDs are unique within an organization, `found` is the group found by the external ID of this one
+    fn is_external_id_conflict(&self, found: Option<&Self>) -> bool {
+        found.is_some_and(|found| found.uuid != self.uuid)
+    }
 }
 
 impl CollectionGroup {
+    /// Merges the access a member gets to the same collection through multiple groups, the most permissive access wins
+    pub fn merge_by_collection(collection_groups: Vec<Self>) -> HashMap<String, Self> {
+        let mut merged: HashMap<String, Self> = HashMap::with_capacity(collection_groups.len());
+        for collection_group in collection_groups {
+            match merged.entry(collection_group.collections_uuid.clone()) {
+                Entry::Occupied(mut entry) => {
+                    let existing = entry.get_mut();
+                    existing.read_only &= collection_group.read_only;
+                    existing.hide_passwords &= collection_group.hide_passwords;
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(collection_group);
+                }
+            }
+        }
+        merged
+    }
+
     pub fn new(collections_uuid: String, groups_uuid: String, read_only: bool, hide_passwords: bool) -> Self {
         Self {
             collections_uuid,
@@ -213,6 +238,18 @@ impl Group {
                 .from_db()
         }}
     }
+
+    /// Whether another group of the organization already uses the external ID of this one
+    pub async fn is_external_id_in_use(&self, conn: &mut DbConn) -> bool {
+        match &self.external_id {
+            Some(external_id) => {
+                let found = Self::find_by_external_id_and_org(external_id, &self.organizations_uuid, conn).await;
+                self.is_external_id_conflict(found.as_ref())
+            }
+            None => false,
+        }
+    }
+
     //Returns all organizations the user has full access to
     pub async fn gather_user_organizations_full_access(user_uuid: &str, conn: &mut DbConn) -> Vec<String> {
         db_run! { conn: {
@@ -574,3 +611,38 @@ impl GroupUser {
         }}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_external_id_unique_in_org() {
+        let group = Group::new(String::from("org"), String::from("Sales"), false, Some(String::from("cn=sales")));
+        let other = Group::new(String::from("org"), String::from("Marketing"), false, Some(String::from("cn=sales")));
+        assert_eq!(group.external_id.as_deref(), Some("cn=sales"));
+
+        assert!(!group.is_external_id_conflict(None));
+        assert!(!group.is_external_id_conflict(Some(&group)));
+        assert!(group.is_external_id_conflict(Some(&other)));
+    }
+
+    #[test]
+    fn group_collection_access_most_permissive() {
+        let access = |group: &str, collection: &str, read_only, hide_passwords| {
+            CollectionGroup::new(String::from(collection), String::from(group), read_only, hide_passwords)
+        };
+        let merged = CollectionGroup::merge_by_collection(vec![
+            access("readers", "sales", true, true),
+            access("editors", "sales", false, true),
+            access("viewers", "sales", true, false),
+            access("readers", "hr", true, true),
+        ]);
+
+        assert_eq!(merged.len(), 2);
+        let sales = &merged["sales"];
+        assert!(!sales.read_only && !sales.hide_passwords);
+        let hr = &merged["hr"];
+        assert!(hr.read_only && hr.hide_passwords);
+    }
+}
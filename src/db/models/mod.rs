@@ -1,33 +1,58 @@
+mod access_log;
+mod access_schedule;
+mod account_inactivity;
+mod account_recovery_token;
 mod attachment;
 mod auth_request;
 mod cipher;
+mod cipher_idempotency_key;
+mod cipher_share;
+mod cipher_transfer;
 mod collection;
 mod device;
+mod device_approval;
 mod emergency_access;
 mod event;
 mod favorite;
 mod folder;
 mod group;
+mod notification_preference;
 mod org_policy;
 mod organization;
+mod personal_access_token;
 mod send;
 mod two_factor;
+mod two_factor_backup;
 mod two_factor_incomplete;
 mod user;
 
+pub use self::access_log::AccessLog;
+pub use self::access_schedule::AccessSchedule;
+pub use self::account_inactivity::{inactivity_action, AccountInactivity, InactivityAction};
+pub use self::account_recovery_token::AccountRecoveryToken;
 pub use self::attachment::Attachment;
 pub use self::auth_request::AuthRequest;
-pub use self::cipher::Cipher;
+pub use self::cipher::{Cipher, RepromptType};
+pub use self::cipher_idempotency_key::CipherIdempotencyKey;
+pub use self::cipher_share::CipherShare;
+pub use self::cipher_transfer::CipherTransfer;
 pub use self::collection::{Collection, CollectionCipher, CollectionUser};
-pub use self::device::{Device, DeviceType};
-pub use self::emergency_access::{EmergencyAccess, EmergencyAccessStatus, EmergencyAccessType};
+pub use self::device::{Device, DeviceType, TwoFactorRemember};
+pub use self::device_approval::DeviceApproval;
+pub use self::emergency_access::{EmergencyAccess, EmergencyAccessQuorum, EmergencyAccessStatus, EmergencyAccessType};
 pub use self::event::{Event, EventType};
 pub use self::favorite::Favorite;
 pub use self::folder::{Folder, FolderCipher};
 pub use self::group::{CollectionGroup, Group, GroupUser};
-pub use self::org_policy::{OrgPolicy, OrgPolicyErr, OrgPolicyType};
+pub use self::notification_preference::{NotificationPreference, UserNotification};
+pub use self::org_policy::{
+    is_cipher_type_allowed, is_email_verified_for_policy, is_reprompt_enforced, satisfies_2fa_policy,
+    satisfies_email_verification_policy, OrgPolicy, OrgPolicyErr, OrgPolicyType,
+};
 pub use self::organization::{Organization, OrganizationApiKey, UserOrgStatus, UserOrgType, UserOrganization};
+pub use self::personal_access_token::PersonalAccessToken;
 pub use self::send::{Send, SendType};
 pub use self::two_factor::{TwoFactor, TwoFactorType};
+pub use self::two_factor_backup::TwoFactorBackup;
 pub use self::two_factor_incomplete::TwoFactorIncomplete;
-pub use self::user::{Invitation, User, UserKdfType, UserStampException};
+pub use self::user::{normalize_email, Invitation, UnverifiedAccountAction, User, UserKdfType, UserStampException};
@@ -0,0 +1,145 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use crate::{api::EmptyResult, db::DbConn, error::MapResult};
+
+db_object! {
+    // Which login and security related emails a user wants to receive.
+    // Users without a stored row receive all notifications.
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = notification_preferences)]
+    #[diesel(primary_key(user_uuid))]
+    pub struct NotificationPreference {
+        pub user_uuid: String,
+        pub new_device_login: bool,
+        pub incomplete_2fa_login: bool,
+        pub emergency_access: bool,
+        pub updated_at: NaiveDateTime,
+        pub key_rotation_reminder: bool,
+        // When the last key rotation reminder was sent, not a preference
+        pub key_rotation_reminded_at: Option<NaiveDateTime>,
+    }
+}
+
+// Account recovery notices, like emergency access recoveries and admin password resets, are always sent
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UserNotification {
+    NewDeviceLogin,
+    Incomplete2faLogin,
+    EmergencyAccess,
+    KeyRotationReminder,
+}
+
+/// Local methods
+impl NotificationPreference {
+    pub fn new(user_uuid: String) -> Self {
+        Self {
+            user_uuid,
+            new_device_login: true,
+            incomplete_2fa_login: true,
+            emergency_access: true,
+            updated_at: Utc::now().naive_utc(),
+            key_rotation_reminder: true,
+            key_rotation_reminded_at: None,
+        }
+    }
+
+    pub fn allows(&self, notification: UserNotification) -> bool {
+        match notification {
+            UserNotification::NewDeviceLogin => self.new_device_login,
+            UserNotification::Incomplete2faLogin => self.incomplete_2fa_login,
+            UserNotification::EmergencyAccess => self.emergency_access,
+            UserNotification::KeyRotationReminder => self.key_rotation_reminder,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "NewDeviceLogin": self.new_device_login,
+            "Incomplete2faLogin": self.incomplete_2fa_login,
+            "EmergencyAccess": self.emergency_access,
+            "KeyRotationReminder": self.key_rotation_reminder,
+            "Object": "notificationPreferences",
+        })
+    }
+}
+
+/// Database methods
+impl NotificationPreference {
+    pub async fn save(&mut self, conn: &mut DbConn) -> EmptyResult {
+        self.updated_at = Utc::now().naive_utc();
+
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(notification_preferences::table)
+                    .values(NotificationPreferenceDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving notification preferences")
+            }
+            postgresql {
+                let value = NotificationPreferenceDb::to_db(self);
+                diesel::insert_into(notification_preferences::table)
+                    .values(&value)
+                    .on_conflict(notification_preferences::user_uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving notification preferences")
+            }
+        }
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(notification_preferences::table.filter(notification_preferences::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting notification preferences")
+        }}
+    }
+
+    pub async fn find_by_user(user_uuid: &str, conn: &mut DbConn) -> Option<Self> {
+        db_run! { conn: {
+            notification_preferences::table
+                .filter(notification_preferences::user_uuid.eq(user_uuid))
+                .first::<NotificationPreferenceDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    /// Returns the stored preferences of the user, or the defaults when none are stored
+    pub async fn find_or_default(user_uuid: &str, conn: &mut DbConn) -> Self {
+        match Self::find_by_user(user_uuid, conn).await {
+            Some(prefs) => prefs,
+            None => Self::new(String::from(user_uuid)),
+        }
+    }
+
+    pub async fn is_enabled(user_uuid: &str, notification: UserNotification, conn: &mut DbConn) -> bool {
+        Self::find_or_default(user_uuid, conn).await.allows(notification)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notification_defaults_enabled() {
+        let prefs = NotificationPreference::new(String::from("user"));
+        assert!(prefs.allows(UserNotification::NewDeviceLogin));
+        assert!(prefs.allows(UserNotification::Incomplete2faLogin));
+        assert!(prefs.allows(UserNotification::EmergencyAccess));
+    }
+
+    #[test]
+    fn notification_disabled_is_suppressed() {
+        let mut prefs = NotificationPreference::new(String::from("user"));
+        prefs.new_device_login = false;
+        prefs.incomplete_2fa_login = false;
+
+        assert!(!prefs.allows(UserNotification::NewDeviceLogin));
+        assert!(!prefs.allows(UserNotification::Incomplete2faLogin));
+        assert!(prefs.allows(UserNotification::EmergencyAccess));
+    }
+}
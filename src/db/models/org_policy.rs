@@ -1,3 +1,4 @@
+use chrono::NaiveDateTime;
 use serde::Deserialize;
 use serde_json::Value;
 
@@ -6,7 +7,7 @@ use crate::db::DbConn;
 use crate::error::MapResult;
 use crate::util::UpCase;
 
-use super::{TwoFactor, UserOrgStatus, UserOrgType, UserOrganization};
+use super::{TwoFactor, User, UserOrgStatus, UserOrgType, UserOrganization};
 
 db_object! {
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
@@ -35,6 +36,12 @@ pub enum OrgPolicyType {
     ResetPassword = 8,
     // MaximumVaultTimeout = 9, // Not supported (Not AGPLv3 Licensed)
     // DisablePersonalVaultExport = 10, // Not supported (Not AGPLv3 Licensed)
+    AllowedCipherTypes = 100,       // Vaultwarden specific
+    EnforcedReprompt = 101,         // Vaultwarden specific
+    DeviceApproval = 102,           // Vaultwarden specific
+    TransferApproval = 103,         // Vaultwarden specific
+    DisablePasswordHint = 104,      // Vaultwarden specific
+    RequireEmailVerification = 105, // Vaultwarden specific, only enforced when mail is enabled
 }
 
 // https://github.com/bitwarden/server/blob/5cbdee137921a19b1f722920f0fa3cd45af2ef0f/src/Core/Models/Data/Organizations/Policies/SendOptionsPolicyData.cs
@@ -51,12 +58,85 @@ pub struct ResetPasswordDataModel {
     pub AutoEnrollEnabled: bool,
 }
 
+// Vaultwarden specific: restricts which two-step login methods satisfy the two-step login policy,
+// e.g. `{"AllowedMethods": [7]}` to only accept WebAuthn. Without it every method is accepted.
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+pub struct TwoFactorPolicyDataModel {
+    pub AllowedMethods: Option<Vec<i32>>,
+}
+
+// Vaultwarden specific: restricts the item types members can create in the organization,
+// e.g. `{"AllowedTypes": [1]}` to only allow logins. Owners and admins are not restricted.
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+pub struct AllowedCipherTypesPolicyData {
+    pub AllowedTypes: Option<Vec<i32>>,
+}
+
+// Vaultwarden specific: requires the master password to be entered again before showing the organization items
+// of these types, e.g. `{"CipherTypes": [1]}` for logins only. Without it every item type is affected.
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+pub struct EnforcedRepromptPolicyData {
+    pub CipherTypes: Option<Vec<i32>>,
+}
+
 pub type OrgPolicyResult = Result<(), OrgPolicyErr>;
 
 #[derive(Debug)]
 pub enum OrgPolicyErr {
     TwoFactorMissing,
+    // The two-step login methods of the user are not accepted by the two-step login policy of this organization
+    TwoFactorMethodNotAllowed,
+    // Another organization of the user enforces the single organization policy
     SingleOrgEnforced,
+    // This organization enforces the single organization policy, but the user is a member of other organizations
+    SingleOrgOtherMemberships,
+}
+
+/// The single organization policy semantics, for a member who isn't an owner or admin of the organization they join.
+fn check_single_org(org_enforced: bool, other_memberships: usize, other_org_enforced: bool) -> OrgPolicyResult {
+    if other_org_enforced {
+        return Err(OrgPolicyErr::SingleOrgEnforced);
+    }
+    if org_enforced && other_memberships > 0 {
+        return Err(OrgPolicyErr::SingleOrgOtherMemberships);
+    }
+    Ok(())
+}
+
+/// Whether the enabled two-step login methods of a user satisfy a two-step login policy.
+/// `allowed` is `None` when the policy accepts every method.
+pub fn satisfies_2fa_policy(user_methods: &[i32], allowed: Option<&[i32]>) -> bool {
+    match allowed {
+        Some(allowed) => user_methods.iter().any(|m| allowed.contains(m)),
+        None => !user_methods.is_empty(),
+    }
+}
+
+/// Whether a cipher type can be created with an allowed cipher types policy.
+/// `allowed` is `None` when the policy allows every type.
+pub fn is_cipher_type_allowed(cipher_type: i32, allowed: Option<&[i32]>) -> bool {
+    allowed.map_or(true, |allowed| allowed.contains(&cipher_type))
+}
+
+/// Whether an enforced reprompt policy applies to a cipher type.
+/// `enforced` is `None` when the policy applies to every type.
+pub fn is_reprompt_enforced(cipher_type: i32, enforced: Option<&[i32]>) -> bool {
+    enforced.map_or(true, |enforced| enforced.contains(&cipher_type))
+}
+
+/// Whether the email of a user counts as verified for the email verification policy.
+/// Without mail nobody could verify their address, so the policy isn't enforced then.
+pub fn is_email_verified_for_policy(verified_at: Option<NaiveDateTime>, mail_enabled: bool) -> bool {
+    verified_at.is_some() || !mail_enabled
+}
+
+/// Whether a member can access an organization with the email verification policy.
+/// Owners and admins aren't restricted, so they can't lock themselves out.
+pub fn satisfies_email_verification_policy(member_type: i32, email_verified: bool) -> bool {
+    email_verified || member_type >= UserOrgType::Admin
 }
 
 /// Local methods
@@ -275,6 +355,56 @@ impl OrgPolicy {
         false
     }
 
+    /// Returns true if the user isn't allowed to have a master password hint, because they are a non-revoked member
+    /// of an org which enabled the `DisablePasswordHint` policy and not an owner or admin of it.
+    /// Pending invitations count as well, so the hint is already rejected when registering via one.
+    pub async fn is_password_hint_disabled(user_uuid: &str, conn: &mut DbConn) -> bool {
+        for member in UserOrganization::find_any_state_by_user(user_uuid, conn).await {
+            if member.atype < UserOrgType::Admin
+                && member.status != UserOrgStatus::Revoked as i32
+                && matches!(
+                    Self::find_by_org_and_type(&member.org_uuid, OrgPolicyType::DisablePasswordHint, conn).await,
+                    Some(p) if p.enabled
+                )
+            {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Removes the master password hint of the user when it isn't allowed anymore, after they joined an organization
+    /// with the `DisablePasswordHint` policy, were restored in one or lost their admin rights
+    pub async fn remove_disabled_password_hint(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        if !Self::is_password_hint_disabled(user_uuid, conn).await {
+            return Ok(());
+        }
+        match User::find_by_uuid(user_uuid, conn).await {
+            Some(mut user) if user.password_hint.take().is_some() => user.save(conn).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// The organizations which have to approve new devices of the user before they can be used
+    pub async fn find_device_approval_org_uuids(user_uuid: &str, conn: &mut DbConn) -> Vec<String> {
+        let mut org_uuids = Vec::new();
+        for policy in OrgPolicy::find_accepted_and_confirmed_by_user_and_active_policy(
+            user_uuid,
+            OrgPolicyType::DeviceApproval,
+            conn,
+        )
+        .await
+        {
+            // Owners and admins approve the devices, so they are not restricted themselves
+            if let Some(user) = UserOrganization::find_by_user_and_org(user_uuid, &policy.org_uuid, conn).await {
+                if user.atype < UserOrgType::Admin {
+                    org_uuids.push(policy.org_uuid);
+                }
+            }
+        }
+        org_uuids
+    }
+
     pub async fn is_user_allowed(
         user_uuid: &str,
         org_uuid: &str,
@@ -282,14 +412,18 @@ impl OrgPolicy {
         conn: &mut DbConn,
     ) -> OrgPolicyResult {
         // Enforce TwoFactor/TwoStep login
-        if TwoFactor::find_by_user(user_uuid, conn).await.is_empty() {
-            match Self::find_by_org_and_type(org_uuid, OrgPolicyType::TwoFactorAuthentication, conn).await {
-                Some(p) if p.enabled => {
+        match Self::find_by_org_and_type(org_uuid, OrgPolicyType::TwoFactorAuthentication, conn).await {
+            Some(p) if p.enabled => {
+                let user_methods = TwoFactor::find_enabled_types_by_user(user_uuid, conn).await;
+                if user_methods.is_empty() {
                     return Err(OrgPolicyErr::TwoFactorMissing);
                 }
-                _ => {}
-            };
-        }
+                if !satisfies_2fa_policy(&user_methods, p.allowed_2fa_methods().as_deref()) {
+                    return Err(OrgPolicyErr::TwoFactorMethodNotAllowed);
+                }
+            }
+            _ => {}
+        };
 
         // Enforce Single Organization Policy of other organizations user is a member of
         // This check here needs to exclude this current org-id, else an accepted user can not be confirmed.
@@ -298,11 +432,70 @@ impl OrgPolicy {
         } else {
             None
         };
-        if Self::is_applicable_to_user(user_uuid, OrgPolicyType::SingleOrg, exclude_org, conn).await {
-            return Err(OrgPolicyErr::SingleOrgEnforced);
+        let other_org_enforced =
+            Self::is_applicable_to_user(user_uuid, OrgPolicyType::SingleOrg, exclude_org, conn).await;
+
+        // Enforce Single Organization Policy of this organization, the user can't be a member of other organizations
+        let org_enforced = matches!(
+            Self::find_by_org_and_type(org_uuid, OrgPolicyType::SingleOrg, conn).await,
+            Some(p) if p.enabled
+        );
+        let other_memberships = if org_enforced {
+            UserOrganization::find_any_state_by_user(user_uuid, conn)
+                .await
+                .iter()
+                .filter(|uo| {
+                    uo.org_uuid != org_uuid
+                        && (uo.status == UserOrgStatus::Accepted as i32 || uo.status == UserOrgStatus::Confirmed as i32)
+                })
+                .count()
+        } else {
+            0
+        };
+
+        check_single_org(org_enforced, other_memberships, other_org_enforced)
+    }
+
+    /// Returns the two-step login methods accepted by this two-step login policy, or `None` if all methods are.
+    pub fn allowed_2fa_methods(&self) -> Option<Vec<i32>> {
+        if self.atype != OrgPolicyType::TwoFactorAuthentication as i32 {
+            return None;
+        }
+        match serde_json::from_str::<Option<UpCase<TwoFactorPolicyDataModel>>>(&self.data) {
+            Ok(opts) => opts.and_then(|o| o.data.AllowedMethods).filter(|m| !m.is_empty()),
+            Err(_) => {
+                error!("Failed to deserialize TwoFactorPolicyDataModel: {}", self.data);
+                None
+            }
         }
+    }
 
-        Ok(())
+    /// Returns the cipher types allowed by this allowed cipher types policy, or `None` if all types are.
+    pub fn allowed_cipher_types(&self) -> Option<Vec<i32>> {
+        if self.atype != OrgPolicyType::AllowedCipherTypes as i32 {
+            return None;
+        }
+        match serde_json::from_str::<Option<UpCase<AllowedCipherTypesPolicyData>>>(&self.data) {
+            Ok(opts) => opts.and_then(|o| o.data.AllowedTypes),
+            Err(_) => {
+                error!("Failed to deserialize AllowedCipherTypesPolicyData: {}", self.data);
+                None
+            }
+        }
+    }
+
+    /// Returns the cipher types affected by this enforced reprompt policy, or `None` if all types are.
+    pub fn reprompt_cipher_types(&self) -> Option<Vec<i32>> {
+        if self.atype != OrgPolicyType::EnforcedReprompt as i32 {
+            return None;
+        }
+        match serde_json::from_str::<Option<UpCase<EnforcedRepromptPolicyData>>>(&self.data) {
+            Ok(opts) => opts.and_then(|o| o.data.CipherTypes),
+            Err(_) => {
+                error!("Failed to deserialize EnforcedRepromptPolicyData: {}", self.data);
+                None
+            }
+        }
     }
 
     pub async fn org_is_reset_password_auto_enroll(org_uuid: &str, conn: &mut DbConn) -> bool {
@@ -348,3 +541,153 @@ impl OrgPolicy {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::TwoFactorType;
+
+    #[test]
+    fn single_org_allowed() {
+        assert!(check_single_org(false, 0, false).is_ok());
+        assert!(check_single_org(false, 3, false).is_ok());
+        // The joining organization enforces it, but the user has no other memberships
+        assert!(check_single_org(true, 0, false).is_ok());
+    }
+
+    #[test]
+    fn single_org_blocked() {
+        // Already a member of an organization which enforces it
+        assert!(matches!(check_single_org(false, 1, true), Err(OrgPolicyErr::SingleOrgEnforced)));
+        assert!(matches!(check_single_org(true, 1, true), Err(OrgPolicyErr::SingleOrgEnforced)));
+        // The joining organization enforces it and the user is a member of other organizations
+        assert!(matches!(check_single_org(true, 2, false), Err(OrgPolicyErr::SingleOrgOtherMemberships)));
+    }
+
+    fn two_factor_policy(data: &str) -> OrgPolicy {
+        OrgPolicy::new(String::from("org"), OrgPolicyType::TwoFactorAuthentication, data.to_string())
+    }
+
+    #[test]
+    fn two_factor_policy_allowed_methods() {
+        assert_eq!(two_factor_policy("null").allowed_2fa_methods(), None);
+        assert_eq!(two_factor_policy("{}").allowed_2fa_methods(), None);
+        assert_eq!(two_factor_policy(r#"{"AllowedMethods":[]}"#).allowed_2fa_methods(), None);
+        assert_eq!(two_factor_policy(r#"{"allowedMethods":[7,3]}"#).allowed_2fa_methods(), Some(vec![7, 3]));
+    }
+
+    #[test]
+    fn two_factor_method_insufficient() {
+        let webauthn_only = [TwoFactorType::Webauthn as i32];
+        // Only email two-step login, but the organization requires WebAuthn
+        assert!(!satisfies_2fa_policy(&[TwoFactorType::Email as i32], Some(&webauthn_only)));
+        assert!(!satisfies_2fa_policy(&[], Some(&webauthn_only)));
+        assert!(!satisfies_2fa_policy(&[], None));
+    }
+
+    #[test]
+    fn two_factor_method_sufficient() {
+        let webauthn_only = [TwoFactorType::Webauthn as i32];
+        let user_methods = [TwoFactorType::Email as i32, TwoFactorType::Webauthn as i32];
+        assert!(satisfies_2fa_policy(&user_methods, Some(&webauthn_only)));
+        assert!(satisfies_2fa_policy(&[TwoFactorType::Email as i32], None));
+    }
+
+    fn cipher_types_policy(data: &str) -> OrgPolicy {
+        OrgPolicy::new(String::from("org"), OrgPolicyType::AllowedCipherTypes, data.to_string())
+    }
+
+    #[test]
+    fn cipher_types_policy_allowed_types() {
+        assert_eq!(cipher_types_policy("null").allowed_cipher_types(), None);
+        assert_eq!(cipher_types_policy("{}").allowed_cipher_types(), None);
+        assert_eq!(cipher_types_policy(r#"{"allowedTypes":[1,2]}"#).allowed_cipher_types(), Some(vec![1, 2]));
+        // An empty list doesn't allow any type to be created
+        assert_eq!(cipher_types_policy(r#"{"AllowedTypes":[]}"#).allowed_cipher_types(), Some(vec![]));
+    }
+
+    #[test]
+    fn cipher_type_disallowed() {
+        // Only logins are allowed, cards and identities are rejected
+        let policy = cipher_types_policy(r#"{"AllowedTypes":[1]}"#);
+        assert!(!is_cipher_type_allowed(3, policy.allowed_cipher_types().as_deref()));
+        assert!(!is_cipher_type_allowed(4, policy.allowed_cipher_types().as_deref()));
+        assert!(!is_cipher_type_allowed(1, Some(&[])));
+    }
+
+    #[test]
+    fn cipher_type_allowed() {
+        let policy = cipher_types_policy(r#"{"AllowedTypes":[1]}"#);
+        assert!(is_cipher_type_allowed(1, policy.allowed_cipher_types().as_deref()));
+        assert!(is_cipher_type_allowed(3, cipher_types_policy("null").allowed_cipher_types().as_deref()));
+    }
+
+    #[test]
+    fn unverified_member_denied_by_email_verification_policy() {
+        assert!(!satisfies_email_verification_policy(UserOrgType::User as i32, false));
+        assert!(!satisfies_email_verification_policy(UserOrgType::Manager as i32, false));
+        assert!(satisfies_email_verification_policy(UserOrgType::User as i32, true));
+        // Owners and admins keep access to the organization
+        assert!(satisfies_email_verification_policy(UserOrgType::Admin as i32, false));
+        assert!(satisfies_email_verification_policy(UserOrgType::Owner as i32, false));
+    }
+
+    #[test]
+    #[cfg(sqlite)]
+    fn password_hint_disabled_for_members_of_policy_org() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut conn = DbConn::sqlite_in_memory();
+            conn.batch_execute(
+                "INSERT INTO users_organizations (uuid, user_uuid, org_uuid, access_all, akey, status, atype) VALUES \
+                ('m1', 'invited', 'org', 0, '', 0, 2), ('m2', 'member', 'org', 0, '', 2, 2), \
+                ('m3', 'admin', 'org', 0, '', 2, 1), ('m4', 'revoked', 'org', 0, '', -1, 2), \
+                ('m5', 'other', 'other-org', 0, '', 2, 2); \
+                INSERT INTO org_policies (uuid, org_uuid, atype, enabled, data) VALUES ('policy', 'org', 104, 1, '{}')",
+            )
+            .await;
+
+            assert!(OrgPolicy::is_password_hint_disabled("invited", &mut conn).await);
+            assert!(OrgPolicy::is_password_hint_disabled("member", &mut conn).await);
+            assert!(!OrgPolicy::is_password_hint_disabled("admin", &mut conn).await);
+            assert!(!OrgPolicy::is_password_hint_disabled("revoked", &mut conn).await);
+            assert!(!OrgPolicy::is_password_hint_disabled("other", &mut conn).await);
+
+            // Demoted or restored members lose their hint
+            for uuid in ["member", "admin"] {
+                conn.insert_user(uuid).await;
+            }
+            conn.batch_execute("UPDATE users SET password_hint = 'hint'").await;
+            OrgPolicy::remove_disabled_password_hint("member", &mut conn).await.unwrap();
+            OrgPolicy::remove_disabled_password_hint("admin", &mut conn).await.unwrap();
+            assert_eq!(User::find_by_uuid("member", &mut conn).await.unwrap().password_hint, None);
+            assert!(User::find_by_uuid("admin", &mut conn).await.unwrap().password_hint.is_some());
+        });
+    }
+
+    #[test]
+    fn email_verification_policy_needs_mail() {
+        assert!(!is_email_verified_for_policy(None, true));
+        assert!(is_email_verified_for_policy(Some(chrono::Utc::now().naive_utc()), true));
+        // Without verification emails nobody could satisfy the policy
+        assert!(is_email_verified_for_policy(None, false));
+    }
+
+    #[test]
+    #[cfg(sqlite)]
+    fn email_verification_policy_enforced_without_signups_verify() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut conn = DbConn::sqlite_in_memory();
+            // Created without SIGNUPS_VERIFY, the address was never verified with the verify email flow either
+            conn.insert_user("member").await;
+            let user = User::find_by_uuid("member", &mut conn).await.unwrap();
+            assert!(user.verified_at.is_none());
+            let email_verified = is_email_verified_for_policy(user.verified_at, true);
+            assert!(!satisfies_email_verification_policy(UserOrgType::User as i32, email_verified));
+
+            conn.batch_execute("UPDATE users SET verified_at = '2024-01-01 00:00:00' WHERE uuid = 'member'").await;
+            let user = User::find_by_uuid("member", &mut conn).await.unwrap();
+            let email_verified = is_email_verified_for_policy(user.verified_at, true);
+            assert!(satisfies_email_verification_policy(UserOrgType::User as i32, email_verified));
+        });
+    }
+}
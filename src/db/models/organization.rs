@@ -1,14 +1,20 @@
-use chrono::{NaiveDateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
 use num_traits::FromPrimitive;
 use serde_json::Value;
 use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 
-use super::{CollectionUser, Group, GroupUser, OrgPolicy, OrgPolicyType, TwoFactor, User};
+use super::{
+    is_email_verified_for_policy, satisfies_email_verification_policy, AccessSchedule, CipherTransfer, CollectionUser,
+    Group, GroupUser, OrgPolicy, OrgPolicyType, TwoFactor, User,
+};
 use crate::CONFIG;
 
 db_object! {
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
     #[diesel(table_name = organizations)]
+    #[diesel(treat_none_as_null = true)]
     #[diesel(primary_key(uuid))]
     pub struct Organization {
         pub uuid: String,
@@ -16,6 +22,17 @@ db_object! {
         pub billing_email: String,
         pub private_key: Option<String>,
         pub public_key: Option<String>,
+        // Maximum number of members, including pending invitations. None means unlimited
+        pub seat_limit: Option<i32>,
+        // Ciphers of an organization under legal hold can be trashed and restored, but never permanently deleted
+        pub legal_hold: bool,
+        // Shown by the clients instead of the organization name and default icon
+        pub branding_name: Option<String>,
+        pub branding_logo_url: Option<String>,
+        // Uploaded logo, stored in the attachments folder. Takes precedence over the logo URL
+        pub branding_logo_file: Option<String>,
+        // Comma separated email domains invited users must have to accept an invitation. None allows any domain
+        pub invite_domains: Option<String>,
     }
 
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
@@ -32,6 +49,12 @@ db_object! {
         pub atype: i32,
         pub reset_password_key: Option<String>,
         pub external_id: Option<String>,
+        // JSON encoded AccessSchedule, None means access at any time
+        pub access_schedule: Option<String>,
+        // When the membership was created or last re-invited, used for ORG_INVITE_EXPIRY_DAYS
+        pub invited_at: Option<NaiveDateTime>,
+        // When the member accepted the invitation, used for ORG_2FA_GRACE_PERIOD_DAYS
+        pub accepted_at: Option<NaiveDateTime>,
     }
 
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
@@ -46,6 +69,9 @@ db_object! {
     }
 }
 
+pub const BRANDING_NAME_MAX_LENGTH: usize = 100;
+pub const INVITE_DOMAINS_MAX: usize = 50;
+
 // https://github.com/bitwarden/server/blob/b86a04cef9f1e1b82cf18e49fc94e017c641130c/src/Core/Enums/OrganizationUserStatusType.cs
 pub enum UserOrgStatus {
     Revoked = -1,
@@ -148,8 +174,114 @@ impl Organization {
             billing_email,
             private_key,
             public_key,
+            seat_limit: None,
+            legal_hold: false,
+            branding_name: None,
+            branding_logo_url: None,
+            branding_logo_file: None,
+            invite_domains: None,
+        }
+    }
+
+    /// Whether `requested` more members fit within the seat limit, when `used` seats are taken already
+    pub fn has_seats_available(&self, used: i64, requested: i64) -> bool {
+        match self.seat_limit {
+            Some(limit) => used + requested <= i64::from(limit),
+            None => true,
+        }
+    }
+
+    /// Sets the branding shown by the clients, empty values remove it
+    pub fn set_branding(&mut self, display_name: Option<String>, logo_url: Option<String>) -> EmptyResult {
+        let display_name = display_name.map(|n| n.trim().to_string()).filter(|n| !n.is_empty());
+        if display_name.as_ref().is_some_and(|n| n.chars().count() > BRANDING_NAME_MAX_LENGTH) {
+            err!(format!("The display name can't be longer than {BRANDING_NAME_MAX_LENGTH} characters"))
+        }
+
+        let logo_url = logo_url.map(|u| u.trim().to_string()).filter(|u| !u.is_empty());
+        if let Some(ref logo_url) = logo_url {
+            match url::Url::parse(logo_url) {
+                Ok(url) if url.scheme() == "https" && url.host().is_some() => (),
+                _ => err!("The logo URL must be a valid https URL"),
+            }
+        }
+
+        self.branding_name = display_name;
+        self.branding_logo_url = logo_url;
+        Ok(())
+    }
+
+    pub fn branding_logo_folder(&self) -> PathBuf {
+        Path::new(&CONFIG.attachments_folder()).join("branding").join(&self.uuid)
+    }
+
+    /// Removes a logo file which was replaced or removed, to be called once the change is saved
+    pub fn remove_branding_logo_file(&self, file: Option<String>) {
+        if let Some(file) = file {
+            std::fs::remove_file(self.branding_logo_folder().join(file)).ok();
+        }
+    }
+
+    pub fn branding_json(&self, host: &str) -> Value {
+        let logo_url = match self.branding_logo_file {
+            Some(ref file) => Some(format!("{host}/api/organizations/{}/branding/logo/{file}", self.uuid)),
+            None => self.branding_logo_url.clone(),
+        };
+        json!({
+            "DisplayName": self.branding_name,
+            "LogoUrl": logo_url,
+            "Object": "organizationBranding",
+        })
+    }
+
+    /// Restricts accepting invitations to users with an email address of one of `domains`, no domains remove the restriction
+    pub fn set_invite_domains(&mut self, domains: Vec<String>) -> EmptyResult {
+        let mut invite_domains: Vec<String> = Vec::new();
+        for domain in domains {
+            let domain = domain.trim().trim_start_matches('@').to_lowercase();
+            if domain.is_empty() {
+                continue;
+            }
+            if !domain.contains('.') || domain.contains(|c: char| c == '@' || c == ',' || c.is_whitespace()) {
+                err!(format!("'{domain}' is not a valid email domain"))
+            }
+            if !invite_domains.contains(&domain) {
+                invite_domains.push(domain);
+            }
+        }
+        if invite_domains.len() > INVITE_DOMAINS_MAX {
+            err!(format!("An organization can't restrict invitations to more than {INVITE_DOMAINS_MAX} domains"))
+        }
+
+        self.invite_domains = Some(invite_domains.join(",")).filter(|d| !d.is_empty());
+        Ok(())
+    }
+
+    /// Fails when a user with `email` can't accept an invitation to this organization.
+    /// With restricted domains the email address has to be verified as well, unless it can't be (mail disabled).
+    pub fn check_invite_domain(&self, email: &str, email_verified: bool) -> EmptyResult {
+        let Some(ref invite_domains) = self.invite_domains else {
+            return Ok(());
+        };
+
+        let email_domain = email.rsplit_once('@').map(|(_, domain)| domain.to_lowercase()).unwrap_or_default();
+        if !invite_domains.split(',').any(|domain| domain == email_domain) {
+            err!("This organization only accepts members with an email address of its own domains")
+        }
+        if !email_verified {
+            err!("Verify your email address before accepting this invitation")
         }
+        Ok(())
+    }
+
+    pub fn invite_domains_json(&self) -> Value {
+        let domains: Vec<&str> = self.invite_domains.as_deref().map(|d| d.split(',').collect()).unwrap_or_default();
+        json!({
+            "Domains": domains,
+            "Object": "organizationInviteDomains",
+        })
     }
+
     // https://github.com/bitwarden/server/blob/13d1e74d6960cf0d042620b72d85bf583a4236f7/src/Api/Models/Response/Organizations/OrganizationResponseModel.cs
     pub fn to_json(&self) -> Value {
         json!({
@@ -210,9 +342,75 @@ impl UserOrganization {
             atype: UserOrgType::User as i32,
             reset_password_key: None,
             external_id: None,
+            access_schedule: None,
+            invited_at: Some(Utc::now().naive_utc()),
+            accepted_at: None,
+        }
+    }
+
+    pub fn accept(&mut self, now: NaiveDateTime) {
+        self.status = UserOrgStatus::Accepted as i32;
+        self.accepted_at = Some(now);
+    }
+
+    /// Whether the member still has time to enroll the two-step login required by the organization.
+    /// The grace period starts when the invitation is accepted, members who joined before don't get one.
+    pub fn in_2fa_grace_period(&self, now: NaiveDateTime, grace_days: i64) -> bool {
+        match (self.accepted_at, TimeDelta::try_days(grace_days)) {
+            (Some(accepted_at), Some(grace)) if grace_days > 0 => now < accepted_at + grace,
+            _ => false,
+        }
+    }
+
+    /// Invitations sent before the returned moment are expired, None means invitations never expire
+    pub fn invite_expiry_cutoff(now: NaiveDateTime, expiry_days: Option<i64>) -> Option<NaiveDateTime> {
+        now.checked_sub_signed(TimeDelta::try_days(expiry_days?)?)
+    }
+
+    pub fn is_invite_expired(&self, cutoff: Option<NaiveDateTime>) -> bool {
+        match (cutoff, self.invited_at) {
+            (Some(cutoff), Some(invited_at)) => self.status == UserOrgStatus::Invited as i32 && invited_at < cutoff,
+            _ => false,
+        }
+    }
+
+    /// Whether the access schedule of this membership allows access to the organization at the given time.
+    /// Owners and admins aren't restricted, so they can't lock themselves out.
+    pub fn is_within_access_schedule(&self, now: DateTime<Utc>) -> bool {
+        if self.atype >= UserOrgType::Admin {
+            return true;
+        }
+        match self.access_schedule {
+            Some(ref schedule) => match serde_json::from_str::<AccessSchedule>(schedule) {
+                Ok(schedule) => schedule.allows(now),
+                Err(_) => {
+                    error!("Failed to deserialize the access schedule of membership {}", self.uuid);
+                    false
+                }
+            },
+            None => true,
         }
     }
 
+    /// The organizations of these memberships which can't be accessed at the given time, or because the email
+    /// address isn't verified while the organization's policy requires it (`verification_required`)
+    pub fn restricted_org_uuids(
+        memberships: &[Self],
+        now: DateTime<Utc>,
+        email_verified: bool,
+        verification_required: &HashSet<String>,
+    ) -> HashSet<String> {
+        memberships
+            .iter()
+            .filter(|uo| {
+                !uo.is_within_access_schedule(now)
+                    || (verification_required.contains(&uo.org_uuid)
+                        && !satisfies_email_verification_policy(uo.atype, email_verified))
+            })
+            .map(|uo| uo.org_uuid.clone())
+            .collect()
+    }
+
     pub fn restore(&mut self) -> bool {
         if self.status < UserOrgStatus::Invited as i32 {
             self.status += ACTIVATE_REVOKE_DIFF;
@@ -229,6 +427,11 @@ impl UserOrganization {
         false
     }
 
+    /// Pending invitations count towards the seat limit of the organization, revoked members don't
+    pub fn occupies_seat(&self) -> bool {
+        self.status >= UserOrgStatus::Invited as i32
+    }
+
     pub fn set_external_id(&mut self, external_id: Option<String>) -> bool {
         //Check if external id is empty. We don't want to have
         //empty strings in the database
@@ -243,6 +446,14 @@ impl UserOrganization {
     }
 }
 
+// Hashed API keys are stored with this prefix, keys stored before hashing was introduced are plaintext
+const HASHED_API_KEY_PREFIX: &str = "sha256:";
+
+fn hash_api_key(api_key: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, api_key.as_bytes());
+    format!("{HASHED_API_KEY_PREFIX}{}", data_encoding::HEXLOWER.encode(digest.as_ref()))
+}
+
 impl OrganizationApiKey {
     pub fn new(org_uuid: String, api_key: String) -> Self {
         Self {
@@ -250,13 +461,28 @@ impl OrganizationApiKey {
 
             org_uuid,
             atype: 0, // Type 0 is the default and only type we support currently
-            api_key,
+            api_key: hash_api_key(&api_key),
             revision_date: Utc::now().naive_utc(),
         }
     }
 
+    /// Replaces the API key, only the hash of the new key is stored so the previous one stops working
+    pub fn set_api_key(&mut self, api_key: &str) {
+        self.api_key = hash_api_key(api_key);
+        self.revision_date = Utc::now().naive_utc();
+    }
+
+    /// Returns true if the API key is stored hashed, false for a legacy plaintext API key
+    pub fn is_hashed(&self) -> bool {
+        self.api_key.starts_with(HASHED_API_KEY_PREFIX)
+    }
+
     pub fn check_valid_api_key(&self, api_key: &str) -> bool {
-        crate::crypto::ct_eq(&self.api_key, api_key)
+        if self.is_hashed() {
+            crate::crypto::ct_eq(&self.api_key, hash_api_key(api_key))
+        } else {
+            crate::crypto::ct_eq(&self.api_key, api_key)
+        }
     }
 }
 
@@ -309,7 +535,11 @@ impl Organization {
     }
 
     pub async fn delete(self, conn: &mut DbConn) -> EmptyResult {
-        use super::{Cipher, Collection};
+        use super::{Cipher, Collection, DeviceApproval};
+
+        if self.legal_hold {
+            err!("This organization is under legal hold and can't be deleted")
+        }
 
         Cipher::delete_all_by_organization(&self.uuid, conn).await?;
         Collection::delete_all_by_organization(&self.uuid, conn).await?;
@@ -317,11 +547,18 @@ impl Organization {
         OrgPolicy::delete_all_by_organization(&self.uuid, conn).await?;
         Group::delete_all_by_organization(&self.uuid, conn).await?;
         OrganizationApiKey::delete_all_by_organization(&self.uuid, conn).await?;
+        DeviceApproval::delete_all_by_organization(&self.uuid, conn).await?;
+        CipherTransfer::delete_all_by_organization(&self.uuid, conn).await?;
+        let branding_logo_folder = self.branding_logo_folder();
 
         db_run! { conn: {
             diesel::delete(organizations::table.filter(organizations::uuid.eq(self.uuid)))
                 .execute(conn)
-                .map_res("Error saving organization")
+                .map_res("Error saving organization")?;
+
+            // The uploaded logos aren't referenced anymore
+            std::fs::remove_dir_all(&branding_logo_folder).ok();
+            Ok(())
         }}
     }
 
@@ -334,11 +571,36 @@ impl Organization {
         }}
     }
 
+    /// Fails when adding `requested` members would exceed the seat limit of this organization
+    pub async fn check_seat_limit(&self, requested: i64, conn: &mut DbConn) -> EmptyResult {
+        if self.seat_limit.is_none() {
+            return Ok(());
+        }
+        let used = UserOrganization::count_seats_by_org(&self.uuid, conn).await;
+        if !self.has_seats_available(used, requested) {
+            err!(format!(
+                "This organization has reached its seat limit of {}. Remove members or revoke invitations to free seats.",
+                self.seat_limit.unwrap_or_default()
+            ))
+        }
+        Ok(())
+    }
+
     pub async fn get_all(conn: &mut DbConn) -> Vec<Self> {
         db_run! { conn: {
             organizations::table.load::<OrganizationDb>(conn).expect("Error loading organizations").from_db()
         }}
     }
+
+    pub async fn find_legal_hold_uuids(conn: &mut DbConn) -> Vec<String> {
+        db_run! { conn: {
+            organizations::table
+                .filter(organizations::legal_hold.eq(true))
+                .select(organizations::uuid)
+                .load::<String>(conn)
+                .unwrap_or_default()
+        }}
+    }
 }
 
 impl UserOrganization {
@@ -423,6 +685,7 @@ impl UserOrganization {
         };
 
         let twofactor_enabled = !TwoFactor::find_by_user(&user.uuid, conn).await.is_empty();
+        let last_active = user.last_active(conn).await.map(|dt| crate::util::format_date(&dt));
 
         let groups: Vec<String> = if include_groups && CONFIG.org_groups_enabled() {
             GroupUser::find_by_user(&self.uuid, conn).await.iter().map(|gu| gu.groups_uuid.clone()).collect()
@@ -462,6 +725,8 @@ impl UserOrganization {
             "AccessAll": self.access_all,
             "TwoFactorEnabled": twofactor_enabled,
             "ResetPasswordEnrolled": self.reset_password_key.is_some(),
+            "LastActive": last_active,
+            "AccessSchedule": self.access_schedule.as_deref().and_then(|s| serde_json::from_str::<Value>(s).ok()),
 
             "Object": "organizationUserUserDetails",
         })
@@ -625,6 +890,33 @@ impl UserOrganization {
         }}
     }
 
+    /// The organizations the user is a confirmed member of, but can't access right now.
+    /// Like for the organization endpoints, their ciphers and collections aren't synced and can't be read.
+    pub async fn find_restricted_org_uuids(user_uuid: &str, conn: &mut DbConn) -> HashSet<String> {
+        let memberships = Self::find_confirmed_by_user(user_uuid, conn).await;
+        if memberships.is_empty() {
+            return HashSet::new();
+        }
+
+        let email_verified = User::find_by_uuid(user_uuid, conn)
+            .await
+            .map_or(true, |user| is_email_verified_for_policy(user.verified_at, CONFIG.mail_enabled()));
+        let verification_required = if email_verified {
+            HashSet::new()
+        } else {
+            OrgPolicy::find_confirmed_by_user_and_active_policy(
+                user_uuid,
+                OrgPolicyType::RequireEmailVerification,
+                conn,
+            )
+            .await
+            .into_iter()
+            .map(|policy| policy.org_uuid)
+            .collect()
+        };
+        Self::restricted_org_uuids(&memberships, Utc::now(), email_verified, &verification_required)
+    }
+
     pub async fn find_invited_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
         db_run! { conn: {
             users_organizations::table
@@ -635,6 +927,16 @@ impl UserOrganization {
         }}
     }
 
+    pub async fn find_expired_invitations(cutoff: &NaiveDateTime, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            users_organizations::table
+                .filter(users_organizations::status.eq(UserOrgStatus::Invited as i32))
+                .filter(users_organizations::invited_at.lt(cutoff))
+                .load::<UserOrganizationDb>(conn)
+                .unwrap_or_default().from_db()
+        }}
+    }
+
     pub async fn find_any_state_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
         db_run! { conn: {
             users_organizations::table
@@ -685,6 +987,18 @@ impl UserOrganization {
         }}
     }
 
+    /// Counts the memberships which take up a seat, including the pending invitations but not the revoked members
+    pub async fn count_seats_by_org(org_uuid: &str, conn: &mut DbConn) -> i64 {
+        db_run! { conn: {
+            users_organizations::table
+                .filter(users_organizations::org_uuid.eq(org_uuid))
+                .filter(users_organizations::status.ge(UserOrgStatus::Invited as i32))
+                .count()
+                .first::<i64>(conn)
+                .unwrap_or(0)
+        }}
+    }
+
     pub async fn find_by_org_and_type(org_uuid: &str, atype: UserOrgType, conn: &mut DbConn) -> Vec<Self> {
         db_run! { conn: {
             users_organizations::table
@@ -901,6 +1215,163 @@ impl OrganizationApiKey {
 mod tests {
     use super::*;
 
+    fn org_with_seat_limit(seat_limit: Option<i32>) -> Organization {
+        let mut org = Organization::new(String::from("org"), String::from("billing@example.com"), None, None);
+        org.seat_limit = seat_limit;
+        org
+    }
+
+    #[test]
+    fn invite_accepted_with_matching_domain() {
+        let mut org = org_with_seat_limit(None);
+        assert!(org.check_invite_domain("user@anywhere.example", false).is_ok());
+
+        org.set_invite_domains(vec![String::from(" @Acme.Example "), String::from("acme.example"), String::new()])
+            .unwrap();
+        assert_eq!(org.invite_domains.as_deref(), Some("acme.example"));
+        assert!(org.check_invite_domain("user@acme.example", true).is_ok());
+        assert!(org.check_invite_domain("User@ACME.example", true).is_ok());
+
+        // No domains remove the restriction
+        org.set_invite_domains(Vec::new()).unwrap();
+        assert_eq!(org.invite_domains, None);
+    }
+
+    #[test]
+    fn invite_rejected_with_other_domain() {
+        let mut org = org_with_seat_limit(None);
+        org.set_invite_domains(vec![String::from("acme.example")]).unwrap();
+
+        assert!(org.check_invite_domain("user@other.example", true).is_err());
+        // Subdomains and lookalike domains don't match
+        assert!(org.check_invite_domain("user@mail.acme.example", true).is_err());
+        assert!(org.check_invite_domain("user@acme.example.evil", true).is_err());
+        // An unverified address isn't trusted
+        assert!(org.check_invite_domain("user@acme.example", false).is_err());
+
+        assert!(org.set_invite_domains(vec![String::from("user@acme.example")]).is_err());
+    }
+
+    #[test]
+    fn seat_limit_reached() {
+        let org = org_with_seat_limit(Some(3));
+        // A confirmed, an accepted and a pending invitation all take a seat
+        assert!(org.has_seats_available(2, 1));
+        assert!(!org.has_seats_available(3, 1));
+        assert!(!org.has_seats_available(1, 3));
+        assert!(org_with_seat_limit(None).has_seats_available(1000, 10));
+    }
+
+    #[test]
+    fn seat_freed_by_revoked_invitation() {
+        let org = org_with_seat_limit(Some(2));
+        let mut members = vec![
+            UserOrganization::new(String::from("user1"), org.uuid.clone()),
+            UserOrganization::new(String::from("user2"), org.uuid.clone()),
+        ];
+        members[0].status = UserOrgStatus::Confirmed as i32;
+        let used = |members: &[UserOrganization]| members.iter().filter(|m| m.occupies_seat()).count() as i64;
+        assert!(!org.has_seats_available(used(&members), 1));
+
+        members[1].revoke();
+        assert!(org.has_seats_available(used(&members), 1));
+    }
+
+    #[test]
+    #[cfg(sqlite)]
+    fn pending_invitations_take_seats() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut conn = DbConn::sqlite_in_memory();
+            conn.batch_execute(
+                "INSERT INTO users_organizations (uuid, user_uuid, org_uuid, access_all, akey, status, atype) VALUES \
+                ('invited', 'user1', 'org', 0, '', 0, 2), ('confirmed', 'user2', 'org', 0, '', 2, 2), \
+                ('revoked', 'user3', 'org', 0, '', -1, 2), ('other', 'user1', 'other-org', 0, '', 2, 2)",
+            )
+            .await;
+            assert_eq!(UserOrganization::count_seats_by_org("org", &mut conn).await, 2);
+        });
+    }
+
+    #[test]
+    fn invite_expires() {
+        let now = Utc::now().naive_utc();
+        let cutoff = UserOrganization::invite_expiry_cutoff(now, Some(7));
+        let mut user_org = UserOrganization::new(String::from("user"), String::from("org"));
+        user_org.status = UserOrgStatus::Invited as i32;
+
+        user_org.invited_at = Some(now - TimeDelta::try_days(6).unwrap());
+        assert!(!user_org.is_invite_expired(cutoff));
+        user_org.invited_at = Some(now - TimeDelta::try_days(8).unwrap());
+        assert!(user_org.is_invite_expired(cutoff));
+
+        // Accepted memberships and invitations without expiry are kept
+        assert!(!user_org.is_invite_expired(UserOrganization::invite_expiry_cutoff(now, None)));
+        user_org.status = UserOrgStatus::Accepted as i32;
+        assert!(!user_org.is_invite_expired(cutoff));
+    }
+
+    #[test]
+    fn new_member_in_2fa_grace_period() {
+        let now = Utc::now().naive_utc();
+        let mut user_org = UserOrganization::new(String::from("user"), String::from("org"));
+        user_org.status = UserOrgStatus::Invited as i32;
+        user_org.accept(now - TimeDelta::try_days(6).unwrap());
+
+        assert_eq!(user_org.status, UserOrgStatus::Accepted as i32);
+        assert!(user_org.in_2fa_grace_period(now, 7));
+        // Without a grace period the policy applies right away
+        assert!(!user_org.in_2fa_grace_period(now, 0));
+    }
+
+    #[test]
+    fn member_after_2fa_grace_period() {
+        let now = Utc::now().naive_utc();
+        let mut user_org = UserOrganization::new(String::from("user"), String::from("org"));
+        user_org.accept(now - TimeDelta::try_days(8).unwrap());
+        assert!(!user_org.in_2fa_grace_period(now, 7));
+
+        // Members who joined before the grace period existed don't get one
+        user_org.accepted_at = None;
+        assert!(!user_org.in_2fa_grace_period(now, 7));
+    }
+
+    #[test]
+    fn branding_set_and_fetched() {
+        let mut org = org_with_seat_limit(None);
+        org.set_branding(Some(String::from(" Acme Corp ")), Some(String::from("https://acme.example/logo.png")))
+            .unwrap();
+
+        let branding = org.branding_json("https://vault.example");
+        assert_eq!(branding["DisplayName"], "Acme Corp");
+        assert_eq!(branding["LogoUrl"], "https://acme.example/logo.png");
+
+        // An uploaded logo is served by us, instead of the logo URL
+        org.branding_logo_file = Some(String::from("logo.png"));
+        let branding = org.branding_json("https://vault.example");
+        assert_eq!(
+            branding["LogoUrl"],
+            format!("https://vault.example/api/organizations/{}/branding/logo/logo.png", org.uuid)
+        );
+
+        // Empty values remove the branding
+        org.branding_logo_file = None;
+        org.set_branding(Some(String::new()), None).unwrap();
+        assert_eq!(
+            org.branding_json("https://vault.example"),
+            json!({"DisplayName": null, "LogoUrl": null, "Object": "organizationBranding"})
+        );
+    }
+
+    #[test]
+    fn branding_rejected() {
+        let mut org = org_with_seat_limit(None);
+        assert!(org.set_branding(Some("a".repeat(BRANDING_NAME_MAX_LENGTH + 1)), None).is_err());
+        assert!(org.set_branding(None, Some(String::from("http://acme.example/logo.png"))).is_err());
+        assert!(org.set_branding(None, Some(String::from("javascript:alert(1)"))).is_err());
+        assert!(org.set_branding(None, Some(String::from("not a url"))).is_err());
+        assert_eq!(org.branding_name, None);
+    }
+
     #[test]
     #[allow(non_snake_case)]
     fn partial_cmp_UserOrgType() {
@@ -908,4 +1379,33 @@ mod tests {
         assert!(UserOrgType::Admin > UserOrgType::Manager);
         assert!(UserOrgType::Manager > UserOrgType::User);
     }
+
+    #[test]
+    fn org_api_key_grant_with_valid_secret() {
+        let org_api_key = OrganizationApiKey::new(String::from("org"), String::from("first-secret"));
+
+        assert!(org_api_key.is_hashed());
+        assert_ne!(org_api_key.api_key, "first-secret");
+        assert!(org_api_key.check_valid_api_key("first-secret"));
+        assert!(!org_api_key.check_valid_api_key("wrong-secret"));
+    }
+
+    #[test]
+    fn org_api_key_rejected_after_rotation() {
+        let mut org_api_key = OrganizationApiKey::new(String::from("org"), String::from("first-secret"));
+        org_api_key.set_api_key("second-secret");
+
+        assert!(!org_api_key.check_valid_api_key("first-secret"));
+        assert!(org_api_key.check_valid_api_key("second-secret"));
+    }
+
+    #[test]
+    fn org_api_key_legacy_plaintext() {
+        let mut org_api_key = OrganizationApiKey::new(String::from("org"), String::new());
+        org_api_key.api_key = String::from("legacy-secret");
+
+        assert!(!org_api_key.is_hashed());
+        assert!(org_api_key.check_valid_api_key("legacy-secret"));
+        assert!(!org_api_key.check_valid_api_key("wrong-secret"));
+    }
 }
@@ -0,0 +1,276 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use super::{Device, DeviceType, User};
+use crate::{api::EmptyResult, crypto, db::DbConn, error::MapResult, util::format_date};
+
+db_object! {
+    // A token which can be used as bearer credential instead of a login session, only the hash of the token is stored
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = personal_access_tokens)]
+    #[diesel(treat_none_as_null = true)]
+    #[diesel(primary_key(uuid))]
+    pub struct PersonalAccessToken {
+        pub uuid: String,
+        pub user_uuid: String,
+        pub name: String,
+        pub token_hash: String,
+        pub read_only: bool,
+        pub expires_at: Option<NaiveDateTime>,
+        pub creation_date: NaiveDateTime,
+        pub last_used_at: Option<NaiveDateTime>,
+        pub security_stamp: String,
+    }
+}
+
+// Personal access tokens are recognized by this prefix, to tell them apart from login JWTs
+const TOKEN_PREFIX: &str = "vwpat_";
+
+// The routes a read-only token can be used for, by route name
+const READ_ONLY_ROUTES: &[&str] = &[
+    "sync",
+    "profile",
+    "get_ciphers",
+    "get_cipher",
+    "get_cipher_details",
+    "get_attachment",
+    "get_folders",
+    "get_folder",
+];
+
+// The routes a read-write token can additionally be used for, by route name
+const READ_WRITE_ROUTES: &[&str] = &[
+    "post_ciphers",
+    "post_ciphers_create",
+    "post_cipher",
+    "put_cipher",
+    "post_cipher_partial",
+    "put_cipher_partial",
+    "delete_cipher",
+    "delete_cipher_post",
+    "delete_cipher_put",
+    "restore_cipher_put",
+    "post_folders",
+    "post_folder",
+    "put_folder",
+    "delete_folder",
+    "delete_folder_post",
+];
+
+fn hash_token(token: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, token.as_bytes());
+    data_encoding::HEXLOWER.encode(digest.as_ref())
+}
+
+/// Local methods
+impl PersonalAccessToken {
+    /// Creates a new token, the returned plaintext token is only available here.
+    /// The token is bound to the current security stamp of the user, so it is revoked together with the logins.
+    pub fn new(user: &User, name: String, read_only: bool, expires_at: Option<NaiveDateTime>) -> (Self, String) {
+        Self::new_with_stamp(user.uuid.clone(), user.security_stamp.clone(), name, read_only, expires_at)
+    }
+
+    fn new_with_stamp(
+        user_uuid: String,
+        security_stamp: String,
+        name: String,
+        read_only: bool,
+        expires_at: Option<NaiveDateTime>,
+    ) -> (Self, String) {
+        let token = format!("{TOKEN_PREFIX}{}", crypto::encode_random_bytes::<32>(data_encoding::HEXLOWER));
+
+        let pat = Self {
+            uuid: crate::util::get_uuid(),
+            user_uuid,
+            name,
+            token_hash: hash_token(&token),
+            read_only,
+            expires_at,
+            creation_date: Utc::now().naive_utc(),
+            last_used_at: None,
+            security_stamp,
+        };
+        (pat, token)
+    }
+
+    pub fn is_token(token: &str) -> bool {
+        token.starts_with(TOKEN_PREFIX)
+    }
+
+    pub fn is_expired(&self, now: &NaiveDateTime) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= *now)
+    }
+
+    /// Tokens can only be used for the vault routes of their scope, never to manage the account
+    pub fn allows_route(&self, route_name: Option<&str>) -> bool {
+        let Some(route_name) = route_name else {
+            return false;
+        };
+        READ_ONLY_ROUTES.contains(&route_name) || (!self.read_only && READ_WRITE_ROUTES.contains(&route_name))
+    }
+
+    /// Every token has its own device, which identifies the requests made with it.
+    /// The device never gets a session, its refresh token is random and never handed out.
+    pub fn new_device(&self) -> Device {
+        let mut device =
+            Device::new(self.uuid.clone(), self.user_uuid.clone(), self.name.clone(), DeviceType::Sdk as i32);
+        device.refresh_token = crypto::encode_random_bytes::<64>(data_encoding::BASE64URL);
+        device
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "Id": self.uuid,
+            "Name": self.name,
+            "ReadOnly": self.read_only,
+            "ExpirationDate": self.expires_at.as_ref().map(format_date),
+            "CreationDate": format_date(&self.creation_date),
+            "LastUsedDate": self.last_used_at.as_ref().map(format_date),
+            "Object": "personalAccessToken",
+        })
+    }
+}
+
+/// Database methods
+impl PersonalAccessToken {
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(personal_access_tokens::table)
+                    .values(PersonalAccessTokenDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving personal access token")
+            }
+            postgresql {
+                let value = PersonalAccessTokenDb::to_db(self);
+                diesel::insert_into(personal_access_tokens::table)
+                    .values(&value)
+                    .on_conflict(personal_access_tokens::uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving personal access token")
+            }
+        }
+    }
+
+    pub async fn update_last_used(&mut self, conn: &mut DbConn) -> EmptyResult {
+        let now = Utc::now().naive_utc();
+        self.last_used_at = Some(now);
+
+        db_run! { conn: {
+            diesel::update(personal_access_tokens::table)
+                .filter(personal_access_tokens::uuid.eq(&self.uuid))
+                .set(personal_access_tokens::last_used_at.eq(now))
+                .execute(conn)
+                .map_res("Error updating personal access token")
+        }}
+    }
+
+    pub async fn delete(self, conn: &mut DbConn) -> EmptyResult {
+        Device::delete_by_uuid_and_user(&self.uuid, &self.user_uuid, conn).await?;
+
+        db_run! { conn: {
+            diesel::delete(personal_access_tokens::table.filter(personal_access_tokens::uuid.eq(self.uuid)))
+                .execute(conn)
+                .map_res("Error deleting personal access token")
+        }}
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(personal_access_tokens::table.filter(personal_access_tokens::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting personal access tokens")
+        }}
+    }
+
+    pub async fn find_by_uuid_and_user(uuid: &str, user_uuid: &str, conn: &mut DbConn) -> Option<Self> {
+        db_run! { conn: {
+            personal_access_tokens::table
+                .filter(personal_access_tokens::uuid.eq(uuid))
+                .filter(personal_access_tokens::user_uuid.eq(user_uuid))
+                .first::<PersonalAccessTokenDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub async fn find_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            personal_access_tokens::table
+                .filter(personal_access_tokens::user_uuid.eq(user_uuid))
+                .load::<PersonalAccessTokenDb>(conn)
+                .expect("Error loading personal access tokens")
+                .from_db()
+        }}
+    }
+
+    pub async fn find_by_token(token: &str, conn: &mut DbConn) -> Option<Self> {
+        let token_hash = hash_token(token);
+        db_run! { conn: {
+            personal_access_tokens::table
+                .filter(personal_access_tokens::token_hash.eq(token_hash))
+                .first::<PersonalAccessTokenDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    fn new_pat(read_only: bool) -> (PersonalAccessToken, String) {
+        PersonalAccessToken::new_with_stamp(
+            String::from("user"),
+            String::from("stamp"),
+            String::from("script"),
+            read_only,
+            None,
+        )
+    }
+
+    #[test]
+    fn pat_stored_hashed() {
+        let (pat, token) = new_pat(true);
+
+        assert!(PersonalAccessToken::is_token(&token));
+        assert!(!token.contains(&pat.token_hash));
+        assert_eq!(pat.token_hash, hash_token(&token));
+        assert!(!PersonalAccessToken::is_token("eyJhbGciOiJSUzI1NiJ9.e30.sig"));
+    }
+
+    #[test]
+    fn pat_read_only_scope() {
+        let (read_only, _) = new_pat(true);
+        assert!(read_only.allows_route(Some("sync")));
+        assert!(read_only.allows_route(Some("get_cipher")));
+        assert!(!read_only.allows_route(Some("put_cipher")));
+        assert!(!read_only.allows_route(Some("delete_cipher")));
+        // Reads outside of the vault aren't in the scope either
+        assert!(!read_only.allows_route(Some("get_access_tokens")));
+        assert!(!read_only.allows_route(None));
+
+        let (read_write, _) = new_pat(false);
+        assert!(read_write.allows_route(Some("get_cipher")));
+        assert!(read_write.allows_route(Some("put_cipher")));
+        assert!(read_write.allows_route(Some("delete_cipher")));
+        assert!(!read_write.allows_route(Some("post_password")));
+        assert!(!read_write.allows_route(Some("post_access_token")));
+    }
+
+    #[test]
+    fn pat_expiry() {
+        let now = Utc::now().naive_utc();
+        let (mut pat, _) = new_pat(true);
+        assert!(!pat.is_expired(&now));
+
+        pat.expires_at = Some(now + TimeDelta::try_days(1).unwrap());
+        assert!(!pat.is_expired(&now));
+        pat.expires_at = Some(now - TimeDelta::try_seconds(1).unwrap());
+        assert!(pat.is_expired(&now));
+    }
+}
@@ -1,5 +1,6 @@
 use serde_json::Value;
 
+use super::TwoFactorBackup;
 use crate::{api::EmptyResult, db::DbConn, error::MapResult};
 
 db_object! {
@@ -72,7 +73,7 @@ impl TwoFactor {
 /// Database methods
 impl TwoFactor {
     pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
-        db_run! { conn:
+        let saved: EmptyResult = db_run! { conn:
             sqlite, mysql {
                 match diesel::replace_into(twofactor::table)
                     .values(TwoFactorDb::to_db(self))
@@ -107,15 +108,20 @@ impl TwoFactor {
                     .execute(conn)
                     .map_res("Error saving twofactor")
             }
-        }
+        };
+        saved?;
+        // Keeps the number of enabled methods of the backup state up to date
+        TwoFactorBackup::update_methods(&self.user_uuid, conn).await
     }
 
     pub async fn delete(self, conn: &mut DbConn) -> EmptyResult {
-        db_run! { conn: {
-            diesel::delete(twofactor::table.filter(twofactor::uuid.eq(self.uuid)))
+        let deleted: EmptyResult = db_run! { conn: {
+            diesel::delete(twofactor::table.filter(twofactor::uuid.eq(&self.uuid)))
                 .execute(conn)
                 .map_res("Error deleting twofactor")
-        }}
+        }};
+        deleted?;
+        TwoFactorBackup::update_methods(&self.user_uuid, conn).await
     }
 
     pub async fn find_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
@@ -129,6 +135,11 @@ impl TwoFactor {
         }}
     }
 
+    /// Returns the types of the enabled two-step login methods of a user
+    pub async fn find_enabled_types_by_user(user_uuid: &str, conn: &mut DbConn) -> Vec<i32> {
+        Self::find_by_user(user_uuid, conn).await.into_iter().filter(|tf| tf.enabled).map(|tf| tf.atype).collect()
+    }
+
     pub async fn find_by_user_and_type(user_uuid: &str, atype: i32, conn: &mut DbConn) -> Option<Self> {
         db_run! { conn: {
             twofactor::table
@@ -140,12 +151,24 @@ impl TwoFactor {
         }}
     }
 
-    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+    pub async fn find_by_type(atype: i32, conn: &mut DbConn) -> Vec<Self> {
         db_run! { conn: {
+            twofactor::table
+                .filter(twofactor::atype.eq(atype))
+                .load::<TwoFactorDb>(conn)
+                .expect("Error loading twofactor")
+                .from_db()
+        }}
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        let deleted: EmptyResult = db_run! { conn: {
             diesel::delete(twofactor::table.filter(twofactor::user_uuid.eq(user_uuid)))
                 .execute(conn)
                 .map_res("Error deleting twofactors")
-        }}
+        }};
+        deleted?;
+        TwoFactorBackup::update_methods(user_uuid, conn).await
     }
 
     pub async fn migrate_u2f_to_webauthn(conn: &mut DbConn) -> EmptyResult {
@@ -0,0 +1,162 @@
+use chrono::{NaiveDateTime, Utc};
+
+use super::TwoFactor;
+use crate::{api::EmptyResult, db::DbConn, error::MapResult};
+
+db_object! {
+    // Whether a user can still log in after losing a two-step login method, see `TWO_FACTOR_BACKUP_REQUIRED`.
+    // Users without a stored row never enabled two-step login.
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = twofactor_backup)]
+    #[diesel(treat_none_as_null = true)]
+    #[diesel(primary_key(user_uuid))]
+    pub struct TwoFactorBackup {
+        pub user_uuid: String,
+        // Number of enabled two-step login methods, recounted by every write of `TwoFactor`
+        pub methods: i32,
+        // When the user last viewed the current recovery code
+        pub recovery_acknowledged_at: Option<NaiveDateTime>,
+    }
+}
+
+/// Local methods
+impl TwoFactorBackup {
+    pub fn new(user_uuid: String) -> Self {
+        Self {
+            user_uuid,
+            methods: 0,
+            recovery_acknowledged_at: None,
+        }
+    }
+
+    /// A single method without a saved recovery code locks the user out when it is lost
+    pub fn is_missing_backup(&self) -> bool {
+        self.methods == 1 && self.recovery_acknowledged_at.is_none()
+    }
+
+    /// Fails when disabling a method would leave the user with a single method and no saved recovery code
+    pub fn check_disable_allowed(&self, backup_required: bool) -> EmptyResult {
+        if backup_required && self.methods == 2 && self.recovery_acknowledged_at.is_none() {
+            err!("View and save your recovery code before removing your backup two-step login method")
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "Methods": self.methods,
+            "RecoveryAcknowledgedAt": self.recovery_acknowledged_at.as_ref().map(crate::util::format_date),
+            "MissingBackup": self.is_missing_backup(),
+        })
+    }
+}
+
+/// Database methods
+impl TwoFactorBackup {
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(twofactor_backup::table)
+                    .values(TwoFactorBackupDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving two-step login backup state")
+            }
+            postgresql {
+                let value = TwoFactorBackupDb::to_db(self);
+                diesel::insert_into(twofactor_backup::table)
+                    .values(&value)
+                    .on_conflict(twofactor_backup::user_uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving two-step login backup state")
+            }
+        }
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(twofactor_backup::table.filter(twofactor_backup::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting two-step login backup state")
+        }}
+    }
+
+    pub async fn find_or_default(user_uuid: &str, conn: &mut DbConn) -> Self {
+        let backup: Option<Self> = db_run! { conn: {
+            twofactor_backup::table
+                .filter(twofactor_backup::user_uuid.eq(user_uuid))
+                .first::<TwoFactorBackupDb>(conn)
+                .ok()
+                .from_db()
+        }};
+        backup.unwrap_or_else(|| Self::new(String::from(user_uuid)))
+    }
+
+    /// Users with a single method and no saved recovery code
+    pub async fn find_missing_backup(conn: &mut DbConn) -> Result<Vec<Self>, crate::Error> {
+        db_run! { conn: {
+            twofactor_backup::table
+                .filter(twofactor_backup::methods.eq(1))
+                .filter(twofactor_backup::recovery_acknowledged_at.is_null())
+                .load::<TwoFactorBackupDb>(conn)
+                .map(FromDb::from_db)
+                .map_res("Error loading two-step login backup states")
+        }}
+    }
+
+    /// Recounts the enabled methods of a user
+    pub async fn update_methods(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        let mut backup = Self::find_or_default(user_uuid, conn).await;
+        backup.methods = TwoFactor::find_enabled_types_by_user(user_uuid, conn).await.len() as i32;
+        backup.save(conn).await
+    }
+
+    /// Marks the current recovery code as saved by the user
+    pub async fn acknowledge_recovery(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        let mut backup = Self::find_or_default(user_uuid, conn).await;
+        backup.recovery_acknowledged_at = Some(Utc::now().naive_utc());
+        backup.save(conn).await
+    }
+
+    /// A new recovery code has to be saved again
+    pub async fn reset_recovery(user_uuid: &str, conn: &mut DbConn) -> EmptyResult {
+        let mut backup = Self::find_or_default(user_uuid, conn).await;
+        backup.recovery_acknowledged_at = None;
+        backup.save(conn).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_method_flags_missing_backup() {
+        let mut backup = TwoFactorBackup::new(String::from("user"));
+        assert!(!backup.is_missing_backup());
+
+        // Enabling the first method
+        backup.methods = 1;
+        assert!(backup.is_missing_backup());
+        assert_eq!(backup.to_json()["MissingBackup"], true);
+
+        // A second method or a saved recovery code are a backup
+        backup.methods = 2;
+        assert!(!backup.is_missing_backup());
+        backup.methods = 1;
+        backup.recovery_acknowledged_at = Some(Utc::now().naive_utc());
+        assert!(!backup.is_missing_backup());
+    }
+
+    #[test]
+    fn last_backup_method_kept_when_required() {
+        let mut backup = TwoFactorBackup::new(String::from("user"));
+        backup.methods = 2;
+        assert!(backup.check_disable_allowed(true).is_err());
+        assert!(backup.check_disable_allowed(false).is_ok());
+
+        backup.recovery_acknowledged_at = Some(Utc::now().naive_utc());
+        assert!(backup.check_disable_allowed(true).is_ok());
+    }
+}
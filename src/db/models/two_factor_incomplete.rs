@@ -1,6 +1,6 @@
 use chrono::{NaiveDateTime, Utc};
 
-use crate::{api::EmptyResult, auth::ClientIp, db::DbConn, error::MapResult, CONFIG};
+use crate::{api::EmptyResult, auth::ClientIp, db::DbConn, error::MapResult, util::stored_ip, CONFIG};
 
 db_object! {
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
@@ -45,7 +45,7 @@ impl TwoFactorIncomplete {
                     twofactor_incomplete::device_uuid.eq(device_uuid),
                     twofactor_incomplete::device_name.eq(device_name),
                     twofactor_incomplete::login_time.eq(Utc::now().naive_utc()),
-                    twofactor_incomplete::ip_address.eq(ip.ip.to_string()),
+                    twofactor_incomplete::ip_address.eq(stored_ip(&ip.ip)),
                 ))
                 .execute(conn)
                 .map_res("Error adding twofactor_incomplete record")
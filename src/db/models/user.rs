@@ -1,5 +1,6 @@
 use chrono::{NaiveDateTime, TimeDelta, Utc};
 use serde_json::Value;
+use std::collections::{HashMap, HashSet};
 
 use crate::crypto;
 use crate::CONFIG;
@@ -52,6 +53,12 @@ db_object! {
         pub avatar_color: Option<String>,
 
         pub external_id: Option<String>, // Todo: Needs to be removed in the future, this is not used anymore.
+        pub key_rotated_at: Option<NaiveDateTime>,
+        pub password_changed_at: Option<NaiveDateTime>,
+        // Shares its email with another account in a different form, stored by older versions
+        pub email_case_duplicate: bool,
+        // When the unverified account purge job sent the reminder announcing the purge date
+        pub unverified_reminder_at: Option<NaiveDateTime>,
     }
 
     #[derive(Identifiable, Queryable, Insertable)]
@@ -80,6 +87,36 @@ pub struct UserStampException {
     pub expire: i64,
 }
 
+/// The form emails are stored and looked up in, so addresses only differing in case or surrounding whitespace match
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Groups of stored emails which only differ in case or surrounding whitespace
+fn email_case_duplicates<'a>(emails: impl IntoIterator<Item = &'a str>) -> Vec<Vec<&'a str>> {
+    let mut groups: HashMap<String, Vec<&'a str>> = HashMap::new();
+    for email in emails {
+        groups.entry(normalize_email(email)).or_default().push(email);
+    }
+    let mut duplicates: Vec<Vec<&str>> = groups.into_values().filter(|group| group.len() > 1).collect();
+    duplicates.sort();
+    duplicates
+}
+
+/// Warning about a group of emails, `login_email` is the one which can log in
+fn email_case_duplicate_message(group: &[&str], login_email: Option<&str>) -> String {
+    let login = match login_email {
+        Some(email) => format!("only the account with {email:?} can log in"),
+        None => String::from("none of them can log in"),
+    };
+    format!(
+        "Multiple accounts share the email {:?} in different forms ({}), {login}. \
+        Delete or change the email of the others from the admin panel",
+        normalize_email(group[0]),
+        group.join(", ")
+    )
+}
+
 /// Local methods
 impl User {
     pub const CLIENT_KDF_TYPE_DEFAULT: i32 = UserKdfType::Pbkdf2 as i32;
@@ -87,7 +124,7 @@ impl User {
 
     pub fn new(email: String) -> Self {
         let now = Utc::now().naive_utc();
-        let email = email.to_lowercase();
+        let email = normalize_email(&email);
 
         Self {
             uuid: crate::util::get_uuid(),
@@ -130,6 +167,10 @@ impl User {
             avatar_color: None,
 
             external_id: None, // Todo: Needs to be removed in the future, this is not used anymore.
+            key_rotated_at: None,
+            password_changed_at: None,
+            email_case_duplicate: false,
+            unverified_reminder_at: None,
         }
     }
 
@@ -185,6 +226,60 @@ impl User {
         if reset_security_stamp {
             self.reset_security_stamp()
         }
+
+        self.mark_key_rotated();
+    }
+
+    /// Records that the master password or account keys were changed
+    pub fn mark_key_rotated(&mut self) {
+        self.key_rotated_at = Some(Utc::now().naive_utc());
+    }
+
+    /// Records that the user changed the master password or KDF settings themselves.
+    /// Unlike `key_rotated_at` this isn't set by resets, takeovers or rehashes, which aren't limited by the interval.
+    pub fn mark_password_changed(&mut self) {
+        self.password_changed_at = Some(Utc::now().naive_utc());
+    }
+
+    /// Whether a key rotation reminder should be sent, at most once every `reminder_days`
+    pub fn is_key_rotation_reminder_due(
+        &self,
+        reminded_at: Option<NaiveDateTime>,
+        now: NaiveDateTime,
+        reminder_days: i64,
+    ) -> bool {
+        key_rotation_reminder_due(self.key_rotated_at.unwrap_or(self.created_at), reminded_at, now, reminder_days)
+    }
+
+    /// Refuses a master password change within `interval_hours` of the previous change
+    pub fn check_password_change_interval(&self, now: NaiveDateTime, interval_hours: i64) -> EmptyResult {
+        if let Some(next_change) = next_password_change(self.password_changed_at, now, interval_hours) {
+            err!(format!(
+                "The master password was changed recently, it can be changed again after {} UTC",
+                next_change.format("%Y-%m-%d %H:%M")
+            ))
+        }
+        Ok(())
+    }
+
+    /// Whether an account which didn't verify its email address has to be reminded or purged, verified accounts are kept
+    pub fn unverified_account_action(
+        &self,
+        signups_verify: bool,
+        now: NaiveDateTime,
+        purge_days: i64,
+        reminder_days: i64,
+    ) -> UnverifiedAccountAction {
+        unverified_account_action(
+            signups_verify,
+            self.verified_at,
+            self.created_at,
+            self.last_verifying_at,
+            self.unverified_reminder_at,
+            now,
+            purge_days,
+            reminder_days,
+        )
     }
 
     pub fn reset_security_stamp(&mut self) {
@@ -214,14 +309,81 @@ impl User {
 }
 
 use super::{
-    Cipher, Device, EmergencyAccess, Favorite, Folder, Send, TwoFactor, TwoFactorIncomplete, UserOrgType,
-    UserOrganization,
+    AccessLog, AccountInactivity, AccountRecoveryToken, Cipher, CipherIdempotencyKey, CipherShare, CipherTransfer,
+    Device, DeviceApproval, EmergencyAccess, EmergencyAccessQuorum, Favorite, Folder, NotificationPreference,
+    PersonalAccessToken, Send, TwoFactor, TwoFactorBackup, TwoFactorIncomplete, UserOrgType, UserOrganization,
 };
 use crate::db::DbConn;
 
 use crate::api::EmptyResult;
 use crate::error::MapResult;
 
+fn key_rotation_reminder_due(
+    rotated_at: NaiveDateTime,
+    reminded_at: Option<NaiveDateTime>,
+    now: NaiveDateTime,
+    reminder_days: i64,
+) -> bool {
+    let Some(interval) = TimeDelta::try_days(reminder_days) else {
+        return false;
+    };
+    let reminded_recently = reminded_at.is_some_and(|reminded_at| now - reminded_at < interval);
+    now - rotated_at >= interval && !reminded_recently
+}
+
+/// The earliest time the password may be changed again, when that is still in the future
+fn next_password_change(
+    changed_at: Option<NaiveDateTime>,
+    now: NaiveDateTime,
+    interval_hours: i64,
+) -> Option<NaiveDateTime> {
+    let next_change = changed_at? + TimeDelta::try_hours(interval_hours)?;
+    (next_change > now).then_some(next_change)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UnverifiedAccountAction {
+    None,
+    Remind,
+    Purge,
+}
+
+/// An unverified account is reminded `reminder_days` before it's `purge_days` old, and purged once both `purge_days`
+/// passed since its creation and `reminder_days` since the reminder. Only the reminder sent by the purge job announces
+/// the purge date, so other verification emails don't count as one.
+///
+/// `verified_at` is only maintained with `SIGNUPS_VERIFY`, so nothing is purged without it. Accounts which were never
+/// sent a verification email (`last_verifying_at` is unset), like the ones created before it was enabled, are kept too.
+#[allow(clippy::too_many_arguments)]
+fn unverified_account_action(
+    signups_verify: bool,
+    verified_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+    last_verifying_at: Option<NaiveDateTime>,
+    reminded_at: Option<NaiveDateTime>,
+    now: NaiveDateTime,
+    purge_days: i64,
+    reminder_days: i64,
+) -> UnverifiedAccountAction {
+    let (true, None, Some(_), Some(purge_after), Some(reminder_period)) = (
+        signups_verify,
+        verified_at,
+        last_verifying_at,
+        TimeDelta::try_days(purge_days),
+        TimeDelta::try_days(reminder_days),
+    ) else {
+        return UnverifiedAccountAction::None;
+    };
+    match reminded_at {
+        Some(reminded_at) if now - created_at >= purge_after && now - reminded_at >= reminder_period => {
+            UnverifiedAccountAction::Purge
+        }
+        Some(_) => UnverifiedAccountAction::None,
+        None if now >= created_at + purge_after - reminder_period => UnverifiedAccountAction::Remind,
+        None => UnverifiedAccountAction::None,
+    }
+}
+
 /// Database methods
 impl User {
     pub async fn to_json(&self, conn: &mut DbConn) -> Value {
@@ -301,6 +463,16 @@ impl User {
         }
     }
 
+    /// Writes the stored row again without changing it, which takes about as long as saving a new user
+    pub async fn rewrite_unchanged(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! {conn: {
+            diesel::update(users::table.filter(users::uuid.eq(&self.uuid)))
+                .set(users::updated_at.eq(self.updated_at))
+                .execute(conn)
+                .map_res("Error saving user")
+        }}
+    }
+
     pub async fn delete(self, conn: &mut DbConn) -> EmptyResult {
         for user_org in UserOrganization::find_confirmed_by_user(&self.uuid, conn).await {
             if user_org.atype == UserOrgType::Owner
@@ -313,14 +485,25 @@ impl User {
 
         Send::delete_all_by_user(&self.uuid, conn).await?;
         EmergencyAccess::delete_all_by_user(&self.uuid, conn).await?;
+        EmergencyAccessQuorum::delete_all_by_user(&self.uuid, conn).await?;
         EmergencyAccess::delete_all_by_grantee_email(&self.email, conn).await?;
         UserOrganization::delete_all_by_user(&self.uuid, conn).await?;
+        CipherShare::delete_all_by_user(&self.uuid, conn).await?;
         Cipher::delete_all_by_user(&self.uuid, conn).await?;
         Favorite::delete_all_by_user(&self.uuid, conn).await?;
         Folder::delete_all_by_user(&self.uuid, conn).await?;
         Device::delete_all_by_user(&self.uuid, conn).await?;
         TwoFactor::delete_all_by_user(&self.uuid, conn).await?;
         TwoFactorIncomplete::delete_all_by_user(&self.uuid, conn).await?;
+        NotificationPreference::delete_all_by_user(&self.uuid, conn).await?;
+        DeviceApproval::delete_all_by_user(&self.uuid, conn).await?;
+        CipherTransfer::delete_all_by_user(&self.uuid, conn).await?;
+        AccountRecoveryToken::delete_all_by_user(&self.uuid, conn).await?;
+        CipherIdempotencyKey::delete_all_by_user(&self.uuid, conn).await?;
+        AccessLog::delete_all_by_user(&self.uuid, conn).await?;
+        AccountInactivity::delete_all_by_user(&self.uuid, conn).await?;
+        TwoFactorBackup::delete_all_by_user(&self.uuid, conn).await?;
+        PersonalAccessToken::delete_all_by_user(&self.uuid, conn).await?;
         Invitation::take(&self.email, conn).await; // Delete invitation if any
 
         db_run! {conn: {
@@ -338,6 +521,8 @@ impl User {
 
     pub async fn update_all_revisions(conn: &mut DbConn) -> EmptyResult {
         let updated_at = Utc::now().naive_utc();
+        // The users sync right after the revision changed, which must not read the older state from a replica
+        crate::db::record_write_all();
 
         db_run! {conn: {
             crate::util::retry(|| {
@@ -356,6 +541,8 @@ impl User {
     }
 
     async fn _update_revision(uuid: &str, date: &NaiveDateTime, conn: &mut DbConn) -> EmptyResult {
+        // The user syncs right after the revision changed, which must not read the older state from a replica
+        crate::db::record_write(uuid);
         db_run! {conn: {
             crate::util::retry(|| {
                 diesel::update(users::table.filter(users::uuid.eq(uuid)))
@@ -367,7 +554,7 @@ impl User {
     }
 
     pub async fn find_by_mail(mail: &str, conn: &mut DbConn) -> Option<Self> {
-        let lower_mail = mail.to_lowercase();
+        let lower_mail = normalize_email(mail);
         db_run! {conn: {
             users::table
                 .filter(users::email.eq(lower_mail))
@@ -377,6 +564,43 @@ impl User {
         }}
     }
 
+    /// Warns about users whose emails only differ in case or surrounding whitespace, fails with `ENFORCE_UNIQUE_EMAILS`.
+    /// Older versions stored changed emails as entered, the migrations normalizing them flag these conflicting accounts.
+    pub async fn check_email_case_duplicates(conn: &mut DbConn) -> EmptyResult {
+        let users: Vec<(String, bool)> = db_run! {conn: {
+            users::table
+                .select((users::email, users::email_case_duplicate))
+                .load::<(String, bool)>(conn)
+                .map_res("Error loading user emails")
+        }}?;
+
+        let flagged: HashSet<&str> =
+            users.iter().filter(|(_, flagged)| *flagged).map(|(email, _)| email.as_str()).collect();
+        let groups = email_case_duplicates(users.iter().map(|(email, _)| email.as_str()));
+        for group in &groups {
+            let login_email = group.iter().find(|email| !flagged.contains(*email)).copied();
+            warn!("{}", email_case_duplicate_message(group, login_email));
+        }
+
+        if !groups.is_empty() && CONFIG.enforce_unique_emails() {
+            err!("Multiple accounts share an email in different forms, resolve them or disable ENFORCE_UNIQUE_EMAILS")
+        }
+        Ok(())
+    }
+
+    /// The users of all the memberships of an organization
+    pub async fn find_by_org(org_uuid: &str, conn: &mut DbConn) -> Vec<Self> {
+        db_run! {conn: {
+            users::table
+                .inner_join(users_organizations::table.on(users_organizations::user_uuid.eq(users::uuid)))
+                .filter(users_organizations::org_uuid.eq(org_uuid))
+                .select(users::all_columns)
+                .load::<UserDb>(conn)
+                .expect("Error loading users of organization")
+                .from_db()
+        }}
+    }
+
     pub async fn find_by_uuid(uuid: &str, conn: &mut DbConn) -> Option<Self> {
         db_run! {conn: {
             users::table.filter(users::uuid.eq(uuid)).first::<UserDb>(conn).ok().from_db()
@@ -389,6 +613,18 @@ impl User {
         }}
     }
 
+    /// Returns the enabled users whose keys weren't rotated since `cutoff`
+    pub async fn find_key_rotation_overdue(cutoff: &NaiveDateTime, conn: &mut DbConn) -> Vec<Self> {
+        db_run! {conn: {
+            users::table
+                .filter(users::enabled.eq(true))
+                .filter(users::key_rotated_at.lt(cutoff).or(users::key_rotated_at.is_null().and(users::created_at.lt(cutoff))))
+                .load::<UserDb>(conn)
+                .expect("Error loading users")
+                .from_db()
+        }}
+    }
+
     pub async fn last_active(&self, conn: &mut DbConn) -> Option<NaiveDateTime> {
         match Device::find_latest_active_by_user(&self.uuid, conn).await {
             Some(device) => Some(device.updated_at),
@@ -399,7 +635,7 @@ impl User {
 
 impl Invitation {
     pub fn new(email: &str) -> Self {
-        let email = email.to_lowercase();
+        let email = normalize_email(email);
         Self {
             email,
         }
@@ -439,7 +675,7 @@ impl Invitation {
     }
 
     pub async fn find_by_mail(mail: &str, conn: &mut DbConn) -> Option<Self> {
-        let lower_mail = mail.to_lowercase();
+        let lower_mail = normalize_email(mail);
         db_run! {conn: {
             invitations::table
                 .filter(invitations::email.eq(lower_mail))
@@ -456,3 +692,161 @@ impl Invitation {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn days(days: i64) -> TimeDelta {
+        TimeDelta::try_days(days).unwrap()
+    }
+
+    #[test]
+    fn email_case_variants_are_the_same_account() {
+        // Registering `User@x.com` and then `user@x.com` looks up the same stored email
+        assert_eq!(normalize_email("User@x.com"), normalize_email("user@x.com"));
+        assert_eq!(normalize_email(" User@X.com\t"), "user@x.com");
+        assert_ne!(normalize_email("user@x.com"), normalize_email("user2@x.com"));
+    }
+
+    #[test]
+    fn email_case_duplicates_detected() {
+        let emails = ["user@x.com", "User@x.com", "other@x.com", " user@X.com", "Solo@y.com"];
+        assert_eq!(email_case_duplicates(emails), vec![vec!["user@x.com", "User@x.com", " user@X.com"]]);
+        assert!(email_case_duplicates(["a@x.com", "b@x.com"]).is_empty());
+    }
+
+    #[test]
+    fn email_case_duplicate_message_names_login() {
+        let message = email_case_duplicate_message(&["user@x.com", "User@x.com"], Some("user@x.com"));
+        assert!(message.contains("only the account with \"user@x.com\" can log in"));
+        let message = email_case_duplicate_message(&["User@x.com", "USER@x.com"], None);
+        assert!(message.contains("\"user@x.com\""));
+        assert!(message.contains("none of them can log in"));
+    }
+
+    #[test]
+    #[cfg(sqlite)]
+    fn email_case_variants_rejected_by_database() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut conn = DbConn::sqlite_in_memory();
+            conn.insert_user("user1").await;
+            conn.insert_user("user2").await;
+
+            let mut user = User::find_by_uuid("user2", &mut conn).await.unwrap();
+            user.email = String::from("USER1@example.com");
+            assert!(user.save(&mut conn).await.is_err());
+
+            // Flagged accounts from older versions are left out
+            user.email_case_duplicate = true;
+            assert!(user.save(&mut conn).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn key_rotation_reminder_overdue() {
+        let now = Utc::now().naive_utc();
+        let rotated_at = now - days(100);
+        assert!(key_rotation_reminder_due(rotated_at, None, now, 90));
+
+        // One reminder per interval
+        assert!(!key_rotation_reminder_due(rotated_at, Some(now), now + days(1), 90));
+        assert!(key_rotation_reminder_due(rotated_at, Some(now), now + days(90), 90));
+    }
+
+    #[test]
+    fn key_rotation_reminder_recently_rotated() {
+        let now = Utc::now().naive_utc();
+        assert!(!key_rotation_reminder_due(now - days(10), None, now, 90));
+        // Reminded before the rotation
+        assert!(!key_rotation_reminder_due(now - days(10), Some(now - days(100)), now, 90));
+    }
+
+    #[test]
+    fn password_change_too_soon_rejected() {
+        let now = Utc::now().naive_utc();
+        let changed_at = now - TimeDelta::try_hours(2).unwrap();
+        assert_eq!(next_password_change(Some(changed_at), now, 24), Some(changed_at + days(1)));
+        assert_eq!(next_password_change(Some(now), now, 1), Some(now + TimeDelta::try_hours(1).unwrap()));
+    }
+
+    #[test]
+    fn password_change_after_interval_allowed() {
+        let now = Utc::now().naive_utc();
+        assert_eq!(next_password_change(Some(now - days(2)), now, 24), None);
+        assert_eq!(next_password_change(Some(now - days(1)), now, 24), None);
+        // Never changed before
+        assert_eq!(next_password_change(None, now, 24), None);
+    }
+
+    #[test]
+    fn unverified_account_purged_after_reminder() {
+        let now = Utc::now().naive_utc();
+        let created_at = now - days(31);
+        // Registered over the threshold ago, the verification email at registration isn't a reminder
+        assert_eq!(
+            unverified_account_action(true, None, created_at, Some(created_at), None, now, 30, 7),
+            UnverifiedAccountAction::Remind
+        );
+
+        // Reminded now, the account is kept for the reminder period
+        let reminded = Some(now);
+        assert_eq!(
+            unverified_account_action(true, None, created_at, Some(created_at), reminded, now + days(1), 30, 7),
+            UnverifiedAccountAction::None
+        );
+        assert_eq!(
+            unverified_account_action(true, None, created_at, Some(created_at), reminded, now + days(7), 30, 7),
+            UnverifiedAccountAction::Purge
+        );
+
+        let created_at = now - days(10);
+        assert_eq!(
+            unverified_account_action(true, None, created_at, Some(created_at), None, now, 30, 7),
+            UnverifiedAccountAction::None
+        );
+    }
+
+    #[test]
+    fn unverified_account_reminded_despite_recent_verification_email() {
+        let now = Utc::now().naive_utc();
+        let created_at = now - days(40);
+        // A failed login resent the verification email within the reminder period, it doesn't state the purge date
+        let sent_at = Some(now - days(8));
+        assert_eq!(
+            unverified_account_action(true, None, created_at, sent_at, None, now, 30, 7),
+            UnverifiedAccountAction::Remind
+        );
+    }
+
+    #[test]
+    fn verified_account_not_purged() {
+        let now = Utc::now().naive_utc();
+        let created_at = now - days(365);
+        let verified_at = Some(now - days(300));
+        assert_eq!(
+            unverified_account_action(true, verified_at, created_at, Some(created_at), None, now, 30, 7),
+            UnverifiedAccountAction::None
+        );
+    }
+
+    #[test]
+    fn unverified_account_kept_without_signups_verify() {
+        let now = Utc::now().naive_utc();
+        let created_at = now - days(365);
+        // Without SIGNUPS_VERIFY no account ever gets verified
+        assert_eq!(
+            unverified_account_action(false, None, created_at, None, None, now, 30, 7),
+            UnverifiedAccountAction::None
+        );
+        assert_eq!(
+            unverified_account_action(false, None, created_at, Some(created_at), None, now, 30, 7),
+            UnverifiedAccountAction::None
+        );
+        // Created before SIGNUPS_VERIFY was enabled, no verification email was ever sent
+        assert_eq!(
+            unverified_account_action(true, None, created_at, None, None, now, 30, 7),
+            UnverifiedAccountAction::None
+        );
+    }
+}
@@ -55,6 +55,9 @@ table! {
         push_token -> Nullable<Text>,
         refresh_token -> Text,
         twofactor_remember -> Nullable<Text>,
+        push_failures -> Integer,
+        twofactor_remember_at -> Nullable<Timestamp>,
+        twofactor_remember_prev -> Nullable<Text>,
     }
 }
 
@@ -76,6 +79,7 @@ table! {
         provider_uuid -> Nullable<Varchar>,
         provider_user_uuid -> Nullable<Varchar>,
         provider_org_uuid -> Nullable<Varchar>,
+        detail -> Nullable<Text>,
     }
 }
 
@@ -126,6 +130,12 @@ table! {
         billing_email -> Text,
         private_key -> Nullable<Text>,
         public_key -> Nullable<Text>,
+        seat_limit -> Nullable<Integer>,
+        legal_hold -> Bool,
+        branding_name -> Nullable<Text>,
+        branding_logo_url -> Nullable<Text>,
+        branding_logo_file -> Nullable<Text>,
+        invite_domains -> Nullable<Text>,
     }
 }
 
@@ -207,6 +217,10 @@ table! {
         api_key -> Nullable<Text>,
         avatar_color -> Nullable<Text>,
         external_id -> Nullable<Text>,
+        key_rotated_at -> Nullable<Timestamp>,
+        password_changed_at -> Nullable<Timestamp>,
+        email_case_duplicate -> Bool,
+        unverified_reminder_at -> Nullable<Timestamp>,
     }
 }
 
@@ -230,6 +244,9 @@ table! {
         atype -> Integer,
         reset_password_key -> Nullable<Text>,
         external_id -> Nullable<Text>,
+        access_schedule -> Nullable<Text>,
+        invited_at -> Nullable<Timestamp>,
+        accepted_at -> Nullable<Timestamp>,
     }
 }
 
@@ -308,6 +325,126 @@ table! {
     }
 }
 
+table! {
+    cipher_shares (cipher_uuid, user_uuid) {
+        cipher_uuid -> Text,
+        user_uuid -> Text,
+        akey -> Text,
+        read_only -> Bool,
+        creation_date -> Timestamp,
+    }
+}
+
+table! {
+    notification_preferences (user_uuid) {
+        user_uuid -> Text,
+        new_device_login -> Bool,
+        incomplete_2fa_login -> Bool,
+        emergency_access -> Bool,
+        updated_at -> Timestamp,
+        key_rotation_reminder -> Bool,
+        key_rotation_reminded_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    cipher_idempotency_keys (user_uuid, key_hash) {
+        user_uuid -> Text,
+        key_hash -> Text,
+        cipher_uuid -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    account_recovery_tokens (user_uuid) {
+        user_uuid -> Text,
+        token_hash -> Text,
+        security_stamp -> Text,
+        expires_at -> Timestamp,
+        failed_attempts -> Integer,
+    }
+}
+
+table! {
+    device_approvals (uuid) {
+        uuid -> Text,
+        user_uuid -> Text,
+        org_uuid -> Text,
+        device_identifier -> Text,
+        device_name -> Text,
+        device_type -> Integer,
+        request_ip -> Text,
+        creation_date -> Timestamp,
+        approved_by -> Nullable<Text>,
+        approval_date -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    cipher_transfers (uuid) {
+        uuid -> Text,
+        cipher_uuid -> Text,
+        user_uuid -> Text,
+        org_uuid -> Text,
+        data -> Nullable<Text>,
+        status -> Integer,
+        creation_date -> Timestamp,
+        decided_by -> Nullable<Text>,
+        decision_date -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    personal_access_tokens (uuid) {
+        uuid -> Text,
+        user_uuid -> Text,
+        name -> Text,
+        token_hash -> Text,
+        read_only -> Bool,
+        expires_at -> Nullable<Timestamp>,
+        creation_date -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+        security_stamp -> Text,
+    }
+}
+
+table! {
+    access_log (uuid) {
+        uuid -> Text,
+        user_uuid -> Text,
+        method -> Text,
+        endpoint_class -> Text,
+        ip_address -> Text,
+        device_type -> Integer,
+        client_name -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    account_inactivity (user_uuid) {
+        user_uuid -> Text,
+        warned_at -> Nullable<Timestamp>,
+        reactivated_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    twofactor_backup (user_uuid) {
+        user_uuid -> Text,
+        methods -> Integer,
+        recovery_acknowledged_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    emergency_access_quorums (grantor_uuid) {
+        grantor_uuid -> Text,
+        quorum -> Integer,
+    }
+}
+
 joinable!(attachments -> ciphers (cipher_uuid));
 joinable!(ciphers -> organizations (organization_uuid));
 joinable!(ciphers -> users (user_uuid));
@@ -336,6 +473,21 @@ joinable!(collections_groups -> collections (collections_uuid));
 joinable!(collections_groups -> groups (groups_uuid));
 joinable!(event -> users_organizations (uuid));
 joinable!(auth_requests -> users (user_uuid));
+joinable!(cipher_shares -> ciphers (cipher_uuid));
+joinable!(cipher_shares -> users (user_uuid));
+joinable!(notification_preferences -> users (user_uuid));
+joinable!(personal_access_tokens -> users (user_uuid));
+joinable!(device_approvals -> users (user_uuid));
+joinable!(account_recovery_tokens -> users (user_uuid));
+joinable!(cipher_idempotency_keys -> users (user_uuid));
+joinable!(access_log -> users (user_uuid));
+joinable!(account_inactivity -> users (user_uuid));
+joinable!(twofactor_backup -> users (user_uuid));
+joinable!(device_approvals -> organizations (org_uuid));
+joinable!(cipher_transfers -> ciphers (cipher_uuid));
+joinable!(cipher_transfers -> users (user_uuid));
+joinable!(cipher_transfers -> organizations (org_uuid));
+joinable!(emergency_access_quorums -> users (grantor_uuid));
 
 allow_tables_to_appear_in_same_query!(
     attachments,
@@ -360,4 +512,15 @@ allow_tables_to_appear_in_same_query!(
     collections_groups,
     event,
     auth_requests,
+    cipher_shares,
+    notification_preferences,
+    personal_access_tokens,
+    device_approvals,
+    account_recovery_tokens,
+    cipher_idempotency_keys,
+    access_log,
+    account_inactivity,
+    twofactor_backup,
+    cipher_transfers,
+    emergency_access_quorums,
 );
@@ -12,14 +12,14 @@ macro_rules! make_error {
 
         #[derive(Debug)]
         pub struct ErrorEvent { pub event: EventType }
-        pub struct Error { message: String, error: ErrorKind, error_code: u16, event: Option<ErrorEvent> }
+        pub struct Error { message: String, error: ErrorKind, error_code: u16, event: Option<ErrorEvent>, event_detail: Option<&'static str> }
 
         $(impl From<$ty> for Error {
             fn from(err: $ty) -> Self { Error::from((stringify!($name), err)) }
         })+
         $(impl<S: Into<String>> From<(S, $ty)> for Error {
             fn from(val: (S, $ty)) -> Self {
-                Error { message: val.0.into(), error: ErrorKind::$name(val.1), error_code: BAD_REQUEST, event: None }
+                Error { message: val.0.into(), error: ErrorKind::$name(val.1), error_code: BAD_REQUEST, event: None, event_detail: None }
             }
         })+
         impl StdError for Error {
@@ -139,9 +139,20 @@ impl Error {
         self
     }
 
+    /// A reason code stored with the event, it must never contain secrets
+    #[must_use]
+    pub const fn with_event_detail(mut self, detail: &'static str) -> Self {
+        self.event_detail = Some(detail);
+        self
+    }
+
     pub fn get_event(&self) -> &Option<ErrorEvent> {
         &self.event
     }
+
+    pub const fn get_event_detail(&self) -> Option<&'static str> {
+        self.event_detail
+    }
 }
 
 pub trait MapResult<S> {
@@ -178,11 +189,21 @@ fn _serialize(e: &impl serde::Serialize, _msg: &str) -> String {
 }
 
 fn _api_error(_: &impl std::any::Any, msg: &str) -> String {
-    let json = json!({
+    _serialize(&api_error_json(msg), "")
+}
+
+/// Returns the error body expected by the Bitwarden clients for the given message
+pub fn api_error_json(msg: &str) -> Value {
+    api_validation_error_json(msg, json!({"": [ msg ]}))
+}
+
+/// Returns the error body expected by the Bitwarden clients, with validation errors per field
+pub fn api_validation_error_json(msg: &str, validation_errors: Value) -> Value {
+    json!({
         "Message": msg,
         "error": "",
         "error_description": "",
-        "ValidationErrors": {"": [ msg ]},
+        "ValidationErrors": validation_errors,
         "ErrorModel": {
             "Message": msg,
             "Object": "error"
@@ -191,8 +212,7 @@ fn _api_error(_: &impl std::any::Any, msg: &str) -> String {
         "ExceptionStackTrace": null,
         "InnerExceptionMessage": null,
         "Object": "error"
-    });
-    _serialize(&json, "")
+    })
 }
 
 //
@@ -296,3 +316,35 @@ macro_rules! err_handler {
         return ::rocket::request::Outcome::Error((rocket::http::Status::Unauthorized, $usr_msg));
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_error_envelope() {
+        let error = Error::new("The field Name is required.", "Missing name");
+        assert_eq!(error.error_code, 400);
+
+        let body: Value = serde_json::from_str(&error.to_string()).unwrap();
+        assert_eq!(body["Message"], "The field Name is required.");
+        assert_eq!(body["ErrorModel"]["Message"], "The field Name is required.");
+        assert_eq!(body["ErrorModel"]["Object"], "error");
+        assert_eq!(body["ValidationErrors"][""][0], "The field Name is required.");
+        assert_eq!(body["Object"], "error");
+        assert!(body["error"].is_string() && body["error_description"].is_string());
+
+        let error = Error::new("Too many requests", "").with_code(429);
+        assert_eq!(error.error_code, 429);
+    }
+
+    #[test]
+    fn api_validation_error_envelope() {
+        let json = api_validation_error_json("The model state is invalid.", json!({"Ciphers[0].Notes": ["Too long"]}));
+        let error: Error = ("Import validation errors", json).into();
+
+        let body: Value = serde_json::from_str(&error.to_string()).unwrap();
+        assert_eq!(body["ErrorModel"]["Message"], "The model state is invalid.");
+        assert_eq!(body["ValidationErrors"]["Ciphers[0].Notes"][0], "Too long");
+    }
+}
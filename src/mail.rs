@@ -13,10 +13,7 @@ use lettre::{
 
 use crate::{
     api::EmptyResult,
-    auth::{
-        encode_jwt, generate_delete_claims, generate_emergency_access_invite_claims, generate_invite_claims,
-        generate_verify_email_claims,
-    },
+    auth::{encode_jwt, generate_emergency_access_invite_claims, generate_invite_claims, generate_verify_email_claims},
     error::Error,
     CONFIG,
 };
@@ -137,10 +134,7 @@ pub async fn send_password_hint(address: &str, hint: Option<String>) -> EmptyRes
     send_email(address, &subject, body_html, body_text).await
 }
 
-pub async fn send_delete_account(address: &str, uuid: &str) -> EmptyResult {
-    let claims = generate_delete_claims(uuid.to_string());
-    let delete_token = encode_jwt(&claims);
-
+pub async fn send_delete_account(address: &str, uuid: &str, delete_token: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
         "email/delete_account",
         json!({
@@ -185,6 +179,18 @@ pub async fn send_welcome(address: &str) -> EmptyResult {
     send_email(address, &subject, body_html, body_text).await
 }
 
+pub async fn send_register_existing_account(address: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/register_existing_account",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text).await
+}
+
 pub async fn send_welcome_must_verify(address: &str, uuid: &str) -> EmptyResult {
     let claims = generate_verify_email_claims(uuid.to_string());
     let verify_email_token = encode_jwt(&claims);
@@ -215,6 +221,31 @@ pub async fn send_2fa_removed_from_org(address: &str, org_name: &str) -> EmptyRe
     send_email(address, &subject, body_html, body_text).await
 }
 
+pub async fn send_two_factor_backup_missing(address: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/twofactor_backup_missing",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text).await
+}
+
+pub async fn send_2fa_method_removed_from_org(address: &str, org_name: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/send_2fa_method_removed_from_org",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "org_name": org_name,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text).await
+}
+
 pub async fn send_single_org_removed_from_org(address: &str, org_name: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
         "email/send_single_org_removed_from_org",
@@ -333,6 +364,19 @@ pub async fn send_emergency_access_recovery_approved(address: &str, grantor_name
     send_email(address, &subject, body_html, body_text).await
 }
 
+pub async fn send_emergency_access_takeover_completed(address: &str, grantee_name: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/emergency_access_takeover_completed",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "grantee_name": grantee_name,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text).await
+}
+
 pub async fn send_emergency_access_recovery_initiated(
     address: &str,
     grantee_name: &str,
@@ -373,6 +417,78 @@ pub async fn send_emergency_access_recovery_reminder(
     send_email(address, &subject, body_html, body_text).await
 }
 
+pub async fn send_key_rotation_reminder(address: &str, days: i64) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/key_rotation_reminder",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "days": days,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text).await
+}
+
+pub async fn send_inactive_account_warning(address: &str, inactive_days: i64, disable_date: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/inactive_account_warning",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "inactive_days": inactive_days,
+            "disable_date": disable_date,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text).await
+}
+
+pub async fn send_unverified_account_reminder(address: &str, uuid: &str, purge_date: &str) -> EmptyResult {
+    let claims = generate_verify_email_claims(uuid.to_string());
+    let verify_email_token = encode_jwt(&claims);
+
+    let (subject, body_html, body_text) = get_text(
+        "email/unverified_account_reminder",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "user_id": uuid,
+            "token": verify_email_token,
+            "purge_date": purge_date,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text).await
+}
+
+pub async fn send_duo_health_check_failed(address: &str, host: &str, error: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/duo_health_check_failed",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "host": host,
+            "error": error,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text).await
+}
+
+pub async fn send_db_integrity_check_failed(address: &str, problems: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/db_integrity_check_failed",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "problems": problems,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text).await
+}
+
 pub async fn send_emergency_access_recovery_rejected(address: &str, grantor_name: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
         "email/emergency_access_recovery_rejected",
@@ -446,6 +562,31 @@ pub async fn send_new_device_logged_in(address: &str, ip: &str, dt: &NaiveDateTi
     send_email(address, &subject, body_html, body_text).await
 }
 
+pub async fn send_device_approval_requested(
+    address: &str,
+    user_email: &str,
+    org_name: &str,
+    ip: &str,
+    device: &str,
+) -> EmptyResult {
+    use crate::util::upcase_first;
+    let device = upcase_first(device);
+
+    let (subject, body_html, body_text) = get_text(
+        "email/device_approval_requested",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "email": user_email,
+            "org_name": org_name,
+            "ip": ip,
+            "device": device,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text).await
+}
+
 pub async fn send_incomplete_2fa_login(address: &str, ip: &str, dt: &NaiveDateTime, device: &str) -> EmptyResult {
     use crate::util::upcase_first;
     let device = upcase_first(device);
@@ -551,37 +692,40 @@ async fn send_with_selected_transport(email: Message) -> EmptyResult {
     } else {
         match smtp_transport().send(email).await {
             Ok(_) => Ok(()),
-            // Match some common errors and make them more user friendly
-            Err(e) => {
-                if e.is_client() {
-                    debug!("SMTP client error: {:#?}", e);
-                    err!(format!("SMTP client error: {e}"));
-                } else if e.is_transient() {
-                    debug!("SMTP 4xx error: {:#?}", e);
-                    err!(format!("SMTP 4xx error: {e}"));
-                } else if e.is_permanent() {
-                    debug!("SMTP 5xx error: {:#?}", e);
-                    let mut msg = e.to_string();
-                    // Add a special check for 535 to add a more descriptive message
-                    if msg.contains("(535)") {
-                        msg = format!("{msg} - Authentication credentials invalid");
-                    }
-                    err!(format!("SMTP 5xx error: {msg}"));
-                } else if e.is_timeout() {
-                    debug!("SMTP timeout error: {:#?}", e);
-                    err!(format!("SMTP timeout error: {e}"));
-                } else if e.is_tls() {
-                    debug!("SMTP encryption error: {:#?}", e);
-                    err!(format!("SMTP encryption error: {e}"));
-                } else {
-                    debug!("SMTP error: {:#?}", e);
-                    err!(format!("SMTP error: {e}"));
-                }
-            }
+            Err(e) => err!(smtp_error_message(&e)),
         }
     }
 }
 
+// Match some common errors and make them more user friendly.
+// The SMTP errors never contain the credentials, so they are safe to log and return.
+fn smtp_error_message(e: &lettre::transport::smtp::Error) -> String {
+    if e.is_client() {
+        debug!("SMTP client error: {:#?}", e);
+        format!("SMTP client error: {e}")
+    } else if e.is_transient() {
+        debug!("SMTP 4xx error: {:#?}", e);
+        format!("SMTP 4xx error: {e}")
+    } else if e.is_permanent() {
+        debug!("SMTP 5xx error: {:#?}", e);
+        let mut msg = e.to_string();
+        // Add a special check for 535 to add a more descriptive message
+        if msg.contains("(535)") {
+            msg = format!("{msg} - Authentication credentials invalid");
+        }
+        format!("SMTP 5xx error: {msg}")
+    } else if e.is_timeout() {
+        debug!("SMTP timeout error: {:#?}", e);
+        format!("SMTP timeout error: {e}")
+    } else if e.is_tls() {
+        debug!("SMTP encryption error: {:#?}", e);
+        format!("SMTP encryption error: {e}")
+    } else {
+        debug!("SMTP error: {:#?}", e);
+        format!("SMTP error: {e}")
+    }
+}
+
 async fn send_email(address: &str, subject: &str, body_html: String, body_text: String) -> EmptyResult {
     let smtp_from = &CONFIG.smtp_from();
 
@@ -613,3 +757,91 @@ async fn send_email(address: &str, subject: &str, body_html: String, body_text:
 
     send_with_selected_transport(email).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    // A very minimal SMTP server, which accepts or rejects the authentication.
+    // The tests send through their own transport, they only cover the error reporting of `smtp_error_message`,
+    // not `send_test` with the configured transport.
+    async fn mock_smtp_server(accept_auth: bool) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            write.write_all(b"220 mock ESMTP\r\n").await.unwrap();
+
+            let mut in_data = false;
+            while let Ok(Some(line)) = lines.next_line().await {
+                let command = line.to_uppercase();
+                let reply: &[u8] = if in_data {
+                    if line != "." {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 2.0.0 Queued\r\n"
+                } else if command.starts_with("EHLO") {
+                    b"250-mock\r\n250 AUTH PLAIN LOGIN\r\n"
+                } else if command.starts_with("AUTH") && accept_auth {
+                    b"235 2.7.0 Authentication successful\r\n"
+                } else if command.starts_with("AUTH") {
+                    b"535 5.7.8 Authentication credentials invalid\r\n"
+                } else if command.starts_with("DATA") {
+                    in_data = true;
+                    b"354 End data with <CR><LF>.<CR><LF>\r\n"
+                } else if command.starts_with("QUIT") {
+                    write.write_all(b"221 2.0.0 Bye\r\n").await.ok();
+                    break;
+                } else {
+                    b"250 2.0.0 OK\r\n"
+                };
+                write.write_all(reply).await.unwrap();
+            }
+        });
+
+        port
+    }
+
+    async fn send_mock_mail(port: u16) -> Result<(), lettre::transport::smtp::Error> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("127.0.0.1")
+            .port(port)
+            .credentials(Credentials::new(String::from("user"), String::from("smtp-secret")))
+            .authentication(vec![SmtpAuthMechanism::Plain])
+            .build();
+        let email = Message::builder()
+            .from("Vaultwarden <vaultwarden@example.com>".parse().unwrap())
+            .to("user@example.com".parse().unwrap())
+            .subject("Test")
+            .body(String::from("Test"))
+            .unwrap();
+
+        transport.send(email).await.map(|_| ())
+    }
+
+    #[test]
+    fn smtp_mock_delivery_succeeds() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let port = mock_smtp_server(true).await;
+            assert!(send_mock_mail(port).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn smtp_auth_failure_reported() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let port = mock_smtp_server(false).await;
+            let error = send_mock_mail(port).await.unwrap_err();
+
+            let msg = smtp_error_message(&error);
+            assert!(msg.starts_with("SMTP 5xx error"));
+            assert!(msg.contains("Authentication credentials invalid"));
+            assert!(!msg.contains("smtp-secret"));
+        });
+    }
+}
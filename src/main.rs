@@ -43,6 +43,7 @@ use tokio::{
 mod error;
 mod api;
 mod auth;
+mod captcha;
 mod config;
 mod crypto;
 #[macro_use]
@@ -87,8 +88,13 @@ async fn main() -> Result<(), Error> {
     create_dir(&CONFIG.attachments_folder(), "attachments folder");
 
     let pool = create_db_pool().await;
+    api::init_push_tracking(pool.clone());
     schedule_jobs(pool.clone());
     crate::db::models::TwoFactor::migrate_u2f_to_webauthn(&mut pool.get().await.unwrap()).await.unwrap();
+    crate::db::models::User::check_email_case_duplicates(&mut pool.get().await.unwrap()).await.unwrap_or_else(|e| {
+        error!("{e}, exiting...");
+        exit(1);
+    });
 
     launch_rocket(pool, extra_debug).await // Blocks until program termination.
 }
@@ -105,6 +111,8 @@ FLAGS:
 
 COMMAND:
     hash [--preset {bitwarden|owasp}]  Generate an Argon2id PHC ADMIN_TOKEN
+    break-glass                        Generate a one-time token to reset a lost ADMIN_TOKEN on the admin page
+    test-smtp <email>                  Send a test email using the current mail configuration
 
 PRESETS:                  m=         t=          p=
     bitwarden (default) 64MiB, 3 Iterations, 4 Threads
@@ -129,28 +137,12 @@ fn parse_args() {
 
     if let Some(command) = pargs.subcommand().unwrap_or_default() {
         if command == "hash" {
-            use argon2::{
-                password_hash::SaltString, Algorithm::Argon2id, Argon2, ParamsBuilder, PasswordHasher, Version::V0x13,
-            };
-
-            let mut argon2_params = ParamsBuilder::new();
             let preset: Option<String> = pargs.opt_value_from_str(["-p", "--preset"]).unwrap_or_default();
-            let selected_preset;
-            match preset.as_deref() {
-                Some("owasp") => {
-                    selected_preset = "owasp";
-                    argon2_params.m_cost(19456);
-                    argon2_params.t_cost(2);
-                    argon2_params.p_cost(1);
-                }
-                _ => {
-                    // Bitwarden preset is the default
-                    selected_preset = "bitwarden";
-                    argon2_params.m_cost(65540);
-                    argon2_params.t_cost(3);
-                    argon2_params.p_cost(4);
-                }
-            }
+            let (selected_preset, argon2_params) = match preset.as_deref() {
+                Some("owasp") => ("owasp", crypto::ARGON2_OWASP_PRESET),
+                // Bitwarden preset is the default
+                _ => ("bitwarden", crypto::ARGON2_BITWARDEN_PRESET),
+            };
 
             println!("Generate an Argon2id PHC string using the '{selected_preset}' preset:\n");
 
@@ -166,21 +158,56 @@ fn parse_args() {
                 exit(1);
             }
 
-            let argon2 = Argon2::new(Argon2id, V0x13, argon2_params.build().unwrap());
-            let salt = SaltString::encode_b64(&crate::crypto::get_random_bytes::<32>()).unwrap();
-
             let argon2_timer = tokio::time::Instant::now();
-            if let Ok(password_hash) = argon2.hash_password(password.as_bytes(), &salt) {
-                println!(
+            match crypto::hash_argon2id(password.as_bytes(), argon2_params) {
+                Ok(password_hash) => println!(
                     "\n\
                     ADMIN_TOKEN='{password_hash}'\n\n\
                     Generation of the Argon2id PHC string took: {:?}",
                     argon2_timer.elapsed()
-                );
-            } else {
-                error!("Unable to generate Argon2id PHC hash.");
+                ),
+                Err(e) => {
+                    error!("{e}");
+                    exit(1);
+                }
+            }
+        } else if command == "break-glass" {
+            match api::create_break_glass_token(&CONFIG.break_glass_token_filename()) {
+                Ok(token) => println!(
+                    "Break-glass token, use it once on the admin login page to set a new admin token:\n\n{token}\n\n\
+                    Only a hash is stored in `{}`, running this command again replaces the token.",
+                    CONFIG.break_glass_token_filename()
+                ),
+                Err(e) => {
+                    println!("Failed to create the break-glass token: {e:?}");
+                    exit(1);
+                }
+            }
+        } else if command == "test-smtp" {
+            let Ok(address) = pargs.free_from_str::<String>() else {
+                println!("Missing the email address to send the test email to");
+                exit(1);
+            };
+
+            if !CONFIG.mail_enabled() {
+                println!("Mail is not enabled, configure SMTP or sendmail first");
                 exit(1);
             }
+
+            // We are already inside the main runtime here, so send the email from a separate one
+            let result = thread::spawn(move || {
+                tokio::runtime::Runtime::new().unwrap().block_on(async { mail::send_test(&address).await })
+            })
+            .join()
+            .unwrap();
+
+            match result {
+                Ok(()) => println!("Test email sent successfully"),
+                Err(e) => {
+                    println!("Failed to send the test email: {e:?}");
+                    exit(1);
+                }
+            }
         }
         exit(0);
     }
@@ -483,19 +510,22 @@ async fn launch_rocket(pool: db::DbPool, extra_debug: bool) -> Result<(), Error>
     config.temp_dir = canonicalize(CONFIG.tmp_folder()).unwrap().into();
     config.cli_colors = false; // Make sure Rocket does not color any values for logging.
     config.limits = Limits::new()
-        .limit("json", 20.megabytes()) // 20MB should be enough for very large imports, something like 5000+ vault entries
+        .limit("json", CONFIG.import_max_size_mb().megabytes()) // Imports are the largest JSON requests
         .limit("data-form", 525.megabytes()) // This needs to match the maximum allowed file size for Send
         .limit("file", 525.megabytes()); // This needs to match the maximum allowed file size for attachments
 
+    // The websocket notifications are long-lived, so they never get a request timeout
+    let timeout = |routes| util::with_request_timeout(routes, CONFIG.request_timeout_secs());
+
     // If adding more paths here, consider also adding them to
     // crate::utils::LOGGED_ROUTES to make sure they appear in the log
     let instance = rocket::custom(config)
-        .mount([basepath, "/"].concat(), api::web_routes())
-        .mount([basepath, "/api"].concat(), api::core_routes())
-        .mount([basepath, "/admin"].concat(), api::admin_routes())
-        .mount([basepath, "/events"].concat(), api::core_events_routes())
-        .mount([basepath, "/identity"].concat(), api::identity_routes())
-        .mount([basepath, "/icons"].concat(), api::icons_routes())
+        .mount([basepath, "/"].concat(), timeout(api::web_routes()))
+        .mount([basepath, "/api"].concat(), timeout(api::core_routes()))
+        .mount([basepath, "/admin"].concat(), timeout(api::admin_routes()))
+        .mount([basepath, "/events"].concat(), timeout(api::core_events_routes()))
+        .mount([basepath, "/identity"].concat(), timeout(api::identity_routes()))
+        .mount([basepath, "/icons"].concat(), timeout(api::icons_routes()))
         .mount([basepath, "/notifications"].concat(), api::notifications_routes())
         .register([basepath, "/"].concat(), api::web_catchers())
         .register([basepath, "/api"].concat(), api::core_catchers())
@@ -505,7 +535,12 @@ async fn launch_rocket(pool: db::DbPool, extra_debug: bool) -> Result<(), Error>
         .manage(Arc::clone(&WS_ANONYMOUS_SUBSCRIPTIONS))
         .attach(util::AppHeaders())
         .attach(util::Cors())
+        .attach(util::Compression {
+            enabled: CONFIG.enable_compression(),
+            min_size: CONFIG.compression_min_size() as usize,
+        })
         .attach(util::BetterLogging(extra_debug))
+        .attach(db::ReadAfterWrite())
         .ignite()
         .await?;
 
@@ -529,6 +564,15 @@ fn schedule_jobs(pool: db::DbPool) {
         return;
     }
 
+    // The jobs use their own small pool, so they don't contend with the request traffic
+    let pool = match db::DbPool::from_config_for_jobs() {
+        Ok(jobs_pool) => jobs_pool,
+        Err(e) => {
+            warn!("Error creating the database pool for scheduled jobs, using the main pool: {:?}", e);
+            pool
+        }
+    };
+
     let runtime = tokio::runtime::Runtime::new().unwrap();
 
     thread::Builder::new()
@@ -570,6 +614,46 @@ fn schedule_jobs(pool: db::DbPool) {
                 }));
             }
 
+            // Remind users to change their master password when it hasn't been changed for a while.
+            if !CONFIG.key_rotation_reminder_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.key_rotation_reminder_schedule().parse().unwrap(), || {
+                    runtime.spawn(api::key_rotation_reminder_job(pool.clone()));
+                }));
+            }
+
+            // Check whether the global Duo integration still works, so a broken one is noticed before users are locked out.
+            if !CONFIG.duo_health_check_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.duo_health_check_schedule().parse().unwrap(), || {
+                    runtime.spawn(api::duo_health_check_job());
+                }));
+            }
+
+            // Check the SQLite database for corruption, so it is noticed before data is lost.
+            if !CONFIG.db_integrity_check_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.db_integrity_check_schedule().parse().unwrap(), || {
+                    runtime.spawn(db::db_integrity_check_job(pool.clone()));
+                }));
+            }
+
+            // Keep the SQLite database fast and compact, preferably during low traffic as VACUUM locks the database.
+            if !CONFIG.db_optimize_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.db_optimize_schedule().parse().unwrap(), || {
+                    runtime.spawn(db::db_optimize_job(pool.clone(), false));
+                }));
+            }
+            if !CONFIG.db_vacuum_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.db_vacuum_schedule().parse().unwrap(), || {
+                    runtime.spawn(db::db_optimize_job(pool.clone(), true));
+                }));
+            }
+
+            // Replace the key signing the tokens, the previous key stays valid during the overlap window.
+            if !CONFIG.jwt_key_rotation_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.jwt_key_rotation_schedule().parse().unwrap(), || {
+                    runtime.spawn(api::jwt_key_rotation_job());
+                }));
+            }
+
             // Send reminders to emergency access grantors that there are pending
             // emergency access requests.
             if !CONFIG.emergency_notification_reminder_schedule().is_empty() {
@@ -584,8 +668,48 @@ fn schedule_jobs(pool: db::DbPool) {
                 }));
             }
 
+            // Remove organization invitations which weren't accepted in time, freeing their seats.
+            if !CONFIG.org_invite_purge_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.org_invite_purge_schedule().parse().unwrap(), || {
+                    runtime.spawn(api::purge_expired_org_invitations(pool.clone()));
+                }));
+            }
+
+            // Remove attachment files which were left behind without an attachment in the database.
+            if !CONFIG.orphaned_attachments_purge_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.orphaned_attachments_purge_schedule().parse().unwrap(), || {
+                    runtime.spawn(api::purge_orphaned_attachments(pool.clone()));
+                }));
+            }
+
+            // Warn and disable accounts without activity for INACTIVE_ACCOUNT_DISABLE_DAYS.
+            if CONFIG.inactive_account_disable_days().is_some()
+                && !CONFIG.inactive_account_disable_schedule().is_empty()
+            {
+                sched.add(Job::new(CONFIG.inactive_account_disable_schedule().parse().unwrap(), || {
+                    runtime.spawn(api::inactive_account_disable_job(pool.clone()));
+                }));
+            }
+
+            // Remind and delete accounts which didn't verify their email address within UNVERIFIED_ACCOUNT_PURGE_DAYS.
+            if CONFIG.unverified_account_purge_days().is_some()
+                && !CONFIG.unverified_account_purge_schedule().is_empty()
+            {
+                sched.add(Job::new(CONFIG.unverified_account_purge_schedule().parse().unwrap(), || {
+                    runtime.spawn(api::unverified_account_purge_job(pool.clone()));
+                }));
+            }
+
+            // Cleanup the access log of entries older than ACCESS_LOG_DAYS_RETAIN.
+            // Always scheduled, since the access log can be enabled from the admin panel.
+            if !CONFIG.access_log_cleanup_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.access_log_cleanup_schedule().parse().unwrap(), || {
+                    runtime.spawn(api::access_log_cleanup_job(pool.clone()));
+                }));
+            }
+
             // Cleanup the event table of records x days old.
-            if CONFIG.org_events_enabled()
+            if (CONFIG.org_events_enabled() || CONFIG.admin_events_enabled())
                 && !CONFIG.event_cleanup_schedule().is_empty()
                 && CONFIG.events_days_retain().is_some()
             {
@@ -1,5 +1,10 @@
+use dashmap::DashMap;
 use once_cell::sync::Lazy;
-use std::{net::IpAddr, num::NonZeroU32, time::Duration};
+use std::{
+    net::IpAddr,
+    num::NonZeroU32,
+    time::{Duration, Instant},
+};
 
 use governor::{clock::DefaultClock, state::keyed::DashMapStateStore, Quota, RateLimiter};
 
@@ -19,6 +24,68 @@ static LIMITER_ADMIN: Lazy<Limiter> = Lazy::new(|| {
     RateLimiter::keyed(Quota::with_period(seconds).expect("Non-zero admin ratelimit seconds").allow_burst(burst))
 });
 
+// Keyed by the Send uuid and the client IP, so one client guessing the password of a Send
+// doesn't lock out other recipients, and doesn't affect any other Send.
+type SendAccessKey = (String, IpAddr);
+
+static LIMITER_SEND_ACCESS: Lazy<FailureLimiter<SendAccessKey>> =
+    Lazy::new(|| FailureLimiter::new(CONFIG.send_access_ratelimit_seconds(), CONFIG.send_access_ratelimit_max_burst()));
+
+/// Like the other limiters, but only the failed attempts count, so clients which know the password are never throttled.
+/// Every key gets a burst of failures, after which one more failure is allowed per period.
+struct FailureLimiter<K: std::hash::Hash + Eq> {
+    period: Duration,
+    burst: u32,
+    // The time at which all failures of a key would have been forgiven
+    forgiven_at: DashMap<K, Instant>,
+}
+
+impl<K: std::hash::Hash + Eq> FailureLimiter<K> {
+    fn new(seconds: u64, burst: u32) -> Self {
+        Self {
+            period: Duration::from_secs(seconds),
+            burst,
+            forgiven_at: DashMap::new(),
+        }
+    }
+
+    fn is_limited(&self, key: &K, now: Instant) -> bool {
+        self.forgiven_at.get(key).is_some_and(|forgiven_at| {
+            *forgiven_at > now && forgiven_at.saturating_duration_since(now) >= self.period * self.burst
+        })
+    }
+
+    fn add_failure(&self, key: K, now: Instant) {
+        // Keep the map small, the forgiven keys aren't limited anymore
+        if self.forgiven_at.len() >= 1000 {
+            self.forgiven_at.retain(|_, forgiven_at| *forgiven_at > now);
+        }
+        let mut forgiven_at = self.forgiven_at.entry(key).or_insert(now);
+        *forgiven_at = (*forgiven_at).max(now) + self.period;
+    }
+}
+
+// Keyed by the user uuid, exporting a whole organization vault is expensive and repeated exports may be exfiltration
+static LIMITER_EXPORT: Lazy<Limiter<String>> =
+    Lazy::new(|| export_limiter(CONFIG.export_ratelimit_seconds(), CONFIG.export_ratelimit_max_burst()));
+
+fn export_limiter(seconds: u64, burst: u32) -> Limiter<String> {
+    let seconds = Duration::from_secs(seconds);
+    let burst = NonZeroU32::new(burst).expect("Non-zero export ratelimit burst");
+    RateLimiter::keyed(Quota::with_period(seconds).expect("Non-zero export ratelimit seconds").allow_burst(burst))
+}
+
+// Only created when `ICON_RATELIMIT_SECONDS` is set
+static LIMITER_ICON: Lazy<Option<Limiter>> = Lazy::new(|| {
+    CONFIG.icon_ratelimit_seconds().map(|seconds| icon_limiter(seconds, CONFIG.icon_ratelimit_max_burst()))
+});
+
+fn icon_limiter(seconds: u64, burst: u32) -> Limiter {
+    let seconds = Duration::from_secs(seconds);
+    let burst = NonZeroU32::new(burst).expect("Non-zero icon ratelimit burst");
+    RateLimiter::keyed(Quota::with_period(seconds).expect("Non-zero icon ratelimit seconds").allow_burst(burst))
+}
+
 pub fn check_limit_login(ip: &IpAddr) -> Result<(), Error> {
     match LIMITER_LOGIN.check_key(ip) {
         Ok(_) => Ok(()),
@@ -36,3 +103,114 @@ pub fn check_limit_admin(ip: &IpAddr) -> Result<(), Error> {
         }
     }
 }
+
+/// Checked before the password, so a throttled client can't try any further passwords
+pub fn check_limit_send_access(send_uuid: &str, ip: &IpAddr) -> Result<(), Error> {
+    _check_limit_send_access(&LIMITER_SEND_ACCESS, send_uuid, ip, Instant::now())
+}
+
+fn _check_limit_send_access(
+    limiter: &FailureLimiter<SendAccessKey>,
+    send_uuid: &str,
+    ip: &IpAddr,
+    now: Instant,
+) -> Result<(), Error> {
+    if limiter.is_limited(&(send_uuid.to_string(), *ip), now) {
+        err_code!("Too many attempts to access this Send, try again later", 429);
+    }
+    Ok(())
+}
+
+pub fn register_send_access_failure(send_uuid: &str, ip: &IpAddr) {
+    LIMITER_SEND_ACCESS.add_failure((send_uuid.to_string(), *ip), Instant::now());
+}
+
+pub fn check_limit_export(user_uuid: &str) -> Result<(), Error> {
+    _check_limit_export(&LIMITER_EXPORT, user_uuid)
+}
+
+fn _check_limit_export(limiter: &Limiter<String>, user_uuid: &str) -> Result<(), Error> {
+    match limiter.check_key(&user_uuid.to_string()) {
+        Ok(_) => Ok(()),
+        Err(_e) => {
+            err_code!("Too many vault exports, try again later", 429);
+        }
+    }
+}
+
+pub fn check_limit_icon(ip: &IpAddr) -> Result<(), Error> {
+    match LIMITER_ICON.as_ref() {
+        Some(limiter) => _check_limit_icon(limiter, ip),
+        None => Ok(()),
+    }
+}
+
+fn _check_limit_icon(limiter: &Limiter, ip: &IpAddr) -> Result<(), Error> {
+    match limiter.check_key(ip) {
+        Ok(_) => Ok(()),
+        Err(_e) => {
+            err_code!("Too many icon requests", 429);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_access_throttled_after_repeated_failures() {
+        let limiter = FailureLimiter::new(60, 3);
+        let ip: IpAddr = "192.0.2.10".parse().unwrap();
+        let other_ip: IpAddr = "192.0.2.11".parse().unwrap();
+        let now = Instant::now();
+
+        // Successful accesses don't count
+        for _ in 0..10 {
+            assert!(_check_limit_send_access(&limiter, "send-a", &ip, now).is_ok());
+        }
+
+        for _ in 0..3 {
+            assert!(_check_limit_send_access(&limiter, "send-a", &ip, now).is_ok());
+            limiter.add_failure((String::from("send-a"), ip), now);
+        }
+        assert!(_check_limit_send_access(&limiter, "send-a", &ip, now).is_err());
+
+        // Other clients and other Sends are not affected
+        assert!(_check_limit_send_access(&limiter, "send-a", &other_ip, now).is_ok());
+        assert!(_check_limit_send_access(&limiter, "send-b", &ip, now).is_ok());
+
+        // One more attempt after each period
+        let later = now + Duration::from_secs(60);
+        assert!(_check_limit_send_access(&limiter, "send-a", &ip, later).is_ok());
+        limiter.add_failure((String::from("send-a"), ip), later);
+        assert!(_check_limit_send_access(&limiter, "send-a", &ip, later).is_err());
+    }
+
+    #[test]
+    fn export_throttled_per_user() {
+        let limiter = export_limiter(720, 5);
+
+        for _ in 0..5 {
+            assert!(_check_limit_export(&limiter, "user-a").is_ok());
+        }
+        assert!(_check_limit_export(&limiter, "user-a").is_err());
+
+        // Other users are not affected
+        assert!(_check_limit_export(&limiter, "user-b").is_ok());
+    }
+
+    #[test]
+    fn icon_requests_throttled_per_ip() {
+        let limiter = icon_limiter(60, 3);
+        let ip: IpAddr = "192.0.2.10".parse().unwrap();
+
+        for _ in 0..3 {
+            assert!(_check_limit_icon(&limiter, &ip).is_ok());
+        }
+        assert!(_check_limit_icon(&limiter, &ip).is_err());
+
+        // Other clients are not affected
+        assert!(_check_limit_icon(&limiter, &"192.0.2.11".parse().unwrap()).is_ok());
+    }
+}
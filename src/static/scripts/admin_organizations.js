@@ -30,10 +30,80 @@ function deleteOrganization(event) {
     }
 }
 
+function enableLegalHold(event) {
+    event.preventDefault();
+    event.stopPropagation();
+    const org_uuid = event.target.dataset.vwOrgUuid;
+    const org_name = event.target.dataset.vwOrgName;
+    if (!org_uuid) {
+        alert("Required parameters not found!");
+        return false;
+    }
+    const confirmed = confirm(`Are you sure you want to place organization "${org_name}" under legal hold? Its items can't be permanently deleted until the hold is released.`);
+    if (confirmed) {
+        _post(`${BASE_URL}/admin/organizations/${org_uuid}/legal-hold/enable`,
+            "Legal hold placed successfully",
+            "Error placing legal hold"
+        );
+    }
+}
+
+function disableLegalHold(event) {
+    event.preventDefault();
+    event.stopPropagation();
+    const org_uuid = event.target.dataset.vwOrgUuid;
+    const org_name = event.target.dataset.vwOrgName;
+    if (!org_uuid) {
+        alert("Required parameters not found!");
+        return false;
+    }
+    const confirmed = confirm(`Are you sure you want to release the legal hold of organization "${org_name}"?`);
+    if (confirmed) {
+        _post(`${BASE_URL}/admin/organizations/${org_uuid}/legal-hold/disable`,
+            "Legal hold released successfully",
+            "Error releasing legal hold"
+        );
+    }
+}
+
+function setSeatLimit(event) {
+    event.preventDefault();
+    event.stopPropagation();
+    const org_uuid = event.target.dataset.vwOrgUuid;
+    const org_name = event.target.dataset.vwOrgName;
+    if (!org_uuid) {
+        alert("Required parameters not found!");
+        return false;
+    }
+    const input = prompt(`Maximum number of members of organization "${org_name}", including pending invitations. Leave empty for no limit.`, event.target.dataset.vwSeatLimit);
+    if (input === null) {
+        return false;
+    }
+    const seat_limit = input.trim() === "" ? null : Number(input);
+    if (seat_limit !== null && (!Number.isInteger(seat_limit) || seat_limit < 0)) {
+        alert("The seat limit must be a whole number");
+        return false;
+    }
+    _post(`${BASE_URL}/admin/organizations/${org_uuid}/seat-limit`,
+        "Seat limit updated successfully",
+        "Error updating seat limit",
+        JSON.stringify({ "seat_limit": seat_limit })
+    );
+}
+
 function initActions() {
     document.querySelectorAll("button[vw-delete-organization]").forEach(btn => {
         btn.addEventListener("click", deleteOrganization);
     });
+    document.querySelectorAll("button[vw-enable-legal-hold]").forEach(btn => {
+        btn.addEventListener("click", enableLegalHold);
+    });
+    document.querySelectorAll("button[vw-disable-legal-hold]").forEach(btn => {
+        btn.addEventListener("click", disableLegalHold);
+    });
+    document.querySelectorAll("button[vw-set-seat-limit]").forEach(btn => {
+        btn.addEventListener("click", setSeatLimit);
+    });
 
     if (jdenticon) {
         jdenticon();
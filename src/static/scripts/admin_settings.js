@@ -79,6 +79,33 @@ function backupDatabase(event) {
     );
 }
 
+function revokeTokens(event) {
+    event.preventDefault();
+    event.stopPropagation();
+    const input = prompt(
+        "This will log out every user on every device, they will have to log in again. Type 'REVOKE' to proceed:"
+    );
+    if (input === "REVOKE") {
+        _post(`${BASE_URL}/admin/tokens/revoke`,
+            "All sessions revoked",
+            "Error revoking sessions", null, false
+        );
+    } else {
+        alert("Wrong input, please try again");
+    }
+}
+
+function rotateJwtKey(event) {
+    event.preventDefault();
+    event.stopPropagation();
+    if (confirm("Replace the key signing the tokens? Tokens signed with the previous key stay valid during the overlap window.")) {
+        _post(`${BASE_URL}/admin/tokens/rotate-key`,
+            "Signing key rotated",
+            "Error rotating the signing key", null, false
+        );
+    }
+}
+
 // Two functions to help check if there were changes to the form fields
 // Useful for example during the smtp test to prevent people from clicking save before testing there new settings
 function initChangeDetection(form) {
@@ -217,6 +244,14 @@ document.addEventListener("DOMContentLoaded", (/*event*/) => {
     if (btnBackupDatabase) {
         btnBackupDatabase.addEventListener("click", backupDatabase);
     }
+    const btnRevokeTokens = document.getElementById("revokeTokens");
+    if (btnRevokeTokens) {
+        btnRevokeTokens.addEventListener("click", revokeTokens);
+    }
+    const btnRotateJwtKey = document.getElementById("rotateJwtKey");
+    if (btnRotateJwtKey) {
+        btnRotateJwtKey.addEventListener("click", rotateJwtKey);
+    }
     const btnDeleteConf = document.getElementById("deleteConf");
     if (btnDeleteConf) {
         btnDeleteConf.addEventListener("click", deleteConf);
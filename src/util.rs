@@ -1,7 +1,7 @@
 //
 // Web Headers and caching
 //
-use std::{collections::HashMap, io::Cursor, ops::Deref, path::Path};
+use std::{collections::HashMap, io::Cursor, ops::Deref, path::Path, sync::Arc};
 
 use num_traits::ToPrimitive;
 use once_cell::sync::Lazy;
@@ -10,7 +10,8 @@ use rocket::{
     http::{ContentType, Header, HeaderMap, Method, Status},
     request::FromParam,
     response::{self, Responder},
-    Data, Orbit, Request, Response, Rocket,
+    route::{self, Handler},
+    Data, Orbit, Request, Response, Rocket, Route,
 };
 
 use tokio::{
@@ -171,6 +172,131 @@ impl Fairing for Cors {
     }
 }
 
+/// Compresses responses with brotli or gzip when the client supports it.
+pub struct Compression {
+    pub enabled: bool,
+    pub min_size: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ContentEncoding {
+    Brotli,
+    Gzip,
+}
+
+impl ContentEncoding {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// Picks the encoding from an `Accept-Encoding` header, brotli is preferred over gzip
+    fn from_accept_encoding(header: &str) -> Option<Self> {
+        let accepted: Vec<String> = header
+            .split(',')
+            .filter_map(|item| {
+                let mut params = item.split(';').map(str::trim);
+                let name = params.next()?.to_lowercase();
+                let disabled = params.any(|p| p.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+                (!disabled).then_some(name)
+            })
+            .collect();
+
+        [Self::Brotli, Self::Gzip].into_iter().find(|e| accepted.iter().any(|a| a == e.name()))
+    }
+
+    fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        use std::io::Write;
+
+        match self {
+            Self::Brotli => {
+                let mut compressed = Vec::new();
+                {
+                    // Quality 5 keeps the compression fast enough for large sync responses
+                    let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+                    writer.write_all(data)?;
+                }
+                Ok(compressed)
+            }
+            Self::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+impl Compression {
+    // Attachments and Sends are encrypted, and images are already compressed, so only text is worth compressing
+    fn is_compressible(content_type: &ContentType) -> bool {
+        content_type.top() == "text"
+            || content_type.is_json()
+            || content_type.is_javascript()
+            || content_type.is_xml()
+            || content_type.is_svg()
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Compression",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if !self.enabled || res.headers().contains("Content-Encoding") {
+            return;
+        }
+        if !res.content_type().is_some_and(|ct| Compression::is_compressible(&ct)) {
+            return;
+        }
+        // Only compress bodies with a known size, streamed bodies are not read into memory
+        if !res.body().preset_size().is_some_and(|size| size >= self.min_size) {
+            return;
+        }
+        let Some(encoding) = req.headers().get("Accept-Encoding").find_map(ContentEncoding::from_accept_encoding)
+        else {
+            return;
+        };
+
+        let body = match res.body_mut().to_bytes().await {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to read response body for compression: {e:#?}");
+                // The body is partially consumed at this point and can't be sent anymore
+                res.set_status(Status::InternalServerError);
+                res.set_sized_body(0, Cursor::new(Vec::new()));
+                return;
+            }
+        };
+        // The body is shared with the compression task, so it can still be sent uncompressed when that fails
+        let body: Arc<[u8]> = body.into();
+        let to_compress = Arc::clone(&body);
+        let compressed = tokio::task::spawn_blocking(move || encoding.compress(&to_compress)).await;
+        match compressed {
+            Ok(Ok(compressed)) => {
+                res.set_raw_header("Content-Encoding", encoding.name());
+                res.adjoin_raw_header("Vary", "Accept-Encoding");
+                res.set_sized_body(compressed.len(), Cursor::new(compressed));
+            }
+            Ok(Err(e)) => {
+                error!("Failed to compress response: {e:#?}");
+                res.set_sized_body(body.len(), Cursor::new(body));
+            }
+            Err(e) => {
+                error!("Failed to compress response: {e:#?}");
+                res.set_sized_body(body.len(), Cursor::new(body));
+            }
+        }
+    }
+}
+
 pub struct Cached<R> {
     response: R,
     is_immutable: bool,
@@ -201,6 +327,11 @@ impl<R> Cached<R> {
             ttl,
         }
     }
+
+    #[cfg(test)]
+    pub fn response(&self) -> &R {
+        &self.response
+    }
 }
 
 impl<'r, R: 'r + Responder<'r, 'static> + Send> Responder<'r, 'static> for Cached<R> {
@@ -257,6 +388,49 @@ impl<'r> FromParam<'r> for SafeString {
     }
 }
 
+/// Wraps a route handler to cut off requests which aren't handled within the timeout with a 504 Gateway Timeout.
+/// The handler is dropped at the timeout, which cancels the work it was still doing.
+#[derive(Clone)]
+pub struct TimeoutHandler {
+    inner: Box<dyn Handler>,
+    timeout: Duration,
+}
+
+#[rocket::async_trait]
+impl Handler for TimeoutHandler {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        match tokio::time::timeout(self.timeout, self.inner.handle(request, data)).await {
+            Ok(outcome) => outcome,
+            Err(_) => {
+                warn!(
+                    "{} {} exceeded the request timeout of {:?}",
+                    request.method(),
+                    request.uri().path(),
+                    self.timeout
+                );
+                route::Outcome::Error(Status::GatewayTimeout)
+            }
+        }
+    }
+}
+
+/// Applies `REQUEST_TIMEOUT_SECS` to the read-only (`GET` and `HEAD`) routes, without a timeout they are returned as-is.
+/// Routes which write are never cut off, as dropping their handler could leave partial changes behind.
+pub fn with_request_timeout(routes: Vec<Route>, timeout_secs: Option<u64>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            if let (Some(timeout), Method::Get | Method::Head) = (timeout_secs, route.method) {
+                route.handler = Box::new(TimeoutHandler {
+                    inner: route.handler,
+                    timeout: Duration::from_secs(timeout),
+                });
+            }
+            route
+        })
+        .collect()
+}
+
 // Log all the routes from the main paths list, and the attachments endpoint
 // Effectively ignores, any static file route, and the alive endpoint
 const LOGGED_ROUTES: [&str; 7] = ["/api", "/admin", "/identity", "/icons", "/attachments", "/events", "/notifications"];
@@ -490,6 +664,11 @@ pub fn parse_date(date: &str) -> NaiveDateTime {
     NaiveDateTime::parse_from_str(date, DATETIME_FORMAT).unwrap()
 }
 
+/// Like `parse_date`, for dates from request parameters which can be malformed
+pub fn try_parse_date(date: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(date, DATETIME_FORMAT).ok()
+}
+
 //
 // Deployment environment methods
 //
@@ -650,6 +829,124 @@ impl NumberOrString {
     }
 }
 
+//
+// Multipart form methods
+//
+
+use rocket::{
+    data::{self, FromData},
+    form::{self, DataField, Form, FromForm, ValueField},
+};
+
+/// Wraps a multipart form and rejects it with a `400` when it has too many parts or when its text parts are too large.
+/// Once a limit is exceeded the remaining parts are skipped, so they don't end up being processed or written to disk.
+pub struct LimitedParts<T> {
+    pub data: T,
+}
+
+pub struct LimitedPartsContext<'r, T: FromForm<'r>> {
+    inner: T::Context,
+    max_parts: usize,
+    max_fields_size: usize,
+    parts: usize,
+    fields_size: usize,
+    exceeded: bool,
+}
+
+impl<'r, T: FromForm<'r>> LimitedPartsContext<'r, T> {
+    fn new(opts: form::Options, max_parts: usize, max_fields_size: usize) -> Self {
+        Self {
+            inner: T::init(opts),
+            max_parts,
+            max_fields_size,
+            parts: 0,
+            fields_size: 0,
+            exceeded: false,
+        }
+    }
+
+    /// Counts a new part and returns whether it can still be processed
+    fn count_part(&mut self, field_size: usize) -> bool {
+        self.parts += 1;
+        self.fields_size = self.fields_size.saturating_add(field_size);
+        if self.parts > self.max_parts || self.fields_size > self.max_fields_size {
+            self.exceeded = true;
+        }
+        !self.exceeded
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: FromForm<'r>> FromForm<'r> for LimitedParts<T> {
+    type Context = LimitedPartsContext<'r, T>;
+
+    fn init(opts: form::Options) -> Self::Context {
+        let max_fields_size = CONFIG.multipart_max_fields_size().saturating_mul(1024);
+        LimitedPartsContext::new(opts, CONFIG.multipart_max_parts() as usize, max_fields_size as usize)
+    }
+
+    fn push_value(ctxt: &mut Self::Context, field: ValueField<'r>) {
+        if ctxt.count_part(field.value.len()) {
+            T::push_value(&mut ctxt.inner, field);
+        }
+    }
+
+    async fn push_data(ctxt: &mut Self::Context, field: DataField<'r, '_>) {
+        // The size of file parts is already limited by the `data-form` and `file` limits
+        if ctxt.count_part(0) {
+            T::push_data(&mut ctxt.inner, field).await;
+        }
+    }
+
+    fn push_error(ctxt: &mut Self::Context, error: form::Error<'r>) {
+        T::push_error(&mut ctxt.inner, error);
+    }
+
+    fn finalize(ctxt: Self::Context) -> form::Result<'r, Self> {
+        if ctxt.exceeded {
+            let msg = format!(
+                "Multipart request exceeds the limit of {} parts or {} bytes of fields",
+                ctxt.max_parts, ctxt.max_fields_size
+            );
+            let kind = form::error::ErrorKind::Custom(Status::BadRequest, Box::new(std::io::Error::other(msg)));
+            return Err(form::Error::from(kind).into());
+        }
+        T::finalize(ctxt.inner).map(|data| LimitedParts {
+            data,
+        })
+    }
+}
+
+/// Reads an uploaded form, and rejects it with a `408` when the body isn't received within `UPLOAD_REQUEST_TIMEOUT_SECS`.
+/// Only reading the body is timed, the handler runs once the upload is complete and is never cut off.
+pub struct UploadForm<T>(Form<T>);
+
+impl<T> UploadForm<T> {
+    pub fn into_inner(self) -> T {
+        self.0.into_inner()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: FromForm<'r>> FromData<'r> for UploadForm<T> {
+    type Error = form::Errors<'r>;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let Some(timeout) = CONFIG.upload_request_timeout_secs() else {
+            return Form::<T>::from_data(req, data).await.map(UploadForm);
+        };
+        match tokio::time::timeout(Duration::from_secs(timeout), Form::<T>::from_data(req, data)).await {
+            Ok(outcome) => outcome.map(UploadForm),
+            Err(_) => {
+                warn!("{} {} wasn't uploaded within {timeout} seconds", req.method(), req.uri().path());
+                let msg = format!("Upload wasn't received within {timeout} seconds");
+                let kind = form::error::ErrorKind::Custom(Status::RequestTimeout, Box::new(std::io::Error::other(msg)));
+                data::Outcome::Error((Status::RequestTimeout, form::Error::from(kind).into()))
+            }
+        }
+    }
+}
+
 //
 // Retry methods
 //
@@ -710,7 +1007,84 @@ pub fn get_reqwest_client() -> &'static Client {
 pub fn get_reqwest_client_builder() -> ClientBuilder {
     let mut headers = header::HeaderMap::new();
     headers.insert(header::USER_AGENT, header::HeaderValue::from_static("Vaultwarden"));
-    Client::builder().default_headers(headers).timeout(Duration::from_secs(10))
+    // The value is checked during config validation, so this should never fallback
+    let min_tls = parse_tls_version(&CONFIG.outbound_min_tls()).unwrap_or(reqwest::tls::Version::TLS_1_2);
+    let mut builder =
+        Client::builder().default_headers(headers).timeout(Duration::from_secs(10)).min_tls_version(min_tls);
+
+    // Setting a proxy disables the environment proxy variables
+    for proxy in configured_outbound_proxies() {
+        builder = builder.proxy(proxy);
+    }
+
+    match DohResolver::instance() {
+        Some(resolver) => builder.dns_resolver(resolver),
+        None => builder,
+    }
+}
+
+/// The configured outbound proxies, also used for the DNS-over-HTTPS lookups
+fn configured_outbound_proxies() -> Vec<reqwest::Proxy> {
+    let password = CONFIG.outbound_proxy_password();
+    let auth = CONFIG.outbound_proxy_username().zip(password);
+    outbound_proxies(
+        CONFIG.outbound_proxy().as_deref(),
+        CONFIG.outbound_proxy_http().as_deref(),
+        CONFIG.outbound_proxy_https().as_deref(),
+        auth.as_ref().map(|(username, password)| (username.as_str(), password.as_str())),
+        CONFIG.outbound_no_proxy().as_deref(),
+    )
+    .expect("The outbound proxies are checked during config validation")
+}
+
+/// The proxies of all outgoing requests, configured with `OUTBOUND_PROXY` and the per-scheme `OUTBOUND_PROXY_HTTP(S)`.
+/// The per-scheme proxies come first, so they take precedence over the proxy for all requests.
+pub fn outbound_proxies(
+    all: Option<&str>,
+    http: Option<&str>,
+    https: Option<&str>,
+    auth: Option<(&str, &str)>,
+    no_proxy: Option<&str>,
+) -> reqwest::Result<Vec<reqwest::Proxy>> {
+    use reqwest::{NoProxy, Proxy};
+
+    let no_proxy = no_proxy.and_then(NoProxy::from_string);
+    [http.map(Proxy::http), https.map(Proxy::https), all.map(Proxy::all)]
+        .into_iter()
+        .flatten()
+        .map(|proxy| {
+            let mut proxy = proxy?.no_proxy(no_proxy.clone());
+            if let Some((username, password)) = auth {
+                proxy = proxy.basic_auth(username, password);
+            }
+            Ok(proxy)
+        })
+        .collect()
+}
+
+/// Parses a TLS version like `1.2` as used by the `OUTBOUND_MIN_TLS` config option.
+/// TLS 1.3 isn't accepted, the native-tls backend can't use it as the minimum version.
+pub fn parse_tls_version(version: &str) -> Option<reqwest::tls::Version> {
+    use reqwest::tls::Version;
+    match version.trim().trim_start_matches("TLS").trim_start_matches("tls").trim() {
+        "1.0" => Some(Version::TLS_1_0),
+        "1.1" => Some(Version::TLS_1_1),
+        "1.2" => Some(Version::TLS_1_2),
+        _ => None,
+    }
+}
+
+/// Parses the version sent by the Bitwarden clients in the `Bitwarden-Client-Version` header,
+/// like `2024.6.2`, `2024.6.2-beta` or `2024.6.0 (19823)`. Missing minor and patch numbers are 0.
+pub fn parse_client_version(version: &str) -> Option<(u32, u32, u32)> {
+    let version = version.trim();
+    let end = version.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(version.len());
+    let mut numbers = version[..end].split('.').map(str::parse::<u32>);
+
+    let major = numbers.next()?.ok()?;
+    let minor = numbers.next().transpose().ok()?.unwrap_or(0);
+    let patch = numbers.next().transpose().ok()?.unwrap_or(0);
+    Some((major, minor, patch))
 }
 
 pub fn convert_json_key_lcase_first(src_json: Value) -> Value {
@@ -766,14 +1140,26 @@ pub fn parse_experimental_client_feature_flags(experimental_client_feature_flags
 
 mod dns_resolver {
     use std::{
+        collections::HashMap,
         fmt,
         net::{IpAddr, SocketAddr},
-        sync::Arc,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
     };
 
-    use hickory_resolver::{system_conf::read_system_conf, TokioAsyncResolver};
+    use hickory_resolver::{
+        proto::{
+            op::{Message, MessageType, OpCode, Query, ResponseCode},
+            rr::{RData, RecordType},
+        },
+        system_conf::read_system_conf,
+        TokioAsyncResolver,
+    };
     use once_cell::sync::Lazy;
-    use reqwest::dns::{Name, Resolve, Resolving};
+    use reqwest::{
+        dns::{Name, Resolve, Resolving},
+        header, Client,
+    };
 
     use crate::{util::is_global, CONFIG};
 
@@ -822,6 +1208,7 @@ mod dns_resolver {
     pub enum CustomDnsResolver {
         Default(),
         Hickory(Arc<TokioAsyncResolver>),
+        Doh(Arc<DohResolver>),
     }
     type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
@@ -832,6 +1219,9 @@ mod dns_resolver {
         }
 
         fn new() -> Arc<Self> {
+            if let Some(resolver) = DohResolver::instance() {
+                return Arc::new(Self::Doh(resolver));
+            }
             match read_system_conf() {
                 Ok((config, opts)) => {
                     let resolver = TokioAsyncResolver::tokio(config.clone(), opts.clone());
@@ -851,6 +1241,7 @@ mod dns_resolver {
             let result = match self {
                 Self::Default() => tokio::net::lookup_host(name).await?.next(),
                 Self::Hickory(r) => r.lookup_ip(name).await?.iter().next().map(|a| SocketAddr::new(a, 0)),
+                Self::Doh(r) => r.lookup_ip(name).await?.into_iter().next().map(|a| SocketAddr::new(a, 0)),
             };
 
             if let Some(addr) = &result {
@@ -882,6 +1273,143 @@ mod dns_resolver {
         }
     }
 
+    /// Resolves hostnames with an RFC 8484 DNS-over-HTTPS endpoint, configured with `OUTBOUND_DOH_URL`
+    #[derive(Debug, Clone)]
+    pub struct DohResolver {
+        url: String,
+        client: Client,
+        // The addresses of each hostname, kept until the smallest TTL of their records expires
+        cache: Arc<Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>>,
+    }
+
+    impl DohResolver {
+        pub fn instance() -> Option<Arc<Self>> {
+            static INSTANCE: Lazy<Option<Arc<DohResolver>>> = Lazy::new(|| {
+                let url = CONFIG.outbound_doh_url()?;
+                let min_tls = super::parse_tls_version(&CONFIG.outbound_min_tls());
+                Some(Arc::new(DohResolver::new(url, min_tls, super::configured_outbound_proxies())))
+            });
+            INSTANCE.clone()
+        }
+
+        pub(super) fn new(url: String, min_tls: Option<reqwest::tls::Version>, proxies: Vec<reqwest::Proxy>) -> Self {
+            let mut headers = header::HeaderMap::new();
+            headers.insert(header::USER_AGENT, header::HeaderValue::from_static("Vaultwarden"));
+            // This client can't use this resolver itself, so the endpoint is resolved by the system when it isn't an IP.
+            // Like the other outgoing requests, the lookups go through the outbound proxy
+            let client = proxies
+                .into_iter()
+                .fold(Client::builder(), |builder, proxy| builder.proxy(proxy))
+                .default_headers(headers)
+                .timeout(std::time::Duration::from_secs(10))
+                .min_tls_version(min_tls.unwrap_or(reqwest::tls::Version::TLS_1_2))
+                .build()
+                .expect("Failed to build DNS-over-HTTPS client");
+            Self {
+                url,
+                client,
+                cache: Arc::new(Mutex::new(HashMap::new())),
+            }
+        }
+
+        /// Returns the IPv4 addresses of a hostname followed by the IPv6 addresses.
+        /// When only one of both lookups fails, the addresses of the other one are returned.
+        pub async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>, BoxError> {
+            let now = Instant::now();
+            let cached = self.cache.lock().unwrap().get(name).filter(|(_, expires_at)| *expires_at > now).cloned();
+            if let Some((addrs, _)) = cached {
+                return Ok(addrs);
+            }
+
+            let (v4, v6) = tokio::join!(self.query(name, RecordType::A), self.query(name, RecordType::AAAA));
+            let (addrs, ttl) = match (v4, v6) {
+                (Ok((mut addrs, v4_ttl)), Ok((v6_addrs, v6_ttl))) => {
+                    addrs.extend(v6_addrs);
+                    (addrs, v4_ttl.into_iter().chain(v6_ttl).min())
+                }
+                // A partial answer isn't cached, so the failed lookup is retried the next time
+                (Ok((addrs, _)), Err(e)) | (Err(e), Ok((addrs, _))) if !addrs.is_empty() => {
+                    warn!("DNS-over-HTTPS lookup of {name} only partially succeeded: {e}");
+                    (addrs, None)
+                }
+                (Err(e), _) | (_, Err(e)) => return Err(e),
+            };
+            if addrs.is_empty() {
+                return Err(format!("No addresses found for {name} via DNS-over-HTTPS").into());
+            }
+
+            if let Some(ttl) = ttl.filter(|ttl| *ttl > 0) {
+                let mut cache = self.cache.lock().unwrap();
+                cache.retain(|_, (_, expires_at)| *expires_at > now);
+                cache.insert(name.to_string(), (addrs.clone(), now + Duration::from_secs(ttl.into())));
+            }
+            Ok(addrs)
+        }
+
+        /// Returns the addresses of the answer and the smallest TTL of their records
+        async fn query(&self, name: &str, record_type: RecordType) -> Result<(Vec<IpAddr>, Option<u32>), BoxError> {
+            let response = self
+                .client
+                .post(&self.url)
+                .header(header::CONTENT_TYPE, "application/dns-message")
+                .header(header::ACCEPT, "application/dns-message")
+                .body(doh_query(name, record_type)?)
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+            doh_answer_ips(&response)
+        }
+    }
+
+    fn doh_query(name: &str, record_type: RecordType) -> Result<Vec<u8>, BoxError> {
+        let mut message = Message::new();
+        // RFC 8484 recommends an ID of 0, which makes the responses cache friendly
+        message
+            .set_id(0)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(Query::query(hickory_resolver::Name::from_ascii(name)?, record_type));
+        Ok(message.to_vec()?)
+    }
+
+    fn doh_answer_ips(response: &[u8]) -> Result<(Vec<IpAddr>, Option<u32>), BoxError> {
+        let message = Message::from_vec(response)?;
+        match message.response_code() {
+            ResponseCode::NoError => {}
+            // The name doesn't exist, the lookup of the other record type reports that no address was found
+            ResponseCode::NXDomain => return Ok((Vec::new(), None)),
+            code => return Err(format!("DNS-over-HTTPS lookup failed: {code}").into()),
+        }
+        let mut ttl = None;
+        let addrs = message
+            .answers()
+            .iter()
+            .filter_map(|record| {
+                let addr = match record.data() {
+                    Some(RData::A(a)) => IpAddr::V4(a.0),
+                    Some(RData::AAAA(aaaa)) => IpAddr::V6(aaaa.0),
+                    _ => return None,
+                };
+                ttl = Some(ttl.map_or(record.ttl(), |ttl: u32| ttl.min(record.ttl())));
+                Some(addr)
+            })
+            .collect();
+        Ok((addrs, ttl))
+    }
+
+    impl Resolve for DohResolver {
+        fn resolve(&self, name: Name) -> Resolving {
+            let this = self.clone();
+            Box::pin(async move {
+                let addrs = this.lookup_ip(name.as_str()).await?;
+                Ok::<reqwest::dns::Addrs, _>(Box::new(addrs.into_iter().map(|a| SocketAddr::new(a, 0))))
+            })
+        }
+    }
+
     impl Resolve for CustomDnsResolver {
         fn resolve(&self, name: Name) -> Resolving {
             let this = self.clone();
@@ -894,7 +1422,135 @@ mod dns_resolver {
     }
 }
 
-pub use dns_resolver::{CustomDnsResolver, CustomResolverError};
+pub use dns_resolver::{CustomDnsResolver, CustomResolverError, DohResolver};
+
+#[cfg(test)]
+mod doh_tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use hickory_resolver::proto::{
+        op::{Message, MessageType},
+        rr::{rdata::A, RData, Record, RecordType},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::DohResolver;
+
+    /// Reads a single HTTP/1.1 request and returns its body
+    async fn read_request(stream: &mut TcpStream) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut chunk).await.unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+            let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+                continue;
+            };
+            let head = String::from_utf8_lossy(&buf[..end]).to_lowercase();
+            let len = head
+                .lines()
+                .find_map(|l| l.strip_prefix("content-length:"))
+                .map_or(0, |l| l.trim().parse::<usize>().unwrap());
+            while buf.len() < end + 4 + len {
+                let n = stream.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            return buf[end + 4..end + 4 + len].to_vec();
+        }
+    }
+
+    async fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) {
+        let head = format!(
+            "HTTP/1.1 {status}\r\ncontent-type: {content_type}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+    }
+
+    /// A DNS-over-HTTPS endpoint which resolves every A query to 127.0.0.1, over plain HTTP to keep the test local.
+    /// With `fail_aaaa` the AAAA queries fail with a server error.
+    async fn spawn_doh_server(queries: Arc<AtomicUsize>, fail_aaaa: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let request = Message::from_vec(&read_request(&mut stream).await).unwrap();
+                queries.fetch_add(1, Ordering::SeqCst);
+
+                let query = request.queries()[0].clone();
+                if fail_aaaa && query.query_type() == RecordType::AAAA {
+                    respond(&mut stream, "500 Internal Server Error", "text/plain", b"").await;
+                    continue;
+                }
+                let mut response = Message::new();
+                response.set_id(request.id()).set_message_type(MessageType::Response).add_query(query.clone());
+                if query.query_type() == RecordType::A {
+                    let rdata = RData::A(A(Ipv4Addr::LOCALHOST));
+                    response.add_answer(Record::from_rdata(query.name().clone(), 60, rdata));
+                }
+                respond(&mut stream, "200 OK", "application/dns-message", &response.to_vec().unwrap()).await;
+            }
+        });
+        format!("http://{addr}/dns-query")
+    }
+
+    async fn spawn_http_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                read_request(&mut stream).await;
+                respond(&mut stream, "200 OK", "text/plain", b"resolved").await;
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn outbound_request_resolved_via_doh() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let queries = Arc::new(AtomicUsize::new(0));
+            let doh_url = spawn_doh_server(Arc::clone(&queries), false).await;
+            let port = spawn_http_server().await;
+
+            let resolver = DohResolver::new(doh_url, None, Vec::new());
+            assert_eq!(resolver.lookup_ip("vault.doh.test").await.unwrap(), vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+
+            // This hostname doesn't exist, it can only be reached through the DNS-over-HTTPS endpoint
+            let client = reqwest::Client::builder().dns_resolver(Arc::new(resolver)).build().unwrap();
+            let body = client.get(format!("http://vault.doh.test:{port}/")).send().await.unwrap().text().await.unwrap();
+            assert_eq!(body, "resolved");
+            // An A and an AAAA query for the first lookup, the client got the cached answer
+            assert_eq!(queries.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    #[test]
+    fn doh_lookup_returns_the_resolved_family() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let queries = Arc::new(AtomicUsize::new(0));
+            let doh_url = spawn_doh_server(Arc::clone(&queries), true).await;
+
+            let resolver = DohResolver::new(doh_url, None, Vec::new());
+            assert_eq!(resolver.lookup_ip("vault.doh.test").await.unwrap(), vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+            // The partial answer isn't cached, both queries are sent again
+            assert_eq!(resolver.lookup_ip("vault.doh.test").await.unwrap(), vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+            assert_eq!(queries.load(Ordering::SeqCst), 4);
+        });
+    }
+}
 
 /// TODO: This is extracted from IpAddr::is_global, which is unstable:
 /// https://doc.rust-lang.org/nightly/std/net/enum.IpAddr.html#method.is_global
@@ -955,6 +1611,381 @@ pub fn is_global(ip: std::net::IpAddr) -> bool {
     ip.is_global()
 }
 
+/// Masks the host part of an IP address, zeroing the last octet of IPv4 and the last 80 bits of IPv6 addresses.
+pub fn anonymize_ip(ip: std::net::IpAddr) -> std::net::IpAddr {
+    match ip {
+        std::net::IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            std::net::IpAddr::V4(std::net::Ipv4Addr::new(a, b, c, 0))
+        }
+        std::net::IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            std::net::IpAddr::V6(std::net::Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+fn format_stored_ip(ip: std::net::IpAddr, anonymize: bool) -> String {
+    if anonymize {
+        anonymize_ip(ip).to_string()
+    } else {
+        ip.to_string()
+    }
+}
+
+/// Formats a client IP to be persisted in the database, anonymized if `IP_ANONYMIZE` is enabled.
+pub fn stored_ip(ip: &std::net::IpAddr) -> String {
+    format_stored_ip(*ip, CONFIG.ip_anonymize())
+}
+
+/// An IP network in CIDR notation like `10.0.0.0/8`, a single address without prefix length only contains itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: std::net::IpAddr,
+    prefix: u32,
+}
+
+impl std::str::FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: std::net::IpAddr = addr.parse().map_err(|_| format!("Invalid IP network: {s}"))?;
+        let max_prefix = if addr.is_ipv4() {
+            32
+        } else {
+            128
+        };
+        let prefix = match prefix.map(str::parse::<u32>) {
+            None => max_prefix,
+            Some(Ok(prefix)) if prefix <= max_prefix => prefix,
+            Some(_) => return Err(format!("Invalid IP network prefix length: {s}")),
+        };
+        Ok(Self {
+            addr,
+            prefix,
+        })
+    }
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: &std::net::IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+        match (self.addr, ip.to_canonical()) {
+            (std::net::IpAddr::V4(net), std::net::IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (std::net::IpAddr::V6(net), std::net::IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses a comma separated list of IP networks, like `IP_HEADER_TRUSTED_PROXIES`
+pub fn parse_ip_networks(list: &str) -> Result<Vec<IpNetwork>, String> {
+    list.split(',').map(str::trim).filter(|network| !network.is_empty()).map(str::parse).collect()
+}
+
+#[cfg(test)]
+mod compression_tests {
+    use super::*;
+    use rocket::{local::blocking::Client, serde::json::Json};
+    use serde_json::Value;
+
+    #[get("/sync")]
+    fn sync() -> Json<Value> {
+        let ciphers: Vec<Value> =
+            (0..500).map(|i| json!({"Id": i, "Name": "2.encrypted|name", "Object": "cipher"})).collect();
+        Json(json!({"Ciphers": ciphers, "Object": "sync"}))
+    }
+
+    fn client(enabled: bool) -> Client {
+        let rocket = rocket::build().mount("/", routes![sync]).attach(Compression {
+            enabled,
+            min_size: 1024,
+        });
+        Client::untracked(rocket).unwrap()
+    }
+
+    #[test]
+    fn test_compression_enabled() {
+        let client = client(true);
+        let res = client.get("/sync").header(Header::new("Accept-Encoding", "gzip, deflate, br")).dispatch();
+        assert_eq!(res.headers().get_one("Content-Encoding"), Some("br"));
+
+        let res = client.get("/sync").header(Header::new("Accept-Encoding", "gzip")).dispatch();
+        assert_eq!(res.headers().get_one("Content-Encoding"), Some("gzip"));
+        let body = res.into_bytes().unwrap();
+        let mut decoded = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&body[..]), &mut decoded).unwrap();
+        assert!(decoded.starts_with("{\"Ciphers\""));
+
+        // Clients without support get the plain response
+        let res = client.get("/sync").dispatch();
+        assert_eq!(res.headers().get_one("Content-Encoding"), None);
+    }
+
+    #[test]
+    fn test_compression_disabled() {
+        let client = client(false);
+        let res = client.get("/sync").header(Header::new("Accept-Encoding", "gzip, deflate, br")).dispatch();
+        assert_eq!(res.headers().get_one("Content-Encoding"), None);
+    }
+
+    #[test]
+    fn test_accept_encoding() {
+        assert_eq!(ContentEncoding::from_accept_encoding("gzip, br;q=0.5"), Some(ContentEncoding::Brotli));
+        assert_eq!(ContentEncoding::from_accept_encoding("gzip, br;q=0"), Some(ContentEncoding::Gzip));
+        assert_eq!(ContentEncoding::from_accept_encoding("identity"), None);
+    }
+}
+
+#[cfg(test)]
+mod timeout_tests {
+    use super::*;
+    use rocket::local::blocking::Client;
+
+    #[get("/slow")]
+    async fn slow() -> &'static str {
+        sleep(Duration::from_secs(10)).await;
+        "done"
+    }
+
+    #[get("/fast")]
+    fn fast() -> &'static str {
+        "done"
+    }
+
+    #[post("/accounts/profile")]
+    async fn write() -> &'static str {
+        sleep(Duration::from_secs(2)).await;
+        "saved"
+    }
+
+    fn client() -> Client {
+        let routes = with_request_timeout(routes![slow, fast, write], Some(1));
+        Client::untracked(rocket::build().mount("/", routes)).unwrap()
+    }
+
+    #[test]
+    fn slow_handler_cut_off() {
+        let client = client();
+        let start = std::time::Instant::now();
+        assert_eq!(client.get("/slow").dispatch().status(), Status::GatewayTimeout);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        assert_eq!(client.get("/fast").dispatch().status(), Status::Ok);
+    }
+
+    #[test]
+    fn write_handler_not_cut_off() {
+        assert_eq!(client().post("/accounts/profile").dispatch().status(), Status::Ok);
+    }
+}
+
+#[cfg(test)]
+mod proxy_tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::outbound_proxies;
+
+    /// Answers every request with `body`, and returns the port and the heads of the received requests
+    async fn spawn_server(body: &'static str) -> (u16, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 1024];
+                while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut chunk).await.unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                tx.send(String::from_utf8_lossy(&buf).to_lowercase()).unwrap();
+                let response =
+                    format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}", body.len());
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (port, rx)
+    }
+
+    fn client(proxies: Vec<reqwest::Proxy>) -> reqwest::Client {
+        proxies.into_iter().fold(reqwest::Client::builder(), |builder, proxy| builder.proxy(proxy)).build().unwrap()
+    }
+
+    #[test]
+    fn outbound_request_routed_through_proxy() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let (proxy_port, mut proxied) = spawn_server("proxied").await;
+            let proxy_url = format!("http://127.0.0.1:{proxy_port}");
+            let proxies =
+                outbound_proxies(Some(&proxy_url), None, None, Some(("vault", "secret")), Some("localhost")).unwrap();
+            let client = client(proxies);
+
+            // This hostname doesn't exist, it can only be reached through the proxy
+            let body = client.get("http://vault.proxy.test/api/sync").send().await.unwrap().text().await.unwrap();
+            assert_eq!(body, "proxied");
+            let head = proxied.recv().await.unwrap();
+            assert!(head.starts_with("get http://vault.proxy.test/api/sync http/1.1"));
+            // base64 of vault:secret
+            assert!(head.contains("proxy-authorization: basic dmf1bhq6c2vjcmv0"));
+
+            // Hosts on the no proxy list are contacted directly
+            let (direct_port, mut direct) = spawn_server("direct").await;
+            let body =
+                client.get(format!("http://localhost:{direct_port}/")).send().await.unwrap().text().await.unwrap();
+            assert_eq!(body, "direct");
+            assert!(direct.recv().await.unwrap().starts_with("get / http/1.1"));
+        });
+    }
+
+    #[test]
+    fn outbound_proxy_per_scheme() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let (http_port, mut http_proxied) = spawn_server("http proxy").await;
+            let (all_port, _) = spawn_server("all proxy").await;
+            let proxies = outbound_proxies(
+                Some(&format!("http://127.0.0.1:{all_port}")),
+                Some(&format!("http://127.0.0.1:{http_port}")),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+
+            let body = client(proxies).get("http://vault.proxy.test/").send().await.unwrap().text().await.unwrap();
+            assert_eq!(body, "http proxy");
+            assert!(!http_proxied.recv().await.unwrap().contains("proxy-authorization"));
+        });
+
+        assert!(outbound_proxies(Some("not a url"), None, None, None, None).is_err());
+        assert!(outbound_proxies(None, None, None, None, None).unwrap().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tls_tests {
+    use super::*;
+    use reqwest::tls::Version;
+
+    #[test]
+    fn test_parse_tls_version() {
+        assert_eq!(parse_tls_version("1.0"), Some(Version::TLS_1_0));
+        assert_eq!(parse_tls_version("1.2"), Some(Version::TLS_1_2));
+        assert_eq!(parse_tls_version("TLS1.2"), Some(Version::TLS_1_2));
+        assert_eq!(parse_tls_version("1.3"), None);
+        assert_eq!(parse_tls_version(" 1.1 "), Some(Version::TLS_1_1));
+        assert_eq!(parse_tls_version("1.4"), None);
+        assert_eq!(parse_tls_version(""), None);
+    }
+}
+
+#[cfg(test)]
+mod client_version_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_client_version() {
+        assert_eq!(parse_client_version("2024.6.2"), Some((2024, 6, 2)));
+        assert_eq!(parse_client_version("2024.6.2-beta"), Some((2024, 6, 2)));
+        assert_eq!(parse_client_version("2024.6.0 (19823)"), Some((2024, 6, 0)));
+        assert_eq!(parse_client_version(" 2024.12 "), Some((2024, 12, 0)));
+        assert_eq!(parse_client_version("2024"), Some((2024, 0, 0)));
+        assert_eq!(parse_client_version("v2024.6.2"), None);
+        assert_eq!(parse_client_version("2024..2"), None);
+        assert_eq!(parse_client_version(""), None);
+    }
+}
+
+#[cfg(test)]
+mod ip_tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_stored_ip_anonymized() {
+        let ip: IpAddr = "203.0.113.42".parse().unwrap();
+        assert_eq!(format_stored_ip(ip, true), "203.0.113.0");
+        let ip: IpAddr = "2001:db8:85a3:8d3:1319:8a2e:370:7348".parse().unwrap();
+        assert_eq!(format_stored_ip(ip, true), "2001:db8:85a3::");
+    }
+
+    #[test]
+    fn test_stored_ip_full() {
+        let ip: IpAddr = "203.0.113.42".parse().unwrap();
+        assert_eq!(format_stored_ip(ip, false), "203.0.113.42");
+        let ip: IpAddr = "2001:db8:85a3:8d3:1319:8a2e:370:7348".parse().unwrap();
+        assert_eq!(format_stored_ip(ip, false), "2001:db8:85a3:8d3:1319:8a2e:370:7348");
+    }
+}
+
+#[cfg(test)]
+mod date_tests {
+    use super::*;
+
+    #[test]
+    fn malformed_dates_not_parsed() {
+        let date = try_parse_date("2024-06-15T12:30:45.123456Z").expect("valid date");
+        assert_eq!(format_date(&date), "2024-06-15T12:30:45.123456Z");
+        assert!(try_parse_date("2024-06-15").is_none());
+        assert!(try_parse_date("not a date").is_none());
+    }
+}
+
+#[cfg(test)]
+mod multipart_tests {
+    use super::*;
+    use rocket::form::Options;
+
+    type Form = LimitedParts<HashMap<String, String>>;
+
+    fn push_fields(ctxt: &mut LimitedPartsContext<'_, HashMap<String, String>>, count: usize) {
+        for _ in 0..count {
+            Form::push_value(ctxt, ValueField::parse("key=value"));
+        }
+    }
+
+    #[test]
+    fn test_multipart_parts_within_limit() {
+        let mut ctxt = LimitedPartsContext::new(Options::Lenient, 4, 1024);
+        push_fields(&mut ctxt, 4);
+        assert!(Form::finalize(ctxt).is_ok());
+    }
+
+    #[test]
+    fn test_multipart_excessive_parts_rejected() {
+        let mut ctxt = LimitedPartsContext::new(Options::Lenient, 4, 1024);
+        push_fields(&mut ctxt, 4);
+        assert!(!ctxt.exceeded);
+        // The fifth part is rejected without being processed, as is everything after it
+        assert!(!ctxt.count_part(0));
+        push_fields(&mut ctxt, 1000);
+        let errors = Form::finalize(ctxt).err().unwrap();
+        assert_eq!(errors.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn test_multipart_excessive_fields_size_rejected() {
+        let mut ctxt = LimitedPartsContext::new(Options::Lenient, 4, 8);
+        push_fields(&mut ctxt, 2);
+        assert!(ctxt.exceeded);
+        assert_eq!(Form::finalize(ctxt).err().unwrap().status(), Status::BadRequest);
+    }
+}
+
 /// These are some tests to check that the implementations match
 /// The IPv4 can be all checked in 30 seconds or so and they are correct as of nightly 2023-07-17
 /// The IPV6 can't be checked in a reasonable time, so we check over a hundred billion random ones, so far correct